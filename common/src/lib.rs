@@ -12,7 +12,8 @@ use tempfile::NamedTempFile;
 
 mod region;
 pub use region::{
-    Block, RegionDefinition, RegionOptions, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
+    Block, RegionDefinition, RegionOptions, EXTENT_VERSION_COMPRESSED,
+    EXTENT_VERSION_RAW, EXTENT_VERSION_SQLITE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
 };
 
 pub mod x509;
@@ -122,6 +123,30 @@ pub enum CrucibleError {
 
     #[error("Failed reconciliation")]
     RegionAssembleError,
+
+    #[error("Region resize failed: {0}")]
+    RegionResizeError(String),
+
+    #[error("Downstairs migration failed: {0}")]
+    MigrationError(String),
+
+    #[error("Erasure coding error: {0}")]
+    ErasureCodingError(String),
+
+    #[error("Read failed the upstairs block integrity check: {0}")]
+    BlockIntegrityError(String),
+
+    #[error("Key rotation failed: {0}")]
+    KeyRotationError(String),
+
+    #[error("Could not get key: {0}")]
+    KeyProviderError(String),
+
+    #[error("Invalid QoS limits: {0}")]
+    InvalidQosLimits(String),
+
+    #[error("Invalid downstairs replicas: {0}")]
+    InvalidReplicas(String),
 }
 
 impl From<std::io::Error> for CrucibleError {
@@ -32,6 +32,21 @@ pub const MIN_BLOCK_SIZE: usize = (1 << MIN_SHIFT) as usize;
 pub const MAX_BLOCK_SIZE: usize = (1 << MAX_SHIFT) as usize;
 pub const MAX_EXTENT_FILE_SIZE: u64 = (1 << 29) as u64; // 512 MiB
 
+/*
+ * On-disk extent formats.  The version is chosen when a region is created
+ * and is recorded in each extent's metadata.
+ */
+// Block contexts and metadata live in a SQLite database next to each extent.
+pub const EXTENT_VERSION_SQLITE: u32 = 1;
+// Block contexts and metadata live at the end of the extent file itself.
+pub const EXTENT_VERSION_RAW: u32 = 2;
+// Like EXTENT_VERSION_RAW, but each block is stored compressed.
+pub const EXTENT_VERSION_COMPRESSED: u32 = 3;
+
+fn default_extent_version() -> u32 {
+    EXTENT_VERSION_SQLITE
+}
+
 impl Block {
     pub fn new(value: u64, shift: u32) -> Block {
         // are you sure you need blocks that small?
@@ -126,6 +141,13 @@ pub struct RegionDefinition {
      * region data will be encrypted
      */
     encrypted: bool,
+
+    /**
+     * On-disk format new extents are created with.  Regions created before
+     * this was recorded all use the SQLite format.
+     */
+    #[serde(default = "default_extent_version")]
+    extent_version: u32,
 }
 
 impl RegionDefinition {
@@ -137,6 +159,7 @@ impl RegionDefinition {
             extent_count: 0,
             uuid: opts.uuid,
             encrypted: opts.encrypted,
+            extent_version: opts.extent_version,
         })
     }
 
@@ -179,6 +202,10 @@ impl RegionDefinition {
     pub fn get_encrypted(&self) -> bool {
         self.encrypted
     }
+
+    pub fn extent_version(&self) -> u32 {
+        self.extent_version
+    }
 }
 
 /**
@@ -193,6 +220,7 @@ impl Default for RegionDefinition {
             extent_count: 0,
             uuid: Uuid::nil(),
             encrypted: false,
+            extent_version: EXTENT_VERSION_SQLITE,
         }
     }
 }
@@ -218,6 +246,12 @@ pub struct RegionOptions {
      * region data will be encrypted
      */
     encrypted: bool,
+
+    /**
+     * On-disk format for the extents of this region
+     */
+    #[serde(default = "default_extent_version")]
+    extent_version: u32,
 }
 
 impl RegionOptions {
@@ -252,6 +286,13 @@ impl RegionOptions {
             );
         }
 
+        if self.extent_version != EXTENT_VERSION_SQLITE
+            && self.extent_version != EXTENT_VERSION_RAW
+            && self.extent_version != EXTENT_VERSION_COMPRESSED
+        {
+            bail!("unknown extent version {}", self.extent_version);
+        }
+
         Ok(())
     }
 
@@ -270,6 +311,10 @@ impl RegionOptions {
     pub fn set_encrypted(&mut self, encrypted: bool) {
         self.encrypted = encrypted;
     }
+
+    pub fn set_extent_version(&mut self, extent_version: u32) {
+        self.extent_version = extent_version;
+    }
 }
 
 impl Default for RegionOptions {
@@ -281,6 +326,7 @@ impl Default for RegionOptions {
             extent_size: Block::new(100, 9),
             uuid: Uuid::nil(),
             encrypted: false,
+            extent_version: EXTENT_VERSION_SQLITE,
         }
     }
 }
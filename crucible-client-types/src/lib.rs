@@ -14,6 +14,15 @@ pub enum VolumeConstructionRequest {
         block_size: u64,
         sub_volumes: Vec<VolumeConstructionRequest>,
         read_only_parent: Option<Box<VolumeConstructionRequest>>,
+        /// When set, the sub volumes are striped instead of concatenated:
+        /// consecutive stripes of this many blocks go to each sub volume
+        /// in turn.  The sub volumes must all be the same size, a whole
+        /// number of stripes.
+        stripe_blocks: Option<u64>,
+        /// A directory to keep the scrub point of this volume in as a scrub
+        /// of its read only parent goes, so a scrub that is stopped picks
+        /// up where it was.  When not set, a stopped scrub starts over.
+        scrub_state_dir: Option<String>,
     },
     Url {
         id: Uuid,
@@ -30,6 +39,22 @@ pub enum VolumeConstructionRequest {
         block_size: u64,
         path: String,
     },
+    /// A set of data_shards + parity_shards regions, one per target in
+    /// opts, holding Reed-Solomon coded stripes instead of full copies.
+    ErasureCoded {
+        id: Uuid,
+        block_size: u64,
+        data_shards: usize,
+        parity_shards: usize,
+        opts: CrucibleOpts,
+        gen: u64,
+        /// Where to keep which shards are faulted, so a shard that missed
+        /// writes is still rebuilt if the upstairs restarts first, and
+        /// which stripes were being written, so their parity is made again
+        /// after a restart.  When not set, every shard that activates is
+        /// taken to be in sync, and so is the parity.
+        shard_state: Option<String>,
+    },
 }
 
 #[derive(
@@ -40,28 +65,144 @@ pub struct CrucibleOpts {
     pub target: Vec<SocketAddr>,
     pub lossy: bool,
     pub flush_timeout: Option<u32>,
-    pub key: Option<String>,
+    /// The key the volume is encrypted with.
+    pub key: Option<KeyReference>,
+    /// A key the volume was encrypted with before a key rotation that
+    /// has not finished yet.  Blocks still encrypted with it can be read,
+    /// but nothing new is written with it.
+    pub old_key: Option<KeyReference>,
     pub cert_pem: Option<String>,
     pub key_pem: Option<String>,
     pub root_cert_pem: Option<String>,
     pub control: Option<SocketAddr>,
     pub read_only: bool,
+    /// How many downstairs must complete a write or flush before it is
+    /// acked.  When not set, this is a majority of the targets.
+    pub write_quorum: Option<usize>,
+    /// Where to keep the extents changed while a downstairs is offline,
+    /// so they are still repaired if the upstairs restarts before that
+    /// downstairs is back.
+    pub dirty_extents: Option<String>,
+    /// Where to keep the record of what the upstairs last wrote to each
+    /// block, so reads of blocks written before it last activated are
+    /// still checked against it.  When not set, only blocks written since
+    /// the upstairs activated are checked.
+    pub block_record: Option<String>,
+    /// Which downstairs reads are sent to.  When not set, reads go to
+    /// all of them.
+    pub read_policy: Option<ReadPolicy>,
+    /// How many seconds a downstairs may leave a job unanswered before
+    /// it is faulted and repaired later.  When not set, a downstairs is
+    /// never faulted for this.
+    pub fault_timeout: Option<u32>,
+    /// How many jobs a downstairs may have outstanding before it is
+    /// faulted and repaired later.  When not set, there is no limit.
+    pub fault_queue: Option<usize>,
+    /// Limits on the IO the guest sends.  When not set, there are none.
+    pub qos: Option<QosLimits>,
 }
 
 impl CrucibleOpts {
-    pub fn key_bytes(&self) -> Option<Vec<u8>> {
-        if let Some(key) = &self.key {
-            // For xts, key size must be 32 bytes
-            let decoded_key =
-                base64::decode(key).expect("could not base64 decode key!");
+    /// The write quorum to use with this set of targets.
+    pub fn write_quorum(&self) -> usize {
+        self.write_quorum.unwrap_or(self.target.len() / 2 + 1)
+    }
 
-            if decoded_key.len() != 32 {
-                panic!("Key length must be 32 bytes!");
-            }
+    /// The read policy to use.
+    pub fn read_policy(&self) -> ReadPolicy {
+        self.read_policy.unwrap_or(ReadPolicy::All)
+    }
+}
+
+/// Which downstairs a read is sent to.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadPolicy {
+    /// Every downstairs, and their results are compared.
+    All,
+    /// One downstairs, taking turns.
+    RoundRobin,
+    /// One downstairs, the one that has been answering reads the
+    /// fastest.
+    LowestLatency,
+}
 
-            Some(decoded_key)
-        } else {
-            None
+/// Token bucket limits on the IO a guest sends.  Each rate is per
+/// second, and a rate that is not set is not limited.
+#[derive(
+    Debug, Copy, Clone, Default, Serialize, Deserialize, JsonSchema, PartialEq,
+)]
+pub struct QosLimits {
+    /// Read IOPs per second.
+    pub read_iops: Option<u64>,
+    /// Write IOPs per second.
+    pub write_iops: Option<u64>,
+    /// Bytes read per second.
+    pub read_bytes: Option<u64>,
+    /// Bytes written per second.
+    pub write_bytes: Option<u64>,
+    /// The size of one IOP, a larger IO counts as more than one.  When
+    /// not set, every IO is one IOP.
+    pub iop_size: Option<u64>,
+    /// How many seconds of IO at each rate can be sent at once by a guest
+    /// that has been under it.  When not set, one second's worth.
+    pub burst_secs: Option<u32>,
+}
+
+/// Names a volume's encryption key and where to get it from, without
+/// holding the key itself.
+///
+/// The key named by `key_id` is usually a key-encryption key that only
+/// the provider has, and the volume's data key is stored wrapped by it in
+/// `wrapped_key`.  Without a `wrapped_key`, the named key is the data key.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct KeyReference {
+    pub key_id: String,
+    pub provider: KeyProviderConfig,
+    /// The data key, wrapped with the key named by `key_id`, base64
+    /// encoded.
+    pub wrapped_key: Option<String>,
+}
+
+impl KeyReference {
+    /// A reference to a base64 encoded key given on a command line.
+    pub fn inline(key: String) -> KeyReference {
+        KeyReference {
+            key_id: String::from("inline"),
+            provider: KeyProviderConfig::Inline { key },
+            wrapped_key: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KeyProviderConfig {
+    /// The key itself, base64 encoded, whatever its ID.  This puts a key
+    /// in the request, so it is meant for tests and tools.
+    Inline { key: String },
+    /// A local file of keys, one per line as the key ID, a space, and the
+    /// base64 encoded key.
+    File { path: String },
+    /// A key server reached over HTTP.
+    Http { url: String },
+}
+
+/*
+ * Requests and options get logged, so keep inline keys out of them.
+ */
+impl std::fmt::Debug for KeyProviderConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyProviderConfig::Inline { .. } => {
+                f.debug_struct("Inline").finish_non_exhaustive()
+            }
+            KeyProviderConfig::File { path } => {
+                f.debug_struct("File").field("path", path).finish()
+            }
+            KeyProviderConfig::Http { url } => {
+                f.debug_struct("Http").field("url", url).finish()
+            }
         }
     }
 }
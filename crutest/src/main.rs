@@ -99,6 +99,28 @@ pub struct Opt {
     )]
     target: Vec<SocketAddr>,
 
+    /// The number of downstairs that must complete a write or flush
+    /// before it is acked.  Defaults to a majority of the targets.
+    #[clap(long, global = true, action)]
+    write_quorum: Option<usize>,
+
+    /// Where to keep the extents changed while a downstairs is offline.
+    #[clap(long, global = true, action)]
+    dirty_extents: Option<String>,
+
+    /// Where to keep the record of what was last written to each block.
+    #[clap(long, global = true, action)]
+    block_record: Option<String>,
+
+    /// Seconds a downstairs may leave a job unanswered before it is
+    /// faulted.
+    #[clap(long, global = true, action)]
+    fault_timeout: Option<u32>,
+
+    /// Jobs a downstairs may have outstanding before it is faulted.
+    #[clap(long, global = true, action)]
+    fault_queue: Option<usize>,
+
     #[clap(subcommand)]
     workload: Workload,
 
@@ -466,12 +488,20 @@ async fn main() -> Result<()> {
         target: opt.target,
         lossy: opt.lossy,
         flush_timeout: opt.flush_timeout,
-        key: opt.key,
+        key: opt.key.map(KeyReference::inline),
+        old_key: None,
         cert_pem: opt.cert_pem,
         key_pem: opt.key_pem,
         root_cert_pem: opt.root_cert_pem,
         control: opt.control,
         read_only: false,
+        write_quorum: opt.write_quorum,
+        dirty_extents: opt.dirty_extents,
+        block_record: opt.block_record,
+        read_policy: None,
+        fault_timeout: opt.fault_timeout,
+        fault_queue: opt.fault_queue,
+        qos: None,
     };
 
     /*
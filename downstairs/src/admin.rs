@@ -3,7 +3,7 @@ use super::*;
 
 use dropshot::{
     endpoint, ApiDescription, ConfigDropshot, HttpError, HttpResponseCreated,
-    HttpServerStarter, Path, RequestContext, TypedBody,
+    HttpResponseOk, HttpServerStarter, Path, RequestContext, TypedBody,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -26,6 +26,7 @@ pub struct RunDownstairsForRegionParams {
     key_pem: Option<String>,
     root_cert_pem: Option<String>,
     read_only: bool,
+    scrub_rate: Option<u64>,
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -69,6 +70,10 @@ pub async fn run_downstairs_for_region(
     )
     .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
 
+    if let Some(scrub_rate) = run_params.scrub_rate {
+        tokio::spawn(scrub::scrub_task(d.clone(), scrub_rate));
+    }
+
     let _join_handle = start_downstairs(
         d.clone(),
         run_params.address,
@@ -89,10 +94,37 @@ pub async fn run_downstairs_for_region(
     Ok(HttpResponseCreated(DownstairsRunningResponse { uuid }))
 }
 
+/**
+ * What the scrubber of a running downstairs has found.
+ */
+#[endpoint {
+    method = GET,
+    path = "/regions/{uuid}/downstairs/scrub"
+}]
+pub async fn downstairs_scrub_report(
+    rqctx: Arc<RequestContext<Arc<ServerContext>>>,
+    path_param: Path<RunDownstairsforRegionPath>,
+) -> Result<HttpResponseOk<scrub::ScrubReport>, HttpError> {
+    let apictx = rqctx.context();
+    let uuid = path_param.into_inner().uuid;
+
+    let downstairs = apictx.downstairs.lock().await;
+    let d = downstairs.get(&uuid).ok_or_else(|| {
+        HttpError::for_not_found(
+            None,
+            format!("downstairs {} is not running", uuid),
+        )
+    })?;
+
+    let report = d.lock().await.scrub_report();
+    Ok(HttpResponseOk(report))
+}
+
 fn register_endpoints(
     api_description: &mut ApiDescription<Arc<ServerContext>>,
 ) -> Result<(), String> {
     api_description.register(run_downstairs_for_region)?;
+    api_description.register(downstairs_scrub_report)?;
 
     Ok(())
 }
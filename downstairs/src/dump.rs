@@ -96,7 +96,7 @@ pub fn dump_region(
              * number
              */
             let extent_info = ExtentMeta {
-                ext_version: 0,
+                ext_version: inner.ext_version(),
                 gen_number: inner.gen_number().unwrap(),
                 flush_number: inner.flush_number().unwrap(),
                 dirty: inner.dirty().unwrap(),
@@ -0,0 +1,619 @@
+// Copyright 2022 Oxide Computer Company
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use crucible_common::*;
+
+use crate::extent_inner_raw::{
+    any_slot_present, clear_stale_slots, decode_block_contexts, full_blocks,
+    place_contexts, slot_area_size, SLOTS_PER_BLOCK, SLOT_AREA_ALIGN,
+    SLOT_SIZE,
+};
+use crate::region::{DownstairsBlockContext, ExtentInner, ExtentMeta};
+
+/*
+ * A compressed extent keeps everything in the one extent file, like a raw
+ * extent, but stores each block compressed:
+ *
+ *   | header | context slots | block index | data area |
+ *
+ * The context slots work just as they do in a raw extent.
+ *
+ * The block index has an entry for each block saying where its data is
+ * in the data area, and how it is stored.  A block that was never written,
+ * or that is all zeros, has an empty entry and takes no space at all.  The
+ * data area is handed out in sectors, so a block takes only as many
+ * sectors as its compressed form needs.  A block that does not compress
+ * is stored as it is.
+ *
+ * Data the index on disk points to is never overwritten.  A write puts
+ * the new data in free sectors and only updates the index in memory.  When
+ * the extent is synced, the index is written out after the data, and only
+ * then are the sectors holding the replaced data freed.  A crash before
+ * the sync leaves the index describing the data as of the last sync, and
+ * a flush makes no promise about anything later.
+ *
+ * The file grows and shrinks with the data area, so the header is at the
+ * start of the file.
+ */
+const COMPRESSED_MAGIC: [u8; 8] = *b"CRUCCMP\0";
+const HEADER_SIZE: u64 = 512;
+const SECTOR_SIZE: u64 = 512;
+
+/*
+ * Header layout, all integers little endian:
+ *
+ *   magic        [0..8]
+ *   block size   [8..16]
+ *   extent size  [16..24]
+ *   ExtentMeta   [24..]   (bincode)
+ */
+const HEADER_META: usize = 24;
+
+/*
+ * Index entry layout, all integers little endian:
+ *
+ *   first sector [0..8]
+ *   stored len   [8..12]
+ *   flags        [12]
+ */
+const ENTRY_SIZE: usize = 16;
+const ENTRY_PRESENT: u8 = 1 << 0;
+const ENTRY_LZ4: u8 = 1 << 1;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct IndexEntry {
+    sector: u64,
+    len: u32,
+    flags: u8,
+}
+
+impl IndexEntry {
+    fn present(&self) -> bool {
+        self.flags & ENTRY_PRESENT != 0
+    }
+
+    fn sectors(&self) -> u64 {
+        (self.len as u64 + SECTOR_SIZE - 1) / SECTOR_SIZE
+    }
+
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut buf = [0u8; ENTRY_SIZE];
+        buf[0..8].copy_from_slice(&self.sector.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.len.to_le_bytes());
+        buf[12] = self.flags;
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> IndexEntry {
+        IndexEntry {
+            sector: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            len: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            flags: buf[12],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CompressedInner {
+    file: File,
+    block_size: u64,
+    extent_size: u64,
+    gen_number: u64,
+    flush_number: u64,
+    dirty: bool,
+    /// The block index as it was last written to disk.
+    synced: Vec<IndexEntry>,
+    /// The block index including every write since the last sync.
+    index: Vec<IndexEntry>,
+    /// Which sectors of the data area either index points to.
+    used: Vec<bool>,
+}
+
+fn index_area_size(extent_size: u64) -> u64 {
+    let size = extent_size * ENTRY_SIZE as u64;
+    (size + SLOT_AREA_ALIGN - 1) / SLOT_AREA_ALIGN * SLOT_AREA_ALIGN
+}
+
+/*
+ * The header is padded out to SLOT_AREA_ALIGN, so the slots start there.
+ */
+fn index_offset(extent_size: u64) -> u64 {
+    SLOT_AREA_ALIGN + slot_area_size(extent_size)
+}
+
+fn data_offset(extent_size: u64) -> u64 {
+    index_offset(extent_size) + index_area_size(extent_size)
+}
+
+/**
+ * Returns true if the file at this path starts with a compressed extent
+ * header.
+ */
+pub fn is_compressed_extent<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() < HEADER_SIZE {
+        return Ok(false);
+    }
+
+    let mut magic = [0u8; COMPRESSED_MAGIC.len()];
+    file.read_exact(&mut magic)?;
+
+    Ok(magic == COMPRESSED_MAGIC)
+}
+
+impl CompressedInner {
+    /**
+     * Size a newly created extent file for its metadata, and write out
+     * the default metadata.  The data area starts out empty.
+     */
+    pub fn create(
+        file: File,
+        def: &RegionDefinition,
+    ) -> Result<CompressedInner> {
+        let extent_size = def.extent_size().value;
+        file.set_len(data_offset(extent_size))?;
+
+        let meta = ExtentMeta {
+            ext_version: EXTENT_VERSION_COMPRESSED,
+            ..Default::default()
+        };
+        let mut inner = CompressedInner {
+            file,
+            block_size: def.block_size(),
+            extent_size,
+            gen_number: meta.gen_number,
+            flush_number: meta.flush_number,
+            dirty: meta.dirty,
+            synced: vec![IndexEntry::default(); extent_size as usize],
+            index: vec![IndexEntry::default(); extent_size as usize],
+            used: Vec::new(),
+        };
+        inner.write_header()?;
+
+        Ok(inner)
+    }
+
+    /**
+     * Verify an existing extent file is a compressed extent that matches
+     * the region definition, and read in its metadata and block index.
+     */
+    pub fn open(
+        mut file: File,
+        def: &RegionDefinition,
+    ) -> Result<CompressedInner> {
+        let block_size = def.block_size();
+        let extent_size = def.extent_size().value;
+        let data_start = data_offset(extent_size);
+        let cur_size = file.metadata()?.len();
+        if cur_size < data_start {
+            bail!(
+                "File size {:?} is smaller than the minimum {:?}",
+                cur_size,
+                data_start
+            );
+        }
+
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+
+        if header[..COMPRESSED_MAGIC.len()] != COMPRESSED_MAGIC {
+            bail!("Extent file is missing the compressed extent header");
+        }
+
+        let file_block_size = u64::from_le_bytes(header[8..16].try_into()?);
+        let file_extent_size = u64::from_le_bytes(header[16..24].try_into()?);
+        if file_block_size != block_size || file_extent_size != extent_size {
+            bail!(
+                "Extent has block size {} and extent size {}, expected {} \
+                 and {}",
+                file_block_size,
+                file_extent_size,
+                block_size,
+                extent_size,
+            );
+        }
+
+        let meta: ExtentMeta = bincode::deserialize(&header[HEADER_META..])?;
+        if meta.ext_version != EXTENT_VERSION_COMPRESSED {
+            bail!("Unsupported compressed extent version {}", meta.ext_version);
+        }
+
+        let mut buf = vec![0u8; extent_size as usize * ENTRY_SIZE];
+        file.seek(SeekFrom::Start(index_offset(extent_size)))?;
+        file.read_exact(&mut buf)?;
+        let index: Vec<IndexEntry> = buf
+            .chunks_exact(ENTRY_SIZE)
+            .map(IndexEntry::decode)
+            .collect();
+
+        // Make sure every entry is sane before trusting any of them.
+        let mut used = Vec::new();
+        for (block, entry) in index.iter().enumerate() {
+            if !entry.present() {
+                continue;
+            }
+            let end =
+                data_start + entry.sector * SECTOR_SIZE + entry.len as u64;
+            if entry.len == 0 || entry.len as u64 > block_size || end > cur_size
+            {
+                bail!("Block {} has a bad index entry {:?}", block, entry);
+            }
+
+            let first = entry.sector as usize;
+            let last = first + entry.sectors() as usize;
+            if used.len() < last {
+                used.resize(last, false);
+            }
+            if used[first..last].iter().any(|u| *u) {
+                bail!("Block {} overlaps another block's data", block);
+            }
+            used[first..last].fill(true);
+        }
+
+        Ok(CompressedInner {
+            file,
+            block_size,
+            extent_size,
+            gen_number: meta.gen_number,
+            flush_number: meta.flush_number,
+            dirty: meta.dirty,
+            synced: index.clone(),
+            index,
+            used,
+        })
+    }
+
+    fn slot_offset(&self, block: u64) -> u64 {
+        SLOT_AREA_ALIGN + block * (SLOTS_PER_BLOCK * SLOT_SIZE) as u64
+    }
+
+    fn sector_offset(&self, sector: u64) -> u64 {
+        data_offset(self.extent_size) + sector * SECTOR_SIZE
+    }
+
+    /*
+     * Write the in memory metadata out to the header, and make sure it is
+     * on disk before returning.
+     */
+    fn write_header(&mut self) -> Result<()> {
+        let meta = ExtentMeta {
+            ext_version: EXTENT_VERSION_COMPRESSED,
+            gen_number: self.gen_number,
+            flush_number: self.flush_number,
+            dirty: self.dirty,
+        };
+
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        header[..COMPRESSED_MAGIC.len()].copy_from_slice(&COMPRESSED_MAGIC);
+        header[8..16].copy_from_slice(&self.block_size.to_le_bytes());
+        header[16..24].copy_from_slice(&self.extent_size.to_le_bytes());
+        let encoded = bincode::serialize(&meta)?;
+        header[HEADER_META..][..encoded.len()].copy_from_slice(&encoded);
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn read_slots(&self, block: u64, count: u64) -> Result<Vec<u8>> {
+        let mut slots = vec![0u8; count as usize * SLOTS_PER_BLOCK * SLOT_SIZE];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.slot_offset(block)))?;
+        file.read_exact(&mut slots)?;
+        Ok(slots)
+    }
+
+    fn write_slots(&mut self, block: u64, slots: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(self.slot_offset(block)))?;
+        self.file.write_all(slots)?;
+        Ok(())
+    }
+
+    /*
+     * Find `count` free sectors in a row, growing the data area if no gap
+     * is big enough, and mark them used.
+     */
+    fn allocate(&mut self, count: u64) -> u64 {
+        let count = count as usize;
+        let mut run = 0;
+        let mut start = None;
+        for (i, used) in self.used.iter().enumerate() {
+            if *used {
+                run = 0;
+            } else {
+                run += 1;
+                if run == count {
+                    start = Some(i + 1 - count);
+                    break;
+                }
+            }
+        }
+
+        // Any free sectors at the end of the data area are the start of
+        // the new space.
+        let start = start.unwrap_or(self.used.len() - run);
+        if self.used.len() < start + count {
+            self.used.resize(start + count, false);
+        }
+        self.used[start..start + count].fill(true);
+
+        start as u64
+    }
+
+    fn free(&mut self, entry: &IndexEntry) {
+        if entry.present() {
+            let first = entry.sector as usize;
+            self.used[first..first + entry.sectors() as usize].fill(false);
+        }
+    }
+
+    fn read_block(&mut self, block: u64, data: &mut [u8]) -> Result<()> {
+        let entry = self.index[block as usize];
+        if !entry.present() {
+            data.fill(0);
+            return Ok(());
+        }
+
+        let mut stored = vec![0u8; entry.len as usize];
+        self.file
+            .seek(SeekFrom::Start(self.sector_offset(entry.sector)))?;
+        self.file.read_exact(&mut stored)?;
+
+        if entry.flags & ENTRY_LZ4 != 0 {
+            let len = lz4_flex::decompress_into(&stored, data)?;
+            if len != data.len() {
+                bail!(
+                    "Block {} decompressed to {} bytes, expected {}",
+                    block,
+                    len,
+                    data.len()
+                );
+            }
+        } else {
+            data.copy_from_slice(&stored);
+        }
+
+        Ok(())
+    }
+
+    fn write_block(&mut self, block: u64, data: &[u8]) -> Result<()> {
+        // Data the on disk index points to stays put until the next sync,
+        // but anything written since then can be reused right away.
+        let old = self.index[block as usize];
+        if old != self.synced[block as usize] {
+            self.free(&old);
+        }
+
+        if data.iter().all(|b| *b == 0) {
+            self.index[block as usize] = IndexEntry::default();
+            return Ok(());
+        }
+
+        let compressed = lz4_flex::compress(data);
+        let (stored, flags) = if compressed.len() < data.len() {
+            (&compressed[..], ENTRY_PRESENT | ENTRY_LZ4)
+        } else {
+            (data, ENTRY_PRESENT)
+        };
+
+        let mut entry = IndexEntry {
+            sector: 0,
+            len: stored.len() as u32,
+            flags,
+        };
+        entry.sector = self.allocate(entry.sectors());
+
+        // Pad out to whole sectors, so the data area always ends on a
+        // sector boundary.
+        let mut sectors = vec![0u8; (entry.sectors() * SECTOR_SIZE) as usize];
+        sectors[..stored.len()].copy_from_slice(stored);
+
+        self.file
+            .seek(SeekFrom::Start(self.sector_offset(entry.sector)))?;
+        self.file.write_all(&sectors)?;
+        self.index[block as usize] = entry;
+
+        Ok(())
+    }
+
+    fn check_alignment(&self, offset: u64, len: usize) -> Result<()> {
+        if offset % self.block_size != 0 || len as u64 % self.block_size != 0 {
+            bail!(
+                "IO at offset {} length {} is not a whole number of blocks",
+                offset,
+                len
+            );
+        }
+        Ok(())
+    }
+}
+
+impl ExtentInner for CompressedInner {
+    fn ext_version(&self) -> u32 {
+        EXTENT_VERSION_COMPRESSED
+    }
+
+    fn read_data(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.check_alignment(offset, buf.len())?;
+
+        let first = offset / self.block_size;
+        for (i, data) in
+            buf.chunks_exact_mut(self.block_size as usize).enumerate()
+        {
+            self.read_block(first + i as u64, data)?;
+        }
+        Ok(())
+    }
+
+    fn write_data(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.check_alignment(offset, data.len())?;
+
+        let first = offset / self.block_size;
+        for (i, data) in data.chunks_exact(self.block_size as usize).enumerate()
+        {
+            self.write_block(first + i as u64, data)?;
+        }
+        Ok(())
+    }
+
+    /*
+     * The data must be on disk before the index that points to it, and
+     * the sectors the old index pointed to can only be reused once the
+     * new index is on disk.
+     */
+    fn sync_data(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        if self.index == self.synced {
+            return Ok(());
+        }
+
+        let buf: Vec<u8> = self.index.iter().flat_map(|e| e.encode()).collect();
+        self.file
+            .seek(SeekFrom::Start(index_offset(self.extent_size)))?;
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+
+        let replaced: Vec<IndexEntry> = self
+            .synced
+            .iter()
+            .zip(self.index.iter())
+            .filter(|(old, new)| old != new)
+            .map(|(old, _)| *old)
+            .collect();
+        for entry in replaced {
+            self.free(&entry);
+        }
+        self.synced = self.index.clone();
+
+        // Give back any free space at the end of the data area.
+        let len = self.used.iter().rposition(|u| *u).map_or(0, |i| i + 1);
+        self.used.truncate(len);
+        let end = self.sector_offset(len as u64);
+        if self.file.metadata()?.len() > end {
+            self.file.set_len(end)?;
+        }
+
+        Ok(())
+    }
+
+    fn gen_number(&self) -> Result<u64> {
+        Ok(self.gen_number)
+    }
+
+    fn flush_number(&self) -> Result<u64> {
+        Ok(self.flush_number)
+    }
+
+    fn dirty(&self) -> Result<bool> {
+        Ok(self.dirty)
+    }
+
+    fn set_dirty(&mut self) -> Result<()> {
+        if !self.dirty {
+            self.dirty = true;
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    fn get_block_contexts(
+        &self,
+        block: u64,
+        count: u64,
+    ) -> Result<Vec<Vec<DownstairsBlockContext>>> {
+        let slots = self.read_slots(block, count)?;
+        Ok(decode_block_contexts(block, &slots))
+    }
+
+    fn set_block_contexts(
+        &mut self,
+        block_contexts: &[&DownstairsBlockContext],
+    ) -> Result<()> {
+        if block_contexts.is_empty() {
+            return Ok(());
+        }
+
+        self.set_dirty()?;
+
+        let first = block_contexts.iter().map(|c| c.block).min().unwrap();
+        let last = block_contexts.iter().map(|c| c.block).max().unwrap();
+        let mut slots = self.read_slots(first, last - first + 1)?;
+
+        // As for a raw extent, a block with no free slot gets one back by
+        // syncing, then freeing every slot that does not match the data.
+        let full = full_blocks(first, &slots, block_contexts);
+        if !full.is_empty() {
+            self.sync_data()?;
+
+            let mut data = vec![0u8; self.block_size as usize];
+            for block in full {
+                self.read_block(block, &mut data)?;
+                let on_disk_hash = integrity_hash(&[&data]);
+                clear_stale_slots(first, &mut slots, block, on_disk_hash);
+            }
+        }
+
+        place_contexts(first, &mut slots, block_contexts)?;
+
+        // The contexts must be durable before any of the data is written.
+        self.write_slots(first, &slots)?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn delete_block_contexts(&mut self, blocks: &[u64]) -> Result<()> {
+        let empty = [0u8; SLOTS_PER_BLOCK * SLOT_SIZE];
+        for block in blocks {
+            self.write_slots(*block, &empty)?;
+        }
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn has_block_contexts(&self) -> Result<bool> {
+        let slots = self.read_slots(0, self.extent_size)?;
+        Ok(any_slot_present(&slots))
+    }
+
+    fn truncate_encryption_contexts_and_hashes(
+        &mut self,
+        extent_block_indexes_and_hashes: Vec<(usize, u64)>,
+    ) -> Result<()> {
+        let mut slots = self.read_slots(0, self.extent_size)?;
+        let mut changed = false;
+
+        for (block, on_disk_hash) in extent_block_indexes_and_hashes {
+            changed |=
+                clear_stale_slots(0, &mut slots, block as u64, on_disk_hash);
+        }
+
+        if changed {
+            self.write_slots(0, &slots)?;
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn set_flush_number(&mut self, new_flush: u64, new_gen: u64) -> Result<()> {
+        self.flush_number = new_flush;
+        self.gen_number = new_gen;
+        self.dirty = false;
+        self.write_header()
+    }
+
+    fn checkpoint(&self) -> Result<()> {
+        // The index on disk already describes the data as of the last
+        // flush, and that is all a copy needs.
+        Ok(())
+    }
+}
@@ -0,0 +1,524 @@
+// Copyright 2022 Oxide Computer Company
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use crucible_common::*;
+use crucible_protocol::{BlockContext, EncryptionContext};
+
+use crate::region::{DownstairsBlockContext, ExtentInner, ExtentMeta};
+
+/*
+ * A raw extent keeps everything in the one extent file:
+ *
+ *   | block data | context slots | padding | header |
+ *
+ * The block data starts at offset zero, so data is read and written at
+ * the same offsets as in an SQLite extent.
+ *
+ * Every block has two context slots.  A write records its context in a
+ * free slot.  If both slots are in use, the extent data is synced and
+ * any slot that does not match what is now on disk is reused.  A flush
+ * clears every slot that does not match the flushed data, leaving each
+ * block with at most one slot in use.
+ *
+ * The header holds the magic and the ExtentMeta.  It lives in the last
+ * sector of the file so a data file can be recognized as a raw extent
+ * without knowing the region definition.
+ */
+const RAW_MAGIC: [u8; 8] = *b"CRUCRAW\0";
+const HEADER_SIZE: u64 = 512;
+pub(crate) const SLOT_AREA_ALIGN: u64 = 4096;
+
+pub(crate) const SLOTS_PER_BLOCK: usize = 2;
+pub(crate) const SLOT_SIZE: usize = 48;
+const SLOT_PRESENT: u8 = 1 << 0;
+const SLOT_ENCRYPTED: u8 = 1 << 1;
+
+/*
+ * Slot layout, all integers little endian:
+ *
+ *   flags        [0]
+ *   hash         [1..9]
+ *   on_disk_hash [9..17]
+ *   nonce        [17..29]
+ *   tag          [29..45]
+ *   key_id       [45..47]
+ *
+ * Slots from before keys had an ID have zeros where the key ID goes.
+ */
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug)]
+pub struct RawInner {
+    file: File,
+    block_size: u64,
+    extent_size: u64,
+    gen_number: u64,
+    flush_number: u64,
+    dirty: bool,
+}
+
+pub(crate) fn slot_area_size(extent_size: u64) -> u64 {
+    let size = extent_size * (SLOTS_PER_BLOCK * SLOT_SIZE) as u64;
+    (size + SLOT_AREA_ALIGN - 1) / SLOT_AREA_ALIGN * SLOT_AREA_ALIGN
+}
+
+/**
+ * The size of a raw extent file for the given region definition.
+ */
+pub fn raw_extent_file_size(def: &RegionDefinition) -> u64 {
+    let extent_size = def.extent_size().value;
+    def.block_size() * extent_size + slot_area_size(extent_size) + HEADER_SIZE
+}
+
+/**
+ * Returns true if the file at this path ends with a raw extent header.
+ */
+pub fn is_raw_extent<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < HEADER_SIZE {
+        return Ok(false);
+    }
+
+    let mut magic = [0u8; RAW_MAGIC.len()];
+    file.seek(SeekFrom::Start(len - HEADER_SIZE))?;
+    file.read_exact(&mut magic)?;
+
+    Ok(magic == RAW_MAGIC)
+}
+
+fn encode_slot(ctx: &DownstairsBlockContext) -> Result<[u8; SLOT_SIZE]> {
+    let mut slot = [0u8; SLOT_SIZE];
+    slot[0] = SLOT_PRESENT;
+    slot[1..9].copy_from_slice(&ctx.block_context.hash.to_le_bytes());
+    slot[9..17].copy_from_slice(&ctx.on_disk_hash.to_le_bytes());
+
+    if let Some(ec) = &ctx.block_context.encryption_context {
+        if ec.nonce.len() != NONCE_LEN || ec.tag.len() != TAG_LEN {
+            bail!(
+                "block {}: nonce length {} tag length {} will not fit in \
+                 a raw extent",
+                ctx.block,
+                ec.nonce.len(),
+                ec.tag.len(),
+            );
+        }
+        slot[0] |= SLOT_ENCRYPTED;
+        slot[17..29].copy_from_slice(&ec.nonce);
+        slot[29..45].copy_from_slice(&ec.tag);
+        slot[45..47].copy_from_slice(&ec.key_id.to_le_bytes());
+    }
+
+    Ok(slot)
+}
+
+fn decode_slot(block: u64, slot: &[u8]) -> Option<DownstairsBlockContext> {
+    if slot[0] & SLOT_PRESENT == 0 {
+        return None;
+    }
+
+    let encryption_context = if slot[0] & SLOT_ENCRYPTED != 0 {
+        Some(EncryptionContext {
+            nonce: slot[17..29].to_vec(),
+            tag: slot[29..45].to_vec(),
+            key_id: u16::from_le_bytes(slot[45..47].try_into().unwrap()),
+        })
+    } else {
+        None
+    };
+
+    Some(DownstairsBlockContext {
+        block_context: BlockContext {
+            hash: u64::from_le_bytes(slot[1..9].try_into().unwrap()),
+            encryption_context,
+        },
+        block,
+        on_disk_hash: u64::from_le_bytes(slot[9..17].try_into().unwrap()),
+    })
+}
+
+/*
+ * Byte offset of a slot within a buffer of slots that starts at block
+ * `first`.
+ */
+fn slot_index(first: u64, block: u64, slot: usize) -> usize {
+    ((block - first) as usize * SLOTS_PER_BLOCK + slot) * SLOT_SIZE
+}
+
+fn slot_present(slots: &[u8], at: usize) -> bool {
+    slots[at] & SLOT_PRESENT != 0
+}
+
+/*
+ * The functions below work on a buffer of context slots read in from an
+ * extent file, starting with the slots for block `first`.  They are shared
+ * with the compressed extent, which keeps its context slots the same way.
+ */
+
+/**
+ * Decode the contexts for every block in a buffer of slots.
+ */
+pub(crate) fn decode_block_contexts(
+    first: u64,
+    slots: &[u8],
+) -> Vec<Vec<DownstairsBlockContext>> {
+    slots
+        .chunks_exact(SLOTS_PER_BLOCK * SLOT_SIZE)
+        .enumerate()
+        .map(|(i, block_slots)| {
+            block_slots
+                .chunks_exact(SLOT_SIZE)
+                .filter_map(|slot| decode_slot(first + i as u64, slot))
+                .collect()
+        })
+        .collect()
+}
+
+/**
+ * Returns true if any slot in the buffer is in use.
+ */
+pub(crate) fn any_slot_present(slots: &[u8]) -> bool {
+    slots.chunks_exact(SLOT_SIZE).any(|s| slot_present(s, 0))
+}
+
+/**
+ * The blocks among these contexts that have no free slot left.
+ */
+pub(crate) fn full_blocks(
+    first: u64,
+    slots: &[u8],
+    block_contexts: &[&DownstairsBlockContext],
+) -> HashSet<u64> {
+    block_contexts
+        .iter()
+        .map(|c| c.block)
+        .filter(|&b| {
+            (0..SLOTS_PER_BLOCK)
+                .all(|i| slot_present(slots, slot_index(first, b, i)))
+        })
+        .collect()
+}
+
+/**
+ * Free every slot for `block` whose context does not match the data on
+ * disk, along with any slot that repeats another.  Returns true if any
+ * slot was freed.
+ */
+pub(crate) fn clear_stale_slots(
+    first: u64,
+    slots: &mut [u8],
+    block: u64,
+    on_disk_hash: u64,
+) -> bool {
+    let mut changed = false;
+    let mut kept: Vec<usize> = Vec::new();
+    for i in 0..SLOTS_PER_BLOCK {
+        let at = slot_index(first, block, i);
+        if let Some(ctx) = decode_slot(block, &slots[at..][..SLOT_SIZE]) {
+            let repeat = kept
+                .iter()
+                .any(|&k| slots[k..][..SLOT_SIZE] == slots[at..][..SLOT_SIZE]);
+            if ctx.on_disk_hash != on_disk_hash || repeat {
+                slots[at..][..SLOT_SIZE].fill(0);
+                changed = true;
+            } else {
+                kept.push(at);
+            }
+        }
+    }
+    changed
+}
+
+/**
+ * Record each context in a slot for its block.  A context that is already
+ * in a slot is left where it is, anything else goes in a free slot.
+ */
+pub(crate) fn place_contexts(
+    first: u64,
+    slots: &mut [u8],
+    block_contexts: &[&DownstairsBlockContext],
+) -> Result<()> {
+    // If a block shows up more than once in this batch, only the last
+    // write's data will land, so later contexts reuse the same slot.
+    let mut placed: HashMap<u64, usize> = HashMap::new();
+    for ctx in block_contexts {
+        let slot = encode_slot(ctx)?;
+        let slot_at = |i| slot_index(first, ctx.block, i);
+
+        let i = match placed.get(&ctx.block) {
+            Some(i) => Some(*i),
+            None => (0..SLOTS_PER_BLOCK)
+                .find(|&i| slots[slot_at(i)..][..SLOT_SIZE] == slot)
+                .or_else(|| {
+                    (0..SLOTS_PER_BLOCK)
+                        .find(|&i| !slot_present(slots, slot_at(i)))
+                }),
+        };
+        let i = match i {
+            Some(i) => i,
+            None => bail!("block {} has no free context slot", ctx.block),
+        };
+
+        slots[slot_at(i)..][..SLOT_SIZE].copy_from_slice(&slot);
+        placed.insert(ctx.block, i);
+    }
+
+    Ok(())
+}
+
+impl RawInner {
+    /**
+     * Size a newly created extent file and write out the default metadata.
+     */
+    pub fn create(file: File, def: &RegionDefinition) -> Result<RawInner> {
+        file.set_len(raw_extent_file_size(def))?;
+
+        let meta = ExtentMeta {
+            ext_version: EXTENT_VERSION_RAW,
+            ..Default::default()
+        };
+        let mut inner = RawInner {
+            file,
+            block_size: def.block_size(),
+            extent_size: def.extent_size().value,
+            gen_number: meta.gen_number,
+            flush_number: meta.flush_number,
+            dirty: meta.dirty,
+        };
+        inner.write_header()?;
+
+        Ok(inner)
+    }
+
+    /**
+     * Verify an existing extent file is a raw extent that matches the
+     * region definition, and read in its metadata.
+     */
+    pub fn open(mut file: File, def: &RegionDefinition) -> Result<RawInner> {
+        let size = raw_extent_file_size(def);
+        let cur_size = file.metadata()?.len();
+        if size != cur_size {
+            bail!(
+                "File size {:?} does not match expected {:?}",
+                size,
+                cur_size
+            );
+        }
+
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(size - HEADER_SIZE))?;
+        file.read_exact(&mut header)?;
+
+        if header[..RAW_MAGIC.len()] != RAW_MAGIC {
+            bail!("Extent file is missing the raw extent header");
+        }
+
+        let meta: ExtentMeta =
+            bincode::deserialize(&header[RAW_MAGIC.len()..])?;
+        if meta.ext_version != EXTENT_VERSION_RAW {
+            bail!("Unsupported raw extent version {}", meta.ext_version);
+        }
+
+        Ok(RawInner {
+            file,
+            block_size: def.block_size(),
+            extent_size: def.extent_size().value,
+            gen_number: meta.gen_number,
+            flush_number: meta.flush_number,
+            dirty: meta.dirty,
+        })
+    }
+
+    fn slot_offset(&self, block: u64) -> u64 {
+        self.block_size * self.extent_size
+            + block * (SLOTS_PER_BLOCK * SLOT_SIZE) as u64
+    }
+
+    /*
+     * Write the in memory metadata out to the header, and make sure it is
+     * on disk before returning.
+     */
+    fn write_header(&mut self) -> Result<()> {
+        let meta = ExtentMeta {
+            ext_version: EXTENT_VERSION_RAW,
+            gen_number: self.gen_number,
+            flush_number: self.flush_number,
+            dirty: self.dirty,
+        };
+
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        header[..RAW_MAGIC.len()].copy_from_slice(&RAW_MAGIC);
+        let encoded = bincode::serialize(&meta)?;
+        header[RAW_MAGIC.len()..][..encoded.len()].copy_from_slice(&encoded);
+
+        let offset = self.slot_offset(0) + slot_area_size(self.extent_size);
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&header)?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn read_slots(&self, block: u64, count: u64) -> Result<Vec<u8>> {
+        let mut slots = vec![0u8; count as usize * SLOTS_PER_BLOCK * SLOT_SIZE];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.slot_offset(block)))?;
+        file.read_exact(&mut slots)?;
+        Ok(slots)
+    }
+
+    fn write_slots(&mut self, block: u64, slots: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(self.slot_offset(block)))?;
+        self.file.write_all(slots)?;
+        Ok(())
+    }
+
+    fn read_block(&mut self, block: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; self.block_size as usize];
+        self.file.seek(SeekFrom::Start(block * self.block_size))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl ExtentInner for RawInner {
+    fn ext_version(&self) -> u32 {
+        EXTENT_VERSION_RAW
+    }
+
+    fn read_data(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn write_data(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    fn gen_number(&self) -> Result<u64> {
+        Ok(self.gen_number)
+    }
+
+    fn flush_number(&self) -> Result<u64> {
+        Ok(self.flush_number)
+    }
+
+    fn dirty(&self) -> Result<bool> {
+        Ok(self.dirty)
+    }
+
+    fn set_dirty(&mut self) -> Result<()> {
+        if !self.dirty {
+            self.dirty = true;
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    fn get_block_contexts(
+        &self,
+        block: u64,
+        count: u64,
+    ) -> Result<Vec<Vec<DownstairsBlockContext>>> {
+        let slots = self.read_slots(block, count)?;
+        Ok(decode_block_contexts(block, &slots))
+    }
+
+    fn set_block_contexts(
+        &mut self,
+        block_contexts: &[&DownstairsBlockContext],
+    ) -> Result<()> {
+        if block_contexts.is_empty() {
+            return Ok(());
+        }
+
+        self.set_dirty()?;
+
+        let first = block_contexts.iter().map(|c| c.block).min().unwrap();
+        let last = block_contexts.iter().map(|c| c.block).max().unwrap();
+        let mut slots = self.read_slots(first, last - first + 1)?;
+
+        // Blocks written more than once since the last flush may have no
+        // free slot.  Sync what has been written so far, then free every
+        // slot that does not match the data now on disk: those contexts
+        // belong to writes that have since been overwritten.
+        let full = full_blocks(first, &slots, block_contexts);
+        if !full.is_empty() {
+            self.file.sync_all()?;
+
+            for block in full {
+                let on_disk_hash = integrity_hash(&[&self.read_block(block)?]);
+                clear_stale_slots(first, &mut slots, block, on_disk_hash);
+            }
+        }
+
+        place_contexts(first, &mut slots, block_contexts)?;
+
+        // The contexts must be durable before any of the data is written.
+        self.write_slots(first, &slots)?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn delete_block_contexts(&mut self, blocks: &[u64]) -> Result<()> {
+        let empty = [0u8; SLOTS_PER_BLOCK * SLOT_SIZE];
+        for block in blocks {
+            self.write_slots(*block, &empty)?;
+        }
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn has_block_contexts(&self) -> Result<bool> {
+        let slots = self.read_slots(0, self.extent_size)?;
+        Ok(any_slot_present(&slots))
+    }
+
+    fn truncate_encryption_contexts_and_hashes(
+        &mut self,
+        extent_block_indexes_and_hashes: Vec<(usize, u64)>,
+    ) -> Result<()> {
+        let mut slots = self.read_slots(0, self.extent_size)?;
+        let mut changed = false;
+
+        for (block, on_disk_hash) in extent_block_indexes_and_hashes {
+            changed |=
+                clear_stale_slots(0, &mut slots, block as u64, on_disk_hash);
+        }
+
+        if changed {
+            self.write_slots(0, &slots)?;
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn set_flush_number(&mut self, new_flush: u64, new_gen: u64) -> Result<()> {
+        self.flush_number = new_flush;
+        self.gen_number = new_gen;
+        self.dirty = false;
+        self.write_header()
+    }
+
+    fn checkpoint(&self) -> Result<()> {
+        // Everything is already in the extent file.
+        Ok(())
+    }
+}
@@ -36,8 +36,11 @@ use uuid::Uuid;
 
 pub mod admin;
 mod dump;
+mod extent_inner_compressed;
+mod extent_inner_raw;
 pub mod region;
 pub mod repair;
+pub mod scrub;
 mod stats;
 
 use region::Region;
@@ -322,6 +325,20 @@ pub async fn show_work(ds: &mut Downstairs) {
                         dsw_type = "WriteU".to_string();
                         dep_list = dependencies.to_vec();
                     }
+                    IOop::Discard {
+                        dependencies,
+                        requests: _,
+                    } => {
+                        dsw_type = "Discard".to_string();
+                        dep_list = dependencies.to_vec();
+                    }
+                    IOop::Resize {
+                        dependencies,
+                        extent_count: _,
+                    } => {
+                        dsw_type = "Resize".to_string();
+                        dep_list = dependencies.to_vec();
+                    }
                 };
                 println!(
                     "DSW:[{:04}] {:>05} {:>05} deps:{:?}",
@@ -344,18 +361,24 @@ pub mod cdt {
     fn submit__writeunwritten__start(_: u64) {}
     fn submit__write__start(_: u64) {}
     fn submit__flush__start(_: u64) {}
+    fn submit__discard__start(_: u64) {}
+    fn submit__resize__start(_: u64) {}
     fn os__read__start(_: u64) {}
     fn os__writeunwritten__start(_: u64) {}
     fn os__write__start(_: u64) {}
     fn os__flush__start(_: u64) {}
+    fn os__discard__start(_: u64) {}
     fn os__read__done(_: u64) {}
     fn os__writeunwritten__done(_: u64) {}
     fn os__write__done(_: u64) {}
     fn os__flush__done(_: u64) {}
+    fn os__discard__done(_: u64) {}
     fn submit__read__done(_: u64) {}
     fn submit__writeunwritten__done(_: u64) {}
     fn submit__write__done(_: u64) {}
     fn submit__flush__done(_: u64) {}
+    fn submit__discard__done(_: u64) {}
+    fn submit__resize__done(_: u64) {}
 }
 /*
  * A new IO request has been received.
@@ -513,6 +536,75 @@ where
             d.add_work(upstairs_connection, *job_id, new_read).await?;
             Some(*job_id)
         }
+        Message::Discard {
+            upstairs_id,
+            session_id,
+            job_id,
+            dependencies,
+            requests,
+        } => {
+            if upstairs_connection.upstairs_id != *upstairs_id {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch {
+                    expected_id: upstairs_connection.upstairs_id,
+                })
+                .await?;
+                return Ok(());
+            }
+            if upstairs_connection.session_id != *session_id {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch {
+                    expected_id: upstairs_connection.session_id,
+                })
+                .await?;
+                return Ok(());
+            }
+            cdt::submit__discard__start!(|| *job_id);
+
+            let new_discard = IOop::Discard {
+                dependencies: dependencies.to_vec(),
+                requests: requests.to_vec(),
+            };
+
+            let mut d = ad.lock().await;
+            d.add_work(upstairs_connection, *job_id, new_discard)
+                .await?;
+            Some(*job_id)
+        }
+        Message::RegionResize {
+            upstairs_id,
+            session_id,
+            job_id,
+            dependencies,
+            extent_count,
+        } => {
+            if upstairs_connection.upstairs_id != *upstairs_id {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch {
+                    expected_id: upstairs_connection.upstairs_id,
+                })
+                .await?;
+                return Ok(());
+            }
+            if upstairs_connection.session_id != *session_id {
+                let mut fw = fw.lock().await;
+                fw.send(Message::UuidMismatch {
+                    expected_id: upstairs_connection.session_id,
+                })
+                .await?;
+                return Ok(());
+            }
+            cdt::submit__resize__start!(|| *job_id);
+
+            let new_resize = IOop::Resize {
+                dependencies: dependencies.to_vec(),
+                extent_count: *extent_count,
+            };
+
+            let mut d = ad.lock().await;
+            d.add_work(upstairs_connection, *job_id, new_resize).await?;
+            Some(*job_id)
+        }
         Message::ExtentFlush {
             repair_id,
             extent_id,
@@ -612,6 +704,43 @@ where
             fw.send(msg).await?;
             return Ok(());
         }
+        Message::ExtentRepairBlocks {
+            repair_id,
+            extent_id,
+            source_client_id,
+            source_repair_address,
+            dest_clients,
+        } => {
+            let msg = {
+                let mut d = ad.lock().await;
+                info!(
+                    d.log,
+                    "{} Repair blocks of extent {} source:[{}] {:?} dest:{:?}",
+                    repair_id,
+                    extent_id,
+                    source_client_id,
+                    source_repair_address,
+                    dest_clients
+                );
+                match d
+                    .region
+                    .repair_extent_blocks(*extent_id, *source_repair_address)
+                    .await
+                {
+                    Ok(()) => Message::RepairAckId {
+                        repair_id: *repair_id,
+                    },
+                    Err(e) => Message::ExtentError {
+                        repair_id: *repair_id,
+                        extent_id: *extent_id,
+                        error: e,
+                    },
+                }
+            };
+            let mut fw = fw.lock().await;
+            fw.send(msg).await?;
+            return Ok(());
+        }
         Message::ExtentReopen {
             repair_id,
             extent_id,
@@ -724,8 +853,17 @@ where
                     // Notify the upstairs before completing work
                     let mut fw = fw.lock().await;
                     fw.send(&m).await?;
+                    let saved = fw.encoder_mut().take_bytes_saved();
                     drop(fw);
 
+                    if saved > 0 {
+                        ads.lock()
+                            .await
+                            .dss
+                            .add_compression_saved(saved as i64)
+                            .await;
+                    }
+
                     ads.lock()
                         .await
                         .complete_work(upstairs_connection, job_id, m)
@@ -799,6 +937,7 @@ where
     let repair_addr = ads.lock().await.repair_address.unwrap();
 
     let mut negotiated = 0;
+    let mut protocol: Option<Negotiated> = None;
     let mut upstairs_connection: Option<UpstairsConnection> = None;
 
     let (_another_upstairs_active_tx, mut another_upstairs_active_rx) =
@@ -913,8 +1052,23 @@ where
                         let mut fw = fw.lock().await;
                         fw.send(Message::Imok).await?;
                     }
+                    Some(Message::HereIAmV1 { version, .. }) => {
+                        let mut fw = fw.lock().await;
+
+                        fw.send(Message::VersionMismatch {
+                            min_version: PROTOCOL_VERSION_MIN,
+                            max_version: PROTOCOL_VERSION_MAX,
+                        }).await?;
+
+                        bail!("closing connection, upstairs speaks \
+                            version {}, we speak {} to {}",
+                            version,
+                            PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX);
+                    }
                     Some(Message::HereIAm {
-                        version,
+                        min_version,
+                        max_version,
+                        features,
                         upstairs_id,
                         session_id,
                         gen,
@@ -926,8 +1080,21 @@ where
                                 negotiated);
                         }
 
-                        if version != 1 {
-                            bail!("expected version 1, got {}", version);
+                        protocol = Negotiated::with_peer(
+                            min_version, max_version, features,
+                        );
+                        if protocol.is_none() {
+                            let mut fw = fw.lock().await;
+
+                            fw.send(Message::VersionMismatch {
+                                min_version: PROTOCOL_VERSION_MIN,
+                                max_version: PROTOCOL_VERSION_MAX,
+                            }).await?;
+
+                            bail!("closing connection, upstairs speaks \
+                                versions {} to {}, we speak {} to {}",
+                                min_version, max_version,
+                                PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX);
                         }
 
                         // Reject an Upstairs negotiation if there is a mismatch
@@ -964,14 +1131,21 @@ where
                             session_id,
                             gen,
                         });
+                        let protocol = protocol.unwrap();
                         info!(
-                            log, "upstairs {:?} connected",
-                            upstairs_connection.unwrap());
+                            log, "upstairs {:?} connected, {:?}",
+                            upstairs_connection.unwrap(), protocol);
 
                         let mut fw = fw.lock().await;
-                        fw.send(
-                            Message::YesItsMe { version: 1, repair_addr }
-                        ).await?;
+                        fw.send(Message::YesItsMe {
+                            version: protocol.version,
+                            features: protocol.features,
+                            repair_addr,
+                        }).await?;
+                        fw.encoder_mut()
+                            .set_compression(protocol.compression());
+                        fw.encoder_mut().set_version(protocol.version);
+                        fr.decoder_mut().set_version(protocol.version);
                     }
                     Some(Message::PromoteToActive {
                         upstairs_id,
@@ -1123,8 +1297,15 @@ where
     assert!(upstairs_connection.is_some());
     let upstairs_connection = upstairs_connection.unwrap();
 
-    resp_loop(ads, fr, fw, another_upstairs_active_rx, upstairs_connection)
-        .await
+    resp_loop(
+        ads,
+        fr,
+        fw,
+        another_upstairs_active_rx,
+        upstairs_connection,
+        protocol.unwrap(),
+    )
+    .await
 }
 
 /*
@@ -1138,6 +1319,7 @@ async fn resp_loop<RT, WT>(
     fw: Arc<Mutex<FramedWrite<WT, CrucibleEncoder>>>,
     mut another_upstairs_active_rx: mpsc::Receiver<UpstairsConnection>,
     upstairs_connection: UpstairsConnection,
+    protocol: Negotiated,
 ) -> Result<()>
 where
     RT: tokio::io::AsyncRead + std::marker::Unpin + std::marker::Send,
@@ -1304,6 +1486,20 @@ where
                         return Ok(());
                     }
                     Some(Ok(msg)) => {
+                        let saved = fr.decoder_mut().take_bytes_saved();
+                        if saved > 0 {
+                            ads.lock()
+                                .await
+                                .dss
+                                .add_compression_saved(saved as i64)
+                                .await;
+                        }
+
+                        if !protocol.permits(&msg) {
+                            bail!("upstairs sent {:?}, not part of {:?}",
+                                msg, protocol);
+                        }
+
                         if matches!(msg, Message::Ruok) {
                             // Respond instantly to pings, don't wait.
                             let mut fw = fw.lock().await;
@@ -1346,6 +1542,7 @@ pub struct Downstairs {
     encrypted: bool,
     pub address: Option<SocketAddr>,
     pub repair_address: Option<SocketAddr>,
+    scrub: scrub::ScrubReport,
     log: Logger,
 }
 
@@ -1363,6 +1560,10 @@ impl Downstairs {
                 region.def().uuid(),
             ))),
         };
+        let scrub = scrub::ScrubReport::load(&region.dir).unwrap_or_else(|e| {
+            warn!(log, "Starting a new scrub report: {:?}", e);
+            Default::default()
+        });
         Downstairs {
             region,
             lossy,
@@ -1373,6 +1574,7 @@ impl Downstairs {
             encrypted,
             address: None,
             repair_address: None,
+            scrub,
             log,
         }
     }
@@ -1470,7 +1672,10 @@ impl Downstairs {
         // we have to accept them. But read-only should never accept writes!
         if self.read_only {
             let is_write = match work {
-                IOop::Write { .. } | IOop::WriteUnwritten { .. } => true,
+                IOop::Write { .. }
+                | IOop::WriteUnwritten { .. }
+                | IOop::Discard { .. }
+                | IOop::Resize { .. } => true,
                 IOop::Read { .. } | IOop::Flush { .. } => false,
             };
 
@@ -1655,6 +1860,45 @@ impl Downstairs {
                     result,
                 }))
             }
+            IOop::Discard {
+                dependencies: _dependencies,
+                requests,
+            } => {
+                let result = if self.return_errors && random() && random() {
+                    warn!(self.log, "returning error on discard!");
+                    Err(CrucibleError::GenericError("test error".to_string()))
+                } else if !self.is_active(job.upstairs_connection) {
+                    error!(self.log, "Upstairs inactive error");
+                    Err(CrucibleError::UpstairsInactive)
+                } else {
+                    self.region.region_discard(requests, job_id)
+                };
+
+                Ok(Some(Message::DiscardAck {
+                    upstairs_id: job.upstairs_connection.upstairs_id,
+                    session_id: job.upstairs_connection.session_id,
+                    job_id: job.ds_id,
+                    result,
+                }))
+            }
+            IOop::Resize {
+                dependencies: _dependencies,
+                extent_count,
+            } => {
+                let result = if !self.is_active(job.upstairs_connection) {
+                    error!(self.log, "Upstairs inactive error");
+                    Err(CrucibleError::UpstairsInactive)
+                } else {
+                    self.region.resize(*extent_count)
+                };
+
+                Ok(Some(Message::RegionResizeAck {
+                    upstairs_id: job.upstairs_connection.upstairs_id,
+                    session_id: job.upstairs_connection.session_id,
+                    job_id: job.ds_id,
+                    result,
+                }))
+            }
         }
     }
 
@@ -1725,6 +1969,12 @@ impl Downstairs {
                 cdt::submit__read__done!(|| ds_id);
                 self.dss.add_read().await;
             }
+            Message::DiscardAck { .. } => {
+                cdt::submit__discard__done!(|| ds_id);
+            }
+            Message::RegionResizeAck { .. } => {
+                cdt::submit__resize__done!(|| ds_id);
+            }
             _ => (),
         }
 
@@ -2186,6 +2436,14 @@ impl Work {
                                     dependencies: _,
                                     requests: _,
                                 } => "Read",
+                                IOop::Discard {
+                                    dependencies: _,
+                                    requests: _,
+                                } => "Discard",
+                                IOop::Resize {
+                                    dependencies: _,
+                                    extent_count: _,
+                                } => "Resize",
                             },
                             job.upstairs_connection,
                             deps_outstanding.len(),
@@ -2315,6 +2573,7 @@ pub fn create_region(
     extent_count: u64,
     uuid: Uuid,
     encrypted: bool,
+    extent_version: u32,
     log: Logger,
 ) -> Result<Region> {
     /*
@@ -2326,6 +2585,7 @@ pub fn create_region(
         .set_extent_size(Block::new(extent_size, block_size.trailing_zeros()));
     region_options.set_uuid(uuid);
     region_options.set_encrypted(encrypted);
+    region_options.set_extent_version(extent_version);
 
     let mut region = Region::create(&data, region_options, log)?;
     region.extend(extent_count as u32)?;
@@ -3403,6 +3663,68 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn import_test_compressed() -> Result<()> {
+        /*
+         * import + export through compressed extents, with data that
+         * mixes zeros, repeated bytes and random bytes
+         */
+        let block_size: u64 = 512;
+        let extent_size = 10;
+
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(block_size);
+        region_options.set_extent_size(Block::new(
+            extent_size,
+            block_size.trailing_zeros(),
+        ));
+        region_options.set_uuid(Uuid::new_v4());
+        region_options.set_extent_version(EXTENT_VERSION_COMPRESSED);
+
+        let dir = tempdir()?;
+        mkdir_for_file(dir.path())?;
+
+        let mut region = Region::create(&dir, region_options, csl())?;
+        region.extend(10)?;
+
+        let total_bytes = region.def().total_size();
+        let mut data = vec![0; total_bytes as usize];
+        let mut rng = ChaCha20Rng::from_entropy();
+        for (i, chunk) in data.chunks_mut(block_size as usize).enumerate() {
+            match i % 3 {
+                0 => (),
+                1 => chunk.fill(i as u8),
+                _ => rng.fill_bytes(chunk),
+            }
+        }
+
+        let tempdir = tempdir()?;
+        mkdir_for_file(tempdir.path())?;
+
+        let data_file_path = tempdir.path().join("mixed_data");
+        let mut data_file = File::create(&data_file_path)?;
+        data_file.write_all(&data[..])?;
+
+        downstairs_import(&mut region, &data_file_path)?;
+        region.region_flush(1, 1, &None, 0)?;
+
+        let export_path = tempdir.path().join("exported_data");
+        downstairs_export(
+            &mut region,
+            &export_path,
+            0,
+            total_bytes / block_size,
+        )?;
+
+        let expected = std::fs::read(data_file_path)?;
+        let actual = std::fs::read(export_path)?;
+
+        assert_eq!(expected, actual);
+
+        Ok(())
+    }
+
     #[test]
     fn import_test_too_small() -> Result<()> {
         /*
@@ -68,6 +68,14 @@ enum Args {
             action(clap::ArgAction::Set)
         )]
         encrypted: bool,
+
+        /*
+         * On-disk extent format: 1 keeps extent metadata in SQLite, 2
+         * keeps it in the extent file itself, 3 does the same and also
+         * stores each block compressed.
+         */
+        #[clap(long, default_value = "1", action)]
+        extent_version: u32,
     },
     /*
      * Dump region information.
@@ -169,6 +177,11 @@ enum Args {
 
         #[clap(long, default_value = "rw", action)]
         mode: Mode,
+
+        /// Check the data at rest in the background, at this many blocks
+        /// a second.
+        #[clap(long, name = "BLOCKS_PER_SECOND", action)]
+        scrub_rate: Option<u64>,
     },
     RepairAPI,
     Serve {
@@ -213,6 +226,7 @@ async fn main() -> Result<()> {
             import_path,
             uuid,
             encrypted,
+            extent_version,
         } => {
             let mut region = create_region(
                 block_size,
@@ -221,6 +235,7 @@ async fn main() -> Result<()> {
                 extent_count,
                 uuid,
                 encrypted,
+                extent_version,
                 log.clone(),
             )?;
 
@@ -292,6 +307,7 @@ async fn main() -> Result<()> {
             key_pem,
             root_cert_pem,
             mode,
+            scrub_rate,
         } => {
             /*
              * If any of our async tasks in our runtime panic, then we should
@@ -329,6 +345,10 @@ async fn main() -> Result<()> {
                 Some(log),
             )?;
 
+            if let Some(scrub_rate) = scrub_rate {
+                tokio::spawn(scrub::scrub_task(d.clone(), scrub_rate));
+            }
+
             let downstairs_join_handle = start_downstairs(
                 d,
                 address,
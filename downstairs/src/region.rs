@@ -6,7 +6,7 @@ use std::fs::{rename, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use anyhow::{bail, Result};
 use crucible_common::*;
@@ -20,6 +20,8 @@ use tokio::macros::support::Pin;
 use tracing::instrument;
 
 use super::*;
+use crate::extent_inner_compressed::{is_compressed_extent, CompressedInner};
+use crate::extent_inner_raw::RawInner;
 
 #[derive(Debug)]
 pub struct Extent {
@@ -28,14 +30,13 @@ pub struct Extent {
     block_size: u64,
     extent_size: Block,
     /// Inner contains information about the actual extent file that holds
-    /// the data, and the metadata (stored in the database) about that
-    /// extent.
+    /// the data, and the metadata (stored in the database, or in the
+    /// extent file itself) about that extent.
     ///
-    /// If Some(), it means the extent file and database metadata for
-    /// it are opened.
+    /// If Some(), it means the extent file and metadata for it are opened.
     /// If None, it means the extent is currently
     /// closed (and possibly being updated out of band).
-    inner: Option<Mutex<Inner>>,
+    inner: Option<Arc<Mutex<Box<dyn ExtentInner>>>>,
 }
 
 /// BlockContext, with the addition of block index and on_disk_hash
@@ -47,14 +48,227 @@ pub struct DownstairsBlockContext {
     pub on_disk_hash: u64,
 }
 
+/// An open extent, as the scrubber sees it.
+pub struct ExtentScrubber {
+    inner: Arc<Mutex<Box<dyn ExtentInner>>>,
+    block_size: u64,
+}
+
+impl ExtentScrubber {
+    /// Check `count` blocks starting at `block` against the on-disk hashes
+    /// in their block contexts, and return the blocks whose data matches
+    /// none of them.  Unwritten blocks have nothing to check against.
+    pub fn scrub(&self, block: u64, count: u64) -> Result<Vec<u64>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let mut data = vec![0u8; (count * self.block_size) as usize];
+        inner.read_data(block * self.block_size, &mut data)?;
+        let block_contexts = inner.get_block_contexts(block, count)?;
+
+        Ok(block_contexts
+            .iter()
+            .zip(data.chunks_exact(self.block_size as usize))
+            .enumerate()
+            .filter(|(_, (ctxs, data))| {
+                let on_disk_hash = integrity_hash(&[data]);
+                !ctxs.is_empty()
+                    && !ctxs.iter().any(|ctx| ctx.on_disk_hash == on_disk_hash)
+            })
+            .map(|(i, _)| block + i as u64)
+            .collect())
+    }
+
+    /// Is this still the open extent, or has it been closed (and maybe
+    /// reopened) since?
+    pub fn is_open_in(&self, extent: &Extent) -> bool {
+        match &extent.inner {
+            Some(inner) => Arc::ptr_eq(inner, &self.inner),
+            None => false,
+        }
+    }
+}
+
+/// How many blocks to read at a time when hashing a whole extent.
+const BLOCK_HASH_CHUNK: u64 = 128;
+
+/// The most blocks a block level repair fetches from the source at once.
+const REPAIR_BLOCKS_MAX: u64 = 256;
+
+/**
+ * A hash of a block's data along with its block contexts.  Two copies of a
+ * block with the same hash hold the same thing, whatever order their
+ * contexts come back in.
+ */
+fn block_hash(data: &[u8], block_contexts: &[DownstairsBlockContext]) -> u64 {
+    let mut contexts: Vec<Vec<u8>> = block_contexts
+        .iter()
+        .map(|ctx| {
+            let mut bytes = Vec::new();
+            bytes.extend_from_slice(&ctx.block_context.hash.to_le_bytes());
+            bytes.extend_from_slice(&ctx.on_disk_hash.to_le_bytes());
+            if let Some(ec) = &ctx.block_context.encryption_context {
+                bytes.extend_from_slice(&ec.nonce);
+                bytes.extend_from_slice(&ec.tag);
+                bytes.extend_from_slice(&ec.key_id.to_le_bytes());
+            }
+            bytes
+        })
+        .collect();
+    contexts.sort();
+
+    let mut parts = vec![data];
+    parts.extend(contexts.iter().map(|c| &c[..]));
+    integrity_hash(&parts)
+}
+
+/**
+ * Group a sorted list of blocks into runs of neighbouring blocks, each no
+ * longer than max, as (first block, count).
+ */
+fn block_runs(blocks: &[u64], max: u64) -> Vec<(u64, u64)> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for block in blocks {
+        match runs.last_mut() {
+            Some((first, count))
+                if *first + *count == *block && *count < max =>
+            {
+                *count += 1;
+            }
+            _ => runs.push((*block, 1)),
+        }
+    }
+    runs
+}
+
+/**
+ * Turn a block context from the repair server back into our own.
+ */
+fn repair_block_context(
+    ctx: repair_client::types::RepairBlockContext,
+) -> Result<DownstairsBlockContext, CrucibleError> {
+    let encryption_context = match (ctx.nonce, ctx.tag, ctx.key_id) {
+        (Some(nonce), Some(tag), Some(key_id)) => {
+            Some(EncryptionContext { nonce, tag, key_id })
+        }
+        (None, None, None) => None,
+        _ => {
+            crucible_bail!(
+                RepairRequestError,
+                "block {} has a partial encryption context",
+                ctx.block,
+            );
+        }
+    };
+
+    Ok(DownstairsBlockContext {
+        block_context: BlockContext {
+            hash: ctx.hash,
+            encryption_context,
+        },
+        block: ctx.block,
+        on_disk_hash: ctx.on_disk_hash,
+    })
+}
+
+/// The on-disk side of an open extent: the file holding the extent data,
+/// plus wherever the flush and generation numbers, the dirty bit and the
+/// block contexts are kept.  Which implementation an extent uses is
+/// recorded in its `ExtentMeta::ext_version`.
+pub trait ExtentInner: Send + fmt::Debug {
+    fn ext_version(&self) -> u32;
+
+    /// Read block data into `buf`, starting at byte `offset` of the
+    /// extent.  Both must be a whole number of blocks.
+    fn read_data(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Write block data starting at byte `offset` of the extent.  The data
+    /// is not durable until `sync_data` returns.
+    fn write_data(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+
+    /// Make all block data written so far durable.
+    fn sync_data(&mut self) -> Result<()>;
+
+    fn gen_number(&self) -> Result<u64>;
+    fn flush_number(&self) -> Result<u64>;
+    fn dirty(&self) -> Result<bool>;
+    fn set_dirty(&mut self) -> Result<()>;
+
+    /// For a given block range, return all contexts since the last flush.
+    /// `get_block_contexts` returns a `Vec<Vec<DownstairsBlockContext>>` of
+    /// length equal to `count`. Each `Vec<DownstairsBlockContext>` inside
+    /// this parent Vec contains all contexts for a single block.
+    fn get_block_contexts(
+        &self,
+        block: u64,
+        count: u64,
+    ) -> Result<Vec<Vec<DownstairsBlockContext>>>;
+
+    /// Set the dirty bit and record these block contexts.  Both must be
+    /// durable before this returns, as the caller writes the extent data
+    /// next.
+    fn set_block_contexts(
+        &mut self,
+        block_contexts: &[&DownstairsBlockContext],
+    ) -> Result<()>;
+
+    /// Remove every block context for these blocks, returning them to the
+    /// unwritten state.
+    fn delete_block_contexts(&mut self, blocks: &[u64]) -> Result<()>;
+
+    /// Returns true if any block in this extent has a block context,
+    /// meaning it has been written (and not since discarded).
+    fn has_block_contexts(&self) -> Result<bool>;
+
+    /// Get rid of all block contexts except those that match the on-disk
+    /// hash that is computed after a flush.
+    fn truncate_encryption_contexts_and_hashes(
+        &mut self,
+        extent_block_indexes_and_hashes: Vec<(usize, u64)>,
+    ) -> Result<()>;
+
+    /// The flush and generation numbers will be updated at the same time,
+    /// and the dirty bit cleared.
+    fn set_flush_number(&mut self, new_flush: u64, new_gen: u64) -> Result<()>;
+
+    /// Make sure the extent files alone hold all committed metadata, so
+    /// they can be copied.
+    fn checkpoint(&self) -> Result<()>;
+}
+
 #[derive(Debug)]
-pub struct Inner {
+pub struct SqliteInner {
     file: File,
     metadb: Connection,
+    /*
+     * False for a read only metadata db from before block contexts had
+     * a key ID, see add_key_id_column.
+     */
+    has_key_id: bool,
 }
 
-impl Inner {
-    pub fn gen_number(&self) -> Result<u64> {
+impl ExtentInner for SqliteInner {
+    fn ext_version(&self) -> u32 {
+        EXTENT_VERSION_SQLITE
+    }
+
+    fn read_data(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn write_data(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    fn gen_number(&self) -> Result<u64> {
         let mut stmt = self.metadb.prepare_cached(
             "SELECT value FROM metadata where name='gen_number'",
         )?;
@@ -70,7 +284,7 @@ impl Inner {
         Ok(gen_number_values[0])
     }
 
-    pub fn flush_number(&self) -> Result<u64> {
+    fn flush_number(&self) -> Result<u64> {
         let mut stmt = self.metadb.prepare_cached(
             "SELECT value FROM metadata where name='flush_number'",
         )?;
@@ -86,10 +300,7 @@ impl Inner {
         Ok(flush_number_values[0])
     }
 
-    /*
-     * The flush and generation numbers will be updated at the same time.
-     */
-    fn set_flush_number(&self, new_flush: u64, new_gen: u64) -> Result<()> {
+    fn set_flush_number(&mut self, new_flush: u64, new_gen: u64) -> Result<()> {
         let mut stmt = self.metadb.prepare_cached(
             "UPDATE metadata SET value=?1 WHERE name='flush_number'",
         )?;
@@ -114,7 +325,7 @@ impl Inner {
         Ok(())
     }
 
-    pub fn dirty(&self) -> Result<bool> {
+    fn dirty(&self) -> Result<bool> {
         let mut stmt = self
             .metadb
             .prepare_cached("SELECT value FROM metadata where name='dirty'")?;
@@ -130,7 +341,7 @@ impl Inner {
         Ok(dirty_values[0])
     }
 
-    fn set_dirty(&self) -> Result<()> {
+    fn set_dirty(&mut self) -> Result<()> {
         let _ = self
             .metadb
             .prepare_cached("UPDATE metadata SET value=1 WHERE name='dirty'")?
@@ -138,20 +349,23 @@ impl Inner {
         Ok(())
     }
 
-    /// For a given block range, return all context rows since the last flush.
-    /// `get_block_contexts` returns a `Vec<Vec<DownstairsBlockContext>>` of
-    /// length equal to `count`. Each `Vec<DownstairsBlockContext>` inside this
-    /// parent Vec contains all contexts for a single block.
     fn get_block_contexts(
         &self,
         block: u64,
         count: u64,
     ) -> Result<Vec<Vec<DownstairsBlockContext>>> {
         // NOTE: "ORDER BY RANDOM()" would be a good --lossy addition here
-        let stmt =
-            "SELECT block, hash, nonce, tag, on_disk_hash FROM block_context \
+        let stmt = if self.has_key_id {
+            "SELECT block, hash, nonce, tag, on_disk_hash, key_id \
+             FROM block_context \
+             WHERE block BETWEEN ?1 AND ?2 \
+             ORDER BY ROWID ASC"
+        } else {
+            "SELECT block, hash, nonce, tag, on_disk_hash, 0 \
+             FROM block_context \
              WHERE block BETWEEN ?1 AND ?2 \
-             ORDER BY ROWID ASC";
+             ORDER BY ROWID ASC"
+        };
         let mut stmt = self.metadb.prepare_cached(stmt)?;
 
         let stmt_iter =
@@ -161,8 +375,9 @@ impl Inner {
                 let nonce: Option<Vec<u8>> = row.get(2)?;
                 let tag: Option<Vec<u8>> = row.get(3)?;
                 let on_disk_hash: Vec<u8> = row.get(4)?;
+                let key_id: u16 = row.get(5)?;
 
-                Ok((block_index, hash, nonce, tag, on_disk_hash))
+                Ok((block_index, hash, nonce, tag, on_disk_hash, key_id))
             })?;
 
         let mut results = Vec::with_capacity(count as usize);
@@ -171,10 +386,10 @@ impl Inner {
         }
 
         for row in stmt_iter {
-            let (block_index, hash, nonce, tag, on_disk_hash) = row?;
+            let (block_index, hash, nonce, tag, on_disk_hash, key_id) = row?;
 
             let encryption_context = if let Some(nonce) = nonce {
-                tag.map(|tag| EncryptionContext { nonce, tag })
+                tag.map(|tag| EncryptionContext { nonce, tag, key_id })
             } else {
                 None
             };
@@ -194,56 +409,30 @@ impl Inner {
         Ok(results)
     }
 
-    /*
-     * Append a block context row.
-     */
-    pub fn tx_set_block_context(
-        tx: &rusqlite::Transaction,
-        block_context: &DownstairsBlockContext,
+    fn set_block_contexts(
+        &mut self,
+        block_contexts: &[&DownstairsBlockContext],
     ) -> Result<()> {
-        let stmt =
-            "INSERT INTO block_context (block, hash, nonce, tag, on_disk_hash) \
-             VALUES (?1, ?2, ?3, ?4, ?5)";
+        let tx = self.metadb.transaction()?;
 
-        let (nonce, tag) = if let Some(encryption_context) =
-            &block_context.block_context.encryption_context
-        {
-            (
-                Some(&encryption_context.nonce),
-                Some(&encryption_context.tag),
-            )
-        } else {
-            (None, None)
-        };
+        let _ = tx
+            .prepare_cached("UPDATE metadata SET value=1 WHERE name='dirty'")?
+            .execute([])?;
 
-        let rows_affected = tx.prepare_cached(stmt)?.execute(params![
-            block_context.block,
-            block_context.block_context.hash.to_le_bytes(),
-            nonce,
-            tag,
-            block_context.on_disk_hash.to_le_bytes(),
-        ])?;
+        for block_context in block_contexts {
+            Self::tx_set_block_context(&tx, block_context)?;
+        }
 
-        assert_eq!(rows_affected, 1);
+        tx.commit()?;
 
         Ok(())
     }
 
-    pub fn metadb_transaction(&mut self) -> Result<rusqlite::Transaction> {
-        Ok(self.metadb.transaction()?)
-    }
-
-    #[cfg(test)]
-    fn set_block_contexts(
-        &mut self,
-        block_contexts: &[&DownstairsBlockContext],
-    ) -> Result<()> {
-        self.set_dirty()?;
-
+    fn delete_block_contexts(&mut self, blocks: &[u64]) -> Result<()> {
         let tx = self.metadb.transaction()?;
 
-        for block_context in block_contexts {
-            Self::tx_set_block_context(&tx, block_context)?;
+        for block in blocks {
+            Self::tx_delete_block_contexts(&tx, *block)?;
         }
 
         tx.commit()?;
@@ -251,10 +440,30 @@ impl Inner {
         Ok(())
     }
 
+    fn has_block_contexts(&self) -> Result<bool> {
+        let mut stmt = self
+            .metadb
+            .prepare_cached("SELECT EXISTS(SELECT 1 FROM block_context)")?;
+        let exists: bool = stmt.query_row([], |row| row.get(0))?;
+        Ok(exists)
+    }
+
     /*
-     * Get rid of all block context rows except those that match the on-disk
-     * hash that is computed after a flush.
+     * Move everything in the write ahead log into the database file, so
+     * the database file alone holds all committed metadata.
      */
+    fn checkpoint(&self) -> Result<()> {
+        let busy: i64 = self.metadb.query_row(
+            "PRAGMA wal_checkpoint(TRUNCATE)",
+            [],
+            |row| row.get(0),
+        )?;
+        if busy != 0 {
+            bail!("wal checkpoint did not complete");
+        }
+        Ok(())
+    }
+
     fn truncate_encryption_contexts_and_hashes(
         &mut self,
         extent_block_indexes_and_hashes: Vec<(usize, u64)>,
@@ -275,11 +484,67 @@ impl Inner {
     }
 }
 
+impl SqliteInner {
+    /*
+     * Append a block context row.
+     */
+    fn tx_set_block_context(
+        tx: &rusqlite::Transaction,
+        block_context: &DownstairsBlockContext,
+    ) -> Result<()> {
+        let stmt = "INSERT INTO block_context \
+             (block, hash, nonce, tag, on_disk_hash, key_id) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+
+        let (nonce, tag, key_id) = if let Some(encryption_context) =
+            &block_context.block_context.encryption_context
+        {
+            (
+                Some(&encryption_context.nonce),
+                Some(&encryption_context.tag),
+                encryption_context.key_id,
+            )
+        } else {
+            (None, None, 0)
+        };
+
+        let rows_affected = tx.prepare_cached(stmt)?.execute(params![
+            block_context.block,
+            block_context.block_context.hash.to_le_bytes(),
+            nonce,
+            tag,
+            block_context.on_disk_hash.to_le_bytes(),
+            key_id,
+        ])?;
+
+        assert_eq!(rows_affected, 1);
+
+        Ok(())
+    }
+
+    /*
+     * Remove every block context row for a block, returning that block to
+     * the unwritten state.
+     */
+    fn tx_delete_block_contexts(
+        tx: &rusqlite::Transaction,
+        block: u64,
+    ) -> Result<()> {
+        let stmt = "DELETE FROM block_context where block == ?1";
+
+        let _rows_affected =
+            tx.prepare_cached(stmt)?.execute(params![block])?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ExtentMeta {
     /**
-     * Version information regarding the extent structure.
-     * Not currently connected to anything XXX
+     * Version information regarding the extent structure: which
+     * ExtentInner implementation keeps the metadata, one of the
+     * EXTENT_VERSION_* values.
      */
     pub ext_version: u32,
     /**
@@ -303,7 +568,7 @@ pub struct ExtentMeta {
 impl Default for ExtentMeta {
     fn default() -> ExtentMeta {
         ExtentMeta {
-            ext_version: 1,
+            ext_version: EXTENT_VERSION_SQLITE,
             gen_number: 0,
             flush_number: 0,
             dirty: false,
@@ -429,6 +694,74 @@ fn config_path<P: AsRef<Path>>(dir: P) -> PathBuf {
     out
 }
 
+/**
+ * The region directory that holds the native (not ZFS) snapshot
+ * "snapshot_name" of the region in "dir".
+ */
+pub fn snapshot_dir<P: AsRef<Path>>(dir: P, snapshot_name: &str) -> PathBuf {
+    let mut out = dir.as_ref().to_path_buf();
+    out.push(".snapshot");
+    out.push(snapshot_name);
+    out
+}
+
+/**
+ * Create "dst" as a copy of "src". If the filesystem supports it the copy
+ * is a reflink, sharing blocks with "src" until either file is written to.
+ * Otherwise, we fall back to copying the contents.
+ */
+pub fn clone_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dst: Q,
+) -> Result<()> {
+    let mut src_file = File::open(&src)?;
+    let mut dst_file =
+        OpenOptions::new().write(true).create_new(true).open(&dst)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        // Safety: both file descriptors are open for as long as this call.
+        let rc = unsafe {
+            libc::ioctl(
+                dst_file.as_raw_fd(),
+                libc::FICLONE,
+                src_file.as_raw_fd(),
+            )
+        };
+        if rc == 0 {
+            dst_file.sync_all()?;
+            return Ok(());
+        }
+    }
+
+    std::io::copy(&mut src_file, &mut dst_file)?;
+    dst_file.sync_all()?;
+    Ok(())
+}
+
+/**
+ * Remove every file that makes up extent "eid", if it exists. Used when a
+ * region is shrunk and the extent is no longer part of it.
+ */
+pub fn remove_extent_files<P: AsRef<Path>>(dir: P, eid: u32) -> Result<()> {
+    for extent_type in [
+        ExtentType::Data,
+        ExtentType::Db,
+        ExtentType::DbShm,
+        ExtentType::DbWal,
+    ] {
+        let mut path = extent_dir(&dir, eid);
+        path.push(extent_file_name(eid, extent_type));
+        if Path::new(&path).exists() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    remove_copy_cleanup_dir(&dir, eid)?;
+    Ok(())
+}
+
 /**
  * Remove directories associated with repair except for the replace
  * directory. Replace is handled specifically during extent open.
@@ -447,12 +780,15 @@ pub fn remove_copy_cleanup_dir<P: AsRef<Path>>(dir: P, eid: u32) -> Result<()> {
 
 /**
  * Validate a list of sorted repair files.
- * There are either two or four files we expect to find, any more or less
- * and we have a bad list.  No duplicates.
+ * A raw extent is a single file.  For an SQLite extent there are either
+ * two or four files we expect to find, any more or less and we have a bad
+ * list.  No duplicates.
  */
 pub fn validate_repair_files(eid: usize, files: &[String]) -> bool {
     let eid = eid as u32;
 
+    let raw = vec![extent_file_name(eid, ExtentType::Data)];
+
     let some = vec![
         extent_file_name(eid, ExtentType::Data),
         extent_file_name(eid, ExtentType::Db),
@@ -464,8 +800,33 @@ pub fn validate_repair_files(eid: usize, files: &[String]) -> bool {
         extent_file_name(eid, ExtentType::DbWal),
     ]);
 
-    // Either we have some or all.
-    files == some || files == all
+    // Either we have a raw extent, or some or all of an SQLite one.
+    files == raw || files == some || files == all
+}
+
+/// Does block_context have the key_id column older extents were made without?
+fn has_key_id_column(metadb: &Connection) -> Result<bool> {
+    let count: u64 = metadb.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('block_context') \
+         WHERE name='key_id'",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/*
+ * Rows from before the key_id column get key ID 0, which the upstairs
+ * takes to mean a block written before key IDs.  A read only extent can't
+ * be changed, so it's read without the column instead.
+ */
+fn add_key_id_column(metadb: &Connection) -> Result<()> {
+    metadb.execute(
+        "ALTER TABLE block_context ADD COLUMN key_id INTEGER NOT NULL \
+         DEFAULT 0",
+        [],
+    )?;
+    Ok(())
 }
 
 /// Always open sqlite with journaling, and synchronous.
@@ -480,7 +841,7 @@ fn open_sqlite_connection<P: AsRef<Path>>(path: &P) -> Result<Connection> {
     // rusqlite provides an LRU Cache (a cache which, when full, evicts the
     // least-recently-used value). This caches prepared statements, allowing
     // us to nullify the cost of parsing and compiling frequently used
-    // statements.  I've changed all sqlite queries in Inner to use
+    // statements.  I've changed all sqlite queries in SqliteInner to use
     // `prepare_cached` to take advantage of this cache. I have not done this
     // for `prepare_cached` region creation,
     // since it wouldn't be relevant there.
@@ -527,10 +888,7 @@ impl Extent {
          * Store extent data in files within a directory hierarchy so that
          * there are not too many files in any level of that hierarchy.
          */
-        let mut path = extent_path(&dir, number);
-
-        let bcount = def.extent_size().value;
-        let size = def.block_size().checked_mul(bcount).unwrap();
+        let path = extent_path(&dir, number);
 
         remove_copy_cleanup_dir(&dir, number)?;
 
@@ -547,8 +905,41 @@ impl Extent {
             move_replacement_extent(&dir, number as usize, log)?;
         }
 
+        Extent::open_files(path, def, number, read_only, log)
+    }
+
+    /**
+     * Open an extent read only, as it is on disk, without finishing or
+     * cleaning up a repair of it first.  This is for looking at an extent
+     * that is closed in the region.
+     */
+    pub(crate) fn open_closed<P: AsRef<Path>>(
+        dir: P,
+        def: &RegionDefinition,
+        number: u32,
+        log: &Logger,
+    ) -> Result<Extent> {
+        Extent::open_files(extent_path(&dir, number), def, number, true, log)
+    }
+
+    /**
+     * Open the files of an extent, given the path of its data file.
+     * Nothing is cleaned up or replaced first, so this can also open the
+     * copy of an extent in its copy directory, or an extent that is
+     * closed in the region.
+     */
+    fn open_files(
+        mut path: PathBuf,
+        def: &RegionDefinition,
+        number: u32,
+        read_only: bool,
+        log: &Logger,
+    ) -> Result<Extent> {
+        let bcount = def.extent_size().value;
+        let size = def.block_size().checked_mul(bcount).unwrap();
+
         /*
-         * Open the extent file and verify the size is as we expect.
+         * Open the extent file.
          */
         let file = match OpenOptions::new()
             .read(true)
@@ -567,50 +958,115 @@ impl Extent {
                     e,
                 );
             }
-            Ok(f) => {
-                let cur_size = f.metadata().unwrap().len();
-                if size != cur_size {
-                    bail!(
-                        "File size {:?} does not match expected {:?}",
-                        size,
-                        cur_size
-                    );
-                }
-                f
-            }
+            Ok(f) => f,
         };
 
         /*
-         * Open a connection to the metadata db
+         * A compressed extent starts with its own header.
          */
-        path.set_extension("db");
-        let metadb =
-            match open_sqlite_connection(&path) {
+        if is_compressed_extent(&path)? {
+            let inner = match CompressedInner::open(file, def) {
                 Err(e) => {
                     error!(
-                    log,
-                    "Error: Open of db file {:?} for extent#{} returned: {}",
-                    path, number, e
-                );
+                        log,
+                        "Error: Open of compressed extent#{} returned: {}",
+                        number,
+                        e
+                    );
                     bail!(
-                        "Open of db file {:?} for extent#{} returned: {}",
-                        path,
+                        "Open of compressed extent#{} returned: {}",
                         number,
-                        e,
+                        e
                     );
                 }
-                Ok(m) => m,
+                Ok(inner) => inner,
             };
 
-        // XXX: schema updates?
+            return Ok(Extent {
+                number,
+                read_only,
+                block_size: def.block_size(),
+                extent_size: def.extent_size(),
+                inner: Some(Arc::new(Mutex::new(Box::new(inner)))),
+            });
+        }
 
-        Ok(Extent {
-            number,
-            read_only,
-            block_size: def.block_size(),
-            extent_size: def.extent_size(),
-            inner: Some(Mutex::new(Inner { file, metadb })),
-        })
+        /*
+         * An SQLite extent has its metadata db next to it, a raw extent
+         * keeps everything in the one file.
+         */
+        path.set_extension("db");
+        if !Path::new(&path).exists() {
+            let inner = match RawInner::open(file, def) {
+                Err(e) => {
+                    error!(
+                        log,
+                        "Error: Open of raw extent#{} returned: {}", number, e
+                    );
+                    bail!("Open of raw extent#{} returned: {}", number, e);
+                }
+                Ok(inner) => inner,
+            };
+
+            return Ok(Extent {
+                number,
+                read_only,
+                block_size: def.block_size(),
+                extent_size: def.extent_size(),
+                inner: Some(Arc::new(Mutex::new(Box::new(inner)))),
+            });
+        }
+
+        /*
+         * Verify the size is as we expect.
+         */
+        let cur_size = file.metadata()?.len();
+        if size != cur_size {
+            bail!(
+                "File size {:?} does not match expected {:?}",
+                size,
+                cur_size
+            );
+        }
+
+        /*
+         * Open a connection to the metadata db
+         */
+        let metadb =
+            match open_sqlite_connection(&path) {
+                Err(e) => {
+                    error!(
+                    log,
+                    "Error: Open of db file {:?} for extent#{} returned: {}",
+                    path, number, e
+                );
+                    bail!(
+                        "Open of db file {:?} for extent#{} returned: {}",
+                        path,
+                        number,
+                        e,
+                    );
+                }
+                Ok(m) => m,
+            };
+
+        let mut has_key_id = has_key_id_column(&metadb)?;
+        if !has_key_id && !read_only {
+            add_key_id_column(&metadb)?;
+            has_key_id = true;
+        }
+
+        Ok(Extent {
+            number,
+            read_only,
+            block_size: def.block_size(),
+            extent_size: def.extent_size(),
+            inner: Some(Arc::new(Mutex::new(Box::new(SqliteInner {
+                file,
+                metadb,
+                has_key_id,
+            })))),
+        })
     }
 
     /**
@@ -658,6 +1114,30 @@ impl Extent {
             .create(true)
             .open(&path)?;
 
+        if def.extent_version() == EXTENT_VERSION_RAW {
+            let inner = RawInner::create(file, def)?;
+
+            return Ok(Extent {
+                number,
+                read_only: false,
+                block_size: def.block_size(),
+                extent_size: def.extent_size(),
+                inner: Some(Arc::new(Mutex::new(Box::new(inner)))),
+            });
+        }
+
+        if def.extent_version() == EXTENT_VERSION_COMPRESSED {
+            let inner = CompressedInner::create(file, def)?;
+
+            return Ok(Extent {
+                number,
+                read_only: false,
+                block_size: def.block_size(),
+                extent_size: def.extent_size(),
+                inner: Some(Arc::new(Mutex::new(Box::new(inner)))),
+            });
+        }
+
         file.set_len(size)?;
         file.seek(SeekFrom::Start(0))?;
 
@@ -714,7 +1194,8 @@ impl Extent {
             // Within an extent, store a context row for each block.
             //
             // The Upstairs will send either an integrity hash, or an integrity
-            // hash along with some encryption context (a nonce and tag).
+            // hash along with some encryption context (a nonce and tag, and the
+            // ID of the key that was used).
             //
             // The Downstairs will have to record multiple context rows for each
             // block, because while what is committed to sqlite is durable (due
@@ -732,6 +1213,7 @@ impl Extent {
                     nonce BLOB,
                     tag BLOB,
                     on_disk_hash BLOB NOT NULL,
+                    key_id INTEGER NOT NULL DEFAULT 0,
                     PRIMARY KEY (block, hash, nonce, tag, on_disk_hash)
                 )",
                 [],
@@ -751,6 +1233,11 @@ impl Extent {
             open_sqlite_connection(&path)?
         };
 
+        // A seed made before block contexts had a key ID won't have it.
+        if !has_key_id_column(&metadb)? {
+            add_key_id_column(&metadb)?;
+        }
+
         /*
          * Complete the construction of our new extent
          */
@@ -759,7 +1246,11 @@ impl Extent {
             read_only: false,
             block_size: def.block_size(),
             extent_size: def.extent_size(),
-            inner: Some(Mutex::new(Inner { file, metadb })),
+            inner: Some(Arc::new(Mutex::new(Box::new(SqliteInner {
+                file,
+                metadb,
+                has_key_id: true,
+            })))),
         })
     }
 
@@ -813,7 +1304,7 @@ impl Extent {
         Ok(file)
     }
 
-    pub fn inner(&self) -> MutexGuard<Inner> {
+    pub fn inner(&self) -> MutexGuard<Box<dyn ExtentInner>> {
         self.inner.as_ref().unwrap().lock().unwrap()
     }
 
@@ -821,6 +1312,76 @@ impl Extent {
         self.number
     }
 
+    /// A handle on the open extent for the scrubber, which checks it
+    /// without holding on to the region.  None if the extent is closed.
+    pub fn scrubber(&self) -> Option<ExtentScrubber> {
+        self.inner.as_ref().map(|inner| ExtentScrubber {
+            inner: inner.clone(),
+            block_size: self.block_size,
+        })
+    }
+
+    /// A hash of every block of the extent, covering both its data and its
+    /// block contexts, for comparing the extent block by block with the
+    /// same extent on another downstairs.
+    pub fn block_hashes(&self) -> Result<Vec<u64>> {
+        let mut inner = self.inner();
+        let extent_size = self.extent_size.value;
+
+        let mut hashes = Vec::with_capacity(extent_size as usize);
+        let mut block = 0;
+        while block < extent_size {
+            let count = std::cmp::min(BLOCK_HASH_CHUNK, extent_size - block);
+            let mut data = vec![0u8; (count * self.block_size) as usize];
+            inner.read_data(block * self.block_size, &mut data)?;
+            let block_contexts = inner.get_block_contexts(block, count)?;
+
+            hashes.extend(
+                block_contexts
+                    .iter()
+                    .zip(data.chunks_exact(self.block_size as usize))
+                    .map(|(ctxs, data)| block_hash(data, ctxs)),
+            );
+            block += count;
+        }
+
+        Ok(hashes)
+    }
+
+    /// Replace the data and block contexts of the blocks starting at
+    /// `block` with a copy of the same blocks from another downstairs.
+    /// Nothing is synced, and the flush and generation numbers are left
+    /// alone.
+    pub fn replace_blocks(
+        &self,
+        block: u64,
+        data: &[u8],
+        block_contexts: &[DownstairsBlockContext],
+    ) -> Result<()> {
+        let count = data.len() as u64 / self.block_size;
+        if data.len() as u64 % self.block_size != 0
+            || block + count > self.extent_size.value
+            || block_contexts
+                .iter()
+                .any(|ctx| ctx.block < block || ctx.block >= block + count)
+        {
+            bail!(
+                "extent {}: bad replacement for {} bytes at block {}",
+                self.number,
+                data.len(),
+                block
+            );
+        }
+
+        let mut inner = self.inner();
+        inner.delete_block_contexts(
+            &(block..block + count).collect::<Vec<_>>(),
+        )?;
+        inner.set_block_contexts(&block_contexts.iter().collect::<Vec<_>>())?;
+        inner.write_data(block * self.block_size, data)?;
+        Ok(())
+    }
+
     /// Read the real data off underlying storage, and get block metadata. If
     /// an error occurs while processing any of the requests, the state of
     /// `responses` is undefined.
@@ -868,14 +1429,14 @@ impl Extent {
             }
 
             // Finally we get to read the actual data. That's why we're here
-            inner.file.seek(SeekFrom::Start(
-                first_req.offset.value * self.block_size,
-            ))?;
             let mut read_buffer = BytesMut::with_capacity(
                 n_contiguous_requests * self.block_size as usize,
             );
             read_buffer.resize(read_buffer.capacity(), 0);
-            inner.file.read_exact(&mut read_buffer)?;
+            inner.read_data(
+                first_req.offset.value * self.block_size,
+                &mut read_buffer,
+            )?;
 
             // Query the block metadata
             let block_contexts = inner.get_block_contexts(
@@ -1051,32 +1612,33 @@ impl Extent {
             }
         }
 
-        inner.set_dirty()?;
-
-        // Write all the metadata to the DB
-        let tx = inner.metadb_transaction()?;
-        for write in writes {
-            if writes_to_skip.contains(&write.offset.value) {
-                debug_assert!(only_write_unwritten);
-                continue;
-            }
-
-            Inner::tx_set_block_context(
-                &tx,
-                &DownstairsBlockContext {
-                    block_context: write.block_context.clone(),
-                    block: write.offset.value,
-                    on_disk_hash: integrity_hash(&[&write.data[..]]),
-                },
-            )?;
-        }
-        tx.commit()?;
+        // Set the dirty bit and write all the metadata
+        let block_contexts: Vec<DownstairsBlockContext> = writes
+            .iter()
+            .filter(|write| {
+                if writes_to_skip.contains(&write.offset.value) {
+                    debug_assert!(only_write_unwritten);
+                    false
+                } else {
+                    true
+                }
+            })
+            .map(|write| DownstairsBlockContext {
+                block_context: write.block_context.clone(),
+                block: write.offset.value,
+                on_disk_hash: integrity_hash(&[&write.data[..]]),
+            })
+            .collect();
+        let block_contexts: Vec<&DownstairsBlockContext> =
+            block_contexts.iter().collect();
+        inner.set_block_contexts(&block_contexts)?;
 
         // Buffer writes for fewer syscalls. The 65536 buffer size here is
         // chosen somewhat arbitrarily.
         let mut write_buffer = [0u8; 65536];
 
         let mut bytes_in_run = 0;
+        let mut run_offset = 0;
         let mut next_block_in_run = u64::MAX;
         for write in writes {
             let block = write.offset.value;
@@ -1086,20 +1648,23 @@ impl Extent {
             }
 
             // If the current write isn't contiguous with previous writes,
-            // write our buffer to the file and seek.
+            // write out our buffer and start a new run at this block.
             if block != next_block_in_run {
                 if bytes_in_run > 0 {
-                    inner.file.write_all(&write_buffer[..bytes_in_run])?;
+                    inner.write_data(
+                        run_offset,
+                        &write_buffer[..bytes_in_run],
+                    )?;
                     bytes_in_run = 0;
                 }
-
-                // Write any buffered data, and then seek
-                inner.file.seek(SeekFrom::Start(block * self.block_size))?;
+                run_offset = block * self.block_size;
             }
 
-            // If the write buffer is full, write out to the file
+            // If the write buffer is full, write it out and continue the
+            // run after it
             if write_buffer.len() - bytes_in_run < self.block_size as usize {
-                inner.file.write_all(&write_buffer[..bytes_in_run])?;
+                inner.write_data(run_offset, &write_buffer[..bytes_in_run])?;
+                run_offset += bytes_in_run as u64;
                 bytes_in_run = 0;
             }
 
@@ -1112,9 +1677,72 @@ impl Extent {
 
         // Write any remaining buffered data
         if bytes_in_run > 0 {
-            inner.file.write_all(&write_buffer[..bytes_in_run])?;
+            inner.write_data(run_offset, &write_buffer[..bytes_in_run])?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Discard blocks in this extent: zero the data and remove all of the
+     * block context rows, so the blocks look like they were never written.
+     * A later write_unwritten to one of these blocks will land.
+     */
+    #[instrument]
+    pub fn discard(
+        &self,
+        requests: &[&crucible_protocol::DiscardRequest],
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        let zero_block = vec![0u8; self.block_size as usize];
+        for request in requests {
+            self.check_input(request.offset, &zero_block)?;
+        }
+
+        let mut inner = self.inner();
+
+        /*
+         * An unwritten block is one with no block contexts, and the Upstairs
+         * expects such a block to read back as all zeros. In order to be
+         * crash consistent, perform the following steps in order:
+         *
+         * 1) set the dirty bit
+         * 2) write zeros over the extent data for each block
+         * 3) fsync, so the zeros are durable
+         * 4) remove the block context rows
+         *
+         * A crash between 3 and 4 leaves zeroed blocks with stale contexts.
+         * Because the dirty bit is set, the flush at the end of
+         * reconciliation will truncate any context whose on disk hash does
+         * not match the zeroed data, finishing the discard for us.
+         */
+        inner.set_dirty()?;
+
+        for request in requests {
+            inner.write_data(
+                request.offset.value * self.block_size,
+                &zero_block,
+            )?;
+        }
+
+        if let Err(e) = inner.sync_data() {
+            crucible_bail!(
+                IoError,
+                "extent {}: fsync discard failure: {:?}",
+                self.number,
+                e
+            );
         }
 
+        let blocks: Vec<u64> = requests
+            .iter()
+            .map(|request| request.offset.value)
+            .collect();
+        inner.delete_block_contexts(&blocks)?;
+
         Ok(())
     }
 
@@ -1148,7 +1776,7 @@ impl Extent {
          * We must first fsync to get any outstanding data written to disk.
          * This must be done before we update the flush number.
          */
-        if let Err(e) = inner.file.sync_all() {
+        if let Err(e) = inner.sync_data() {
             /*
              * XXX Retry?  Mark extent as broken?
              */
@@ -1169,8 +1797,7 @@ impl Extent {
             self.extent_size.value as usize * self.block_size as usize;
         let mut extent_data: Vec<u8> = vec![0; total_bytes];
 
-        inner.file.seek(SeekFrom::Start(0))?;
-        inner.file.read_exact(&mut extent_data)?;
+        inner.read_data(0, &mut extent_data)?;
 
         let extent_block_indexes_and_hashes = extent_data
             .chunks(self.block_size as usize)
@@ -1182,10 +1809,7 @@ impl Extent {
             extent_block_indexes_and_hashes,
         )?;
 
-        // Reset the file's seek offset to 0, and set the flush number and gen
-        // number
-
-        inner.file.seek(SeekFrom::Start(0))?;
+        // Set the flush number and gen number
 
         inner.set_flush_number(new_flush, new_gen)?;
 
@@ -1486,9 +2110,9 @@ impl Region {
         );
 
         // The repair file list should always contain the extent data
-        // file itself, and the .db file (metadata) for that extent.
-        // Missing these means the repair will not succeed.
-        // Optionally, there could be both .db-shm and .db-wal.
+        // file itself.  An SQLite extent will also have the .db file
+        // (metadata) for that extent, and optionally both .db-shm and
+        // .db-wal.  A raw or compressed extent is just the one file.
         if !validate_repair_files(eid, &repair_files) {
             crucible_bail!(
                 RepairFilesInvalid,
@@ -1515,27 +2139,10 @@ impl Region {
         };
         save_stream_to_file(extent_copy, repair_stream.into_inner()).await?;
 
-        // The .db file is also required to exist for any valid extent.
-        let extent_db =
-            extent.create_copy_file(copy_dir.clone(), Some(ExtentType::Db))?;
-        let repair_stream = match repair_server
-            .get_extent_file(eid as u32, FileType::Db)
-            .await
+        // The rest only exist for an SQLite extent, and validation above
+        // made sure we have the .db file if we have either of the others.
+        for opt_file in &[ExtentType::Db, ExtentType::DbShm, ExtentType::DbWal]
         {
-            Ok(rs) => rs,
-            Err(e) => {
-                crucible_bail!(
-                    RepairRequestError,
-                    "Failed to get extent {} db file: {:?}",
-                    eid,
-                    e,
-                );
-            }
-        };
-        save_stream_to_file(extent_db, repair_stream.into_inner()).await?;
-
-        // These next two are optional.
-        for opt_file in &[ExtentType::DbShm, ExtentType::DbWal] {
             let filename = extent_file_name(eid as u32, opt_file.clone());
 
             if repair_files.contains(&filename) {
@@ -1582,51 +2189,347 @@ impl Region {
     }
 
     /**
-     * if there is a difference between what our actual extent_count is
-     * and what is requested, go out and create the new extent files.
+     * Repair an extent from another downstairs, copying only the blocks
+     * that differ.
+     *
+     * Both sides hash every block of the extent, data and block contexts
+     * together, and we compare the two lists.  Our own extent files are
+     * copied into the copy directory, the blocks that differ are fetched
+     * from the source and written over the copy, and the copy takes the
+     * source's flush and generation numbers and dirty bit.  From there on
+     * this is the same as repair_extent: the copy directory becomes the
+     * replace directory, which then replaces the extent, so an
+     * interruption at any point is recovered from in the same way.
+     *
+     * An extent in a different format on the source is copied whole.
      */
-    pub fn extend(&mut self, newsize: u32) -> Result<()> {
-        if self.read_only {
-            // XXX return CrucibleError instead of anyhow?
-            bail!(CrucibleError::ModifyingReadOnlyRegion.to_string());
-        }
+    pub async fn repair_extent_blocks(
+        &mut self,
+        eid: usize,
+        repair_addr: SocketAddr,
+    ) -> Result<(), CrucibleError> {
+        // Make sure the extent:
+        // is currently closed, matches our eid, is not read-only
+        assert!(self.extents[eid].inner.is_none());
+        assert_eq!(self.extents[eid].number, eid as u32);
+        assert!(!self.read_only);
 
-        if newsize < self.def.extent_count() {
-            bail!(
-                "will not truncate {} -> {} for now",
-                self.def.extent_count(),
-                newsize
+        // XXX TLS someday?  Authentication?
+        let url = format!("http://{:?}", repair_addr);
+        let repair_server = Client::new(&url);
+
+        let source = match repair_server.get_extent_blocks(eid as u32).await {
+            Ok(b) => b.into_inner(),
+            Err(e) => {
+                crucible_bail!(
+                    RepairRequestError,
+                    "Failed to get extent {} block hashes: {:?}",
+                    eid,
+                    e,
+                );
+            }
+        };
+
+        let local =
+            Extent::open_closed(&self.dir, &self.def, eid as u32, &self.log)?;
+        let ext_version = local.inner().ext_version();
+        if ext_version != source.ext_version
+            || source.block_hashes.len() as u64 != self.def.extent_size().value
+        {
+            info!(
+                self.log,
+                "eid:{} is version {} here and {} on the source, copying \
+                 all of it",
+                eid,
+                ext_version,
+                source.ext_version,
             );
+            drop(local);
+            return self.repair_extent(eid, repair_addr).await;
         }
 
-        if newsize > self.def.extent_count() {
-            self.def.set_extent_count(newsize);
-            write_json(config_path(&self.dir), &self.def, true)?;
-            self.open_extents(true)?;
+        let differ: Vec<u64> = local
+            .block_hashes()?
+            .iter()
+            .zip(source.block_hashes.iter())
+            .enumerate()
+            .filter(|(_, (ours, theirs))| ours != theirs)
+            .map(|(block, _)| block as u64)
+            .collect();
+        drop(local);
+        info!(
+            self.log,
+            "eid:{} {} of {} blocks differ from the source",
+            eid,
+            differ.len(),
+            source.block_hashes.len(),
+        );
+
+        let (copy_dir, copy) = self.clone_extent_to_copy_dir(eid)?;
+
+        for (block, count) in block_runs(&differ, REPAIR_BLOCKS_MAX) {
+            let data = match repair_server
+                .get_extent_block_data(eid as u32, block, count)
+                .await
+            {
+                Ok(rs) => read_stream(rs.into_inner()).await?,
+                Err(e) => {
+                    crucible_bail!(
+                        RepairRequestError,
+                        "Failed to get extent {} block {} data: {:?}",
+                        eid,
+                        block,
+                        e,
+                    );
+                }
+            };
+            if data.len() as u64 != count * self.def.block_size() {
+                crucible_bail!(
+                    RepairStreamError,
+                    "extent {} block {}: got {} bytes for {} blocks",
+                    eid,
+                    block,
+                    data.len(),
+                    count,
+                );
+            }
+
+            let block_contexts = match repair_server
+                .get_extent_block_contexts(eid as u32, block, count)
+                .await
+            {
+                Ok(ctxs) => ctxs
+                    .into_inner()
+                    .into_iter()
+                    .map(repair_block_context)
+                    .collect::<Result<Vec<_>, _>>()?,
+                Err(e) => {
+                    crucible_bail!(
+                        RepairRequestError,
+                        "Failed to get extent {} block {} contexts: {:?}",
+                        eid,
+                        block,
+                        e,
+                    );
+                }
+            };
+
+            copy.replace_blocks(block, &data, &block_contexts)?;
         }
-        Ok(())
-    }
 
-    pub fn region_def(&self) -> (u64, Block, u32) {
-        (
-            self.def.block_size(),
-            self.def.extent_size(),
-            self.def.extent_count(),
+        self.replace_extent_with_copy(
+            eid,
+            copy_dir,
+            copy,
+            source.flush_number,
+            source.gen_number,
+            source.dirty,
         )
     }
-    pub fn def(&self) -> RegionDefinition {
-        self.def
-    }
 
-    pub fn flush_numbers(&self) -> Result<Vec<u64>> {
-        let mut ver = self
-            .extents
-            .iter()
-            .map(|e| e.inner().flush_number())
-            .collect::<Result<Vec<_>>>()?;
+    /**
+     * Copy our own files for an extent into its copy directory, and open
+     * the copy.  The extent must be closed.
+     */
+    fn clone_extent_to_copy_dir(
+        &self,
+        eid: usize,
+    ) -> Result<(PathBuf, Extent), CrucibleError> {
+        assert!(self.extents[eid].inner.is_none());
 
-        if ver.len() > 12 {
-            ver = ver[0..12].to_vec();
+        let rd = replace_dir(&self.dir, eid as u32);
+        if rd.exists() {
+            crucible_bail!(
+                IoError,
+                "Replace directory: {:?} already exists",
+                rd,
+            );
+        }
+
+        let copy_dir = self.extents[eid].create_copy_dir(&self.dir)?;
+        info!(self.log, "Created copy dir {:?}", copy_dir);
+
+        let mut original = extent_path(&self.dir, eid as u32);
+        let mut copy = copy_dir.clone();
+        copy.push(extent_file_name(eid as u32, ExtentType::Data));
+        clone_file(&original, &copy)?;
+
+        // The rest only exist for an SQLite extent.
+        for ext in &["db", "db-shm", "db-wal"] {
+            original.set_extension(ext);
+            copy.set_extension(ext);
+            if original.exists() {
+                clone_file(&original, &copy)?;
+            }
+        }
+        copy.set_extension("");
+
+        let extent =
+            Extent::open_files(copy, &self.def, eid as u32, false, &self.log)?;
+        Ok((copy_dir, extent))
+    }
+
+    /**
+     * Finish a block level repair: give the copy of an extent the
+     * source's metadata, then replace the extent with it.
+     */
+    fn replace_extent_with_copy(
+        &self,
+        eid: usize,
+        copy_dir: PathBuf,
+        copy: Extent,
+        flush_number: u64,
+        gen_number: u64,
+        dirty: bool,
+    ) -> Result<(), CrucibleError> {
+        {
+            let mut inner = copy.inner();
+            inner.sync_data()?;
+            inner.set_flush_number(flush_number, gen_number)?;
+            if dirty {
+                inner.set_dirty()?;
+            }
+            inner.checkpoint()?;
+        }
+        drop(copy);
+
+        // The copy now matches the source: move the copy dir, and go on
+        // just as repair_extent does.
+        let rd = replace_dir(&self.dir, eid as u32);
+        info!(
+            self.log,
+            "Repair blocks written, move directory {:?} to {:?}", copy_dir, rd
+        );
+        rename(copy_dir, rd)?;
+        sync_path(extent_dir(&self.dir, eid as u32), &self.log)?;
+
+        move_replacement_extent(&self.dir, eid, &self.log)?;
+
+        Ok(())
+    }
+
+    /**
+     * if there is a difference between what our actual extent_count is
+     * and what is requested, go out and create the new extent files.
+     */
+    pub fn extend(&mut self, newsize: u32) -> Result<()> {
+        if self.read_only {
+            // XXX return CrucibleError instead of anyhow?
+            bail!(CrucibleError::ModifyingReadOnlyRegion.to_string());
+        }
+
+        if newsize < self.def.extent_count() {
+            bail!(
+                "will not truncate {} -> {} for now",
+                self.def.extent_count(),
+                newsize
+            );
+        }
+
+        if newsize > self.def.extent_count() {
+            self.def.set_extent_count(newsize);
+            write_json(config_path(&self.dir), &self.def, true)?;
+            self.open_extents(true)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Grow or shrink this region to `extent_count` extents.
+     *
+     * Growing creates new, empty extents at the end of the region.
+     * Shrinking is only allowed if every extent being removed is
+     * unwritten, meaning none of its blocks has a block context. This
+     * is checked for every tail extent before anything is changed.
+     */
+    pub fn resize(&mut self, extent_count: u32) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        if extent_count == 0 {
+            crucible_bail!(RegionResizeError, "extent count must be non zero");
+        }
+
+        let current = self.def.extent_count();
+
+        if extent_count > current {
+            /*
+             * A crash part way through a previous shrink could leave the
+             * files of a removed extent behind. Clean those up so the
+             * extent can be created again.
+             */
+            for eid in current..extent_count {
+                remove_extent_files(&self.dir, eid)?;
+            }
+
+            info!(
+                self.log,
+                "Grow region from {} to {} extents", current, extent_count
+            );
+            self.extend(extent_count)?;
+        } else if extent_count < current {
+            for eid in extent_count..current {
+                let extent = &self.extents[eid as usize];
+                if extent.inner.is_none() {
+                    crucible_bail!(
+                        RegionResizeError,
+                        "extent {} is closed",
+                        eid
+                    );
+                }
+                if extent.inner().has_block_contexts()? {
+                    crucible_bail!(
+                        RegionResizeError,
+                        "extent {} has written blocks",
+                        eid
+                    );
+                }
+            }
+
+            info!(
+                self.log,
+                "Shrink region from {} to {} extents", current, extent_count
+            );
+
+            /*
+             * Update the region config first. If we crash after this, the
+             * region will still open, and any extent files left behind are
+             * removed by the next grow.
+             */
+            self.def.set_extent_count(extent_count);
+            write_json(config_path(&self.dir), &self.def, true)?;
+
+            for mut extent in self.extents.drain(extent_count as usize..) {
+                extent.close()?;
+                remove_extent_files(&self.dir, extent.number)?;
+            }
+            assert_eq!(self.def.extent_count() as usize, self.extents.len());
+        }
+
+        Ok(())
+    }
+
+    pub fn region_def(&self) -> (u64, Block, u32) {
+        (
+            self.def.block_size(),
+            self.def.extent_size(),
+            self.def.extent_count(),
+        )
+    }
+    pub fn def(&self) -> RegionDefinition {
+        self.def
+    }
+
+    pub fn flush_numbers(&self) -> Result<Vec<u64>> {
+        let mut ver = self
+            .extents
+            .iter()
+            .map(|e| e.inner().flush_number())
+            .collect::<Result<Vec<_>>>()?;
+
+        if ver.len() > 12 {
+            ver = ver[0..12].to_vec();
         }
         info!(self.log, "Current flush_numbers [0..12]: {:?}", ver);
 
@@ -1725,6 +2628,43 @@ impl Region {
         Ok(())
     }
 
+    #[instrument]
+    pub fn region_discard(
+        &self,
+        requests: &[crucible_protocol::DiscardRequest],
+        job_id: u64,
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+
+        /*
+         * Batch discards so they can all be sent to the appropriate extent
+         * together.
+         */
+        let mut batched_discards: HashMap<
+            usize,
+            Vec<&crucible_protocol::DiscardRequest>,
+        > = HashMap::new();
+
+        for request in requests {
+            let extent_vec = batched_discards
+                .entry(request.eid as usize)
+                .or_insert_with(Vec::new);
+            extent_vec.push(request);
+        }
+
+        cdt::os__discard__start!(|| job_id);
+        for eid in batched_discards.keys() {
+            let extent = &self.extents[*eid];
+            let requests = batched_discards.get(eid).unwrap();
+            extent.discard(&requests[..])?;
+        }
+        cdt::os__discard__done!(|| job_id);
+
+        Ok(())
+    }
+
     #[instrument]
     pub fn region_read(
         &self,
@@ -1886,9 +2826,99 @@ impl Region {
                     );
                 }
             }
-        } else if snapshot_details.is_some() {
-            error!(self.log, "Snapshot request received on unsupported binary");
+        } else if let Some(snapshot_details) = snapshot_details {
+            info!(self.log, "Flush and snap request received");
+            self.snapshot(&snapshot_details.snapshot_name)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Take a snapshot of this region without help from the filesystem.
+     *
+     * The data file and metadata database of every extent, along with the
+     * region config, are cloned (see clone_file) into a new region
+     * directory at snapshot_dir(). That directory can then be opened with
+     * Region::open(.., read_only=true) and served by a read only
+     * downstairs.
+     *
+     * This must only be called after all extents are flushed, and while
+     * nothing else can write to the region. The snapshot is built in a
+     * temporary directory and renamed into place once complete, so a
+     * crash part way through won't leave a partial snapshot behind.
+     */
+    fn snapshot(&self, snapshot_name: &str) -> Result<(), CrucibleError> {
+        if snapshot_name.is_empty()
+            || snapshot_name.starts_with('.')
+            || snapshot_name.contains('/')
+        {
+            crucible_bail!(
+                SnapshotFailed,
+                "invalid snapshot name {:?}",
+                snapshot_name
+            );
+        }
+
+        let final_dir = snapshot_dir(&self.dir, snapshot_name);
+        if final_dir.exists() {
+            crucible_bail!(SnapshotExistsAlready, "{}", snapshot_name);
+        }
+
+        // The leading '.' can't be in a snapshot name, so this won't
+        // collide with a real snapshot.
+        let tmp_dir = snapshot_dir(&self.dir, &format!(".{}", snapshot_name));
+        if tmp_dir.exists() {
+            std::fs::remove_dir_all(&tmp_dir)
+                .map_err(|e| CrucibleError::SnapshotFailed(e.to_string()))?;
+        }
+
+        let result = self.snapshot_to(&tmp_dir).and_then(|_| {
+            rename(&tmp_dir, &final_dir)?;
+            sync_path(final_dir.parent().unwrap(), &self.log)?;
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            error!(self.log, "snapshot {} failed: {}", snapshot_name, e);
+            let _ = std::fs::remove_dir_all(&tmp_dir);
+            crucible_bail!(SnapshotFailed, "{}: {}", snapshot_name, e);
+        }
+
+        info!(self.log, "Created snapshot at {:?}", final_dir);
+        Ok(())
+    }
+
+    fn snapshot_to(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+
+        for extent in &self.extents {
+            if extent.inner.is_none() {
+                bail!("extent {} is closed", extent.number);
+            }
+
+            // Hold the lock while we clone, nothing should change the
+            // extent under us.
+            let inner = extent.inner();
+            inner.checkpoint()?;
+
+            let src = extent_dir(&self.dir, extent.number);
+            let dst = extent_dir(dir, extent.number);
+            std::fs::create_dir_all(&dst)?;
+
+            let mut extent_types = vec![ExtentType::Data];
+            if inner.ext_version() == EXTENT_VERSION_SQLITE {
+                extent_types.push(ExtentType::Db);
+            }
+            for extent_type in extent_types {
+                let name = extent_file_name(extent.number, extent_type);
+                clone_file(src.join(&name), dst.join(&name))?;
+            }
+            sync_path(&dst, &self.log)?;
         }
+
+        clone_file(config_path(&self.dir), config_path(dir))?;
+        sync_path(dir, &self.log)?;
+
         Ok(())
     }
 }
@@ -1957,64 +2987,34 @@ pub fn move_replacement_extent<P: AsRef<Path>>(
     }
     sync_path(&original_file, log)?;
 
-    new_file.set_extension("db");
-    original_file.set_extension("db");
-    if let Err(e) = std::fs::copy(new_file.clone(), original_file.clone()) {
-        crucible_bail!(
-            IoError,
-            "copy {:?} to {:?} got: {:?}",
-            new_file,
-            original_file,
-            e
-        );
-    }
-    sync_path(&original_file, log)?;
-
-    // The .db-shm and .db-wal files may or may not exist.  If they don't
-    // exist on the source side, then be sure to remove them locally to
-    // avoid database corruption from a mismatch between old and new.
-    new_file.set_extension("db-shm");
-    original_file.set_extension("db-shm");
-    if new_file.exists() {
-        if let Err(e) = std::fs::copy(new_file.clone(), original_file.clone()) {
-            crucible_bail!(
-                IoError,
-                "copy {:?} to {:?} got: {:?}",
-                new_file,
-                original_file,
-                e
-            );
-        }
-        sync_path(&original_file, log)?;
-    } else if original_file.exists() {
-        info!(
-            log,
-            "Remove old file {:?} as there is no replacement",
-            original_file.clone()
-        );
-        std::fs::remove_file(&original_file)?;
-    }
-
-    new_file.set_extension("db-wal");
-    original_file.set_extension("db-wal");
-    if new_file.exists() {
-        if let Err(e) = std::fs::copy(new_file.clone(), original_file.clone()) {
-            crucible_bail!(
-                IoError,
-                "copy {:?} to {:?} got: {:?}",
-                new_file,
-                original_file,
-                e
+    // The .db, .db-shm and .db-wal files may or may not exist.  If they
+    // don't exist on the source side, then be sure to remove them locally
+    // to avoid database corruption from a mismatch between old and new, or
+    // an old .db being found next to what is now a raw extent.
+    for ext in &["db", "db-shm", "db-wal"] {
+        new_file.set_extension(ext);
+        original_file.set_extension(ext);
+        if new_file.exists() {
+            if let Err(e) =
+                std::fs::copy(new_file.clone(), original_file.clone())
+            {
+                crucible_bail!(
+                    IoError,
+                    "copy {:?} to {:?} got: {:?}",
+                    new_file,
+                    original_file,
+                    e
+                );
+            }
+            sync_path(&original_file, log)?;
+        } else if original_file.exists() {
+            info!(
+                log,
+                "Remove old file {:?} as there is no replacement",
+                original_file.clone()
             );
+            std::fs::remove_file(&original_file)?;
         }
-        sync_path(&original_file, log)?;
-    } else if original_file.exists() {
-        info!(
-            log,
-            "Remove old file {:?} as there is no replacement",
-            original_file.clone()
-        );
-        std::fs::remove_file(&original_file)?;
     }
     sync_path(&destination_dir, log)?;
 
@@ -2072,6 +3072,31 @@ pub async fn save_stream_to_file(
     Ok(())
 }
 
+/**
+ * Read all of a stream returned to us from a progenitor endpoint.
+ */
+pub async fn read_stream(
+    mut stream: Pin<
+        Box<
+            dyn futures::Stream<
+                    Item = std::result::Result<crucible::Bytes, reqwest::Error>,
+                > + std::marker::Send,
+        >,
+    >,
+) -> Result<Vec<u8>, CrucibleError> {
+    let mut data = Vec::new();
+    loop {
+        match stream.try_next().await {
+            Ok(Some(bytes)) => data.extend_from_slice(&bytes),
+            Ok(None) => break,
+            Err(e) => {
+                crucible_bail!(RepairStreamError, "stream error: {:?}", e);
+            }
+        }
+    }
+    Ok(data)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -2090,9 +3115,10 @@ mod test {
     fn new_extent(number: u32) -> Extent {
         let ff = File::open("/dev/null").unwrap();
 
-        let inn = Inner {
+        let inn = SqliteInner {
             file: ff,
             metadb: Connection::open_in_memory().unwrap(),
+            has_key_id: true,
         };
 
         /*
@@ -2104,7 +3130,7 @@ mod test {
             read_only: false,
             block_size: 512,
             extent_size: Block::new_512(100),
-            inner: Some(Mutex::new(inn)),
+            inner: Some(Arc::new(Mutex::new(Box::new(inn)))),
         }
     }
 
@@ -2554,12 +3580,217 @@ mod test {
         assert!(!validate_repair_files(1, &good_files));
     }
 
+    fn write_block(region: &mut Region, eid: u64, val: u8) -> Result<()> {
+        let data = BytesMut::from(&[val; 512][..]);
+        let hash = integrity_hash(&[&data[..]]);
+        region.region_write(
+            &[crucible_protocol::Write {
+                eid,
+                offset: Block::new_512(0),
+                data: data.freeze(),
+                block_context: BlockContext {
+                    encryption_context: None,
+                    hash,
+                },
+            }],
+            0,
+            false,
+        )?;
+        Ok(())
+    }
+
+    fn read_block(region: &Region, eid: u64) -> Result<Vec<u8>> {
+        let responses = region.region_read(
+            &[crucible_protocol::ReadRequest {
+                eid,
+                offset: Block::new_512(0),
+            }],
+            0,
+        )?;
+        Ok(responses[0].data.to_vec())
+    }
+
+    #[cfg(not(feature = "zfs_snapshot"))]
     #[test]
-    fn reopen_all_extents() -> Result<()> {
-        // Create the region, make three extents
+    fn native_snapshot() -> Result<()> {
         let dir = tempdir()?;
         let mut region = Region::create(&dir, new_region_options(), csl())?;
-        region.extend(5)?;
+        region.extend(3)?;
+
+        write_block(&mut region, 1, 1)?;
+        region.region_flush(
+            1,
+            1,
+            &Some(SnapshotDetails {
+                snapshot_name: "snap1".to_string(),
+            }),
+            1,
+        )?;
+
+        // Writes after the snapshot don't show up in it.
+        write_block(&mut region, 1, 2)?;
+        write_block(&mut region, 2, 2)?;
+        region.region_flush(2, 1, &None, 2)?;
+        assert_eq!(read_block(&region, 1)?, vec![2u8; 512]);
+
+        let snap_dir = snapshot_dir(&dir, "snap1");
+        let mut snap =
+            Region::open(&snap_dir, new_region_options(), true, true, &csl())?;
+        assert_eq!(snap.def().extent_count(), 3);
+        assert_eq!(read_block(&snap, 1)?, vec![1u8; 512]);
+        assert_eq!(read_block(&snap, 2)?, vec![0u8; 512]);
+        assert_eq!(snap.extents[1].inner().flush_number()?, 1);
+
+        // The snapshot is read only.
+        assert!(write_block(&mut snap, 0, 3).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "zfs_snapshot"))]
+    #[test]
+    fn native_snapshot_exists() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options(), csl())?;
+        region.extend(2)?;
+
+        let snapshot_details = Some(SnapshotDetails {
+            snapshot_name: "snap1".to_string(),
+        });
+        region.region_flush(1, 1, &snapshot_details, 1)?;
+
+        let res = region.region_flush(2, 1, &snapshot_details, 2);
+        assert!(matches!(res, Err(CrucibleError::SnapshotExistsAlready(_))));
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "zfs_snapshot"))]
+    #[test]
+    fn native_snapshot_bad_name() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options(), csl())?;
+        region.extend(2)?;
+
+        for name in ["", ".snap", "../snap", "a/b"] {
+            let res = region.region_flush(
+                1,
+                1,
+                &Some(SnapshotDetails {
+                    snapshot_name: name.to_string(),
+                }),
+                1,
+            );
+            assert!(matches!(res, Err(CrucibleError::SnapshotFailed(_))));
+        }
+        assert!(!Path::new(&dir.path().join(".snapshot")).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn resize_grow_and_shrink() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options(), csl())?;
+        region.extend(3)?;
+
+        // Grow, the new extents should be there after a reopen.
+        region.resize(5)?;
+        assert_eq!(region.def().extent_count(), 5);
+        assert_eq!(region.extents.len(), 5);
+        drop(region);
+
+        let mut region =
+            Region::open(&dir, new_region_options(), true, false, &csl())?;
+        assert_eq!(region.def().extent_count(), 5);
+        assert!(Path::new(&extent_path(&dir, 4)).exists());
+
+        // Shrink, all tail extents are unwritten.
+        region.resize(2)?;
+        assert_eq!(region.def().extent_count(), 2);
+        assert_eq!(region.extents.len(), 2);
+        assert!(!Path::new(&extent_path(&dir, 2)).exists());
+        assert!(!Path::new(&extent_path(&dir, 4)).exists());
+        drop(region);
+
+        let region =
+            Region::open(&dir, new_region_options(), true, false, &csl())?;
+        assert_eq!(region.def().extent_count(), 2);
+        assert_eq!(region.extents.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resize_shrink_written_extent() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options(), csl())?;
+        region.extend(3)?;
+
+        let data = BytesMut::from(&[9u8; 512][..]);
+        let hash = integrity_hash(&[&data[..]]);
+        let eid = 2;
+        let offset = Block::new_512(4);
+        region.region_write(
+            &[crucible_protocol::Write {
+                eid,
+                offset,
+                data: data.freeze(),
+                block_context: BlockContext {
+                    encryption_context: None,
+                    hash,
+                },
+            }],
+            0,
+            false,
+        )?;
+
+        // Extent 2 is written, we can't drop it
+        assert!(region.resize(2).is_err());
+        assert_eq!(region.def().extent_count(), 3);
+        assert_eq!(region.extents.len(), 3);
+
+        // Once the block is discarded, the shrink is allowed
+        region.region_discard(
+            &[crucible_protocol::DiscardRequest { eid, offset }],
+            1,
+        )?;
+        region.resize(2)?;
+        assert_eq!(region.def().extent_count(), 2);
+
+        // And we can grow back into the space we gave up
+        region.resize(3)?;
+        let responses = region.region_read(
+            &[crucible_protocol::ReadRequest { eid, offset }],
+            2,
+        )?;
+        assert!(responses[0].hashes().is_empty());
+        assert_eq!(responses[0].data[..], [0u8; 512][..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn resize_read_only() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options(), csl())?;
+        region.extend(3)?;
+        drop(region);
+
+        let mut region =
+            Region::open(&dir, new_region_options(), true, true, &csl())?;
+        assert!(region.resize(4).is_err());
+        assert!(region.resize(2).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_all_extents() -> Result<()> {
+        // Create the region, make three extents
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options(), csl())?;
+        region.extend(5)?;
 
         // Close extent 1
         let ext_one = &mut region.extents[1];
@@ -2885,6 +4116,7 @@ mod test {
                 encryption_context: Some(EncryptionContext {
                     nonce: [1, 2, 3].to_vec(),
                     tag: [4, 5, 6, 7].to_vec(),
+                    key_id: 0,
                 }),
                 hash: 123,
             },
@@ -2931,6 +4163,7 @@ mod test {
                 encryption_context: Some(EncryptionContext {
                     nonce: blob1.to_vec(),
                     tag: blob2.to_vec(),
+                    key_id: 0,
                 }),
                 hash: 1024,
             },
@@ -3017,6 +4250,81 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn block_context_key_id() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options(), csl())?;
+        region.extend(1)?;
+
+        let ctx = DownstairsBlockContext {
+            block_context: BlockContext {
+                encryption_context: Some(EncryptionContext {
+                    nonce: vec![1; 12],
+                    tag: vec![2; 16],
+                    key_id: 0x1234,
+                }),
+                hash: 123,
+            },
+            block: 3,
+            on_disk_hash: 456,
+        };
+        region.extents[0].inner().set_block_contexts(&[&ctx])?;
+
+        let ctxs = region.extents[0].inner().get_block_contexts(3, 1)?;
+        assert_eq!(ctxs[0].len(), 1);
+        assert_eq!(ctxs[0][0].block_context, ctx.block_context);
+
+        Ok(())
+    }
+
+    #[test]
+    fn block_context_key_id_added_on_open() -> Result<()> {
+        // An extent from before keys had an ID gets the column added when
+        // it is opened, and its blocks read back with key ID 0.
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options(), csl())?;
+        region.extend(1)?;
+
+        let ctx = DownstairsBlockContext {
+            block_context: BlockContext {
+                encryption_context: Some(EncryptionContext {
+                    nonce: vec![1; 12],
+                    tag: vec![2; 16],
+                    key_id: 0x1234,
+                }),
+                hash: 123,
+            },
+            block: 3,
+            on_disk_hash: 456,
+        };
+        region.extents[0].inner().set_block_contexts(&[&ctx])?;
+        region.extents[0].close()?;
+
+        let mut path = extent_path(&dir, 0);
+        path.set_extension("db");
+        let metadb = Connection::open(&path)?;
+        metadb.execute("ALTER TABLE block_context DROP COLUMN key_id", [])?;
+        assert!(!has_key_id_column(&metadb)?);
+        drop(metadb);
+
+        region.reopen_extent(0)?;
+
+        let ctxs = region.extents[0].inner().get_block_contexts(3, 1)?;
+        assert_eq!(ctxs[0].len(), 1);
+        let ec = ctxs[0][0]
+            .block_context
+            .encryption_context
+            .as_ref()
+            .unwrap();
+        assert_eq!(ec.nonce, vec![1; 12]);
+        assert_eq!(ec.key_id, 0);
+
+        let metadb = Connection::open(&path)?;
+        assert!(has_key_id_column(&metadb)?);
+
+        Ok(())
+    }
+
     #[test]
     fn multiple_context() -> Result<()> {
         let dir = tempdir()?;
@@ -3039,6 +4347,7 @@ mod test {
                     encryption_context: Some(EncryptionContext {
                         nonce: [1, 2, 3].to_vec(),
                         tag: [4, 5, 6, 7].to_vec(),
+                        key_id: 0,
                     }),
                     hash: 123,
                 },
@@ -3050,6 +4359,7 @@ mod test {
                     encryption_context: Some(EncryptionContext {
                         nonce: [4, 5, 6].to_vec(),
                         tag: [8, 9, 0, 1].to_vec(),
+                        key_id: 0,
                     }),
                     hash: 9999,
                 },
@@ -3171,6 +4481,7 @@ mod test {
                                 .gen::<[u8; 32]>()
                                 .to_vec(),
                             tag: rand::thread_rng().gen::<[u8; 32]>().to_vec(),
+                            key_id: 0,
                         }),
                         hash: rand::thread_rng().gen::<u64>(),
                     },
@@ -3184,6 +4495,7 @@ mod test {
                                 .gen::<[u8; 32]>()
                                 .to_vec(),
                             tag: rand::thread_rng().gen::<[u8; 32]>().to_vec(),
+                            key_id: 0,
                         }),
                         hash: rand::thread_rng().gen::<u64>(),
                     },
@@ -3386,6 +4698,7 @@ mod test {
                         crucible_protocol::EncryptionContext {
                             nonce: vec![1, 2, 3],
                             tag: vec![4, 5, 6],
+                            key_id: 0,
                         },
                     ),
                     hash: 5061083712412462836,
@@ -3420,6 +4733,7 @@ mod test {
                         crucible_protocol::EncryptionContext {
                             nonce: vec![1, 2, 3],
                             tag: vec![4, 5, 6],
+                            key_id: 0,
                         },
                     ),
                     hash: 4798852240582462654, // Hash for all 9's
@@ -3471,6 +4785,7 @@ mod test {
                         crucible_protocol::EncryptionContext {
                             nonce: vec![1, 2, 3],
                             tag: vec![4, 5, 6],
+                            key_id: 0,
                         },
                     ),
                     hash: 4798852240582462654, // Hash for all 9's
@@ -3491,6 +4806,7 @@ mod test {
                         crucible_protocol::EncryptionContext {
                             nonce: vec![1, 2, 3],
                             tag: vec![4, 5, 6],
+                            key_id: 0,
                         },
                     ),
                     hash: 5061083712412462836, // hash for all 1s
@@ -3515,6 +4831,76 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_write_unwritten_after_discard() -> Result<()> {
+        // Verify that a discarded block reads back as zero with no block
+        // contexts, and that a write_unwritten to it then lands.
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options(), csl())?;
+        region.extend(1)?;
+
+        let eid = 0;
+        let offset = Block::new_512(0);
+
+        // Write the block
+        let data = BytesMut::from(&[9u8; 512][..]);
+        let hash = integrity_hash(&[&data[..]]);
+        let writes: Vec<crucible_protocol::Write> =
+            vec![crucible_protocol::Write {
+                eid,
+                offset,
+                data: data.freeze(),
+                block_context: BlockContext {
+                    encryption_context: None,
+                    hash,
+                },
+            }];
+        region.region_write(&writes, 0, false)?;
+
+        // Discard it
+        region.region_discard(
+            &[crucible_protocol::DiscardRequest { eid, offset }],
+            1,
+        )?;
+
+        // The dirty bit stays set until the next flush.
+        let inner = &region.extents[eid as usize].inner.as_ref().unwrap();
+        assert!(inner.lock().unwrap().dirty().unwrap());
+
+        let responses = region.region_read(
+            &[crucible_protocol::ReadRequest { eid, offset }],
+            2,
+        )?;
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].hashes().is_empty());
+        assert_eq!(responses[0].data[..], [0u8; 512][..]);
+
+        // A write_unwritten should now land
+        let data = BytesMut::from(&[1u8; 512][..]);
+        let hash = integrity_hash(&[&data[..]]);
+        let writes: Vec<crucible_protocol::Write> =
+            vec![crucible_protocol::Write {
+                eid,
+                offset,
+                data: data.freeze(),
+                block_context: BlockContext {
+                    encryption_context: None,
+                    hash,
+                },
+            }];
+        region.region_write(&writes, 3, true)?;
+
+        let responses = region.region_read(
+            &[crucible_protocol::ReadRequest { eid, offset }],
+            4,
+        )?;
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].hashes(), vec![hash]);
+        assert_eq!(responses[0].data[..], [1u8; 512][..]);
+
+        Ok(())
+    }
+
     #[test]
     fn test_write_unwritten_when_written_flush() -> Result<()> {
         // Verify that a read fill does not write to the block when
@@ -3540,6 +4926,7 @@ mod test {
                         crucible_protocol::EncryptionContext {
                             nonce: vec![1, 2, 3],
                             tag: vec![4, 5, 6],
+                            key_id: 0,
                         },
                     ),
                     hash: 4798852240582462654, // Hash for all 9s
@@ -3573,6 +4960,7 @@ mod test {
                         crucible_protocol::EncryptionContext {
                             nonce: vec![1, 2, 3],
                             tag: vec![4, 5, 6],
+                            key_id: 0,
                         },
                     ),
                     hash: 5061083712412462836, // hash for all 1s
@@ -3715,6 +5103,7 @@ mod test {
                         crucible_protocol::EncryptionContext {
                             nonce: vec![1, 2, 3],
                             tag: vec![4, 5, 6],
+                            key_id: 0,
                         },
                     ),
                     hash: 4798852240582462654, // Hash for all 9s
@@ -3829,6 +5218,7 @@ mod test {
                         crucible_protocol::EncryptionContext {
                             nonce: vec![1, 2, 3],
                             tag: vec![4, 5, 6],
+                            key_id: 0,
                         },
                     ),
                     hash: 4798852240582462654, // Hash for all 9s,
@@ -3947,6 +5337,7 @@ mod test {
                         crucible_protocol::EncryptionContext {
                             nonce: vec![1, 2, 3],
                             tag: vec![4, 5, 6],
+                            key_id: 0,
                         },
                     ),
                     hash: 4798852240582462654, // Hash for all 9s
@@ -4055,6 +5446,7 @@ mod test {
                             crucible_protocol::EncryptionContext {
                                 nonce: vec![1, 2, 3],
                                 tag: vec![4, 5, 6],
+                                key_id: 0,
                             },
                         ),
                         hash: 4798852240582462654, // Hash for all 9s
@@ -4149,6 +5541,7 @@ mod test {
                         crucible_protocol::EncryptionContext {
                             nonce: vec![1, 2, 3],
                             tag: vec![4, 5, 6],
+                            key_id: 0,
                         },
                     ),
                     hash: 2398419238764,
@@ -4191,4 +5584,672 @@ mod test {
 
         Ok(())
     }
+
+    fn new_raw_region_options() -> crucible_common::RegionOptions {
+        let mut region_options = new_region_options();
+        region_options.set_extent_version(EXTENT_VERSION_RAW);
+        region_options
+    }
+
+    #[test]
+    fn raw_extent_is_one_file() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_raw_region_options(), csl())?;
+        region.extend(2)?;
+
+        let mut files: Vec<String> = std::fs::read_dir(extent_dir(&dir, 1))?
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["000", "001"]);
+        assert!(validate_repair_files(1, &["001".to_string()]));
+
+        for eid in 0..2 {
+            assert_eq!(
+                region.extents[eid].inner().ext_version(),
+                EXTENT_VERSION_RAW
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_extent_reopen() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_raw_region_options(), csl())?;
+        region.extend(2)?;
+
+        write_block(&mut region, 1, 7)?;
+        assert_eq!(region.dirty()?, vec![false, true]);
+        region.region_flush(3, 2, &None, 1)?;
+        write_block(&mut region, 1, 8)?;
+        drop(region);
+
+        // The dirty bit and the contexts of the unflushed write survive
+        // the reopen, along with the flush and gen numbers.
+        let region =
+            Region::open(&dir, new_raw_region_options(), true, false, &csl())?;
+        assert_eq!(region.def().extent_version(), EXTENT_VERSION_RAW);
+        assert_eq!(region.flush_numbers()?, vec![0, 3]);
+        assert_eq!(region.gen_numbers()?, vec![0, 2]);
+        assert_eq!(region.dirty()?, vec![false, true]);
+        assert_eq!(read_block(&region, 1)?, vec![8; 512]);
+        assert_eq!(
+            region.extents[1].inner().get_block_contexts(0, 1)?[0].len(),
+            2
+        );
+
+        region.region_flush(4, 2, &None, 2)?;
+        assert_eq!(region.dirty()?, vec![false, false]);
+        let ctxs = region.extents[1].inner().get_block_contexts(0, 1)?;
+        assert_eq!(ctxs[0].len(), 1);
+        assert_eq!(
+            ctxs[0][0].block_context.hash,
+            integrity_hash(&[&[8u8; 512][..]])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_extent_rewrite_before_flush() -> Result<()> {
+        // Only two slots per block: writing the same block over and over
+        // between flushes must always keep the context of the data that
+        // is on disk.
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_raw_region_options(), csl())?;
+        region.extend(1)?;
+
+        for val in 1..=5u8 {
+            write_block(&mut region, 0, val)?;
+
+            let ctxs = region.extents[0].inner().get_block_contexts(0, 1)?;
+            assert!(ctxs[0].len() <= 2);
+            let hash = integrity_hash(&[&[val; 512][..]]);
+            assert!(ctxs[0].iter().any(|c| c.block_context.hash == hash));
+            assert_eq!(read_block(&region, 0)?, vec![val; 512]);
+        }
+
+        region.region_flush(1, 1, &None, 6)?;
+        let ctxs = region.extents[0].inner().get_block_contexts(0, 1)?;
+        assert_eq!(ctxs[0].len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_extent_same_write_before_flush() -> Result<()> {
+        // The same data written again and again gives the same context
+        // each time, which must not use up the block's slots.
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_raw_region_options(), csl())?;
+        region.extend(1)?;
+
+        for _ in 0..3 {
+            write_block(&mut region, 0, 9)?;
+            assert_eq!(read_block(&region, 0)?, vec![9; 512]);
+        }
+        let ctxs = region.extents[0].inner().get_block_contexts(0, 1)?;
+        assert_eq!(ctxs[0].len(), 1);
+
+        // Something new still finds a slot.
+        write_block(&mut region, 0, 10)?;
+        assert_eq!(read_block(&region, 0)?, vec![10; 512]);
+
+        region.region_flush(1, 1, &None, 5)?;
+        let ctxs = region.extents[0].inner().get_block_contexts(0, 1)?;
+        assert_eq!(ctxs[0].len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_extent_flush_removes_partial_writes() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_raw_region_options(), csl())?;
+        region.extend(1)?;
+
+        // A write of some sort only wrote a block context
+        region.extents[0].inner().set_block_contexts(&[
+            &DownstairsBlockContext {
+                block_context: BlockContext {
+                    encryption_context: None,
+                    hash: 1024,
+                },
+                block: 3,
+                on_disk_hash: 65536,
+            },
+        ])?;
+        assert!(region.extents[0].inner().has_block_contexts()?);
+
+        region.region_flush(1, 1, &None, 123)?;
+
+        let inner = region.extents[0].inner();
+        assert!(inner.get_block_contexts(3, 1)?[0].is_empty());
+        assert!(!inner.has_block_contexts()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_extent_encryption_context() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_raw_region_options(), csl())?;
+        region.extend(1)?;
+
+        let mut inner = region.extents[0].inner();
+        let ctx = DownstairsBlockContext {
+            block_context: BlockContext {
+                encryption_context: Some(
+                    crucible_protocol::EncryptionContext {
+                        nonce: vec![1; 12],
+                        tag: vec![2; 16],
+                        key_id: 0x1234,
+                    },
+                ),
+                hash: 123,
+            },
+            block: 9,
+            on_disk_hash: 456,
+        };
+        inner.set_block_contexts(&[&ctx])?;
+
+        let ctxs = inner.get_block_contexts(8, 2)?;
+        assert!(ctxs[0].is_empty());
+        assert_eq!(ctxs[1].len(), 1);
+        assert_eq!(ctxs[1][0].block, 9);
+        assert_eq!(ctxs[1][0].on_disk_hash, 456);
+        assert_eq!(ctxs[1][0].block_context, ctx.block_context);
+
+        // A nonce that won't fit in a slot is refused
+        let bad = DownstairsBlockContext {
+            block_context: BlockContext {
+                encryption_context: Some(
+                    crucible_protocol::EncryptionContext {
+                        nonce: vec![1; 3],
+                        tag: vec![2; 16],
+                        key_id: 0,
+                    },
+                ),
+                hash: 123,
+            },
+            block: 1,
+            on_disk_hash: 456,
+        };
+        assert!(inner.set_block_contexts(&[&bad]).is_err());
+        assert!(inner.get_block_contexts(1, 1)?[0].is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_extent_discard() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_raw_region_options(), csl())?;
+        region.extend(1)?;
+
+        write_block(&mut region, 0, 3)?;
+        region.region_discard(
+            &[crucible_protocol::DiscardRequest {
+                eid: 0,
+                offset: Block::new_512(0),
+            }],
+            1,
+        )?;
+
+        let responses = region.region_read(
+            &[crucible_protocol::ReadRequest {
+                eid: 0,
+                offset: Block::new_512(0),
+            }],
+            2,
+        )?;
+        assert!(responses[0].hashes().is_empty());
+        assert_eq!(responses[0].data[..], [0u8; 512][..]);
+        assert!(!region.extents[0].inner().has_block_contexts()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_extent_bad_version() {
+        let mut options = new_region_options();
+        options.set_extent_version(EXTENT_VERSION_COMPRESSED + 1);
+        let dir = tempdir().unwrap();
+        assert!(Region::create(&dir, options, csl()).is_err());
+    }
+
+    fn new_compressed_region_options() -> crucible_common::RegionOptions {
+        let mut region_options = new_region_options();
+        region_options.set_extent_version(EXTENT_VERSION_COMPRESSED);
+        region_options
+    }
+
+    fn extent_file_len(dir: &Path, eid: u32) -> u64 {
+        std::fs::metadata(extent_path(dir, eid)).unwrap().len()
+    }
+
+    #[test]
+    fn compressed_extent_is_one_file() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region =
+            Region::create(&dir, new_compressed_region_options(), csl())?;
+        region.extend(2)?;
+
+        let mut files: Vec<String> = std::fs::read_dir(extent_dir(&dir, 1))?
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["000", "001"]);
+
+        for eid in 0..2 {
+            assert_eq!(
+                region.extents[eid].inner().ext_version(),
+                EXTENT_VERSION_COMPRESSED
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_extent_mixed_blocks() -> Result<()> {
+        // Zeros, repeated bytes and random bytes all read back the same,
+        // before and after a flush and a reopen.
+        let dir = tempdir()?;
+        let mut region =
+            Region::create(&dir, new_compressed_region_options(), csl())?;
+        region.extend(1)?;
+
+        let mut rng = rand::thread_rng();
+        let blocks: Vec<Vec<u8>> = (0..10)
+            .map(|i| match i % 3 {
+                0 => vec![0; 512],
+                1 => vec![i as u8; 512],
+                _ => (0..512).map(|_| rng.gen()).collect(),
+            })
+            .collect();
+
+        let writes: Vec<crucible_protocol::Write> = blocks
+            .iter()
+            .enumerate()
+            .map(|(i, data)| crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(i as u64),
+                data: Bytes::from(data.clone()),
+                block_context: BlockContext {
+                    encryption_context: None,
+                    hash: integrity_hash(&[data]),
+                },
+            })
+            .collect();
+        region.region_write(&writes, 1, false)?;
+
+        let requests: Vec<crucible_protocol::ReadRequest> = (0..10)
+            .map(|i| crucible_protocol::ReadRequest {
+                eid: 0,
+                offset: Block::new_512(i),
+            })
+            .collect();
+        let check = |region: &Region| -> Result<()> {
+            let responses = region.region_read(&requests, 2)?;
+            for (resp, data) in responses.iter().zip(blocks.iter()) {
+                assert_eq!(&resp.data[..], &data[..]);
+                assert_eq!(resp.hashes(), vec![integrity_hash(&[data])]);
+            }
+            Ok(())
+        };
+
+        check(&region)?;
+        region.region_flush(1, 1, &None, 3)?;
+        check(&region)?;
+        drop(region);
+
+        let region = Region::open(
+            &dir,
+            new_compressed_region_options(),
+            true,
+            false,
+            &csl(),
+        )?;
+        check(&region)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_extent_uses_less_space() -> Result<()> {
+        // A 4096 byte block of one repeated byte fits in one sector, and
+        // a block of zeros takes no space at all.
+        let mut options = new_compressed_region_options();
+        options.set_block_size(4096);
+        options.set_extent_size(Block::new(10, 12));
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, options, csl())?;
+        region.extend(1)?;
+        let empty = extent_file_len(dir.path(), 0);
+
+        let writes: Vec<crucible_protocol::Write> = (0..10)
+            .map(|i| {
+                let data = vec![(i % 2) as u8; 4096];
+                crucible_protocol::Write {
+                    eid: 0,
+                    offset: Block::new(i, 12),
+                    block_context: BlockContext {
+                        encryption_context: None,
+                        hash: integrity_hash(&[&data]),
+                    },
+                    data: Bytes::from(data),
+                }
+            })
+            .collect();
+        region.region_write(&writes, 1, false)?;
+        region.region_flush(1, 1, &None, 2)?;
+
+        assert_eq!(extent_file_len(dir.path(), 0) - empty, 5 * 512);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_extent_reuses_space() -> Result<()> {
+        // The data a flush left on disk is kept until the next flush, but
+        // then its space is reused, so rewriting a block never takes more
+        // than one extra sector.
+        let dir = tempdir()?;
+        let mut region =
+            Region::create(&dir, new_compressed_region_options(), csl())?;
+        region.extend(1)?;
+
+        write_block(&mut region, 0, 1)?;
+        region.region_flush(1, 1, &None, 1)?;
+        let len = extent_file_len(dir.path(), 0);
+
+        for val in 2..=6u8 {
+            write_block(&mut region, 0, val)?;
+            write_block(&mut region, 0, val + 10)?;
+            region.region_flush(val as u64, 1, &None, val as u64)?;
+            assert!(extent_file_len(dir.path(), 0) <= len + 512);
+            assert_eq!(read_block(&region, 0)?, vec![val + 10; 512]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_extent_unflushed_write_lost_on_reopen() -> Result<()> {
+        // Only the data as of the last flush survives a reopen, but the
+        // contexts of the lost write are still there until the next flush
+        // clears them out.
+        let dir = tempdir()?;
+        let mut region =
+            Region::create(&dir, new_compressed_region_options(), csl())?;
+        region.extend(2)?;
+
+        write_block(&mut region, 1, 7)?;
+        region.region_flush(3, 2, &None, 1)?;
+        write_block(&mut region, 1, 8)?;
+        drop(region);
+
+        let region = Region::open(
+            &dir,
+            new_compressed_region_options(),
+            true,
+            false,
+            &csl(),
+        )?;
+        assert_eq!(region.def().extent_version(), EXTENT_VERSION_COMPRESSED);
+        assert_eq!(region.flush_numbers()?, vec![0, 3]);
+        assert_eq!(region.gen_numbers()?, vec![0, 2]);
+        assert_eq!(region.dirty()?, vec![false, true]);
+        assert_eq!(read_block(&region, 1)?, vec![7; 512]);
+        assert_eq!(
+            region.extents[1].inner().get_block_contexts(0, 1)?[0].len(),
+            2
+        );
+
+        region.region_flush(4, 2, &None, 2)?;
+        assert_eq!(region.dirty()?, vec![false, false]);
+        let ctxs = region.extents[1].inner().get_block_contexts(0, 1)?;
+        assert_eq!(ctxs[0].len(), 1);
+        assert_eq!(
+            ctxs[0][0].block_context.hash,
+            integrity_hash(&[&[7u8; 512][..]])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_extent_rewrite_before_flush() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region =
+            Region::create(&dir, new_compressed_region_options(), csl())?;
+        region.extend(1)?;
+
+        for val in 1..=5u8 {
+            write_block(&mut region, 0, val)?;
+
+            let ctxs = region.extents[0].inner().get_block_contexts(0, 1)?;
+            assert!(ctxs[0].len() <= 2);
+            let hash = integrity_hash(&[&[val; 512][..]]);
+            assert!(ctxs[0].iter().any(|c| c.block_context.hash == hash));
+            assert_eq!(read_block(&region, 0)?, vec![val; 512]);
+        }
+
+        region.region_flush(1, 1, &None, 6)?;
+        let ctxs = region.extents[0].inner().get_block_contexts(0, 1)?;
+        assert_eq!(ctxs[0].len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_extent_same_write_before_flush() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region =
+            Region::create(&dir, new_compressed_region_options(), csl())?;
+        region.extend(1)?;
+
+        for _ in 0..3 {
+            write_block(&mut region, 0, 9)?;
+            assert_eq!(read_block(&region, 0)?, vec![9; 512]);
+        }
+        let ctxs = region.extents[0].inner().get_block_contexts(0, 1)?;
+        assert_eq!(ctxs[0].len(), 1);
+
+        write_block(&mut region, 0, 10)?;
+        assert_eq!(read_block(&region, 0)?, vec![10; 512]);
+
+        region.region_flush(1, 1, &None, 5)?;
+        let ctxs = region.extents[0].inner().get_block_contexts(0, 1)?;
+        assert_eq!(ctxs[0].len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_extent_discard() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region =
+            Region::create(&dir, new_compressed_region_options(), csl())?;
+        region.extend(1)?;
+        let empty = extent_file_len(dir.path(), 0);
+
+        write_block(&mut region, 0, 3)?;
+        region.region_flush(1, 1, &None, 1)?;
+        assert!(extent_file_len(dir.path(), 0) > empty);
+
+        region.region_discard(
+            &[crucible_protocol::DiscardRequest {
+                eid: 0,
+                offset: Block::new_512(0),
+            }],
+            2,
+        )?;
+
+        // The discarded block takes no space, and reads back as zeros
+        // with no contexts.
+        assert_eq!(extent_file_len(dir.path(), 0), empty);
+        assert_eq!(read_block(&region, 0)?, vec![0; 512]);
+        assert!(!region.extents[0].inner().has_block_contexts()?);
+
+        Ok(())
+    }
+
+    fn write_blocks(
+        region: &mut Region,
+        eid: u64,
+        blocks: &[(u64, u8)],
+    ) -> Result<()> {
+        let writes: Vec<crucible_protocol::Write> = blocks
+            .iter()
+            .map(|(block, val)| {
+                let data = BytesMut::from(&[*val; 512][..]);
+                crucible_protocol::Write {
+                    eid,
+                    offset: Block::new_512(*block),
+                    block_context: BlockContext {
+                        encryption_context: None,
+                        hash: integrity_hash(&[&data[..]]),
+                    },
+                    data: data.freeze(),
+                }
+            })
+            .collect();
+        region.region_write(&writes, 0, false)?;
+        Ok(())
+    }
+
+    #[test]
+    fn block_runs_join_neighbours() {
+        assert_eq!(block_runs(&[], 4), vec![]);
+        assert_eq!(
+            block_runs(&[0, 1, 2, 5, 7, 8], 4),
+            vec![(0, 3), (5, 1), (7, 2)]
+        );
+        // A run is never longer than max
+        assert_eq!(
+            block_runs(&[1, 2, 3, 4, 5, 6, 7], 3),
+            vec![(1, 3), (4, 3), (7, 1)]
+        );
+    }
+
+    #[test]
+    fn block_hashes_find_different_blocks() -> Result<()> {
+        let dir_a = tempdir()?;
+        let mut a = Region::create(&dir_a, new_region_options(), csl())?;
+        a.extend(1)?;
+        let dir_b = tempdir()?;
+        let mut b = Region::create(&dir_b, new_region_options(), csl())?;
+        b.extend(1)?;
+
+        write_blocks(&mut a, 0, &[(1, 1), (2, 2), (3, 3)])?;
+        write_blocks(&mut b, 0, &[(1, 1), (2, 9), (4, 0)])?;
+        a.region_flush(1, 1, &None, 1)?;
+        b.region_flush(1, 1, &None, 1)?;
+
+        let a_hashes = a.extents[0].block_hashes()?;
+        let b_hashes = b.extents[0].block_hashes()?;
+        assert_eq!(a_hashes.len(), 10);
+
+        // Block 2 has other data, block 3 is only written on a, and
+        // block 4 is written with zeros on b, which only its block
+        // context tells apart from unwritten.
+        let differ: Vec<usize> =
+            (0..10).filter(|i| a_hashes[*i] != b_hashes[*i]).collect();
+        assert_eq!(differ, vec![2, 3, 4]);
+
+        Ok(())
+    }
+
+    /*
+     * Repair extent 1 of b from a, block by block, the way
+     * repair_extent_blocks does, but without a repair server in between.
+     */
+    fn repair_blocks_from(a: &Region, b: &mut Region) -> Result<usize> {
+        b.extents[1].close()?;
+
+        let local = Extent::open_closed(&b.dir, &b.def, 1, &b.log)?;
+        let theirs = a.extents[1].block_hashes()?;
+        let differ: Vec<u64> = local
+            .block_hashes()?
+            .iter()
+            .zip(theirs.iter())
+            .enumerate()
+            .filter(|(_, (ours, theirs))| ours != theirs)
+            .map(|(block, _)| block as u64)
+            .collect();
+        drop(local);
+
+        let (copy_dir, copy) = b.clone_extent_to_copy_dir(1)?;
+        for (block, count) in block_runs(&differ, 2) {
+            let mut data = vec![0u8; count as usize * 512];
+            let mut source = a.extents[1].inner();
+            source.read_data(block * 512, &mut data)?;
+            let ctxs: Vec<DownstairsBlockContext> = source
+                .get_block_contexts(block, count)?
+                .into_iter()
+                .flatten()
+                .collect();
+            drop(source);
+            copy.replace_blocks(block, &data, &ctxs)?;
+        }
+
+        let source = a.extents[1].inner();
+        let (flush, gen, dirty) = (
+            source.flush_number()?,
+            source.gen_number()?,
+            source.dirty()?,
+        );
+        drop(source);
+        b.replace_extent_with_copy(1, copy_dir, copy, flush, gen, dirty)?;
+        b.reopen_extent(1)?;
+
+        Ok(differ.len())
+    }
+
+    #[test]
+    fn repair_only_different_blocks() -> Result<()> {
+        for options in &[
+            new_region_options(),
+            new_raw_region_options(),
+            new_compressed_region_options(),
+        ] {
+            let dir_a = tempdir()?;
+            let mut a = Region::create(&dir_a, options.clone(), csl())?;
+            a.extend(2)?;
+            let dir_b = tempdir()?;
+            let mut b = Region::create(&dir_b, options.clone(), csl())?;
+            b.extend(2)?;
+
+            // b missed the last flush, and has since been written to
+            // without a's knowing.
+            let blocks: Vec<(u64, u8)> = (0..10).map(|b| (b, 1)).collect();
+            write_blocks(&mut a, 1, &blocks)?;
+            write_blocks(&mut b, 1, &blocks)?;
+            b.region_flush(2, 1, &None, 1)?;
+            write_blocks(&mut a, 1, &[(3, 5), (4, 5), (8, 5)])?;
+            a.region_flush(3, 2, &None, 2)?;
+            write_blocks(&mut b, 1, &[(6, 9)])?;
+
+            assert_eq!(repair_blocks_from(&a, &mut b)?, 4);
+
+            assert_eq!(
+                b.extents[1].block_hashes()?,
+                a.extents[1].block_hashes()?
+            );
+            assert_eq!(b.flush_numbers()?, vec![0, 3]);
+            assert_eq!(b.gen_numbers()?, vec![0, 2]);
+            assert_eq!(b.dirty()?, vec![false, false]);
+            assert!(!copy_dir(&dir_b, 1).exists());
+            assert!(!replace_dir(&dir_b, 1).exists());
+
+            // Repairing again finds nothing to copy
+            assert_eq!(repair_blocks_from(&a, &mut b)?, 0);
+        }
+
+        Ok(())
+    }
 }
@@ -2,6 +2,7 @@
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use crucible_common::RegionDefinition;
 use dropshot::ApiDescription;
 use dropshot::ConfigDropshot;
 use dropshot::HttpError;
@@ -12,16 +13,23 @@ use dropshot::{endpoint, Path};
 use http::{Response, StatusCode};
 use hyper::Body;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use super::*;
-use crate::region::{extent_dir, extent_file_name, extent_path, ExtentType};
+use crate::extent_inner_compressed::is_compressed_extent;
+use crate::extent_inner_raw::is_raw_extent;
+use crate::region::{
+    extent_dir, extent_file_name, extent_path, DownstairsBlockContext, Extent,
+    ExtentType,
+};
 
 /**
- * Our context is the root of the region we want to serve.
+ * Our context is the root of the region we want to serve, and the shape
+ * of the extents in it.
  */
 pub struct FileServerContext {
     region_dir: PathBuf,
+    region_def: RegionDefinition,
 }
 
 pub fn write_openapi<W: Write>(f: &mut W) -> Result<()> {
@@ -34,6 +42,9 @@ fn build_api() -> ApiDescription<FileServerContext> {
     let mut api = ApiDescription::new();
     api.register(get_extent_file).unwrap();
     api.register(get_files_for_extent).unwrap();
+    api.register(get_extent_blocks).unwrap();
+    api.register(get_extent_block_data).unwrap();
+    api.register(get_extent_block_contexts).unwrap();
 
     api
 }
@@ -64,9 +75,13 @@ pub async fn repair_main(
      */
     let ds = ds.lock().await;
     let region_dir = ds.region.dir.clone();
+    let region_def = ds.region.def();
     drop(ds);
 
-    let context = FileServerContext { region_dir };
+    let context = FileServerContext {
+        region_dir,
+        region_def,
+    };
 
     info!(log, "Repair listens on {}", addr);
     /*
@@ -221,19 +236,226 @@ async fn get_files_for_extent(
     }
 }
 
+/// The block hashes of an extent, along with its metadata.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct ExtentBlocks {
+    ext_version: u32,
+    gen_number: u64,
+    flush_number: u64,
+    dirty: bool,
+    /// A hash of each block's data and block contexts together.
+    block_hashes: Vec<u64>,
+}
+
+/// A block context, as sent to a downstairs repairing its copy of a block.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct RepairBlockContext {
+    block: u64,
+    hash: u64,
+    on_disk_hash: u64,
+    /// The nonce, tag and key ID are only there for an encrypted block.
+    nonce: Option<Vec<u8>>,
+    tag: Option<Vec<u8>>,
+    key_id: Option<u16>,
+}
+
+impl From<&DownstairsBlockContext> for RepairBlockContext {
+    fn from(ctx: &DownstairsBlockContext) -> Self {
+        let ec = ctx.block_context.encryption_context.as_ref();
+        RepairBlockContext {
+            block: ctx.block,
+            hash: ctx.block_context.hash,
+            on_disk_hash: ctx.on_disk_hash,
+            nonce: ec.map(|ec| ec.nonce.clone()),
+            tag: ec.map(|ec| ec.tag.clone()),
+            key_id: ec.map(|ec| ec.key_id),
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct BlockRange {
+    eid: u32,
+    block: u64,
+    count: u64,
+}
+
+/*
+ * Open an extent to serve its blocks.  The extent is closed in the
+ * downstairs while it is being repaired, so we open our own copy.
+ */
+fn open_extent(
+    rqctx: &RequestContext<FileServerContext>,
+    eid: u32,
+) -> Result<Extent, HttpError> {
+    let context = rqctx.context();
+    Extent::open_closed(
+        &context.region_dir,
+        &context.region_def,
+        eid,
+        &rqctx.log,
+    )
+    .map_err(|e| {
+        HttpError::for_bad_request(
+            None,
+            format!("Failed to open extent {}: {:#}", eid, e),
+        )
+    })
+}
+
+fn open_block_range(
+    rqctx: &RequestContext<FileServerContext>,
+    range: &BlockRange,
+) -> Result<Extent, HttpError> {
+    let extent_size = rqctx.context().region_def.extent_size().value;
+    if range.count == 0
+        || range.block >= extent_size
+        || range.count > extent_size - range.block
+    {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!(
+                "Blocks {}+{} are outside the extent",
+                range.block, range.count
+            ),
+        ));
+    }
+    open_extent(rqctx, range.eid)
+}
+
+/**
+ * Get a hash of every block of an extent.
+ *
+ * Each hash covers both the data and the block contexts of a block, so a
+ * downstairs can tell which of its blocks differ from ours.
+ */
+#[endpoint {
+    method = GET,
+    path = "/extent/{eid}/blocks",
+}]
+async fn get_extent_blocks(
+    rqctx: Arc<RequestContext<FileServerContext>>,
+    path: Path<Eid>,
+) -> Result<HttpResponseOk<ExtentBlocks>, HttpError> {
+    let eid = path.into_inner().eid;
+    let extent = open_extent(&rqctx, eid)?;
+
+    let blocks = || -> Result<ExtentBlocks> {
+        let block_hashes = extent.block_hashes()?;
+        let inner = extent.inner();
+        Ok(ExtentBlocks {
+            ext_version: inner.ext_version(),
+            gen_number: inner.gen_number()?,
+            flush_number: inner.flush_number()?,
+            dirty: inner.dirty()?,
+            block_hashes,
+        })
+    };
+
+    match blocks() {
+        Ok(blocks) => Ok(HttpResponseOk(blocks)),
+        Err(e) => Err(HttpError::for_internal_error(format!(
+            "Failed to hash extent {}: {:#}",
+            eid, e
+        ))),
+    }
+}
+
+/**
+ * Get the data of some blocks of an extent.
+ */
+#[endpoint {
+    method = GET,
+    path = "/extent/{eid}/blocks/{block}/{count}/data",
+}]
+async fn get_extent_block_data(
+    rqctx: Arc<RequestContext<FileServerContext>>,
+    path: Path<BlockRange>,
+) -> Result<Response<Body>, HttpError> {
+    let range = path.into_inner();
+    let extent = open_block_range(&rqctx, &range)?;
+
+    let block_size = rqctx.context().region_def.block_size();
+    let mut data = vec![0u8; (range.count * block_size) as usize];
+    extent
+        .inner()
+        .read_data(range.block * block_size, &mut data)
+        .map_err(|e| {
+            HttpError::for_internal_error(format!(
+                "Failed to read extent {}: {:#}",
+                range.eid, e
+            ))
+        })?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .body(Body::from(data))?)
+}
+
+/**
+ * Get the block contexts of some blocks of an extent.
+ */
+#[endpoint {
+    method = GET,
+    path = "/extent/{eid}/blocks/{block}/{count}/contexts",
+}]
+async fn get_extent_block_contexts(
+    rqctx: Arc<RequestContext<FileServerContext>>,
+    path: Path<BlockRange>,
+) -> Result<HttpResponseOk<Vec<RepairBlockContext>>, HttpError> {
+    let range = path.into_inner();
+    let extent = open_block_range(&rqctx, &range)?;
+
+    let block_contexts = extent
+        .inner()
+        .get_block_contexts(range.block, range.count)
+        .map_err(|e| {
+            HttpError::for_internal_error(format!(
+                "Failed to read extent {} block contexts: {:#}",
+                range.eid, e
+            ))
+        })?;
+
+    Ok(HttpResponseOk(
+        block_contexts
+            .iter()
+            .flatten()
+            .map(|ctx| ctx.into())
+            .collect(),
+    ))
+}
+
+fn is_single_file_extent(path: &std::path::Path) -> Result<bool> {
+    Ok(is_raw_extent(path)? || is_compressed_extent(path)?)
+}
+
 /**
  * Return the list of extent files we have in our region directory
  * that correspond to the given extent.  Return an error if any
  * of the required files are missing.
+ *
+ * A raw or compressed extent is only the data file, an SQLite extent
+ * also requires the .db file.
  */
 async fn extent_file_list(
     extent_dir: PathBuf,
     eid: u32,
 ) -> Result<Vec<String>, HttpError> {
+    let mut data_file = extent_dir.clone();
+    data_file.push(extent_file_name(eid, ExtentType::Data));
+    let single_file = data_file.exists()
+        && is_single_file_extent(&data_file).map_err(|e| {
+            HttpError::for_internal_error(format!(
+                "Failed to read {:?}: {:#}",
+                data_file, e
+            ))
+        })?;
+
     let mut files = Vec::new();
     let possible_files = vec![
         (extent_file_name(eid, ExtentType::Data), true),
-        (extent_file_name(eid, ExtentType::Db), true),
+        (extent_file_name(eid, ExtentType::Db), !single_file),
         (extent_file_name(eid, ExtentType::DbShm), false),
         (extent_file_name(eid, ExtentType::DbWal), false),
     ];
@@ -402,6 +624,38 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn extent_expected_files_raw() -> Result<()> {
+        // A raw extent is only the data file, and does not need a .db
+        let dir = tempdir()?;
+        let mut region_options = new_region_options();
+        region_options.set_extent_version(EXTENT_VERSION_RAW);
+        let mut region = Region::create(&dir, region_options, csl())?;
+        region.extend(3)?;
+
+        let ed = extent_dir(&dir, 1);
+        let ex_files = extent_file_list(ed, 1).await.unwrap();
+        assert_eq!(ex_files, vec!["001"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extent_expected_files_compressed() -> Result<()> {
+        // A compressed extent is also only the data file
+        let dir = tempdir()?;
+        let mut region_options = new_region_options();
+        region_options.set_extent_version(EXTENT_VERSION_COMPRESSED);
+        let mut region = Region::create(&dir, region_options, csl())?;
+        region.extend(3)?;
+
+        let ed = extent_dir(&dir, 1);
+        let ex_files = extent_file_list(ed, 1).await.unwrap();
+        assert_eq!(ex_files, vec!["001"]);
+
+        Ok(())
+    }
+
     #[test]
     fn test_crucible_repair_openapi() {
         let mut raw = Vec::new();
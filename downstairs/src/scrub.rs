@@ -0,0 +1,453 @@
+// Copyright 2022 Oxide Computer Company
+use super::*;
+
+use crate::region::ExtentScrubber;
+use crucible_common::{read_json_maybe, write_json};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/*
+ * The scrubber checks the data at rest.
+ *
+ * A block's on-disk hash is only looked at when an upstairs reads the
+ * block, so data that goes bad on the disk goes unnoticed until then.  The
+ * scrubber walks every block of the region in the background, a few at a
+ * time, and checks each written block against the on-disk hashes in its
+ * block contexts.  A block that matches none of them is bad.
+ *
+ * What the scrubber found, and how far it has got, is kept in the region
+ * directory, so it picks up where it left off when the downstairs starts
+ * again.  A bad block stays in the report until a later pass finds it
+ * good again, for instance after the upstairs has written it.
+ */
+
+/// The most blocks the scrubber checks at once.
+const SCRUB_CHUNK: u64 = 128;
+
+/// A block whose data matches none of its block contexts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BadBlock {
+    pub extent: u32,
+    pub block: u64,
+    /// When the scrubber first found it, in seconds since the Unix epoch.
+    pub found: u64,
+}
+
+/// What the scrubber has found so far.
+#[derive(
+    Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema,
+)]
+pub struct ScrubReport {
+    /// Passes over the whole region finished.
+    pub passes: u64,
+    /// The extent and block the current pass checks next.
+    pub extent: u32,
+    pub block: u64,
+    /// Blocks checked, over all passes.
+    pub blocks_checked: u64,
+    pub bad_blocks: Vec<BadBlock>,
+}
+
+impl ScrubReport {
+    fn path(dir: &Path) -> PathBuf {
+        dir.join("scrub.json")
+    }
+
+    /// Load the report kept in a region directory, or start a new one.
+    pub fn load(dir: &Path) -> Result<ScrubReport> {
+        Ok(read_json_maybe(ScrubReport::path(dir))?.unwrap_or_default())
+    }
+
+    fn save(&self, dir: &Path) -> Result<()> {
+        write_json(ScrubReport::path(dir), self, true)
+    }
+}
+
+/**
+ * The blocks of one extent the scrubber checks next.  The extent is only
+ * None when it is closed, in which case its blocks are skipped.
+ */
+pub struct ScrubChunk {
+    eid: u32,
+    block: u64,
+    count: u64,
+    extent: Option<ExtentScrubber>,
+}
+
+impl ScrubChunk {
+    /**
+     * Check the blocks, and return the bad ones, or None if the extent is
+     * closed.  This does not need the Downstairs.
+     */
+    pub fn check(&self) -> Result<Option<Vec<u64>>> {
+        match &self.extent {
+            Some(extent) => Ok(Some(extent.scrub(self.block, self.count)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Downstairs {
+    /**
+     * Scrub up to max_blocks more blocks of the region, without going past
+     * the end of an extent, and return how many were checked.
+     */
+    pub async fn scrub_step(&mut self, max_blocks: u64) -> Result<u64> {
+        let chunk = match self.scrub_next(max_blocks) {
+            Some(chunk) => chunk,
+            None => return Ok(0),
+        };
+        let bad = chunk.check()?;
+        self.scrub_record(chunk, bad).await
+    }
+
+    /**
+     * The next up to max_blocks blocks to scrub, or None if the region
+     * has no extents.  The rest of a closed extent is skipped at once.
+     */
+    pub fn scrub_next(&mut self, max_blocks: u64) -> Option<ScrubChunk> {
+        let def = self.region.def();
+        let extent_size = def.extent_size().value;
+
+        let extent_count = def.extent_count();
+        if extent_count == 0 {
+            return None;
+        }
+
+        // The region may have shrunk since this was saved.
+        if self.scrub.extent >= extent_count {
+            self.scrub.extent = 0;
+            self.scrub.block = 0;
+        }
+
+        let eid = self.scrub.extent;
+        let block = self.scrub.block;
+        let extent = self.region.extents[eid as usize].scrubber();
+        let count = match extent {
+            Some(_) => std::cmp::min(max_blocks, extent_size - block),
+            None => extent_size - block,
+        };
+
+        Some(ScrubChunk {
+            eid,
+            block,
+            count,
+            extent,
+        })
+    }
+
+    /**
+     * Record what checking a chunk found, and move on past it.  Returns
+     * how many blocks were checked, which is none if the extent was closed
+     * or has been closed since, as then nothing is known about them.
+     */
+    pub async fn scrub_record(
+        &mut self,
+        chunk: ScrubChunk,
+        bad: Option<Vec<u64>>,
+    ) -> Result<u64> {
+        let def = self.region.def();
+        let extent_size = def.extent_size().value;
+        let extent_count = def.extent_count();
+
+        /*
+         * The region may have changed while the chunk was checked.  If so,
+         * or if the extent was reopened, the same blocks are tried again.
+         */
+        let ScrubChunk {
+            eid,
+            block,
+            count,
+            extent,
+        } = chunk;
+        if eid != self.scrub.extent || block != self.scrub.block {
+            return Ok(0);
+        }
+        let current = match &extent {
+            Some(extent) => {
+                (eid as usize) < self.region.extents.len()
+                    && extent.is_open_in(&self.region.extents[eid as usize])
+            }
+            None => true,
+        };
+        if !current {
+            return Ok(0);
+        }
+
+        let found = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut changed = false;
+        let mut new_bad = 0;
+        let mut checked = 0;
+        if let Some(bad) = bad {
+            checked = count;
+            for b in block..block + count {
+                let known = self
+                    .scrub
+                    .bad_blocks
+                    .iter()
+                    .position(|bb| bb.extent == eid && bb.block == b);
+                match (known, bad.contains(&b)) {
+                    (None, true) => {
+                        error!(
+                            self.log,
+                            "Scrub found bad block {} of extent {}", b, eid
+                        );
+                        self.scrub.bad_blocks.push(BadBlock {
+                            extent: eid,
+                            block: b,
+                            found,
+                        });
+                        new_bad += 1;
+                        changed = true;
+                    }
+                    (Some(i), false) => {
+                        info!(
+                            self.log,
+                            "Scrub found block {} of extent {} good again",
+                            b,
+                            eid
+                        );
+                        self.scrub.bad_blocks.remove(i);
+                        changed = true;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.scrub.blocks_checked += checked;
+        self.scrub.block += count;
+        if self.scrub.block >= extent_size {
+            self.scrub.extent += 1;
+            self.scrub.block = 0;
+            changed = true;
+        }
+
+        if self.scrub.extent >= extent_count {
+            self.scrub.passes += 1;
+            self.scrub.extent = 0;
+
+            // Forget blocks of extents the region no longer has.
+            self.scrub.bad_blocks.retain(|b| b.extent < extent_count);
+
+            info!(
+                self.log,
+                "Scrub pass {} done, {} bad blocks",
+                self.scrub.passes,
+                self.scrub.bad_blocks.len(),
+            );
+        }
+
+        if changed {
+            self.save_scrub()?;
+        }
+        self.dss.add_scrub(checked as i64, new_bad).await;
+
+        Ok(checked)
+    }
+
+    /**
+     * What the scrubber has found so far.
+     */
+    pub fn scrub_report(&self) -> ScrubReport {
+        self.scrub.clone()
+    }
+
+    fn save_scrub(&self) -> Result<()> {
+        // A read only region is likely on a read only file system.
+        if self.read_only {
+            return Ok(());
+        }
+        self.scrub.save(&self.region.dir)
+    }
+}
+
+/**
+ * Scrub the region in the background forever, checking no more than
+ * blocks_per_second blocks a second.
+ */
+pub async fn scrub_task(ds: Arc<Mutex<Downstairs>>, blocks_per_second: u64) {
+    let blocks_per_second = std::cmp::max(blocks_per_second, 1);
+    let chunk = std::cmp::min(SCRUB_CHUNK, blocks_per_second);
+    let log = ds.lock().await.log.new(o!("task" => "scrub".to_string()));
+    info!(log, "Scrubbing {} blocks a second", blocks_per_second);
+
+    loop {
+        /*
+         * The Downstairs is only locked to pick the blocks and record what
+         * was found, not while they are read.
+         */
+        let next = ds.lock().await.scrub_next(chunk);
+        let checked = match next {
+            Some(next) => match next.check() {
+                Ok(bad) => ds.lock().await.scrub_record(next, bad).await,
+                Err(e) => Err(e),
+            },
+            None => Ok(0),
+        };
+        let blocks = match checked {
+            Ok(blocks) => std::cmp::max(blocks, 1),
+            Err(e) => {
+                warn!(log, "Scrub failed: {:?}", e);
+                chunk
+            }
+        };
+
+        tokio::time::sleep(Duration::from_millis(
+            blocks * 1000 / blocks_per_second,
+        ))
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::extent_path;
+    use std::io::{Seek, SeekFrom};
+    use tempfile::tempdir;
+
+    fn csl() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+
+    // A region of two extents of four blocks, with block 1 of extent 1
+    // written.
+    fn scrub_region(dir: &Path) -> Result<Arc<Mutex<Downstairs>>> {
+        let mut region_options: crucible_common::RegionOptions =
+            Default::default();
+        region_options.set_block_size(512);
+        region_options.set_extent_size(Block::new_512(4));
+        region_options.set_uuid(Uuid::new_v4());
+
+        let mut region = Region::create(dir, region_options, csl())?;
+        region.extend(2)?;
+
+        let data = BytesMut::from(&[7u8; 512][..]);
+        region.region_write(
+            &[crucible_protocol::Write {
+                eid: 1,
+                offset: Block::new_512(1),
+                block_context: crucible_protocol::BlockContext {
+                    hash: integrity_hash(&[&data[..]]),
+                    encryption_context: None,
+                },
+                data: data.freeze(),
+            }],
+            0,
+            false,
+        )?;
+        region.region_flush(1, 1, &None, 0)?;
+        drop(region);
+
+        build_downstairs_for_region(dir, false, false, false, Some(csl()))
+    }
+
+    // Overwrite block 1 of extent 1 behind the downstairs' back.
+    fn corrupt(dir: &Path, val: u8) -> Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(extent_path(dir, 1))?;
+        file.seek(SeekFrom::Start(512))?;
+        file.write_all(&[val; 512])?;
+        Ok(())
+    }
+
+    async fn scrub_pass(ds: &Arc<Mutex<Downstairs>>) -> Result<()> {
+        let mut ds = ds.lock().await;
+        let passes = ds.scrub.passes;
+        while ds.scrub.passes == passes {
+            ds.scrub_step(3).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scrub_clean_region() -> Result<()> {
+        let dir = tempdir()?;
+        let ds = scrub_region(dir.path())?;
+
+        scrub_pass(&ds).await?;
+
+        let report = ds.lock().await.scrub_report();
+        assert_eq!(report.passes, 1);
+        assert_eq!(report.blocks_checked, 8);
+        assert!(report.bad_blocks.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scrub_finds_bad_block() -> Result<()> {
+        let dir = tempdir()?;
+        let ds = scrub_region(dir.path())?;
+        corrupt(dir.path(), 9)?;
+
+        scrub_pass(&ds).await?;
+
+        let report = ds.lock().await.scrub_report();
+        assert_eq!(report.bad_blocks.len(), 1);
+        assert_eq!(report.bad_blocks[0].extent, 1);
+        assert_eq!(report.bad_blocks[0].block, 1);
+
+        // A second pass does not report it twice
+        scrub_pass(&ds).await?;
+        assert_eq!(ds.lock().await.scrub_report().bad_blocks.len(), 1);
+
+        // Once the block is good again, it leaves the report
+        corrupt(dir.path(), 7)?;
+        scrub_pass(&ds).await?;
+        assert!(ds.lock().await.scrub_report().bad_blocks.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scrub_skips_closed_extent() -> Result<()> {
+        let dir = tempdir()?;
+        let ds = scrub_region(dir.path())?;
+        corrupt(dir.path(), 9)?;
+
+        scrub_pass(&ds).await?;
+        assert_eq!(ds.lock().await.scrub_report().bad_blocks.len(), 1);
+
+        // Nothing is known about the blocks of a closed extent, so the
+        // bad block found before stays in the report.
+        ds.lock().await.region.extents[1].close()?;
+        scrub_pass(&ds).await?;
+
+        let report = ds.lock().await.scrub_report();
+        assert_eq!(report.passes, 2);
+        assert_eq!(report.blocks_checked, 12);
+        assert_eq!(report.bad_blocks.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scrub_report_is_kept() -> Result<()> {
+        let dir = tempdir()?;
+        let ds = scrub_region(dir.path())?;
+        corrupt(dir.path(), 9)?;
+
+        // Stop part way through the first pass, after the bad block
+        {
+            let mut ds = ds.lock().await;
+            while ds.scrub.extent < 1 || ds.scrub.block < 2 {
+                ds.scrub_step(2).await?;
+            }
+        }
+        let report = ds.lock().await.scrub_report();
+        drop(ds);
+
+        assert_eq!(ScrubReport::load(dir.path())?, report);
+        assert_eq!(report.bad_blocks.len(), 1);
+
+        // A new downstairs for the region carries on from there.
+        let ds =
+            build_downstairs_for_region(dir.path(), false, false, false, None)?;
+        assert_eq!(ds.lock().await.scrub_report(), report);
+        Ok(())
+    }
+}
@@ -39,6 +39,24 @@ pub struct Flush {
     #[datum]
     pub count: Cumulative<i64>,
 }
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct CompressionSaved {
+    // Count of bytes compression kept off the network
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct BlocksScrubbed {
+    // Count of blocks the scrubber has checked
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct BadBlocks {
+    // Count of bad blocks the scrubber has found
+    #[datum]
+    pub count: Cumulative<i64>,
+}
 
 // All the counter stats in one struct.
 #[derive(Clone, Debug)]
@@ -48,6 +66,9 @@ pub struct DsCountStat {
     write_count: Write,
     read_count: Read,
     flush_count: Flush,
+    compression_saved: CompressionSaved,
+    blocks_scrubbed: BlocksScrubbed,
+    bad_blocks: BadBlocks,
 }
 
 impl DsCountStat {
@@ -58,6 +79,9 @@ impl DsCountStat {
             write_count: Default::default(),
             read_count: Default::default(),
             flush_count: Default::default(),
+            compression_saved: Default::default(),
+            blocks_scrubbed: Default::default(),
+            bad_blocks: Default::default(),
         }
     }
 }
@@ -95,6 +119,18 @@ impl DsStatOuter {
         let datum = dss.flush_count.datum_mut();
         *datum += 1;
     }
+    pub async fn add_compression_saved(&mut self, bytes: i64) {
+        let mut dss = self.ds_stat_wrap.lock().await;
+        let datum = dss.compression_saved.datum_mut();
+        *datum += bytes;
+    }
+    pub async fn add_scrub(&mut self, blocks: i64, bad_blocks: i64) {
+        let mut dss = self.ds_stat_wrap.lock().await;
+        let datum = dss.blocks_scrubbed.datum_mut();
+        *datum += blocks;
+        let datum = dss.bad_blocks.datum_mut();
+        *datum += bad_blocks;
+    }
 }
 
 // This trait is what is called to update the data to send to Oximeter.
@@ -108,13 +144,16 @@ impl Producer for DsStatOuter {
     ) -> Result<Box<dyn Iterator<Item = Sample> + 'static>, MetricsError> {
         let dss = executor::block_on(self.ds_stat_wrap.lock());
 
-        let mut data = Vec::with_capacity(4);
+        let mut data = Vec::with_capacity(7);
         let name = dss.stat_name;
 
         data.push(Sample::new(&name, &dss.up_connect_count));
         data.push(Sample::new(&name, &dss.flush_count));
         data.push(Sample::new(&name, &dss.write_count));
         data.push(Sample::new(&name, &dss.read_count));
+        data.push(Sample::new(&name, &dss.compression_saved));
+        data.push(Sample::new(&name, &dss.blocks_scrubbed));
+        data.push(Sample::new(&name, &dss.bad_blocks));
 
         // Yield the available samples.
         Ok(Box::new(data.into_iter()))
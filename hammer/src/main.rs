@@ -84,12 +84,20 @@ async fn main() -> Result<()> {
         target: opt.target,
         lossy: false,
         flush_timeout: None,
-        key: opt.key,
+        key: opt.key.map(KeyReference::inline),
+        old_key: None,
         cert_pem: opt.cert_pem,
         key_pem: opt.key_pem,
         root_cert_pem: opt.root_cert_pem,
         control: opt.control,
         read_only: false,
+        write_quorum: None,
+        dirty_extents: None,
+        block_record: None,
+        read_policy: None,
+        fault_timeout: None,
+        fault_queue: None,
+        qos: None,
     };
 
     if let Some(tracing_endpoint) = opt.tracing_endpoint {
@@ -53,6 +53,7 @@ mod test {
                     188, /* extent_count */
                     Uuid::new_v4(),
                     encrypted,
+                    EXTENT_VERSION_SQLITE,
                     csl(),
                 )?
             } else {
@@ -64,6 +65,7 @@ mod test {
                     2, /* extent_count */
                     Uuid::new_v4(),
                     encrypted,
+                    EXTENT_VERSION_SQLITE,
                     csl(),
                 )?
             };
@@ -171,12 +173,20 @@ mod test {
                 ],
                 lossy: false,
                 flush_timeout: None,
-                key: Some(key_string),
+                key: Some(KeyReference::inline(key_string)),
+                old_key: None,
                 cert_pem: None,
                 key_pem: None,
                 root_cert_pem: None,
                 control: None,
                 read_only,
+                write_quorum: None,
+                dirty_extents: None,
+                block_record: None,
+                read_policy: None,
+                fault_timeout: None,
+                fault_queue: None,
+                qos: None,
             };
 
             Ok(TestDownstairsSet {
@@ -227,6 +237,8 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume = Arc::new(Volume::construct(vcr, None).await?);
@@ -389,6 +401,8 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let mut volume = Volume::construct(vcr, None).await?;
@@ -479,8 +493,12 @@ mod test {
                             url: server.url("/ff.raw").to_string(),
                         }],
                         read_only_parent: None,
+                        stripe_blocks: None,
+                        scrub_state_dir: None,
                     },
                 )),
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume = Volume::construct(vcr, None).await?;
@@ -532,6 +550,8 @@ mod test {
                         gen: 1,
                     },
                 )),
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume = Volume::construct(vcr, None).await?;
@@ -577,6 +597,8 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume = Arc::new(Volume::construct(vcr, None).await?);
@@ -646,6 +668,8 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume = Arc::new(Volume::construct(vcr, None).await?);
@@ -717,6 +741,8 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume = Arc::new(Volume::construct(vcr, None).await?);
@@ -798,6 +824,8 @@ mod test {
                 block_size: BLOCK_SIZE as u64,
                 sub_volumes: sv,
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume = Arc::new(Volume::construct(vcr, None).await?);
@@ -880,6 +908,8 @@ mod test {
                 block_size: BLOCK_SIZE as u64,
                 sub_volumes: sv,
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume = Arc::new(Volume::construct(vcr, None).await?);
@@ -984,6 +1014,8 @@ mod test {
                 block_size: BLOCK_SIZE as u64,
                 sub_volumes: sv,
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume = Arc::new(Volume::construct(vcr, None).await?);
@@ -1496,6 +1528,8 @@ mod test {
                 block_size: BLOCK_SIZE as u64,
                 sub_volumes: sv,
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let mut volume = Volume::construct(vcr, None).await?;
@@ -1617,6 +1651,8 @@ mod test {
                 block_size: BLOCK_SIZE as u64,
                 sub_volumes: sv,
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let mut volume = Volume::construct(vcr, None).await?;
@@ -1714,6 +1750,8 @@ mod test {
                         gen: 1,
                     },
                 )),
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         // Second volume should have a unique UUID
@@ -1731,6 +1769,8 @@ mod test {
                         gen: 1,
                     },
                 )),
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume1 = Volume::construct(vcr_1, None).await?;
@@ -1909,8 +1949,12 @@ mod test {
                             gen: 3,
                         }],
                         read_only_parent: None,
+                        stripe_blocks: None,
+                        scrub_state_dir: None,
                     },
                 )),
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let volume = Volume::construct(vcr, None).await?;
@@ -1961,6 +2005,165 @@ mod test {
         Ok(())
     }
 
+    // Boot three downstairs, write fill over the blocks in range, then
+    // reboot them read only to be a read only parent.
+    async fn read_only_layer(
+        fill: u8,
+        range: std::ops::Range<u64>,
+    ) -> Result<TestDownstairsSet> {
+        const BLOCK_SIZE: usize = 512;
+
+        let mut tds = TestDownstairsSet::small(false).await?;
+        let mut volume = Volume::new(BLOCK_SIZE as u64);
+        volume
+            .add_subvolume_create_guest(tds.opts(), 1, None)
+            .await?;
+        volume.activate().await?;
+        volume
+            .write(
+                Block::new(range.start, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![
+                    fill;
+                    BLOCK_SIZE
+                        * (range.end - range.start) as usize
+                ]),
+            )
+            .await?;
+        volume.deactivate().await?;
+        drop(volume);
+
+        tds.reboot_read_only().await?;
+        Ok(tds)
+    }
+
+    // A volume of one region, with an optional read only parent.
+    fn layer_request(
+        id: Uuid,
+        opts: CrucibleOpts,
+        read_only_parent: Option<VolumeConstructionRequest>,
+    ) -> VolumeConstructionRequest {
+        VolumeConstructionRequest::Volume {
+            id,
+            block_size: 512,
+            sub_volumes: vec![VolumeConstructionRequest::Region {
+                block_size: 512,
+                opts,
+                gen: 1,
+            }],
+            read_only_parent: read_only_parent.map(Box::new),
+            stripe_blocks: None,
+            scrub_state_dir: None,
+        }
+    }
+
+    // Set the generation number of every region in the request.
+    fn set_gen(request: &mut VolumeConstructionRequest, new_gen: u64) {
+        if let VolumeConstructionRequest::Volume {
+            sub_volumes,
+            read_only_parent,
+            ..
+        } = request
+        {
+            for sub_volume in sub_volumes.iter_mut() {
+                set_gen(sub_volume, new_gen);
+            }
+            if let Some(parent) = read_only_parent {
+                set_gen(parent, new_gen);
+            }
+        } else if let VolumeConstructionRequest::Region { gen, .. } = request {
+            *gen = new_gen;
+        }
+    }
+
+    #[tokio::test]
+    async fn integration_test_flatten_parent_chain() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        // top:         3
+        //    a:     2 2 2 2
+        //    b: 1 1 1 1 1 1 1 1 1 1
+        let b = read_only_layer(1, 0..10).await?;
+        let a = read_only_layer(2, 2..6).await?;
+        let top = TestDownstairsSet::small(false).await?;
+
+        let (a_id, b_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut request = layer_request(
+            Uuid::new_v4(),
+            top.opts(),
+            Some(layer_request(
+                a_id,
+                a.opts(),
+                Some(layer_request(b_id, b.opts(), None)),
+            )),
+        );
+        set_gen(&mut request, 2);
+
+        let mut expected = vec![1; BLOCK_SIZE * 2];
+        expected.extend(vec![2; BLOCK_SIZE * 2]);
+        expected.extend(vec![3; BLOCK_SIZE]);
+        expected.extend(vec![2; BLOCK_SIZE]);
+        expected.extend(vec![1; BLOCK_SIZE * 4]);
+
+        {
+            let volume = Volume::construct(request.clone(), None).await?;
+            volume.activate().await?;
+            volume
+                .write(
+                    Block::new(4, BLOCK_SIZE.trailing_zeros()),
+                    Bytes::from(vec![3; BLOCK_SIZE]),
+                )
+                .await?;
+            volume.deactivate().await?;
+        }
+
+        // Flatten both parents into the top, one at a time.
+        set_gen(&mut request, 3);
+        let mut request = Volume::flatten(&csl(), request, a_id, None).await?;
+        set_gen(&mut request, 4);
+        let mut request = Volume::flatten(&csl(), request, b_id, None).await?;
+        assert!(matches!(
+            request,
+            VolumeConstructionRequest::Volume {
+                read_only_parent: None,
+                ..
+            }
+        ));
+
+        // The parents are gone, and the top reads the same on its own.
+        drop(a);
+        drop(b);
+        set_gen(&mut request, 5);
+        let volume = Volume::construct(request, None).await?;
+        volume.activate().await?;
+
+        let buffer = Buffer::new(BLOCK_SIZE * 10);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+        assert_eq!(expected, *buffer.as_vec().await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn integration_test_flatten_into_read_only_layer() -> Result<()> {
+        // The layer a parent is merged into has to take writes.
+        let parent = TestDownstairsSet::small(true).await?;
+        let child = TestDownstairsSet::small(true).await?;
+        let parent_id = Uuid::new_v4();
+        let request = layer_request(
+            Uuid::new_v4(),
+            child.opts(),
+            Some(layer_request(parent_id, parent.opts(), None)),
+        );
+
+        assert!(Volume::flatten(&csl(), request, parent_id, None)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
     // The following tests work at the "guest" layer. The volume
     // layers above (in general) will eventually call a BlockIO trait
     // on a guest layer.
@@ -2416,7 +2619,9 @@ mod test {
         let opts = tds.opts();
 
         // Start the pantry
-        let (log, pantry) = crucible_pantry::initialize_pantry().await.unwrap();
+        let (log, pantry) = crucible_pantry::initialize_pantry(None, None)
+            .await
+            .unwrap();
         let (pantry_addr, _join_handle) = crucible_pantry::server::run_server(
             &log,
             "127.0.0.1:0".parse().unwrap(),
@@ -2437,6 +2642,8 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let client =
@@ -2477,6 +2684,8 @@ mod test {
                             sha256: sha256_digest.to_string(),
                         },
                     ),
+                    format: None,
+                    target_is_zeroed: None,
                 },
             )
             .await
@@ -2523,6 +2732,8 @@ mod test {
                     gen: 2,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
         let volume = Volume::construct(vcr, None).await.unwrap();
         volume.activate().await.unwrap();
@@ -2555,7 +2766,9 @@ mod test {
         let opts = tds.opts();
 
         // Start the pantry
-        let (log, pantry) = crucible_pantry::initialize_pantry().await.unwrap();
+        let (log, pantry) = crucible_pantry::initialize_pantry(None, None)
+            .await
+            .unwrap();
         let (pantry_addr, _join_handle) = crucible_pantry::server::run_server(
             &log,
             "127.0.0.1:0".parse().unwrap(),
@@ -2576,6 +2789,8 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         let client =
@@ -2616,6 +2831,8 @@ mod test {
                             sha256: sha256_digest.to_string(),
                         },
                     ),
+                    format: None,
+                    target_is_zeroed: None,
                 },
             )
             .await
@@ -2670,6 +2887,8 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         // Verify contents are zero on init
@@ -2695,7 +2914,9 @@ mod test {
 
         // Start the pantry, then use it to import img.raw
 
-        let (log, pantry) = crucible_pantry::initialize_pantry().await.unwrap();
+        let (log, pantry) = crucible_pantry::initialize_pantry(None, None)
+            .await
+            .unwrap();
         let (pantry_addr, _join_handle) = crucible_pantry::server::run_server(
             &log,
             "127.0.0.1:0".parse().unwrap(),
@@ -2717,6 +2938,8 @@ mod test {
                     gen: 2,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
         client
             .attach(
@@ -2743,6 +2966,8 @@ mod test {
                 &crucible_pantry_client::types::ImportFromUrlRequest {
                     url: server.url("/img.raw").to_string(),
                     expected_digest: None,
+                    format: None,
+                    target_is_zeroed: None,
                 },
             )
             .await
@@ -2765,6 +2990,8 @@ mod test {
                     gen: 3,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
         let volume = Volume::construct(vcr, None).await.unwrap();
         volume.activate().await.unwrap();
@@ -2799,11 +3026,15 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         // Start the pantry, then use it to snapshot
 
-        let (log, pantry) = crucible_pantry::initialize_pantry().await.unwrap();
+        let (log, pantry) = crucible_pantry::initialize_pantry(None, None)
+            .await
+            .unwrap();
         let (pantry_addr, _join_handle) = crucible_pantry::server::run_server(
             &log,
             "127.0.0.1:0".parse().unwrap(),
@@ -2868,11 +3099,15 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         // Start the pantry, then use it to bulk_write in data
 
-        let (log, pantry) = crucible_pantry::initialize_pantry().await.unwrap();
+        let (log, pantry) = crucible_pantry::initialize_pantry(None, None)
+            .await
+            .unwrap();
         let (pantry_addr, _join_handle) = crucible_pantry::server::run_server(
             &log,
             "127.0.0.1:0".parse().unwrap(),
@@ -2930,6 +3165,8 @@ mod test {
                     gen: 2,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
         let volume = Volume::construct(vcr, None).await.unwrap();
         volume.activate().await.unwrap();
@@ -2970,11 +3207,15 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         // Start the pantry, then use it to bulk_write in data
 
-        let (log, pantry) = crucible_pantry::initialize_pantry().await.unwrap();
+        let (log, pantry) = crucible_pantry::initialize_pantry(None, None)
+            .await
+            .unwrap();
         let (pantry_addr, _join_handle) = crucible_pantry::server::run_server(
             &log,
             "127.0.0.1:0".parse().unwrap(),
@@ -3031,6 +3272,8 @@ mod test {
                     gen: 2,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
         let volume = Volume::construct(vcr, None).await.unwrap();
         volume.activate().await.unwrap();
@@ -3048,6 +3291,406 @@ mod test {
         );
     }
 
+    // Start a pantry, and attach a volume of the 5120 byte region at opts to
+    // it with every byte written as 0x55.
+    async fn pantry_with_0x55_volume(
+        opts: CrucibleOpts,
+        volume_id: Uuid,
+        s3_credentials: Option<crucible_pantry::s3::S3Credentials>,
+    ) -> CruciblePantryClient {
+        const BLOCK_SIZE: usize = 512;
+
+        let (log, pantry) =
+            crucible_pantry::initialize_pantry(None, s3_credentials)
+                .await
+                .unwrap();
+        let (pantry_addr, _join_handle) = crucible_pantry::server::run_server(
+            &log,
+            "127.0.0.1:0".parse().unwrap(),
+            pantry,
+        )
+        .await
+        .unwrap();
+
+        let client =
+            CruciblePantryClient::new(&format!("http://{}", pantry_addr));
+
+        let vcr: VolumeConstructionRequest =
+            VolumeConstructionRequest::Volume {
+                id: volume_id,
+                block_size: BLOCK_SIZE as u64,
+                sub_volumes: vec![VolumeConstructionRequest::Region {
+                    block_size: BLOCK_SIZE as u64,
+                    opts,
+                    gen: 1,
+                }],
+                read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
+            };
+        client
+            .attach(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::AttachRequest {
+                    volume_construction_request: serde_json::from_str(
+                        &serde_json::to_string(&vcr).unwrap(),
+                    )
+                    .unwrap(),
+                },
+            )
+            .await
+            .unwrap();
+
+        client
+            .bulk_write(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::BulkWriteRequest {
+                    offset: 0,
+                    base64_encoded_data: base64::encode(vec![0x55; 5120]),
+                },
+            )
+            .await
+            .unwrap();
+
+        client
+    }
+
+    #[tokio::test]
+    async fn test_pantry_bulk_read() {
+        let tds = TestDownstairsSet::small(false).await.unwrap();
+        let volume_id = Uuid::new_v4();
+        let client = pantry_with_0x55_volume(tds.opts(), volume_id, None).await;
+
+        client
+            .bulk_write(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::BulkWriteRequest {
+                    offset: 1024,
+                    base64_encoded_data: base64::encode(vec![0x11; 512]),
+                },
+            )
+            .await
+            .unwrap();
+
+        let response = client
+            .bulk_read(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::BulkReadRequest {
+                    offset: 512,
+                    size: 1536,
+                },
+            )
+            .await
+            .unwrap();
+
+        let mut expected = vec![0x55; 512];
+        expected.extend(vec![0x11; 512]);
+        expected.extend(vec![0x55; 512]);
+        assert_eq!(
+            expected,
+            base64::decode(&response.base64_encoded_data).unwrap()
+        );
+
+        // More than one chunk can't be read at once.
+        assert!(client
+            .bulk_read(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::BulkReadRequest {
+                    offset: 0,
+                    size: crucible_pantry::pantry::PantryEntry::MAX_CHUNK_SIZE
+                        as u64
+                        + 512,
+                },
+            )
+            .await
+            .is_err());
+
+        client.detach(&volume_id.to_string()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pantry_export_to_url_put() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("PUT", "/disk.raw"),
+                request::body(eq(vec![0x55; 5120])),
+            ])
+            .times(1)
+            .respond_with(status_code(200)),
+        );
+
+        let tds = TestDownstairsSet::small(false).await.unwrap();
+        let volume_id = Uuid::new_v4();
+        let client = pantry_with_0x55_volume(tds.opts(), volume_id, None).await;
+
+        let response = client
+            .export_to_url(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::ExportToUrlRequest {
+                    url: server.url("/disk.raw").to_string(),
+                    method: None,
+                    digest: Some(
+                        crucible_pantry_client::types::DigestAlgorithm::Sha256,
+                    ),
+                },
+            )
+            .await
+            .unwrap();
+
+        while !client
+            .is_job_finished(&response.job_id)
+            .await
+            .unwrap()
+            .job_is_finished
+        {
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        }
+
+        let poll = client.is_job_finished(&response.job_id).await.unwrap();
+        assert_eq!(
+            poll.digest.as_deref(),
+            Some(
+                "b38da3bd1a6ebc454a96a2a2ffc87ff31e90142aa1b10e50f26219e48d2328df"
+            ),
+        );
+
+        client.job_result_ok(&response.job_id).await.unwrap();
+        client.detach(&volume_id.to_string()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pantry_export_to_url_s3_multipart() {
+        // Every request to the bucket is signed with these.
+        let signed = || {
+            request::headers(contains((
+                "authorization",
+                matches(
+                    "^AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/[0-9]{8}/\
+                    us-east-1/s3/aws4_request, SignedHeaders=host;\
+                    x-amz-content-sha256;x-amz-date, Signature=[0-9a-f]{64}$",
+                ),
+            )))
+        };
+
+        // A stand-in for an S3 bucket.
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/bucket/disk.raw"),
+                request::query(url_decoded(contains(("uploads", "")))),
+                signed(),
+            ])
+            .times(1)
+            .respond_with(status_code(200).body(
+                "<InitiateMultipartUploadResult>\
+                <Bucket>bucket</Bucket><Key>disk.raw</Key>\
+                <UploadId>upload-1</UploadId>\
+                </InitiateMultipartUploadResult>",
+            )),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("PUT", "/bucket/disk.raw"),
+                request::query(url_decoded(contains(("partNumber", "1")))),
+                request::query(url_decoded(contains(("uploadId", "upload-1")))),
+                request::body(eq(vec![0x55; 5120])),
+                signed(),
+            ])
+            .times(1)
+            .respond_with(status_code(200).append_header("ETag", "\"part-1\"")),
+        );
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("POST", "/bucket/disk.raw"),
+                request::query(url_decoded(contains(("uploadId", "upload-1")))),
+                request::body(matches("<ETag>&quot;part-1&quot;</ETag>")),
+                signed(),
+            ])
+            .times(1)
+            .respond_with(status_code(200).body(
+                "<CompleteMultipartUploadResult>\
+                </CompleteMultipartUploadResult>",
+            )),
+        );
+
+        let tds = TestDownstairsSet::small(false).await.unwrap();
+        let volume_id = Uuid::new_v4();
+        let client = pantry_with_0x55_volume(
+            tds.opts(),
+            volume_id,
+            Some(crucible_pantry::s3::S3Credentials {
+                access_key_id: "AKIDEXAMPLE".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: None,
+                region: "us-east-1".to_string(),
+            }),
+        )
+        .await;
+
+        let response = client
+            .export_to_url(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::ExportToUrlRequest {
+                    url: server.url("/bucket/disk.raw").to_string(),
+                    method: Some(
+                        crucible_pantry_client::types::ExportMethod::S3Multipart,
+                    ),
+                    digest: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        // Test not polling here
+        client.job_result_ok(&response.job_id).await.unwrap();
+
+        client.detach(&volume_id.to_string()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pantry_export_to_url_s3_multipart_needs_credentials() {
+        let tds = TestDownstairsSet::small(false).await.unwrap();
+        let volume_id = Uuid::new_v4();
+        let client = pantry_with_0x55_volume(tds.opts(), volume_id, None).await;
+
+        // Nothing is sent without credentials to sign it with.
+        client
+            .export_to_url(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::ExportToUrlRequest {
+                    url: "http://127.0.0.1:1/bucket/disk.raw".to_string(),
+                    method: Some(
+                        crucible_pantry_client::types::ExportMethod::S3Multipart,
+                    ),
+                    digest: None,
+                },
+            )
+            .await
+            .unwrap_err();
+
+        client.detach(&volume_id.to_string()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pantry_import_qcow2_from_local_server() {
+        // A qcow2 image of 512 byte clusters: the header, the L1 table, one
+        // L2 table, then clusters 0, 1 and 4 of the disk.
+        let mut image = vec![0; 1536];
+        image[0..4].copy_from_slice(b"QFI\xfb");
+        image[4..8].copy_from_slice(&2u32.to_be_bytes());
+        image[20..24].copy_from_slice(&9u32.to_be_bytes());
+        image[24..32].copy_from_slice(&5120u64.to_be_bytes());
+        image[36..40].copy_from_slice(&1u32.to_be_bytes());
+        image[40..48].copy_from_slice(&512u64.to_be_bytes());
+        image[512..520].copy_from_slice(&1024u64.to_be_bytes());
+        for (guest, host) in [(0, 1536u64), (1, 2048), (4, 2560)] {
+            image[1024 + guest * 8..1024 + guest * 8 + 8]
+                .copy_from_slice(&host.to_be_bytes());
+        }
+        image.extend(vec![0x11; 1024]);
+        image.extend(vec![0x22; 512]);
+
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/img.qcow2"))
+                .times(1..)
+                .respond_with(status_code(200).body(image.clone())),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("HEAD", "/img.qcow2"))
+                .times(1..)
+                .respond_with(status_code(200).append_header(
+                    "Content-Length",
+                    format!("{}", image.len()),
+                )),
+        );
+
+        const BLOCK_SIZE: usize = 512;
+
+        let tds = TestDownstairsSet::small(false).await.unwrap();
+        let volume_id = Uuid::new_v4();
+
+        let (log, pantry) = crucible_pantry::initialize_pantry(None, None)
+            .await
+            .unwrap();
+        let (pantry_addr, _join_handle) = crucible_pantry::server::run_server(
+            &log,
+            "127.0.0.1:0".parse().unwrap(),
+            pantry,
+        )
+        .await
+        .unwrap();
+
+        let client =
+            CruciblePantryClient::new(&format!("http://{}", pantry_addr));
+
+        let vcr: VolumeConstructionRequest =
+            VolumeConstructionRequest::Volume {
+                id: volume_id,
+                block_size: BLOCK_SIZE as u64,
+                sub_volumes: vec![VolumeConstructionRequest::Region {
+                    block_size: BLOCK_SIZE as u64,
+                    opts: tds.opts(),
+                    gen: 1,
+                }],
+                read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
+            };
+        client
+            .attach(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::AttachRequest {
+                    volume_construction_request: serde_json::from_str(
+                        &serde_json::to_string(&vcr).unwrap(),
+                    )
+                    .unwrap(),
+                },
+            )
+            .await
+            .unwrap();
+
+        let response = client
+            .import_from_url(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::ImportFromUrlRequest {
+                    url: server.url("/img.qcow2").to_string(),
+                    expected_digest: None,
+                    format: Some(
+                        crucible_pantry_client::types::ImportFormat::Qcow2,
+                    ),
+                    target_is_zeroed: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        client.job_result_ok(&response.job_id).await.unwrap();
+
+        let response = client
+            .bulk_read(
+                &volume_id.to_string(),
+                &crucible_pantry_client::types::BulkReadRequest {
+                    offset: 0,
+                    size: 5120,
+                },
+            )
+            .await
+            .unwrap();
+
+        let mut expected = vec![0x11; 1024];
+        expected.extend(vec![0x00; 1024]);
+        expected.extend(vec![0x22; 512]);
+        expected.extend(vec![0x00; 2560]);
+        assert_eq!(
+            expected,
+            base64::decode(&response.base64_encoded_data).unwrap(),
+        );
+
+        client.detach(&volume_id.to_string()).await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_pantry_scrub() {
         // Test scrubbing the OVMF image from a URL
@@ -3101,6 +3744,8 @@ mod test {
                     gen: 1,
                 }],
                 read_only_parent: read_only_parent.clone(),
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         // Verify contents match data on init
@@ -3126,7 +3771,9 @@ mod test {
 
         // Start the pantry, then use it to scrub
 
-        let (log, pantry) = crucible_pantry::initialize_pantry().await.unwrap();
+        let (log, pantry) = crucible_pantry::initialize_pantry(None, None)
+            .await
+            .unwrap();
         let (pantry_addr, _join_handle) = crucible_pantry::server::run_server(
             &log,
             "127.0.0.1:0".parse().unwrap(),
@@ -3148,6 +3795,8 @@ mod test {
                     gen: 2,
                 }],
                 read_only_parent,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
         client
             .attach(
@@ -3195,6 +3844,8 @@ mod test {
                     gen: 3,
                 }],
                 read_only_parent: None,
+                stripe_blocks: None,
+                scrub_state_dir: None,
             };
 
         // Attach, validate random data got imported
@@ -75,12 +75,20 @@ async fn main() -> Result<()> {
         target: opt.target,
         lossy: false,
         flush_timeout: None,
-        key: opt.key,
+        key: opt.key.map(KeyReference::inline),
+        old_key: None,
         cert_pem: opt.cert_pem,
         key_pem: opt.key_pem,
         root_cert_pem: opt.root_cert_pem,
         control: None,
         read_only: false,
+        write_quorum: None,
+        dirty_extents: None,
+        block_record: None,
+        read_policy: None,
+        fault_timeout: None,
+        fault_queue: None,
+        qos: None,
     };
 
     /*
@@ -96,11 +104,11 @@ async fn main() -> Result<()> {
     let mut guest = Guest::new();
 
     if let Some(iop_limit) = opt.iop_limit {
-        guest.set_iop_limit(16 * 1024 * 1024, iop_limit);
+        guest.set_iop_limit(16 * 1024 * 1024, iop_limit)?;
     }
 
     if let Some(bw_limit) = opt.bw_limit_in_bytes {
-        guest.set_bw_limit(bw_limit);
+        guest.set_bw_limit(bw_limit)?;
     }
 
     let guest = Arc::new(guest);
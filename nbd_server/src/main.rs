@@ -1,4 +1,5 @@
 // Copyright 2021 Oxide Computer Company
+use std::convert::TryInto;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
@@ -7,25 +8,179 @@ use clap::Parser;
 
 use crucible::*;
 
-use nbd::server::{handshake, transmission, Export};
+use nbd::server::{handshake, Export};
+use slog::{error, o, Drain, Logger};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::{TcpListener, TcpStream as NetTcpStream};
 
 /*
  * NBD server commands translate through the CruciblePseudoFile and turn
  * into Guest work ops.
+ *
+ * The transmission phase is handled here rather than by the nbd crate so
+ * that NBD_CMD_TRIM can be turned into a Crucible discard.
  */
+const NBD_REQUEST_MAGIC: u32 = 0x25609513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+
+const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+
+/*
+ * The most data a read or write may carry.  The client picks the length,
+ * so this bounds what it can make us allocate.
+ */
+const NBD_MAX_PAYLOAD: u64 = 32 * 1024 * 1024;
+
+const NBD_EIO: u32 = 5;
+const NBD_EINVAL: u32 = 22;
+
+fn nbd_reply(
+    stream: &mut NetTcpStream,
+    error: u32,
+    handle: u64,
+    data: Option<&[u8]>,
+) -> Result<()> {
+    let mut reply = Vec::with_capacity(16);
+    reply.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+    reply.extend_from_slice(&error.to_be_bytes());
+    reply.extend_from_slice(&handle.to_be_bytes());
+    stream.write_all(&reply)?;
+    if let Some(data) = data {
+        stream.write_all(data)?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+fn transmission<T: crucible::BlockIO>(
+    stream: &mut NetTcpStream,
+    cpf: &mut crucible::CruciblePseudoFile<T>,
+    log: &Logger,
+) -> Result<()> {
+    loop {
+        let mut request = [0u8; 28];
+        stream.read_exact(&mut request)?;
+
+        let magic = u32::from_be_bytes(request[0..4].try_into()?);
+        let flags = u16::from_be_bytes(request[4..6].try_into()?);
+        let typ = u16::from_be_bytes(request[6..8].try_into()?);
+        let handle = u64::from_be_bytes(request[8..16].try_into()?);
+        let offset = u64::from_be_bytes(request[16..24].try_into()?);
+        let length = u32::from_be_bytes(request[24..28].try_into()?) as u64;
+
+        if magic != NBD_REQUEST_MAGIC {
+            bail!("bad nbd request magic {:#x}", magic);
+        }
+
+        let too_long = (typ == NBD_CMD_READ || typ == NBD_CMD_WRITE)
+            && length > NBD_MAX_PAYLOAD;
+        if too_long
+            || (typ != NBD_CMD_DISC
+                && typ != NBD_CMD_FLUSH
+                && offset
+                    .checked_add(length)
+                    .map_or(true, |end| end > cpf.sz()))
+        {
+            if typ == NBD_CMD_WRITE {
+                // Consume the payload so the stream stays in sync
+                std::io::copy(
+                    &mut (&mut *stream).take(length),
+                    &mut std::io::sink(),
+                )?;
+            }
+            nbd_reply(stream, NBD_EINVAL, handle, None)?;
+            continue;
+        }
+
+        match typ {
+            NBD_CMD_READ => {
+                let mut data = vec![0u8; length as usize];
+                let result = cpf
+                    .seek(SeekFrom::Start(offset))
+                    .and_then(|_| cpf.read_exact(&mut data));
+                match result {
+                    Ok(()) => nbd_reply(stream, 0, handle, Some(&data))?,
+                    Err(e) => {
+                        error!(log, "nbd read error: {}", e);
+                        nbd_reply(stream, NBD_EIO, handle, None)?;
+                    }
+                }
+            }
+            NBD_CMD_WRITE => {
+                let mut data = vec![0u8; length as usize];
+                stream.read_exact(&mut data)?;
+                let result = cpf
+                    .seek(SeekFrom::Start(offset))
+                    .and_then(|_| cpf.write_all(&data))
+                    .and_then(|_| fua_flush(cpf, flags));
+                match result {
+                    Ok(()) => nbd_reply(stream, 0, handle, None)?,
+                    Err(e) => {
+                        error!(log, "nbd write error: {}", e);
+                        nbd_reply(stream, NBD_EIO, handle, None)?;
+                    }
+                }
+            }
+            NBD_CMD_DISC => {
+                return Ok(());
+            }
+            NBD_CMD_FLUSH => match cpf.flush() {
+                Ok(()) => nbd_reply(stream, 0, handle, None)?,
+                Err(e) => {
+                    error!(log, "nbd flush error: {}", e);
+                    nbd_reply(stream, NBD_EIO, handle, None)?;
+                }
+            },
+            NBD_CMD_TRIM => match cpf
+                .discard(offset, length)
+                .and_then(|_| fua_flush(cpf, flags))
+            {
+                Ok(()) => nbd_reply(stream, 0, handle, None)?,
+                Err(e) => {
+                    error!(log, "nbd trim error: {}", e);
+                    nbd_reply(stream, NBD_EIO, handle, None)?;
+                }
+            },
+            _ => {
+                nbd_reply(stream, NBD_EINVAL, handle, None)?;
+            }
+        }
+    }
+}
+
+/*
+ * A write or trim with the FUA flag has to be on stable storage before
+ * it is acked, so flush it behind the request.
+ */
+fn fua_flush<T: crucible::BlockIO>(
+    cpf: &mut crucible::CruciblePseudoFile<T>,
+    flags: u16,
+) -> std::io::Result<()> {
+    if flags & NBD_CMD_FLAG_FUA != 0 {
+        cpf.flush()?;
+    }
+    Ok(())
+}
 
 fn handle_nbd_client<T: crucible::BlockIO>(
     cpf: &mut crucible::CruciblePseudoFile<T>,
     mut stream: NetTcpStream,
+    log: &Logger,
 ) -> Result<()> {
     let e = Export {
         size: cpf.sz(),
         readonly: false,
+        send_trim: true,
         ..Default::default()
     };
     handshake(&mut stream, &e)?;
-    transmission(&mut stream, cpf)?;
+    transmission(&mut stream, cpf, log)?;
     Ok(())
 }
 
@@ -73,11 +228,16 @@ pub fn opts() -> Result<Opt> {
 #[tokio::main]
 async fn main() -> Result<()> {
     let opt = opts()?;
+    let decorator = slog_term::TermDecorator::new().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    let log = Logger::root(drain, o!());
+
     let crucible_opts = CrucibleOpts {
         target: opt.target,
         lossy: false,
         flush_timeout: None,
-        key: opt.key,
+        key: opt.key.map(KeyReference::inline),
         cert_pem: opt.cert_pem,
         key_pem: opt.key_pem,
         root_cert_pem: opt.root_cert_pem,
@@ -110,10 +270,10 @@ async fn main() -> Result<()> {
     for stream in listener.incoming() {
         println!("waiting on nbd traffic");
         match stream {
-            Ok(stream) => match handle_nbd_client(&mut cpf, stream) {
+            Ok(stream) => match handle_nbd_client(&mut cpf, stream, &log) {
                 Ok(_) => {}
                 Err(e) => {
-                    eprintln!("handle_nbd_client error: {}", e);
+                    error!(log, "handle_nbd_client error: {}", e);
                 }
             },
             Err(_) => {
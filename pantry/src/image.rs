@@ -0,0 +1,1285 @@
+// Copyright 2022 Oxide Computer Company
+
+//! Importing virtual disk images into a volume.
+//!
+//! An image can be raw, qcow2, a stream optimized VMDK, or a fixed or
+//! dynamic VHD. Anything other than raw is converted as it is imported: only
+//! the clusters (or grains, or blocks) the image has allocated are read, and
+//! the volume is written in the same layout a raw image would have. Every
+//! block the image covers is written, zeros and all, unless the volume is
+//! known to read as zeros already, in which case blocks of zeros and the
+//! parts of the image that are not allocated are skipped.
+
+use std::convert::TryInto;
+use std::io::Read;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Result;
+use async_trait::async_trait;
+use flate2::read::DeflateDecoder;
+use flate2::read::ZlibDecoder;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crucible::BlockIO;
+use crucible::Bytes;
+use crucible::Volume;
+
+use crate::pantry::PantryEntry;
+
+/// Where an image is read from. The image formats say where their data is,
+/// so it is read at byte offsets rather than from start to end.
+#[async_trait]
+pub trait ImageSource: Send + Sync {
+    fn size(&self) -> u64;
+
+    async fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>>;
+}
+
+/// An image at a URL, read with HTTP range requests.
+pub struct UrlSource {
+    client: reqwest::Client,
+    url: String,
+    size: u64,
+}
+
+impl UrlSource {
+    pub async fn new(
+        client: reqwest::Client,
+        url: String,
+    ) -> Result<UrlSource> {
+        // validate the URL can be reached, and grab the content length
+        let response = client.head(&url).send().await?;
+
+        if !response.status().is_success() {
+            bail!("querying url returned: {}", response.status());
+        }
+
+        let content_length = response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .ok_or("no content length!")
+            .map_err(|e| anyhow!(e))?;
+
+        let size = u64::from_str(content_length.to_str()?)?;
+
+        Ok(UrlSource { client, url, size })
+    }
+
+    async fn get_range(&self, start: u64, end: u64) -> Result<Bytes> {
+        let response = self
+            .client
+            .get(&self.url)
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", start, end - 1),
+            )
+            .send()
+            .await?;
+
+        let content_length = response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .ok_or("no content length!")
+            .map_err(|e| anyhow!(e))?;
+
+        let content_length = u64::from_str(content_length.to_str()?)?;
+
+        if content_length != (end - start) {
+            // the remote web server didn't honour the RANGE header!
+            bail!(
+                "RANGE header bytes={}-{}, content length returned is {}!",
+                start,
+                end - 1,
+                content_length,
+            );
+        }
+
+        Ok(response.bytes().await?)
+    }
+}
+
+#[async_trait]
+impl ImageSource for UrlSource {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    async fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let end = match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => end,
+            _ => bail!(
+                "read of {} bytes at {} is past the end of {}",
+                len,
+                offset,
+                self.url,
+            ),
+        };
+
+        // An image that fits in one chunk is always fetched whole, so a
+        // server that ignores the RANGE header can still serve it.
+        if self.size <= PantryEntry::MAX_CHUNK_SIZE as u64 {
+            let whole = self.get_range(0, self.size).await?;
+            return Ok(whole[offset as usize..end as usize].to_vec());
+        }
+
+        let mut data = Vec::with_capacity(len);
+        let mut start = offset;
+        while start < end {
+            let chunk_end =
+                std::cmp::min(start + PantryEntry::MAX_CHUNK_SIZE as u64, end);
+            data.extend_from_slice(&self.get_range(start, chunk_end).await?);
+            start = chunk_end;
+        }
+
+        Ok(data)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+    VmdkStreamOptimized,
+    VhdFixed,
+    VhdDynamic,
+}
+
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+const VMDK_MAGIC: &[u8] = b"KDMV";
+const VMDK_DESCRIPTOR: &[u8] = b"# Disk DescriptorFile";
+const VHD_COOKIE: &[u8] = b"conectix";
+
+const VHD_FIXED: u32 = 2;
+const VHD_DYNAMIC: u32 = 3;
+const VHD_DIFFERENCING: u32 = 4;
+
+impl ImageFormat {
+    /// Work out the format from the magic at the start of the image, or for
+    /// a fixed VHD, the footer at the end of it. Anything else is raw.
+    pub async fn detect(source: &dyn ImageSource) -> Result<ImageFormat> {
+        let size = source.size();
+        if size < 512 {
+            return Ok(ImageFormat::Raw);
+        }
+
+        let head = source.read_at(0, 512).await?;
+        if head.starts_with(QCOW2_MAGIC) {
+            return Ok(ImageFormat::Qcow2);
+        }
+        if head.starts_with(VMDK_MAGIC) {
+            return Ok(ImageFormat::VmdkStreamOptimized);
+        }
+        if head.starts_with(VMDK_DESCRIPTOR) {
+            bail!(
+                "VMDK descriptor files refer to other files, only a stream \
+                optimized VMDK can be imported"
+            );
+        }
+        if head.starts_with(VHD_COOKIE) {
+            // A dynamic VHD has a copy of its footer at the start.
+            return match VhdFooter::parse(&head)?.disk_type {
+                VHD_DYNAMIC => Ok(ImageFormat::VhdDynamic),
+                VHD_DIFFERENCING => {
+                    bail!("a differencing VHD needs its parent to be imported")
+                }
+                disk_type => bail!("unsupported VHD disk type {}", disk_type),
+            };
+        }
+
+        let tail = source.read_at(size - 512, 512).await?;
+        if tail.starts_with(VHD_COOKIE)
+            && VhdFooter::parse(&tail)?.disk_type == VHD_FIXED
+        {
+            return Ok(ImageFormat::VhdFixed);
+        }
+
+        Ok(ImageFormat::Raw)
+    }
+}
+
+/// Writes an image into a volume. Data can be written at any byte offset
+/// and length, and is gathered into whole blocks before it is written.
+/// Unless the volume is known to be zeroed, anything the image does not
+/// write up to its size is written as zeros. If it is, blocks that are all
+/// zeros are skipped.
+///
+/// Data is expected in order of offset. A block that is only partly
+/// written is filled out with zeros, so data that goes back into a block
+/// already written replaces what was there.
+pub struct DiskWriter<'a> {
+    volume: &'a Volume,
+    block_size: u64,
+    volume_size: u64,
+    skip_zeros: bool,
+
+    /// The size of the image, set once its header has been read.
+    size: u64,
+    /// Everything before this has been buffered or written.
+    filled: u64,
+
+    /// Where buffer starts in the volume, always on a block boundary.
+    start: u64,
+    buffer: Vec<u8>,
+}
+
+impl<'a> DiskWriter<'a> {
+    /// A writer for the volume, which with zeroed set is known to read as
+    /// all zeros.
+    pub async fn new(volume: &'a Volume, zeroed: bool) -> Result<DiskWriter<'a>> {
+        Ok(DiskWriter {
+            volume,
+            block_size: volume.get_block_size().await?,
+            volume_size: volume.total_size().await?,
+            skip_zeros: zeroed,
+            size: 0,
+            filled: 0,
+            start: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Check the image fits in the volume, and note how much of the volume
+    /// it covers.
+    pub fn set_size(&mut self, size: u64) -> Result<()> {
+        self.check_size(size)?;
+        self.size = size;
+        Ok(())
+    }
+
+    fn check_size(&self, size: u64) -> Result<()> {
+        if size > self.volume_size {
+            bail!(
+                "volume size {} smaller than image size {}",
+                self.volume_size,
+                size,
+            );
+        }
+        Ok(())
+    }
+
+    pub async fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.check_size(offset + data.len() as u64)?;
+        if !self.skip_zeros {
+            self.fill_to(offset).await?;
+        }
+        self.put(offset, data).await
+    }
+
+    /// Buffer zeros over anything not yet written before end.
+    async fn fill_to(&mut self, end: u64) -> Result<()> {
+        if self.filled >= end {
+            return Ok(());
+        }
+        let zeros = vec![
+            0;
+            std::cmp::min(
+                PantryEntry::MAX_CHUNK_SIZE as u64,
+                end - self.filled
+            ) as usize
+        ];
+        while self.filled < end {
+            let len = std::cmp::min(zeros.len() as u64, end - self.filled);
+            self.put(self.filled, &zeros[..len as usize]).await?;
+        }
+        Ok(())
+    }
+
+    async fn put(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.filled = std::cmp::max(self.filled, offset + data.len() as u64);
+
+        let buffer_end = self.start + self.buffer.len() as u64;
+        let block_end = round_up(buffer_end, self.block_size);
+        if self.buffer.is_empty() || offset < self.start || offset > block_end {
+            self.flush(true).await?;
+            self.start = offset - offset % self.block_size;
+        }
+
+        let at = (offset - self.start) as usize;
+        if self.buffer.len() < at + data.len() {
+            self.buffer.resize(at + data.len(), 0);
+        }
+        self.buffer[at..at + data.len()].copy_from_slice(data);
+
+        if self.buffer.len() >= PantryEntry::MAX_CHUNK_SIZE {
+            self.flush(false).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write whatever is still buffered, and zeros over the rest of the
+    /// image.
+    pub async fn finish(mut self) -> Result<()> {
+        if !self.skip_zeros {
+            let size = self.size;
+            self.fill_to(size).await?;
+        }
+        self.flush(true).await
+    }
+
+    /// Is this block of the buffer one that can be skipped?
+    fn skip(&self, block: usize) -> bool {
+        self.skip_zeros
+            && self.buffer[block..block + self.block_size as usize]
+                .iter()
+                .all(|b| *b == 0)
+    }
+
+    /// Write out the whole blocks in the buffer, and with all set, the
+    /// last partial block too.
+    async fn flush(&mut self, all: bool) -> Result<()> {
+        let block_size = self.block_size as usize;
+        let len = if all {
+            round_up(self.buffer.len() as u64, self.block_size) as usize
+        } else {
+            self.buffer.len() - self.buffer.len() % block_size
+        };
+        self.buffer.resize(std::cmp::max(self.buffer.len(), len), 0);
+
+        let mut block = 0;
+        while block < len {
+            if self.skip(block) {
+                block += block_size;
+                continue;
+            }
+
+            let run_start = block;
+            while block < len
+                && block - run_start < PantryEntry::MAX_CHUNK_SIZE
+                && !self.skip(block)
+            {
+                block += block_size;
+            }
+
+            self.volume
+                .write_to_byte_offset(
+                    self.start + run_start as u64,
+                    Bytes::from(self.buffer[run_start..block].to_vec()),
+                )
+                .await?;
+        }
+
+        self.buffer.drain(..len);
+        self.start += len as u64;
+        Ok(())
+    }
+}
+
+fn round_up(n: u64, to: u64) -> u64 {
+    (n + to - 1) / to * to
+}
+
+/// Sectors as bytes, for sector counts and numbers read from an image.
+fn sectors_to_bytes(sectors: u64) -> Result<u64> {
+    sectors
+        .checked_mul(512)
+        .ok_or_else(|| anyhow!("{} sectors is too big for any disk", sectors))
+}
+
+/// How many of something of the given size it takes to cover len bytes.
+fn count_to_cover(len: u64, size: u64) -> u64 {
+    len / size + u64::from(len % size != 0)
+}
+
+fn be_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn be_u64(data: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn le_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+}
+
+fn le_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn le_u64(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+/// Inflate compressed data that should come out to exactly len bytes.
+fn inflate<R: Read>(mut decoder: R, len: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(len);
+    decoder.by_ref().take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len {
+        bail!(
+            "compressed data inflated to {} bytes, not {}",
+            data.len(),
+            len
+        );
+    }
+    Ok(data)
+}
+
+/// Import an image of the given format into the volume. If given a hasher,
+/// every byte of the image is fed to it, in order.
+pub async fn import(
+    format: ImageFormat,
+    source: &dyn ImageSource,
+    writer: &mut DiskWriter<'_>,
+    hasher: Option<&mut Sha256>,
+) -> Result<()> {
+    match format {
+        ImageFormat::Raw => {
+            // A raw image is read from start to end anyway, so it is
+            // hashed as it goes.
+            return import_raw(source, source.size(), writer, hasher).await;
+        }
+        ImageFormat::Qcow2 => import_qcow2(source, writer).await?,
+        ImageFormat::VmdkStreamOptimized => import_vmdk(source, writer).await?,
+        ImageFormat::VhdFixed => {
+            // The data is everything before the footer.
+            import_raw(source, source.size() - 512, writer, None).await?
+        }
+        ImageFormat::VhdDynamic => import_vhd_dynamic(source, writer).await?,
+    }
+
+    // The other formats are read out of order, so the digest, which is of
+    // the image as it was given, takes another pass over it.
+    if let Some(hasher) = hasher {
+        let size = source.size();
+        let mut offset = 0;
+        while offset < size {
+            let len = std::cmp::min(
+                PantryEntry::MAX_CHUNK_SIZE as u64,
+                size - offset,
+            );
+            hasher.update(&source.read_at(offset, len as usize).await?);
+            offset += len;
+        }
+    }
+
+    Ok(())
+}
+
+async fn import_raw(
+    source: &dyn ImageSource,
+    size: u64,
+    writer: &mut DiskWriter<'_>,
+    mut hasher: Option<&mut Sha256>,
+) -> Result<()> {
+    writer.set_size(size)?;
+
+    let mut offset = 0;
+    while offset < size {
+        let len =
+            std::cmp::min(PantryEntry::MAX_CHUNK_SIZE as u64, size - offset);
+        let data = source.read_at(offset, len as usize).await?;
+
+        if let Some(ref mut hasher) = hasher {
+            hasher.update(&data);
+        }
+
+        writer.write(offset, &data).await?;
+        offset += len;
+    }
+
+    Ok(())
+}
+
+/*
+ * qcow2
+ */
+
+const QCOW2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const QCOW2_COMPRESSED: u64 = 1 << 62;
+const QCOW2_ZERO: u64 = 1;
+
+// The incompatible features we can read an image with: the dirty bit, which
+// only means the refcounts may be out of date, and a compression type
+// field, which is checked for zlib.
+const QCOW2_DIRTY: u64 = 1;
+const QCOW2_COMPRESSION_TYPE: u64 = 1 << 3;
+
+async fn import_qcow2(
+    source: &dyn ImageSource,
+    writer: &mut DiskWriter<'_>,
+) -> Result<()> {
+    let header_len = std::cmp::min(source.size(), 112) as usize;
+    let header = source.read_at(0, header_len).await?;
+    if header.len() < 72 {
+        bail!("qcow2 header is too short");
+    }
+
+    let version = be_u32(&header, 4);
+    let backing_file_offset = be_u64(&header, 8);
+    let cluster_bits = be_u32(&header, 20);
+    let size = be_u64(&header, 24);
+    let crypt_method = be_u32(&header, 32);
+    let l1_size = be_u32(&header, 36) as u64;
+    let l1_table_offset = be_u64(&header, 40);
+
+    if version != 2 && version != 3 {
+        bail!("unsupported qcow2 version {}", version);
+    }
+    if backing_file_offset != 0 {
+        bail!("a qcow2 image with a backing file can't be imported");
+    }
+    if crypt_method != 0 {
+        bail!("an encrypted qcow2 image can't be imported");
+    }
+    if !(9..=21).contains(&cluster_bits) {
+        bail!("unsupported qcow2 cluster bits {}", cluster_bits);
+    }
+    if version == 3 {
+        if header.len() < 104 {
+            bail!("qcow2 header is too short");
+        }
+        let incompatible = be_u64(&header, 72);
+        if incompatible & !(QCOW2_DIRTY | QCOW2_COMPRESSION_TYPE) != 0 {
+            bail!(
+                "unsupported qcow2 incompatible features {:#x}",
+                incompatible
+            );
+        }
+        let header_length = be_u32(&header, 100);
+        if incompatible & QCOW2_COMPRESSION_TYPE != 0
+            && (header_length <= 104 || header.len() <= 104 || header[104] != 0)
+        {
+            bail!("only zlib compressed qcow2 images can be imported");
+        }
+    }
+
+    writer.set_size(size)?;
+
+    let cluster_size = 1u64 << cluster_bits;
+    let l2_entries = cluster_size / 8;
+    // Where the size of a compressed cluster starts in its L2 entry.
+    let compressed_shift = 62 - (cluster_bits - 8);
+
+    // Only the L1 entries that cover the disk are read, however many the
+    // header says there are.
+    let l1_size =
+        std::cmp::min(l1_size, count_to_cover(size, l2_entries * cluster_size));
+    let l1 = source
+        .read_at(l1_table_offset, l1_size as usize * 8)
+        .await?;
+
+    // Clusters next to each other in both the image and the volume are
+    // read together.
+    let mut run: Option<(u64, u64, u64)> = None;
+
+    for l1_index in 0..l1_size {
+        let l2_offset = be_u64(&l1, l1_index as usize * 8) & QCOW2_OFFSET_MASK;
+        if l2_offset == 0 {
+            continue;
+        }
+
+        let l2 = source.read_at(l2_offset, cluster_size as usize).await?;
+        for l2_index in 0..l2_entries {
+            let guest = l1_index
+                .checked_mul(l2_entries)
+                .and_then(|n| n.checked_add(l2_index))
+                .and_then(|n| n.checked_mul(cluster_size))
+                .ok_or_else(|| anyhow!("qcow2 cluster is past any disk"))?;
+            if guest >= size {
+                break;
+            }
+            let len = std::cmp::min(cluster_size, size - guest);
+            let entry = be_u64(&l2, l2_index as usize * 8);
+
+            if entry & QCOW2_COMPRESSED != 0 {
+                let host = entry & ((1 << compressed_shift) - 1);
+                let sectors = (entry & !(QCOW2_COMPRESSED | (1 << 63)))
+                    >> compressed_shift;
+                let compressed_len = std::cmp::min(
+                    (sectors + 1) * 512 - (host % 512),
+                    source.size().saturating_sub(host),
+                );
+                let compressed =
+                    source.read_at(host, compressed_len as usize).await?;
+                let data = inflate(
+                    DeflateDecoder::new(&compressed[..]),
+                    cluster_size as usize,
+                )?;
+                writer.write(guest, &data[..len as usize]).await?;
+                continue;
+            }
+
+            let host = entry & QCOW2_OFFSET_MASK;
+            if entry & QCOW2_ZERO != 0 || host == 0 {
+                continue;
+            }
+
+            run = match run {
+                Some((run_guest, run_host, run_len))
+                    if run_guest + run_len == guest
+                        && run_host + run_len == host
+                        && run_len + len
+                            <= PantryEntry::MAX_CHUNK_SIZE as u64 =>
+                {
+                    Some((run_guest, run_host, run_len + len))
+                }
+                _ => {
+                    if let Some((run_guest, run_host, run_len)) = run {
+                        let data =
+                            source.read_at(run_host, run_len as usize).await?;
+                        writer.write(run_guest, &data).await?;
+                    }
+                    Some((guest, host, len))
+                }
+            };
+        }
+    }
+
+    if let Some((run_guest, run_host, run_len)) = run {
+        let data = source.read_at(run_host, run_len as usize).await?;
+        writer.write(run_guest, &data).await?;
+    }
+
+    Ok(())
+}
+
+/*
+ * VMDK, stream optimized
+ */
+
+const VMDK_COMPRESSED: u32 = 1 << 16;
+const VMDK_MARKERS: u32 = 1 << 17;
+const VMDK_COMPRESSION_DEFLATE: u16 = 1;
+const VMDK_MARKER_EOS: u32 = 0;
+
+/// Reads a source from start to end, for formats that are laid out as a
+/// stream.
+struct StreamReader<'a> {
+    source: &'a dyn ImageSource,
+    offset: u64,
+    buffer: Vec<u8>,
+}
+
+impl<'a> StreamReader<'a> {
+    fn new(source: &'a dyn ImageSource, offset: u64) -> StreamReader<'a> {
+        StreamReader {
+            source,
+            offset,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.buffer.is_empty() && self.offset >= self.source.size()
+    }
+
+    async fn read(&mut self, len: usize) -> Result<Vec<u8>> {
+        while self.buffer.len() < len {
+            let size = self.source.size();
+            if self.offset >= size {
+                bail!("image ends in the middle of its data");
+            }
+            let more = std::cmp::min(
+                std::cmp::max(
+                    len - self.buffer.len(),
+                    PantryEntry::MAX_CHUNK_SIZE,
+                ) as u64,
+                size - self.offset,
+            );
+            let data = self.source.read_at(self.offset, more as usize).await?;
+            self.buffer.extend_from_slice(&data);
+            self.offset += more;
+        }
+        Ok(self.buffer.drain(..len).collect())
+    }
+
+    async fn skip(&mut self, len: u64) -> Result<()> {
+        if len <= self.buffer.len() as u64 {
+            self.buffer.drain(..len as usize);
+        } else {
+            self.offset = self
+                .offset
+                .checked_add(len - self.buffer.len() as u64)
+                .ok_or_else(|| anyhow!("skipped past the end of the image"))?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+async fn import_vmdk(
+    source: &dyn ImageSource,
+    writer: &mut DiskWriter<'_>,
+) -> Result<()> {
+    let header = source.read_at(0, 512).await?;
+
+    let flags = le_u32(&header, 8);
+    let capacity = sectors_to_bytes(le_u64(&header, 12))?;
+    let grain_size = sectors_to_bytes(le_u64(&header, 20))?;
+    let overhead = sectors_to_bytes(le_u64(&header, 64))?;
+    let compress_algorithm = le_u16(&header, 77);
+
+    if flags & VMDK_COMPRESSED == 0
+        || flags & VMDK_MARKERS == 0
+        || compress_algorithm != VMDK_COMPRESSION_DEFLATE
+    {
+        bail!("only a stream optimized VMDK can be imported");
+    }
+    if grain_size == 0 {
+        bail!("VMDK grain size is zero");
+    }
+
+    writer.set_size(capacity)?;
+
+    // After the overhead, every grain and every piece of metadata starts
+    // on a sector with a marker: the sector number of the grain, then the
+    // size of the compressed grain that follows. Metadata has a size of
+    // zero, followed by its type, and its length in sectors is where the
+    // grain's sector number would be.
+    let mut reader = StreamReader::new(source, overhead);
+    while !reader.at_end() {
+        let marker = reader.read(12).await?;
+        let value = le_u64(&marker, 0);
+        let compressed_len = le_u32(&marker, 8) as usize;
+
+        if compressed_len == 0 {
+            let rest = reader.read(500).await?;
+            if le_u32(&rest, 0) == VMDK_MARKER_EOS {
+                break;
+            }
+            reader.skip(sectors_to_bytes(value)?).await?;
+            continue;
+        }
+
+        let compressed = reader.read(compressed_len).await?;
+        let used = 12 + compressed_len as u64;
+        reader.skip(round_up(used, 512) - used).await?;
+
+        let guest = sectors_to_bytes(value)?;
+        if guest >= capacity {
+            bail!("VMDK grain at sector {} is past the end of the disk", value);
+        }
+        let len = std::cmp::min(grain_size, capacity - guest);
+        let data = inflate(ZlibDecoder::new(&compressed[..]), len as usize)?;
+        writer.write(guest, &data).await?;
+    }
+
+    Ok(())
+}
+
+/*
+ * VHD
+ */
+
+const VHD_SPARSE_COOKIE: &[u8] = b"cxsparse";
+const VHD_UNALLOCATED: u32 = 0xffff_ffff;
+
+struct VhdFooter {
+    data_offset: u64,
+    current_size: u64,
+    disk_type: u32,
+}
+
+impl VhdFooter {
+    fn parse(footer: &[u8]) -> Result<VhdFooter> {
+        if footer.len() < 512 || !footer.starts_with(VHD_COOKIE) {
+            bail!("not a VHD footer");
+        }
+        Ok(VhdFooter {
+            data_offset: be_u64(footer, 16),
+            current_size: be_u64(footer, 48),
+            disk_type: be_u32(footer, 60),
+        })
+    }
+}
+
+async fn import_vhd_dynamic(
+    source: &dyn ImageSource,
+    writer: &mut DiskWriter<'_>,
+) -> Result<()> {
+    let footer = VhdFooter::parse(&source.read_at(0, 512).await?)?;
+    writer.set_size(footer.current_size)?;
+
+    let header = source.read_at(footer.data_offset, 1024).await?;
+    if !header.starts_with(VHD_SPARSE_COOKIE) {
+        bail!("VHD dynamic disk header not found");
+    }
+    let table_offset = be_u64(&header, 16);
+    let max_table_entries = be_u32(&header, 28) as u64;
+    let block_size = be_u32(&header, 32) as u64;
+    if block_size == 0 || block_size % 512 != 0 {
+        bail!("unsupported VHD block size {}", block_size);
+    }
+
+    // Each block starts with a bitmap of which of its sectors are there,
+    // padded out to a whole sector. A sector that isn't is all zeros.
+    let bitmap_len = round_up((block_size / 512 + 7) / 8, 512);
+
+    // Only the entries that cover the disk are read, however many the
+    // header says there are.
+    let max_table_entries = std::cmp::min(
+        max_table_entries,
+        count_to_cover(footer.current_size, block_size),
+    );
+    let bat = source
+        .read_at(table_offset, max_table_entries as usize * 4)
+        .await?;
+    for index in 0..max_table_entries {
+        let entry = be_u32(&bat, index as usize * 4);
+        if entry == VHD_UNALLOCATED {
+            continue;
+        }
+
+        let guest = index * block_size;
+        if guest >= footer.current_size {
+            break;
+        }
+        let len = std::cmp::min(block_size, footer.current_size - guest);
+        let sectors = round_up(len, 512) / 512;
+
+        let host = entry as u64 * 512;
+        let bitmap = source.read_at(host, bitmap_len as usize).await?;
+        let present = |sector: u64| {
+            bitmap[sector as usize / 8] & (0x80 >> (sector % 8)) != 0
+        };
+        if !(0..sectors).any(present) {
+            continue;
+        }
+
+        let data = source.read_at(host + bitmap_len, len as usize).await?;
+        let mut sector = 0;
+        while sector < sectors {
+            if !present(sector) {
+                sector += 1;
+                continue;
+            }
+            let run_start = sector;
+            while sector < sectors && present(sector) {
+                sector += 1;
+            }
+            let start = (run_start * 512) as usize;
+            let end = std::cmp::min(sector * 512, len) as usize;
+            writer
+                .write(guest + start as u64, &data[start..end])
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crucible::Block;
+    use crucible::Buffer;
+    use crucible::InMemoryBlockIO;
+    use flate2::write::DeflateEncoder;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    struct MemorySource(Vec<u8>);
+
+    #[async_trait]
+    impl ImageSource for MemorySource {
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        async fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+            let start = offset as usize;
+            if start + len > self.0.len() {
+                bail!("read past the end");
+            }
+            Ok(self.0[start..start + len].to_vec())
+        }
+    }
+
+    async fn volume(size: usize) -> Volume {
+        let block_io =
+            Arc::new(InMemoryBlockIO::new(Uuid::new_v4(), 512, size));
+        let volume = Volume::from_block_io(block_io).await.unwrap();
+        volume.activate().await.unwrap();
+        volume
+    }
+
+    async fn volume_data(volume: &Volume) -> Vec<u8> {
+        let size = volume.total_size().await.unwrap() as usize;
+        let buffer = Buffer::new(size);
+        volume
+            .read(Block::new_512(0), buffer.clone())
+            .await
+            .unwrap();
+        let data = buffer.as_vec().await.clone();
+        data
+    }
+
+    async fn import_into(
+        volume: &Volume,
+        source: &MemorySource,
+        zeroed: bool,
+    ) -> Result<()> {
+        let format = ImageFormat::detect(source).await?;
+        let mut writer = DiskWriter::new(volume, zeroed).await?;
+        import(format, source, &mut writer, None).await?;
+        writer.finish().await
+    }
+
+    // Import the image into a volume of size bytes, both skipping zeros and
+    // not, and return what the volume then holds.
+    async fn import_image(image: Vec<u8>, size: usize) -> Result<Vec<u8>> {
+        let source = MemorySource(image);
+        let skipped = volume(size).await;
+        import_into(&skipped, &source, true).await?;
+        let written = volume(size).await;
+        import_into(&written, &source, false).await?;
+
+        let data = volume_data(&skipped).await;
+        assert_eq!(data, volume_data(&written).await);
+        Ok(data)
+    }
+
+    // Some data that is never all zeros in any sector.
+    fn pattern(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| seed.wrapping_add(i as u8) | 1).collect()
+    }
+
+    fn put_u32(image: &mut [u8], at: usize, n: u32, be: bool) {
+        let bytes = if be { n.to_be_bytes() } else { n.to_le_bytes() };
+        image[at..at + 4].copy_from_slice(&bytes);
+    }
+
+    fn put_u64(image: &mut [u8], at: usize, n: u64, be: bool) {
+        let bytes = if be { n.to_be_bytes() } else { n.to_le_bytes() };
+        image[at..at + 8].copy_from_slice(&bytes);
+    }
+
+    #[tokio::test]
+    async fn raw_image_with_zeros() {
+        let mut image = vec![0; 4096];
+        image[1024..1536].copy_from_slice(&pattern(1, 512));
+        image[3000] = 7;
+
+        let source = MemorySource(image.clone());
+        assert_eq!(
+            ImageFormat::detect(&source).await.unwrap(),
+            ImageFormat::Raw
+        );
+        assert_eq!(
+            import_image(image.clone(), 8192).await.unwrap()[..4096],
+            image
+        );
+
+        // Too big for the volume.
+        assert!(import_image(image, 2048).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn import_writes_zeros_over_old_data() {
+        let size = 4096 * 6;
+        let (image, expected) = qcow2_image(3, size as u64);
+        let mut raw = vec![0; 4096];
+        raw[1024..1536].copy_from_slice(&pattern(1, 512));
+
+        for (image, expected) in [(image, expected), (raw.clone(), raw.clone())]
+        {
+            let source = MemorySource(image);
+            let volume = volume(size + 4096).await;
+            volume
+                .write_to_byte_offset(0, Bytes::from(vec![0xff; size + 4096]))
+                .await
+                .unwrap();
+
+            // Every block the image covers is replaced, and nothing past
+            // its end is touched.
+            import_into(&volume, &source, false).await.unwrap();
+            let data = volume_data(&volume).await;
+            assert_eq!(data[..expected.len()], expected);
+            assert!(data[expected.len()..].iter().all(|b| *b == 0xff));
+        }
+
+        // Skipping zeros leaves what was there.
+        let source = MemorySource(raw);
+        let volume = volume(4096).await;
+        volume
+            .write_to_byte_offset(0, Bytes::from(vec![0xff; 4096]))
+            .await
+            .unwrap();
+        import_into(&volume, &source, true).await.unwrap();
+        let data = volume_data(&volume).await;
+        assert_eq!(data[..512], [0xff; 512]);
+        assert_eq!(data[1024..1536], pattern(1, 512));
+    }
+
+    #[tokio::test]
+    async fn writer_fills_partial_blocks() {
+        let volume = volume(4096).await;
+        let mut writer = DiskWriter::new(&volume, false).await.unwrap();
+        writer.write(100, &[1; 100]).await.unwrap();
+        writer.write(300, &[2; 1000]).await.unwrap();
+        writer.write(3000, &[3; 10]).await.unwrap();
+        writer.finish().await.unwrap();
+
+        let mut expected = vec![0; 4096];
+        expected[100..200].copy_from_slice(&[1; 100]);
+        expected[300..1300].copy_from_slice(&[2; 1000]);
+        expected[3000..3010].copy_from_slice(&[3; 10]);
+        assert_eq!(volume_data(&volume).await, expected);
+    }
+
+    // A qcow2 image of 4 KiB clusters: the header, the L1 table, one L2
+    // table, then the data clusters.
+    fn qcow2_image(version: u32, size: u64) -> (Vec<u8>, Vec<u8>) {
+        let cluster = 4096;
+        let mut image = vec![0; cluster * 3];
+        image[0..4].copy_from_slice(QCOW2_MAGIC);
+        put_u32(&mut image, 4, version, true);
+        put_u32(&mut image, 20, 12, true);
+        put_u64(&mut image, 24, size, true);
+        put_u32(&mut image, 36, 1, true);
+        put_u64(&mut image, 40, cluster as u64, true);
+        put_u32(&mut image, 100, 104, true);
+        put_u64(&mut image, cluster, 2 * cluster as u64, true);
+
+        let mut expected = vec![0; size as usize];
+        let l2 = 2 * cluster;
+
+        // Cluster 0 and 1 are allocated next to each other.
+        for guest in 0..2 {
+            let host = image.len();
+            let data = pattern(guest as u8, cluster);
+            expected[guest * cluster..(guest + 1) * cluster]
+                .copy_from_slice(&data);
+            image.extend_from_slice(&data);
+            put_u64(&mut image, l2 + guest * 8, host as u64 | 1 << 63, true);
+        }
+
+        // Cluster 3 is compressed, and starts part way into a sector.
+        image.extend_from_slice(&[0xaa; 100]);
+        let host = image.len() as u64;
+        let data = pattern(3, cluster);
+        expected[3 * cluster..4 * cluster].copy_from_slice(&data);
+        let mut encoder =
+            DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        image.extend_from_slice(&compressed);
+        let sectors = (host % 512 + compressed.len() as u64 + 511) / 512 - 1;
+        put_u64(
+            &mut image,
+            l2 + 3 * 8,
+            QCOW2_COMPRESSED | sectors << (62 - 4) | host,
+            true,
+        );
+
+        // Cluster 4 is allocated but reads as zeros.
+        let host = image.len();
+        image.extend_from_slice(&pattern(4, cluster));
+        put_u64(&mut image, l2 + 4 * 8, host as u64 | QCOW2_ZERO, true);
+
+        (image, expected)
+    }
+
+    #[tokio::test]
+    async fn qcow2_import() {
+        let size = 4096 * 6;
+        let (image, expected) = qcow2_image(3, size);
+        let source = MemorySource(image.clone());
+        assert_eq!(
+            ImageFormat::detect(&source).await.unwrap(),
+            ImageFormat::Qcow2
+        );
+        let data = import_image(image, 65536).await.unwrap();
+        assert_eq!(data[..size as usize], expected);
+        assert!(data[size as usize..].iter().all(|b| *b == 0));
+
+        // A disk bigger than the volume is refused before anything is read.
+        let (image, _) = qcow2_image(2, 1 << 20);
+        assert!(import_image(image, 65536).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn qcow2_reads_only_the_l1_it_needs() {
+        // An L1 table that claims to be far bigger than the image.
+        let size = 4096 * 6;
+        let (mut image, expected) = qcow2_image(3, size);
+        put_u32(&mut image, 36, u32::MAX, true);
+        let data = import_image(image, 65536).await.unwrap();
+        assert_eq!(data[..size as usize], expected);
+    }
+
+    #[tokio::test]
+    async fn qcow2_refuses_backing_file() {
+        let (mut image, _) = qcow2_image(3, 4096 * 6);
+        put_u64(&mut image, 8, 512, true);
+        assert!(import_image(image, 65536).await.is_err());
+    }
+
+    fn vmdk_grain(image: &mut Vec<u8>, sector: u64, data: &[u8]) {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        image.extend_from_slice(&sector.to_le_bytes());
+        image.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        image.extend_from_slice(&compressed);
+        image.resize(round_up(image.len() as u64, 512) as usize, 0);
+    }
+
+    fn vmdk_metadata(image: &mut Vec<u8>, sectors: u64, marker_type: u32) {
+        let mut marker = vec![0; 512];
+        put_u64(&mut marker, 0, sectors, false);
+        put_u32(&mut marker, 12, marker_type, false);
+        image.extend_from_slice(&marker);
+        image.extend(vec![0xee; sectors as usize * 512]);
+    }
+
+    #[tokio::test]
+    async fn vmdk_stream_optimized_import() {
+        // 8 sector grains, on a 40 sector disk.
+        let mut image = vec![0; 512];
+        image[0..4].copy_from_slice(VMDK_MAGIC);
+        put_u32(&mut image, 4, 3, false);
+        put_u32(&mut image, 8, VMDK_COMPRESSED | VMDK_MARKERS | 1, false);
+        put_u64(&mut image, 12, 40, false);
+        put_u64(&mut image, 20, 8, false);
+        put_u64(&mut image, 64, 1, false);
+        image[77..79].copy_from_slice(&VMDK_COMPRESSION_DEFLATE.to_le_bytes());
+
+        let mut expected = vec![0; 40 * 512];
+        let grain = pattern(1, 8 * 512);
+        vmdk_grain(&mut image, 8, &grain);
+        expected[8 * 512..16 * 512].copy_from_slice(&grain);
+
+        // A grain table between the grains is skipped.
+        vmdk_metadata(&mut image, 1, 1);
+
+        // The last grain is cut short by the end of the disk.
+        let grain = pattern(2, 8 * 512);
+        vmdk_grain(&mut image, 32, &grain);
+        expected[32 * 512..].copy_from_slice(&grain);
+
+        vmdk_metadata(&mut image, 0, VMDK_MARKER_EOS);
+
+        let source = MemorySource(image.clone());
+        assert_eq!(
+            ImageFormat::detect(&source).await.unwrap(),
+            ImageFormat::VmdkStreamOptimized
+        );
+        let data = import_image(image.clone(), 40 * 512).await.unwrap();
+        assert_eq!(data, expected);
+
+        // A capacity in sectors that is too big to be in bytes.
+        let mut huge = image.clone();
+        put_u64(&mut huge, 12, u64::MAX / 256, false);
+        assert!(import_image(huge, 40 * 512).await.is_err());
+
+        // Without compressed grains, it isn't stream optimized.
+        put_u32(&mut image, 8, 1, false);
+        assert!(import_image(image, 40 * 512).await.is_err());
+    }
+
+    fn vhd_footer(disk_type: u32, data_offset: u64, size: u64) -> Vec<u8> {
+        let mut footer = vec![0; 512];
+        footer[0..8].copy_from_slice(VHD_COOKIE);
+        put_u64(&mut footer, 16, data_offset, true);
+        put_u64(&mut footer, 40, size, true);
+        put_u64(&mut footer, 48, size, true);
+        put_u32(&mut footer, 60, disk_type, true);
+        footer
+    }
+
+    #[tokio::test]
+    async fn vhd_fixed_import() {
+        let data = pattern(5, 4096);
+        let mut image = data.clone();
+        image.extend(vhd_footer(VHD_FIXED, u64::MAX, 4096));
+
+        let source = MemorySource(image.clone());
+        assert_eq!(
+            ImageFormat::detect(&source).await.unwrap(),
+            ImageFormat::VhdFixed
+        );
+        assert_eq!(import_image(image, 4096).await.unwrap(), data);
+    }
+
+    /// Two blocks, with only the second allocated, and only some of its
+    /// sectors present. Returns the image and what it should import as.
+    fn vhd_dynamic_image(block_size: u32) -> (Vec<u8>, Vec<u8>) {
+        let size = 2 * block_size as u64;
+        let mut image = vhd_footer(VHD_DYNAMIC, 512, size);
+
+        let mut header = vec![0; 1024];
+        header[0..8].copy_from_slice(VHD_SPARSE_COOKIE);
+        put_u64(&mut header, 8, u64::MAX, true);
+        put_u64(&mut header, 16, 1536, true);
+        put_u32(&mut header, 28, 2, true);
+        put_u32(&mut header, 32, block_size, true);
+        image.extend(header);
+
+        let mut bat = vec![0; 512];
+        put_u32(&mut bat, 0, VHD_UNALLOCATED, true);
+        put_u32(&mut bat, 4, 4, true);
+        image.extend(bat);
+
+        let mut bitmap = vec![0; 512];
+        bitmap[0] = 0b1100_0001;
+        image.extend(bitmap);
+        let block = pattern(9, block_size as usize);
+        image.extend(&block);
+        image.extend(vhd_footer(VHD_DYNAMIC, 512, size));
+
+        let mut expected = vec![0; size as usize];
+        let second = block_size as usize;
+        expected[second..second + 1024].copy_from_slice(&block[..1024]);
+        if block_size > 3584 {
+            expected[second + 3584..second + 4096]
+                .copy_from_slice(&block[3584..4096]);
+        }
+
+        (image, expected)
+    }
+
+    #[tokio::test]
+    async fn vhd_dynamic_import() {
+        let (image, expected) = vhd_dynamic_image(4096);
+
+        let source = MemorySource(image.clone());
+        assert_eq!(
+            ImageFormat::detect(&source).await.unwrap(),
+            ImageFormat::VhdDynamic
+        );
+        assert_eq!(import_image(image, 8192).await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn vhd_dynamic_reads_only_the_bat_it_needs() {
+        // A block allocation table that claims to be far bigger than the
+        // image.
+        let (mut image, expected) = vhd_dynamic_image(4096);
+        put_u32(&mut image, 512 + 28, u32::MAX, true);
+        assert_eq!(import_image(image, 8192).await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn vhd_dynamic_import_small_blocks() {
+        // Blocks of fewer than 8 sectors still have a bitmap sector.
+        let (image, expected) = vhd_dynamic_image(2048);
+        assert_eq!(import_image(image, 4096).await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn hasher_sees_the_whole_image() {
+        let (image, _) = qcow2_image(3, 4096 * 6);
+        let source = MemorySource(image.clone());
+        let volume = volume(65536).await;
+        let mut writer = DiskWriter::new(&volume, true).await.unwrap();
+        let mut hasher = Sha256::new();
+        import(ImageFormat::Qcow2, &source, &mut writer, Some(&mut hasher))
+            .await
+            .unwrap();
+        assert_eq!(hasher.finalize(), Sha256::digest(&image));
+    }
+}
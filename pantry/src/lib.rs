@@ -1,5 +1,6 @@
 // Copyright 2022 Oxide Computer Company
 
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::Result;
@@ -8,17 +9,25 @@ use slog::{o, Logger};
 
 pub const PROG: &str = "crucible-pantry";
 
+pub mod image;
 pub mod pantry;
+pub mod s3;
 pub mod server;
 
-pub async fn initialize_pantry() -> Result<(Logger, Arc<pantry::Pantry>)> {
+pub async fn initialize_pantry(
+    scrub_state_dir: Option<PathBuf>,
+    s3_credentials: Option<s3::S3Credentials>,
+) -> Result<(Logger, Arc<pantry::Pantry>)> {
     let log = ConfigLogging::StderrTerminal {
         level: ConfigLoggingLevel::Info,
     }
     .to_logger(PROG)?;
 
-    let pantry =
-        Arc::new(pantry::Pantry::new(log.new(o!("component" => "datafile")))?);
+    let pantry = Arc::new(pantry::Pantry::new(
+        log.new(o!("component" => "datafile")),
+        scrub_state_dir,
+        s3_credentials,
+    )?);
 
     Ok((log, pantry))
 }
@@ -18,6 +18,17 @@ enum Args {
     Run {
         #[clap(short = 'l', action)]
         listen: SocketAddr,
+
+        /// Save the progress of volume scrubs in this directory, so a
+        /// scrub picks up where it was if the pantry is restarted.
+        #[clap(long, action)]
+        scrub_state_dir: Option<PathBuf>,
+
+        /// A JSON file with the credentials S3 multipart exports are signed
+        /// with: access_key_id, secret_access_key, region, and optionally
+        /// session_token. Without it, those exports are refused.
+        #[clap(long, action)]
+        s3_credentials: Option<PathBuf>,
     },
 }
 
@@ -44,8 +55,17 @@ async fn main() -> Result<()> {
                 .open(output)?;
             write_openapi(&mut f)
         }
-        Args::Run { listen } => {
-            let (log, pantry) = initialize_pantry().await?;
+        Args::Run {
+            listen,
+            scrub_state_dir,
+            s3_credentials,
+        } => {
+            let s3_credentials = match s3_credentials {
+                Some(path) => Some(crucible_common::read_json(&path)?),
+                None => None,
+            };
+            let (log, pantry) =
+                initialize_pantry(scrub_state_dir, s3_credentials).await?;
 
             let (_, join_handle) =
                 server::run_server(&log, listen, pantry).await?;
@@ -1,7 +1,7 @@
 // Copyright 2022 Oxide Computer Company
 
 use std::collections::BTreeMap;
-use std::str::FromStr;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::anyhow;
@@ -13,64 +13,72 @@ use sha2::Sha256;
 use slog::error;
 use slog::info;
 use slog::Logger;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
 use tokio::sync::Mutex;
+use tokio::sync::OwnedRwLockReadGuard;
+use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 use uuid::Uuid;
 
 use crucible::BlockIO;
+use crucible::Buffer;
+use crucible::Bytes;
+use crucible::ScrubStatus;
 use crucible::SnapshotDetails;
 use crucible::Volume;
 use crucible::VolumeConstructionRequest;
 
+use crate::image;
+use crate::image::DiskWriter;
+use crate::image::ImageFormat;
+use crate::image::UrlSource;
+use crate::s3;
+use crate::s3::S3Credentials;
+use crate::server::DigestAlgorithm;
 use crate::server::ExpectedDigest;
+use crate::server::ExportMethod;
+use crate::server::ImportFormat;
 
 pub struct PantryEntry {
-    volume: Volume,
+    volume: Arc<Volume>,
     volume_construction_request: VolumeConstructionRequest,
+
+    /// Held for reading by every export of the volume, which run without
+    /// the entry lock. Writes to the volume are refused while it is held.
+    exports: Arc<RwLock<()>>,
 }
 
 impl PantryEntry {
     pub const MAX_CHUNK_SIZE: usize = 512 * 1024;
 
+    /// Stop writes to the volume while an export is reading it out.
+    fn writes_allowed(&self) -> Result<tokio::sync::RwLockWriteGuard<'_, ()>> {
+        self.exports
+            .try_write()
+            .map_err(|_| anyhow!("volume is being exported"))
+    }
+
     pub async fn import_from_url(
         &self,
         url: String,
         expected_digest: Option<ExpectedDigest>,
+        format: ImportFormat,
+        target_is_zeroed: bool,
     ) -> Result<()> {
-        // validate the URL can be reached, and grab the content length
+        let _writes = self.writes_allowed()?;
+
         let dur = std::time::Duration::from_secs(5);
         let client = reqwest::ClientBuilder::new()
             .connect_timeout(dur)
             .timeout(dur)
             .build()?;
 
-        let response = client.head(&url).send().await?;
-
-        if !response.status().is_success() {
-            bail!("querying url returned: {}", response.status());
-        }
-
-        let content_length = response
-            .headers()
-            .get(reqwest::header::CONTENT_LENGTH)
-            .ok_or("no content length!")
-            .map_err(|e| anyhow!(e))?;
-
-        let request_total_size = usize::from_str(content_length.to_str()?)?;
-
-        // check volume size
-        let volume_total_size = self.volume.total_size().await?;
-        if request_total_size > volume_total_size as usize {
-            bail!(
-                "volume size {} smaller than size {} at url {}",
-                volume_total_size,
-                request_total_size,
-                url,
-            );
-        }
+        let source = UrlSource::new(client, url).await?;
 
-        // import chunks into the volume, optionally hashing the bytes for later
-        // matching against the expected digest
+        // import into the volume, converting from the image's format,
+        // optionally hashing the bytes for later matching against the
+        // expected digest
         let mut hasher = if let Some(ref expected_digest) = expected_digest {
             match expected_digest {
                 ExpectedDigest::Sha256(_) => Some(Sha256::new()),
@@ -79,52 +87,34 @@ impl PantryEntry {
             None
         };
 
-        let volume_block_size = self.volume.get_block_size().await?;
-        for chunk in (0..request_total_size).step_by(Self::MAX_CHUNK_SIZE) {
-            let start = chunk;
-            let end =
-                std::cmp::min(start + Self::MAX_CHUNK_SIZE, request_total_size);
-
-            let response = client
-                .get(&url)
-                .header(
-                    reqwest::header::RANGE,
-                    format!("bytes={}-{}", start, end - 1),
-                )
-                .send()
-                .await?;
-
-            let content_length = response
-                .headers()
-                .get(reqwest::header::CONTENT_LENGTH)
-                .ok_or("no content length!")
-                .map_err(|e| anyhow!(e))?;
-
-            let content_length = usize::from_str(content_length.to_str()?)?;
-
-            if content_length != (end - start) {
-                // the remote web server didn't honour the RANGE header!
-                bail!(
-                    "RANGE header bytes={}-{}, content length returned is {}!",
-                    start,
-                    end - 1,
-                    content_length,
-                );
-            }
-
-            assert!(content_length <= Self::MAX_CHUNK_SIZE);
-            assert!(content_length % volume_block_size as usize == 0);
-
-            let bytes = response.bytes().await?;
-
-            if let Some(ref mut hasher) = hasher {
-                hasher.update(&bytes);
+        // A raw image is never probed, as a disk can start with anything,
+        // including another format's magic.
+        let format = match format {
+            ImportFormat::Raw => ImageFormat::Raw,
+            ImportFormat::Detect => ImageFormat::detect(&source).await?,
+            claimed => {
+                let detected = ImageFormat::detect(&source).await?;
+                if !matches!(
+                    (claimed, detected),
+                    (ImportFormat::Qcow2, ImageFormat::Qcow2)
+                        | (
+                            ImportFormat::Vmdk,
+                            ImageFormat::VmdkStreamOptimized
+                        )
+                        | (
+                            ImportFormat::Vhd,
+                            ImageFormat::VhdFixed | ImageFormat::VhdDynamic
+                        )
+                ) {
+                    bail!("image is {:?}, not {:?}", detected, claimed);
+                }
+                detected
             }
-
-            self.volume
-                .write_to_byte_offset(start as u64, bytes)
-                .await?;
-        }
+        };
+        let mut writer =
+            DiskWriter::new(&self.volume, target_is_zeroed).await?;
+        image::import(format, &source, &mut writer, hasher.as_mut()).await?;
+        writer.finish().await?;
 
         // flush
 
@@ -168,6 +158,7 @@ impl PantryEntry {
             );
         }
 
+        let _writes = self.writes_allowed()?;
         self.volume
             .write_to_byte_offset(offset, data.into())
             .await?;
@@ -175,18 +166,323 @@ impl PantryEntry {
         Ok(())
     }
 
+    pub async fn bulk_read(&self, offset: u64, size: usize) -> Result<Bytes> {
+        if size > Self::MAX_CHUNK_SIZE {
+            bail!(
+                "read size {} over max chunk size {}!",
+                size,
+                Self::MAX_CHUNK_SIZE
+            );
+        }
+
+        let buffer = Buffer::new(size);
+        self.volume
+            .read_from_byte_offset(offset, buffer.clone())
+            .await?;
+
+        let data = buffer.as_vec().await.clone();
+        Ok(Bytes::from(data))
+    }
+
+    /// A handle to read the volume out through without holding the entry
+    /// lock. The volume can't be written to until the handle is dropped.
+    pub async fn export_handle(&self) -> ExportHandle {
+        ExportHandle {
+            volume: self.volume.clone(),
+            _exporting: self.exports.clone().read_owned().await,
+        }
+    }
+
     pub async fn scrub(&self, log: &Logger) -> Result<()> {
         self.volume.scrub(log, None, None).await?;
         Ok(())
     }
 
+    pub fn scrub_status(&self) -> watch::Receiver<Option<ScrubStatus>> {
+        self.volume.scrub_status()
+    }
+
     pub async fn detach(&self) -> Result<()> {
+        // Let any export finish reading first.
+        let _exports = self.exports.write().await;
         self.volume.flush(None).await?;
         self.volume.deactivate().await?;
         Ok(())
     }
 }
 
+/// A volume being exported, see PantryEntry::export_handle
+pub struct ExportHandle {
+    volume: Arc<Volume>,
+    _exporting: OwnedRwLockReadGuard<()>,
+}
+
+impl ExportHandle {
+    /// S3 wants every part of a multipart upload but the last to be at least
+    /// 5 MiB.
+    pub const MULTIPART_PART_SIZE: usize = 16 * PantryEntry::MAX_CHUNK_SIZE;
+
+    /// Read the whole volume out, a chunk at a time, and upload it to a URL.
+    /// If asked to, return the digest of what was sent.
+    pub async fn export_to_url(
+        &self,
+        url: String,
+        method: ExportMethod,
+        digest: Option<DigestAlgorithm>,
+        s3: Option<S3Credentials>,
+    ) -> Result<Option<String>> {
+        // Only connecting is timed out, uploading a large volume takes as
+        // long as it takes.
+        let client = reqwest::ClientBuilder::new()
+            .connect_timeout(std::time::Duration::from_secs(5))
+            .build()?;
+
+        let mut hasher = digest.map(|digest| match digest {
+            DigestAlgorithm::Sha256 => Sha256::new(),
+        });
+
+        match method {
+            ExportMethod::Put => {
+                self.export_put(&client, &url, &mut hasher).await?
+            }
+            ExportMethod::S3Multipart => {
+                let s3 = s3.ok_or_else(|| anyhow!("no S3 credentials"))?;
+                self.export_s3_multipart(&client, &s3, &url, &mut hasher)
+                    .await?
+            }
+        }
+
+        Ok(hasher.map(|hasher| hex::encode(hasher.finalize())))
+    }
+
+    async fn read_chunk(
+        &self,
+        offset: usize,
+        total_size: usize,
+        hasher: &mut Option<Sha256>,
+    ) -> Result<Bytes> {
+        let end =
+            std::cmp::min(offset + PantryEntry::MAX_CHUNK_SIZE, total_size);
+        let buffer = Buffer::new(end - offset);
+        self.volume
+            .read_from_byte_offset(offset as u64, buffer.clone())
+            .await?;
+        let bytes = Bytes::from(buffer.as_vec().await.clone());
+
+        if let Some(ref mut hasher) = hasher {
+            hasher.update(&bytes);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Send the volume as the body of one PUT. The body is streamed as it is
+    /// read, so the volume is never all in memory.
+    async fn export_put(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        hasher: &mut Option<Sha256>,
+    ) -> Result<()> {
+        let total_size = self.volume.total_size().await? as usize;
+
+        let (tx, rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(2);
+        let body = reqwest::Body::wrap_stream(futures::stream::unfold(
+            rx,
+            |mut rx| async move { rx.recv().await.map(|chunk| (chunk, rx)) },
+        ));
+        let request = tokio::spawn(
+            client
+                .put(url)
+                .header(reqwest::header::CONTENT_LENGTH, total_size)
+                .body(body)
+                .send(),
+        );
+
+        for offset in (0..total_size).step_by(PantryEntry::MAX_CHUNK_SIZE) {
+            let bytes = match self.read_chunk(offset, total_size, hasher).await
+            {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    request.abort();
+                    return Err(e);
+                }
+            };
+
+            if tx.send(Ok(bytes)).await.is_err() {
+                // The request has stopped taking the body, it will say why.
+                break;
+            }
+        }
+        drop(tx);
+
+        let response = request.await??;
+        if !response.status().is_success() {
+            bail!("PUT to url returned: {}", response.status());
+        }
+
+        Ok(())
+    }
+
+    /// Send the volume as an S3 multipart upload, in parts of
+    /// MULTIPART_PART_SIZE. If a part fails, the upload is aborted.
+    async fn export_s3_multipart(
+        &self,
+        client: &reqwest::Client,
+        s3: &S3Credentials,
+        url: &str,
+        hasher: &mut Option<Sha256>,
+    ) -> Result<()> {
+        let response = s3
+            .send(
+                client,
+                reqwest::Method::POST,
+                url,
+                &[("uploads", "")],
+                vec![],
+            )
+            .await?;
+        if !response.status().is_success() {
+            bail!("starting multipart upload returned: {}", response.status());
+        }
+        let body = response.text().await?;
+        let upload_id =
+            s3::s3_response(&body, "InitiateMultipartUploadResult")?
+                .child("UploadId")
+                .ok_or_else(|| anyhow!("no UploadId in response: {}", body))?
+                .text
+                .clone();
+
+        let result = self
+            .upload_s3_parts(client, s3, url, &upload_id, hasher)
+            .await;
+
+        if result.is_err() {
+            // The parts already sent are kept until the upload is aborted.
+            let _ = s3
+                .send(
+                    client,
+                    reqwest::Method::DELETE,
+                    url,
+                    &[("uploadId", &upload_id)],
+                    vec![],
+                )
+                .await;
+        }
+
+        result
+    }
+
+    async fn upload_s3_parts(
+        &self,
+        client: &reqwest::Client,
+        s3: &S3Credentials,
+        url: &str,
+        upload_id: &str,
+        hasher: &mut Option<Sha256>,
+    ) -> Result<()> {
+        let total_size = self.volume.total_size().await? as usize;
+
+        let mut etags = Vec::new();
+        for part_start in (0..total_size).step_by(Self::MULTIPART_PART_SIZE) {
+            let part_end = std::cmp::min(
+                part_start + Self::MULTIPART_PART_SIZE,
+                total_size,
+            );
+
+            let mut part = Vec::with_capacity(part_end - part_start);
+            for offset in
+                (part_start..part_end).step_by(PantryEntry::MAX_CHUNK_SIZE)
+            {
+                part.extend_from_slice(
+                    &self.read_chunk(offset, part_end, hasher).await?,
+                );
+            }
+
+            let part_number = (etags.len() + 1).to_string();
+            let response = s3
+                .send(
+                    client,
+                    reqwest::Method::PUT,
+                    url,
+                    &[("partNumber", &part_number), ("uploadId", upload_id)],
+                    part,
+                )
+                .await?;
+            if !response.status().is_success() {
+                bail!(
+                    "uploading part {} returned: {}",
+                    part_number,
+                    response.status()
+                );
+            }
+
+            let etag = response
+                .headers()
+                .get(reqwest::header::ETAG)
+                .ok_or_else(|| anyhow!("no ETag for part {}!", part_number))?
+                .to_str()?
+                .to_string();
+            etags.push(etag);
+        }
+
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in etags.iter().enumerate() {
+            complete.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                i + 1,
+                String::from_utf8_lossy(&quick_xml::escape::escape(
+                    etag.as_bytes()
+                )),
+            ));
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+
+        let response = s3
+            .send(
+                client,
+                reqwest::Method::POST,
+                url,
+                &[("uploadId", upload_id)],
+                complete.into_bytes(),
+            )
+            .await?;
+        if !response.status().is_success() {
+            bail!(
+                "completing multipart upload returned: {}",
+                response.status()
+            );
+        }
+
+        // S3 can fail a completion after it has sent a 200.
+        let body = response.text().await?;
+        s3::s3_response(&body, "CompleteMultipartUploadResult")?;
+
+        Ok(())
+    }
+}
+
+/// A background job, and what it reports while it runs
+struct PantryJob {
+    join_handle: JoinHandle<Result<()>>,
+
+    /// How a scrub job is going
+    scrub_status: Option<watch::Receiver<Option<ScrubStatus>>>,
+
+    /// The digest an export job computed, once it has finished
+    digest: Option<watch::Receiver<Option<String>>>,
+}
+
+impl PantryJob {
+    fn new(join_handle: JoinHandle<Result<()>>) -> PantryJob {
+        PantryJob {
+            join_handle,
+            scrub_status: None,
+            digest: None,
+        }
+    }
+}
+
 /// Pantry stores opened Volumes in-memory
 pub struct Pantry {
     pub log: Logger,
@@ -198,15 +494,32 @@ pub struct Pantry {
 
     /// Pantry can run background jobs on Volumes, and currently running jobs
     /// are stored here.
-    jobs: Mutex<BTreeMap<String, JoinHandle<Result<()>>>>,
+    jobs: Mutex<BTreeMap<String, PantryJob>>,
+
+    /// Where Volumes save how far a scrub got, if anywhere.
+    scrub_state_dir: Option<PathBuf>,
+
+    /// What S3 multipart exports are signed with. Without them, those
+    /// exports are refused.
+    s3_credentials: Option<S3Credentials>,
 }
 
 impl Pantry {
-    pub fn new(log: Logger) -> Result<Pantry> {
+    pub fn new(
+        log: Logger,
+        scrub_state_dir: Option<PathBuf>,
+        s3_credentials: Option<S3Credentials>,
+    ) -> Result<Pantry> {
+        if let Some(dir) = &scrub_state_dir {
+            std::fs::create_dir_all(dir)?;
+        }
+
         Ok(Pantry {
             log,
             entries: Mutex::new(BTreeMap::default()),
             jobs: Mutex::new(BTreeMap::default()),
+            scrub_state_dir,
+            s3_credentials,
         })
     }
 
@@ -254,9 +567,22 @@ impl Pantry {
             "no entry exists for volume {}, constructing...", volume_id
         );
 
-        let volume =
-            Volume::construct(volume_construction_request.clone(), None)
-                .await?;
+        // The pantry's scrub state directory is used unless the request
+        // names one of its own.
+        let mut request = volume_construction_request.clone();
+        if let (
+            Some(dir),
+            VolumeConstructionRequest::Volume {
+                scrub_state_dir, ..
+            },
+        ) = (&self.scrub_state_dir, &mut request)
+        {
+            if scrub_state_dir.is_none() {
+                *scrub_state_dir = Some(dir.to_string_lossy().to_string());
+            }
+        }
+
+        let volume = Volume::construct(request, None).await?;
 
         info!(self.log, "volume {} constructed ok", volume_id);
 
@@ -267,8 +593,9 @@ impl Pantry {
         entries.insert(
             volume_id.clone(),
             Arc::new(Mutex::new(PantryEntry {
-                volume,
+                volume: Arc::new(volume),
                 volume_construction_request,
+                exports: Arc::new(RwLock::new(())),
             })),
         );
 
@@ -303,7 +630,47 @@ impl Pantry {
     ) -> Result<bool, HttpError> {
         let jobs = self.jobs.lock().await;
         match jobs.get(&job_id) {
-            Some(join_handle) => Ok(join_handle.is_finished()),
+            Some(job) => Ok(job.join_handle.is_finished()),
+
+            None => {
+                error!(self.log, "job {} not a pantry job", job_id);
+
+                Err(HttpError::for_not_found(None, job_id.to_string()))
+            }
+        }
+    }
+
+    /// How a scrub job is going, if the job is a scrub that has started
+    pub async fn job_scrub_status(
+        &self,
+        job_id: String,
+    ) -> Result<Option<ScrubStatus>, HttpError> {
+        let jobs = self.jobs.lock().await;
+        match jobs.get(&job_id) {
+            Some(job) => Ok(job
+                .scrub_status
+                .as_ref()
+                .and_then(|status| status.borrow().clone())),
+
+            None => {
+                error!(self.log, "job {} not a pantry job", job_id);
+
+                Err(HttpError::for_not_found(None, job_id.to_string()))
+            }
+        }
+    }
+
+    /// The digest an export job computed, if it was asked to and has finished
+    pub async fn job_digest(
+        &self,
+        job_id: String,
+    ) -> Result<Option<String>, HttpError> {
+        let jobs = self.jobs.lock().await;
+        match jobs.get(&job_id) {
+            Some(job) => Ok(job
+                .digest
+                .as_ref()
+                .and_then(|digest| digest.borrow().clone())),
 
             None => {
                 error!(self.log, "job {} not a pantry job", job_id);
@@ -323,8 +690,8 @@ impl Pantry {
         // If this errors, then the job has failed in some way, so don't leave
         // it in the list of jobs.
         match jobs.remove(&job_id) {
-            Some(join_handle) => {
-                let result = join_handle.await.map_err(|e| {
+            Some(job) => {
+                let result = job.join_handle.await.map_err(|e| {
                     HttpError::for_internal_error(e.to_string())
                 })?;
                 jobs.remove(&job_id);
@@ -344,6 +711,8 @@ impl Pantry {
         volume_id: String,
         url: String,
         expected_digest: Option<ExpectedDigest>,
+        format: ImportFormat,
+        target_is_zeroed: bool,
     ) -> Result<String, HttpError> {
         let entry = self.entry(volume_id).await?;
         let entry = entry.clone();
@@ -352,13 +721,18 @@ impl Pantry {
             entry
                 .lock()
                 .await
-                .import_from_url(url, expected_digest)
+                .import_from_url(
+                    url,
+                    expected_digest,
+                    format,
+                    target_is_zeroed,
+                )
                 .await
         });
 
         let mut jobs = self.jobs.lock().await;
         let job_id = Uuid::new_v4().to_string();
-        jobs.insert(job_id.clone(), join_handle);
+        jobs.insert(job_id.clone(), PantryJob::new(join_handle));
 
         Ok(job_id)
     }
@@ -396,17 +770,91 @@ impl Pantry {
         Ok(())
     }
 
+    pub async fn bulk_read(
+        &self,
+        volume_id: String,
+        offset: u64,
+        size: usize,
+    ) -> Result<Bytes, HttpError> {
+        let entry = self.entry(volume_id).await?;
+        let data = entry
+            .lock()
+            .await
+            .bulk_read(offset, size)
+            .await
+            .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+
+        Ok(data)
+    }
+
+    pub async fn export_to_url(
+        &self,
+        volume_id: String,
+        url: String,
+        method: ExportMethod,
+        digest: Option<DigestAlgorithm>,
+    ) -> Result<String, HttpError> {
+        let s3_credentials = match method {
+            ExportMethod::Put => None,
+            ExportMethod::S3Multipart => match &self.s3_credentials {
+                Some(s3_credentials) => Some(s3_credentials.clone()),
+                None => {
+                    return Err(HttpError::for_bad_request(
+                        None,
+                        "the pantry has no S3 credentials".to_string(),
+                    ));
+                }
+            },
+        };
+
+        let entry = self.entry(volume_id).await?;
+        let entry = entry.clone();
+
+        let (digest_tx, digest_rx) = watch::channel(None);
+        let join_handle = tokio::spawn(async move {
+            // The entry is only locked long enough to get a handle, other
+            // requests can use it while the export runs.
+            let handle = entry.lock().await.export_handle().await;
+            let digest = handle
+                .export_to_url(url, method, digest, s3_credentials)
+                .await?;
+            digest_tx.send_replace(digest);
+            Ok(())
+        });
+
+        let mut jobs = self.jobs.lock().await;
+        let job_id = Uuid::new_v4().to_string();
+        jobs.insert(
+            job_id.clone(),
+            PantryJob {
+                join_handle,
+                scrub_status: None,
+                digest: Some(digest_rx),
+            },
+        );
+
+        Ok(job_id)
+    }
+
     pub async fn scrub(&self, volume_id: String) -> Result<String, HttpError> {
         let entry = self.entry(volume_id).await?;
         let entry = entry.clone();
         let log = self.log.clone();
 
+        let scrub_status = entry.lock().await.scrub_status();
         let join_handle =
             tokio::spawn(async move { entry.lock().await.scrub(&log).await });
 
         let mut jobs = self.jobs.lock().await;
         let job_id = Uuid::new_v4().to_string();
-        jobs.insert(job_id.clone(), join_handle);
+        jobs.insert(
+            job_id.clone(),
+            PantryJob {
+                join_handle,
+                scrub_status: Some(scrub_status),
+                digest: None,
+            },
+        );
 
         Ok(job_id)
     }
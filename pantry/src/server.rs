@@ -18,6 +18,7 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use slog::{info, o, Logger};
 
+use crucible::ScrubStatus;
 use crucible::VolumeConstructionRequest;
 
 #[derive(Deserialize, JsonSchema)]
@@ -66,6 +67,14 @@ struct JobPath {
 #[derive(Serialize, JsonSchema)]
 struct JobPollResponse {
     pub job_is_finished: bool,
+
+    /// For a scrub job, how far it has got, how fast it is going, and
+    /// whether the volume still needs its read only parent.
+    pub scrub_status: Option<ScrubStatus>,
+
+    /// For an export job that was asked for a digest, the hex digest of the
+    /// data sent, once the job has finished.
+    pub digest: Option<String>,
 }
 
 /// Poll to see if a Pantry background job is done
@@ -80,9 +89,15 @@ async fn is_job_finished(
     let path = path.into_inner();
     let pantry = rc.context();
 
-    let job_is_finished = pantry.is_job_finished(path.id).await?;
+    let job_is_finished = pantry.is_job_finished(path.id.clone()).await?;
+    let scrub_status = pantry.job_scrub_status(path.id.clone()).await?;
+    let digest = pantry.job_digest(path.id).await?;
 
-    Ok(HttpResponseOk(JobPollResponse { job_is_finished }))
+    Ok(HttpResponseOk(JobPollResponse {
+        job_is_finished,
+        scrub_status,
+        digest,
+    }))
 }
 
 /// Block on returning a Pantry background job result, then return 200 OK if the
@@ -112,10 +127,38 @@ pub enum ExpectedDigest {
     Sha256(String),
 }
 
+/// What an imported image is
+#[derive(Debug, Copy, Clone, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    /// The image is the disk, byte for byte, whatever it starts with
+    Raw,
+
+    /// A qcow2 image, without a backing file
+    Qcow2,
+
+    /// A stream optimized VMDK
+    Vmdk,
+
+    /// A fixed or dynamic VHD
+    Vhd,
+
+    /// Work out which of the above the image is from its contents
+    Detect,
+}
+
 #[derive(Deserialize, JsonSchema)]
 struct ImportFromUrlRequest {
     pub url: String,
     pub expected_digest: Option<ExpectedDigest>,
+
+    /// What the image is, raw if not set
+    pub format: Option<ImportFormat>,
+
+    /// The volume is known to read as all zeros, so blocks of zeros in the
+    /// image do not need to be written. When not set, every block the
+    /// image covers is written.
+    pub target_is_zeroed: Option<bool>,
 }
 
 #[derive(Serialize, JsonSchema)]
@@ -138,7 +181,13 @@ async fn import_from_url(
     let pantry = rc.context();
 
     let job_id = pantry
-        .import_from_url(path.id.clone(), body.url, body.expected_digest)
+        .import_from_url(
+            path.id.clone(),
+            body.url,
+            body.expected_digest,
+            body.format.unwrap_or(ImportFormat::Raw),
+            body.target_is_zeroed.unwrap_or(false),
+        )
         .await
         .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
 
@@ -204,6 +253,101 @@ async fn bulk_write(
     Ok(HttpResponseUpdatedNoContent())
 }
 
+#[derive(Deserialize, JsonSchema)]
+struct BulkReadRequest {
+    pub offset: u64,
+
+    pub size: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct BulkReadResponse {
+    pub base64_encoded_data: String,
+}
+
+/// Bulk read data from a volume at a specified offset
+#[endpoint {
+    method = POST,
+    path = "/crucible/pantry/0/volume/{id}/bulk_read",
+}]
+async fn bulk_read(
+    rc: Arc<RequestContext<Arc<Pantry>>>,
+    path: TypedPath<VolumePath>,
+    body: TypedBody<BulkReadRequest>,
+) -> Result<HttpResponseOk<BulkReadResponse>, HttpError> {
+    let path = path.into_inner();
+    let body = body.into_inner();
+    let pantry = rc.context();
+
+    let data = pantry
+        .bulk_read(path.id.clone(), body.offset, body.size as usize)
+        .await?;
+
+    Ok(HttpResponseOk(BulkReadResponse {
+        base64_encoded_data: base64::encode(data),
+    }))
+}
+
+/// How an export is uploaded
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportMethod {
+    /// One HTTP PUT of the whole volume
+    Put,
+
+    /// An S3 multipart upload, with requests signed by the credentials the
+    /// pantry was started with
+    S3Multipart,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub enum DigestAlgorithm {
+    Sha256,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ExportToUrlRequest {
+    pub url: String,
+
+    /// How to upload the volume, a PUT if not set
+    pub method: Option<ExportMethod>,
+
+    /// Compute a digest of the data sent, which the job reports when it has
+    /// finished
+    pub digest: Option<DigestAlgorithm>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ExportToUrlResponse {
+    pub job_id: String,
+}
+
+/// Export the whole of a volume to a URL
+#[endpoint {
+    method = POST,
+    path = "/crucible/pantry/0/volume/{id}/export_to_url",
+}]
+async fn export_to_url(
+    rc: Arc<RequestContext<Arc<Pantry>>>,
+    path: TypedPath<VolumePath>,
+    body: TypedBody<ExportToUrlRequest>,
+) -> Result<HttpResponseOk<ExportToUrlResponse>, HttpError> {
+    let path = path.into_inner();
+    let body = body.into_inner();
+    let pantry = rc.context();
+
+    let job_id = pantry
+        .export_to_url(
+            path.id.clone(),
+            body.url,
+            body.method.unwrap_or(ExportMethod::Put),
+            body.digest,
+        )
+        .await?;
+
+    Ok(HttpResponseOk(ExportToUrlResponse { job_id }))
+}
+
 #[derive(Serialize, JsonSchema)]
 struct ScrubResponse {
     pub job_id: String,
@@ -258,6 +402,8 @@ pub fn make_api() -> Result<dropshot::ApiDescription<Arc<Pantry>>, String> {
     api.register(import_from_url)?;
     api.register(snapshot)?;
     api.register(bulk_write)?;
+    api.register(bulk_read)?;
+    api.register(export_to_url)?;
     api.register(scrub)?;
     api.register(detach)?;
 
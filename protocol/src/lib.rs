@@ -3,15 +3,115 @@ use std::cmp::Ordering;
 use std::net::SocketAddr;
 
 use anyhow::bail;
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
 use uuid::Uuid;
 
 const MAX_FRM_LEN: usize = 100 * 1024 * 1024; // 100M
 
+/*
+ * The top two bits of a frame's length say how the rest of it is
+ * compressed, see Compression::frame_tag.
+ */
+const FRM_TAG_SHIFT: u32 = 30;
+const FRM_LEN_MASK: u32 = (1 << FRM_TAG_SHIFT) - 1;
+
+const ZSTD_LEVEL: i32 = 1;
+
 use crucible_common::{Block, CrucibleError, RegionDefinition};
 
+/*
+ * Protocol versions this build speaks.  The upstairs sends its range in
+ * HereIAm, and the downstairs picks the newest version in both ranges.
+ *
+ * Version 1 was a single version exchanged in HereIAmV1, and can't be
+ * spoken by anything that negotiates.  New messages are only ever added
+ * at the end of Message, so a version 1 upstairs can still be decoded and
+ * told so.
+ *
+ * 2: HereIAm carries a version range and feature bits.
+ * 3: RegionResize.
+ * 4: Encryption contexts carry a key ID.  This changed the layout of
+ *    every message with a block context in it, so the codec sends and
+ *    expects the old layout (see v3) until it is told otherwise.
+ */
+pub const PROTOCOL_VERSION_MIN: u32 = 2;
+pub const PROTOCOL_VERSION_MAX: u32 = 4;
+
+pub const VERSION_REGION_RESIZE: u32 = 3;
+pub const VERSION_KEY_ID: u32 = 4;
+
+/*
+ * Optional features, advertised as bits alongside the version.  A feature
+ * is only used on a connection if both sides have it.
+ */
+pub const FEATURE_DISCARD: u64 = 1 << 0;
+pub const FEATURE_COMPRESS_LZ4: u64 = 1 << 1;
+pub const FEATURE_COMPRESS_ZSTD: u64 = 1 << 2;
+pub const FEATURE_DELTA_REPAIR: u64 = 1 << 3;
+
+/// The features this build knows how to use.
+pub const SUPPORTED_FEATURES: u64 = FEATURE_DISCARD
+    | FEATURE_COMPRESS_LZ4
+    | FEATURE_COMPRESS_ZSTD
+    | FEATURE_DELTA_REPAIR;
+
+/// What the two ends of a connection agreed to speak.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Negotiated {
+    pub version: u32,
+    pub features: u64,
+}
+
+impl Negotiated {
+    /// Agree with a peer on the newest version we both speak, and the
+    /// features we both have.  None if our version ranges don't overlap.
+    pub fn with_peer(
+        min_version: u32,
+        max_version: u32,
+        features: u64,
+    ) -> Option<Negotiated> {
+        let version = std::cmp::min(max_version, PROTOCOL_VERSION_MAX);
+        if version < std::cmp::max(min_version, PROTOCOL_VERSION_MIN) {
+            return None;
+        }
+
+        Some(Negotiated {
+            version,
+            features: features & SUPPORTED_FEATURES,
+        })
+    }
+
+    /// Is this what a peer could have agreed to with us?
+    pub fn is_valid(&self) -> bool {
+        (PROTOCOL_VERSION_MIN..=PROTOCOL_VERSION_MAX).contains(&self.version)
+            && self.features & !SUPPORTED_FEATURES == 0
+    }
+
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature == feature
+    }
+
+    /// How block data is compressed on this connection.  lz4 wins when
+    /// both are there, as it costs far less time per IO.
+    pub fn compression(&self) -> Compression {
+        if self.has_feature(FEATURE_COMPRESS_LZ4) {
+            Compression::Lz4
+        } else if self.has_feature(FEATURE_COMPRESS_ZSTD) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Can this message be sent on the connection?
+    pub fn permits(&self, m: &Message) -> bool {
+        self.version >= m.min_version()
+            && self.has_feature(m.required_features())
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Write {
     pub eid: u64,
@@ -27,6 +127,13 @@ pub struct ReadRequest {
     pub offset: Block,
 }
 
+/// Discard (TRIM) a single block, returning it to the unwritten state.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DiscardRequest {
+    pub eid: u64,
+    pub offset: Block,
+}
+
 // Note: if you change this, you may have to add to the dump commands that show
 // block specific data.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -80,6 +187,9 @@ pub struct BlockContext {
 pub struct EncryptionContext {
     pub nonce: Vec<u8>,
     pub tag: Vec<u8>,
+    /// Which of the volume's keys the block was encrypted with.  0 for
+    /// blocks written before keys had an ID.
+    pub key_id: u16,
 }
 
 impl ReadResponse {
@@ -129,9 +239,11 @@ pub struct SnapshotDetails {
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Message {
     /**
-     * Initial negotiation messages
+     * Initial negotiation messages, as version 1 sent them.  A version 1
+     * upstairs still starts with HereIAmV1, so it stays where it was to be
+     * decodable and answered with VersionMismatch.
      */
-    HereIAm {
+    HereIAmV1 {
         version: u32,
         upstairs_id: Uuid,
         session_id: Uuid,
@@ -139,7 +251,7 @@ pub enum Message {
         read_only: bool,
         encrypted: bool,
     },
-    YesItsMe {
+    YesItsMeV1 {
         version: u32,
         repair_addr: SocketAddr,
     },
@@ -320,18 +432,521 @@ pub enum Message {
         result: Result<(), CrucibleError>,
     },
 
+    Discard {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        dependencies: Vec<u64>,
+        requests: Vec<DiscardRequest>,
+    },
+    DiscardAck {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        result: Result<(), CrucibleError>,
+    },
+
+    /// Grow or shrink the region to the given number of extents.
+    RegionResize {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        dependencies: Vec<u64>,
+        extent_count: u32,
+    },
+    RegionResizeAck {
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        job_id: u64,
+        result: Result<(), CrucibleError>,
+    },
+
+    /// Like ExtentRepair, but only copy the blocks that differ from the
+    /// given downstairs.
+    ExtentRepairBlocks {
+        repair_id: u64,
+        extent_id: usize,
+        source_client_id: u8,
+        source_repair_address: SocketAddr,
+        dest_clients: Vec<u8>,
+    },
+
+    /**
+     * Initial negotiation messages from version 2 on.
+     */
+    HereIAm {
+        min_version: u32,
+        max_version: u32,
+        features: u64,
+        upstairs_id: Uuid,
+        session_id: Uuid,
+        gen: u64,
+        read_only: bool,
+        encrypted: bool,
+    },
+    YesItsMe {
+        version: u32,
+        features: u64,
+        repair_addr: SocketAddr,
+    },
+    VersionMismatch {
+        min_version: u32,
+        max_version: u32,
+    },
+
     /*
      * Misc
      */
     Unknown(u32, BytesMut),
 }
 
+impl Message {
+    /// The oldest protocol version with this message in it.
+    pub fn min_version(&self) -> u32 {
+        match self {
+            Message::RegionResize { .. } | Message::RegionResizeAck { .. } => {
+                VERSION_REGION_RESIZE
+            }
+            _ => PROTOCOL_VERSION_MIN,
+        }
+    }
+
+    /// Features both sides need for this message to be sent.
+    pub fn required_features(&self) -> u64 {
+        match self {
+            Message::Discard { .. } | Message::DiscardAck { .. } => {
+                FEATURE_DISCARD
+            }
+            Message::ExtentRepairBlocks { .. } => FEATURE_DELTA_REPAIR,
+            _ => 0,
+        }
+    }
+}
+
+/// How messages carrying block data are compressed on the wire.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    fn frame_tag(&self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_frame_tag(tag: u32) -> Result<Compression, anyhow::Error> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => bail!("unknown frame compression {}", tag),
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        Ok(match self {
+            Compression::None => data.to_vec(),
+            Compression::Lz4 => lz4_flex::compress(data),
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)?,
+        })
+    }
+
+    fn decompress(
+        &self,
+        data: &[u8],
+        len: usize,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let out = match self {
+            Compression::None => data.to_vec(),
+            Compression::Lz4 => lz4_flex::decompress(data, len)?,
+            Compression::Zstd => zstd::bulk::decompress(data, len)?,
+        };
+
+        if out.len() != len {
+            bail!(
+                "frame decompressed to {} bytes, expected {}",
+                out.len(),
+                len
+            );
+        }
+
+        Ok(out)
+    }
+}
+
+impl Message {
+    /*
+     * Only the messages carrying block data are worth compressing.
+     */
+    fn has_block_data(&self) -> bool {
+        matches!(
+            self,
+            Message::Write { .. }
+                | Message::WriteUnwritten { .. }
+                | Message::ReadResponse { .. }
+        )
+    }
+}
+
+/*
+ * Messages with a block context in them, laid out as they were before
+ * encryption contexts had a key ID.  bincode writes a struct variant as
+ * its index and then its fields, so a tuple of the index and the fields
+ * is the same bytes.
+ */
+mod v3 {
+    use super::*;
+
+    const WRITE: u32 = 22;
+    const READ_RESPONSE: u32 = 27;
+    const WRITE_UNWRITTEN: u32 = 28;
+
+    #[derive(Serialize, Deserialize)]
+    struct EncryptionContext {
+        nonce: Vec<u8>,
+        tag: Vec<u8>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct BlockContext {
+        hash: u64,
+        encryption_context: Option<EncryptionContext>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Write<D> {
+        eid: u64,
+        offset: Block,
+        data: D,
+        block_context: BlockContext,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ReadResponse<D> {
+        eid: u64,
+        offset: Block,
+        data: D,
+        block_contexts: Vec<BlockContext>,
+    }
+
+    impl From<&super::BlockContext> for BlockContext {
+        fn from(ctx: &super::BlockContext) -> BlockContext {
+            BlockContext {
+                hash: ctx.hash,
+                encryption_context: ctx.encryption_context.as_ref().map(|ec| {
+                    EncryptionContext {
+                        nonce: ec.nonce.clone(),
+                        tag: ec.tag.clone(),
+                    }
+                }),
+            }
+        }
+    }
+
+    /*
+     * Anything written by a peer that old was written before key IDs.
+     */
+    impl From<BlockContext> for super::BlockContext {
+        fn from(ctx: BlockContext) -> super::BlockContext {
+            super::BlockContext {
+                hash: ctx.hash,
+                encryption_context: ctx.encryption_context.map(|ec| {
+                    super::EncryptionContext {
+                        nonce: ec.nonce,
+                        tag: ec.tag,
+                        key_id: 0,
+                    }
+                }),
+            }
+        }
+    }
+
+    impl<'a> From<&'a super::Write> for Write<&'a bytes::Bytes> {
+        fn from(w: &'a super::Write) -> Self {
+            Write {
+                eid: w.eid,
+                offset: w.offset,
+                data: &w.data,
+                block_context: (&w.block_context).into(),
+            }
+        }
+    }
+
+    impl From<Write<bytes::Bytes>> for super::Write {
+        fn from(w: Write<bytes::Bytes>) -> Self {
+            super::Write {
+                eid: w.eid,
+                offset: w.offset,
+                data: w.data,
+                block_context: w.block_context.into(),
+            }
+        }
+    }
+
+    impl<'a> From<&'a super::ReadResponse> for ReadResponse<&'a BytesMut> {
+        fn from(r: &'a super::ReadResponse) -> Self {
+            ReadResponse {
+                eid: r.eid,
+                offset: r.offset,
+                data: &r.data,
+                block_contexts: r
+                    .block_contexts
+                    .iter()
+                    .map(BlockContext::from)
+                    .collect(),
+            }
+        }
+    }
+
+    impl From<ReadResponse<BytesMut>> for super::ReadResponse {
+        fn from(r: ReadResponse<BytesMut>) -> Self {
+            super::ReadResponse {
+                eid: r.eid,
+                offset: r.offset,
+                data: r.data,
+                block_contexts: r
+                    .block_contexts
+                    .into_iter()
+                    .map(super::BlockContext::from)
+                    .collect(),
+            }
+        }
+    }
+
+    /// Serialize a message the way a version 3 peer expects it, or None
+    /// if its layout hasn't changed since.
+    pub(super) fn serialize(
+        m: &Message,
+    ) -> Result<Option<Vec<u8>>, bincode::Error> {
+        let raw = match m {
+            Message::Write {
+                upstairs_id,
+                session_id,
+                job_id,
+                dependencies,
+                writes,
+            }
+            | Message::WriteUnwritten {
+                upstairs_id,
+                session_id,
+                job_id,
+                dependencies,
+                writes,
+            } => {
+                let variant = if matches!(m, Message::Write { .. }) {
+                    WRITE
+                } else {
+                    WRITE_UNWRITTEN
+                };
+                let writes: Vec<Write<&bytes::Bytes>> =
+                    writes.iter().map(Write::from).collect();
+                bincode::serialize(&(
+                    variant,
+                    upstairs_id,
+                    session_id,
+                    job_id,
+                    dependencies,
+                    writes,
+                ))?
+            }
+            Message::ReadResponse {
+                upstairs_id,
+                session_id,
+                job_id,
+                responses,
+            } => {
+                let responses: Result<Vec<ReadResponse<&BytesMut>>, _> =
+                    match responses {
+                        Ok(r) => Ok(r.iter().map(ReadResponse::from).collect()),
+                        Err(e) => Err(e),
+                    };
+                bincode::serialize(&(
+                    READ_RESPONSE,
+                    upstairs_id,
+                    session_id,
+                    job_id,
+                    responses,
+                ))?
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(raw))
+    }
+
+    /*
+     * The variant index and fields of each message, in order.
+     */
+    type WriteFields =
+        (u32, Uuid, Uuid, u64, Vec<u64>, Vec<Write<bytes::Bytes>>);
+    type ReadResponseFields = (
+        u32,
+        Uuid,
+        Uuid,
+        u64,
+        Result<Vec<ReadResponse<BytesMut>>, CrucibleError>,
+    );
+
+    /// Deserialize a message a version 3 peer sent, or None if its layout
+    /// hasn't changed since.
+    pub(super) fn deserialize(
+        raw: &[u8],
+    ) -> Result<Option<Message>, bincode::Error> {
+        if raw.len() < 4 {
+            return Ok(None);
+        }
+        let mut variant = [0u8; 4];
+        variant.copy_from_slice(&raw[..4]);
+
+        let m = match u32::from_le_bytes(variant) {
+            WRITE | WRITE_UNWRITTEN => {
+                let (
+                    variant,
+                    upstairs_id,
+                    session_id,
+                    job_id,
+                    dependencies,
+                    writes,
+                ): WriteFields = bincode::deserialize(raw)?;
+                let writes =
+                    writes.into_iter().map(super::Write::from).collect();
+                if variant == WRITE {
+                    Message::Write {
+                        upstairs_id,
+                        session_id,
+                        job_id,
+                        dependencies,
+                        writes,
+                    }
+                } else {
+                    Message::WriteUnwritten {
+                        upstairs_id,
+                        session_id,
+                        job_id,
+                        dependencies,
+                        writes,
+                    }
+                }
+            }
+            READ_RESPONSE => {
+                let fields: ReadResponseFields = bincode::deserialize(raw)?;
+                let (_, upstairs_id, session_id, job_id, responses) = fields;
+                Message::ReadResponse {
+                    upstairs_id,
+                    session_id,
+                    job_id,
+                    responses: responses.map(|r| {
+                        r.into_iter().map(super::ReadResponse::from).collect()
+                    }),
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(m))
+    }
+}
+
 #[derive(Debug)]
-pub struct CrucibleEncoder {}
+pub struct CrucibleEncoder {
+    compression: Compression,
+    version: u32,
+    bytes_saved: u64,
+}
 
 impl CrucibleEncoder {
     pub fn new() -> Self {
-        CrucibleEncoder {}
+        CrucibleEncoder {
+            compression: Compression::None,
+            version: PROTOCOL_VERSION_MAX,
+            bytes_saved: 0,
+        }
+    }
+
+    /// Compress block data from now on.  Only to be set once the other
+    /// side has agreed to it.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Lay messages out for the version the other side agreed to.
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    /// Bytes compression kept off the wire since the last call.
+    pub fn take_bytes_saved(&mut self) -> u64 {
+        std::mem::take(&mut self.bytes_saved)
+    }
+
+    fn encode_message(
+        &mut self,
+        m: &Message,
+        dst: &mut BytesMut,
+    ) -> Result<(), anyhow::Error> {
+        if self.version < VERSION_KEY_ID {
+            if let Some(raw) = v3::serialize(m)? {
+                return self.encode_raw(raw, dst);
+            }
+        }
+
+        if self.compression == Compression::None || !m.has_block_data() {
+            let len = CrucibleEncoder::serialized_size(m)?;
+
+            dst.reserve(len);
+            dst.put_u32_le(len as u32);
+            bincode::serialize_into(dst.writer(), m)?;
+
+            return Ok(());
+        }
+
+        let raw = bincode::serialize(m)?;
+        self.encode_raw(raw, dst)
+    }
+
+    /*
+     * Frame an already serialized message carrying block data.
+     */
+    fn encode_raw(
+        &mut self,
+        raw: Vec<u8>,
+        dst: &mut BytesMut,
+    ) -> Result<(), anyhow::Error> {
+        let compressed = self.compression.compress(&raw)?;
+
+        /*
+         * Encrypted blocks won't shrink, so send those as they are.
+         */
+        if self.compression == Compression::None
+            || compressed.len() + 4 >= raw.len()
+        {
+            dst.reserve(raw.len() + 4);
+            dst.put_u32_le((raw.len() + 4) as u32);
+            dst.extend_from_slice(&raw);
+
+            return Ok(());
+        }
+
+        /*
+         * [len | tag] [uncompressed len] [compressed message]
+         */
+        let len = compressed.len() + 8;
+        dst.reserve(len);
+        dst.put_u32_le(
+            len as u32 | (self.compression.frame_tag() << FRM_TAG_SHIFT),
+        );
+        dst.put_u32_le(raw.len() as u32);
+        dst.extend_from_slice(&compressed);
+
+        self.bytes_saved += (raw.len() + 4 - len) as u64;
+
+        Ok(())
     }
 
     fn serialized_size<T: serde::Serialize>(
@@ -358,6 +973,7 @@ impl CrucibleEncoder {
                 encryption_context: Some(EncryptionContext {
                     nonce: vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
                     tag: vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                    key_id: 0,
                 }),
             },
         }
@@ -493,13 +1109,7 @@ impl Encoder<Message> for CrucibleEncoder {
         m: Message,
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
-        let len = CrucibleEncoder::serialized_size(&m)?;
-
-        dst.reserve(len);
-        dst.put_u32_le(len as u32);
-        bincode::serialize_into(dst.writer(), &m)?;
-
-        Ok(())
+        self.encode_message(&m, dst)
     }
 }
 
@@ -511,21 +1121,41 @@ impl Encoder<&Message> for CrucibleEncoder {
         m: &Message,
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
-        let len = CrucibleEncoder::serialized_size(&m)?;
-
-        dst.reserve(len);
-        dst.put_u32_le(len as u32);
-        bincode::serialize_into(dst.writer(), &m)?;
-
-        Ok(())
+        self.encode_message(m, dst)
     }
 }
 
-pub struct CrucibleDecoder {}
+pub struct CrucibleDecoder {
+    version: u32,
+    bytes_saved: u64,
+}
 
 impl CrucibleDecoder {
     pub fn new() -> Self {
-        CrucibleDecoder {}
+        CrucibleDecoder {
+            version: PROTOCOL_VERSION_MAX,
+            bytes_saved: 0,
+        }
+    }
+
+    /// Expect messages laid out for the version the other side agreed to.
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    fn deserialize(&self, raw: &[u8]) -> Result<Message, anyhow::Error> {
+        if self.version < VERSION_KEY_ID {
+            if let Some(m) = v3::deserialize(raw)? {
+                return Ok(m);
+            }
+        }
+
+        Ok(bincode::deserialize(raw)?)
+    }
+
+    /// Bytes compression kept off the wire since the last call.
+    pub fn take_bytes_saved(&mut self) -> u64 {
+        std::mem::take(&mut self.bytes_saved)
     }
 }
 
@@ -555,7 +1185,9 @@ impl Decoder for CrucibleDecoder {
          */
         let mut length_bytes = [0u8; 4];
         length_bytes.copy_from_slice(&src[0..4]);
-        let len = u32::from_le_bytes(length_bytes) as usize;
+        let header = u32::from_le_bytes(length_bytes);
+        let compression = Compression::from_frame_tag(header >> FRM_TAG_SHIFT)?;
+        let len = (header & FRM_LEN_MASK) as usize;
 
         if len > MAX_FRM_LEN {
             bail!("frame is {} bytes, more than maximum {}", len, MAX_FRM_LEN);
@@ -569,11 +1201,33 @@ impl Decoder for CrucibleDecoder {
             return Ok(None);
         }
 
-        src.advance(4);
+        let frame = src.split_to(len);
+        if len < 4 {
+            bail!("frame is only {} bytes", len);
+        }
 
-        let message = bincode::deserialize_from(src.reader());
+        if compression == Compression::None {
+            return Ok(Some(self.deserialize(&frame[4..])?));
+        }
 
-        Ok(Some(message?))
+        if len < 8 {
+            bail!("compressed frame is only {} bytes", len);
+        }
+
+        length_bytes.copy_from_slice(&frame[4..8]);
+        let raw_len = u32::from_le_bytes(length_bytes) as usize;
+        if raw_len > MAX_FRM_LEN {
+            bail!(
+                "frame decompresses to {} bytes, more than maximum {}",
+                raw_len,
+                MAX_FRM_LEN
+            );
+        }
+
+        let raw = compression.decompress(&frame[8..], raw_len)?;
+        self.bytes_saved += (raw_len + 4).saturating_sub(len) as u64;
+
+        Ok(Some(self.deserialize(&raw)?))
     }
 }
 
@@ -599,7 +1253,9 @@ mod tests {
     #[test]
     fn rt_here_i_am() -> Result<()> {
         let input = Message::HereIAm {
-            version: 2,
+            min_version: PROTOCOL_VERSION_MIN,
+            max_version: PROTOCOL_VERSION_MAX,
+            features: FEATURE_DISCARD,
             upstairs_id: Uuid::new_v4(),
             session_id: Uuid::new_v4(),
             gen: 123,
@@ -614,12 +1270,449 @@ mod tests {
     fn rt_yes_its_me() -> Result<()> {
         let input = Message::YesItsMe {
             version: 20000,
+            features: u64::MAX,
             repair_addr: "127.0.0.1:123".parse().unwrap(),
         };
         assert_eq!(input, round_trip(&input)?);
         Ok(())
     }
 
+    #[test]
+    fn rt_version_mismatch() -> Result<()> {
+        let input = Message::VersionMismatch {
+            min_version: PROTOCOL_VERSION_MIN,
+            max_version: PROTOCOL_VERSION_MAX,
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn version_1_messages_keep_their_place() -> Result<()> {
+        /*
+         * What a version 1 upstairs sends first: the HereIAm variant tag,
+         * then its fields.
+         */
+        let upstairs_id = Uuid::new_v4();
+        let session_id = Uuid::new_v4();
+        let mut raw = 0u32.to_le_bytes().to_vec();
+        raw.extend_from_slice(&1u32.to_le_bytes());
+        raw.extend_from_slice(&bincode::serialize(&upstairs_id)?);
+        raw.extend_from_slice(&bincode::serialize(&session_id)?);
+        raw.extend_from_slice(&7u64.to_le_bytes());
+        raw.push(0);
+        raw.push(1);
+
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(raw.len() as u32 + 4);
+        buf.extend_from_slice(&raw);
+
+        let mut dec = CrucibleDecoder::new();
+        assert_eq!(
+            dec.decode(&mut buf)?,
+            Some(Message::HereIAmV1 {
+                version: 1,
+                upstairs_id,
+                session_id,
+                gen: 7,
+                read_only: false,
+                encrypted: true,
+            })
+        );
+
+        /*
+         * A version 1 peer can still tell a ping from a write.
+         */
+        assert_eq!(
+            &bincode::serialize(&Message::Ruok)?[..4],
+            &8u32.to_le_bytes()
+        );
+        assert_eq!(
+            &bincode::serialize(&a_write_message(vec![1; 512]))?[..4],
+            &22u32.to_le_bytes()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn negotiate_newest_common_version() {
+        let n = Negotiated::with_peer(1, 1000, SUPPORTED_FEATURES).unwrap();
+        assert_eq!(n.version, PROTOCOL_VERSION_MAX);
+        assert_eq!(n.features, SUPPORTED_FEATURES);
+        assert!(n.is_valid());
+
+        let n = Negotiated::with_peer(0, PROTOCOL_VERSION_MIN, 0).unwrap();
+        assert_eq!(n.version, PROTOCOL_VERSION_MIN);
+        assert_eq!(n.features, 0);
+        assert!(n.is_valid());
+    }
+
+    #[test]
+    fn negotiate_no_common_version() {
+        assert!(Negotiated::with_peer(0, PROTOCOL_VERSION_MIN - 1, 0).is_none());
+        assert!(Negotiated::with_peer(
+            PROTOCOL_VERSION_MAX + 1,
+            PROTOCOL_VERSION_MAX + 2,
+            SUPPORTED_FEATURES
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn negotiate_drops_unknown_features() {
+        let n = Negotiated::with_peer(
+            PROTOCOL_VERSION_MIN,
+            PROTOCOL_VERSION_MAX,
+            u64::MAX,
+        )
+        .unwrap();
+        assert_eq!(n.features, SUPPORTED_FEATURES);
+
+        let bad = Negotiated {
+            version: PROTOCOL_VERSION_MAX,
+            features: 1 << 63,
+        };
+        assert!(!bad.is_valid());
+        let bad = Negotiated {
+            version: PROTOCOL_VERSION_MAX + 1,
+            features: 0,
+        };
+        assert!(!bad.is_valid());
+    }
+
+    #[test]
+    fn negotiated_gates_messages() {
+        let resize = Message::RegionResize {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1,
+            dependencies: vec![],
+            extent_count: 4,
+        };
+        let discard = Message::Discard {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 2,
+            dependencies: vec![],
+            requests: vec![],
+        };
+        let repair_blocks = Message::ExtentRepairBlocks {
+            repair_id: 3,
+            extent_id: 1,
+            source_client_id: 0,
+            source_repair_address: "127.0.0.1:4000".parse().unwrap(),
+            dest_clients: vec![1],
+        };
+
+        let old = Negotiated {
+            version: VERSION_REGION_RESIZE - 1,
+            features: 0,
+        };
+        assert!(!old.permits(&resize));
+
+        let plain = Negotiated {
+            version: PROTOCOL_VERSION_MIN,
+            features: 0,
+        };
+        assert!(plain.permits(&Message::Ruok));
+        assert!(!plain.permits(&discard));
+
+        let new = Negotiated {
+            version: PROTOCOL_VERSION_MAX,
+            features: FEATURE_DISCARD,
+        };
+        assert!(new.permits(&resize));
+        assert!(new.permits(&discard));
+        assert!(!new.permits(&repair_blocks));
+
+        let delta = Negotiated {
+            version: PROTOCOL_VERSION_MAX,
+            features: FEATURE_DELTA_REPAIR,
+        };
+        assert!(delta.permits(&repair_blocks));
+        assert!(!delta.permits(&discard));
+    }
+
+    #[test]
+    fn negotiated_compression() {
+        let n = Negotiated::with_peer(
+            PROTOCOL_VERSION_MIN,
+            PROTOCOL_VERSION_MAX,
+            SUPPORTED_FEATURES,
+        )
+        .unwrap();
+        assert_eq!(n.compression(), Compression::Lz4);
+        let n = Negotiated::with_peer(
+            PROTOCOL_VERSION_MIN,
+            PROTOCOL_VERSION_MAX,
+            FEATURE_COMPRESS_ZSTD,
+        )
+        .unwrap();
+        assert_eq!(n.compression(), Compression::Zstd);
+        let n = Negotiated::with_peer(
+            PROTOCOL_VERSION_MIN,
+            PROTOCOL_VERSION_MAX,
+            FEATURE_DISCARD,
+        )
+        .unwrap();
+        assert_eq!(n.compression(), Compression::None);
+    }
+
+    fn a_write_message(data: Vec<u8>) -> Message {
+        Message::Write {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 12,
+            dependencies: vec![10, 11],
+            writes: vec![Write {
+                eid: 1,
+                offset: Block::new_512(3),
+                block_context: BlockContext {
+                    hash: crucible_common::integrity_hash(&[&data]),
+                    encryption_context: None,
+                },
+                data: bytes::Bytes::from(data),
+            }],
+        }
+    }
+
+    fn compressed_round_trip(
+        compression: Compression,
+        input: &Message,
+    ) -> Result<(Message, u64, u64)> {
+        let mut enc = CrucibleEncoder::new();
+        enc.set_compression(compression);
+        let mut buf = BytesMut::new();
+        enc.encode(input, &mut buf)?;
+
+        let mut dec = CrucibleDecoder::new();
+        let output = dec.decode(&mut buf)?;
+        assert!(buf.is_empty());
+        if let Some(output) = output {
+            Ok((output, enc.take_bytes_saved(), dec.take_bytes_saved()))
+        } else {
+            bail!("expected message, got None");
+        }
+    }
+
+    #[test]
+    fn rt_compressed_write() -> Result<()> {
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let input = a_write_message(vec![0u8; 4096]);
+            let (output, sent, received) =
+                compressed_round_trip(compression, &input)?;
+
+            assert_eq!(input, output);
+            assert!(sent > 3000);
+            assert_eq!(sent, received);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rt_compressed_read_response() -> Result<()> {
+        let data = b"the quick brown fox ".repeat(200);
+        let input = Message::ReadResponse {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 7,
+            responses: Ok(vec![ReadResponse::from_request_with_data(
+                &ReadRequest {
+                    eid: 0,
+                    offset: Block::new_4096(1),
+                },
+                &data,
+            )]),
+        };
+
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let (output, sent, _) = compressed_round_trip(compression, &input)?;
+            assert_eq!(input, output);
+            assert!(sent > 0);
+        }
+        Ok(())
+    }
+
+    fn versioned_round_trip(
+        version: u32,
+        compression: Compression,
+        input: &Message,
+    ) -> Result<(Message, usize)> {
+        let mut enc = CrucibleEncoder::new();
+        enc.set_version(version);
+        enc.set_compression(compression);
+        let mut buf = BytesMut::new();
+        enc.encode(input, &mut buf)?;
+        let sent = buf.len();
+
+        let mut dec = CrucibleDecoder::new();
+        dec.set_version(version);
+        let output = dec.decode(&mut buf)?;
+        assert!(buf.is_empty());
+        if let Some(output) = output {
+            Ok((output, sent))
+        } else {
+            bail!("expected message, got None");
+        }
+    }
+
+    fn encrypted_contexts(m: &mut Message, key_id: u16) {
+        let ctx = Some(EncryptionContext {
+            nonce: vec![1; 12],
+            tag: vec![2; 16],
+            key_id,
+        });
+        match m {
+            Message::Write { writes, .. }
+            | Message::WriteUnwritten { writes, .. } => {
+                for w in writes {
+                    w.block_context.encryption_context = ctx.clone();
+                }
+            }
+            Message::ReadResponse {
+                responses: Ok(responses),
+                ..
+            } => {
+                for r in responses {
+                    for c in &mut r.block_contexts {
+                        c.encryption_context = ctx.clone();
+                    }
+                }
+            }
+            _ => panic!("no block contexts in {:?}", m),
+        }
+    }
+
+    #[test]
+    fn version_3_drops_key_id() -> Result<()> {
+        let write = a_write_message(vec![9u8; 512]);
+        let write_unwritten = match write.clone() {
+            Message::Write {
+                upstairs_id,
+                session_id,
+                job_id,
+                dependencies,
+                writes,
+            } => Message::WriteUnwritten {
+                upstairs_id,
+                session_id,
+                job_id,
+                dependencies,
+                writes,
+            },
+            _ => unreachable!(),
+        };
+        let read_response = Message::ReadResponse {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 7,
+            responses: Ok(vec![ReadResponse::from_request_with_data(
+                &ReadRequest {
+                    eid: 0,
+                    offset: Block::new_512(1),
+                },
+                &[3u8; 512],
+            )]),
+        };
+
+        for m in [write, write_unwritten, read_response] {
+            let mut input = m.clone();
+            encrypted_contexts(&mut input, 5);
+            let mut legacy = m.clone();
+            encrypted_contexts(&mut legacy, 0);
+
+            for compression in [Compression::None, Compression::Lz4] {
+                let (output, old_len) =
+                    versioned_round_trip(3, compression, &input)?;
+                assert_eq!(output, legacy);
+
+                let (output, new_len) =
+                    versioned_round_trip(VERSION_KEY_ID, compression, &input)?;
+                assert_eq!(output, input);
+
+                if compression == Compression::None {
+                    // Only the key ID is missing from the old layout.
+                    assert_eq!(old_len + 2, new_len);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn version_3_read_error() -> Result<()> {
+        let input = Message::ReadResponse {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 7,
+            responses: Err(CrucibleError::DataLenUnaligned),
+        };
+        let (output, _) = versioned_round_trip(3, Compression::None, &input)?;
+        assert_eq!(input, output);
+        Ok(())
+    }
+
+    #[test]
+    fn incompressible_data_costs_nothing_extra() -> Result<()> {
+        // Something like encrypted data, with no repeats in it
+        let mut x: u32 = 0x12345678;
+        let data: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let input = a_write_message(data);
+
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let mut enc = CrucibleEncoder::new();
+            enc.set_compression(compression);
+            let mut buf = BytesMut::new();
+            enc.encode(&input, &mut buf)?;
+            assert!(buf.len() <= CrucibleEncoder::serialized_size(&input)?);
+
+            let mut dec = CrucibleDecoder::new();
+            assert_eq!(dec.decode(&mut buf)?, Some(input.clone()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn only_block_data_is_compressed() -> Result<()> {
+        let input = Message::ExtentVersions {
+            gen_numbers: vec![0; 1000],
+            flush_numbers: vec![0; 1000],
+            dirty_bits: vec![false; 1000],
+        };
+
+        let mut enc = CrucibleEncoder::new();
+        enc.set_compression(Compression::Zstd);
+        let mut buf = BytesMut::new();
+        enc.encode(&input, &mut buf)?;
+
+        assert_eq!(buf.len(), CrucibleEncoder::serialized_size(&input)?);
+        assert_eq!(enc.take_bytes_saved(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn partial_compressed_frame_waits() -> Result<()> {
+        let mut enc = CrucibleEncoder::new();
+        enc.set_compression(Compression::Lz4);
+        let mut buf = BytesMut::new();
+        enc.encode(a_write_message(vec![0u8; 4096]), &mut buf)?;
+
+        let mut dec = CrucibleDecoder::new();
+        let mut partial = buf.split_to(buf.len() - 1);
+        assert!(dec.decode(&mut partial)?.is_none());
+
+        partial.unsplit(buf);
+        assert!(dec.decode(&mut partial)?.is_some());
+        Ok(())
+    }
+
     #[test]
     fn rt_ruok() -> Result<()> {
         let input = Message::Ruok;
@@ -663,13 +1756,50 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn rt_discard() -> Result<()> {
+        let input = Message::Discard {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1000,
+            dependencies: vec![998, 999],
+            requests: vec![
+                DiscardRequest {
+                    eid: 2,
+                    offset: Block::new_512(7),
+                },
+                DiscardRequest {
+                    eid: 3,
+                    offset: Block::new_4096(0),
+                },
+            ],
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_region_resize() -> Result<()> {
+        let input = Message::RegionResize {
+            upstairs_id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            job_id: 1001,
+            dependencies: vec![1000],
+            extent_count: 40,
+        };
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
     #[test]
     fn correctly_detect_truncated_message() -> Result<()> {
         let mut encoder = CrucibleEncoder::new();
         let mut decoder = CrucibleDecoder::new();
 
         let input = Message::HereIAm {
-            version: 0,
+            min_version: 0,
+            max_version: 0,
+            features: 0,
             upstairs_id: Uuid::new_v4(),
             session_id: Uuid::new_v4(),
             gen: 23849183,
@@ -106,6 +106,18 @@ impl BlockIO for FileBlockIO {
         )
     }
 
+    async fn discard(
+        &self,
+        _offset: Block,
+        _len: u64,
+    ) -> Result<(), CrucibleError> {
+        crucible_bail!(Unsupported, "discard unsupported for FileBlockIO")
+    }
+
+    async fn resize(&self, _extent_count: u32) -> Result<(), CrucibleError> {
+        crucible_bail!(Unsupported, "resize unsupported for FileBlockIO")
+    }
+
     async fn flush(
         &self,
         _snapshot_details: Option<SnapshotDetails>,
@@ -280,6 +292,18 @@ impl BlockIO for ReqwestBlockIO {
         )
     }
 
+    async fn discard(
+        &self,
+        _offset: Block,
+        _len: u64,
+    ) -> Result<(), CrucibleError> {
+        crucible_bail!(Unsupported, "discard unsupported for ReqwestBlockIO")
+    }
+
+    async fn resize(&self, _extent_count: u32) -> Result<(), CrucibleError> {
+        crucible_bail!(Unsupported, "resize unsupported for ReqwestBlockIO")
+    }
+
     async fn flush(
         &self,
         _snapshot_details: Option<SnapshotDetails>,
@@ -0,0 +1,808 @@
+// Copyright 2022 Oxide Computer Company
+use super::*;
+
+use crucible_common::{read_json_maybe, write_json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/*
+ * The upstairs side record of what it last wrote to each block.
+ *
+ * A downstairs holds both the data and the block contexts, so a broken or
+ * malicious downstairs can hand back an older write of a block along with
+ * the block context that went with it.  That passes the integrity hash
+ * check, and decrypts, so it can't be caught from the read response alone.
+ * It can also drop the block contexts of a written block, making it look
+ * unwritten.
+ *
+ * To catch both, the upstairs remembers the block context hash of the last
+ * write it sent to each block, or that it discarded the block, and checks
+ * every read response against that.  Only the upstairs ever writes to the
+ * region while it is active, so nothing else can legitimately change a
+ * block under it.
+ *
+ * The record lives in memory and starts out empty on each activation.  It
+ * can also be kept in a file, so blocks written before the upstairs last
+ * activated are still checked.  Each change is tagged with the flush that
+ * covers it, and once a flush has retired, every change it covers is
+ * written out along with the flush number and the root of a hash tree:
+ * one hash over the blocks of each extent, and the root a hash over
+ * those.  Changes made after that flush are left for the next one.
+ *
+ * On activation the file is read back, and its root checked, before the
+ * downstairs are reconciled.  The flush numbers and dirty bits the
+ * downstairs report then decide which extents of it we can still trust.
+ * An extent that any downstairs has dirty, or that was flushed after the
+ * record was written, may hold writes the record knows nothing about, so
+ * its blocks are left out and go unchecked until they are written again.
+ * That also means a downstairs that lies about its extents can get them
+ * left out, so this does not stop every rollback, only those the region
+ * metadata can't hide.
+ *
+ * Without a file, only what this upstairs wrote during this activation is
+ * covered.  All that can be said of any other block is that it was not
+ * written by a newer generation than ours, which would mean another
+ * upstairs has taken over the region.  Either way the record holds at
+ * most BLOCK_RECORD_LIMIT blocks: past that, the blocks written longest
+ * ago are forgotten, go unchecked, and are not written to the file.
+ *
+ * A key rotation also uses the record to tell if a block it read has been
+ * changed since.  A forgotten block counts as changed.
+ */
+
+/// The most blocks the record holds at once.
+pub(crate) const BLOCK_RECORD_LIMIT: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum BlockState {
+    /// Written, with a block context that has this hash.
+    Written(u64),
+    /// Discarded.
+    Unwritten,
+    /// Changed, but we can't tell if it is written or not.
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BlockEntry {
+    /// The job that last changed this block.
+    job_id: u64,
+    state: BlockState,
+    /// The flush number of the flush that covers this change, once that
+    /// flush has been sent.
+    flush: Option<u64>,
+}
+
+/*
+ * A block as it is kept in the file.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedBlock {
+    offset: u64,
+    flush: u64,
+    state: BlockState,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedRecord {
+    upstairs: Uuid,
+    // The flush every block in here was covered by.
+    flush: u64,
+    // The root of the hash tree over the extents.
+    root: String,
+    // Indexed by extent, in block offset order.
+    extents: BTreeMap<u64, Vec<SavedBlock>>,
+}
+
+/*
+ * The hash of the blocks of one extent, a leaf of the hash tree.
+ */
+fn extent_hash(eid: u64, blocks: &[SavedBlock]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(eid.to_le_bytes());
+    for block in blocks {
+        hasher.update(block.offset.to_le_bytes());
+        hasher.update(block.flush.to_le_bytes());
+        match block.state {
+            BlockState::Written(hash) => {
+                hasher.update([1]);
+                hasher.update(hash.to_le_bytes());
+            }
+            BlockState::Unwritten => hasher.update([2]),
+            BlockState::Unknown => hasher.update([3]),
+        }
+    }
+    hasher.finalize().to_vec()
+}
+
+fn root_hash(
+    upstairs: &Uuid,
+    flush: u64,
+    extents: &BTreeMap<u64, Vec<SavedBlock>>,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(upstairs.as_bytes());
+    hasher.update(flush.to_le_bytes());
+    for (eid, blocks) in extents {
+        hasher.update(extent_hash(*eid, blocks));
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/*
+ * The blocks a retired flush covers, to be written out to the file after
+ * the Downstairs lock is dropped.
+ */
+#[derive(Debug)]
+pub(crate) struct BlockSnapshot {
+    path: PathBuf,
+    upstairs: Uuid,
+    flush: u64,
+    blocks: Vec<((u64, u64), SavedBlock)>,
+    log: Logger,
+}
+
+impl BlockSnapshot {
+    /*
+     * This is best effort, failing to keep the record only means fewer
+     * blocks are checked after the next activation.
+     */
+    pub(crate) fn write(self) {
+        let mut extents: BTreeMap<u64, Vec<SavedBlock>> = BTreeMap::new();
+        for ((eid, _), block) in self.blocks {
+            extents.entry(eid).or_default().push(block);
+        }
+        for blocks in extents.values_mut() {
+            blocks.sort_by_key(|b| b.offset);
+        }
+
+        let saved = SavedRecord {
+            upstairs: self.upstairs,
+            flush: self.flush,
+            root: root_hash(&self.upstairs, self.flush, &extents),
+            extents,
+        };
+        if let Err(e) = write_json(&self.path, &saved, true) {
+            warn!(
+                self.log,
+                "Failed to save block record {:?}: {}", self.path, e
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct BlockRecord {
+    // Indexed by (extent, block offset in the extent).
+    blocks: HashMap<(u64, u64), BlockEntry>,
+    // Each change to a block, oldest first, as (job ID, block).  Only the
+    // newest change to a block is in blocks, the others are left here
+    // until they are cleaned out.
+    order: VecDeque<(u64, (u64, u64))>,
+    limit: usize,
+    // The newest job that changed a block that has since been forgotten.
+    forgotten: u64,
+    // The generation of this upstairs.
+    generation: u64,
+    // Where the record is kept, if anywhere.
+    path: Option<PathBuf>,
+    // The blocks changed since the last flush was sent, when there is a
+    // file to keep them in.
+    unflushed: Vec<(u64, u64)>,
+    upstairs: Uuid,
+    log: Logger,
+}
+
+impl Default for BlockRecord {
+    fn default() -> BlockRecord {
+        BlockRecord::with_limit(BLOCK_RECORD_LIMIT)
+    }
+}
+
+impl BlockRecord {
+    /*
+     * A record kept in this file, if there is one, for this upstairs.
+     * Nothing is read from the file until the upstairs activates.
+     */
+    pub(crate) fn new(
+        path: Option<PathBuf>,
+        upstairs: Uuid,
+        log: &Logger,
+    ) -> BlockRecord {
+        BlockRecord {
+            path,
+            upstairs,
+            log: log.clone(),
+            ..BlockRecord::default()
+        }
+    }
+
+    fn with_limit(limit: usize) -> BlockRecord {
+        BlockRecord {
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+            limit,
+            forgotten: 0,
+            generation: 0,
+            path: None,
+            unflushed: Vec::new(),
+            upstairs: Uuid::nil(),
+            log: Logger::root(slog::Discard, o!()),
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+        self.order.clear();
+        self.unflushed.clear();
+        self.forgotten = 0;
+    }
+
+    pub(crate) fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    fn insert(&mut self, key: (u64, u64), entry: BlockEntry) {
+        self.blocks.insert(key, entry);
+        self.order.push_back((entry.job_id, key));
+        if self.path.is_some() && entry.flush.is_none() {
+            self.unflushed.push(key);
+        }
+
+        if self.order.len() > 2 * self.limit {
+            let blocks = &self.blocks;
+            self.order.retain(|(job_id, key)| {
+                matches!(blocks.get(key), Some(e) if e.job_id == *job_id)
+            });
+        }
+
+        while self.blocks.len() > self.limit {
+            let (job_id, key) = self.order.pop_front().unwrap();
+            if matches!(self.blocks.get(&key), Some(e) if e.job_id == job_id) {
+                self.blocks.remove(&key);
+                self.forgotten = std::cmp::max(self.forgotten, job_id);
+            }
+        }
+    }
+
+    pub(crate) fn record_write(
+        &mut self,
+        job_id: u64,
+        eid: u64,
+        offset: u64,
+        hash: u64,
+    ) {
+        self.insert(
+            (eid, offset),
+            BlockEntry {
+                job_id,
+                state: BlockState::Written(hash),
+                flush: None,
+            },
+        );
+    }
+
+    /*
+     * A write_unwritten only lands if the block has no data, and we can't
+     * know which way that went.  A block we know to be written stays
+     * that way, anything else is no longer known.
+     */
+    pub(crate) fn record_write_unwritten(
+        &mut self,
+        job_id: u64,
+        eid: u64,
+        offset: u64,
+    ) {
+        let unwritten = matches!(
+            self.blocks.get(&(eid, offset)),
+            Some(entry) if entry.state == BlockState::Unwritten
+        );
+        if unwritten {
+            self.insert(
+                (eid, offset),
+                BlockEntry {
+                    job_id,
+                    state: BlockState::Unknown,
+                    flush: None,
+                },
+            );
+        }
+    }
+
+    pub(crate) fn record_discard(
+        &mut self,
+        job_id: u64,
+        eid: u64,
+        offset: u64,
+    ) {
+        self.insert(
+            (eid, offset),
+            BlockEntry {
+                job_id,
+                state: BlockState::Unwritten,
+                flush: None,
+            },
+        );
+    }
+
+    /*
+     * A flush with this flush number has been sent, and it covers every
+     * change made before it.
+     */
+    pub(crate) fn flush_sent(&mut self, flush_number: u64) {
+        for key in self.unflushed.drain(..) {
+            if let Some(entry) = self.blocks.get_mut(&key) {
+                if entry.flush.is_none() {
+                    entry.flush = Some(flush_number);
+                }
+            }
+        }
+    }
+
+    /*
+     * The flush with this flush number has retired, take the blocks it
+     * covers to be written out to the file.  A block changed again since
+     * is left out, as we don't know what it held before.
+     */
+    pub(crate) fn flush_retired(
+        &self,
+        flush_number: u64,
+    ) -> Option<BlockSnapshot> {
+        let path = self.path.clone()?;
+
+        let blocks = self
+            .blocks
+            .iter()
+            .filter_map(|(key, entry)| match entry.flush {
+                Some(flush) if flush <= flush_number => Some((
+                    *key,
+                    SavedBlock {
+                        offset: key.1,
+                        flush,
+                        state: entry.state,
+                    },
+                )),
+                _ => None,
+            })
+            .collect();
+
+        Some(BlockSnapshot {
+            path,
+            upstairs: self.upstairs,
+            flush: flush_number,
+            blocks,
+            log: self.log.clone(),
+        })
+    }
+
+    /*
+     * Read back the record kept in the file, keeping the blocks of each
+     * extent only if the region metadata from every downstairs says
+     * nothing has changed that extent since the record was written.  The
+     * blocks read back count as changed before any job of ours.
+     */
+    pub(crate) fn load(&mut self, regions: &HashMap<u8, RegionMetadata>) {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let saved: SavedRecord = match read_json_maybe(&path) {
+            Ok(Some(saved)) => saved,
+            Ok(None) => return,
+            Err(e) => {
+                warn!(self.log, "Ignoring block record {:?}: {}", path, e);
+                return;
+            }
+        };
+        if saved.upstairs != self.upstairs {
+            warn!(
+                self.log,
+                "Ignoring block record {:?} of upstairs {}",
+                path,
+                saved.upstairs,
+            );
+            return;
+        }
+        if root_hash(&saved.upstairs, saved.flush, &saved.extents) != saved.root
+        {
+            warn!(
+                self.log,
+                "Ignoring block record {:?}, its root does not match", path,
+            );
+            return;
+        }
+
+        let mut kept = 0;
+        let mut dropped = 0;
+        for (eid, blocks) in saved.extents {
+            let mut flushed = Some(0);
+            let mut dirty = false;
+            for rm in regions.values() {
+                flushed = match rm.flush_numbers.get(eid as usize) {
+                    Some(f) => flushed.map(|flushed| flushed.max(*f)),
+                    None => None,
+                };
+                dirty |= rm.dirty.get(eid as usize).copied().unwrap_or(true);
+            }
+            let flushed = match flushed {
+                Some(flushed) if !dirty && flushed <= saved.flush => flushed,
+                _ => {
+                    dropped += blocks.len();
+                    continue;
+                }
+            };
+
+            for block in blocks {
+                // The flush that covers this block never landed.
+                if block.flush > flushed {
+                    dropped += 1;
+                    continue;
+                }
+                kept += 1;
+                self.insert(
+                    (eid, block.offset),
+                    BlockEntry {
+                        job_id: 0,
+                        state: block.state,
+                        flush: Some(block.flush),
+                    },
+                );
+            }
+        }
+
+        info!(
+            self.log,
+            "Block record of flush {}: {} blocks kept, {} left out",
+            saved.flush,
+            kept,
+            dropped,
+        );
+    }
+
+    /**
+     * Has a job after job_id changed this block?
+     */
+    pub(crate) fn changed_since(
+        &self,
+        job_id: u64,
+        eid: u64,
+        offset: u64,
+    ) -> bool {
+        match self.blocks.get(&(eid, offset)) {
+            Some(entry) => entry.job_id > job_id,
+            None => self.forgotten > job_id,
+        }
+    }
+
+    /**
+     * Check the block context hash a read job got back for a block (None
+     * for a block with no block context) against what we last wrote
+     * there.  An encrypted block also has the generation of the upstairs
+     * that wrote it.
+     */
+    pub(crate) fn check(
+        &self,
+        job_id: u64,
+        eid: u64,
+        offset: u64,
+        hash: Option<u64>,
+        write_gen: Option<u64>,
+    ) -> Result<(), CrucibleError> {
+        if let Some(write_gen) = write_gen {
+            if write_gen > self.generation {
+                crucible_bail!(
+                    BlockIntegrityError,
+                    "eid {} block {}: job {} read a block written by \
+                     generation {}, newer than ours {}",
+                    eid,
+                    offset,
+                    job_id,
+                    write_gen,
+                    self.generation,
+                );
+            }
+        }
+
+        let entry = match self.blocks.get(&(eid, offset)) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+
+        // The read was sent before the block last changed, so we don't
+        // know which write it should have seen.
+        if entry.job_id > job_id {
+            return Ok(());
+        }
+
+        let expected = match entry.state {
+            BlockState::Written(hash) => Some(hash),
+            BlockState::Unwritten => None,
+            BlockState::Unknown => return Ok(()),
+        };
+
+        if expected != hash {
+            crucible_bail!(
+                BlockIntegrityError,
+                "eid {} block {}: job {} expected {:?} from job {}, read {:?}",
+                eid,
+                offset,
+                job_id,
+                expected,
+                entry.job_id,
+                hash,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn csl() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+
+    // Region metadata for three downstairs with these extent flush
+    // numbers, and nothing dirty.
+    fn regions(flush_numbers: &[u64]) -> HashMap<u8, RegionMetadata> {
+        (0..3)
+            .map(|cid| {
+                (
+                    cid,
+                    RegionMetadata {
+                        generation: vec![1; flush_numbers.len()],
+                        flush_numbers: flush_numbers.to_vec(),
+                        dirty: vec![false; flush_numbers.len()],
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unknown_block_passes() {
+        let record = BlockRecord::default();
+        assert!(record.check(1000, 0, 1, Some(7), None).is_ok());
+        assert!(record.check(1000, 0, 1, None, None).is_ok());
+    }
+
+    #[test]
+    fn last_write_must_match() {
+        let mut record = BlockRecord::default();
+        record.record_write(1000, 0, 1, 7);
+        record.record_write(1001, 0, 1, 8);
+
+        assert!(record.check(1002, 0, 1, Some(8), None).is_ok());
+
+        // An older write of the block is a rollback
+        assert!(record.check(1002, 0, 1, Some(7), None).is_err());
+
+        // A written block with its context stripped
+        assert!(record.check(1002, 0, 1, None, None).is_err());
+
+        // Other blocks are not affected
+        assert!(record.check(1002, 0, 2, Some(7), None).is_ok());
+        assert!(record.check(1002, 1, 1, Some(7), None).is_ok());
+    }
+
+    #[test]
+    fn read_sent_before_write_is_not_checked() {
+        let mut record = BlockRecord::default();
+        record.record_write(1000, 0, 1, 7);
+        record.record_write(1005, 0, 1, 8);
+
+        // Job 1003 could have seen either write
+        assert!(record.check(1003, 0, 1, Some(7), None).is_ok());
+        assert!(record.check(1006, 0, 1, Some(7), None).is_err());
+    }
+
+    #[test]
+    fn discarded_block_must_be_unwritten() {
+        let mut record = BlockRecord::default();
+        record.record_write(1000, 0, 1, 7);
+        record.record_discard(1001, 0, 1);
+
+        assert!(record.check(1002, 0, 1, None, None).is_ok());
+        assert!(record.check(1002, 0, 1, Some(7), None).is_err());
+    }
+
+    #[test]
+    fn write_unwritten_forgets_unwritten_blocks() {
+        let mut record = BlockRecord::default();
+        record.record_write(1000, 0, 1, 7);
+        record.record_discard(1001, 0, 2);
+
+        record.record_write_unwritten(1002, 0, 1);
+        record.record_write_unwritten(1002, 0, 2);
+
+        // Block 1 was written, so the write_unwritten did not land
+        assert!(record.check(1003, 0, 1, Some(7), None).is_ok());
+        assert!(record.check(1003, 0, 1, Some(9), None).is_err());
+        assert!(!record.changed_since(1001, 0, 1));
+
+        // Block 2 may or may not have been written
+        assert!(record.check(1003, 0, 2, Some(9), None).is_ok());
+        assert!(record.check(1003, 0, 2, None, None).is_ok());
+        assert!(record.changed_since(1001, 0, 2));
+    }
+
+    #[test]
+    fn changed_since() {
+        let mut record = BlockRecord::default();
+        record.record_write(1000, 0, 1, 7);
+        record.record_discard(1002, 0, 2);
+
+        assert!(record.changed_since(999, 0, 1));
+        assert!(!record.changed_since(1000, 0, 1));
+        assert!(record.changed_since(1001, 0, 2));
+        assert!(!record.changed_since(1002, 0, 2));
+        assert!(!record.changed_since(0, 0, 3));
+    }
+
+    #[test]
+    fn limit_forgets_oldest_blocks() {
+        let mut record = BlockRecord::with_limit(2);
+        record.record_write(1000, 0, 1, 7);
+        record.record_write(1001, 0, 2, 8);
+        record.record_write(1002, 0, 1, 9);
+        record.record_write(1003, 0, 3, 10);
+
+        // Block 2 is the one written longest ago
+        assert_eq!(record.blocks.len(), 2);
+        assert!(record.check(1004, 0, 2, Some(1), None).is_ok());
+        assert!(record.check(1004, 0, 1, Some(7), None).is_err());
+        assert!(record.check(1004, 0, 3, Some(9), None).is_err());
+
+        // We can't say if a forgotten block changed after its last write
+        assert!(record.changed_since(1000, 0, 2));
+        assert!(!record.changed_since(1001, 0, 2));
+
+        // Writing the same blocks over and over doesn't grow the record
+        for job_id in 1005..1100 {
+            record.record_write(job_id, 0, 1 + job_id % 2, job_id);
+        }
+        assert_eq!(record.blocks.len(), 2);
+        assert!(record.order.len() <= 4);
+    }
+
+    #[test]
+    fn newer_generation_is_refused() {
+        let mut record = BlockRecord::default();
+        record.set_generation(3);
+        assert!(record.check(1000, 0, 1, Some(7), Some(3)).is_ok());
+        assert!(record.check(1000, 0, 1, Some(7), Some(2)).is_ok());
+        assert!(record.check(1000, 0, 1, Some(7), Some(4)).is_err());
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut record = BlockRecord::default();
+        record.record_write(1000, 0, 1, 7);
+        record.clear();
+        assert!(record.check(1001, 0, 1, Some(8), None).is_ok());
+        assert_eq!(record.blocks.len(), 0);
+    }
+
+    #[test]
+    fn saved_record_is_checked_after_restart() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("blocks.json");
+        let upstairs = Uuid::new_v4();
+
+        let mut record = BlockRecord::new(Some(path.clone()), upstairs, &csl());
+        record.record_write(1000, 0, 1, 7);
+        record.record_discard(1001, 1, 2);
+        record.flush_sent(5);
+
+        // Nothing is kept until the flush retires.
+        assert!(record.flush_retired(4).unwrap().blocks.is_empty());
+        record.flush_retired(5).unwrap().write();
+
+        let mut record = BlockRecord::new(Some(path), upstairs, &csl());
+        record.load(&regions(&[5, 5]));
+        assert!(record.check(1000, 0, 1, Some(7), None).is_ok());
+        assert!(record.check(1000, 0, 1, Some(6), None).is_err());
+        assert!(record.check(1000, 1, 2, None, None).is_ok());
+        assert!(record.check(1000, 1, 2, Some(6), None).is_err());
+
+        // What was read back changed before any job of ours.
+        assert!(!record.changed_since(1000, 0, 1));
+
+        // Without a file there is nothing to keep.
+        let mut record = BlockRecord::new(None, upstairs, &csl());
+        record.record_write(1000, 0, 1, 7);
+        record.flush_sent(5);
+        assert!(record.flush_retired(5).is_none());
+    }
+
+    #[test]
+    fn later_changes_are_not_saved() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("blocks.json");
+        let upstairs = Uuid::new_v4();
+
+        let mut record = BlockRecord::new(Some(path.clone()), upstairs, &csl());
+        record.record_write(1000, 0, 1, 7);
+        record.record_write(1000, 0, 2, 8);
+        record.flush_sent(5);
+
+        // Block 1 changes again after the flush, and block 3 is new, so
+        // the flush covers neither.
+        record.record_write(1002, 0, 1, 9);
+        record.record_write(1002, 0, 3, 10);
+        record.flush_retired(5).unwrap().write();
+
+        let mut record = BlockRecord::new(Some(path), upstairs, &csl());
+        record.load(&regions(&[5]));
+        assert!(record.check(1000, 0, 1, Some(1), None).is_ok());
+        assert!(record.check(1000, 0, 2, Some(1), None).is_err());
+        assert!(record.check(1000, 0, 3, Some(1), None).is_ok());
+    }
+
+    #[test]
+    fn changed_extents_are_left_out() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("blocks.json");
+        let upstairs = Uuid::new_v4();
+
+        let mut record = BlockRecord::new(Some(path.clone()), upstairs, &csl());
+        record.record_write(1000, 0, 1, 7);
+        record.flush_sent(4);
+        for eid in 1..4 {
+            record.record_write(1001, eid, 1, 7);
+        }
+        record.flush_sent(5);
+        record.flush_retired(5).unwrap().write();
+
+        // Extent 1 is dirty on one downstairs, extent 2 was flushed after
+        // the record was written, and extent 3 never got the flush that
+        // covers its block.  Extent 0 was not touched since.
+        let mut rm = regions(&[4, 5, 6, 4]);
+        rm.get_mut(&1).unwrap().dirty[1] = true;
+
+        let mut record = BlockRecord::new(Some(path), upstairs, &csl());
+        record.load(&rm);
+        assert!(record.check(1002, 0, 1, Some(8), None).is_err());
+        for eid in 1..4 {
+            assert!(record.check(1002, eid, 1, Some(8), None).is_ok());
+        }
+    }
+
+    #[test]
+    fn bad_record_is_ignored() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("blocks.json");
+        let upstairs = Uuid::new_v4();
+
+        let mut record = BlockRecord::new(Some(path.clone()), upstairs, &csl());
+        record.record_write(1000, 0, 1, 7);
+        record.flush_sent(5);
+        record.flush_retired(5).unwrap().write();
+
+        // The record of another upstairs
+        let mut other =
+            BlockRecord::new(Some(path.clone()), Uuid::new_v4(), &csl());
+        other.load(&regions(&[5]));
+        assert!(other.blocks.is_empty());
+
+        // A record changed after it was written
+        let changed = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("\"Written\": 7", "\"Written\": 8");
+        std::fs::write(&path, changed).unwrap();
+        let mut record = BlockRecord::new(Some(path.clone()), upstairs, &csl());
+        record.load(&regions(&[5]));
+        assert!(record.blocks.is_empty());
+
+        std::fs::write(&path, "not json").unwrap();
+        let mut record = BlockRecord::new(Some(path), upstairs, &csl());
+        record.load(&regions(&[5]));
+        assert!(record.blocks.is_empty());
+    }
+}
@@ -7,6 +7,7 @@ use dropshot::ConfigLoggingLevel;
 use dropshot::HttpError;
 use dropshot::HttpResponseCreated;
 use dropshot::HttpResponseOk;
+use dropshot::HttpResponseUpdatedNoContent;
 use dropshot::HttpServerStarter;
 use dropshot::RequestContext;
 use dropshot::TypedBody;
@@ -21,6 +22,10 @@ pub(crate) fn build_api() -> ApiDescription<UpstairsInfo> {
     let mut api = ApiDescription::new();
     api.register(upstairs_fill_info).unwrap();
     api.register(take_snapshot).unwrap();
+    api.register(migrate_downstairs).unwrap();
+    api.register(get_qos).unwrap();
+    api.register(set_qos).unwrap();
+    api.register(get_scrub).unwrap();
 
     api
 }
@@ -108,6 +113,9 @@ struct UpstairsStats {
     ds_jobs: usize,
     repair_done: usize,
     repair_needed: usize,
+    blocks_healed: usize,
+    ds_fault_reason: Vec<Option<FaultReason>>,
+    ds_target: Vec<SocketAddr>,
 }
 
 /**
@@ -130,6 +138,9 @@ async fn upstairs_fill_info(
     let ds_jobs = ds.ds_active.len();
     let repair_done = ds.reconcile_repaired;
     let repair_needed = ds.reconcile_repair_needed;
+    let blocks_healed = ds.blocks_healed;
+    let ds_fault_reason = ds.fault_check.reasons();
+    let ds_target = api_context.up.opts.lock().await.target.clone();
 
     Ok(HttpResponseOk(UpstairsStats {
         state: act,
@@ -138,6 +149,9 @@ async fn upstairs_fill_info(
         ds_jobs,
         repair_done,
         repair_needed,
+        blocks_healed,
+        ds_fault_reason,
+        ds_target,
     }))
 }
 
@@ -179,6 +193,112 @@ async fn take_snapshot(
     }))
 }
 
+/**
+ * Replace the downstairs at `old` with a new, empty, downstairs at `new`
+ */
+#[derive(Deserialize, JsonSchema)]
+pub struct MigrateDownstairsParams {
+    old: SocketAddr,
+    new: SocketAddr,
+}
+
+/**
+ * Start replacing one downstairs with a new downstairs
+ */
+#[endpoint {
+    method = POST,
+    path = "/migrate"
+}]
+async fn migrate_downstairs(
+    rqctx: Arc<RequestContext<UpstairsInfo>>,
+    migrate_params: TypedBody<MigrateDownstairsParams>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let apictx = rqctx.context();
+    let migrate_params = migrate_params.into_inner();
+
+    apictx
+        .up
+        .guest
+        .migrate_downstairs(migrate_params.old, migrate_params.new)
+        .await
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+/**
+ * The QoS limits on the IO the guest sends, if any
+ */
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct QosParams {
+    limits: Option<QosLimits>,
+}
+
+/**
+ * Fetch the QoS limits on the IO the guest sends
+ */
+#[endpoint {
+    method = GET,
+    path = "/qos"
+}]
+async fn get_qos(
+    rqctx: Arc<RequestContext<UpstairsInfo>>,
+) -> Result<HttpResponseOk<QosParams>, HttpError> {
+    let apictx = rqctx.context();
+    let limits = apictx.up.guest.get_qos().await;
+
+    Ok(HttpResponseOk(QosParams { limits }))
+}
+
+/**
+ * Replace the QoS limits on the IO the guest sends
+ */
+#[endpoint {
+    method = POST,
+    path = "/qos"
+}]
+async fn set_qos(
+    rqctx: Arc<RequestContext<UpstairsInfo>>,
+    qos_params: TypedBody<QosParams>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    let apictx = rqctx.context();
+    let qos_params = qos_params.into_inner();
+
+    apictx
+        .up
+        .guest
+        .set_qos(qos_params.limits)
+        .await
+        .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+
+    Ok(HttpResponseUpdatedNoContent())
+}
+
+/**
+ * How a scrub of the volume this upstairs is part of is going, if one has
+ * been reported
+ */
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct ScrubInfo {
+    status: Option<ScrubStatus>,
+}
+
+/**
+ * Fetch how a scrub of the volume this upstairs is part of is going
+ */
+#[endpoint {
+    method = GET,
+    path = "/scrub"
+}]
+async fn get_scrub(
+    rqctx: Arc<RequestContext<UpstairsInfo>>,
+) -> Result<HttpResponseOk<ScrubInfo>, HttpError> {
+    let apictx = rqctx.context();
+    let status = apictx.up.guest.get_scrub_status().await;
+
+    Ok(HttpResponseOk(ScrubInfo { status }))
+}
+
 #[cfg(test)]
 mod test {
     use openapiv3::OpenAPI;
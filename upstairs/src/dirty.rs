@@ -0,0 +1,537 @@
+// Copyright 2022 Oxide Computer Company
+use super::*;
+
+use bitvec::prelude::*;
+use crucible_common::{read_json_maybe, write_json};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/*
+ * Catching up a downstairs that was offline for a long time.
+ *
+ * A downstairs that goes Offline comes back with a replay of every job
+ * since the last flush it acked, so the upstairs holds on to all of those
+ * jobs while it is away.  Once the upstairs holds more jobs than the
+ * replay window allows, it stops doing that for the offline downstairs.
+ * Instead it marks each extent that the jobs it missed change as dirty,
+ * and skips those jobs for it so they can retire.
+ *
+ * When that downstairs comes back, only the dirty extents are copied to
+ * it from a healthy downstairs.  This uses the same steps as a downstairs
+ * migration, with the downstairs being its own replacement.  It takes IO
+ * while the copy goes on, and once every dirty extent has been copied it
+ * goes Active again.
+ *
+ * The dirty extents can also be kept in a file, keyed by region UUID, so
+ * they are not lost if the upstairs restarts before the downstairs is
+ * caught up.  The next activation then repairs those extents along with
+ * any that don't match.  Newly marked or cleaned extents are only written
+ * out with the next flush, and never while the Downstairs lock is held.
+ * An extent written since the last flush is dirty on the downstairs that
+ * took the write, so reconciliation repairs it even if the upstairs goes
+ * away before the file is written.
+ */
+
+/// How many jobs the upstairs holds while a downstairs is offline before
+/// it starts tracking dirty extents for that downstairs instead.
+pub(crate) const REPLAY_WINDOW: usize = 10_000;
+
+/*
+ * The dirty extents of one downstairs.
+ */
+#[derive(Debug)]
+struct Tracked {
+    region: Uuid,
+    extents: BitVec,
+}
+
+#[derive(Debug)]
+pub(crate) struct DirtyExtents {
+    // Where the dirty extents are kept, if anywhere.
+    path: Option<PathBuf>,
+    // Indexed by client ID.
+    tracked: HashMap<u8, Tracked>,
+    // Dirty extents read from the file, indexed by region UUID, for
+    // regions that are not tracked by this upstairs (yet).
+    saved: BTreeMap<Uuid, Vec<u32>>,
+    // Extents were marked or cleaned since the file was last written.
+    unsaved: bool,
+    log: Logger,
+}
+
+/*
+ * The dirty extents as they were when taken, to be written out to the
+ * file after the Downstairs lock is dropped.
+ */
+#[derive(Debug)]
+pub(crate) struct DirtySnapshot {
+    path: PathBuf,
+    all: BTreeMap<Uuid, Vec<u32>>,
+    log: Logger,
+}
+
+impl DirtySnapshot {
+    /*
+     * This is best effort, failing to keep the dirty extents only costs
+     * us a longer repair.
+     */
+    pub(crate) fn write(self) {
+        if let Err(e) = write_json(&self.path, &self.all, true) {
+            warn!(
+                self.log,
+                "Failed to save dirty extents {:?}: {}", self.path, e
+            );
+        }
+    }
+}
+
+impl DirtyExtents {
+    /*
+     * Start with the dirty extents kept in this file, if there is one.  A
+     * file we can't read is logged and otherwise ignored, as the extents
+     * in it are also caught by reconciliation.
+     */
+    pub(crate) fn new(path: Option<PathBuf>, log: &Logger) -> DirtyExtents {
+        let saved = match &path {
+            Some(path) => match read_json_maybe(path) {
+                Ok(saved) => saved.unwrap_or_default(),
+                Err(e) => {
+                    warn!(log, "Ignoring dirty extents {:?}: {}", path, e);
+                    BTreeMap::new()
+                }
+            },
+            None => BTreeMap::new(),
+        };
+
+        DirtyExtents {
+            path,
+            tracked: HashMap::new(),
+            saved,
+            unsaved: false,
+            log: log.clone(),
+        }
+    }
+
+    pub(crate) fn is_tracking(&self, client_id: u8) -> bool {
+        self.tracked.contains_key(&client_id)
+    }
+
+    /*
+     * Start tracking the dirty extents of this client, which has this
+     * region.
+     */
+    pub(crate) fn start(&mut self, client_id: u8, region: Uuid) {
+        self.tracked.entry(client_id).or_insert(Tracked {
+            region,
+            extents: BitVec::new(),
+        });
+    }
+
+    /*
+     * Mark an extent as dirty for this client, and return true if it was
+     * not already.
+     */
+    pub(crate) fn mark(&mut self, client_id: u8, eid: u64) -> bool {
+        let t = match self.tracked.get_mut(&client_id) {
+            Some(t) => t,
+            None => return false,
+        };
+        let eid = eid as usize;
+        if eid >= t.extents.len() {
+            t.extents.resize(eid + 1, false);
+        }
+        let marked = !t.extents.replace(eid, true);
+        self.unsaved |= marked;
+        marked
+    }
+
+    /*
+     * An extent has been copied to this client, so it is clean again.
+     */
+    pub(crate) fn clean(&mut self, client_id: u8, eid: u32) {
+        if let Some(t) = self.tracked.get_mut(&client_id) {
+            if (eid as usize) < t.extents.len() {
+                self.unsaved |= t.extents.replace(eid as usize, false);
+            }
+        }
+    }
+
+    /*
+     * The dirty extents of this client, in order.
+     */
+    pub(crate) fn extents(&self, client_id: u8) -> Vec<u32> {
+        match self.tracked.get(&client_id) {
+            Some(t) => t.extents.iter_ones().map(|e| e as u32).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /*
+     * This client is caught up, stop tracking it.
+     */
+    pub(crate) fn finish(&mut self, client_id: u8) {
+        self.tracked.remove(&client_id);
+    }
+
+    /*
+     * Take the dirty extents kept in the file for this region, if there
+     * are any.
+     */
+    pub(crate) fn take_saved(&mut self, region: &Uuid) -> Vec<u32> {
+        self.saved.remove(region).unwrap_or_default()
+    }
+
+    /*
+     * Take every dirty extent we know of, to be written out to the file.
+     */
+    pub(crate) fn snapshot(&mut self) -> Option<DirtySnapshot> {
+        let path = self.path.clone()?;
+        self.unsaved = false;
+
+        let mut all = self.saved.clone();
+        for t in self.tracked.values() {
+            all.insert(
+                t.region,
+                t.extents.iter_ones().map(|e| e as u32).collect(),
+            );
+        }
+
+        Some(DirtySnapshot {
+            path,
+            all,
+            log: self.log.clone(),
+        })
+    }
+
+    /*
+     * Like snapshot(), but only if extents were marked or cleaned since
+     * the file was last written.
+     */
+    pub(crate) fn take_unsaved(&mut self) -> Option<DirtySnapshot> {
+        if !self.unsaved {
+            return None;
+        }
+        self.snapshot()
+    }
+
+    /*
+     * Write every dirty extent we know of out to the file now.  This is
+     * for the rare changes, like starting or finishing to track a client.
+     */
+    pub(crate) fn save(&mut self) {
+        if let Some(snapshot) = self.snapshot() {
+            snapshot.write();
+        }
+    }
+}
+
+/*
+ * The extents this job changes.
+ */
+pub(crate) fn changed_extents(work: &IOop) -> Vec<u64> {
+    match work {
+        IOop::Write { writes, .. } | IOop::WriteUnwritten { writes, .. } => {
+            writes.iter().map(|w| w.eid).collect()
+        }
+        IOop::Discard { requests, .. } => {
+            requests.iter().map(|r| r.eid).collect()
+        }
+        IOop::Read { .. } | IOop::Flush { .. } | IOop::Resize { .. } => {
+            Vec::new()
+        }
+    }
+}
+
+impl Downstairs {
+    /*
+     * Mark the extents this job changes as dirty for this client.  They
+     * are written out to the file with the next flush.
+     */
+    pub(crate) fn dirty_mark(&mut self, client_id: u8, work: &IOop) {
+        for eid in changed_extents(work) {
+            self.dirty.mark(client_id, eid);
+        }
+    }
+
+    /*
+     * Called after a job is queued.  If a downstairs has been offline for
+     * so long that the jobs it missed fill the replay window, start
+     * tracking its dirty extents, and let go of the jobs.
+     */
+    pub(crate) fn replay_window_check(&mut self) {
+        if self.ds_active.len() <= self.replay_window {
+            return;
+        }
+        for client_id in 0..self.replicas {
+            if self.ds_state[client_id as usize] == DsState::Offline
+                && !self.dirty.is_tracking(client_id)
+            {
+                self.dirty_start(client_id);
+            }
+        }
+    }
+
+    /*
+     * Start tracking the dirty extents for an offline or faulted client.
+     */
+    pub(crate) fn dirty_start(&mut self, client_id: u8) {
+        let region = match self.ds_uuid.get(&client_id) {
+            Some(region) => *region,
+            None => return,
+        };
+        self.dirty.start(client_id, region);
+        let skipped = self.dirty_since_flush(client_id);
+        self.dirty.save();
+
+        warn!(
+            self.log,
+            "[{}] tracking dirty extents, skipped {} jobs, {} dirty extents",
+            client_id,
+            skipped,
+            self.dirty.extents(client_id).len(),
+        );
+    }
+
+    /*
+     * Everything since the last flush this client acked may not be on its
+     * disk, so the extents of all those jobs are dirty.  The jobs it has
+     * not done yet are skipped for it.  Return how many were.
+     */
+    pub(crate) fn dirty_since_flush(&mut self, client_id: u8) -> usize {
+        let lf = self.ds_last_flush[client_id as usize];
+        let mut ids: Vec<u64> = self
+            .ds_active
+            .keys()
+            .filter(|ds_id| **ds_id > lf)
+            .cloned()
+            .collect();
+        ids.sort_unstable();
+
+        let mut skipped = 0;
+        for ds_id in ids {
+            let job = self.ds_active.get_mut(&ds_id).unwrap();
+            for eid in changed_extents(&job.work) {
+                self.dirty.mark(client_id, eid);
+            }
+            let state = job.state.get_mut(&client_id).unwrap();
+            if *state == IOState::New || *state == IOState::InProgress {
+                *state = IOState::Skipped;
+                skipped += 1;
+            }
+        }
+        skipped
+    }
+
+    /*
+     * An offline client we track dirty extents for has come back.  Start
+     * a migration of it to itself that copies just the dirty extents.
+     * The caller is responsible for moving it to DsState::Migrating.
+     */
+    pub(crate) fn catch_up_start(
+        &mut self,
+        client_id: u8,
+    ) -> Result<(), CrucibleError> {
+        if let Some(m) = &self.migration {
+            crucible_bail!(
+                MigrationError,
+                "[{}] can't catch up while {} migrates to {}",
+                client_id,
+                m.old_target,
+                m.new_target,
+            );
+        }
+        let source = match (0..self.replicas).find(|c| {
+            *c != client_id && self.ds_state[*c as usize] == DsState::Active
+        }) {
+            Some(source) => source,
+            None => {
+                crucible_bail!(
+                    MigrationError,
+                    "[{}] no active downstairs to catch up from",
+                    client_id
+                );
+            }
+        };
+
+        let target = *self.ds_target.get(&client_id).unwrap();
+        let extents = self.dirty.extents(client_id);
+        info!(
+            self.log,
+            "[{}] catch up {} dirty extents from [{}]",
+            client_id,
+            extents.len(),
+            source,
+        );
+        self.skip_all_jobs(client_id);
+        self.migration =
+            Some(Migration::new(client_id, source, target, target, extents));
+        Ok(())
+    }
+
+    /*
+     * Add the dirty extents kept in the file for any of our regions to
+     * the extents reconciliation repairs, copying them from a downstairs
+     * that has them clean.  They are forgotten once they are on the list.
+     */
+    pub(crate) fn mend_saved_dirty(
+        &mut self,
+        mend: &mut Option<DownstairsMend>,
+    ) {
+        let mut dirty: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        for client_id in 0..self.replicas {
+            let extents = match self.ds_uuid.get(&client_id) {
+                Some(region) => self.dirty.take_saved(region),
+                None => continue,
+            };
+            if !extents.is_empty() {
+                info!(
+                    self.log,
+                    "[{}] {} extents were left dirty",
+                    client_id,
+                    extents.len(),
+                );
+            }
+            for eid in extents {
+                dirty.entry(eid as usize).or_default().push(client_id);
+            }
+        }
+        if dirty.is_empty() {
+            return;
+        }
+        self.dirty.save();
+
+        let dsm = mend.get_or_insert_with(|| DownstairsMend {
+            mend: HashMap::new(),
+        });
+        for (eid, clients) in dirty {
+            let source = match (0..self.replicas).find(|c| !clients.contains(c))
+            {
+                Some(source) => source,
+                None => {
+                    warn!(self.log, "No clean copy of extent {}", eid);
+                    continue;
+                }
+            };
+            let ef = dsm.mend.entry(eid).or_insert(ExtentFix {
+                source,
+                dest: Vec::new(),
+            });
+            if clients.contains(&ef.source) {
+                ef.source = source;
+                ef.dest = (0..self.replicas).filter(|c| *c != source).collect();
+            } else {
+                for client_id in clients {
+                    if !ef.dest.contains(&client_id) {
+                        ef.dest.push(client_id);
+                    }
+                }
+            }
+        }
+        if dsm.mend.is_empty() {
+            *mend = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn csl() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+
+    #[test]
+    fn mark_and_clean() {
+        let mut dirty = DirtyExtents::new(None, &csl());
+
+        // Nothing is marked until the client is tracked.
+        assert!(!dirty.mark(0, 3));
+        assert!(!dirty.is_tracking(0));
+
+        dirty.start(0, Uuid::new_v4());
+        assert!(dirty.is_tracking(0));
+        assert!(dirty.mark(0, 3));
+        assert!(!dirty.mark(0, 3));
+        assert!(dirty.mark(0, 70));
+        assert!(dirty.mark(0, 1));
+        assert_eq!(dirty.extents(0), vec![1, 3, 70]);
+        assert!(dirty.extents(1).is_empty());
+
+        dirty.clean(0, 3);
+        dirty.clean(0, 500);
+        assert_eq!(dirty.extents(0), vec![1, 70]);
+
+        dirty.finish(0);
+        assert!(!dirty.is_tracking(0));
+        assert!(dirty.extents(0).is_empty());
+    }
+
+    #[test]
+    fn saved_across_restart() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dirty.json");
+        let region = Uuid::new_v4();
+        let other = Uuid::new_v4();
+
+        let mut dirty = DirtyExtents::new(Some(path.clone()), &csl());
+        dirty.start(1, region);
+        dirty.mark(1, 4);
+        dirty.mark(1, 2);
+        dirty.save();
+
+        let mut dirty = DirtyExtents::new(Some(path), &csl());
+        assert!(!dirty.is_tracking(1));
+        assert!(dirty.take_saved(&other).is_empty());
+        assert_eq!(dirty.take_saved(&region), vec![2, 4]);
+        assert!(dirty.take_saved(&region).is_empty());
+    }
+
+    #[test]
+    fn marks_are_saved_once() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dirty.json");
+        let region = Uuid::new_v4();
+
+        let mut dirty = DirtyExtents::new(Some(path.clone()), &csl());
+        dirty.start(0, region);
+        assert!(dirty.take_unsaved().is_none());
+
+        dirty.mark(0, 5);
+        dirty.mark(0, 5);
+        dirty.take_unsaved().unwrap().write();
+        assert!(dirty.take_unsaved().is_none());
+        let mut saved = DirtyExtents::new(Some(path.clone()), &csl());
+        assert_eq!(saved.take_saved(&region), vec![5]);
+
+        // Marking an extent again, or cleaning one that is clean, is not
+        // a change.
+        dirty.mark(0, 5);
+        dirty.clean(0, 6);
+        assert!(dirty.take_unsaved().is_none());
+
+        dirty.clean(0, 5);
+        dirty.take_unsaved().unwrap().write();
+
+        let mut dirty = DirtyExtents::new(Some(path), &csl());
+        assert!(dirty.take_saved(&region).is_empty());
+
+        // Without a file there is nothing to save.
+        let mut dirty = DirtyExtents::new(None, &csl());
+        dirty.start(0, region);
+        dirty.mark(0, 1);
+        assert!(dirty.take_unsaved().is_none());
+    }
+
+    #[test]
+    fn bad_file_is_ignored() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dirty.json");
+        std::fs::write(&path, "not json").unwrap();
+
+        let mut dirty = DirtyExtents::new(Some(path), &csl());
+        assert!(dirty.take_saved(&Uuid::new_v4()).is_empty());
+    }
+}
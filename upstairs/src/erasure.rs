@@ -0,0 +1,1939 @@
+// Copyright 2022 Oxide Computer Company
+
+use super::*;
+
+use crucible_common::{read_json_maybe, write_json};
+use futures::stream::FuturesUnordered;
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Weak;
+use std::time::Duration;
+
+/*
+ * How many stripes a shard rebuild reconstructs before letting other IO
+ * to the set through.
+ */
+const REBUILD_STRIPES: u64 = 128;
+
+/*
+ * How many stripes each entry in the journal of stripes being written
+ * covers.
+ */
+const JOURNAL_STRIPES: u64 = 1024;
+
+/*
+ * Once enough shards to read the data back have activated, how long to
+ * wait for the rest before activating without them.
+ */
+const ACTIVATE_WAIT: Duration = Duration::from_secs(10);
+
+/*
+ * How often a set made by create looks for faulted shards it can rebuild.
+ */
+const REBUILD_INTERVAL: Duration = Duration::from_secs(5);
+
+/*
+ * Arithmetic in GF(2^8), using the 0x11d reducing polynomial.
+ */
+struct Galois {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Galois {
+    fn new() -> Self {
+        let mut exp = [0u8; 512];
+        let mut log = [0u8; 256];
+
+        let mut x: u16 = 1;
+        for (i, e) in exp.iter_mut().enumerate().take(255) {
+            *e = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+
+        /*
+         * Doubling the table saves a modulo when adding two logs.
+         */
+        for i in 255..512 {
+            exp[i] = exp[i - 255];
+        }
+
+        Galois { exp, log }
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            self.exp
+                [self.log[a as usize] as usize + self.log[b as usize] as usize]
+        }
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        assert_ne!(a, 0);
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+
+    /*
+     * dst ^= c * src
+     */
+    fn mul_add(&self, c: u8, src: &[u8], dst: &mut [u8]) {
+        assert_eq!(src.len(), dst.len());
+        match c {
+            0 => {}
+            1 => {
+                for (d, s) in dst.iter_mut().zip(src) {
+                    *d ^= s;
+                }
+            }
+            _ => {
+                let mut table = [0u8; 256];
+                for (b, t) in table.iter_mut().enumerate() {
+                    *t = self.mul(c, b as u8);
+                }
+                for (d, s) in dst.iter_mut().zip(src) {
+                    *d ^= table[*s as usize];
+                }
+            }
+        }
+    }
+}
+
+/*
+ * A systematic Reed-Solomon code.  The data shards are stored as is, and
+ * each parity shard is a combination of all of them given by a row of a
+ * Cauchy matrix.  Stacked under the identity, any data_shards rows of that
+ * matrix are invertible, so any data_shards surviving shards are enough to
+ * get the rest back.
+ */
+pub(crate) struct ReedSolomon {
+    data_shards: usize,
+    parity_shards: usize,
+    gf: Galois,
+    matrix: Vec<Vec<u8>>,
+}
+
+impl ReedSolomon {
+    pub(crate) fn new(
+        data_shards: usize,
+        parity_shards: usize,
+    ) -> Result<Self, CrucibleError> {
+        if data_shards == 0 || data_shards + parity_shards > 256 {
+            crucible_bail!(
+                ErasureCodingError,
+                "unsupported layout of {} data and {} parity shards",
+                data_shards,
+                parity_shards,
+            );
+        }
+
+        let gf = Galois::new();
+        let mut matrix = Vec::with_capacity(data_shards + parity_shards);
+        for i in 0..data_shards {
+            let mut row = vec![0u8; data_shards];
+            row[i] = 1;
+            matrix.push(row);
+        }
+        for i in 0..parity_shards {
+            let x = (data_shards + i) as u8;
+            matrix
+                .push((0..data_shards).map(|j| gf.inv(x ^ j as u8)).collect());
+        }
+
+        Ok(ReedSolomon {
+            data_shards,
+            parity_shards,
+            gf,
+            matrix,
+        })
+    }
+
+    /*
+     * Compute the parity shards for the given data shards, which must all
+     * be the same length.
+     */
+    pub(crate) fn encode(&self, data: &[&[u8]]) -> Vec<Vec<u8>> {
+        assert_eq!(data.len(), self.data_shards);
+        let len = data[0].len();
+
+        (0..self.parity_shards)
+            .map(|p| {
+                let row = &self.matrix[self.data_shards + p];
+                let mut parity = vec![0u8; len];
+                for (c, shard) in row.iter().zip(data) {
+                    self.gf.mul_add(*c, shard, &mut parity);
+                }
+                parity
+            })
+            .collect()
+    }
+
+    /*
+     * Fill in every missing (None) shard, data or parity, from the ones
+     * that are present.  At least data_shards of them must be.
+     */
+    pub(crate) fn reconstruct(
+        &self,
+        shards: &mut [Option<Vec<u8>>],
+    ) -> Result<(), CrucibleError> {
+        let k = self.data_shards;
+        assert_eq!(shards.len(), k + self.parity_shards);
+
+        let present: Vec<usize> = shards
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.is_some())
+            .map(|(i, _)| i)
+            .take(k)
+            .collect();
+        if present.len() < k {
+            crucible_bail!(
+                ErasureCodingError,
+                "{} of {} shards needed are available",
+                present.len(),
+                k,
+            );
+        }
+
+        if shards[..k].iter().any(|s| s.is_none()) {
+            let len = shards[present[0]].as_ref().unwrap().len();
+            let decode = self.invert(
+                present.iter().map(|i| self.matrix[*i].clone()).collect(),
+            )?;
+
+            for i in 0..k {
+                if shards[i].is_some() {
+                    continue;
+                }
+                let mut data = vec![0u8; len];
+                for (c, j) in decode[i].iter().zip(&present) {
+                    self.gf.mul_add(
+                        *c,
+                        shards[*j].as_ref().unwrap(),
+                        &mut data,
+                    );
+                }
+                shards[i] = Some(data);
+            }
+        }
+
+        if shards[k..].iter().any(|s| s.is_none()) {
+            let data: Vec<&[u8]> = shards[..k]
+                .iter()
+                .map(|s| &s.as_ref().unwrap()[..])
+                .collect();
+            let parity = self.encode(&data);
+            for (shard, p) in shards[k..].iter_mut().zip(parity) {
+                if shard.is_none() {
+                    *shard = Some(p);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Gauss-Jordan elimination over GF(2^8).
+     */
+    fn invert(
+        &self,
+        mut m: Vec<Vec<u8>>,
+    ) -> Result<Vec<Vec<u8>>, CrucibleError> {
+        let n = m.len();
+        let mut inv: Vec<Vec<u8>> = (0..n)
+            .map(|i| {
+                let mut row = vec![0u8; n];
+                row[i] = 1;
+                row
+            })
+            .collect();
+
+        for col in 0..n {
+            let pivot = match (col..n).find(|r| m[*r][col] != 0) {
+                Some(pivot) => pivot,
+                None => {
+                    crucible_bail!(ErasureCodingError, "singular matrix")
+                }
+            };
+            m.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = self.gf.inv(m[col][col]);
+            for j in 0..n {
+                m[col][j] = self.gf.mul(m[col][j], scale);
+                inv[col][j] = self.gf.mul(inv[col][j], scale);
+            }
+
+            for r in 0..n {
+                let f = m[r][col];
+                if r == col || f == 0 {
+                    continue;
+                }
+                for j in 0..n {
+                    let v = self.gf.mul(f, m[col][j]);
+                    m[r][j] ^= v;
+                    let v = self.gf.mul(f, inv[col][j]);
+                    inv[r][j] ^= v;
+                }
+            }
+        }
+
+        Ok(inv)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ShardState {
+    /// In sync with the rest of the set.
+    Active,
+    /// Missed an update, and is not used until it is rebuilt.
+    Faulted,
+    /// Being rebuilt: it receives updates but is not read from.
+    Rebuilding,
+}
+
+/*
+ * What is kept in the state file: which shards are faulted, and the
+ * journal of stripes that may have been written since the last flush,
+ * each entry covering JOURNAL_STRIPES stripes.
+ */
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedState {
+    shards: Vec<ShardState>,
+    dirty: BTreeSet<u64>,
+}
+
+/*
+ * The stripe range of one shard, along with which of its blocks hold
+ * written data.
+ */
+struct ShardData {
+    data: Vec<u8>,
+    owned: Vec<bool>,
+}
+
+enum Update<'a> {
+    Write(&'a [u8]),
+    WriteUnwritten(&'a [u8]),
+    Discard,
+}
+
+/// Implement BlockIO over a set of data_shards + parity_shards BlockIOs,
+/// usually one downstairs each.
+///
+/// Logical blocks are dealt round robin across the data shards: block L is
+/// block L / data_shards of data shard L % data_shards.  Block S of every
+/// parity shard protects stripe S, the blocks at S on each data shard.
+/// Reads are served from any data_shards shards, and a shard that misses a
+/// write is faulted until rebuild_shard brings it back.  The set activates
+/// without the shards that don't, as long as data_shards of them do.
+///
+/// Which shards are faulted can be kept in a file, so a shard that missed
+/// writes is not read from after a restart until it has been rebuilt.
+/// The same file journals the stripes written since the last flush: the
+/// data and parity of a stripe are written together, and a restart part
+/// way through can leave parity that no longer matches the data, so the
+/// parity of every journaled stripe is made again from the data when the
+/// set activates.
+pub struct ErasureCodedBlockIO {
+    uuid: Uuid,
+    block_size: u64,
+    data_shards: usize,
+    parity_shards: usize,
+    codec: ReedSolomon,
+    shards: Vec<Arc<dyn BlockIO + Send + Sync>>,
+
+    /*
+     * Writes are a read-modify-write of whole stripes, so all IO to the set
+     * is serialized on this.
+     */
+    state: Mutex<Vec<ShardState>>,
+    // Where the shard state is kept, if anywhere.
+    state_path: Option<PathBuf>,
+    // The journal entries saved in the state file, only changed with the
+    // state lock held.
+    dirty: std::sync::Mutex<BTreeSet<u64>>,
+    log: Logger,
+}
+
+impl ErasureCodedBlockIO {
+    pub fn new(
+        id: Uuid,
+        block_size: u64,
+        data_shards: usize,
+        parity_shards: usize,
+        shards: Vec<Arc<dyn BlockIO + Send + Sync>>,
+        state_path: Option<PathBuf>,
+        log: Logger,
+    ) -> Result<Self, CrucibleError> {
+        let codec = ReedSolomon::new(data_shards, parity_shards)?;
+
+        if shards.len() != data_shards + parity_shards {
+            crucible_bail!(
+                ErasureCodingError,
+                "{} shards given for {} data and {} parity",
+                shards.len(),
+                data_shards,
+                parity_shards,
+            );
+        }
+
+        /*
+         * A shard that was being rebuilt has to start over, and a file we
+         * can't read is an error, as it may be all that stops us reading a
+         * shard that missed writes.
+         */
+        let mut state = vec![ShardState::Active; shards.len()];
+        let mut dirty = BTreeSet::new();
+        if let Some(path) = &state_path {
+            let saved: Option<SavedState> =
+                read_json_maybe(path).map_err(|e| {
+                    CrucibleError::ErasureCodingError(format!(
+                        "reading shard state {:?}: {}",
+                        path, e
+                    ))
+                })?;
+            if let Some(saved) = saved {
+                if saved.shards.len() != shards.len() {
+                    crucible_bail!(
+                        ErasureCodingError,
+                        "shard state {:?} has {} shards, not {}",
+                        path,
+                        saved.shards.len(),
+                        shards.len(),
+                    );
+                }
+                for (s, saved) in state.iter_mut().zip(saved.shards) {
+                    if saved != ShardState::Active {
+                        *s = ShardState::Faulted;
+                    }
+                }
+                dirty = saved.dirty;
+            }
+        }
+
+        Ok(Self {
+            uuid: id,
+            block_size,
+            data_shards,
+            parity_shards,
+            codec,
+            state: Mutex::new(state),
+            state_path,
+            dirty: std::sync::Mutex::new(dirty),
+            log,
+            shards,
+        })
+    }
+
+    /// Start an upstairs for each target in `opts`, and put them together
+    /// into an activated erasure coded set.  Faulted shards are rebuilt in
+    /// the background once their downstairs is back.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create(
+        id: Uuid,
+        block_size: u64,
+        data_shards: usize,
+        parity_shards: usize,
+        opts: CrucibleOpts,
+        gen: u64,
+        state_path: Option<PathBuf>,
+        producer_registry: Option<ProducerRegistry>,
+    ) -> Result<Arc<Self>, CrucibleError> {
+        if opts.target.len() != data_shards + parity_shards {
+            crucible_bail!(
+                ErasureCodingError,
+                "{} targets given for {} data and {} parity shards",
+                opts.target.len(),
+                data_shards,
+                parity_shards,
+            );
+        }
+
+        let decorator = slog_term::TermDecorator::new().build();
+        let drain = slog_term::FullFormat::new(decorator)
+            .build()
+            .filter_level(slog::Level::Info)
+            .fuse();
+        let drain = slog_async::Async::new(drain).build().fuse();
+        let log = Logger::root(drain, o!("erasure_set" => id.to_string()));
+
+        let mut shards: Vec<Arc<dyn BlockIO + Send + Sync>> = Vec::new();
+        for target in &opts.target {
+            /*
+             * Each shard holds different data, so each gets an upstairs of
+             * its own with the one downstairs.
+             */
+            let shard_opts = CrucibleOpts {
+                target: vec![*target],
+                control: None,
+                write_quorum: None,
+                dirty_extents: None,
+                block_record: None,
+                read_policy: None,
+                fault_timeout: None,
+                fault_queue: None,
+                qos: None,
+                ..opts.clone()
+            };
+
+            let guest = Arc::new(Guest::new());
+            let _join_handle = up_main(
+                shard_opts,
+                gen,
+                guest.clone(),
+                producer_registry.clone(),
+            )
+            .await?;
+
+            shards.push(guest);
+        }
+
+        let set = Arc::new(ErasureCodedBlockIO::new(
+            id,
+            block_size,
+            data_shards,
+            parity_shards,
+            shards,
+            state_path,
+            log,
+        )?);
+        set.activate().await?;
+
+        tokio::spawn(rebuild_task(Arc::downgrade(&set)));
+
+        Ok(set)
+    }
+
+    fn block(&self, value: u64) -> Block {
+        Block::new(value, self.block_size.trailing_zeros())
+    }
+
+    /*
+     * The stripes covering `count` logical blocks starting at `lba`.
+     */
+    fn stripes(&self, lba: u64, count: u64) -> Range<u64> {
+        let k = self.data_shards as u64;
+        (lba / k)..((lba + count - 1) / k + 1)
+    }
+
+    /*
+     * Where a logical block lives, as (data shard, stripe).
+     */
+    fn locate(&self, lba: u64) -> (usize, u64) {
+        let k = self.data_shards as u64;
+        ((lba % k) as usize, lba / k)
+    }
+
+    /*
+     * The blocks of data shard `shard` that hold any of the `count`
+     * logical blocks starting at `lba`.
+     */
+    fn shard_range(
+        &self,
+        shard: usize,
+        lba: u64,
+        count: u64,
+    ) -> Option<Range<u64>> {
+        let k = self.data_shards as u64;
+        let shard = shard as u64;
+
+        let first = lba + (shard + k - lba % k) % k;
+        let end = lba + count;
+        if first >= end {
+            return None;
+        }
+        let last = end - 1 - ((end - 1) % k + k - shard) % k;
+
+        Some((first / k)..(last / k + 1))
+    }
+
+    async fn read_shard(
+        &self,
+        shard: usize,
+        stripes: &Range<u64>,
+    ) -> Result<ShardData, CrucibleError> {
+        let len =
+            (stripes.end - stripes.start) as usize * self.block_size as usize;
+        let buffer = Buffer::new(len);
+
+        self.shards[shard]
+            .read(self.block(stripes.start), buffer.clone())
+            .await?;
+
+        let data = buffer.as_vec().await.clone();
+        let owned = buffer.owned_vec().await.clone();
+        Ok(ShardData { data, owned })
+    }
+
+    /*
+     * Read the data shards for a range of stripes, reconstructing any that
+     * are faulted or fail the read from the parity shards.
+     */
+    async fn read_stripes(
+        &self,
+        state: &[ShardState],
+        stripes: &Range<u64>,
+    ) -> Result<Vec<ShardData>, CrucibleError> {
+        let k = self.data_shards;
+        let n = k + self.parity_shards;
+        let active: Vec<usize> =
+            (0..n).filter(|i| state[*i] == ShardState::Active).collect();
+
+        /*
+         * Parity is only read when we already know it will be needed.
+         */
+        let mut wanted: Vec<usize> =
+            active.iter().copied().filter(|i| *i < k).collect();
+        if wanted.len() < k {
+            wanted = active.clone();
+        }
+
+        let mut results: Vec<Option<ShardData>> =
+            (0..n).map(|_| None).collect();
+        let mut last_error = None;
+        for round in 0..2 {
+            let reads = futures::future::join_all(
+                wanted.iter().map(|i| self.read_shard(*i, stripes)),
+            )
+            .await;
+            for (i, result) in wanted.iter().zip(reads) {
+                match result {
+                    Ok(shard) => results[*i] = Some(shard),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+
+            if round > 0
+                || results[..k].iter().all(|r| r.is_some())
+                || wanted.iter().any(|i| *i >= k)
+            {
+                break;
+            }
+            wanted = active.iter().copied().filter(|i| *i >= k).collect();
+        }
+
+        if results[..k].iter().all(|r| r.is_some()) {
+            return Ok(results
+                .into_iter()
+                .take(k)
+                .map(|r| r.unwrap())
+                .collect());
+        }
+
+        let available = results.iter().filter(|r| r.is_some()).count();
+        if available < k {
+            crucible_bail!(
+                ErasureCodingError,
+                "{} of {} shards needed are readable, last error {:?}",
+                available,
+                k,
+                last_error,
+            );
+        }
+
+        let mut owned: Vec<Option<Vec<bool>>> = Vec::with_capacity(n);
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(n);
+        for result in results {
+            match result {
+                Some(shard) => {
+                    owned.push(Some(shard.owned));
+                    shards.push(Some(shard.data));
+                }
+                None => {
+                    owned.push(None);
+                    shards.push(None);
+                }
+            }
+        }
+        self.codec.reconstruct(&mut shards)?;
+
+        /*
+         * Whether a rebuilt block was ever written is not something parity
+         * can tell us, so err on the side of keeping it.
+         */
+        Ok(shards
+            .into_iter()
+            .zip(owned)
+            .take(k)
+            .map(|(data, owned)| {
+                let data = data.unwrap();
+                let owned = owned.unwrap_or_else(|| vec![true; data.len()]);
+                ShardData { data, owned }
+            })
+            .collect())
+    }
+
+    /*
+     * Fault every shard whose part of an update failed.  The update only
+     * fails if that leaves too few shards to read the data back.
+     */
+    fn settle(
+        &self,
+        state: &mut [ShardState],
+        targets: &[usize],
+        results: Vec<Result<(), CrucibleError>>,
+    ) -> Result<(), CrucibleError> {
+        let mut last_error = None;
+        for (i, result) in targets.iter().zip(results) {
+            if let Err(e) = result {
+                state[*i] = ShardState::Faulted;
+                last_error = Some(e);
+            }
+        }
+
+        if last_error.is_some() {
+            self.save_state(state)?;
+        }
+
+        let active = state.iter().filter(|s| **s == ShardState::Active).count();
+        if active < self.data_shards {
+            crucible_bail!(
+                ErasureCodingError,
+                "{} of {} shards needed are active, last error {:?}",
+                active,
+                self.data_shards,
+                last_error,
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        state: &mut [ShardState],
+        lba: u64,
+        count: u64,
+        update: Update<'_>,
+    ) -> Result<(), CrucibleError> {
+        let k = self.data_shards;
+        let bs = self.block_size as usize;
+        let stripes = self.stripes(lba, count);
+        let len = (stripes.end - stripes.start) as usize * bs;
+
+        /*
+         * Stripes that are entirely replaced don't need reading first.
+         */
+        let whole = lba % k as u64 == 0 && count % k as u64 == 0;
+        let mut data = match update {
+            Update::Write(_) | Update::Discard if whole => (0..k)
+                .map(|_| ShardData {
+                    data: vec![0; len],
+                    owned: vec![false; len],
+                })
+                .collect(),
+            _ => self.read_stripes(state, &stripes).await?,
+        };
+
+        for i in 0..count {
+            let (shard, stripe) = self.locate(lba + i);
+            let start = (stripe - stripes.start) as usize * bs;
+            let dst = &mut data[shard];
+            let src = i as usize * bs..(i as usize + 1) * bs;
+
+            match update {
+                Update::Write(buf) => {
+                    dst.data[start..start + bs].copy_from_slice(&buf[src]);
+                }
+                Update::WriteUnwritten(buf) => {
+                    if !dst.owned[start] {
+                        dst.data[start..start + bs].copy_from_slice(&buf[src]);
+                    }
+                }
+                Update::Discard => {
+                    for b in &mut dst.data[start..start + bs] {
+                        *b = 0;
+                    }
+                }
+            }
+        }
+
+        let parity = {
+            let refs: Vec<&[u8]> = data.iter().map(|s| &s.data[..]).collect();
+            self.codec.encode(&refs)
+        };
+
+        self.journal(state, &stripes)?;
+
+        let mut targets = Vec::new();
+        let mut ops: Vec<CrucibleBlockIOFuture> = Vec::new();
+        for (i, shard) in data.iter().enumerate() {
+            if state[i] == ShardState::Faulted {
+                continue;
+            }
+            let range = match self.shard_range(i, lba, count) {
+                Some(range) => range,
+                None => continue,
+            };
+            let start = (range.start - stripes.start) as usize * bs;
+            let end = (range.end - stripes.start) as usize * bs;
+
+            match update {
+                Update::Discard => {
+                    ops.push(self.shards[i].discard(
+                        self.block(range.start),
+                        (end - start) as u64,
+                    ));
+                }
+                _ => {
+                    ops.push(self.shards[i].write(
+                        self.block(range.start),
+                        Bytes::copy_from_slice(&shard.data[start..end]),
+                    ));
+                }
+            }
+            targets.push(i);
+        }
+        for (p, parity) in parity.into_iter().enumerate() {
+            let i = k + p;
+            if state[i] == ShardState::Faulted {
+                continue;
+            }
+            ops.push(
+                self.shards[i]
+                    .write(self.block(stripes.start), Bytes::from(parity)),
+            );
+            targets.push(i);
+        }
+
+        let results = futures::future::join_all(ops).await;
+        self.settle(state, &targets, results)
+    }
+
+    /*
+     * Journal the stripes an update is about to write, if they are not
+     * already.  This has to be saved before any of them are written, so a
+     * restart part way through knows which parity to make again.
+     */
+    fn journal(
+        &self,
+        state: &[ShardState],
+        stripes: &Range<u64>,
+    ) -> Result<(), CrucibleError> {
+        if self.state_path.is_none() {
+            return Ok(());
+        }
+
+        let entries = stripes.start / JOURNAL_STRIPES
+            ..=(stripes.end - 1) / JOURNAL_STRIPES;
+        let added = {
+            let mut dirty = self.dirty.lock().unwrap();
+            let mut added = false;
+            for entry in entries {
+                added |= dirty.insert(entry);
+            }
+            added
+        };
+        if added {
+            self.save_state(state)?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Once the shards have flushed, every journaled stripe has its data
+     * and parity in step.
+     */
+    fn clear_journal(&self, state: &[ShardState]) -> Result<(), CrucibleError> {
+        let cleared = {
+            let mut dirty = self.dirty.lock().unwrap();
+            let cleared = !dirty.is_empty();
+            dirty.clear();
+            cleared
+        };
+        if cleared {
+            self.save_state(state)?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Make the parity of every journaled stripe again from its data, for a
+     * set that stopped with writes in flight.
+     */
+    async fn resync_parity(
+        &self,
+        state: &mut [ShardState],
+    ) -> Result<(), CrucibleError> {
+        let dirty: Vec<u64> =
+            self.dirty.lock().unwrap().iter().copied().collect();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+
+        let k = self.data_shards;
+        let missing =
+            (0..k).filter(|i| state[*i] != ShardState::Active).count();
+        if missing > 0 {
+            warn!(
+                self.log,
+                "{} data shards are faulted, the {} journaled stripe ranges \
+                are rebuilt from parity that may not match",
+                missing,
+                dirty.len(),
+            );
+        }
+        info!(
+            self.log,
+            "Making parity again for {} journaled stripe ranges",
+            dirty.len()
+        );
+
+        let mut stripe_count = u64::MAX;
+        for (i, shard) in self.shards.iter().enumerate() {
+            if state[i] != ShardState::Faulted {
+                stripe_count = std::cmp::min(
+                    stripe_count,
+                    shard.total_size().await? / self.block_size,
+                );
+            }
+        }
+
+        for entry in dirty {
+            let start = entry * JOURNAL_STRIPES;
+            if start >= stripe_count {
+                continue;
+            }
+            let stripes =
+                start..std::cmp::min(start + JOURNAL_STRIPES, stripe_count);
+
+            let data = self.read_stripes(state, &stripes).await?;
+            let refs: Vec<&[u8]> = data.iter().map(|s| &s.data[..]).collect();
+            let parity = self.codec.encode(&refs);
+
+            let mut targets = Vec::new();
+            let mut ops: Vec<CrucibleBlockIOFuture> = Vec::new();
+            for (p, parity) in parity.into_iter().enumerate() {
+                let i = k + p;
+                if state[i] == ShardState::Faulted {
+                    continue;
+                }
+                ops.push(
+                    self.shards[i]
+                        .write(self.block(stripes.start), Bytes::from(parity)),
+                );
+                targets.push(i);
+            }
+            let results = futures::future::join_all(ops).await;
+            self.settle(state, &targets, results)?;
+        }
+
+        self.flush_shards(state, None).await?;
+        self.clear_journal(state)
+    }
+
+    /*
+     * Flush every shard that is not faulted.
+     */
+    async fn flush_shards(
+        &self,
+        state: &mut [ShardState],
+        snapshot_details: Option<SnapshotDetails>,
+    ) -> Result<(), CrucibleError> {
+        let targets: Vec<usize> = (0..self.shards.len())
+            .filter(|i| state[*i] != ShardState::Faulted)
+            .collect();
+        let results = futures::future::join_all(
+            targets
+                .iter()
+                .map(|i| self.shards[*i].flush(snapshot_details.clone())),
+        )
+        .await;
+
+        self.settle(state, &targets, results)
+    }
+
+    /*
+     * Write the shard state out to the file, if there is one.  This has to
+     * happen before an update a shard missed is acked, or a restart could
+     * read that shard again.
+     */
+    fn save_state(&self, state: &[ShardState]) -> Result<(), CrucibleError> {
+        if let Some(path) = &self.state_path {
+            let saved = SavedState {
+                shards: state.to_vec(),
+                dirty: self.dirty.lock().unwrap().clone(),
+            };
+            if let Err(e) = write_json(path, &saved, true) {
+                crucible_bail!(
+                    ErasureCodingError,
+                    "saving shard state {:?}: {}",
+                    path,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check_len(&self, len: usize) -> Result<u64, CrucibleError> {
+        if len % self.block_size as usize != 0 {
+            crucible_bail!(DataLenUnaligned);
+        }
+        Ok((len / self.block_size as usize) as u64)
+    }
+
+    /// Write the contents of `shard` back out from the other shards, and
+    /// return it to service.  IO to the set carries on while this runs.
+    pub async fn rebuild_shard(
+        &self,
+        shard: usize,
+    ) -> Result<(), CrucibleError> {
+        let k = self.data_shards;
+        if shard >= self.shards.len() {
+            crucible_bail!(
+                ErasureCodingError,
+                "no shard {} in a set of {}",
+                shard,
+                self.shards.len()
+            );
+        }
+
+        let stripe_count =
+            self.shards[shard].total_size().await? / self.block_size;
+
+        self.state.lock().await[shard] = ShardState::Rebuilding;
+
+        let mut stripe = 0;
+        while stripe < stripe_count {
+            let stripes =
+                stripe..std::cmp::min(stripe + REBUILD_STRIPES, stripe_count);
+
+            let mut state = self.state.lock().await;
+            if state[shard] != ShardState::Rebuilding {
+                crucible_bail!(
+                    ErasureCodingError,
+                    "shard {} faulted during rebuild",
+                    shard
+                );
+            }
+
+            let mut data = self.read_stripes(&state, &stripes).await?;
+            let rebuilt = if shard < k {
+                data.swap_remove(shard).data
+            } else {
+                let refs: Vec<&[u8]> =
+                    data.iter().map(|s| &s.data[..]).collect();
+                self.codec.encode(&refs).swap_remove(shard - k)
+            };
+
+            if let Err(e) = self.shards[shard]
+                .write(self.block(stripes.start), Bytes::from(rebuilt))
+                .await
+            {
+                state[shard] = ShardState::Faulted;
+                return Err(e);
+            }
+
+            stripe = stripes.end;
+        }
+
+        let mut state = self.state.lock().await;
+        state[shard] = ShardState::Active;
+        self.save_state(&state)
+    }
+
+    /// Rebuild every faulted shard that is active again.
+    pub async fn rebuild_faulted(&self) -> Result<(), CrucibleError> {
+        for shard in 0..self.shards.len() {
+            if self.state.lock().await[shard] != ShardState::Faulted {
+                continue;
+            }
+            if !self.shards[shard].query_is_active().await.unwrap_or(false) {
+                continue;
+            }
+            self.rebuild_shard(shard).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/*
+ * Rebuild the faulted shards of a set as they come back, until the set
+ * is dropped.
+ */
+async fn rebuild_task(set: Weak<ErasureCodedBlockIO>) {
+    loop {
+        tokio::time::sleep(REBUILD_INTERVAL).await;
+
+        let set = match set.upgrade() {
+            Some(set) => set,
+            None => return,
+        };
+        if let Err(e) = set.rebuild_faulted().await {
+            error!(
+                set.log,
+                "Erasure coded set {} rebuild failed: {}", set.uuid, e
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl BlockIO for ErasureCodedBlockIO {
+    /*
+     * Activate the shards together.  Once enough of them to read the data
+     * back are up, the rest get ACTIVATE_WAIT to join them, and any that
+     * don't are faulted.  Their activation is left to finish on its own,
+     * and they are rebuilt once it does.
+     */
+    async fn activate(&self) -> Result<(), CrucibleError> {
+        let mut state = self.state.lock().await;
+
+        let mut pending: FuturesUnordered<_> = self
+            .shards
+            .iter()
+            .enumerate()
+            .map(|(i, shard)| async move { (i, shard.activate().await) })
+            .collect();
+
+        let mut activated = vec![false; self.shards.len()];
+        let mut last_error = None;
+        let mut deadline = None;
+        loop {
+            let next = match deadline {
+                None => pending.next().await,
+                Some(deadline) => {
+                    match tokio::time::timeout_at(deadline, pending.next())
+                        .await
+                    {
+                        Ok(next) => next,
+                        Err(_) => break,
+                    }
+                }
+            };
+
+            match next {
+                Some((i, Ok(()))) => {
+                    if self.shards[i].get_block_size().await? != self.block_size
+                    {
+                        crucible_bail!(BlockSizeMismatch);
+                    }
+                    activated[i] = true;
+                }
+                Some((_, Err(e))) => last_error = Some(e),
+                None => break,
+            }
+
+            let usable = (0..self.shards.len())
+                .filter(|i| activated[*i] && state[*i] == ShardState::Active)
+                .count();
+            if deadline.is_none() && usable >= self.data_shards {
+                deadline = Some(Instant::now() + ACTIVATE_WAIT);
+            }
+        }
+
+        let mut faulted = false;
+        for (i, activated) in activated.iter().enumerate() {
+            if !activated && state[i] != ShardState::Faulted {
+                state[i] = ShardState::Faulted;
+                faulted = true;
+            }
+        }
+        if faulted {
+            self.save_state(&state)?;
+        }
+
+        let active = state.iter().filter(|s| **s == ShardState::Active).count();
+        if active < self.data_shards {
+            crucible_bail!(
+                ErasureCodingError,
+                "{} of {} shards needed activated, last error {:?}",
+                active,
+                self.data_shards,
+                last_error,
+            );
+        }
+
+        self.resync_parity(&mut state).await
+    }
+
+    async fn deactivate(&self) -> Result<(), CrucibleError> {
+        let state = self.state.lock().await;
+
+        /*
+         * A faulted shard may never have activated, so only the others
+         * have to deactivate cleanly.
+         */
+        let results = futures::future::join_all(
+            self.shards.iter().map(|shard| shard.deactivate()),
+        )
+        .await;
+        for (i, result) in results.into_iter().enumerate() {
+            if state[i] != ShardState::Faulted {
+                result?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn query_is_active(&self) -> Result<bool, CrucibleError> {
+        let state = self.state.lock().await;
+        for (i, shard) in self.shards.iter().enumerate() {
+            if state[i] != ShardState::Faulted
+                && !shard.query_is_active().await?
+            {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn total_size(&self) -> Result<u64, CrucibleError> {
+        let state = self.state.lock().await;
+        let mut blocks = None;
+        for (i, shard) in self.shards.iter().enumerate() {
+            if state[i] == ShardState::Faulted {
+                continue;
+            }
+            let shard_blocks = shard.total_size().await? / self.block_size;
+            blocks = Some(match blocks {
+                Some(blocks) => std::cmp::min(blocks, shard_blocks),
+                None => shard_blocks,
+            });
+        }
+
+        match blocks {
+            Some(blocks) => {
+                Ok(blocks * self.data_shards as u64 * self.block_size)
+            }
+            None => {
+                crucible_bail!(ErasureCodingError, "every shard is faulted")
+            }
+        }
+    }
+
+    async fn get_block_size(&self) -> Result<u64, CrucibleError> {
+        Ok(self.block_size)
+    }
+
+    async fn get_uuid(&self) -> Result<Uuid, CrucibleError> {
+        Ok(self.uuid)
+    }
+
+    async fn read(
+        &self,
+        offset: Block,
+        data: Buffer,
+    ) -> Result<(), CrucibleError> {
+        let count = self.check_len(data.len())?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let state = self.state.lock().await;
+        let stripes = self.stripes(offset.value, count);
+        let shards = self.read_stripes(&state, &stripes).await?;
+
+        let bs = self.block_size as usize;
+        let mut data_vec = data.as_vec().await;
+        let mut owned_vec = data.owned_vec().await;
+        for i in 0..count {
+            let (shard, stripe) = self.locate(offset.value + i);
+            let src = (stripe - stripes.start) as usize * bs;
+            let dst = i as usize * bs;
+
+            data_vec[dst..dst + bs]
+                .copy_from_slice(&shards[shard].data[src..src + bs]);
+            owned_vec[dst..dst + bs]
+                .copy_from_slice(&shards[shard].owned[src..src + bs]);
+        }
+
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        offset: Block,
+        data: Bytes,
+    ) -> Result<(), CrucibleError> {
+        let count = self.check_len(data.len())?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().await;
+        self.update(&mut state, offset.value, count, Update::Write(&data))
+            .await
+    }
+
+    async fn write_unwritten(
+        &self,
+        offset: Block,
+        data: Bytes,
+    ) -> Result<(), CrucibleError> {
+        let count = self.check_len(data.len())?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().await;
+        self.update(
+            &mut state,
+            offset.value,
+            count,
+            Update::WriteUnwritten(&data),
+        )
+        .await
+    }
+
+    async fn flush(
+        &self,
+        snapshot_details: Option<SnapshotDetails>,
+    ) -> Result<(), CrucibleError> {
+        let mut state = self.state.lock().await;
+        self.flush_shards(&mut state, snapshot_details).await?;
+        self.clear_journal(&state)
+    }
+
+    async fn discard(
+        &self,
+        offset: Block,
+        len: u64,
+    ) -> Result<(), CrucibleError> {
+        let count = self.check_len(len as usize)?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().await;
+        self.update(&mut state, offset.value, count, Update::Discard)
+            .await
+    }
+
+    async fn resize(&self, extent_count: u32) -> Result<(), CrucibleError> {
+        let _state = self.state.lock().await;
+        join_all(self.shards.iter().map(|shard| shard.resize(extent_count)))
+            .await
+    }
+
+    async fn show_work(&self) -> Result<WQCounts, CrucibleError> {
+        let mut wq_counts = WQCounts {
+            up_count: 0,
+            ds_count: 0,
+        };
+
+        for shard in &self.shards {
+            let shard_wq_counts = shard.show_work().await?;
+
+            wq_counts.up_count += shard_wq_counts.up_count;
+            wq_counts.ds_count += shard_wq_counts.ds_count;
+        }
+
+        Ok(wq_counts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /*
+     * An in memory shard that can be taken offline.
+     */
+    struct FlakyBlockIO {
+        inner: InMemoryBlockIO,
+        broken: AtomicBool,
+    }
+
+    impl FlakyBlockIO {
+        fn new(block_size: u64, blocks: usize) -> Self {
+            FlakyBlockIO {
+                inner: InMemoryBlockIO::new(
+                    Uuid::new_v4(),
+                    block_size,
+                    blocks * block_size as usize,
+                ),
+                broken: AtomicBool::new(false),
+            }
+        }
+
+        fn set_broken(&self, broken: bool) {
+            self.broken.store(broken, Ordering::SeqCst);
+        }
+
+        fn check(&self) -> Result<(), CrucibleError> {
+            if self.broken.load(Ordering::SeqCst) {
+                crucible_bail!(GenericError, "shard offline");
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl BlockIO for FlakyBlockIO {
+        async fn activate(&self) -> Result<(), CrucibleError> {
+            self.check()?;
+            self.inner.activate().await
+        }
+
+        async fn deactivate(&self) -> Result<(), CrucibleError> {
+            self.inner.deactivate().await
+        }
+
+        async fn query_is_active(&self) -> Result<bool, CrucibleError> {
+            if self.broken.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            self.inner.query_is_active().await
+        }
+
+        async fn total_size(&self) -> Result<u64, CrucibleError> {
+            self.inner.total_size().await
+        }
+
+        async fn get_block_size(&self) -> Result<u64, CrucibleError> {
+            self.inner.get_block_size().await
+        }
+
+        async fn get_uuid(&self) -> Result<Uuid, CrucibleError> {
+            self.inner.get_uuid().await
+        }
+
+        async fn read(
+            &self,
+            offset: Block,
+            data: Buffer,
+        ) -> Result<(), CrucibleError> {
+            self.check()?;
+            self.inner.read(offset, data).await
+        }
+
+        async fn write(
+            &self,
+            offset: Block,
+            data: Bytes,
+        ) -> Result<(), CrucibleError> {
+            self.check()?;
+            self.inner.write(offset, data).await
+        }
+
+        async fn write_unwritten(
+            &self,
+            offset: Block,
+            data: Bytes,
+        ) -> Result<(), CrucibleError> {
+            self.check()?;
+            self.inner.write_unwritten(offset, data).await
+        }
+
+        async fn flush(
+            &self,
+            snapshot_details: Option<SnapshotDetails>,
+        ) -> Result<(), CrucibleError> {
+            self.check()?;
+            self.inner.flush(snapshot_details).await
+        }
+
+        async fn discard(
+            &self,
+            offset: Block,
+            len: u64,
+        ) -> Result<(), CrucibleError> {
+            self.check()?;
+            self.inner.discard(offset, len).await
+        }
+
+        async fn resize(&self, extent_count: u32) -> Result<(), CrucibleError> {
+            self.inner.resize(extent_count).await
+        }
+
+        async fn show_work(&self) -> Result<WQCounts, CrucibleError> {
+            self.inner.show_work().await
+        }
+    }
+
+    const BLOCK_SIZE: u64 = 512;
+
+    fn csl() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+
+    /*
+     * A 4+2 set with `blocks` blocks on each shard.
+     */
+    fn make_set(
+        blocks: usize,
+    ) -> (ErasureCodedBlockIO, Vec<Arc<FlakyBlockIO>>) {
+        let flaky: Vec<Arc<FlakyBlockIO>> = (0..6)
+            .map(|_| Arc::new(FlakyBlockIO::new(BLOCK_SIZE, blocks)))
+            .collect();
+        let shards: Vec<Arc<dyn BlockIO + Send + Sync>> = flaky
+            .iter()
+            .map(|f| f.clone() as Arc<dyn BlockIO + Send + Sync>)
+            .collect();
+
+        let set = ErasureCodedBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE,
+            4,
+            2,
+            shards,
+            None,
+            csl(),
+        )
+        .unwrap();
+        (set, flaky)
+    }
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        thread_rng().fill(&mut data[..]);
+        data
+    }
+
+    async fn read_all(set: &ErasureCodedBlockIO) -> Result<Vec<u8>> {
+        let size = set.total_size().await? as usize;
+        let buffer = Buffer::new(size);
+        set.read(Block::new_512(0), buffer.clone()).await?;
+        let data = buffer.as_vec().await.clone();
+        Ok(data)
+    }
+
+    #[test]
+    fn codec_rebuilds_any_two_missing() -> Result<()> {
+        let codec = ReedSolomon::new(4, 2)?;
+        let data: Vec<Vec<u8>> = (0..4).map(|_| random_data(64)).collect();
+        let refs: Vec<&[u8]> = data.iter().map(|d| &d[..]).collect();
+        let parity = codec.encode(&refs);
+        let all: Vec<Vec<u8>> = data.into_iter().chain(parity).collect();
+
+        for a in 0..6 {
+            for b in a..6 {
+                let mut shards: Vec<Option<Vec<u8>>> =
+                    all.iter().cloned().map(Some).collect();
+                shards[a] = None;
+                shards[b] = None;
+
+                codec.reconstruct(&mut shards)?;
+
+                for (shard, expected) in shards.iter().zip(&all) {
+                    assert_eq!(shard.as_ref().unwrap(), expected);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn codec_needs_data_shards_present() -> Result<()> {
+        let codec = ReedSolomon::new(4, 2)?;
+        let mut shards: Vec<Option<Vec<u8>>> = vec![None; 6];
+        shards[0] = Some(vec![1; 16]);
+        shards[4] = Some(vec![2; 16]);
+        shards[5] = Some(vec![3; 16]);
+
+        assert!(codec.reconstruct(&mut shards).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn codec_rejects_bad_layouts() {
+        assert!(ReedSolomon::new(0, 2).is_err());
+        assert!(ReedSolomon::new(200, 57).is_err());
+        assert!(ReedSolomon::new(200, 56).is_ok());
+    }
+
+    #[test]
+    fn set_needs_one_shard_per_slot() {
+        let shards: Vec<Arc<dyn BlockIO + Send + Sync>> = (0..5)
+            .map(|_| {
+                Arc::new(FlakyBlockIO::new(BLOCK_SIZE, 8))
+                    as Arc<dyn BlockIO + Send + Sync>
+            })
+            .collect();
+        assert!(ErasureCodedBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE,
+            4,
+            2,
+            shards,
+            None,
+            csl(),
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn set_total_size() -> Result<()> {
+        let (set, _) = make_set(8);
+        set.activate().await?;
+        assert_eq!(set.total_size().await?, 32 * BLOCK_SIZE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_write_read() -> Result<()> {
+        let (set, _) = make_set(8);
+        set.activate().await?;
+
+        let mut expected = random_data(32 * BLOCK_SIZE as usize);
+        set.write(Block::new_512(0), Bytes::from(expected.clone()))
+            .await?;
+        assert_eq!(read_all(&set).await?, expected);
+
+        /*
+         * A write that starts and ends part way through a stripe.
+         */
+        let data = random_data(6 * BLOCK_SIZE as usize);
+        set.write(Block::new_512(3), Bytes::from(data.clone()))
+            .await?;
+        expected[3 * 512..9 * 512].copy_from_slice(&data);
+        assert_eq!(read_all(&set).await?, expected);
+
+        /*
+         * And a read that does the same.
+         */
+        let buffer = Buffer::new(5 * BLOCK_SIZE as usize);
+        set.read(Block::new_512(2), buffer.clone()).await?;
+        assert_eq!(*buffer.as_vec().await, expected[2 * 512..7 * 512]);
+        assert!(buffer.owned_vec().await.iter().all(|o| *o));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_read_with_missing_shards() -> Result<()> {
+        let (set, flaky) = make_set(8);
+        set.activate().await?;
+
+        let expected = random_data(32 * BLOCK_SIZE as usize);
+        set.write(Block::new_512(0), Bytes::from(expected.clone()))
+            .await?;
+
+        flaky[1].set_broken(true);
+        flaky[4].set_broken(true);
+        assert_eq!(read_all(&set).await?, expected);
+
+        flaky[2].set_broken(true);
+        assert!(read_all(&set).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_write_faults_shard_until_rebuilt() -> Result<()> {
+        let (set, flaky) = make_set(8);
+        set.activate().await?;
+
+        let mut expected = random_data(32 * BLOCK_SIZE as usize);
+        set.write(Block::new_512(0), Bytes::from(expected.clone()))
+            .await?;
+
+        /*
+         * Shard 2 misses this write, and must not be read from afterwards
+         * even once it is back.
+         */
+        flaky[2].set_broken(true);
+        let data = random_data(10 * BLOCK_SIZE as usize);
+        set.write(Block::new_512(5), Bytes::from(data.clone()))
+            .await?;
+        expected[5 * 512..15 * 512].copy_from_slice(&data);
+        flaky[2].set_broken(false);
+
+        assert_eq!(read_all(&set).await?, expected);
+        assert_eq!(set.state.lock().await[2], ShardState::Faulted);
+
+        set.rebuild_shard(2).await?;
+        assert_eq!(set.state.lock().await[2], ShardState::Active);
+
+        /*
+         * With two other shards gone, the data has to come from the one
+         * that was rebuilt.
+         */
+        flaky[0].set_broken(true);
+        flaky[5].set_broken(true);
+        assert_eq!(read_all(&set).await?, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_rebuild_parity_shard() -> Result<()> {
+        let (set, flaky) = make_set(8);
+        set.activate().await?;
+
+        flaky[5].set_broken(true);
+        let expected = random_data(32 * BLOCK_SIZE as usize);
+        set.write(Block::new_512(0), Bytes::from(expected.clone()))
+            .await?;
+        flaky[5].set_broken(false);
+
+        set.rebuild_shard(5).await?;
+
+        flaky[0].set_broken(true);
+        flaky[3].set_broken(true);
+        assert_eq!(read_all(&set).await?, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_activates_without_a_shard() -> Result<()> {
+        let (set, flaky) = make_set(8);
+
+        flaky[3].set_broken(true);
+        set.activate().await?;
+        assert_eq!(set.state.lock().await[3], ShardState::Faulted);
+
+        let expected = random_data(32 * BLOCK_SIZE as usize);
+        set.write(Block::new_512(0), Bytes::from(expected.clone()))
+            .await?;
+
+        /*
+         * Nothing is rebuilt until the shard is active again.
+         */
+        set.rebuild_faulted().await?;
+        assert_eq!(set.state.lock().await[3], ShardState::Faulted);
+
+        flaky[3].set_broken(false);
+        set.rebuild_faulted().await?;
+        assert_eq!(set.state.lock().await[3], ShardState::Active);
+
+        flaky[0].set_broken(true);
+        flaky[1].set_broken(true);
+        assert_eq!(read_all(&set).await?, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_activate_needs_data_shards() -> Result<()> {
+        let (set, flaky) = make_set(8);
+
+        flaky[0].set_broken(true);
+        flaky[2].set_broken(true);
+        flaky[4].set_broken(true);
+        assert!(set.activate().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_shard_state_survives_restart() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("shards.json");
+
+        let flaky: Vec<Arc<FlakyBlockIO>> = (0..6)
+            .map(|_| Arc::new(FlakyBlockIO::new(BLOCK_SIZE, 8)))
+            .collect();
+        let shards: Vec<Arc<dyn BlockIO + Send + Sync>> = flaky
+            .iter()
+            .map(|f| f.clone() as Arc<dyn BlockIO + Send + Sync>)
+            .collect();
+
+        let set = ErasureCodedBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE,
+            4,
+            2,
+            shards.clone(),
+            Some(path.clone()),
+            csl(),
+        )?;
+        set.activate().await?;
+
+        flaky[1].set_broken(true);
+        let expected = random_data(32 * BLOCK_SIZE as usize);
+        set.write(Block::new_512(0), Bytes::from(expected.clone()))
+            .await?;
+        flaky[1].set_broken(false);
+        drop(set);
+
+        /*
+         * Shard 1 is back, but still holds stale data, so the set made
+         * again from the same file must not read from it.
+         */
+        let set = ErasureCodedBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE,
+            4,
+            2,
+            shards.clone(),
+            Some(path.clone()),
+            csl(),
+        )?;
+        set.activate().await?;
+        assert_eq!(set.state.lock().await[1], ShardState::Faulted);
+
+        flaky[0].set_broken(true);
+        flaky[2].set_broken(true);
+        assert!(read_all(&set).await.is_err());
+        flaky[0].set_broken(false);
+        flaky[2].set_broken(false);
+
+        set.rebuild_faulted().await?;
+        drop(set);
+
+        let set = ErasureCodedBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE,
+            4,
+            2,
+            shards,
+            Some(path),
+            csl(),
+        )?;
+        assert_eq!(set.state.lock().await[1], ShardState::Active);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_resyncs_parity_after_restart() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("shards.json");
+
+        let flaky: Vec<Arc<FlakyBlockIO>> = (0..6)
+            .map(|_| Arc::new(FlakyBlockIO::new(BLOCK_SIZE, 8)))
+            .collect();
+        let shards: Vec<Arc<dyn BlockIO + Send + Sync>> = flaky
+            .iter()
+            .map(|f| f.clone() as Arc<dyn BlockIO + Send + Sync>)
+            .collect();
+
+        let set = ErasureCodedBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE,
+            4,
+            2,
+            shards.clone(),
+            Some(path.clone()),
+            csl(),
+        )?;
+        set.activate().await?;
+
+        // A flush takes written stripes out of the journal.
+        let mut expected = random_data(32 * BLOCK_SIZE as usize);
+        set.write(Block::new_512(0), Bytes::from(expected.clone()))
+            .await?;
+        let saved: SavedState = read_json_maybe(&path)?.unwrap();
+        assert_eq!(saved.dirty, BTreeSet::from([0]));
+        set.flush(None).await?;
+        let saved: SavedState = read_json_maybe(&path)?.unwrap();
+        assert!(saved.dirty.is_empty());
+
+        /*
+         * Stop part way through the next write: the new data got to data
+         * shard 0, but none of the parity did.
+         */
+        set.write(Block::new_512(0), Bytes::from(expected.clone()))
+            .await?;
+        drop(set);
+        let torn = random_data(8 * BLOCK_SIZE as usize);
+        flaky[0]
+            .write(Block::new_512(0), Bytes::from(torn.clone()))
+            .await?;
+        let bs = BLOCK_SIZE as usize;
+        for stripe in 0..8 {
+            expected[stripe * 4 * bs..(stripe * 4 + 1) * bs]
+                .copy_from_slice(&torn[stripe * bs..(stripe + 1) * bs]);
+        }
+
+        /*
+         * The parity is made again when the set activates, so a data
+         * shard rebuilt from it comes back as it was.
+         */
+        let set = ErasureCodedBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE,
+            4,
+            2,
+            shards,
+            Some(path.clone()),
+            csl(),
+        )?;
+        set.activate().await?;
+        assert!(set.dirty.lock().unwrap().is_empty());
+
+        flaky[1].set_broken(true);
+        assert_eq!(read_all(&set).await?, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_write_fails_without_enough_shards() -> Result<()> {
+        let (set, flaky) = make_set(8);
+        set.activate().await?;
+
+        flaky[0].set_broken(true);
+        flaky[1].set_broken(true);
+        flaky[2].set_broken(true);
+
+        let data = random_data(4 * BLOCK_SIZE as usize);
+        assert!(set
+            .write(Block::new_512(0), Bytes::from(data))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_write_unwritten() -> Result<()> {
+        let (set, _) = make_set(8);
+        set.activate().await?;
+
+        let first = random_data(BLOCK_SIZE as usize);
+        set.write(Block::new_512(1), Bytes::from(first.clone()))
+            .await?;
+
+        let second = random_data(4 * BLOCK_SIZE as usize);
+        set.write_unwritten(Block::new_512(0), Bytes::from(second.clone()))
+            .await?;
+
+        let buffer = Buffer::new(4 * BLOCK_SIZE as usize);
+        set.read(Block::new_512(0), buffer.clone()).await?;
+        let data = buffer.as_vec().await;
+        assert_eq!(data[..512], second[..512]);
+        assert_eq!(data[512..1024], first[..]);
+        assert_eq!(data[1024..], second[1024..]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_discard_keeps_parity() -> Result<()> {
+        let (set, flaky) = make_set(8);
+        set.activate().await?;
+
+        let mut expected = random_data(32 * BLOCK_SIZE as usize);
+        set.write(Block::new_512(0), Bytes::from(expected.clone()))
+            .await?;
+
+        set.discard(Block::new_512(6), 9 * BLOCK_SIZE).await?;
+        for b in &mut expected[6 * 512..15 * 512] {
+            *b = 0;
+        }
+        assert_eq!(read_all(&set).await?, expected);
+
+        let buffer = Buffer::new(BLOCK_SIZE as usize);
+        set.read(Block::new_512(7), buffer.clone()).await?;
+        assert!(buffer.owned_vec().await.iter().all(|o| !*o));
+
+        /*
+         * Parity has to agree with the discarded data shards.
+         */
+        flaky[2].set_broken(true);
+        flaky[3].set_broken(true);
+        assert_eq!(read_all(&set).await?, expected);
+
+        Ok(())
+    }
+}
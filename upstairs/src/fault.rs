@@ -0,0 +1,262 @@
+// Copyright 2022 Oxide Computer Company
+use super::*;
+
+use std::collections::BTreeMap;
+
+/*
+ * Faulting a downstairs that is too slow.
+ *
+ * A downstairs that is still connected but very slow stays Active, and
+ * every flush and every retired job waits on it.  With a fault timeout or
+ * a fault queue limit set, a downstairs that leaves a job unanswered for
+ * longer than the timeout, or has more jobs outstanding than the limit, is
+ * moved to DsState::Faulted.  Its jobs are skipped, the extents they
+ * change are tracked as dirty, and its task hangs up.  When it connects
+ * again, it is caught up by copying its dirty extents from another
+ * downstairs, the same as one that was offline past the replay window.
+ *
+ * A downstairs is only faulted when it is the one downstairs over a
+ * limit.  If they all are, it is the load that is the problem, not that
+ * downstairs.  It is also only faulted when all the others are Active and
+ * make up the write quorum, so faulting it never stops IO.
+ */
+
+/*
+ * Why a downstairs was faulted.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FaultReason {
+    /*
+     * A job went unanswered for longer than the fault timeout.
+     */
+    JobTimeout,
+    /*
+     * More jobs were outstanding than the fault queue limit.
+     */
+    QueueFull,
+    /*
+     * A resize went one way on this downstairs and the other way on the
+     * rest, so its region is not the size ours is.
+     */
+    ResizeMismatch,
+}
+
+#[derive(Debug)]
+pub(crate) struct FaultCheck {
+    timeout: Option<Duration>,
+    queue: Option<usize>,
+    // When each job that may still be outstanding was sent, by client ID
+    // and then job ID.
+    sent: Vec<BTreeMap<u64, Instant>>,
+    // Why each client was last faulted.
+    reasons: Vec<Option<FaultReason>>,
+}
+
+impl FaultCheck {
+    pub(crate) fn new(
+        timeout: Option<u32>,
+        queue: Option<usize>,
+        replicas: u8,
+    ) -> FaultCheck {
+        FaultCheck {
+            timeout: timeout.map(|t| Duration::from_secs(t as u64)),
+            queue,
+            sent: vec![BTreeMap::new(); replicas as usize],
+            reasons: vec![None; replicas as usize],
+        }
+    }
+
+    pub(crate) fn sent(&mut self, ds_id: u64, client_id: u8) {
+        if self.timeout.is_some() {
+            self.sent[client_id as usize].insert(ds_id, Instant::now());
+        }
+    }
+
+    pub(crate) fn reasons(&self) -> Vec<Option<FaultReason>> {
+        self.reasons.clone()
+    }
+
+    /*
+     * Is this client over a limit, given how many jobs it has
+     * outstanding?  Only the sends of jobs that are still outstanding are
+     * kept.
+     */
+    fn check<F>(
+        &mut self,
+        client_id: u8,
+        outstanding: usize,
+        now: Instant,
+        in_progress: F,
+    ) -> Option<FaultReason>
+    where
+        F: Fn(u64) -> bool,
+    {
+        let sent = &mut self.sent[client_id as usize];
+        sent.retain(|ds_id, _| in_progress(*ds_id));
+
+        if let Some(queue) = self.queue {
+            if outstanding > queue {
+                return Some(FaultReason::QueueFull);
+            }
+        }
+        let timeout = self.timeout?;
+        let oldest = sent.values().min()?;
+        if now.saturating_duration_since(*oldest) > timeout {
+            Some(FaultReason::JobTimeout)
+        } else {
+            None
+        }
+    }
+
+    fn fault(&mut self, client_id: u8, reason: FaultReason) {
+        self.sent[client_id as usize].clear();
+        self.reasons[client_id as usize] = Some(reason);
+    }
+}
+
+impl Downstairs {
+    /*
+     * Look for the one downstairs that is over a limit, if faulting it
+     * would leave enough of the others to keep taking IO.
+     */
+    pub(crate) fn fault_find(
+        &mut self,
+        now: Instant,
+    ) -> Option<(u8, FaultReason)> {
+        if self.migration.is_some()
+            || self.replicas <= self.write_quorum
+            || self.ds_state.iter().any(|s| *s != DsState::Active)
+        {
+            return None;
+        }
+
+        let mut over = Vec::new();
+        for client_id in 0..self.replicas {
+            let mut outstanding = 0;
+            for job in self.ds_active.values() {
+                let state = job.state.get(&client_id);
+                if state == Some(&IOState::New)
+                    || state == Some(&IOState::InProgress)
+                {
+                    outstanding += 1;
+                }
+            }
+            let ds_active = &self.ds_active;
+            let in_progress = |ds_id| {
+                matches!(
+                    ds_active.get(&ds_id),
+                    Some(job) if job.state.get(&client_id)
+                        == Some(&IOState::InProgress)
+                )
+            };
+            if let Some(reason) =
+                self.fault_check
+                    .check(client_id, outstanding, now, in_progress)
+            {
+                over.push((client_id, reason));
+            }
+        }
+
+        if over.len() == 1 {
+            over.pop()
+        } else {
+            None
+        }
+    }
+
+    /*
+     * Stop sending IO to this client.  Every job it has not done is
+     * skipped, and we track the extents they change so they can be
+     * repaired when it comes back.  A read only sent to it goes to
+     * another downstairs if it can.  Return true if a job is now ready
+     * to ack.  The caller is responsible for moving the client to
+     * DsState::Faulted.
+     */
+    pub(crate) fn fault(&mut self, client_id: u8, reason: FaultReason) -> bool {
+        self.fault_check.fault(client_id, reason);
+        self.dirty_start(client_id);
+        self.skip_all_jobs(client_id);
+
+        let mut ids: Vec<u64> = self.ds_active.keys().cloned().collect();
+        ids.sort_unstable();
+
+        let mut notify_guest = false;
+        for ds_id in ids {
+            let job = self.ds_active.get(&ds_id).unwrap();
+            if job.ack_status != AckStatus::NotAcked {
+                continue;
+            }
+            let wc = job.state_count();
+            if wc.error + wc.skipped + wc.done != self.replicas as u64 {
+                continue;
+            }
+            if job.work.is_read()
+                && wc.done == 0
+                && self.read_fallback(ds_id, client_id)
+            {
+                continue;
+            }
+            let job = self.ds_active.get_mut(&ds_id).unwrap();
+            job.ack_status = AckStatus::AckReady;
+            notify_guest = true;
+        }
+        notify_guest
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nothing_set_never_faults() {
+        let mut fc = FaultCheck::new(None, None, 3);
+        fc.sent(1, 0);
+        let later = Instant::now() + Duration::from_secs(3600);
+        assert_eq!(fc.check(0, 100_000, later, |_| true), None);
+    }
+
+    #[test]
+    fn queue_over_limit_faults() {
+        let mut fc = FaultCheck::new(None, Some(10), 3);
+        let now = Instant::now();
+        assert_eq!(fc.check(0, 10, now, |_| true), None);
+        assert_eq!(
+            fc.check(0, 11, now, |_| true),
+            Some(FaultReason::QueueFull)
+        );
+    }
+
+    #[test]
+    fn old_job_faults() {
+        let mut fc = FaultCheck::new(Some(5), None, 3);
+        fc.sent(1, 0);
+        fc.sent(2, 0);
+        let now = Instant::now();
+        assert_eq!(fc.check(0, 2, now, |_| true), None);
+
+        let later = now + Duration::from_secs(6);
+        assert_eq!(
+            fc.check(0, 2, later, |_| true),
+            Some(FaultReason::JobTimeout)
+        );
+        assert_eq!(fc.check(1, 2, later, |_| true), None);
+
+        // Once the jobs are answered, they don't count.
+        assert_eq!(fc.check(0, 0, later, |_| false), None);
+        assert!(fc.sent[0].is_empty());
+    }
+
+    #[test]
+    fn fault_keeps_reason() {
+        let mut fc = FaultCheck::new(Some(5), None, 3);
+        fc.sent(1, 2);
+        fc.fault(2, FaultReason::JobTimeout);
+        assert!(fc.sent[2].is_empty());
+        assert_eq!(
+            fc.reasons(),
+            vec![None, None, Some(FaultReason::JobTimeout)]
+        );
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2022 Oxide Computer Company
+use super::*;
+
+/*
+ * Healing the blocks a downstairs hands back corrupt.
+ *
+ * Every read goes to every downstairs.  A block in a read response that
+ * fails its integrity hash, or fails to decrypt, is corrupt on that
+ * downstairs.  That downstairs' part of the read is an error, and the
+ * guest gets the read from a downstairs whose response was intact.  The
+ * corrupt block is noted on the read job.
+ *
+ * Once the read job has an intact copy of the block, a write of that copy
+ * with the block context that came with it is queued for just the
+ * downstairs that had it corrupt, so all of them hold the same thing
+ * again.  For an encrypted volume, the block is encrypted again with the
+ * key and nonce it was written with, which gives back the ciphertext the
+ * other downstairs hold.
+ *
+ * A block the guest has changed since the read is left alone, as the job
+ * that changed it also fixes it.  So is a block a downstairs hands back
+ * corrupt after the read's data has gone to the guest, as the job no
+ * longer has it.
+ */
+
+impl Downstairs {
+    /**
+     * Queue a repair write for every corrupt block of this read job that
+     * there is now an intact copy of.
+     */
+    pub(crate) fn heal(
+        &mut self,
+        ds_id: u64,
+        encryption_context: &Option<Arc<EncryptionContext>>,
+    ) {
+        let job = match self.ds_active.get_mut(&ds_id) {
+            Some(job) => job,
+            None => return,
+        };
+
+        if job.data.is_none()
+            && job.ack_status == AckStatus::Acked
+            && !job.heal.is_empty()
+        {
+            warn!(
+                self.log,
+                "{} data already handed to the guest, not healing {:?}",
+                ds_id,
+                job.heal,
+            );
+            job.heal.clear();
+        }
+
+        if let (Some(good), false) = (&job.data, job.heal.is_empty()) {
+            let heal = std::mem::take(&mut job.heal);
+
+            let mut writes: HashMap<u8, Vec<crucible_protocol::Write>> =
+                HashMap::new();
+            for (client_id, i) in heal {
+                let response = &good[i];
+                let (eid, offset) = (response.eid, response.offset.value);
+
+                if self.block_record.changed_since(ds_id, eid, offset) {
+                    info!(
+                        self.log,
+                        "[{}] {} eid {} block {} changed since, not healing",
+                        client_id,
+                        ds_id,
+                        eid,
+                        offset,
+                    );
+                    continue;
+                }
+
+                match heal_write(
+                    response,
+                    job.read_response_hashes[i],
+                    encryption_context,
+                ) {
+                    Ok(write) => {
+                        warn!(
+                            self.log,
+                            "[{}] {} eid {} block {} is corrupt, healing",
+                            client_id,
+                            ds_id,
+                            eid,
+                            offset,
+                        );
+                        writes.entry(client_id).or_default().push(write);
+                    }
+                    Err(e) => {
+                        error!(
+                            self.log,
+                            "[{}] {} eid {} block {} is corrupt, can't heal \
+                             it: {}",
+                            client_id,
+                            ds_id,
+                            eid,
+                            offset,
+                            e,
+                        );
+                    }
+                }
+            }
+
+            let guest_id = job.guest_id;
+            let impacted_blocks = job.impacted_blocks.new_like();
+            for (client_id, writes) in writes {
+                self.enqueue_heal(
+                    client_id,
+                    guest_id,
+                    writes,
+                    impacted_blocks.new_like(),
+                );
+            }
+        }
+
+        /*
+         * Once every downstairs has answered, an acked read no longer
+         * needs its copy of the data.
+         */
+        if let Some(job) = self.ds_active.get_mut(&ds_id) {
+            if job.ack_status == AckStatus::Acked
+                && job.state_count().active == 0
+            {
+                job.data = None;
+            }
+        }
+    }
+
+    /*
+     * Queue a write that only goes to client_id.  Nothing waits on it, so
+     * it is acked from the start.
+     */
+    fn enqueue_heal(
+        &mut self,
+        client_id: u8,
+        guest_id: u64,
+        writes: Vec<crucible_protocol::Write>,
+        mut impacted_blocks: ImpactedBlocks,
+    ) {
+        for write in &writes {
+            impacted_blocks.add(write.eid, write.offset);
+        }
+
+        /*
+         * Depend on the last flush, and anything active for the same
+         * blocks, just like a guest write.
+         */
+        let mut dep = Vec::new();
+        for job_id in self.ds_active.keys().sorted().rev() {
+            let job = &self.ds_active[job_id];
+            if job.work.is_flush() {
+                dep.push(*job_id);
+                break;
+            }
+            if impacted_blocks.conflicts(&job.impacted_blocks) {
+                dep.push(*job_id);
+            }
+        }
+
+        let ds_id = self.next_id();
+        let healed = writes.len();
+        let mut io = create_write_eob(
+            ds_id,
+            dep,
+            guest_id,
+            writes,
+            false,
+            impacted_blocks,
+        );
+        io.ack_status = AckStatus::Acked;
+        for cl in 0..self.replicas {
+            let state = if cl == client_id {
+                IOState::New
+            } else {
+                IOState::Skipped
+            };
+            io.state.insert(cl, state);
+        }
+        self.ds_active.insert(ds_id, io);
+        self.blocks_healed += healed;
+    }
+}
+
+/*
+ * Build the write that puts an intact copy of a block from a read back
+ * the way it came from the downstairs.  hash is the hash of the block
+ * context that the copy matched.
+ */
+fn heal_write(
+    response: &ReadResponse,
+    hash: Option<u64>,
+    encryption_context: &Option<Arc<EncryptionContext>>,
+) -> Result<crucible_protocol::Write, CrucibleError> {
+    let hash = match hash {
+        Some(hash) => hash,
+        None => {
+            crucible_bail!(
+                GenericError,
+                "the intact copy is unwritten, leaving it for reconciliation"
+            );
+        }
+    };
+
+    let block_context = response
+        .block_contexts
+        .iter()
+        .find(|ctx| ctx.hash == hash)
+        .ok_or_else(|| {
+            CrucibleError::GenericError(format!(
+                "no block context has hash {}",
+                hash
+            ))
+        })?;
+
+    let data = match (encryption_context, &block_context.encryption_context) {
+        (Some(context), Some(ctx)) => {
+            let mut data = response.data.to_vec();
+            context
+                .encrypt_again_in_place(
+                    &mut data,
+                    Nonce::from_slice(&ctx.nonce[..]),
+                    Tag::from_slice(&ctx.tag[..]),
+                    ctx.key_id,
+                    response.eid,
+                    response.offset.value,
+                )
+                .map_err(|e| CrucibleError::EncryptionError(e.to_string()))?;
+            Bytes::from(data)
+        }
+        (None, None) => Bytes::copy_from_slice(&response.data[..]),
+        _ => {
+            crucible_bail!(
+                GenericError,
+                "block context does not match the volume's encryption"
+            );
+        }
+    };
+
+    Ok(crucible_protocol::Write {
+        eid: response.eid,
+        offset: response.offset,
+        data,
+        block_context: block_context.clone(),
+    })
+}
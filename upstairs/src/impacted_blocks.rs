@@ -23,6 +23,11 @@ impl ImpactedBlocks {
         }
     }
 
+    /// An empty list of impacted blocks of the same region.
+    pub fn new_like(&self) -> Self {
+        ImpactedBlocks::new(self.ddef)
+    }
+
     pub fn blocks_in_extent(&self) -> usize {
         self.ddef.extent_size().value as usize
     }
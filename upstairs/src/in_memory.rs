@@ -25,6 +25,23 @@ impl InMemoryBlockIO {
             }),
         }
     }
+
+    /*
+     * Where in the bytes a request of len bytes at offset starts, if it
+     * fits in them.
+     */
+    fn start_of(
+        &self,
+        inner: &Inner,
+        offset: Block,
+        len: usize,
+    ) -> Result<usize, CrucibleError> {
+        let start = offset.value as usize * self.block_size as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= inner.bytes.len() => Ok(start),
+            _ => crucible_bail!(OffsetInvalid),
+        }
+    }
 }
 
 #[async_trait]
@@ -64,7 +81,7 @@ impl BlockIO for InMemoryBlockIO {
         let mut data_vec = data.as_vec().await;
         let mut owned_vec = data.owned_vec().await;
 
-        let start = offset.value as usize * self.block_size as usize;
+        let start = self.start_of(&inner, offset, data_vec.len())?;
 
         for i in 0..data_vec.len() {
             data_vec[i] = inner.bytes[start + i];
@@ -81,7 +98,7 @@ impl BlockIO for InMemoryBlockIO {
     ) -> Result<(), CrucibleError> {
         let mut inner = self.inner.lock().await;
 
-        let start = offset.value as usize * self.block_size as usize;
+        let start = self.start_of(&inner, offset, data.len())?;
 
         for i in 0..data.len() {
             inner.bytes[start + i] = data[i];
@@ -98,7 +115,7 @@ impl BlockIO for InMemoryBlockIO {
     ) -> Result<(), CrucibleError> {
         let mut inner = self.inner.lock().await;
 
-        let start = offset.value as usize * self.block_size as usize;
+        let start = self.start_of(&inner, offset, data.len())?;
 
         for i in 0..data.len() {
             if !inner.owned[start + i] {
@@ -117,6 +134,30 @@ impl BlockIO for InMemoryBlockIO {
         Ok(())
     }
 
+    async fn discard(
+        &self,
+        offset: Block,
+        len: u64,
+    ) -> Result<(), CrucibleError> {
+        if len % self.block_size != 0 {
+            crucible_bail!(DataLenUnaligned);
+        }
+        let mut inner = self.inner.lock().await;
+
+        let start = self.start_of(&inner, offset, len as usize)?;
+
+        for i in 0..len as usize {
+            inner.bytes[start + i] = 0;
+            inner.owned[start + i] = false;
+        }
+
+        Ok(())
+    }
+
+    async fn resize(&self, _extent_count: u32) -> Result<(), CrucibleError> {
+        crucible_bail!(Unsupported, "resize unsupported for InMemoryBlockIO")
+    }
+
     async fn show_work(&self) -> Result<WQCounts, CrucibleError> {
         Ok(WQCounts {
             up_count: 0,
@@ -0,0 +1,648 @@
+// Copyright 2022 Oxide Computer Company
+use super::*;
+
+use crucible_client_types::{KeyProviderConfig, KeyReference};
+use std::time::Duration;
+
+/*
+ * Where the upstairs gets the keys for an encrypted volume.
+ *
+ * CrucibleOpts only ever names a key, with a KeyReference.  The key named
+ * is usually a key-encryption key, and the volume's data key is kept
+ * wrapped by it next to the reference.  Only the provider can unwrap it,
+ * so the reference can go in a request, a log, or a file without giving
+ * the data key away.
+ *
+ * The key server spoken to over HTTP does the same things a KMIP server
+ * would for Get, Encrypt and Decrypt of a key, using JSON:
+ *
+ *   GET  <url>/keys/<key id>         -> { "key": <base64> }
+ *   POST <url>/keys/<key id>/wrap    { "key": <base64> }
+ *                                    -> { "wrapped_key": <base64> }
+ *   POST <url>/keys/<key id>/unwrap  { "wrapped_key": <base64> }
+ *                                    -> { "key": <base64> }
+ *
+ * The key ID is a single path segment, percent encoded as needed.
+ *
+ * The other providers have the key-encryption key on hand, and wrap data
+ * keys with it here.
+ */
+
+/// How long to wait to connect to a key server.
+const KEY_SERVER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a key server to answer a request, all told.
+const KEY_SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[async_trait]
+pub trait KeyProvider: Send + Sync {
+    /// The key with this ID.
+    async fn get_key(&self, key_id: &str) -> Result<Vec<u8>, CrucibleError>;
+
+    /// Wrap a data key with the key with this ID.
+    async fn wrap_key(
+        &self,
+        key_id: &str,
+        data_key: &[u8],
+    ) -> Result<Vec<u8>, CrucibleError> {
+        let key = self.get_key(key_id).await?;
+        wrap_locally(&key, data_key)
+    }
+
+    /// Unwrap a data key that was wrapped with the key with this ID.
+    async fn unwrap_key(
+        &self,
+        key_id: &str,
+        wrapped_key: &[u8],
+    ) -> Result<Vec<u8>, CrucibleError> {
+        let key = self.get_key(key_id).await?;
+        unwrap_locally(&key, wrapped_key)
+    }
+}
+
+pub fn key_provider(
+    config: &KeyProviderConfig,
+) -> Result<Box<dyn KeyProvider>, CrucibleError> {
+    Ok(match config {
+        KeyProviderConfig::Inline { key } => {
+            Box::new(InlineKeyProvider { key: key.clone() })
+        }
+        KeyProviderConfig::File { path } => {
+            Box::new(FileKeyProvider { path: path.clone() })
+        }
+        KeyProviderConfig::Http { url } => {
+            let client = reqwest::ClientBuilder::new()
+                .connect_timeout(KEY_SERVER_CONNECT_TIMEOUT)
+                .timeout(KEY_SERVER_TIMEOUT)
+                .build()
+                .map_err(|e| {
+                    CrucibleError::KeyProviderError(format!("{}: {}", url, e))
+                })?;
+            Box::new(HttpKeyProvider {
+                url: url.clone(),
+                client,
+            })
+        }
+    })
+}
+
+/**
+ * Get the data key a reference is for.
+ */
+pub async fn data_key(key: &KeyReference) -> Result<Vec<u8>, CrucibleError> {
+    let provider = key_provider(&key.provider)?;
+    let data_key = match &key.wrapped_key {
+        Some(wrapped_key) => {
+            let wrapped_key = decode_key(wrapped_key)?;
+            provider.unwrap_key(&key.key_id, &wrapped_key).await?
+        }
+        None => provider.get_key(&key.key_id).await?,
+    };
+
+    if data_key.len() != 32 {
+        crucible_bail!(
+            KeyProviderError,
+            "Key {} is {} bytes, a data key must be 32",
+            key.key_id,
+            data_key.len()
+        );
+    }
+
+    Ok(data_key)
+}
+
+/**
+ * Make a reference to a data key by wrapping it with the key key_id
+ * names.
+ */
+pub async fn wrap_data_key(
+    provider: KeyProviderConfig,
+    key_id: String,
+    data_key: &[u8],
+) -> Result<KeyReference, CrucibleError> {
+    let wrapped_key =
+        key_provider(&provider)?.wrap_key(&key_id, data_key).await?;
+
+    Ok(KeyReference {
+        key_id,
+        provider,
+        wrapped_key: Some(base64::encode(wrapped_key)),
+    })
+}
+
+/**
+ * The encryption context for a volume, if it has a key.
+ */
+pub async fn encryption_context(
+    opt: &CrucibleOpts,
+) -> Result<Option<EncryptionContext>, CrucibleError> {
+    let key = match &opt.key {
+        Some(key) => data_key(key).await?,
+        None => return Ok(None),
+    };
+
+    /*
+     * XXX: It would be good to do BlockOp::QueryBlockSize here, but the
+     * downstairs haven't reported in yet, so this is the default.
+     */
+    let context = EncryptionContext::new(key, 512);
+
+    // Blocks may still be encrypted with the key from before a key
+    // rotation that has not finished.
+    if let Some(old_key) = &opt.old_key {
+        context
+            .add_retired_key(data_key(old_key).await?)
+            .map_err(|e| CrucibleError::KeyProviderError(e.to_string()))?;
+    }
+
+    Ok(Some(context))
+}
+
+fn decode_key(key: &str) -> Result<Vec<u8>, CrucibleError> {
+    base64::decode(key).map_err(|e| {
+        CrucibleError::KeyProviderError(format!("Bad base64 key: {}", e))
+    })
+}
+
+fn volume_key(key: &[u8]) -> Result<VolumeKey, CrucibleError> {
+    VolumeKey::new(key)
+        .map_err(|e| CrucibleError::KeyProviderError(e.to_string()))
+}
+
+/*
+ * A wrapped key is the nonce followed by the encrypted key and tag.
+ */
+const WRAP_AD: &[u8] = b"crucible data key";
+
+fn wrap_locally(key: &[u8], data_key: &[u8]) -> Result<Vec<u8>, CrucibleError> {
+    let key = volume_key(key)?;
+    let nonce = Nonce::from(rand::thread_rng().gen::<[u8; 12]>());
+
+    let mut wrapped = data_key.to_vec();
+    let tag = key
+        .cipher
+        .encrypt_in_place_detached(&nonce, WRAP_AD, &mut wrapped)
+        .map_err(|e| {
+            CrucibleError::KeyProviderError(format!("Wrap failed: {:?}", e))
+        })?;
+
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&wrapped);
+    out.extend_from_slice(&tag);
+    Ok(out)
+}
+
+fn unwrap_locally(
+    key: &[u8],
+    wrapped_key: &[u8],
+) -> Result<Vec<u8>, CrucibleError> {
+    let key = volume_key(key)?;
+    if wrapped_key.len() < 12 + 16 {
+        crucible_bail!(KeyProviderError, "Wrapped key is too short");
+    }
+
+    let (nonce, rest) = wrapped_key.split_at(12);
+    let (data_key, tag) = rest.split_at(rest.len() - 16);
+
+    let mut data_key = data_key.to_vec();
+    key.cipher
+        .decrypt_in_place_detached(
+            Nonce::from_slice(nonce),
+            WRAP_AD,
+            &mut data_key,
+            Tag::from_slice(tag),
+        )
+        .map_err(|_| {
+            CrucibleError::KeyProviderError(
+                "Could not unwrap key, it was wrapped with another key"
+                    .to_string(),
+            )
+        })?;
+
+    Ok(data_key)
+}
+
+/// The one key given in the config.  Nothing says what ID that key has, so
+/// there is no key_id to check against and it is the key for any ID.  The
+/// key_id of a reference to it is only a label, "inline" when made with
+/// KeyReference::inline.
+pub struct InlineKeyProvider {
+    key: String,
+}
+
+#[async_trait]
+impl KeyProvider for InlineKeyProvider {
+    async fn get_key(&self, _key_id: &str) -> Result<Vec<u8>, CrucibleError> {
+        decode_key(&self.key)
+    }
+}
+
+pub struct FileKeyProvider {
+    path: String,
+}
+
+#[async_trait]
+impl KeyProvider for FileKeyProvider {
+    async fn get_key(&self, key_id: &str) -> Result<Vec<u8>, CrucibleError> {
+        let contents =
+            tokio::fs::read_to_string(&self.path).await.map_err(|e| {
+                CrucibleError::KeyProviderError(format!(
+                    "Could not read key file {}: {}",
+                    self.path, e
+                ))
+            })?;
+
+        for line in contents.lines() {
+            let mut fields = line.split_whitespace();
+            if let (Some(id), Some(key)) = (fields.next(), fields.next()) {
+                if id == key_id {
+                    return decode_key(key);
+                }
+            }
+        }
+
+        crucible_bail!(
+            KeyProviderError,
+            "No key {} in key file {}",
+            key_id,
+            self.path
+        );
+    }
+}
+
+pub struct HttpKeyProvider {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyBody {
+    key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WrappedKeyBody {
+    wrapped_key: String,
+}
+
+impl HttpKeyProvider {
+    /*
+     * The URL of keys/<key id>, followed by `action` if there is one.
+     */
+    fn key_url(
+        &self,
+        key_id: &str,
+        action: Option<&str>,
+    ) -> Result<reqwest::Url, CrucibleError> {
+        // Percent encoding leaves these as they are, and they would
+        // change which path is asked for.
+        if key_id.is_empty() || key_id == "." || key_id == ".." {
+            crucible_bail!(KeyProviderError, "Bad key ID {:?}", key_id);
+        }
+
+        let mut url = reqwest::Url::parse(&self.url).map_err(|e| {
+            CrucibleError::KeyProviderError(format!("{}: {}", self.url, e))
+        })?;
+        url.path_segments_mut()
+            .map_err(|_| {
+                CrucibleError::KeyProviderError(format!(
+                    "{}: not a base URL",
+                    self.url
+                ))
+            })?
+            .pop_if_empty()
+            .push("keys")
+            .push(key_id)
+            .extend(action);
+        Ok(url)
+    }
+
+    async fn call<B, R>(
+        &self,
+        url: reqwest::Url,
+        body: Option<B>,
+    ) -> Result<R, CrucibleError>
+    where
+        B: Serialize,
+        R: serde::de::DeserializeOwned,
+    {
+        let err = |e: String| {
+            CrucibleError::KeyProviderError(format!("{}: {}", url, e))
+        };
+
+        let request = match body {
+            Some(body) => self
+                .client
+                .post(url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(
+                    serde_json::to_vec(&body)
+                        .map_err(|e| err(e.to_string()))?,
+                ),
+            None => self.client.get(url.clone()),
+        };
+
+        let response = request.send().await.map_err(|e| err(e.to_string()))?;
+
+        if !response.status().is_success() {
+            crucible_bail!(
+                KeyProviderError,
+                "{}: key server returned {}",
+                url,
+                response.status()
+            );
+        }
+
+        let bytes = response.bytes().await.map_err(|e| err(e.to_string()))?;
+        serde_json::from_slice(&bytes).map_err(|e| err(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl KeyProvider for HttpKeyProvider {
+    async fn get_key(&self, key_id: &str) -> Result<Vec<u8>, CrucibleError> {
+        let body: KeyBody = self
+            .call::<(), _>(self.key_url(key_id, None)?, None)
+            .await?;
+        decode_key(&body.key)
+    }
+
+    async fn wrap_key(
+        &self,
+        key_id: &str,
+        data_key: &[u8],
+    ) -> Result<Vec<u8>, CrucibleError> {
+        let body: WrappedKeyBody = self
+            .call(
+                self.key_url(key_id, Some("wrap"))?,
+                Some(KeyBody {
+                    key: base64::encode(data_key),
+                }),
+            )
+            .await?;
+        decode_key(&body.wrapped_key)
+    }
+
+    async fn unwrap_key(
+        &self,
+        key_id: &str,
+        wrapped_key: &[u8],
+    ) -> Result<Vec<u8>, CrucibleError> {
+        let body: KeyBody = self
+            .call(
+                self.key_url(key_id, Some("unwrap"))?,
+                Some(WrappedKeyBody {
+                    wrapped_key: base64::encode(wrapped_key),
+                }),
+            )
+            .await?;
+        decode_key(&body.key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn inline(key: &[u8]) -> KeyProviderConfig {
+        KeyProviderConfig::Inline {
+            key: base64::encode(key),
+        }
+    }
+
+    #[tokio::test]
+    async fn inline_key() {
+        let key = KeyReference::inline(base64::encode([7u8; 32]));
+        assert_eq!(data_key(&key).await.unwrap(), vec![7u8; 32]);
+
+        // Not a usable data key
+        let key = KeyReference::inline(base64::encode([7u8; 16]));
+        assert!(data_key(&key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn inline_key_is_not_logged() {
+        let key = KeyReference::inline(base64::encode([7u8; 32]));
+        let shown = format!("{:?}", key);
+        assert!(!shown.contains(&base64::encode([7u8; 32])));
+    }
+
+    #[tokio::test]
+    async fn wrapped_key() {
+        let kek = [1u8; 32];
+        let data = [2u8; 32];
+
+        let key = wrap_data_key(inline(&kek), "kek".to_string(), &data)
+            .await
+            .unwrap();
+        assert_ne!(key.wrapped_key, Some(base64::encode(data)));
+        assert_eq!(data_key(&key).await.unwrap(), data.to_vec());
+
+        // Another key-encryption key can't unwrap it
+        let other = KeyReference {
+            provider: inline(&[3u8; 32]),
+            ..key
+        };
+        assert!(data_key(&other).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn file_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        std::fs::write(
+            &path,
+            format!(
+                "one {}\ntwo {}\n",
+                base64::encode([1u8; 32]),
+                base64::encode([2u8; 32])
+            ),
+        )
+        .unwrap();
+
+        let provider = KeyProviderConfig::File {
+            path: path.to_str().unwrap().to_string(),
+        };
+
+        let key =
+            wrap_data_key(provider.clone(), "two".to_string(), &[9u8; 32])
+                .await
+                .unwrap();
+        assert_eq!(data_key(&key).await.unwrap(), vec![9u8; 32]);
+
+        // Wrapped with key two, so key one won't do
+        let wrong = KeyReference {
+            key_id: "one".to_string(),
+            ..key.clone()
+        };
+        assert!(data_key(&wrong).await.is_err());
+
+        let missing = KeyReference {
+            key_id: "three".to_string(),
+            ..key
+        };
+        assert!(data_key(&missing).await.is_err());
+    }
+
+    /*
+     * A stand-in key server that answers one request at a time, with the
+     * key-encryption key "kek".
+     */
+    async fn key_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let kek = [5u8; 32];
+            loop {
+                let (mut sock, _) = listener.accept().await.unwrap();
+
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                let (head, body) = loop {
+                    let n = sock.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let head = text[..end].to_string();
+                        let len = head
+                            .lines()
+                            .find_map(|l| {
+                                let l = l.to_lowercase();
+                                l.strip_prefix("content-length:")
+                                    .map(|v| v.trim().parse().unwrap())
+                            })
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + len {
+                            break (head, text[end + 4..].to_string());
+                        }
+                    }
+                };
+
+                let mut words = head.split_whitespace();
+                let method = words.next().unwrap().to_string();
+                let path = words.next().unwrap().to_string();
+
+                let reply = match (method.as_str(), path.as_str()) {
+                    ("GET", "/keys/kek") => Some(
+                        serde_json::to_string(&KeyBody {
+                            key: base64::encode(kek),
+                        })
+                        .unwrap(),
+                    ),
+                    ("POST", "/keys/kek/wrap") => {
+                        let b: KeyBody = serde_json::from_str(&body).unwrap();
+                        let key = base64::decode(b.key).unwrap();
+                        Some(
+                            serde_json::to_string(&WrappedKeyBody {
+                                wrapped_key: base64::encode(
+                                    wrap_locally(&kek, &key).unwrap(),
+                                ),
+                            })
+                            .unwrap(),
+                        )
+                    }
+                    ("POST", "/keys/kek/unwrap") => {
+                        let b: WrappedKeyBody =
+                            serde_json::from_str(&body).unwrap();
+                        let wrapped = base64::decode(b.wrapped_key).unwrap();
+                        unwrap_locally(&kek, &wrapped).ok().map(|key| {
+                            serde_json::to_string(&KeyBody {
+                                key: base64::encode(key),
+                            })
+                            .unwrap()
+                        })
+                    }
+                    _ => None,
+                };
+
+                let response = match reply {
+                    Some(json) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        json.len(),
+                        json
+                    ),
+                    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+                             Connection: close\r\n\r\n"
+                        .to_string(),
+                };
+                sock.write_all(response.as_bytes()).await.unwrap();
+                sock.shutdown().await.unwrap();
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn http_key() {
+        let url = key_server().await;
+        let provider = KeyProviderConfig::Http { url };
+
+        let key =
+            wrap_data_key(provider.clone(), "kek".to_string(), &[4u8; 32])
+                .await
+                .unwrap();
+        assert_eq!(data_key(&key).await.unwrap(), vec![4u8; 32]);
+
+        // The server wrapped it with its own key-encryption key
+        let local = KeyReference {
+            provider: inline(&[5u8; 32]),
+            ..key.clone()
+        };
+        assert_eq!(data_key(&local).await.unwrap(), vec![4u8; 32]);
+
+        let unknown = KeyReference {
+            key_id: "nope".to_string(),
+            ..key
+        };
+        assert!(data_key(&unknown).await.is_err());
+
+        // The key itself, with no wrapping
+        let direct = KeyReference {
+            key_id: "kek".to_string(),
+            provider,
+            wrapped_key: None,
+        };
+        assert_eq!(data_key(&direct).await.unwrap(), vec![5u8; 32]);
+    }
+
+    #[test]
+    fn http_key_url() {
+        let provider = HttpKeyProvider {
+            url: "http://keys.example/v1/".to_string(),
+            client: reqwest::Client::new(),
+        };
+
+        assert_eq!(
+            provider.key_url("kek", None).unwrap().as_str(),
+            "http://keys.example/v1/keys/kek"
+        );
+        assert_eq!(
+            provider.key_url("a/b?c", Some("wrap")).unwrap().as_str(),
+            "http://keys.example/v1/keys/a%2Fb%3Fc/wrap"
+        );
+        assert!(provider.key_url("..", None).is_err());
+        assert!(provider.key_url("", Some("unwrap")).is_err());
+    }
+
+    #[tokio::test]
+    async fn encryption_context_from_opts() {
+        let old = [1u8; 32];
+        let new = [2u8; 32];
+        let opts = CrucibleOpts {
+            key: Some(KeyReference::inline(base64::encode(new))),
+            old_key: Some(KeyReference::inline(base64::encode(old))),
+            ..Default::default()
+        };
+
+        let context = encryption_context(&opts).await.unwrap().unwrap();
+        assert_eq!(context.key_id(), VolumeKey::new(&new).unwrap().id());
+
+        let opts = CrucibleOpts::default();
+        assert!(encryption_context(&opts).await.unwrap().is_none());
+    }
+}
@@ -12,12 +12,15 @@ use std::convert::TryFrom;
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::io::{Read, Result as IOResult, Seek, SeekFrom, Write};
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 
-pub use crucible_client_types::{CrucibleOpts, VolumeConstructionRequest};
+pub use crucible_client_types::{
+    CrucibleOpts, KeyProviderConfig, KeyReference, QosLimits, ReadPolicy,
+    VolumeConstructionRequest,
+};
 pub use crucible_common::*;
 pub use crucible_protocol::*;
 
@@ -57,12 +60,41 @@ pub use in_memory::InMemoryBlockIO;
 pub mod block_io;
 pub use block_io::{FileBlockIO, ReqwestBlockIO};
 
+pub mod erasure;
+pub use erasure::ErasureCodedBlockIO;
+
+mod block_record;
+use block_record::BlockRecord;
+
+mod heal;
+
+mod rekey;
+
+pub mod key_provider;
+pub use key_provider::KeyProvider;
+
 pub mod block_req;
 pub(crate) use block_req::{BlockReq, BlockReqWaiter};
 
 mod mend;
 pub use mend::{DownstairsMend, ExtentFix, RegionMetadata};
+
+mod dirty;
+use dirty::{DirtyExtents, REPLAY_WINDOW};
+
+mod fault;
+use fault::{FaultCheck, FaultReason};
+
+mod read_balance;
 pub use pseudo_file::CruciblePseudoFile;
+use read_balance::ReadBalance;
+
+mod qos;
+use qos::{check_qos_limits, Qos};
+
+mod scrub;
+pub use scrub::ScrubStatus;
+use scrub::{ScrubProgress, ScrubState};
 
 mod stats;
 pub use stats::*;
@@ -119,10 +151,32 @@ pub trait BlockIO: Sync {
         snapshot_details: Option<SnapshotDetails>,
     ) -> Result<(), CrucibleError>;
 
+    /// Discard (TRIM/UNMAP) `len` bytes starting at `offset`. `len` must be
+    /// a multiple of block size. Discarded blocks are treated as unwritten,
+    /// so a later `write_unwritten` to them will land, and they read back
+    /// as zero. A Volume with a read only parent writes zeros over the
+    /// discarded blocks the parent still covers instead, so those read
+    /// back as zero and not as the parent's data, but are not unwritten.
+    async fn discard(
+        &self,
+        offset: Block,
+        len: u64,
+    ) -> Result<(), CrucibleError>;
+
+    /// Grow or shrink the underlying region(s) to `extent_count` extents
+    /// while active. Once this returns, `total_size` reports the new size.
+    /// A shrink is refused if any of the extents being removed has been
+    /// written to and not discarded.
+    async fn resize(&self, extent_count: u32) -> Result<(), CrucibleError>;
+
     /// Test call that displays the internal job queue on the upstairs, and
     /// returns the guest side and downstairs side job queue depths.
     async fn show_work(&self) -> Result<WQCounts, CrucibleError>;
 
+    /// Tell this block device how a scrub of the volume it is part of is
+    /// going, so it can report it. Most have nowhere to report it.
+    async fn set_scrub_status(&self, _status: ScrubStatus) {}
+
     // Common methods for BlockIO
 
     async fn byte_offset_to_block(
@@ -262,36 +316,52 @@ mod cdt {
     fn volume__write__start(_: u32, _: Uuid) {}
     fn volume__writeunwritten__start(_: u32, _: Uuid) {}
     fn volume__flush__start(_: u32, _: Uuid) {}
+    fn volume__discard__start(_: u32, _: Uuid) {}
+    fn volume__resize__start(_: u32, _: Uuid) {}
     fn gw__read__start(_: u64) {}
     fn gw__write__start(_: u64) {}
     fn gw__write__unwritten__start(_: u64) {}
     fn gw__flush__start(_: u64) {}
+    fn gw__discard__start(_: u64) {}
+    fn gw__resize__start(_: u64) {}
     fn up__to__ds__read__start(_: u64) {}
     fn up__to__ds__write__start(_: u64) {}
     fn up__to__ds__write__unwritten__start(_: u64) {}
     fn up__to__ds__flush__start(_: u64) {}
+    fn up__to__ds__discard__start(_: u64) {}
+    fn up__to__ds__resize__start(_: u64) {}
     fn ds__read__io__start(_: u64, _: u64) {}
     fn ds__write__io__start(_: u64, _: u64) {}
     fn ds__write__unwritten__io__start(_: u64, _: u64) {}
     fn ds__flush__io__start(_: u64, _: u64) {}
+    fn ds__discard__io__start(_: u64, _: u64) {}
+    fn ds__resize__io__start(_: u64, _: u64) {}
     fn ds__read__io__done(_: u64, _: u64) {}
     fn ds__write__io__done(_: u64, _: u64) {}
     fn ds__write__unwritten__io__done(_: u64, _: u64) {}
     fn ds__flush__io__done(_: u64, _: u64) {}
+    fn ds__discard__io__done(_: u64, _: u64) {}
+    fn ds__resize__io__done(_: u64, _: u64) {}
     fn up__to__ds__read__done(_: u64) {}
     fn up__to__ds__write__done(_: u64) {}
     fn up__to__ds__write__unwritten__done(_: u64) {}
     fn up__to__ds__flush__done(_: u64) {}
+    fn up__to__ds__discard__done(_: u64) {}
+    fn up__to__ds__resize__done(_: u64) {}
     fn gw__read__done(_: u64) {}
     fn gw__write__done(_: u64) {}
     fn gw__write__unwritten__done(_: u64) {}
     fn gw__flush__done(_: u64) {}
+    fn gw__discard__done(_: u64) {}
+    fn gw__resize__done(_: u64) {}
     fn reqwest__read__start(_: u32, _: Uuid) {}
     fn reqwest__read__done(_: u32, _: Uuid) {}
     fn volume__read__done(_: u32, _: Uuid) {}
     fn volume__write__done(_: u32, _: Uuid) {}
     fn volume__writeunwritten__done(_: u32, _: Uuid) {}
     fn volume__flush__done(_: u32, _: Uuid) {}
+    fn volume__discard__done(_: u32, _: Uuid) {}
+    fn volume__resize__done(_: u32, _: Uuid) {}
 }
 
 pub fn deadline_secs(secs: u64) -> Instant {
@@ -362,6 +432,34 @@ async fn process_message(
             cdt::ds__read__io__done!(|| (job_id, up_coms.client_id as u64));
             (*upstairs_id, *session_id, *job_id, responses.clone())
         }
+        Message::DiscardAck {
+            upstairs_id,
+            session_id,
+            job_id,
+            result,
+        } => {
+            cdt::ds__discard__io__done!(|| (job_id, up_coms.client_id as u64));
+            (
+                *upstairs_id,
+                *session_id,
+                *job_id,
+                result.clone().map(|_| Vec::new()),
+            )
+        }
+        Message::RegionResizeAck {
+            upstairs_id,
+            session_id,
+            job_id,
+            result,
+        } => {
+            cdt::ds__resize__io__done!(|| (job_id, up_coms.client_id as u64));
+            (
+                *upstairs_id,
+                *session_id,
+                *job_id,
+                result.clone().map(|_| Vec::new()),
+            )
+        }
         /*
          * For this case, we will (TODO) want to log an error to someone, but
          * I don't think there is anything else we can do.
@@ -426,11 +524,12 @@ async fn process_message(
  * ACKs for, then stop sending more work and let the receive side catch up.
  * We return true if we have more work to do, false if we are all caught up.
  */
-#[instrument(skip(fw))]
+#[instrument(skip(fw, ds_done_tx))]
 async fn io_send<WT>(
     u: &Arc<Upstairs>,
     fw: &mut FramedWrite<WT, CrucibleEncoder>,
     client_id: u8,
+    ds_done_tx: &mpsc::Sender<u64>,
 ) -> Result<bool>
 where
     WT: tokio::io::AsyncWrite
@@ -484,28 +583,34 @@ where
 
         /*
          * If in_progress returns None, it means that this client should
-         * be skipped.
+         * be skipped.  A migration copying an extent may also be holding
+         * back this job, and every job after it.
          */
-        let job = u.downstairs.lock().await.in_progress(*new_id, client_id);
+        let job = {
+            let mut ds = u.downstairs.lock().await;
+            if ds.migration_fenced(client_id, *new_id) {
+                break;
+            }
+            ds.in_progress(*new_id, client_id)
+        };
         if job.is_none() {
             continue;
         }
 
         active_count += 1;
-        match job.unwrap() {
+        let message = match job.unwrap() {
             IOop::Write {
                 dependencies,
                 writes,
             } => {
                 cdt::ds__write__io__start!(|| (*new_id, client_id as u64));
-                fw.send(Message::Write {
+                Message::Write {
                     upstairs_id: u.uuid,
                     session_id: u.session_id,
                     job_id: *new_id,
                     dependencies: dependencies.clone(),
                     writes: writes.clone(),
-                })
-                .await?
+                }
             }
             IOop::WriteUnwritten {
                 dependencies,
@@ -515,14 +620,13 @@ where
                     *new_id,
                     client_id as u64
                 ));
-                fw.send(Message::WriteUnwritten {
+                Message::WriteUnwritten {
                     upstairs_id: u.uuid,
                     session_id: u.session_id,
                     job_id: *new_id,
                     dependencies: dependencies.clone(),
                     writes: writes.clone(),
-                })
-                .await?
+                }
             }
             IOop::Flush {
                 dependencies,
@@ -531,7 +635,7 @@ where
                 snapshot_details,
             } => {
                 cdt::ds__flush__io__start!(|| (*new_id, client_id as u64));
-                fw.send(Message::Flush {
+                Message::Flush {
                     upstairs_id: u.uuid,
                     session_id: u.session_id,
                     job_id: *new_id,
@@ -539,24 +643,88 @@ where
                     flush_number,
                     gen_number,
                     snapshot_details,
-                })
-                .await?
+                }
             }
             IOop::Read {
                 dependencies,
                 requests,
             } => {
                 cdt::ds__read__io__start!(|| (*new_id, client_id as u64));
-                fw.send(Message::ReadRequest {
+                Message::ReadRequest {
                     upstairs_id: u.uuid,
                     session_id: u.session_id,
                     job_id: *new_id,
                     dependencies: dependencies.clone(),
                     requests,
-                })
-                .await?
+                }
+            }
+            IOop::Discard {
+                dependencies,
+                requests,
+            } => {
+                cdt::ds__discard__io__start!(|| (*new_id, client_id as u64));
+                Message::Discard {
+                    upstairs_id: u.uuid,
+                    session_id: u.session_id,
+                    job_id: *new_id,
+                    dependencies: dependencies.clone(),
+                    requests,
+                }
+            }
+            IOop::Resize {
+                dependencies,
+                extent_count,
+            } => {
+                cdt::ds__resize__io__start!(|| (*new_id, client_id as u64));
+                Message::RegionResize {
+                    upstairs_id: u.uuid,
+                    session_id: u.session_id,
+                    job_id: *new_id,
+                    dependencies: dependencies.clone(),
+                    extent_count,
+                }
+            }
+        };
+
+        /*
+         * The guest side checks what the downstairs can take before
+         * submitting, but one may have reconnected since with an older
+         * version.  It fails the job instead of getting something it
+         * can't handle.
+         */
+        let protocol = u
+            .downstairs
+            .lock()
+            .await
+            .ds_protocol
+            .get(&client_id)
+            .copied();
+        if let Some(protocol) = protocol {
+            if !protocol.permits(&message) {
+                warn!(
+                    u.log,
+                    "[{}] job {} can't be sent over {:?}",
+                    client_id,
+                    new_id,
+                    protocol,
+                );
+                let e = CrucibleError::Unsupported(format!(
+                    "downstairs speaks {:?}",
+                    protocol
+                ));
+                if u.process_ds_operation(*new_id, client_id, Err(e)).await? {
+                    ds_done_tx.send(*new_id).await?;
+                }
+                continue;
             }
         }
+
+        fw.send(message).await?;
+
+        let saved = fw.encoder_mut().take_bytes_saved();
+        if saved > 0 {
+            u.stats.add_compression_saved(saved as i64).await;
+        }
     }
     Ok(false)
 }
@@ -615,6 +783,7 @@ where
     // works if this Downstairs is new, reconnecting, or was replaced entirely -
     // the repair address could have changed in any of these cases.
     up.ds_clear_repair_address(up_coms.client_id).await;
+    up.ds_clear_protocol(up_coms.client_id).await;
 
     // If this Downstairs is returning from being disconnected, we need to call
     // re_new.
@@ -635,6 +804,8 @@ where
             && my_state != DsState::Disconnected
             && my_state != DsState::Failed
             && my_state != DsState::Offline
+            && my_state != DsState::Migrating
+            && my_state != DsState::Faulted
         {
             panic!(
                 "[{}] failed proc with state {:?}",
@@ -647,9 +818,13 @@ where
          * being disconnected. Mark any in progress jobs since the
          * last good flush back to New, as we have reconnected to
          * this downstairs and will need to replay any work that we
-         * were holding that we did not flush.
+         * were holding that we did not flush.  If we let go of that work
+         * while it was away, it is caught up by copying its dirty extents
+         * instead.
          */
-        if my_state == DsState::Offline {
+        if my_state == DsState::Offline
+            && !ds.dirty.is_tracking(up_coms.client_id)
+        {
             ds.re_new(up_coms.client_id);
         }
     }
@@ -659,8 +834,19 @@ where
     /*
      * As the "client", we must begin the negotiation.
      */
+    /*
+     * Blocks we encrypt are bound to their key ID, which a peer older than
+     * VERSION_KEY_ID would drop on the floor.
+     */
+    let min_version = if up.encrypted() {
+        VERSION_KEY_ID
+    } else {
+        PROTOCOL_VERSION_MIN
+    };
     let m = Message::HereIAm {
-        version: 1,
+        min_version,
+        max_version: PROTOCOL_VERSION_MAX,
+        features: SUPPORTED_FEATURES,
         upstairs_id: up.uuid,
         session_id: up.session_id,
         gen: up.get_generation().await,
@@ -818,26 +1004,59 @@ where
                             up.encrypted(),
                         );
                     }
-                    Some(Message::YesItsMe { version, repair_addr }) => {
+                    Some(Message::VersionMismatch {
+                        min_version,
+                        max_version,
+                    }) => {
+                        up.ds_transition(
+                            up_coms.client_id,
+                            DsState::BadVersion
+                        ).await;
+                        bail!(
+                            "downstairs speaks versions {} to {}, we speak \
+                            {} to {}",
+                            min_version,
+                            max_version,
+                            PROTOCOL_VERSION_MIN,
+                            PROTOCOL_VERSION_MAX,
+                        );
+                    }
+                    Some(Message::YesItsMe {
+                        version,
+                        features,
+                        repair_addr,
+                    }) => {
                         if negotiated != 0 {
                             bail!("Got version already!");
                         }
 
                         /*
-                         * XXX Valid version to compare with should come
-                         * from main task. In the future we will also have
-                         * to handle a version mismatch.
+                         * The downstairs picks from what we offered, so
+                         * anything else means it isn't playing by the rules.
                          */
-                        if version != 1 {
+                        let protocol = Negotiated { version, features };
+                        if !protocol.is_valid() || version < min_version {
                             up.ds_transition(
                                 up_coms.client_id,
                                 DsState::BadVersion
                             ).await;
-                            bail!("expected version 1, got {}", version);
+                            bail!("downstairs picked {:?}, not one we offered",
+                                protocol);
                         }
+                        info!(
+                            up.log,
+                            "[{}] negotiated {:?}",
+                            up_coms.client_id,
+                            protocol,
+                        );
 
                         negotiated = 1;
 
+                        up.ds_set_protocol(up_coms.client_id, protocol).await;
+                        fw.encoder_mut().set_compression(protocol.compression());
+                        fw.encoder_mut().set_version(protocol.version);
+                        fr.decoder_mut().set_version(protocol.version);
+
                         up.ds_set_repair_address(
                             up_coms.client_id, repair_addr,
                         ).await;
@@ -1073,7 +1292,30 @@ where
                             up_coms.client_id, target, region_def.uuid(),
                         );
 
+                        /*
+                         * A downstairs we are migrating to must have the
+                         * same shape region as the one it replaces.
+                         */
+                        if up.ds_state(up_coms.client_id).await
+                            == DsState::Migrating
+                        {
+                            let ddef = up.ddef.lock().await;
+                            if ddef.block_size() != region_def.block_size()
+                                || ddef.extent_size().value
+                                    != region_def.extent_size().value
+                                || ddef.extent_count()
+                                    != region_def.extent_count()
+                            {
+                                bail!(
+                                    "[{}] migration target region {:?} does \
+                                    not match {:?}",
+                                    up_coms.client_id, region_def, *ddef
+                                );
+                            }
+                        }
+
                         up.add_ds_region(up_coms.client_id, region_def).await?;
+                        up.catch_up_start(up_coms.client_id).await?;
 
                         let my_state = {
                             let state = &up.downstairs.lock().await.ds_state;
@@ -1106,6 +1348,31 @@ where
                             negotiated = 4;
                             fw.send(Message::ExtentVersionsPlease).await?;
 
+                        } else if my_state == DsState::Migrating {
+                            /*
+                             * This is a new downstairs replacing one that
+                             * was active, or one being caught up after a
+                             * long time offline.  It gets every job from
+                             * here on, so tell it that anything before
+                             * that is already flushed, and it won't wait
+                             * on dependencies it will never see.  Its
+                             * extents are copied over once it is taking
+                             * IO.
+                             */
+                            let first_job = up
+                                .downstairs
+                                .lock()
+                                .await
+                                .migration_connected(up_coms.client_id)?;
+                            info!(
+                                up.log,
+                                "[{}] migration target starts at job {}",
+                                up_coms.client_id, first_job);
+                            negotiated = 3;
+                            fw.send(Message::LastFlush {
+                                last_flush_number: first_job - 1
+                            }).await?;
+
                         } else {
                             /*
                              * TODO: This is the case where a downstairs
@@ -1134,21 +1401,28 @@ where
                             let state = &up.downstairs.lock().await.ds_state;
                             state[up_coms.client_id as usize]
                         };
-                        assert_eq!(my_state, DsState::Offline);
                         info!(
                             up.log,
                             "[{}] replied this last flush ID: {}",
                             up_coms.client_id,
                             last_flush_number,
                         );
-                        // Assert now, but this should eventually be an
-                        // error and move the downstairs to failed. XXX
-                        assert_eq!(
-                            up.last_flush_id(up_coms.client_id).await, last_flush_number
-                        );
-                        up.ds_transition(
-                            up_coms.client_id, DsState::Replay
-                        ).await;
+                        if my_state == DsState::Migrating {
+                            up.downstairs.lock().await.migration_ready(
+                                up_coms.client_id, last_flush_number
+                            )?;
+                        } else {
+                            assert_eq!(my_state, DsState::Offline);
+                            // Assert now, but this should eventually be an
+                            // error and move the downstairs to failed. XXX
+                            assert_eq!(
+                                up.last_flush_id(up_coms.client_id).await,
+                                last_flush_number
+                            );
+                            up.ds_transition(
+                                up_coms.client_id, DsState::Replay
+                            ).await;
+                        }
 
                         *connected = true;
                         negotiated = 5;
@@ -1309,10 +1583,24 @@ where
      * do any repairs that might be necessary.
      */
     let mut more_work = up.ds_is_replay(up_coms.client_id).await;
-    if !more_work {
+
+    /*
+     * A new downstairs we are migrating to skips reconciliation.  It has
+     * its extents copied over while it is taking IO.
+     */
+    let migrating = up.ds_state(up_coms.client_id).await == DsState::Migrating;
+    if migrating {
+        more_work = true;
+    } else if !more_work {
         do_reconcile_work(up, &mut fr, &mut fw, up_coms).await?;
     }
 
+    /*
+     * If a migration moves this client to a new downstairs, we have to
+     * hang up on the one we are talking to.
+     */
+    let target = up.ds_target(up_coms.client_id).await;
+
     /*
      * To keep things alive, initiate a ping any time we have been idle for
      * 10 seconds.
@@ -1360,6 +1648,24 @@ where
                 if up_c.ds_deactivate(up_coms_c.client_id).await {
                     bail!("[{}] exits after deactivation", up_coms_c.client_id);
                 }
+
+                /*
+                 * A migration may be waiting for this downstairs to
+                 * finish the work in front of its fence.
+                 */
+                let drained = up_c
+                    .downstairs
+                    .lock()
+                    .await
+                    .migration_drained(up_coms_c.client_id);
+                if let Some(fence) = drained {
+                    up_c.ds_repair_done_notify(
+                        up_coms_c.client_id,
+                        fence,
+                        &up_coms_c.ds_reconcile_done_tx,
+                    )
+                    .await?;
+                }
             }
             Ok(())
         })
@@ -1388,6 +1694,11 @@ where
                 timeout_deadline = deadline_secs(50);
                 ping_interval = deadline_secs(10);
 
+                let saved = fr.decoder_mut().take_bytes_saved();
+                if saved > 0 {
+                    up.stats.add_compression_saved(saved as i64).await;
+                }
+
                 match f.transpose()? {
                     None => {
                         // Downstairs disconnected
@@ -1485,12 +1796,72 @@ where
                             up_coms.client_id, expected_id
                         );
                     }
+                    Some(Message::RepairAckId { repair_id }) => {
+                        /*
+                         * A step of copying an extent for a downstairs
+                         * migration has finished.
+                         */
+                        if up.downstairs.lock().await.migration_rep_done(
+                            up_coms.client_id, repair_id
+                        ) {
+                            up.ds_repair_done_notify(
+                                up_coms.client_id,
+                                repair_id,
+                                &up_coms.ds_reconcile_done_tx,
+                            ).await?;
+                        }
+                    }
+                    Some(Message::ExtentError {
+                        repair_id,
+                        extent_id,
+                        error,
+                    }) => {
+                        error!(
+                            up.log,
+                            "[{}] Extent {} error on job {}: {}",
+                            up_coms.client_id,
+                            extent_id,
+                            repair_id,
+                            error,
+                        );
+                        bail!(
+                            "[{}] Extent {} error on job {}: {}",
+                            up_coms.client_id,
+                            extent_id,
+                            repair_id,
+                            error,
+                        );
+                    }
                     Some(m) => {
                         tx.send(m).await?;
                     }
                 }
             }
+            _ = up_coms.ds_reconcile_work_rx.changed() => {
+                /*
+                 * Once we are taking IO, the only reconcile work is
+                 * copying an extent for a downstairs migration.
+                 */
+                let job = up
+                    .downstairs
+                    .lock()
+                    .await
+                    .rep_in_progress(up_coms.client_id);
+                if let Some(op) = job {
+                    fw.send(op).await?;
+                }
+            }
             _ = up_coms.ds_work_rx.changed() => {
+                if up.ds_target(up_coms.client_id).await != target {
+                    bail!(
+                        "[{}] {} replaced by a migration",
+                        up_coms.client_id, target
+                    );
+                }
+                if up.ds_state(up_coms.client_id).await == DsState::Faulted {
+                    bail!("[{}] {} faulted", up_coms.client_id, target);
+                }
+
                 /*
                  * A change here indicates the work hashmap has changed
                  * and we should go look for new work to do. It is possible
@@ -1498,7 +1869,7 @@ where
                  * check.
                  */
                 let more =
-                    io_send(up, &mut fw, up_coms.client_id).await?;
+                    io_send(up, &mut fw, up_coms.client_id, &up_coms.ds_done_tx).await?;
 
                 if more && !more_work {
                     warn!(up.log, "[{}] flow control start ",
@@ -1514,7 +1885,7 @@ where
                     up_coms.client_id
                 );
 
-                let more = io_send(up, &mut fw, up_coms.client_id).await?;
+                let more = io_send(up, &mut fw, up_coms.client_id, &up_coms.ds_done_tx).await?;
 
                 if more {
                     more_work = true;
@@ -1548,7 +1919,7 @@ where
                      * every now and then, signal the downstairs task to
                      * check and see if we skipped some work earlier.
                      */
-                    io_send(up, &mut fw, up_coms.client_id).await?;
+                    io_send(up, &mut fw, up_coms.client_id, &up_coms.ds_done_tx).await?;
                 }
 
                 /*
@@ -1790,6 +2161,13 @@ where
                                 source_client_id: _,
                                 source_repair_address: _,
                                 ref dest_clients,
+                            }
+                            | Message::ExtentRepairBlocks {
+                                repair_id,
+                                extent_id: _,
+                                source_client_id: _,
+                                source_repair_address: _,
+                                ref dest_clients,
                             } => {
                                 let mut send_repair = false;
                                 for d in dest_clients {
@@ -1989,7 +2367,6 @@ enum WrappedStream {
  * instance.  This task will run forever.
  */
 async fn looper(
-    target: SocketAddr,
     tls_context: Arc<
         tokio::sync::Mutex<Option<crucible_common::x509::TLSContext>>,
     >,
@@ -2007,6 +2384,12 @@ async fn looper(
             tokio::time::sleep(Duration::from_secs(1)).await;
         }
 
+        /*
+         * A migration may have moved this client to a new downstairs, so
+         * look up where to connect each time around.
+         */
+        let target = up.ds_target(up_coms.client_id).await;
+
         /*
          * Make connection to this downstairs.
          */
@@ -2156,11 +2539,23 @@ impl WorkCounts {
 }
 
 /*
- * The structure that tracks information about the three downstairs
- * connections as well as the work that each is doing.
+ * The structure that tracks information about the downstairs connections
+ * as well as the work that each is doing.
  */
 #[derive(Debug)]
 struct Downstairs {
+    /*
+     * The number of downstairs that hold a copy of the region, one for
+     * each target.  Client IDs run from 0 to this number.
+     */
+    replicas: u8,
+
+    /*
+     * The number of downstairs that must complete a write or flush
+     * before we ack it back to the guest.
+     */
+    write_quorum: u8,
+
     /*
      * UUID for each downstairs, index by client ID
      */
@@ -2172,6 +2567,19 @@ struct Downstairs {
      */
     ds_repair: HashMap<u8, SocketAddr>,
 
+    /*
+     * The protocol version and features each downstairs agreed to when
+     * it connected, hashed by client ID.
+     */
+    ds_protocol: HashMap<u8, Negotiated>,
+
+    /*
+     * The IP:Port of each downstairs region, hashed by client ID.  The
+     * task for a client reads this each time it (re)connects, so a
+     * downstairs migration can point a client at a new downstairs.
+     */
+    ds_target: HashMap<u8, SocketAddr>,
+
     /*
      * The state of a downstairs connection, based on client ID
      * Ready here indicates it can receive IO.
@@ -2219,6 +2627,54 @@ struct Downstairs {
     reconcile_repaired: usize,
     reconcile_repair_needed: usize,
 
+    /**
+     * A downstairs that is being replaced by a new downstairs while
+     * the upstairs is active, or caught up after being offline, if any.
+     */
+    migration: Option<Migration>,
+
+    /**
+     * The extents changed since a downstairs went offline, for those
+     * offline longer than the replay window.
+     */
+    dirty: DirtyExtents,
+
+    /**
+     * How many jobs we hold for replay to an offline downstairs before we
+     * start tracking its dirty extents instead.
+     */
+    replay_window: usize,
+
+    /**
+     * What the upstairs last wrote to each block since it activated,
+     * which every read is checked against.
+     */
+    block_record: BlockRecord,
+
+    /**
+     * Count of corrupt blocks a downstairs returned that were repaired
+     * with a copy from another downstairs since the start of this
+     * upstairs.
+     */
+    blocks_healed: usize,
+
+    /**
+     * Which downstairs each read goes to.
+     */
+    read_balance: ReadBalance,
+
+    /**
+     * Count of reads that failed on the one downstairs they were sent to
+     * and were sent to another since the start of this upstairs.
+     */
+    reads_redirected: usize,
+
+    /**
+     * How slow a downstairs may get before it is faulted, and why each
+     * one last was.
+     */
+    fault_check: FaultCheck,
+
     /**
      * The logger for messages sent from downstairs methods.
      */
@@ -2226,12 +2682,16 @@ struct Downstairs {
 }
 
 impl Downstairs {
-    fn new(log: Logger) -> Self {
+    fn new(log: Logger, replicas: u8, write_quorum: u8) -> Self {
         Self {
+            replicas,
+            write_quorum,
             ds_uuid: HashMap::new(),
             ds_repair: HashMap::new(),
-            ds_state: vec![DsState::New; 3],
-            ds_last_flush: vec![0; 3],
+            ds_protocol: HashMap::new(),
+            ds_target: HashMap::new(),
+            ds_state: vec![DsState::New; replicas as usize],
+            ds_last_flush: vec![0; replicas as usize],
             downstairs_errors: HashMap::new(),
             ds_active: HashMap::new(),
             completed: AllocRingBuffer::with_capacity(2048),
@@ -2241,6 +2701,14 @@ impl Downstairs {
             reconcile_task_list: VecDeque::new(),
             reconcile_repaired: 0,
             reconcile_repair_needed: 0,
+            migration: None,
+            dirty: DirtyExtents::new(None, &log),
+            replay_window: REPLAY_WINDOW,
+            block_record: BlockRecord::default(),
+            blocks_healed: 0,
+            read_balance: ReadBalance::new(ReadPolicy::All, replicas),
+            reads_redirected: 0,
+            fault_check: FaultCheck::new(None, None, replicas),
             log: log.new(o!("" => "downstairs".to_string())),
         }
     }
@@ -2257,26 +2725,49 @@ impl Downstairs {
     /**
      * Mark this request as in progress for this client, and return a copy
      * of the details of the request. If the downstairs client has
-     * experienced errors in the past, return None and mark this as
+     * experienced errors in the past, or is a migration target that
+     * should not see this request, return None and mark this as
      * Skipped.
      *
      * XXX Better error handling might mean clearing previous downstairs
      * errors, as for all we know it's a new downstairs.
      */
     fn in_progress(&mut self, ds_id: u64, client_id: u8) -> Option<IOop> {
-        let job = self.ds_active.get_mut(&ds_id).unwrap();
-
         let newstate = match &self.downstairs_errors.get(&client_id) {
             Some(_) => IOState::Skipped,
+            None if self.migration_skips(client_id, ds_id) => IOState::Skipped,
             None => IOState::InProgress,
         };
 
+        let job = self.ds_active.get_mut(&ds_id).unwrap();
+
         let oldstate = job.state.insert(client_id, newstate.clone());
         assert_eq!(oldstate, Some(IOState::New));
 
         match newstate {
             IOState::Skipped => None,
-            IOState::InProgress => Some(job.work.clone()),
+            IOState::InProgress => {
+                if job.work.is_read() {
+                    self.read_balance.sent(ds_id, client_id);
+                }
+                self.fault_check.sent(ds_id, client_id);
+
+                /*
+                 * This client never gets a job that was skipped for it,
+                 * such as a repair write for another downstairs, so it
+                 * must not wait on one.
+                 */
+                let mut work = job.work.clone();
+                let ds_active = &self.ds_active;
+                work.deps_mut().retain(|dep| {
+                    !matches!(
+                        ds_active.get(dep),
+                        Some(job) if job.state.get(&client_id)
+                            == Some(&IOState::Skipped)
+                    )
+                });
+                Some(work)
+            }
             _ => panic!("bad state in in_progress!"),
         }
     }
@@ -2346,10 +2837,19 @@ impl Downstairs {
      *    should just continue waiting for work to show up.
      */
     fn rep_in_progress(&mut self, client_id: u8) -> Option<Message> {
-        if self.ds_state[client_id as usize] != DsState::Repair {
+        if self.ds_state[client_id as usize] != DsState::Repair
+            && self.migration.is_none()
+        {
             return None;
         }
         if let Some(job) = &mut self.reconcile_current_work {
+            /*
+             * Work for a migration only involves some of the downstairs,
+             * the others are skipped and have nothing to send.
+             */
+            if job.state.get(&client_id) == Some(&IOState::Skipped) {
+                return None;
+            }
             let oldstate = job.state.insert(client_id, IOState::InProgress);
 
             /*
@@ -2393,7 +2893,7 @@ impl Downstairs {
                     done += 1;
                 }
             }
-            done == 3
+            done == self.replicas
         } else {
             panic!(
                 "[{}] Attempted to complete job {} that does not exist",
@@ -2443,6 +2943,7 @@ impl Downstairs {
                     flush_number: max_flush,
                     gen_number: max_gen,
                 },
+                self.replicas,
             ));
             rep_id += 1;
 
@@ -2452,19 +2953,23 @@ impl Downstairs {
                     repair_id: rep_id,
                     extent_id: ext,
                 },
+                self.replicas,
             ));
             rep_id += 1;
 
             let repair = self.repair_addr(ef.source);
+            let message = Downstairs::repair_message(
+                &self.ds_protocol,
+                rep_id,
+                ext,
+                ef.source,
+                repair,
+                ef.dest,
+            );
             self.reconcile_task_list.push_back(ReconcileIO::new(
                 rep_id,
-                Message::ExtentRepair {
-                    repair_id: rep_id,
-                    extent_id: ext,
-                    source_client_id: ef.source,
-                    source_repair_address: repair,
-                    dest_clients: ef.dest,
-                },
+                message,
+                self.replicas,
             ));
             rep_id += 1;
 
@@ -2474,6 +2979,7 @@ impl Downstairs {
                     repair_id: rep_id,
                     extent_id: ext,
                 },
+                self.replicas,
             ));
             rep_id += 1;
         }
@@ -2481,6 +2987,46 @@ impl Downstairs {
         info!(self.log, "Task list: {:?}", self.reconcile_task_list);
     }
 
+    /*
+     * The message that copies an extent from the source to the dest
+     * clients.  If the source and every downstairs being repaired can do
+     * it, only the blocks that differ are copied.
+     */
+    fn repair_message(
+        ds_protocol: &HashMap<u8, Negotiated>,
+        repair_id: u64,
+        extent_id: usize,
+        source_client_id: u8,
+        source_repair_address: SocketAddr,
+        dest_clients: Vec<u8>,
+    ) -> Message {
+        let delta = std::iter::once(&source_client_id)
+            .chain(&dest_clients)
+            .all(|c| {
+                matches!(
+                    ds_protocol.get(c),
+                    Some(p) if p.has_feature(FEATURE_DELTA_REPAIR)
+                )
+            });
+        if delta {
+            Message::ExtentRepairBlocks {
+                repair_id,
+                extent_id,
+                source_client_id,
+                source_repair_address,
+                dest_clients,
+            }
+        } else {
+            Message::ExtentRepair {
+                repair_id,
+                extent_id,
+                source_client_id,
+                source_repair_address,
+                dest_clients,
+            }
+        }
+    }
+
     /**
      * We have received a deactivate command from the guest, but we have
      * a downstairs that is offline.  Since we don't know when it might
@@ -2515,6 +3061,588 @@ impl Downstairs {
         }
     }
 
+    /**
+     * Mark every job this client has not finished as skipped.  This is
+     * used when a downstairs is replaced by a migration, as the new
+     * downstairs will only receive work created after it connects.  If
+     * we track dirty extents for this client, the extents the skipped
+     * jobs change are dirty.
+     */
+    fn skip_all_jobs(&mut self, client_id: u8) {
+        let mut skipped = 0;
+        let mut marked = false;
+        for job in self.ds_active.values_mut() {
+            let state = job.state.get(&client_id).unwrap();
+
+            if *state == IOState::InProgress || *state == IOState::New {
+                job.state.insert(client_id, IOState::Skipped);
+                skipped += 1;
+                for eid in dirty::changed_extents(&job.work) {
+                    marked |= self.dirty.mark(client_id, eid);
+                }
+            }
+        }
+        if marked {
+            self.dirty.save();
+        }
+        info!(self.log, "[{}] client skip {} jobs", client_id, skipped);
+    }
+
+    /*
+     * A resize that has not been acked yet may take extents away before
+     * IO queued behind it runs.  Refuse IO that reaches past the smallest
+     * extent count any such resize takes the region to, so it does not
+     * fail on (and fault) the downstairs that removed them.
+     */
+    fn resize_fence(
+        &self,
+        ddef: &RegionDefinition,
+        offset: Block,
+        len: usize,
+    ) -> Result<(), CrucibleError> {
+        let limit = match self
+            .ds_active
+            .values()
+            .filter(|job| job.ack_status == AckStatus::NotAcked)
+            .filter_map(|job| match job.work {
+                IOop::Resize { extent_count, .. } => Some(extent_count),
+                _ => None,
+            })
+            .min()
+        {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let end = offset.value + Block::from_bytes(len, ddef).value;
+        if end > limit as u64 * ddef.extent_size().value {
+            crucible_bail!(
+                RegionResizeError,
+                "IO ending at block {} is past the {} extents the region \
+                is being resized to",
+                end,
+                limit
+            );
+        }
+        Ok(())
+    }
+
+    /*
+     * Once a resize has been decided one way, a downstairs that went the
+     * other way has a region of a different size.  Return every such
+     * downstairs that is still Active, so they can be faulted.
+     */
+    fn resize_out_of_step(&mut self, ds_id: u64) -> Vec<u8> {
+        match self.ds_active.get(&ds_id) {
+            Some(job)
+                if matches!(job.work, IOop::Resize { .. })
+                    && job.ack_status != AckStatus::NotAcked => {}
+            _ => return Vec::new(),
+        }
+        let resized = self.result(ds_id).is_ok();
+
+        let job = self.ds_active.get(&ds_id).unwrap();
+        (0..self.replicas)
+            .filter(|cl| self.ds_state[*cl as usize] == DsState::Active)
+            .filter(|cl| match job.state.get(cl) {
+                Some(IOState::Done) => !resized,
+                Some(IOState::Error(_)) => resized,
+                _ => false,
+            })
+            .collect()
+    }
+
+    /**
+     * Start replacing the downstairs at the old target with the one at
+     * the new target.  On success, return the client ID of the downstairs
+     * being replaced.  The caller is responsible for moving that client
+     * to DsState::Migrating.
+     */
+    fn migration_start(
+        &mut self,
+        old: SocketAddr,
+        new: SocketAddr,
+        extent_count: u32,
+    ) -> Result<u8, CrucibleError> {
+        if let Some(m) = &self.migration {
+            crucible_bail!(
+                MigrationError,
+                "migration of {} to {} is already in progress",
+                m.old_target,
+                m.new_target
+            );
+        }
+
+        let client_id = match self.ds_target.iter().find(|(_, t)| **t == old) {
+            Some((client_id, _)) => *client_id,
+            None => {
+                crucible_bail!(MigrationError, "{} is not a target", old);
+            }
+        };
+        if self.ds_target.values().any(|t| *t == new) {
+            crucible_bail!(MigrationError, "{} is already a target", new);
+        }
+        /*
+         * A faulted downstairs can be replaced as well, it may be one
+         * that missed a resize and can't be caught up.
+         */
+        if self.ds_state.iter().enumerate().any(|(cl, s)| {
+            *s != DsState::Active
+                && (cl != client_id as usize || *s != DsState::Faulted)
+        }) {
+            crucible_bail!(
+                MigrationError,
+                "all downstairs must be active, not {:?}",
+                self.ds_state
+            );
+        }
+        if self
+            .ds_active
+            .values()
+            .any(|job| matches!(job.work, IOop::Resize { .. }))
+        {
+            crucible_bail!(MigrationError, "a region resize is in progress");
+        }
+
+        let source = match (0..self.replicas).find(|c| *c != client_id) {
+            Some(source) => source,
+            None => {
+                crucible_bail!(
+                    MigrationError,
+                    "no other downstairs to copy from"
+                );
+            }
+        };
+        info!(
+            self.log,
+            "[{}] migrate from {} to {}, copy from [{}] {} extents",
+            client_id,
+            old,
+            new,
+            source,
+            extent_count,
+        );
+
+        /*
+         * The new downstairs has its own region UUID, so forget the one
+         * we have for the old downstairs.
+         */
+        self.ds_target.insert(client_id, new);
+        self.ds_uuid.remove(&client_id);
+        self.skip_all_jobs(client_id);
+        self.migration = Some(Migration::new(
+            client_id,
+            source,
+            old,
+            new,
+            (0..extent_count).collect(),
+        ));
+
+        Ok(client_id)
+    }
+
+    /**
+     * The new downstairs for a migration has connected and is about to
+     * start taking IO.  Everything queued up to now is skipped for it, it
+     * will get every job from the returned job ID on.  A downstairs being
+     * caught up starts over with the extents that are dirty now.
+     */
+    fn migration_connected(&mut self, client_id: u8) -> Result<u64> {
+        let first_job = self.next_id;
+        match &self.migration {
+            Some(m) if m.client_id == client_id => {}
+            _ => bail!("[{}] has no migration in progress", client_id),
+        }
+        self.skip_all_jobs(client_id);
+
+        let dirty = self.dirty.extents(client_id);
+        let m = self.migration.as_mut().unwrap();
+        m.first_job = Some(first_job);
+        m.next_extent = 0;
+        if m.catch_up() {
+            m.extents = dirty;
+        }
+
+        info!(
+            self.log,
+            "[{}] migration target connected, first job {}",
+            client_id,
+            first_job
+        );
+        Ok(first_job)
+    }
+
+    /**
+     * The new downstairs has acknowledged where its work starts, so
+     * we can start copying extents to it.
+     */
+    fn migration_ready(
+        &mut self,
+        client_id: u8,
+        last_flush_number: u64,
+    ) -> Result<()> {
+        match &mut self.migration {
+            Some(m) if m.client_id == client_id => {
+                if m.first_job != Some(last_flush_number + 1) {
+                    bail!(
+                        "[{}] migration target replied {}, expected {:?}",
+                        client_id,
+                        last_flush_number,
+                        m.first_job,
+                    );
+                }
+                if m.step == MigrateStep::Connect {
+                    m.step = MigrateStep::Fence;
+                }
+                Ok(())
+            }
+            _ => bail!("[{}] has no migration in progress", client_id),
+        }
+    }
+
+    /**
+     * Return true if this job should be skipped for this client because
+     * the client is a migration target that has not yet seen the job.
+     */
+    fn migration_skips(&self, client_id: u8, ds_id: u64) -> bool {
+        match &self.migration {
+            Some(m) if m.client_id == client_id => match m.first_job {
+                Some(first_job) => ds_id < first_job,
+                None => true,
+            },
+            _ => false,
+        }
+    }
+
+    /**
+     * Return true if a migration copying an extent is holding back this
+     * job for this client.
+     */
+    fn migration_fenced(&self, client_id: u8, ds_id: u64) -> bool {
+        match &self.migration {
+            Some(m) if m.involves(client_id) => match m.fence {
+                Some(fence) => ds_id >= fence,
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /*
+     * Return true if the source and the new downstairs have no job before
+     * the fence left to do.
+     */
+    fn fence_drained(
+        ds_active: &HashMap<u64, DownstairsIO>,
+        m: &Migration,
+    ) -> bool {
+        let fence = match m.fence {
+            Some(fence) => fence,
+            None => return false,
+        };
+        ds_active
+            .values()
+            .filter(|job| job.ds_id < fence)
+            .all(|job| {
+                [m.source, m.client_id].iter().all(|c| {
+                    !matches!(
+                        job.state.get(c),
+                        Some(IOState::New) | Some(IOState::InProgress)
+                    )
+                })
+            })
+    }
+
+    /**
+     * Return the fence if this client was one the migration is waiting on
+     * to finish the work before the fence, and it now has.
+     */
+    fn migration_drained(&self, client_id: u8) -> Option<u64> {
+        match &self.migration {
+            Some(m)
+                if m.step == MigrateStep::Drain
+                    && m.involves(client_id)
+                    && Downstairs::fence_drained(&self.ds_active, m) =>
+            {
+                m.fence
+            }
+            _ => None,
+        }
+    }
+
+    /**
+     * Mark a migration step as done for this client, and return true if
+     * all clients are done with it.  A stale reply to a step we have
+     * already given up on is ignored.
+     */
+    fn migration_rep_done(&mut self, client_id: u8, rep_id: u64) -> bool {
+        match &self.reconcile_current_work {
+            Some(job)
+                if job.id == rep_id
+                    && job.state.get(&client_id)
+                        == Some(&IOState::InProgress) =>
+            {
+                self.rep_done(client_id, rep_id)
+            }
+            _ => {
+                warn!(
+                    self.log,
+                    "[{}] ignore migration reply for {}", client_id, rep_id
+                );
+                false
+            }
+        }
+    }
+
+    /**
+     * A downstairs taking part in a migration has gone away.  If it was
+     * the new downstairs, the copy starts over once it comes back.  An
+     * extent being copied when either the source or the new downstairs
+     * went away will be copied again.
+     */
+    fn migration_missing(&mut self, client_id: u8) {
+        let m = match &mut self.migration {
+            Some(m) if m.involves(client_id) => m,
+            _ => return,
+        };
+
+        if client_id == m.client_id {
+            m.first_job = None;
+        }
+        let catch_up = client_id == m.client_id && m.catch_up();
+
+        match m.step {
+            MigrateStep::Connect | MigrateStep::Fence | MigrateStep::Done => {}
+            MigrateStep::Drain => {
+                m.fence = None;
+                m.step = MigrateStep::Fence;
+            }
+            MigrateStep::Close | MigrateStep::Repair | MigrateStep::Reopen => {
+                /*
+                 * A downstairs reopens all its extents when it goes
+                 * active again, so don't wait on it.
+                 */
+                m.interrupted = true;
+                m.closed.retain(|c| *c != client_id);
+                if let Some(job) = &mut self.reconcile_current_work {
+                    if let Some(state) = job.state.get_mut(&client_id) {
+                        if *state == IOState::New
+                            || *state == IOState::InProgress
+                        {
+                            *state = IOState::Skipped;
+                        }
+                    }
+                }
+            }
+        }
+        info!(
+            self.log,
+            "[{}] missing during migration step {:?}", client_id, m.step
+        );
+
+        /*
+         * A downstairs being caught up may lose what it has not flushed,
+         * so the extents of that work are dirty again.
+         */
+        if catch_up {
+            self.dirty_since_flush(client_id);
+            self.dirty.save();
+        }
+    }
+
+    /**
+     * Move a downstairs migration along as far as it can go.  Return true
+     * if the downstairs tasks should be told there is new work, either a
+     * new migration step for them to send, or IO no longer held back by
+     * the fence.  Once every extent has been copied, the migration is
+     * Done and waits for migration_finish.
+     */
+    fn migration_advance(&mut self) -> bool {
+        let mut notify = false;
+        loop {
+            let m = match &mut self.migration {
+                Some(m) => m,
+                None => return notify,
+            };
+
+            match m.step {
+                MigrateStep::Connect | MigrateStep::Done => return notify,
+                MigrateStep::Fence => {
+                    if m.first_job.is_none() {
+                        m.step = MigrateStep::Connect;
+                        return notify;
+                    }
+                    if m.next_extent == m.extents.len() {
+                        m.step = MigrateStep::Done;
+                        return true;
+                    }
+
+                    /*
+                     * Copy from any active downstairs, preferring the one
+                     * we have been using.
+                     */
+                    let ds_state = &self.ds_state;
+                    let source =
+                        if ds_state[m.source as usize] == DsState::Active {
+                            Some(m.source)
+                        } else {
+                            (0..self.replicas).find(|c| {
+                                *c != m.client_id
+                                    && ds_state[*c as usize] == DsState::Active
+                            })
+                        };
+                    match source {
+                        Some(source) => m.source = source,
+                        None => return notify,
+                    }
+
+                    m.fence = Some(self.next_id);
+                    m.interrupted = false;
+                    m.step = MigrateStep::Drain;
+                }
+                MigrateStep::Drain => {
+                    if !Downstairs::fence_drained(&self.ds_active, m) {
+                        return notify;
+                    }
+
+                    let rep_id = m.next_rep_id();
+                    self.reconcile_current_work =
+                        Some(ReconcileIO::for_clients(
+                            rep_id,
+                            Message::ExtentClose {
+                                repair_id: rep_id,
+                                extent_id: m.extents[m.next_extent] as usize,
+                            },
+                            self.replicas,
+                            &[m.source, m.client_id],
+                        ));
+                    m.step = MigrateStep::Close;
+                    notify = true;
+                }
+                MigrateStep::Close
+                | MigrateStep::Repair
+                | MigrateStep::Reopen => {
+                    let done = match &self.reconcile_current_work {
+                        Some(job) => {
+                            if job.state.values().any(|s| {
+                                *s != IOState::Done && *s != IOState::Skipped
+                            }) {
+                                return notify;
+                            }
+                            (0..self.replicas)
+                                .filter(|c| {
+                                    job.state.get(c) == Some(&IOState::Done)
+                                })
+                                .collect::<Vec<u8>>()
+                        }
+                        None => Vec::new(),
+                    };
+
+                    let rep_id = m.next_rep_id();
+                    let extent_id = m.extents[m.next_extent] as usize;
+                    let repair_addr = self.ds_repair.get(&m.source).copied();
+                    match (m.step, repair_addr) {
+                        (MigrateStep::Close, Some(source_repair_address))
+                            if !m.interrupted =>
+                        {
+                            m.closed = done;
+                            let (source, client_id) = (m.source, m.client_id);
+                            let repair = Downstairs::repair_message(
+                                &self.ds_protocol,
+                                rep_id,
+                                extent_id,
+                                source,
+                                source_repair_address,
+                                vec![client_id],
+                            );
+                            self.reconcile_current_work =
+                                Some(ReconcileIO::for_clients(
+                                    rep_id,
+                                    repair,
+                                    self.replicas,
+                                    &[client_id],
+                                ));
+                            m.step = MigrateStep::Repair;
+                        }
+                        (MigrateStep::Close, _) | (MigrateStep::Repair, _) => {
+                            if m.step == MigrateStep::Close {
+                                m.closed = done;
+                                m.interrupted = true;
+                            } else if !done.contains(&m.client_id) {
+                                m.interrupted = true;
+                            }
+                            self.reconcile_current_work =
+                                Some(ReconcileIO::for_clients(
+                                    rep_id,
+                                    Message::ExtentReopen {
+                                        repair_id: rep_id,
+                                        extent_id,
+                                    },
+                                    self.replicas,
+                                    &m.closed,
+                                ));
+                            m.step = MigrateStep::Reopen;
+                        }
+                        _ => {
+                            self.reconcile_current_work = None;
+                            m.closed.clear();
+                            m.fence = None;
+                            if !m.interrupted {
+                                info!(
+                                    self.log,
+                                    "[{}] migration copied extent {} ({}/{})",
+                                    m.client_id,
+                                    extent_id,
+                                    m.next_extent + 1,
+                                    m.extents.len(),
+                                );
+                                m.next_extent += 1;
+                                if m.catch_up() {
+                                    self.dirty
+                                        .clean(m.client_id, extent_id as u32);
+                                }
+                            }
+                            m.step = MigrateStep::Fence;
+                        }
+                    }
+                    notify = true;
+                }
+            }
+        }
+    }
+
+    /**
+     * Every extent has been copied, so the new downstairs can take over.
+     * If the migration is Done, end it and return the client ID and
+     * target of the new downstairs.  Moving that downstairs to Active is
+     * left to the caller.
+     */
+    fn migration_finish(&mut self) -> Option<(u8, SocketAddr)> {
+        match &self.migration {
+            Some(m) if m.step == MigrateStep::Done => {}
+            _ => return None,
+        }
+
+        let m = self.migration.take().unwrap();
+        info!(
+            self.log,
+            "[{}] migration from {} to {} is complete",
+            m.client_id,
+            m.old_target,
+            m.new_target,
+        );
+        /*
+         * A downstairs replaced while we tracked its dirty extents, such
+         * as a faulted one, has every extent copied to it, so it is not
+         * behind on any of them either.
+         */
+        if self.dirty.is_tracking(m.client_id) {
+            self.dirty.finish(m.client_id);
+            self.dirty.save();
+        }
+        Some((m.client_id, m.new_target))
+    }
+
     /**
      * We have reconnected to a downstairs. Move every job since the
      * last flush for this client_id back to New, even if we already have
@@ -2583,11 +3711,12 @@ impl Downstairs {
                         }
                     } else {
                         /*
-                         * For a write or flush, if we have 3 completed,
-                         * then we can leave this job as AckReady, if not,
-                         * then we have to undo the AckReady.
+                         * For a write or flush, if we still have a write
+                         * quorum without this downstairs, then we can
+                         * leave this job as AckReady, if not, then we have
+                         * to undo the AckReady.
                          */
-                        if jobs_completed_ok < 3 {
+                        if jobs_completed_ok <= self.write_quorum as u64 {
                             info!(
                                 self.log,
                                 "Remove AckReady for W/F {}", ds_id
@@ -2645,10 +3774,26 @@ impl Downstairs {
     }
 
     /**
-     * Enqueue a new downstairs request.
-     */
-    fn enqueue(&mut self, io: DownstairsIO) {
+     * Enqueue a new downstairs request, to be sent to every downstairs.
+     * A faulted downstairs, or an offline one we track dirty extents for,
+     * skips it, and the extents it changes are dirty.  A read may be
+     * skipped by all but one downstairs, depending on the read policy.
+     */
+    fn enqueue(&mut self, mut io: DownstairsIO) {
+        for cl in 0..self.replicas {
+            let state = self.ds_state[cl as usize];
+            if state == DsState::Faulted
+                || (state == DsState::Offline && self.dirty.is_tracking(cl))
+            {
+                self.dirty_mark(cl, &io.work);
+                io.state.insert(cl, IOState::Skipped);
+            } else {
+                io.state.insert(cl, IOState::New);
+            }
+        }
+        self.read_pick(&mut io);
         self.ds_active.insert(io.ds_id, io);
+        self.replay_window_check();
     }
 
     /**
@@ -2688,8 +3833,8 @@ impl Downstairs {
          * the Guest
          *
          * Not ok:
-         * - 2+ errors for Write/Flush
-         * - 3+ errors for Reads
+         * - Too many errors for Write/Flush to reach the write quorum
+         * - Errors from every downstairs a Read was sent to
          *
          * TODO: this doesn't tell the Guest what the error(s) were?
          * TODO: Add retries here as well.
@@ -2702,34 +3847,44 @@ impl Downstairs {
             .ok_or_else(|| anyhow!("reqid {} is not active", ds_id))?;
 
         /*
-         * XXX: this code assumes that 3 downstairs is the max that we'll
-         * ever support.
+         * A job that changes the region fails once so many downstairs
+         * have returned an error that the rest can't make a write quorum.
          */
+        let replicas = self.replicas as u64;
+        let max_errors = replicas - self.write_quorum as u64;
         let bad_job = match &job.work {
             IOop::Read {
                 dependencies: _dependencies,
                 requests: _,
-            } => wc.error == 3,
+            } => wc.done == 0 && wc.error + wc.skipped == replicas,
             IOop::Write {
                 dependencies: _dependencies,
                 writes: _,
-            } => wc.error >= 2,
+            } => wc.error > max_errors,
             IOop::WriteUnwritten {
                 dependencies: _dependencies,
                 writes: _,
-            } => wc.error == 2,
+            } => wc.error > max_errors,
             IOop::Flush {
                 dependencies: _dependencies,
                 flush_number: _flush_number,
                 gen_number: _gen_number,
                 snapshot_details: _,
-            } => wc.error >= 2,
+            } => wc.error > max_errors,
+            IOop::Discard {
+                dependencies: _dependencies,
+                requests: _,
+            } => wc.error > max_errors,
+            IOop::Resize {
+                dependencies: _dependencies,
+                extent_count: _,
+            } => wc.error > max_errors,
         };
 
         if bad_job {
             Err(CrucibleError::IoError(format!(
-                "{} out of 3 downstairs returned an error",
-                wc.error
+                "{} out of {} downstairs returned an error",
+                wc.error, replicas
             )))
         } else {
             Ok(())
@@ -2785,6 +3940,20 @@ impl Downstairs {
                 cdt::gw__flush__done!(|| (gw_id));
                 stats.add_flush().await;
             }
+            IOop::Discard {
+                dependencies: _,
+                requests: _,
+            } => {
+                cdt::gw__discard__done!(|| (gw_id));
+                // Discards move no data, so they are not included in the
+                // metrics for this guest.
+            }
+            IOop::Resize {
+                dependencies: _,
+                extent_count: _,
+            } => {
+                cdt::gw__resize__done!(|| (gw_id));
+            }
         }
     }
 
@@ -2833,14 +4002,44 @@ impl Downstairs {
             // reconciliation is a flush (which will remove block contexts that
             // do not match with the extent data), we should never expect to see
             // this case unless this is a blank block.
-            //
-            // XXX if it's not a blank block, we may be under attack?
-            assert!(response.data[..].iter().all(|&x| x == 0));
+            if !response.data[..].iter().all(|&x| x == 0) {
+                error!(log, "Block with data has no block context");
+                crucible_bail!(
+                    BlockIntegrityError,
+                    "eid {} block {} has data but no block context",
+                    response.eid,
+                    response.offset.value,
+                );
+            }
         }
 
         Ok(valid_hash)
     }
 
+    /*
+     * The write generation of the block a read got back, from the nonce of
+     * the block context that matched.  Blocks from before keys had an ID
+     * have a random nonce, and no write generation.
+     */
+    fn write_generation(
+        response: &ReadResponse,
+        hash: Option<u64>,
+    ) -> Option<u64> {
+        let hash = hash?;
+        let ec = response
+            .block_contexts
+            .iter()
+            .find(|c| c.hash == hash)?
+            .encryption_context
+            .as_ref()?;
+        if ec.key_id == 0 || ec.nonce.len() != 12 {
+            return None;
+        }
+        Some(EncryptionContext::write_generation(Nonce::from_slice(
+            &ec.nonce,
+        )))
+    }
+
     /// Returns:
     /// - Ok(Some(valid_hash)) for successfully decrypted data
     /// - Ok(None) if there were no block contexts and block was all 0
@@ -2853,12 +4052,16 @@ impl Downstairs {
         encryption_context: &Arc<EncryptionContext>,
         log: &Logger,
     ) -> Result<Option<u64>, CrucibleError> {
-        // XXX because we don't have block generation numbers, an attacker
-        // downstairs could:
+        // The block's location is part of the associated data, so data
+        // and encryption context copied from another block will not
+        // decrypt.  An attacker downstairs could still:
         //
         // 1) remove encryption context and cause a denial of service, or
         // 2) roll back a block by writing an old data and encryption context
         //
+        // Both leave a valid looking response.  They are caught by checking
+        // the result against the BlockRecord, for the blocks it covers.
+        //
         // check that this read response contains block contexts that contain
         // (at least one) encryption context.
 
@@ -2871,9 +4074,15 @@ impl Downstairs {
             // final step of reconciliation is a flush (which will remove block
             // contexts that do not match with the extent data), we should never
             // expect to see this case unless this is a blank block.
-            //
-            // XXX if it's not a blank block, we may be under attack?
-            assert!(response.data[..].iter().all(|&x| x == 0));
+            if !response.data[..].iter().all(|&x| x == 0) {
+                error!(log, "Block with data has no encryption context");
+                crucible_bail!(
+                    BlockIntegrityError,
+                    "eid {} block {} has data but no block context",
+                    response.eid,
+                    response.offset.value,
+                );
+            }
             return Ok(None);
         }
 
@@ -2918,6 +4127,9 @@ impl Downstairs {
                     &mut response.data[..],
                     Nonce::from_slice(&block_encryption_ctx.nonce[..]),
                     Tag::from_slice(&block_encryption_ctx.tag[..]),
+                    block_encryption_ctx.key_id,
+                    response.eid,
+                    response.offset.value,
                 );
 
                 if decryption_result.is_ok() {
@@ -3013,84 +4225,129 @@ impl Downstairs {
          */
         let wc = self.state_count(ds_id)?;
         let mut jobs_completed_ok = wc.completed_ok();
+        let replicas = self.replicas as u64;
+        if responses.is_ok() {
+            self.read_balance.done(ds_id, client_id);
+        }
+        let write_quorum = self.write_quorum as u64;
+        let migrating = match &self.migration {
+            Some(m) => m.client_id == client_id,
+            None => false,
+        };
 
+        let block_record = &self.block_record;
         let job = self
             .ds_active
             .get_mut(&ds_id)
             .ok_or_else(|| anyhow!("reqid {} is not active", ds_id))?;
 
+        /*
+         * A downstairs we are migrating to is only sent reads so that the
+         * writes depending on them can run.  It may not have the data
+         * yet, so whatever it returns is dropped.
+         */
+        if migrating && matches!(job.work, IOop::Read { .. }) {
+            let oldstate =
+                job.state.insert(client_id, IOState::Skipped).unwrap();
+            if oldstate != IOState::InProgress {
+                bail!(
+                    "[{}] job completed while not InProgress: {:?}",
+                    client_id,
+                    oldstate
+                );
+            }
+
+            let wc = job.state_count();
+            if job.ack_status == AckStatus::NotAcked
+                && (wc.error + wc.skipped + wc.done) == replicas
+            {
+                job.ack_status = AckStatus::AckReady;
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+
         // Validate integrity hashes and optionally authenticated decryption.
         //
         // With AE, responses can come back that are invalid given an encryption
         // context. Test this here. It will allow us to determine if the
         // decryption is bad and set the job result to error accordingly.
+        //
+        // A block that fails its hash or decryption is corrupt on this
+        // downstairs.  That is an error for this downstairs' part of the
+        // read, and the block is noted so it can be healed from another
+        // downstairs.
         let mut read_response_hashes = Vec::new();
+        let mut corrupt = Vec::new();
         let read_data: Result<Vec<ReadResponse>, CrucibleError> =
-            if let Some(context) = &encryption_context {
-                if let Ok(mut responses) = responses {
+            match responses {
+                Ok(mut responses) => {
                     let vlog = self.log.clone();
-                    let result: Result<(), CrucibleError> =
-                        responses.iter_mut().try_for_each(|x| {
-                            let mh =
+                    let mut result = Ok(());
+                    for (i, x) in responses.iter_mut().enumerate() {
+                        let mh = match encryption_context {
+                            Some(context) => {
                                 Downstairs::validate_encrypted_read_response(
                                     x, context, &vlog,
-                                )?;
-                            read_response_hashes.push(mh);
-                            Ok(())
-                        });
-
-                    if let Some(error) = result.err() {
-                        Err(error)
-                    } else {
-                        Ok(responses)
-                    }
-                } else {
-                    // The downstairs sent us this error
-                    warn!(
-                        self.log,
-                        "[{}] DS Reports error {:?} on job {}, {:?} EC",
-                        client_id,
-                        responses,
-                        ds_id,
-                        job,
-                    );
-                    // bad responses
-                    responses
-                }
-            } else {
-                // no upstairs encryption context
-                if let Ok(mut responses) = responses {
-                    let vlog = self.log.clone();
-                    let result: Result<(), CrucibleError> =
-                        responses.iter_mut().try_for_each(|x| {
-                            let mh =
+                                )
+                            }
+                            None => {
                                 Downstairs::validate_unencrypted_read_response(
                                     x, &vlog,
-                                )?;
-                            read_response_hashes.push(mh);
-                            Ok(())
-                        });
-
-                    if let Some(error) = result.err() {
-                        Err(error)
-                    } else {
-                        Ok(responses)
+                                )
+                            }
+                        };
+                        let mh = match mh {
+                            Ok(mh) => mh,
+                            Err(
+                                e @ (CrucibleError::HashMismatch
+                                | CrucibleError::DecryptionError),
+                            ) => {
+                                corrupt.push(i);
+                                if result.is_ok() {
+                                    result = Err(e);
+                                }
+                                continue;
+                            }
+                            Err(e) => {
+                                result = Err(e);
+                                break;
+                            }
+                        };
+                        let write_gen = Downstairs::write_generation(x, mh);
+                        if let Err(e) = block_record.check(
+                            ds_id,
+                            x.eid,
+                            x.offset.value,
+                            mh,
+                            write_gen,
+                        ) {
+                            result = Err(e);
+                            break;
+                        }
+                        read_response_hashes.push(mh);
                     }
-                } else {
+
+                    result.map(|_| responses)
+                }
+                Err(e) => {
                     // The downstairs sent us this error
                     warn!(
                         self.log,
                         "[{}] DS Reports error {:?} on job {}, {:?}",
                         client_id,
-                        responses,
+                        e,
                         ds_id,
                         job,
                     );
                     // bad responses
-                    responses
+                    Err(e)
                 }
             };
 
+        // Each of these is healed once there is a good copy of the block.
+        job.heal.extend(corrupt.into_iter().map(|i| (client_id, i)));
+
         let newstate = if let Err(ref e) = read_data {
             warn!(
                 self.log,
@@ -3119,6 +4376,18 @@ impl Downstairs {
             );
         }
 
+        /*
+         * A read that failed on the one downstairs it was sent to is sent
+         * to another, if we can.
+         */
+        if matches!(newstate, IOState::Error(_))
+            && job.work.is_read()
+            && job.ack_status == AckStatus::NotAcked
+        {
+            self.read_fallback(ds_id, client_id);
+        }
+        let job = self.ds_active.get_mut(&ds_id).unwrap();
+
         if let IOState::Error(e) = newstate {
             // Some errors can be returned without considering the Downstairs
             // bad. For example, it's still an error if a snapshot exists
@@ -3147,6 +4416,14 @@ impl Downstairs {
                             flush_number: _,
                             gen_number: _,
                             snapshot_details: _,
+                        }
+                        | IOop::Discard {
+                            dependencies: _,
+                            requests: _,
+                        }
+                        | IOop::Resize {
+                            dependencies: _,
+                            extent_count: _,
                         } => {
                             let errors: u64 =
                                 match self.downstairs_errors.get(&client_id) {
@@ -3164,20 +4441,40 @@ impl Downstairs {
                             // It's possible we get a read error if the
                             // downstairs disconnects.  However XXX, someone
                             // should be told about this error.
-                            //
-                            // Some errors, we need to panic on.
                             match e {
-                                CrucibleError::HashMismatch => {
-                                    panic!(
-                                        "[{}] {} read hash mismatch {:?} {:?}",
-                                        client_id, ds_id, e, job
+                                CrucibleError::HashMismatch
+                                | CrucibleError::DecryptionError => {
+                                    // The corrupt blocks are healed from
+                                    // another downstairs, which is also
+                                    // where the guest gets them from.
+                                    error!(
+                                        self.log,
+                                        "[{}] {} read corrupt blocks {:?}",
+                                        client_id,
+                                        ds_id,
+                                        e,
                                     );
                                 }
-                                CrucibleError::DecryptionError => {
-                                    panic!(
-                                        "[{}] {} read decrypt error {:?} {:?}",
-                                        client_id, ds_id, e, job
+                                CrucibleError::BlockIntegrityError(_) => {
+                                    // This downstairs handed back stale
+                                    // data, so stop sending it IO.
+                                    error!(
+                                        self.log,
+                                        "[{}] {} read integrity error {:?}",
+                                        client_id,
+                                        ds_id,
+                                        e,
                                     );
+                                    let errors: u64 = match self
+                                        .downstairs_errors
+                                        .get(&client_id)
+                                    {
+                                        Some(v) => *v,
+                                        None => 0,
+                                    };
+
+                                    self.downstairs_errors
+                                        .insert(client_id, errors + 1);
                                 }
                                 _ => {
                                     error!(
@@ -3245,7 +4542,12 @@ impl Downstairs {
                         }
                     }
                 }
-                _ => { /* Write and WriteUnwritten IOs have no action here */ }
+                _ => {
+                    /*
+                     * Write, WriteUnwritten, Discard, and Resize IOs have
+                     * no action here
+                     */
+                }
             }
         } else {
             assert_eq!(newstate, IOState::Done);
@@ -3301,7 +4603,7 @@ impl Downstairs {
                     writes: _,
                 } => {
                     assert!(read_data.is_empty());
-                    if jobs_completed_ok == 2 {
+                    if jobs_completed_ok == write_quorum {
                         notify_guest = true;
                         job.ack_status = AckStatus::AckReady;
                         cdt::up__to__ds__write__done!(|| job.guest_id);
@@ -3312,7 +4614,7 @@ impl Downstairs {
                     writes: _,
                 } => {
                     assert!(read_data.is_empty());
-                    if jobs_completed_ok == 2 {
+                    if jobs_completed_ok == write_quorum {
                         notify_guest = true;
                         job.ack_status = AckStatus::AckReady;
                         cdt::up__to__ds__write__unwritten__done!(
@@ -3320,6 +4622,28 @@ impl Downstairs {
                         );
                     }
                 }
+                IOop::Discard {
+                    dependencies: _,
+                    requests: _,
+                } => {
+                    assert!(read_data.is_empty());
+                    if jobs_completed_ok == write_quorum {
+                        notify_guest = true;
+                        job.ack_status = AckStatus::AckReady;
+                        cdt::up__to__ds__discard__done!(|| job.guest_id);
+                    }
+                }
+                IOop::Resize {
+                    dependencies: _,
+                    extent_count: _,
+                } => {
+                    assert!(read_data.is_empty());
+                    if jobs_completed_ok == write_quorum {
+                        notify_guest = true;
+                        job.ack_status = AckStatus::AckReady;
+                        cdt::up__to__ds__resize__done!(|| job.guest_id);
+                    }
+                }
                 IOop::Flush {
                     dependencies: _dependencies,
                     flush_number: _flush_number,
@@ -3329,12 +4653,12 @@ impl Downstairs {
                     assert!(read_data.is_empty());
                     /*
                      * If we are deactivating, then we want an ACK from
-                     * all three downstairs, not the usual two.
+                     * all the downstairs, not just the write quorum.
                      * TODO here for handling the case where one (or two,
                      * or three! gasp!) downstairs are Offline.
                      */
-                    if (deactivate && jobs_completed_ok == 3)
-                        || (!deactivate && jobs_completed_ok == 2)
+                    if (deactivate && jobs_completed_ok == replicas)
+                        || (!deactivate && jobs_completed_ok == write_quorum)
                     {
                         notify_guest = true;
                         job.ack_status = AckStatus::AckReady;
@@ -3354,7 +4678,7 @@ impl Downstairs {
         }
 
         /*
-         * If all 3 jobs are done, we can check here to see if we can
+         * If all jobs are done, we can check here to see if we can
          * remove this job from the DS list. If we have completed the ack
          * to the guest, then there will be no more work on this job
          * but messages may still be unprocessed.
@@ -3367,22 +4691,24 @@ impl Downstairs {
             // double count three done and return true if we already have
             // AckReady set.
             let wc = job.state_count();
-            if (wc.error + wc.skipped + wc.done) == 3 {
+            if (wc.error + wc.skipped + wc.done) == replicas {
                 notify_guest = true;
                 job.ack_status = AckStatus::AckReady;
             }
         }
 
+        self.heal(ds_id, encryption_context);
+
         Ok(notify_guest)
     }
 
     /**
      * This request is now complete on all peers, but is is ready to retire?
-     * Only when a flush is complete on all three downstairs do we check to
+     * Only when a flush is complete on all downstairs do we check to
      * see if we can remove jobs. Double check that all write jobs have
      * finished and panic if not.
      *
-     * Note we don't retire jobs until all three downstairs have returned
+     * Note we don't retire jobs until all downstairs have returned
      * from the same flush because the Upstairs replays all jobs since
      * the last flush if a downstairs goes away and then comes back.
      * This includes reads because they can be in the deps list for
@@ -3397,7 +4723,7 @@ impl Downstairs {
         // Only a completed flush will remove jobs from the active queue -
         // currently we have to keep everything around for use during replay
         let wc = self.state_count(ds_id).unwrap();
-        if (wc.error + wc.skipped + wc.done) == 3 {
+        if (wc.error + wc.skipped + wc.done) == self.replicas as u64 {
             assert!(!self.completed.contains(&ds_id));
             assert_eq!(wc.active, 0);
 
@@ -3413,6 +4739,20 @@ impl Downstairs {
 
             kvec.sort_unstable();
 
+            /*
+             * Keep the blocks this flush covers.  The record is written
+             * out away from the Downstairs lock.
+             */
+            if let IOop::Flush { flush_number, .. } =
+                self.ds_active[&ds_id].work
+            {
+                if let Some(snapshot) =
+                    self.block_record.flush_retired(flush_number)
+                {
+                    tokio::task::spawn_blocking(move || snapshot.write());
+                }
+            }
+
             for id in kvec.iter() {
                 // Remove everything before this flush
                 assert!(*id <= ds_id);
@@ -3436,11 +4776,15 @@ impl Downstairs {
                 }
 
                 assert_eq!(wc.active, 0);
-                assert_eq!(wc.error + wc.skipped + wc.done, 3);
+                assert_eq!(
+                    wc.error + wc.skipped + wc.done,
+                    self.replicas as u64
+                );
                 assert!(!self.completed.contains(id));
 
                 let oj = self.ds_active.remove(id).unwrap();
                 assert_eq!(oj.ack_status, AckStatus::Acked);
+                self.read_balance.forget(*id);
                 self.completed.push(*id);
             }
         }
@@ -3509,10 +4853,65 @@ impl Downstairs {
 
 /// Implement AES-GCM-SIV encryption
 pub struct EncryptionContext {
-    cipher: Aes256GcmSiv,
+    keys: std::sync::RwLock<KeyRing>,
     block_size: usize,
 }
 
+/*
+ * New blocks are encrypted with the current key.  Blocks can also still
+ * be encrypted with one of the retired keys, until a key rotation has
+ * moved them all to the current key.
+ */
+struct KeyRing {
+    current: VolumeKey,
+    retired: Vec<VolumeKey>,
+}
+
+/// One of the keys a volume is encrypted with.
+#[derive(Clone)]
+pub struct VolumeKey {
+    id: u16,
+    cipher: Aes256GcmSiv,
+}
+
+impl Debug for VolumeKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("VolumeKey").field("id", &self.id).finish()
+    }
+}
+
+impl VolumeKey {
+    pub fn new(key: &[u8]) -> Result<VolumeKey> {
+        if key.len() != 32 {
+            bail!("Key length must be 32 bytes, not {}", key.len());
+        }
+        let cipher = Aes256GcmSiv::new(Key::from_slice(key));
+
+        /*
+         * The ID comes from the key, so a key has the same ID every time
+         * a volume is opened with it.  It's taken from a tag made with the
+         * key, which gives nothing away about the key itself.  ID 0 is
+         * kept for blocks written before keys had an ID.
+         */
+        let tag = match cipher.encrypt_in_place_detached(
+            Nonce::from_slice(&[0u8; 12]),
+            b"crucible key id",
+            &mut [],
+        ) {
+            Ok(tag) => tag,
+            Err(e) => bail!("Could not make a key ID: {:?}", e),
+        };
+        let id = std::cmp::max(u16::from_le_bytes([tag[0], tag[1]]), 1);
+
+        Ok(VolumeKey { id, cipher })
+    }
+
+    /// The ID stored with every block encrypted with this key.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+}
+
 impl Debug for EncryptionContext {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         f.debug_struct("EncryptionContext")
@@ -3524,16 +4923,81 @@ impl Debug for EncryptionContext {
 impl EncryptionContext {
     pub fn new(key: Vec<u8>, block_size: usize) -> EncryptionContext {
         assert!(key.len() == 32);
-        let key = Key::from_slice(&key[..]);
-        let cipher = Aes256GcmSiv::new(key);
+        let current = VolumeKey::new(&key).unwrap();
 
-        EncryptionContext { cipher, block_size }
+        EncryptionContext {
+            keys: std::sync::RwLock::new(KeyRing {
+                current,
+                retired: Vec::new(),
+            }),
+            block_size,
+        }
     }
 
     pub fn block_size(&self) -> usize {
         self.block_size
     }
 
+    /**
+     * The ID of the key new blocks are encrypted with.
+     */
+    pub fn key_id(&self) -> u16 {
+        self.keys.read().unwrap().current.id
+    }
+
+    /**
+     * Accept blocks encrypted with this key as well as the current one.
+     */
+    pub fn add_retired_key(&self, key: Vec<u8>) -> Result<()> {
+        let key = VolumeKey::new(&key)?;
+        let mut keys = self.keys.write().unwrap();
+        if keys.current.id != key.id
+            && !keys.retired.iter().any(|k| k.id == key.id)
+        {
+            keys.retired.push(key);
+        }
+        Ok(())
+    }
+
+    /**
+     * Start encrypting new blocks with new_key instead of old_key, while
+     * still accepting blocks encrypted with old_key.  If new_key is
+     * already the current key, this is a rotation being picked up again,
+     * and old_key just has to be accepted.
+     */
+    pub fn rotate(&self, old_key: VolumeKey, new_key: VolumeKey) -> Result<()> {
+        if old_key.id == new_key.id {
+            bail!(
+                "Old and new keys have the same key ID {}, \
+                 the new key must be a different one",
+                new_key.id
+            );
+        }
+
+        let mut keys = self.keys.write().unwrap();
+        if keys.current.id == old_key.id {
+            let new_id = new_key.id;
+            let old_key = std::mem::replace(&mut keys.current, new_key);
+            keys.retired.retain(|k| k.id != new_id);
+            keys.retired.push(old_key);
+        } else if keys.current.id == new_key.id {
+            if !keys.retired.iter().any(|k| k.id == old_key.id) {
+                keys.retired.push(old_key);
+            }
+        } else {
+            bail!("Neither key is the key the volume is encrypted with");
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Stop accepting blocks encrypted with anything but the current key.
+     */
+    pub fn drop_retired_keys(&self) {
+        self.keys.write().unwrap().retired.clear();
+    }
+
     pub fn get_random_nonce(&self) -> Nonce {
         let mut rng = ChaCha20Rng::from_entropy();
 
@@ -3544,13 +5008,76 @@ impl EncryptionContext {
         Nonce::clone_from_slice(&random_iv)
     }
 
+    /*
+     * The nonce for a block is random, except for bytes 4..8, which hold
+     * the generation of the upstairs that wrote it.  Nothing else in the
+     * nonce is tied to the write, as job IDs start over when the upstairs
+     * does and a generation can be used again by an upstairs that never
+     * flushed, so the 64 random bits are all that keep nonces apart.
+     */
+    fn get_write_nonce(&self, write_gen: u64) -> Nonce {
+        let mut nonce = self.get_random_nonce();
+        nonce[4..8].copy_from_slice(&(write_gen as u32).to_le_bytes());
+        nonce
+    }
+
+    /**
+     * The generation of the upstairs that encrypted a block.
+     */
+    pub fn write_generation(nonce: &Nonce) -> u64 {
+        let mut write_gen = [0u8; 4];
+        write_gen.copy_from_slice(&nonce[4..8]);
+        u32::from_le_bytes(write_gen) as u64
+    }
+
+    /*
+     * The associated data for a block is where it lives and the start of
+     * its nonce, which holds the write generation, so ciphertext copied to
+     * any other block fails to decrypt.
+     *
+     * Blocks from before keys had an ID were encrypted with no associated
+     * data.  Their key ID of 0 marks them, and they keep decrypting that
+     * way until they are written again.
+     */
+    fn associated_data(
+        eid: u64,
+        offset: u64,
+        nonce: &Nonce,
+        key_id: u16,
+    ) -> Vec<u8> {
+        if key_id == 0 {
+            return Vec::new();
+        }
+        let mut ad = Vec::with_capacity(24);
+        ad.extend_from_slice(&eid.to_le_bytes());
+        ad.extend_from_slice(&offset.to_le_bytes());
+        ad.extend_from_slice(&nonce[..8]);
+        ad
+    }
+
+    /**
+     * Encrypt a block headed for block `offset` of extent `eid` with the
+     * current key, returning the ID of that key along with the nonce, tag
+     * and integrity hash.  The write generation is the generation of the
+     * upstairs doing the write.
+     */
     pub fn encrypt_in_place(
         &self,
         data: &mut [u8],
-    ) -> Result<(Nonce, Tag, u64)> {
-        let nonce = self.get_random_nonce();
-
-        let tag = self.cipher.encrypt_in_place_detached(&nonce, b"", data);
+        eid: u64,
+        offset: u64,
+        write_gen: u64,
+    ) -> Result<(Nonce, Tag, u16, u64)> {
+        let nonce = self.get_write_nonce(write_gen);
+
+        let keys = self.keys.read().unwrap();
+        let key_id = keys.current.id;
+        let ad = Self::associated_data(eid, offset, &nonce, key_id);
+        let tag = keys
+            .current
+            .cipher
+            .encrypt_in_place_detached(&nonce, &ad, data);
+        drop(keys);
 
         if tag.is_err() {
             bail!("Could not encrypt! {:?}", tag.err());
@@ -3562,23 +5089,83 @@ impl EncryptionContext {
         // encryption so that the downstairs can verify it without the key.
         let computed_hash = integrity_hash(&[&nonce[..], &tag[..], &data[..]]);
 
-        Ok((nonce, tag, computed_hash))
+        Ok((nonce, tag, key_id, computed_hash))
     }
 
+    /**
+     * Decrypt a block with the key it says it was encrypted with.  A
+     * block from before keys had an ID could have been encrypted with any
+     * of them.
+     */
     pub fn decrypt_in_place(
         &self,
         data: &mut [u8],
         nonce: &Nonce,
         tag: &Tag,
+        key_id: u16,
+        eid: u64,
+        offset: u64,
     ) -> Result<()> {
-        let result =
-            self.cipher.decrypt_in_place_detached(nonce, b"", data, tag);
+        let ad = Self::associated_data(eid, offset, nonce, key_id);
+        let keys = self.keys.read().unwrap();
 
-        if result.is_err() {
-            bail!("Could not decrypt! {:?}", result.err().unwrap());
+        let mut tried = false;
+        for key in std::iter::once(&keys.current).chain(keys.retired.iter()) {
+            if key_id != 0 && key.id != key_id {
+                continue;
+            }
+            tried = true;
+
+            if key
+                .cipher
+                .decrypt_in_place_detached(nonce, &ad, data, tag)
+                .is_ok()
+            {
+                return Ok(());
+            }
         }
 
-        Ok(())
+        if !tried {
+            bail!("Could not decrypt! No key with ID {}", key_id);
+        }
+        bail!("Could not decrypt! Key ID {}", key_id);
+    }
+
+    /**
+     * Encrypt a decrypted block again just as it was encrypted before,
+     * with the key and nonce it was written with, which gives back the
+     * same ciphertext and tag.  Fails if no key gives back that tag.
+     */
+    pub fn encrypt_again_in_place(
+        &self,
+        data: &mut [u8],
+        nonce: &Nonce,
+        tag: &Tag,
+        key_id: u16,
+        eid: u64,
+        offset: u64,
+    ) -> Result<()> {
+        let ad = Self::associated_data(eid, offset, nonce, key_id);
+        let keys = self.keys.read().unwrap();
+
+        for key in std::iter::once(&keys.current).chain(keys.retired.iter()) {
+            if key_id != 0 && key.id != key_id {
+                continue;
+            }
+
+            let mut attempt = data.to_vec();
+            if let Ok(t) =
+                key.cipher
+                    .encrypt_in_place_detached(nonce, &ad, &mut attempt)
+            {
+                if &t == tag {
+                    data.copy_from_slice(&attempt);
+                    return Ok(());
+                }
+            }
+        }
+
+        bail!("Could not encrypt again! Key ID {}", key_id);
     }
 }
 
@@ -3735,6 +5322,13 @@ pub struct Upstairs {
      */
     need_flush: Mutex<bool>,
 
+    /*
+     * Notified when work has been queued while processing a completion,
+     * such as repair writes for corrupt blocks or a read sent to another
+     * downstairs, so the downstairs tasks are told about it.
+     */
+    work_notify: Notify,
+
     /*
      * Upstairs stats.
      */
@@ -3750,25 +5344,72 @@ pub struct Upstairs {
      */
     read_only: bool,
 
+    /*
+     * The options this upstairs was started with.  The targets are kept
+     * up to date as downstairs are replaced.
+     */
+    opts: Mutex<CrucibleOpts>,
+
     /*
      * Logger used by the upstairs
      */
     log: Logger,
 }
 
+/*
+ * The targets and write quorum come from the caller, so check them here
+ * rather than have a bad set of them take the process down.  A write
+ * quorum has to be met by the downstairs there are.
+ */
+pub(crate) fn check_replicas(opt: &CrucibleOpts) -> Result<(), CrucibleError> {
+    let replicas = opt.target.len();
+    if replicas == 0 || replicas > u8::MAX as usize {
+        crucible_bail!(
+            InvalidReplicas,
+            "unsupported number of downstairs: {}",
+            replicas
+        );
+    }
+    let write_quorum = opt.write_quorum();
+    if write_quorum == 0 || write_quorum > replicas {
+        crucible_bail!(
+            InvalidReplicas,
+            "write quorum {} is not possible with {} downstairs",
+            write_quorum,
+            replicas
+        );
+    }
+    Ok(())
+}
+
 impl Upstairs {
     pub fn default() -> Arc<Self> {
         let opts = CrucibleOpts {
             id: Uuid::new_v4(),
-            target: vec![],
+            target: (0..3)
+                .map(|p| {
+                    SocketAddr::new(
+                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                        3810 + p,
+                    )
+                })
+                .collect(),
             lossy: false,
             flush_timeout: None,
             key: None,
+            old_key: None,
             cert_pem: None,
             key_pem: None,
             root_cert_pem: None,
             control: None,
             read_only: false,
+            write_quorum: None,
+            dirty_extents: None,
+            block_record: None,
+            read_policy: None,
+            fault_timeout: None,
+            fault_queue: None,
+            qos: None,
         };
 
         // Register DTrace, and setup slog logging to use it.
@@ -3790,8 +5431,10 @@ impl Upstairs {
             0,
             RegionDefinition::default(),
             Arc::new(Guest::default()),
+            None,
             log,
         )
+        .unwrap()
     }
 
     pub fn new(
@@ -3799,30 +5442,18 @@ impl Upstairs {
         gen: u64,
         def: RegionDefinition,
         guest: Arc<Guest>,
+        encryption_context: Option<EncryptionContext>,
         log: Logger,
-    ) -> Arc<Upstairs> {
+    ) -> Result<Arc<Upstairs>, CrucibleError> {
         /*
-         * XXX Make sure we have three and only three downstairs
+         * There is one downstairs for each target, and a write or flush
+         * is acked to the guest once the write quorum of them has it.
          */
-        #[cfg(not(test))]
-        assert_eq!(opt.target.len(), 3);
+        check_replicas(opt)?;
+        let replicas = opt.target.len();
+        let write_quorum = opt.write_quorum();
 
-        // create an encryption context if a key is supplied.
-        let encryption_context = opt.key_bytes().map(|key| {
-            Arc::new(EncryptionContext::new(
-                key,
-                /*
-                 * XXX: It would be good to do BlockOp::QueryBlockSize here,
-                 * but this creates a deadlock. Upstairs::new runs before
-                 * up_ds_listen in up_main, and up_ds_listen needs to run
-                 * to answer BlockOp::QueryBlockSize.
-                 *
-                 * At this point ddef is the default, the downstairs haven't
-                 * reported in.
-                 */
-                512,
-            ))
-        });
+        let encryption_context = encryption_context.map(Arc::new);
 
         let uuid = opt.id;
         info!(log, "Crucible stats registered with UUID: {}", uuid);
@@ -3832,22 +5463,47 @@ impl Upstairs {
 
         let session_id = Uuid::new_v4();
         info!(log, "Crucible {} has session id: {}", uuid, session_id);
-        Arc::new(Upstairs {
+
+        let mut downstairs =
+            Downstairs::new(log.clone(), replicas as u8, write_quorum as u8);
+        for (client_id, target) in opt.target.iter().enumerate() {
+            downstairs.ds_target.insert(client_id as u8, *target);
+        }
+        downstairs.read_balance =
+            ReadBalance::new(opt.read_policy(), replicas as u8);
+        downstairs.fault_check =
+            FaultCheck::new(opt.fault_timeout, opt.fault_queue, replicas as u8);
+        if let Some(path) = &opt.block_record {
+            downstairs.block_record = BlockRecord::new(
+                Some(std::path::PathBuf::from(path)),
+                opt.id,
+                &log,
+            );
+        }
+        downstairs.block_record.set_generation(gen);
+        if let Some(path) = &opt.dirty_extents {
+            downstairs.dirty =
+                DirtyExtents::new(Some(std::path::PathBuf::from(path)), &log);
+        }
+
+        Ok(Arc::new(Upstairs {
             active: Mutex::new(UpstairsState::default()),
             uuid,
             session_id: Uuid::new_v4(),
             generation: Mutex::new(gen),
             guest,
-            downstairs: Mutex::new(Downstairs::new(log.clone())),
+            downstairs: Mutex::new(downstairs),
             flush_info: Mutex::new(FlushInfo::new()),
             ddef: Mutex::new(def),
             encryption_context,
             need_flush: Mutex::new(false),
+            work_notify: Notify::new(),
             stats,
             lossy: opt.lossy,
             read_only: opt.read_only,
+            opts: Mutex::new(opt.clone()),
             log,
-        })
+        }))
     }
 
     pub fn encrypted(&self) -> bool {
@@ -3879,6 +5535,13 @@ impl Upstairs {
         let mut gen = self.generation.lock().await;
         *gen = new_gen;
         info!(self.log, "Set desired generation to :{}", *gen);
+        drop(gen);
+
+        self.downstairs
+            .lock()
+            .await
+            .block_record
+            .set_generation(new_gen);
     }
 
     async fn get_generation(&self) -> u64 {
@@ -3983,6 +5646,14 @@ impl Upstairs {
                 req.send_err(CrucibleError::UpstairsDeactivating).await;
             }
             return Err(());
+        } else if ds.migration.is_some() {
+            if let Some(req) = req {
+                req.send_err(CrucibleError::MigrationError(
+                    "can't deactivate during a migration".to_string(),
+                ))
+                .await;
+            }
+            return Err(());
         }
 
         active.active_request = false;
@@ -4176,6 +5847,12 @@ impl Upstairs {
                 active.active_request = true;
                 active.req = Some(req);
                 info!(self.log, "{} active request set", self.uuid);
+
+                // Someone else may have written to the region since we
+                // were last active.  What can still be trusted of a saved
+                // record is read back once we have the region metadata.
+                drop(active);
+                self.downstairs.lock().await.block_record.clear();
                 Ok(())
             }
             UpState::Deactivating => {
@@ -4330,8 +6007,8 @@ impl Upstairs {
                 break;
             }
 
-            // Depend on all writes seen
-            if job.work.is_write() {
+            // Depend on all writes (and resizes) seen
+            if job.work.is_write() || job.work.is_resize() {
                 dep.push(**job_id);
             }
         }
@@ -4363,8 +6040,21 @@ impl Upstairs {
         gw.active.insert(gw_id, new_gtos);
 
         downstairs.enqueue(fl);
+        downstairs.block_record.flush_sent(next_flush);
         cdt::up__to__ds__flush__start!(|| (gw_id));
 
+        /*
+         * Write out the dirty extents marked since the last flush, once
+         * nothing else is waiting on the locks we hold.
+         */
+        let unsaved = downstairs.dirty.take_unsaved();
+        drop(ddef);
+        drop(downstairs);
+        drop(gw);
+        if let Some(unsaved) = unsaved {
+            unsaved.write();
+        }
+
         Ok(())
     }
 
@@ -4386,6 +6076,53 @@ impl Upstairs {
         req: Option<BlockReq>,
         is_write_unwritten: bool,
     ) -> Result<(), ()> {
+        self.submit_write_blocks(
+            offset,
+            data,
+            req,
+            is_write_unwritten,
+            |_, _, _, _| true,
+        )
+        .await
+    }
+
+    /*
+     * Write back the blocks a key rotation read that are still encrypted
+     * with an old key, which encrypts them with the current key.  stale
+     * has an entry for each block of data, and since is the last job from
+     * before the rotation started.  A block changed by a job after that
+     * has been moved to the current key by it, and what was read from the
+     * block may be out of date, so it's left alone.
+     */
+    async fn submit_rekey_write(
+        &self,
+        offset: Block,
+        data: Bytes,
+        stale: Vec<bool>,
+        since: u64,
+        req: Option<BlockReq>,
+    ) -> Result<(), ()> {
+        self.submit_write_blocks(offset, data, req, false, |ds, i, eid, bo| {
+            stale[i] && !ds.block_record.changed_since(since, eid, bo.value)
+        })
+        .await
+    }
+
+    /*
+     * Submit a write of just the blocks that keep says to, given the
+     * downstairs, and each block's index in data and where it goes.
+     */
+    async fn submit_write_blocks<F>(
+        &self,
+        offset: Block,
+        data: Bytes,
+        req: Option<BlockReq>,
+        is_write_unwritten: bool,
+        keep: F,
+    ) -> Result<(), ()>
+    where
+        F: Fn(&Downstairs, usize, u64, Block) -> bool,
+    {
         if !self.guest_io_ready().await {
             if let Some(req) = req {
                 req.send_err(CrucibleError::UpstairsInactive).await;
@@ -4415,12 +6152,32 @@ impl Upstairs {
          * and length may span two extents, and eventually XXX, two regions.
          */
         let ddef = self.ddef.lock().await;
+        if let Err(e) = downstairs.resize_fence(&ddef, offset, data.len()) {
+            if let Some(req) = req {
+                req.send_err(e).await;
+            }
+            return Err(());
+        }
         let impacted_blocks = extent_from_offset(
             *ddef,
             offset,
             Block::from_bytes(data.len(), &ddef),
         );
 
+        let blocks: Vec<(usize, u64, Block)> = impacted_blocks
+            .tuples()
+            .into_iter()
+            .enumerate()
+            .filter(|(i, (eid, bo))| keep(&downstairs, *i, *eid, *bo))
+            .map(|(i, (eid, bo))| (i, eid, bo))
+            .collect();
+        if blocks.is_empty() && !impacted_blocks.is_empty() {
+            if let Some(req) = req {
+                req.send_ok().await;
+            }
+            return Ok(());
+        }
+
         /*
          * Grab this ID after extent_from_offset: in case of Err we don't
          * want to create a gap in the IDs.
@@ -4441,7 +6198,6 @@ impl Upstairs {
          */
         let mut sub = HashMap::new();
         let next_id = downstairs.next_id();
-        let mut cur_offset: usize = 0;
 
         /*
          * To build the dependency list for this write, iterate from the end
@@ -4501,10 +6257,17 @@ impl Upstairs {
         }
 
         let mut writes: Vec<crucible_protocol::Write> =
-            Vec::with_capacity(impacted_blocks.tuples().len());
+            Vec::with_capacity(blocks.len());
 
-        for (eid, bo) in impacted_blocks.tuples() {
+        /*
+         * The write generation of every block in this write is our
+         * generation, so it keeps going up across activations.
+         */
+        let write_gen = self.get_generation().await;
+
+        for (i, eid, bo) in blocks {
             let byte_len: usize = ddef.block_size() as usize;
+            let cur_offset = i * byte_len;
 
             let (sub_data, encryption_context, hash) = if let Some(context) =
                 &self.encryption_context
@@ -4513,26 +6276,31 @@ impl Upstairs {
                 let mut mut_data =
                     data.slice(cur_offset..(cur_offset + byte_len)).to_vec();
 
-                let (nonce, tag, hash) =
-                    match context.encrypt_in_place(&mut mut_data[..]) {
-                        Err(e) => {
-                            if let Some(req) = req {
-                                req.send_err(CrucibleError::EncryptionError(
-                                    e.to_string(),
-                                ))
-                                .await;
-                            }
-                            return Err(());
+                let (nonce, tag, key_id, hash) = match context.encrypt_in_place(
+                    &mut mut_data[..],
+                    eid,
+                    bo.value,
+                    write_gen,
+                ) {
+                    Err(e) => {
+                        if let Some(req) = req {
+                            req.send_err(CrucibleError::EncryptionError(
+                                e.to_string(),
+                            ))
+                            .await;
                         }
+                        return Err(());
+                    }
 
-                        Ok(v) => v,
-                    };
+                    Ok(v) => v,
+                };
 
                 (
                     Bytes::copy_from_slice(&mut_data),
                     Some(crucible_protocol::EncryptionContext {
                         nonce: Vec::from(nonce.as_slice()),
                         tag: Vec::from(tag.as_slice()),
+                        key_id,
                     }),
                     hash,
                 )
@@ -4544,6 +6312,16 @@ impl Upstairs {
                 (sub_data, None, hash)
             };
 
+            if is_write_unwritten {
+                downstairs
+                    .block_record
+                    .record_write_unwritten(next_id, eid, bo.value);
+            } else {
+                downstairs
+                    .block_record
+                    .record_write(next_id, eid, bo.value, hash);
+            }
+
             writes.push(crucible_protocol::Write {
                 eid,
                 offset: bo,
@@ -4553,8 +6331,6 @@ impl Upstairs {
                     encryption_context,
                 },
             });
-
-            cur_offset += byte_len;
         }
 
         let wr = create_write_eob(
@@ -4583,21 +6359,308 @@ impl Upstairs {
             cdt::up__to__ds__write__start!(|| (gw_id));
         }
 
-        Ok(())
+        Ok(())
+    }
+
+    /*
+     * When we have a guest discard request with offset and length, build
+     * both the upstairs work guest tracking struct as well as the
+     * downstairs work struct, the same as we do for a write.  A discard
+     * carries no data, just the list of blocks that should be returned to
+     * the unwritten state.
+     */
+    #[instrument]
+    async fn submit_discard(
+        &self,
+        offset: Block,
+        len: u64,
+        req: Option<BlockReq>,
+    ) -> Result<(), ()> {
+        if !self.guest_io_ready().await {
+            if let Some(req) = req {
+                req.send_err(CrucibleError::UpstairsInactive).await;
+            }
+            return Err(());
+        }
+
+        if self.read_only {
+            if let Some(req) = req {
+                req.send_err(CrucibleError::ModifyingReadOnlyRegion).await;
+            }
+            return Err(());
+        }
+
+        let mut gw = self.guest.guest_work.lock().await;
+        let mut downstairs = self.downstairs.lock().await;
+
+        /*
+         * A downstairs that can't discard would be left holding data the
+         * others dropped, so every one of them has to support it.
+         */
+        if let Some(client_id) = downstairs
+            .ds_protocol
+            .iter()
+            .find(|(_, p)| !p.has_feature(FEATURE_DISCARD))
+            .map(|(client_id, _)| *client_id)
+        {
+            warn!(
+                self.log,
+                "Discard refused, downstairs {} does not support it", client_id
+            );
+            if let Some(req) = req {
+                req.send_err(CrucibleError::Unsupported(format!(
+                    "downstairs {} does not support discard",
+                    client_id
+                )))
+                .await;
+            }
+            return Err(());
+        }
+
+        let ddef = self.ddef.lock().await;
+        if let Err(e) = downstairs.resize_fence(&ddef, offset, len as usize) {
+            if let Some(req) = req {
+                req.send_err(e).await;
+            }
+            return Err(());
+        }
+        self.set_flush_need().await;
+
+        let impacted_blocks = extent_from_offset(
+            *ddef,
+            offset,
+            Block::from_bytes(len as usize, &ddef),
+        );
+
+        let gw_id: u64 = gw.next_gw_id();
+        cdt::gw__discard__start!(|| (gw_id));
+
+        let mut sub = HashMap::new();
+        let next_id = downstairs.next_id();
+
+        /*
+         * A discard changes the contents of the blocks it covers, so it
+         * follows the same dependency rules as a write: depend on the last
+         * flush, and on any active job that touches the same blocks.
+         */
+        let num_jobs = downstairs.ds_active.keys().len();
+        let mut dep: Vec<u64> = Vec::with_capacity(num_jobs);
+
+        for job_id in downstairs
+            .ds_active
+            .keys()
+            .sorted()
+            .collect::<Vec<&u64>>()
+            .iter()
+            .rev()
+        {
+            let job = &downstairs.ds_active[job_id];
+
+            if job.work.is_flush() {
+                dep.push(**job_id);
+                break;
+            }
+
+            if impacted_blocks.conflicts(&job.impacted_blocks) {
+                dep.push(**job_id);
+            }
+        }
+
+        let requests: Vec<DiscardRequest> = impacted_blocks
+            .tuples()
+            .into_iter()
+            .map(|(eid, offset)| DiscardRequest { eid, offset })
+            .collect();
+        for request in &requests {
+            downstairs.block_record.record_discard(
+                next_id,
+                request.eid,
+                request.offset.value,
+            );
+        }
+
+        let di =
+            create_discard_eob(next_id, dep, gw_id, requests, impacted_blocks);
+
+        sub.insert(next_id, 0);
+
+        let new_gtos = GtoS::new(sub, Vec::new(), None, HashMap::new(), req);
+        gw.active.insert(gw_id, new_gtos);
+
+        downstairs.enqueue(di);
+        cdt::up__to__ds__discard__start!(|| (gw_id));
+
+        Ok(())
+    }
+
+    /*
+     * Ask every downstairs to grow or shrink its region to the given
+     * number of extents.
+     *
+     * The resize depends on every job currently active, and a flush is put
+     * on the queue right behind it, so IO submitted after this will not run
+     * until the resize is done.  Until the resize is acked, IO past the end
+     * of the region it shrinks to is refused (see resize_fence).  Our
+     * region definition is updated when the resize is acked by the write
+     * quorum (see process_ds_operation).  Any downstairs that went the
+     * other way from that decision is faulted, and has to be replaced with
+     * migrate_downstairs.
+     *
+     * We require every downstairs to be active.  A downstairs that missed
+     * the resize would come back with region info that no longer matches
+     * ours, and replay can't fix that.
+     */
+    #[instrument]
+    async fn submit_resize(
+        &self,
+        extent_count: u32,
+        req: Option<BlockReq>,
+    ) -> Result<(), ()> {
+        if !self.guest_io_ready().await {
+            if let Some(req) = req {
+                req.send_err(CrucibleError::UpstairsInactive).await;
+            }
+            return Err(());
+        }
+
+        if self.read_only {
+            if let Some(req) = req {
+                req.send_err(CrucibleError::ModifyingReadOnlyRegion).await;
+            }
+            return Err(());
+        }
+
+        let mut gw = self.guest.guest_work.lock().await;
+        let mut downstairs = self.downstairs.lock().await;
+
+        if downstairs.ds_state.iter().any(|s| *s != DsState::Active) {
+            warn!(
+                self.log,
+                "Resize to {} extents refused, downstairs state: {:?}",
+                extent_count,
+                downstairs.ds_state,
+            );
+            if let Some(req) = req {
+                req.send_err(CrucibleError::RegionResizeError(format!(
+                    "all downstairs must be active, not {:?}",
+                    downstairs.ds_state
+                )))
+                .await;
+            }
+            return Err(());
+        }
+
+        if let Some(client_id) = downstairs
+            .ds_protocol
+            .iter()
+            .find(|(_, p)| p.version < VERSION_REGION_RESIZE)
+            .map(|(client_id, _)| *client_id)
+        {
+            warn!(
+                self.log,
+                "Resize refused, downstairs {} protocol too old", client_id
+            );
+            if let Some(req) = req {
+                req.send_err(CrucibleError::RegionResizeError(format!(
+                    "downstairs {} protocol version is older than {}",
+                    client_id, VERSION_REGION_RESIZE
+                )))
+                .await;
+            }
+            return Err(());
+        }
+
+        let ddef = self.ddef.lock().await;
+        info!(
+            self.log,
+            "Resize region from {} to {} extents",
+            ddef.extent_count(),
+            extent_count
+        );
+        let impacted_blocks = ImpactedBlocks::new(*ddef);
+        drop(ddef);
+
+        let gw_id: u64 = gw.next_gw_id();
+        cdt::gw__resize__start!(|| (gw_id));
+
+        let next_id = downstairs.next_id();
+        let dep: Vec<u64> =
+            downstairs.ds_active.keys().sorted().cloned().collect();
+
+        let ri = create_resize_eob(
+            next_id,
+            dep,
+            gw_id,
+            extent_count,
+            impacted_blocks,
+        );
+
+        let mut sub = HashMap::new();
+        sub.insert(next_id, 0);
+
+        let new_gtos = GtoS::new(sub, Vec::new(), None, HashMap::new(), req);
+        gw.active.insert(gw_id, new_gtos);
+
+        downstairs.enqueue(ri);
+        cdt::up__to__ds__resize__start!(|| (gw_id));
+
+        /*
+         * The flush after the resize is what later writes will depend on,
+         * and it gives the downstairs a clean point to reconcile from.
+         */
+        self.submit_flush_internal(gw, downstairs, None, None).await
+    }
+
+    /*
+     * When we have a guest read request with offset and buffer, take them
+     * and build both the upstairs work guest tracking struct as well as the
+     * downstairs work struct. Once both are ready, submit them to the
+     * required places.
+     */
+    #[instrument]
+    async fn submit_read(
+        &self,
+        offset: Block,
+        data: Buffer,
+        req: Option<BlockReq>,
+    ) -> Result<(), ()> {
+        self.submit_read_blocks(offset, data, req, None).await
+    }
+
+    /*
+     * A read for a key rotation.  Instead of the blocks that are written,
+     * the blocks marked as owned in the buffer are the ones encrypted with
+     * some key other than the current one.
+     */
+    async fn submit_rekey_read(
+        &self,
+        offset: Block,
+        data: Buffer,
+        req: Option<BlockReq>,
+    ) -> Result<(), ()> {
+        let key_id = match &self.encryption_context {
+            Some(context) => context.key_id(),
+            None => {
+                if let Some(req) = req {
+                    req.send_err(CrucibleError::KeyRotationError(
+                        "Volume is not encrypted".to_string(),
+                    ))
+                    .await;
+                }
+                return Err(());
+            }
+        };
+
+        self.submit_read_blocks(offset, data, req, Some(key_id))
+            .await
     }
 
-    /*
-     * When we have a guest read request with offset and buffer, take them
-     * and build both the upstairs work guest tracking struct as well as the
-     * downstairs work struct. Once both are ready, submit them to the
-     * required places.
-     */
-    #[instrument]
-    async fn submit_read(
+    async fn submit_read_blocks(
         &self,
         offset: Block,
         data: Buffer,
         req: Option<BlockReq>,
+        rekey_key: Option<u16>,
     ) -> Result<(), ()> {
         if !self.guest_io_ready().await {
             if let Some(req) = req {
@@ -4621,6 +6684,12 @@ impl Upstairs {
          * and length may span many extents, and eventually, TODO, regions.
          */
         let ddef = self.ddef.lock().await;
+        if let Err(e) = downstairs.resize_fence(&ddef, offset, data.len()) {
+            if let Some(req) = req {
+                req.send_err(e).await;
+            }
+            return Err(());
+        }
         let impacted_blocks = extent_from_offset(
             *ddef,
             offset,
@@ -4670,9 +6739,11 @@ impl Upstairs {
             let job = &downstairs.ds_active[job_id];
 
             // If this is a write and it impacts the same blocks as something
-            // already active, create a dependency.
-            if job.work.is_write()
-                && impacted_blocks.conflicts(&job.impacted_blocks)
+            // already active, create a dependency. Reads don't wait on
+            // flushes, so they also have to wait on any resize.
+            if (job.work.is_write()
+                && impacted_blocks.conflicts(&job.impacted_blocks))
+                || job.work.is_resize()
             {
                 dep.push(**job_id);
             }
@@ -4706,8 +6777,9 @@ impl Upstairs {
          * downstairs.
          */
         assert!(!sub.is_empty());
-        let new_gtos =
+        let mut new_gtos =
             GtoS::new(sub, Vec::new(), Some(data), HashMap::new(), req);
+        new_gtos.rekey_key = rekey_key;
         {
             gw.active.insert(gw_id, new_gtos);
         }
@@ -4733,7 +6805,8 @@ impl Upstairs {
             DsState::Active => DsState::Offline,
             DsState::Replay => DsState::Offline,
             DsState::Offline => DsState::Offline,
-            DsState::Migrating => DsState::Failed,
+            DsState::Migrating => DsState::Migrating,
+            DsState::Faulted => DsState::Faulted,
             DsState::Deactivated => DsState::New,
             DsState::Repair => DsState::New,
             DsState::FailedRepair => DsState::New,
@@ -4756,6 +6829,7 @@ impl Upstairs {
             new_state,
         );
         ds.ds_state[client_id as usize] = new_state;
+        ds.migration_missing(client_id);
     }
 
     /*
@@ -4807,13 +6881,11 @@ impl Upstairs {
     ) {
         info!(
             self.log,
-            "[{}] {} ({}) {:?} {:?} {:?} ds_transition to {:?}",
+            "[{}] {} ({}) {:?} ds_transition to {:?}",
             client_id,
             self.uuid,
             self.session_id,
-            ds.ds_state[0],
-            ds.ds_state[1],
-            ds.ds_state[2],
+            ds.ds_state,
             new_state
         );
 
@@ -4855,9 +6927,22 @@ impl Upstairs {
                 assert_eq!(old_state, DsState::Offline);
                 assert_eq!(up_state, UpState::Active);
             }
+            DsState::Migrating => {
+                assert!(
+                    old_state == DsState::Active
+                        || old_state == DsState::Offline
+                        || old_state == DsState::Faulted
+                );
+                assert_eq!(up_state, UpState::Active);
+            }
+            DsState::Faulted => {
+                assert_eq!(old_state, DsState::Active);
+                assert_eq!(up_state, UpState::Active);
+            }
             DsState::Active => {
                 if old_state != DsState::WaitQuorum
                     && old_state != DsState::Repair
+                    && old_state != DsState::Migrating
                 {
                     panic!(
                         "[{}] {} Invalid transition: {:?} -> {:?}",
@@ -4870,6 +6955,9 @@ impl Upstairs {
                 if old_state == DsState::Repair {
                     assert_ne!(up_state, UpState::Active);
                 }
+                if old_state == DsState::Migrating {
+                    assert_eq!(up_state, UpState::Active);
+                }
             }
             DsState::Deactivated => {
                 /*
@@ -4904,6 +6992,170 @@ impl Upstairs {
         }
     }
 
+    /*
+     * The address the task for this client should connect to.
+     */
+    async fn ds_target(&self, client_id: u8) -> SocketAddr {
+        *self
+            .downstairs
+            .lock()
+            .await
+            .ds_target
+            .get(&client_id)
+            .unwrap()
+    }
+
+    /*
+     * Start replacing the downstairs at the old address with a new, empty,
+     * downstairs at the new address.  The task for the old downstairs will
+     * drop its connection and connect to the new one, which is then
+     * brought up to date by copying every extent from one of the other
+     * downstairs.  Once that is done, the new downstairs goes Active.
+     */
+    async fn migrate_downstairs(
+        &self,
+        old: SocketAddr,
+        new: SocketAddr,
+    ) -> Result<(), CrucibleError> {
+        let active = self.active.lock().await;
+        let up_state = active.up_state;
+        if up_state != UpState::Active {
+            return Err(CrucibleError::UpstairsInactive);
+        }
+        let mut ds = self.downstairs.lock().await;
+        drop(active);
+
+        let extent_count = self.ddef.lock().await.extent_count();
+        let client_id = ds.migration_start(old, new, extent_count)?;
+        self.ds_transition_with_lock(
+            ds,
+            up_state,
+            client_id,
+            DsState::Migrating,
+        );
+        Ok(())
+    }
+
+    /*
+     * If this downstairs is returning from being offline longer than the
+     * replay window, or from being faulted, start catching it up by
+     * copying its dirty extents.
+     */
+    async fn catch_up_start(&self, client_id: u8) -> Result<()> {
+        let active = self.active.lock().await;
+        let up_state = active.up_state;
+        let mut ds = self.downstairs.lock().await;
+        drop(active);
+
+        let state = ds.ds_state[client_id as usize];
+        if (state != DsState::Offline && state != DsState::Faulted)
+            || !ds.dirty.is_tracking(client_id)
+        {
+            return Ok(());
+        }
+        ds.catch_up_start(client_id)?;
+        self.ds_transition_with_lock(
+            ds,
+            up_state,
+            client_id,
+            DsState::Migrating,
+        );
+        Ok(())
+    }
+
+    /*
+     * If one downstairs is too slow, fault it.  Return None if we did
+     * not, or whether doing so left a job ready to ack.
+     */
+    async fn fault_check(&self) -> Option<bool> {
+        let active = self.active.lock().await;
+        let up_state = active.up_state;
+        if up_state != UpState::Active {
+            return None;
+        }
+        let mut ds = self.downstairs.lock().await;
+        drop(active);
+
+        let (client_id, reason) = ds.fault_find(Instant::now())?;
+        error!(
+            self.log,
+            "[{}] {} is too slow ({:?}), faulting it",
+            client_id,
+            ds.ds_target.get(&client_id).unwrap(),
+            reason,
+        );
+        let notify_guest = ds.fault(client_id, reason);
+        self.ds_transition_with_lock(ds, up_state, client_id, DsState::Faulted);
+        self.stats.add_downstairs_faulted(reason).await;
+        Some(notify_guest)
+    }
+
+    /*
+     * Start encrypting new writes with new_key, while still accepting
+     * blocks encrypted with old_key.  Returns the last job from before
+     * that, every job after it was encrypted with new_key.
+     */
+    async fn rekey_start(
+        &self,
+        old_key: VolumeKey,
+        new_key: VolumeKey,
+    ) -> Result<u64, CrucibleError> {
+        if !self.guest_io_ready().await {
+            return Err(CrucibleError::UpstairsInactive);
+        }
+        if self.read_only {
+            return Err(CrucibleError::ModifyingReadOnlyRegion);
+        }
+        let context = match &self.encryption_context {
+            Some(context) => context,
+            None => {
+                crucible_bail!(KeyRotationError, "Volume is not encrypted");
+            }
+        };
+
+        /*
+         * Hold the downstairs lock so no write is submitted while the key
+         * changes.
+         */
+        let ds = self.downstairs.lock().await;
+        if let Err(e) = context.rotate(old_key, new_key) {
+            crucible_bail!(KeyRotationError, "{}", e);
+        }
+        info!(self.log, "Rotated to key ID {}", context.key_id());
+
+        Ok(ds.next_id - 1)
+    }
+
+    /*
+     * Move any downstairs migration along, and tell the downstairs tasks
+     * if that produced work for them.
+     */
+    async fn migration_step(&self, dst: &[Target], lastcast: &mut u64) {
+        if self.migration_progress().await {
+            send_reconcile_work(dst, *lastcast);
+            send_work(dst, *lastcast);
+            *lastcast += 1;
+        }
+    }
+
+    /*
+     * Move any downstairs migration along, and put the new downstairs in
+     * service once it is done.  Return true if that produced work.
+     */
+    async fn migration_progress(&self) -> bool {
+        let up_state = self.active.lock().await.up_state;
+        let mut ds = self.downstairs.lock().await;
+        let notify = ds.migration_advance();
+
+        let (client_id, new_target) = match ds.migration_finish() {
+            Some(done) => done,
+            None => return notify,
+        };
+        self.opts.lock().await.target[client_id as usize] = new_target;
+        self.ds_transition_with_lock(ds, up_state, client_id, DsState::Active);
+        true
+    }
+
     async fn ds_state(&self, client_id: u8) -> DsState {
         let ds = self.downstairs.lock().await;
         ds.ds_state[client_id as usize]
@@ -4916,12 +7168,12 @@ impl Upstairs {
      * at it.
      */
     fn mismatch_list(&self, ds: &Downstairs) -> Option<DownstairsMend> {
-        let c0_rec = ds.region_metadata.get(&0).unwrap();
-        let c1_rec = ds.region_metadata.get(&1).unwrap();
-        let c2_rec = ds.region_metadata.get(&2).unwrap();
+        let recs = (0..ds.replicas)
+            .map(|cid| ds.region_metadata.get(&cid).unwrap())
+            .collect::<Vec<_>>();
 
         let log = self.log.new(o!("" => "mend".to_string()));
-        DownstairsMend::new(c0_rec, c1_rec, c2_rec, log)
+        DownstairsMend::new(&recs, log)
     }
 
     /*
@@ -4985,7 +7237,7 @@ impl Upstairs {
                         done += 1;
                     }
                 }
-                assert_eq!(done, 3);
+                assert_eq!(done, ds.replicas);
             }
 
             ds.reconcile_current_work = Some(rio);
@@ -5093,11 +7345,18 @@ impl Upstairs {
         }
         info!(self.log, "Next flush: {}", max_flush);
 
+        /*
+         * Read back what we can still trust of the saved block record,
+         * before repair changes the region metadata it is checked against.
+         */
+        ds.block_record.load(&ds.region_metadata);
+
         /*
          * Determine what extents don't match and what to do
          * about that
          */
-        let reconcile_list = self.mismatch_list(ds);
+        let mut reconcile_list = self.mismatch_list(ds);
+        ds.mend_saved_dirty(&mut reconcile_list);
         if let Some(reconcile_list) = reconcile_list {
             /*
              * We transition all the downstairs to needing repair here
@@ -5409,7 +7668,7 @@ impl Upstairs {
                 .filter(|s| **s == DsState::Repair)
                 .count();
 
-            if ready != 3 {
+            if ready != ds.ds_state.len() {
                 /*
                  * Some downstairs was not in the proper state any longer,
                  * so we need to abort this reconciliation and start
@@ -5467,7 +7726,7 @@ impl Upstairs {
                 .filter(|s| **s == DsState::WaitQuorum)
                 .count();
 
-            if ready != 3 {
+            if ready != ds.ds_state.len() {
                 bail!("Unexpected Downstairs state after collation.");
             } else {
                 info!(self.log, "No repair work was required");
@@ -5654,6 +7913,19 @@ impl Upstairs {
                 != client_ddef.extent_size().block_size_in_bytes()
             || ddef.extent_count() != client_ddef.extent_count()
         {
+            /*
+             * A downstairs faulted for missing a resize comes back with
+             * its old size.  It stays faulted until it is replaced.
+             */
+            if ds.ds_state[client_id as usize] == DsState::Faulted {
+                bail!(
+                    "[{}] region {:?} no longer matches {:?}",
+                    client_id,
+                    client_ddef,
+                    *ddef
+                );
+            }
+
             // XXX Figure out if we can handle this error. Possibly not.
             panic!(
                 "New downstairs region info mismatch {:?} vs. {:?}",
@@ -5708,6 +7980,20 @@ impl Upstairs {
          * we already received, because it may never come back.
          */
         let ds_state = ds.ds_state[client_id as usize];
+
+        /*
+         * Every job a faulted downstairs had was skipped for it, so
+         * anything it still sends back is of no use to us.
+         */
+        if ds_state == DsState::Faulted {
+            info!(
+                self.log,
+                "[{}] drop job {} reply from faulted downstairs",
+                client_id,
+                ds_id,
+            );
+            return Ok(false);
+        }
         if ds_state != DsState::Active && ds_state != DsState::Repair {
             warn!(
                 self.log,
@@ -5720,7 +8006,9 @@ impl Upstairs {
         }
 
         // Mark this ds_id for the client_id as completed.
-        let notify_guest = match ds.process_ds_completion(
+        let blocks_healed = ds.blocks_healed;
+        let reads_redirected = ds.reads_redirected;
+        let mut notify_guest = match ds.process_ds_completion(
             ds_id,
             client_id,
             read_data,
@@ -5743,6 +8031,73 @@ impl Upstairs {
             Ok(ng) => ng,
         };
 
+        let healed = ds.blocks_healed - blocks_healed;
+        if healed > 0 {
+            self.stats.add_blocks_healed(healed as i64).await;
+            self.set_flush_need().await;
+            self.work_notify.notify_one();
+        }
+        if ds.reads_redirected != reads_redirected {
+            self.work_notify.notify_one();
+        }
+
+        /*
+         * A resize that has been acked by the write quorum is what the
+         * region looks like now. Update our region definition before the
+         * guest hears about it, so IO and size queries that follow
+         * see the new extent count.
+         */
+        if notify_guest {
+            let new_extent_count = match ds.ds_active.get(&ds_id) {
+                Some(DownstairsIO {
+                    work:
+                        IOop::Resize {
+                            dependencies: _,
+                            extent_count,
+                        },
+                    ..
+                }) => Some(*extent_count),
+                _ => None,
+            };
+
+            if let Some(extent_count) = new_extent_count {
+                if ds.result(ds_id).is_ok() {
+                    let mut ddef = self.ddef.lock().await;
+                    info!(
+                        self.log,
+                        "Region resized from {} to {} extents",
+                        ddef.extent_count(),
+                        extent_count
+                    );
+                    ddef.set_extent_count(extent_count);
+                }
+            }
+        }
+
+        /*
+         * A downstairs that went the other way on a decided resize has a
+         * region of a different size than ours, and nothing short of
+         * migrating it to a new downstairs can fix that.
+         */
+        let out_of_step = ds.resize_out_of_step(ds_id);
+        for cl in out_of_step.iter().cloned() {
+            error!(
+                self.log,
+                "[{}] region size differs after resize job {}, faulting it",
+                cl,
+                ds_id,
+            );
+            notify_guest |= ds.fault(cl, FaultReason::ResizeMismatch);
+            self.ds_transition_with_lock(ds, up_state, cl, DsState::Faulted);
+            self.stats
+                .add_downstairs_faulted(FaultReason::ResizeMismatch)
+                .await;
+            ds = self.downstairs.lock().await;
+        }
+        if out_of_step.contains(&client_id) {
+            return Ok(notify_guest);
+        }
+
         // Mark this downstairs as bad if this was a write or flush
         if let Err(err) = ds.client_error(ds_id, client_id) {
             if err == CrucibleError::UpstairsInactive {
@@ -5771,6 +8126,9 @@ impl Upstairs {
                 // ds_transition_with_lock( ...  DsState::Untrusted);
             } else if matches!(err, CrucibleError::SnapshotExistsAlready(_)) {
                 // skip
+            } else if matches!(err, CrucibleError::RegionResizeError(_)) {
+                // A refused resize leaves the region as it was.  If the
+                // others resized anyway, it was faulted above.
             }
             /*
              * After work.complete, it's possible that the job is gone
@@ -5790,6 +8148,12 @@ impl Upstairs {
                     } | IOop::WriteUnwritten {
                         dependencies: _,
                         writes: _,
+                    } | IOop::Discard {
+                        dependencies: _,
+                        requests: _,
+                    } | IOop::Resize {
+                        dependencies: _,
+                        extent_count: _,
                     }
                 ) {
                     self.ds_transition_with_lock(
@@ -5814,6 +8178,16 @@ impl Upstairs {
         let mut ds = self.downstairs.lock().await;
         ds.ds_repair.remove(&client_id);
     }
+
+    async fn ds_set_protocol(&self, client_id: u8, protocol: Negotiated) {
+        let mut ds = self.downstairs.lock().await;
+        ds.ds_protocol.insert(client_id, protocol);
+    }
+
+    async fn ds_clear_protocol(&self, client_id: u8) {
+        let mut ds = self.downstairs.lock().await;
+        ds.ds_protocol.remove(&client_id);
+    }
 }
 
 #[derive(Debug)]
@@ -5898,6 +8272,12 @@ enum DsState {
      * error such that we can no longer use it.
      */
     Failed,
+    /*
+     * This downstairs was too slow, so we stopped sending it IO and
+     * dropped our connection to it.  When it reconnects, it is caught up
+     * by copying the extents it missed changes to.
+     */
+    Faulted,
     /*
      * This downstairs is being migrated to a new location
      */
@@ -5960,6 +8340,9 @@ impl fmt::Display for DsState {
             DsState::Failed => {
                 write!(f, "Failed")
             }
+            DsState::Faulted => {
+                write!(f, "Faulted")
+            }
             DsState::Migrating => {
                 write!(f, "Migrating")
             }
@@ -6018,6 +8401,12 @@ struct DownstairsIO {
     data: Option<Vec<ReadResponse>>,
     read_response_hashes: Vec<Option<u64>>,
 
+    /*
+     * If the operation is a Read, the blocks that came back corrupt and
+     * still need a repair write, as (client ID, index in the read).
+     */
+    heal: Vec<(u8, usize)>,
+
     impacted_blocks: ImpactedBlocks,
 }
 
@@ -6069,6 +8458,14 @@ impl DownstairsIO {
                     0
                 }
             }
+            IOop::Discard {
+                dependencies: _,
+                requests: _,
+            } => 0,
+            IOop::Resize {
+                dependencies: _,
+                extent_count: _,
+            } => 0,
         }
     }
 }
@@ -6081,13 +8478,169 @@ struct ReconcileIO {
 }
 
 impl ReconcileIO {
-    fn new(id: u64, op: Message) -> ReconcileIO {
+    fn new(id: u64, op: Message, replicas: u8) -> ReconcileIO {
         let mut state = HashMap::new();
-        for cl in 0..3 {
+        for cl in 0..replicas {
             state.insert(cl, IOState::New);
         }
         ReconcileIO { id, op, state }
     }
+
+    /*
+     * Build a ReconcileIO that only the given clients will do, the
+     * others are skipped.
+     */
+    fn for_clients(
+        id: u64,
+        op: Message,
+        replicas: u8,
+        clients: &[u8],
+    ) -> ReconcileIO {
+        let mut rio = ReconcileIO::new(id, op, replicas);
+        for (cl, state) in rio.state.iter_mut() {
+            if !clients.contains(cl) {
+                *state = IOState::Skipped;
+            }
+        }
+        rio
+    }
+}
+
+/*
+ * The steps a downstairs migration walks through.  Each extent is
+ * copied with the Fence, Drain, Close, Repair, and Reopen steps before
+ * we move on to the next extent.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum MigrateStep {
+    /*
+     * Waiting for the new downstairs to connect.
+     */
+    Connect,
+    /*
+     * Ready to start copying the next extent.
+     */
+    Fence,
+    /*
+     * New IO to the source and new downstairs is held back, wait for
+     * the IO already sent to them to finish.
+     */
+    Drain,
+    /*
+     * Close the extent on the source and new downstairs.
+     */
+    Close,
+    /*
+     * The new downstairs copies the extent from the source.
+     */
+    Repair,
+    /*
+     * Reopen the extent on the downstairs that closed it.
+     */
+    Reopen,
+    /*
+     * Every extent has been copied.
+     */
+    Done,
+}
+
+/*
+ * A downstairs being replaced by a new (empty) downstairs while the
+ * upstairs is active.  A downstairs that comes back after being offline
+ * longer than the replay window is caught up the same way, as its own
+ * replacement, copying only its dirty extents.
+ *
+ * Once the new downstairs has connected it receives every write and
+ * flush, but we don't trust what it has until we have copied every
+ * extent from a healthy source downstairs.  An extent is copied with the
+ * same ExtentClose, ExtentRepair, and ExtentReopen messages that
+ * reconciliation uses.  While an extent is copied, the fence holds back
+ * any new IO for the source and the new downstairs.
+ */
+#[derive(Debug)]
+struct Migration {
+    /*
+     * The client ID of the downstairs being replaced.
+     */
+    client_id: u8,
+    /*
+     * The client ID of the downstairs we copy extents from.
+     */
+    source: u8,
+    old_target: SocketAddr,
+    new_target: SocketAddr,
+    /*
+     * The extents to copy, and the index in that of the next one.
+     */
+    extents: Vec<u32>,
+    /*
+     * The first job sent to the new downstairs.  Jobs before this one
+     * are skipped for it.  None until the new downstairs has connected.
+     */
+    first_job: Option<u64>,
+    next_extent: usize,
+    step: MigrateStep,
+    /*
+     * While copying an extent, no job at or after this ID is sent to the
+     * source or the new downstairs.
+     */
+    fence: Option<u64>,
+    /*
+     * The clients that have closed the extent being copied.
+     */
+    closed: Vec<u8>,
+    /*
+     * The source or new downstairs went away while copying this extent,
+     * so it must be copied again.
+     */
+    interrupted: bool,
+    rep_id: u64,
+}
+
+impl Migration {
+    fn new(
+        client_id: u8,
+        source: u8,
+        old_target: SocketAddr,
+        new_target: SocketAddr,
+        extents: Vec<u32>,
+    ) -> Migration {
+        Migration {
+            client_id,
+            source,
+            old_target,
+            new_target,
+            extents,
+            first_job: None,
+            next_extent: 0,
+            step: MigrateStep::Connect,
+            fence: None,
+            closed: Vec::new(),
+            interrupted: false,
+            rep_id: 0,
+        }
+    }
+
+    /*
+     * A downstairs that is its own replacement is being caught up after
+     * a long time offline, and only its dirty extents are copied.
+     */
+    fn catch_up(&self) -> bool {
+        self.old_target == self.new_target
+    }
+
+    /*
+     * The clients that take part in copying an extent.
+     */
+    fn involves(&self, client_id: u8) -> bool {
+        client_id == self.client_id || client_id == self.source
+    }
+
+    fn next_rep_id(&mut self) -> u64 {
+        let id = self.rep_id;
+        self.rep_id += 1;
+        id
+    }
 }
 /*
  * Crucible to storage IO operations.
@@ -6112,6 +8665,14 @@ pub enum IOop {
         gen_number: u64,
         snapshot_details: Option<SnapshotDetails>,
     },
+    Discard {
+        dependencies: Vec<u64>, // Jobs that must finish before this
+        requests: Vec<DiscardRequest>,
+    },
+    Resize {
+        dependencies: Vec<u64>, // Jobs that must finish before this
+        extent_count: u32,
+    },
 }
 
 impl IOop {
@@ -6135,6 +8696,25 @@ impl IOop {
                 dependencies,
                 writes: _,
             } => dependencies,
+            IOop::Discard {
+                dependencies,
+                requests: _,
+            } => dependencies,
+            IOop::Resize {
+                dependencies,
+                extent_count: _,
+            } => dependencies,
+        }
+    }
+
+    pub fn deps_mut(&mut self) -> &mut Vec<u64> {
+        match self {
+            IOop::Write { dependencies, .. }
+            | IOop::Flush { dependencies, .. }
+            | IOop::Read { dependencies, .. }
+            | IOop::WriteUnwritten { dependencies, .. }
+            | IOop::Discard { dependencies, .. }
+            | IOop::Resize { dependencies, .. } => dependencies,
         }
     }
 
@@ -6142,13 +8722,25 @@ impl IOop {
         matches!(self, IOop::Read { .. })
     }
 
+    /// Any operation that modifies block contents, including a discard.
     pub fn is_write(&self) -> bool {
-        matches!(self, IOop::Write { .. } | IOop::WriteUnwritten { .. })
+        matches!(
+            self,
+            IOop::Write { .. }
+                | IOop::WriteUnwritten { .. }
+                | IOop::Discard { .. }
+        )
     }
 
     pub fn is_flush(&self) -> bool {
         matches!(self, IOop::Flush { .. })
     }
+
+    /// A resize changes which extents exist, so every job submitted after
+    /// it has to wait for it.
+    pub fn is_resize(&self) -> bool {
+        matches!(self, IOop::Resize { .. })
+    }
 }
 
 /*
@@ -6196,26 +8788,31 @@ impl fmt::Display for IOState {
 
 #[derive(Debug, Clone, Serialize)]
 struct IOStateCount {
-    new: [u32; 3],
-    in_progress: [u32; 3],
-    done: [u32; 3],
-    skipped: [u32; 3],
-    error: [u32; 3],
+    new: Vec<u32>,
+    in_progress: Vec<u32>,
+    done: Vec<u32>,
+    skipped: Vec<u32>,
+    error: Vec<u32>,
 }
 
 impl IOStateCount {
-    fn new() -> IOStateCount {
+    fn new(replicas: u8) -> IOStateCount {
+        let replicas = replicas as usize;
         IOStateCount {
-            new: [0; 3],
-            in_progress: [0; 3],
-            done: [0; 3],
-            skipped: [0; 3],
-            error: [0; 3],
+            new: vec![0; replicas],
+            in_progress: vec![0; replicas],
+            done: vec![0; replicas],
+            skipped: vec![0; replicas],
+            error: vec![0; replicas],
         }
     }
 
     fn show_all(&mut self) {
-        println!("   STATES      DS:0   DS:1   DS:2   TOTAL");
+        print!("   STATES      ");
+        for cid in 0..self.new.len() {
+            print!("DS:{:<4}", cid);
+        }
+        println!("TOTAL");
         self.show(IOState::New);
         self.show(IOState::InProgress);
         self.show(IOState::Done);
@@ -6228,23 +8825,23 @@ impl IOStateCount {
         let state_stat;
         match state {
             IOState::New => {
-                state_stat = self.new;
+                state_stat = &self.new;
                 print!("    New        ");
             }
             IOState::InProgress => {
-                state_stat = self.in_progress;
+                state_stat = &self.in_progress;
                 print!("    Sent       ");
             }
             IOState::Done => {
-                state_stat = self.done;
+                state_stat = &self.done;
                 print!("    Done       ");
             }
             IOState::Skipped => {
-                state_stat = self.skipped;
+                state_stat = &self.skipped;
                 print!("    Skipped    ");
             }
             IOState::Error(_) => {
-                state_stat = self.error;
+                state_stat = &self.error;
                 print!("    Error      ");
             }
         }
@@ -6257,8 +8854,8 @@ impl IOStateCount {
     }
 
     pub fn incr(&mut self, state: &IOState, cid: u8) {
-        assert!(cid < 3);
         let cid = cid as usize;
+        assert!(cid < self.new.len());
         match state {
             IOState::New => {
                 self.new[cid] += 1;
@@ -6429,6 +9026,37 @@ pub enum BlockOp {
     Flush {
         snapshot_details: Option<SnapshotDetails>,
     },
+    Discard {
+        offset: Block,
+        len: u64,
+    },
+    Resize {
+        extent_count: u32,
+    },
+    MigrateDownstairs {
+        old: SocketAddr,
+        new: SocketAddr,
+    },
+    /*
+     * Key rotation, see Guest::rekey.  Rekey answers with the last job
+     * from before the rotation started.
+     */
+    Rekey {
+        old_key: VolumeKey,
+        new_key: VolumeKey,
+        data: Arc<Mutex<u64>>,
+    },
+    RekeyRead {
+        offset: Block,
+        data: Buffer,
+    },
+    RekeyWrite {
+        offset: Block,
+        data: Bytes,
+        stale: Vec<bool>,
+        since: u64,
+    },
+    RekeyDone,
     GoActive,
     GoActiveWithGen {
         gen: u64,
@@ -6481,6 +9109,8 @@ impl BlockOp {
      * We are not counting WriteUnwritten ops as IO toward the users IO
      * limits.  Though, if too many volumes are created with scrubbers
      * running, we may have to revisit that.
+     *
+     * Discard ops move no data, so they are not counted either.
      */
     pub async fn iops(&self, iop_sz: usize) -> Option<usize> {
         match self {
@@ -6588,6 +9218,12 @@ struct GtoS {
      * we don't have to ACK it to anyone.
      */
     req: Option<BlockReq>,
+
+    /*
+     * For a key rotation read, the current key.  Blocks encrypted with
+     * any other key are marked as owned in the guest buffer.
+     */
+    rekey_key: Option<u16>,
 }
 
 impl GtoS {
@@ -6604,6 +9240,7 @@ impl GtoS {
             guest_buffer,
             downstairs_buffer,
             req,
+            rekey_key: None,
         }
     }
 
@@ -6626,6 +9263,18 @@ impl GtoS {
                 let responses = self.downstairs_buffer.remove(ds_id).unwrap();
 
                 for response in responses {
+                    let owned = match self.rekey_key {
+                        None => !response.block_contexts.is_empty(),
+                        Some(key_id) => {
+                            response.block_contexts.iter().any(|ctx| {
+                                matches!(
+                                    &ctx.encryption_context,
+                                    Some(ec) if ec.key_id != key_id
+                                )
+                            })
+                        }
+                    };
+
                     // Copy over into guest memory.
                     {
                         let _ignored =
@@ -6634,8 +9283,7 @@ impl GtoS {
 
                         for i in &response.data {
                             vec[offset] = *i;
-                            owned_vec[offset] =
-                                !response.block_contexts.is_empty();
+                            owned_vec[offset] = owned;
                             offset += 1;
                         }
                     }
@@ -6846,20 +9494,16 @@ pub struct Guest {
     guest_work: Mutex<GuestWork>,
 
     /*
-     * Setting an IOP limit means that the rate at which block reqs are
-     * pulled off will be limited. No setting means they are sent right
-     * away.
+     * Token bucket limits on reads and writes, which can be changed while
+     * the guest is running.  No limits means block reqs are pulled off
+     * the queue right away.
      */
-    iop_tokens: Mutex<usize>,
-    bytes_per_iop: Option<usize>,
-    iop_limit: Option<usize>,
+    qos: Mutex<Qos>,
 
     /*
-     * Setting a bandwidth limit will also limit the rate at which block
-     * reqs are pulled off the queue.
+     * How the last scrub of a volume this guest is part of went.
      */
-    bw_tokens: Mutex<usize>, // bytes
-    bw_limit: Option<usize>, // bytes per second
+    scrub_status: Mutex<Option<ScrubStatus>>,
 }
 
 /*
@@ -6886,33 +9530,79 @@ impl Guest {
                 completed: AllocRingBuffer::with_capacity(2048),
             }),
 
-            iop_tokens: Mutex::new(0),
-            bytes_per_iop: None,
-            iop_limit: None,
+            qos: Mutex::new(Qos::default()),
 
-            bw_tokens: Mutex::new(0),
-            bw_limit: None,
+            scrub_status: Mutex::new(None),
         }
     }
 
-    pub fn set_iop_limit(&mut self, bytes_per_iop: usize, limit: usize) {
-        self.bytes_per_iop = Some(bytes_per_iop);
-        self.iop_limit = Some(limit);
+    /*
+     * Limit reads and writes to `limit` IOPs per second each, where an IOP
+     * is `bytes_per_iop` bytes.  This sets the IOP part of the QoS limits
+     * before the guest is shared, and keeps any other limits.
+     */
+    pub fn set_iop_limit(
+        &mut self,
+        bytes_per_iop: usize,
+        limit: usize,
+    ) -> Result<(), CrucibleError> {
+        let mut limits = self.qos.get_mut().limits().unwrap_or_default();
+        limits.read_iops = Some(limit as u64);
+        limits.write_iops = Some(limit as u64);
+        limits.iop_size = Some(bytes_per_iop as u64);
+        self.set_qos_mut(limits)
+    }
+
+    /*
+     * Limit reads and writes to `bytes_per_second` each, keeping any
+     * other QoS limits.
+     */
+    pub fn set_bw_limit(
+        &mut self,
+        bytes_per_second: usize,
+    ) -> Result<(), CrucibleError> {
+        let mut limits = self.qos.get_mut().limits().unwrap_or_default();
+        limits.read_bytes = Some(bytes_per_second as u64);
+        limits.write_bytes = Some(bytes_per_second as u64);
+        self.set_qos_mut(limits)
+    }
+
+    fn set_qos_mut(&mut self, limits: QosLimits) -> Result<(), CrucibleError> {
+        check_qos_limits(&limits)?;
+        self.qos.get_mut().set(Some(limits), Instant::now());
+        Ok(())
     }
 
     /*
-     * Return IOPs per second
+     * Replace the QoS limits, None removes them.
      */
-    pub fn get_iop_limit(&self) -> Option<usize> {
-        self.iop_limit
+    pub async fn set_qos(
+        &self,
+        limits: Option<QosLimits>,
+    ) -> Result<(), CrucibleError> {
+        if let Some(limits) = &limits {
+            check_qos_limits(limits)?;
+        }
+        self.qos.lock().await.set(limits, Instant::now());
+
+        // Wake up recv, a request may be able to go now.
+        self.notify.notify_one();
+        Ok(())
     }
 
-    pub fn set_bw_limit(&mut self, bytes_per_second: usize) {
-        self.bw_limit = Some(bytes_per_second);
+    pub async fn get_qos(&self) -> Option<QosLimits> {
+        self.qos.lock().await.limits()
     }
 
-    pub fn get_bw_limit(&self) -> Option<usize> {
-        self.bw_limit
+    pub async fn get_scrub_status(&self) -> Option<ScrubStatus> {
+        self.scrub_status.lock().await.clone()
+    }
+
+    /*
+     * How many IOs have had to wait for a QoS limit since the last call.
+     */
+    async fn qos_delayed(&self) -> u64 {
+        self.qos.lock().await.take_delayed()
     }
 
     /*
@@ -6936,13 +9626,27 @@ impl Guest {
                 return req;
             }
 
-            self.notify.notified().await;
+            // A request held back by a QoS limit needs no notify to go,
+            // just time to pass.
+            match self.qos.lock().await.ready_at() {
+                Some(ready_at) => {
+                    tokio::select! {
+                        _ = self.notify.notified() => {}
+                        _ = sleep_until(ready_at) => {}
+                    }
+                }
+                None => self.notify.notified().await,
+            }
         }
     }
 
     /*
-     * Consume one request off queue if it is under the IOP limit and the BW
-     * limit.
+     * Consume one request off the queue if the QoS limits let it go.
+     *
+     * Reads and writes that draw from a QoS bucket go in the order they
+     * were sent, so the first of them that has to wait holds back the
+     * rest.  Anything that draws from no bucket, a flush or a read when
+     * only writes are limited, does not wait behind them.
      */
     async fn consume_req(&self) -> Option<BlockReq> {
         let mut reqs = self.reqs.lock().await;
@@ -6950,114 +9654,29 @@ impl Guest {
         // TODO exposing queue depth here would be a good metric for disk
         // contention
 
-        // Check if no requests are queued
-        if reqs.is_empty() {
-            return None;
-        }
-
-        let req_ref: &BlockReq = reqs.front().unwrap();
-
-        // Check if we can consume right away
-        let iop_limit_applies =
-            self.iop_limit.is_some() && req_ref.op.consumes_iops();
-        let bw_limit_applies =
-            self.bw_limit.is_some() && req_ref.op.sz().await.is_some();
-
-        if !iop_limit_applies && !bw_limit_applies {
-            return Some(reqs.pop_front().unwrap());
-        }
-
-        // Check bandwidth limit before IOP limit, but make sure only to consume
-        // tokens if both checks pass!
-
-        let mut bw_check_ok = true;
-        let mut iop_check_ok = true;
-
-        // XXX if recv ever is called from multiple threads, token locks must be
-        // taken for the whole of the procedure, not multiple times in the below
-        // if blocks!
-
-        // When checking tokens vs the limit, do not check by checking if adding
-        // the block request's values to the applicable limit: this would create
-        // a scenario where a large IO enough would stall the pipeline (see
-        // test_impossible_io). Instead, check if the limits are already
-        // reached.
-
-        if let Some(bw_limit) = self.bw_limit {
-            if req_ref.op.sz().await.is_some() {
-                let bw_tokens = self.bw_tokens.lock().await;
-                if *bw_tokens >= bw_limit {
-                    bw_check_ok = false;
-                }
-            }
-        }
-
-        if let Some(iop_limit) = self.iop_limit {
-            let bytes_per_iops = self.bytes_per_iop.unwrap();
-            if req_ref.op.iops(bytes_per_iops).await.is_some() {
-                let iop_tokens = self.iop_tokens.lock().await;
-                if *iop_tokens >= iop_limit {
-                    iop_check_ok = false;
-                }
+        let mut qos = self.qos.lock().await;
+        let now = Instant::now();
+        let mut held = false;
+        for i in 0..reqs.len() {
+            let op = &reqs[i].op;
+            if !qos.limited(op) {
+                return reqs.remove(i);
             }
-        }
-
-        // If both checks pass, consume appropriate resources and return the
-        // block req
-        if bw_check_ok && iop_check_ok {
-            if self.bw_limit.is_some() {
-                if let Some(sz) = req_ref.op.sz().await {
-                    let mut bw_tokens = self.bw_tokens.lock().await;
-                    *bw_tokens += sz;
-                }
+            if held {
+                continue;
             }
 
-            if self.iop_limit.is_some() {
-                let bytes_per_iops = self.bytes_per_iop.unwrap();
-                if let Some(req_iops) = req_ref.op.iops(bytes_per_iops).await {
-                    let mut iop_tokens = self.iop_tokens.lock().await;
-                    *iop_tokens += req_iops;
-                }
+            // Only take the tokens once we know it is going.
+            if qos.ready(op, now) {
+                qos.take(op);
+                return reqs.remove(i);
             }
-
-            return Some(reqs.pop_front().unwrap());
+            held = true;
         }
 
-        // Otherwise, don't consume this block req
         None
     }
 
-    /*
-     * IOPs are IO operations per second, so leak tokens to allow that
-     * through.
-     */
-    pub async fn leak_iop_tokens(&self, tokens: usize) {
-        let mut iop_tokens = self.iop_tokens.lock().await;
-
-        if tokens > *iop_tokens {
-            *iop_tokens = 0;
-        } else {
-            *iop_tokens -= tokens;
-        }
-
-        // Notify to wake up recv now that there may be room.
-        self.notify.notify_one();
-    }
-
-    // Leak bytes from bandwidth tokens
-    pub async fn leak_bw_tokens(&self, bytes: usize) {
-        let mut bw_tokens = self.bw_tokens.lock().await;
-
-        if bytes > *bw_tokens {
-            *bw_tokens = 0;
-        } else {
-            *bw_tokens -= bytes;
-        }
-
-        // Notify to wake up recv now that there may be room.
-        self.notify.notify_one();
-    }
-
     pub async fn query_extent_size(&self) -> Result<Block, CrucibleError> {
         let data = Arc::new(Mutex::new(Block::new(0, 9)));
         let extent_query = BlockOp::QueryExtentSize { data: data.clone() };
@@ -7081,6 +9700,24 @@ impl Guest {
         Ok(*wc)
     }
 
+    /*
+     * Replace the downstairs at the old address with a new, empty,
+     * downstairs at the new address while this upstairs stays active.
+     * This returns once the migration has started.  The downstairs being
+     * replaced is in the Migrating state until every extent has been
+     * copied to the new downstairs.
+     */
+    pub async fn migrate_downstairs(
+        &self,
+        old: SocketAddr,
+        new: SocketAddr,
+    ) -> Result<(), CrucibleError> {
+        self.send(BlockOp::MigrateDownstairs { old, new })
+            .await
+            .wait()
+            .await
+    }
+
     pub async fn commit(&self) -> Result<(), CrucibleError> {
         self.send(BlockOp::Commit).await.wait().await.unwrap();
         Ok(())
@@ -7219,6 +9856,44 @@ impl BlockIO for Guest {
             .await?)
     }
 
+    async fn discard(
+        &self,
+        offset: Block,
+        len: u64,
+    ) -> Result<(), CrucibleError> {
+        let bs = self.get_block_size().await?;
+
+        if (len % bs) != 0 {
+            crucible_bail!(DataLenUnaligned);
+        }
+
+        if offset.block_size_in_bytes() as u64 != bs {
+            crucible_bail!(BlockSizeMismatch);
+        }
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        let dio = BlockOp::Discard { offset, len };
+        Ok(self.send(dio).await.wait().await?)
+    }
+
+    async fn resize(&self, extent_count: u32) -> Result<(), CrucibleError> {
+        if extent_count == 0 {
+            crucible_bail!(
+                RegionResizeError,
+                "a region must have at least one extent"
+            );
+        }
+
+        Ok(self
+            .send(BlockOp::Resize { extent_count })
+            .await
+            .wait()
+            .await?)
+    }
+
     async fn show_work(&self) -> Result<WQCounts, CrucibleError> {
         // Note: for this implementation, BlockOp::ShowWork will be sent and
         // processed by the Upstairs even if it isn't active.
@@ -7234,6 +9909,10 @@ impl BlockIO for Guest {
         let wc = data.lock().await;
         Ok(*wc)
     }
+
+    async fn set_scrub_status(&self, status: ScrubStatus) {
+        *self.scrub_status.lock().await = Some(status);
+    }
 }
 
 /*
@@ -7378,7 +10057,18 @@ async fn up_ds_listen(up: &Arc<Upstairs>, mut ds_done_rx: mpsc::Receiver<u64>) {
             assert_eq!(*ds_id_done, ds_id);
 
             let io_size = done.io_size();
-            let data = done.data.take();
+
+            /*
+             * A read only keeps a copy of its data if it still has corrupt
+             * blocks to heal from it.  Copying every read in case a slow
+             * downstairs answers with a corrupt block costs too much, so
+             * such a block is left to the next read of it.
+             */
+            let data = if !done.heal.is_empty() {
+                done.data.clone()
+            } else {
+                done.data.take()
+            };
 
             ds.ack(ds_id);
 
@@ -7522,6 +10212,79 @@ async fn process_new_io(
             send_work(dst, *lastcast);
             *lastcast += 1;
         }
+        BlockOp::Discard { offset, len } => {
+            if up.submit_discard(offset, len, Some(req)).await.is_err() {
+                return;
+            }
+            send_work(dst, *lastcast);
+            *lastcast += 1;
+        }
+        BlockOp::Resize { extent_count } => {
+            if up.submit_resize(extent_count, Some(req)).await.is_err() {
+                return;
+            }
+            send_work(dst, *lastcast);
+            *lastcast += 1;
+        }
+        BlockOp::MigrateDownstairs { old, new } => {
+            if let Err(e) = up.migrate_downstairs(old, new).await {
+                warn!(up.log, "Migrate {} to {} refused: {}", old, new, e);
+                req.send_err(e).await;
+                return;
+            }
+            req.send_ok().await;
+
+            /*
+             * Wake up the downstairs tasks so the one being replaced
+             * notices and connects to the new downstairs.
+             */
+            send_work(dst, *lastcast);
+            *lastcast += 1;
+        }
+        BlockOp::Rekey {
+            old_key,
+            new_key,
+            data,
+        } => match up.rekey_start(old_key, new_key).await {
+            Ok(since) => {
+                *data.lock().await = since;
+                req.send_ok().await;
+            }
+            Err(e) => {
+                warn!(up.log, "Key rotation refused: {}", e);
+                req.send_err(e).await;
+            }
+        },
+        BlockOp::RekeyRead { offset, data } => {
+            if up.submit_rekey_read(offset, data, Some(req)).await.is_err() {
+                return;
+            }
+            send_work(dst, *lastcast);
+            *lastcast += 1;
+        }
+        BlockOp::RekeyWrite {
+            offset,
+            data,
+            stale,
+            since,
+        } => {
+            if up
+                .submit_rekey_write(offset, data, stale, since, Some(req))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            send_work(dst, *lastcast);
+            *lastcast += 1;
+        }
+        BlockOp::RekeyDone => {
+            if let Some(context) = &up.encryption_context {
+                context.drop_retired_keys();
+            }
+            info!(up.log, "Key rotation done");
+            req.send_ok().await;
+        }
         // Query ops
         BlockOp::QueryBlockSize { data } => {
             if !up.guest_io_ready().await {
@@ -7656,6 +10419,7 @@ async fn up_listen(
     dst: Vec<Target>,
     mut ds_status_rx: mpsc::Receiver<Condition>,
     mut ds_reconcile_done_rx: mpsc::Receiver<Repair>,
+    ds_done_tx: mpsc::Sender<u64>,
     timeout: Option<u32>,
 ) {
     info!(up.log, "up_listen starts"; "task" => "up_listen");
@@ -7664,16 +10428,6 @@ async fn up_listen(
     info!(up.log, "Flush timeout: {}", flush_timeout);
     let mut lastcast = 1;
 
-    /*
-     * If this guest was configured with an IOPs or BW limit, one branch of
-     * the loop below has to leak tokens. Leak every LEAK_MS
-     * milliseconds.
-     */
-    const LEAK_MS: usize = 1000;
-
-    let leak_tick = tokio::time::Duration::from_millis(LEAK_MS as u64);
-    let mut leak_deadline = Instant::now().checked_add(leak_tick).unwrap();
-
     up.stat_update("start").await;
     let mut flush_check = deadline_secs(flush_timeout.into());
     let mut show_work_interval = deadline_secs(5);
@@ -7715,6 +10469,7 @@ async fn up_listen(
                             c.client_id, c.target
                         );
                     }
+                    up.migration_step(&dst, &mut lastcast).await;
                 } else {
                     /*
                      * This message channel should only return None if we
@@ -7729,18 +10484,23 @@ async fn up_listen(
             req = up.guest.recv() => {
                 process_new_io(up, &dst, req, &mut lastcast).await;
             }
-            _ = sleep_until(leak_deadline) => {
-                if let Some(iop_limit) = up.guest.get_iop_limit() {
-                    let tokens = iop_limit / (1000 / LEAK_MS);
-                    up.guest.leak_iop_tokens(tokens).await;
-                }
-
-                if let Some(bw_limit) = up.guest.get_bw_limit() {
-                    let tokens = bw_limit / (1000 / LEAK_MS);
-                    up.guest.leak_bw_tokens(tokens).await;
+            r = ds_reconcile_done_rx.recv() => {
+                /*
+                 * Outside of reconciliation at activation, these tell us
+                 * a downstairs migration is ready for its next step.
+                 */
+                if r.is_some() {
+                    up.migration_step(&dst, &mut lastcast).await;
                 }
-
-                leak_deadline = Instant::now().checked_add(leak_tick).unwrap();
+            }
+            _ = up.work_notify.notified() => {
+                /*
+                 * Repair writes for corrupt blocks, or a read for another
+                 * downstairs, were queued while processing a read, let the
+                 * downstairs tasks know.
+                 */
+                send_work(&dst, lastcast);
+                lastcast += 1;
             }
             _ = sleep_until(flush_check) => {
                 /*
@@ -7762,6 +10522,28 @@ async fn up_listen(
                  * counters with some regularity.
                  */
                 up.stat_update("loop").await;
+                let delayed = up.guest.qos_delayed().await;
+                if delayed > 0 {
+                    up.stats.add_qos_delayed(delayed as i64).await;
+                }
+
+                /*
+                 * A migration waiting on a downstairs to come back does
+                 * not get a message when it does, so check on it here.
+                 */
+                up.migration_step(&dst, &mut lastcast).await;
+
+                /*
+                 * Fault a downstairs that has fallen too far behind.  Its
+                 * task sees that when it looks for new work, and hangs up.
+                 */
+                if let Some(notify_guest) = up.fault_check().await {
+                    send_work(&dst, lastcast);
+                    lastcast += 1;
+                    if notify_guest {
+                        let _ = ds_done_tx.send(0).await;
+                    }
+                }
 
                 flush_check = deadline_secs(flush_timeout.into());
             }
@@ -7804,7 +10586,18 @@ pub async fn up_main(
      * Build the Upstairs struct that we use to share data between
      * the different async tasks
      */
-    let up = Upstairs::new(&opt, gen, RegionDefinition::default(), guest, log);
+    let encryption_context = key_provider::encryption_context(&opt).await?;
+    if opt.qos.is_some() {
+        guest.set_qos(opt.qos).await?;
+    }
+    let up = Upstairs::new(
+        &opt,
+        gen,
+        RegionDefinition::default(),
+        guest,
+        encryption_context,
+        log,
+    )?;
 
     /*
      * Use this channel to receive updates on target status from each task
@@ -7883,7 +10676,6 @@ pub async fn up_main(
             let (ds_active_tx, ds_active_rx) = watch::channel(0);
 
             let up = Arc::clone(&up);
-            let t0 = *dst;
             let up_coms = UpComs {
                 client_id,
                 ds_work_rx,
@@ -7895,7 +10687,7 @@ pub async fn up_main(
             };
             let tls_context = tls_context.clone();
             tokio::spawn(async move {
-                looper(t0, tls_context, &up, up_coms).await;
+                looper(tls_context, &up, up_coms).await;
             });
             client_id += 1;
 
@@ -7908,8 +10700,9 @@ pub async fn up_main(
         })
         .collect::<Vec<_>>();
 
-    // Drop here, otherwise receivers will be kept waiting if looper quits
-    drop(ds_done_tx);
+    // Drop here, otherwise receivers will be kept waiting if looper quits.
+    // The last ds_done_tx goes to up_listen, as faulting a downstairs
+    // can leave jobs ready to ack.
     drop(ds_status_tx);
     drop(ds_reconcile_done_tx);
 
@@ -7930,8 +10723,15 @@ pub async fn up_main(
          * Once connected, we then take work requests from the guest and
          * submit them into the upstairs
          */
-        up_listen(&up, dst, ds_status_rx, ds_reconcile_done_rx, flush_timeout)
-            .await
+        up_listen(
+            &up,
+            dst,
+            ds_status_rx,
+            ds_reconcile_done_rx,
+            ds_done_tx,
+            flush_timeout,
+        )
+        .await
     });
 
     Ok(join_handle)
@@ -7969,20 +10769,16 @@ fn create_write_eob(
         }
     };
 
-    let mut state = HashMap::new();
-    for cl in 0..3 {
-        state.insert(cl, IOState::New);
-    }
-
     DownstairsIO {
         ds_id,
         guest_id: gw_id,
         work: awrite,
-        state,
+        state: HashMap::new(),
         ack_status: AckStatus::NotAcked,
         replay: false,
         data: None,
         read_response_hashes: Vec::new(),
+        heal: Vec::new(),
         impacted_blocks,
     }
 }
@@ -8004,20 +10800,72 @@ fn create_read_eob(
         requests,
     };
 
-    let mut state = HashMap::new();
-    for cl in 0..3 {
-        state.insert(cl, IOState::New);
+    DownstairsIO {
+        ds_id,
+        guest_id: gw_id,
+        work: aread,
+        state: HashMap::new(),
+        ack_status: AckStatus::NotAcked,
+        replay: false,
+        data: None,
+        read_response_hashes: Vec::new(),
+        heal: Vec::new(),
+        impacted_blocks,
     }
+}
+
+/*
+ * Create a discard DownstairsIO structure from a list of blocks to be
+ * returned to the unwritten state.
+ */
+fn create_discard_eob(
+    ds_id: u64,
+    dependencies: Vec<u64>,
+    gw_id: u64,
+    requests: Vec<DiscardRequest>,
+    impacted_blocks: ImpactedBlocks,
+) -> DownstairsIO {
+    let adiscard = IOop::Discard {
+        dependencies,
+        requests,
+    };
 
     DownstairsIO {
         ds_id,
         guest_id: gw_id,
-        work: aread,
-        state,
+        work: adiscard,
+        state: HashMap::new(),
         ack_status: AckStatus::NotAcked,
         replay: false,
         data: None,
         read_response_hashes: Vec::new(),
+        heal: Vec::new(),
+        impacted_blocks,
+    }
+}
+
+fn create_resize_eob(
+    ds_id: u64,
+    dependencies: Vec<u64>,
+    gw_id: u64,
+    extent_count: u32,
+    impacted_blocks: ImpactedBlocks,
+) -> DownstairsIO {
+    let aresize = IOop::Resize {
+        dependencies,
+        extent_count,
+    };
+
+    DownstairsIO {
+        ds_id,
+        guest_id: gw_id,
+        work: aresize,
+        state: HashMap::new(),
+        ack_status: AckStatus::NotAcked,
+        replay: false,
+        data: None,
+        read_response_hashes: Vec::new(),
+        heal: Vec::new(),
         impacted_blocks,
     }
 }
@@ -8041,19 +10889,16 @@ fn create_flush(
         snapshot_details,
     };
 
-    let mut state = HashMap::new();
-    for cl in 0..3 {
-        state.insert(cl, IOState::New);
-    }
     DownstairsIO {
         ds_id,
         guest_id,
         work: flush,
-        state,
+        state: HashMap::new(),
         ack_status: AckStatus::NotAcked,
         replay: false,
         data: None,
         read_response_hashes: Vec::new(),
+        heal: Vec::new(),
         impacted_blocks,
     }
 }
@@ -8063,11 +10908,11 @@ fn create_flush(
  * the clients.
  */
 async fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
-    let mut iosc: IOStateCount = IOStateCount::new();
     let gior = up.guest_io_ready().await;
     let up_count = up.guest.guest_work.lock().await.active.len();
 
     let ds = up.downstairs.lock().await;
+    let mut iosc: IOStateCount = IOStateCount::new(ds.replicas);
     let mut kvec: Vec<u64> = ds.ds_active.keys().cloned().collect::<Vec<u64>>();
     println!(
         "----------------------------------------------------------------"
@@ -8085,18 +10930,14 @@ async fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
             show_guest_work(&up.guest).await;
         }
     } else {
-        println!(
-            "{0:>5} {1:>8} {2:>5} {3:>7} {4:>7} {5:>5} {6:>5} {7:>5} {8:>7}",
-            "GW_ID",
-            "ACK",
-            "DSID",
-            "TYPE",
-            "BLOCKS",
-            "DS:0",
-            "DS:1",
-            "DS:2",
-            "REPLAY",
+        print!(
+            "{0:>5} {1:>8} {2:>5} {3:>7} {4:>7}",
+            "GW_ID", "ACK", "DSID", "TYPE", "BLOCKS",
         );
+        for cid in 0..ds.replicas {
+            print!(" {:>5}", format!("DS:{}", cid));
+        }
+        println!(" {:>7}", "REPLAY");
 
         kvec.sort_unstable();
         for id in kvec.iter() {
@@ -8149,6 +10990,21 @@ async fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
                     let job_type = "Flush".to_string();
                     (job_type, 0)
                 }
+                IOop::Discard {
+                    dependencies: _dependencies,
+                    requests,
+                } => {
+                    let job_type = "Discard".to_string();
+                    let num_blocks = requests.len();
+                    (job_type, num_blocks)
+                }
+                IOop::Resize {
+                    dependencies: _dependencies,
+                    extent_count: _,
+                } => {
+                    let job_type = "Resize".to_string();
+                    (job_type, 0)
+                }
             };
 
             print!(
@@ -8156,7 +11012,7 @@ async fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
                 job.guest_id, ack, id, job_type, num_blocks
             );
 
-            for cid in 0..3 {
+            for cid in 0..ds.replicas {
                 let state = job.state.get(&cid);
                 match state {
                     Some(state) => {
@@ -40,55 +40,45 @@ pub struct DownstairsMend {
 impl DownstairsMend {
     /*
      * Use the data provided from each downstairs to build a list of extents
-     * that need repair.
+     * that need repair.  The RegionMetadata for each downstairs is indexed
+     * by its client ID.
      */
     pub fn new(
-        c0: &RegionMetadata,
-        c1: &RegionMetadata,
-        c2: &RegionMetadata,
+        recs: &[&RegionMetadata],
         log: Logger,
     ) -> Option<DownstairsMend> {
         let mut dsm = DownstairsMend {
             mend: HashMap::new(),
         };
 
+        assert!(!recs.is_empty());
+
         /*
          * Sanity check that all fields of the RegionMetadata struct have the
          * same length.  Pick one vec as the standard and compare.
          */
-        let match_len = c0.generation.len();
+        let match_len = recs[0].generation.len();
 
-        if match_len != c1.generation.len() || match_len != c2.generation.len()
-        {
+        if recs.iter().any(|r| r.generation.len() != match_len) {
             panic!(
-                "Downstairs: vec len mismatch for generation: {} {} {}",
-                c0.generation.len(),
-                c1.generation.len(),
-                c2.generation.len()
+                "Downstairs: vec len mismatch for generation: {:?}",
+                recs.iter().map(|r| r.generation.len()).collect::<Vec<_>>()
             );
         }
 
-        if match_len != c0.flush_numbers.len()
-            || match_len != c1.flush_numbers.len()
-            || match_len != c2.flush_numbers.len()
-        {
+        if recs.iter().any(|r| r.flush_numbers.len() != match_len) {
             panic!(
-                "Downstairs: vec len mismatch for flush_number: {} {} {}",
-                c0.flush_numbers.len(),
-                c1.flush_numbers.len(),
-                c2.flush_numbers.len()
+                "Downstairs: vec len mismatch for flush_number: {:?}",
+                recs.iter()
+                    .map(|r| r.flush_numbers.len())
+                    .collect::<Vec<_>>()
             );
         }
 
-        if match_len != c0.dirty.len()
-            || match_len != c1.dirty.len()
-            || match_len != c2.dirty.len()
-        {
+        if recs.iter().any(|r| r.dirty.len() != match_len) {
             panic!(
-                "Downstairs: vec len mismatch for dirty: {} {} {}",
-                c0.dirty.len(),
-                c1.dirty.len(),
-                c2.dirty.len()
+                "Downstairs: vec len mismatch for dirty: {:?}",
+                recs.iter().map(|r| r.dirty.len()).collect::<Vec<_>>()
             );
         }
 
@@ -103,13 +93,13 @@ impl DownstairsMend {
         /*
          * Any dirty bit set means that extent needs repair.
          * Pick our source extent, and from that we decide which of the other
-         * two extents also need repair.
+         * extents also need repair.
          */
-        for (i, dirty0) in c0.dirty.iter().enumerate() {
-            if *dirty0 || c1.dirty[i] || c2.dirty[i] {
+        for i in 0..match_len {
+            if recs.iter().any(|r| r.dirty[i]) {
                 info!(log, "Extents {} dirty", i);
                 let log_mrl = log.new(o!("mrl" => "dirty".to_string()));
-                let ef = make_repair_list(i, c0, c1, c2, log_mrl);
+                let ef = make_repair_list(i, recs, log_mrl);
                 dsm.mend.insert(i, ef);
             } else {
                 to_check.push(i as usize);
@@ -123,13 +113,14 @@ impl DownstairsMend {
          */
         let mut second_check = Vec::new();
         for i in to_check.iter() {
-            if c0.flush_numbers[*i] != c1.flush_numbers[*i]
-                || c1.flush_numbers[*i] != c2.flush_numbers[*i]
+            if recs
+                .iter()
+                .any(|r| r.flush_numbers[*i] != recs[0].flush_numbers[*i])
             {
                 info!(log, "Extent {} has flush number mismatch", i);
                 let log_mrl =
                     log.new(o!("mrl" => "flush_mismatch".to_string()));
-                let ef = make_repair_list(*i, c0, c1, c2, log_mrl);
+                let ef = make_repair_list(*i, recs, log_mrl);
                 dsm.mend.insert(*i, ef);
             } else {
                 second_check.push(*i);
@@ -141,12 +132,13 @@ impl DownstairsMend {
          * any that are not all the same.
          */
         for i in second_check.iter() {
-            if c0.generation[*i] != c1.generation[*i]
-                || c1.generation[*i] != c2.generation[*i]
+            if recs
+                .iter()
+                .any(|r| r.generation[*i] != recs[0].generation[*i])
             {
                 info!(log, "generation number mismatch {}", i);
                 let log_mrl = log.new(o!("mrl" => "gen_mismatch".to_string()));
-                let ef = make_repair_list(*i, c0, c1, c2, log_mrl);
+                let ef = make_repair_list(*i, recs, log_mrl);
                 dsm.mend.insert(*i, ef);
             }
         }
@@ -166,20 +158,18 @@ impl DownstairsMend {
  */
 fn make_repair_list(
     i: usize,
-    c0: &RegionMetadata,
-    c1: &RegionMetadata,
-    c2: &RegionMetadata,
+    recs: &[&RegionMetadata],
     log: Logger,
 ) -> ExtentFix {
-    let source = find_source(i, c0, c1, c2, &log);
-    let dest = find_dest(i, source, c0, c1, c2, &log);
+    let source = find_source(i, recs, &log);
+    let dest = find_dest(i, source, recs, &log);
 
     ExtentFix { source, dest }
 }
 
 /*
  * Given the index of an extent that has a mismatch, return the client ID
- * (0, 1, or 2) for the extent that should be the source of the repair
+ * for the extent that should be the source of the repair.
  * This requires that you have a mismatch at the provided extent, or the
  * dirty bit is set somewhere.
  *
@@ -192,89 +182,49 @@ fn make_repair_list(
  *
  * If there is still a tie at the end, then just pick one.
  */
-fn find_source(
-    i: usize,
-    c0: &RegionMetadata,
-    c1: &RegionMetadata,
-    c2: &RegionMetadata,
-    log: &Logger,
-) -> u8 {
-    /*
-     * All three client IDs are candidates for the max generation number,
-     * remove a client ID as we find it has a lower value than the others.
-     */
-    let mut max_gen = vec![0, 1, 2];
-
+fn find_source(i: usize, recs: &[&RegionMetadata], log: &Logger) -> u8 {
     info!(log, "First source client ID for extent {}", i);
 
-    let gen0 = c0.generation[i];
-    let gen1 = c1.generation[i];
-    let gen2 = c2.generation[i];
+    /*
+     * Every client ID is a candidate for the max generation number, keep
+     * only the ones that have the highest value.
+     */
+    let gens = recs.iter().map(|r| r.generation[i]).collect::<Vec<u64>>();
+    info!(log, "extent:{}  gens: {:?}", i, gens);
 
-    info!(log, "extent:{}  gens: {} {} {}", i, gen0, gen1, gen2);
+    let max = *gens.iter().max().unwrap();
+    let max_gen = (0..recs.len() as u8)
+        .filter(|cid| gens[*cid as usize] == max)
+        .collect::<Vec<u8>>();
 
-    if gen0 != gen1 || gen1 != gen2 {
-        /*
-         * We know at least one of the generation numbers is greater,
-         * so this first check will see if one is the largest.
-         */
-        if gen0 > gen1 && gen0 > gen2 {
-            return 0;
-        } else if gen1 > gen0 && gen1 > gen2 {
-            return 1;
-        } else if gen2 > gen0 && gen2 > gen1 {
-            return 2;
-        }
-
-        /*
-         * If we are here, then two gen numbers match, and we need to take
-         * the third lower generation number off the list for comparison
-         * before we move on to try to break the tie with flush numbers.
-         */
-        if gen0 == gen1 {
-            max_gen.remove(2);
-        } else if gen0 == gen2 {
-            max_gen.remove(1);
-        } else {
-            assert_eq!(gen1, gen2);
-            max_gen.remove(0);
-        }
+    if max_gen.len() == 1 {
+        return max_gen[0];
     }
 
     /*
      * Our generation numbers did not break the tie, either they are all
-     * the same, or we have just two that are the same and have removed
-     * the lower one.  Now look for a flush number that is greater.
-     *
-     * Put the three downstairs RegionMetadata structs into an array with the
-     * index being the client ID.  We use the max_gen vec to see if any
-     * of the remaining client IDs have a higher flush number.
+     * the same, or we have removed the lower ones.  Now look for a flush
+     * number that is greater among the remaining client IDs.
      */
-    let rec = vec![c0, c1, c2];
-
     info!(
         log,
-        "extent:{}  flush: {} {} {} scs: {:?}",
+        "extent:{}  flush: {:?} scs: {:?}",
         i,
-        c0.flush_numbers[i],
-        c1.flush_numbers[i],
-        c2.flush_numbers[i],
+        recs.iter()
+            .map(|r| r.flush_numbers[i])
+            .collect::<Vec<u64>>(),
         max_gen,
     );
 
-    let mut max = 0;
-    let mut max_flush = Vec::new();
-
-    for sc in max_gen.iter() {
-        if rec[*sc as usize].flush_numbers[i] > max {
-            max = rec[*sc as usize].flush_numbers[i];
-        }
-    }
-    for sc in max_gen.iter() {
-        if rec[*sc as usize].flush_numbers[i] == max {
-            max_flush.push(*sc);
-        }
-    }
+    let max = max_gen
+        .iter()
+        .map(|sc| recs[*sc as usize].flush_numbers[i])
+        .max()
+        .unwrap();
+    let max_flush = max_gen
+        .into_iter()
+        .filter(|sc| recs[*sc as usize].flush_numbers[i] == max)
+        .collect::<Vec<u8>>();
 
     info!(log, "max_flush now has: {:?}", max_flush);
     if max_flush.len() == 1 {
@@ -282,16 +232,18 @@ fn find_source(
     }
 
     /*
-     * To get here we have at least two (and possibly three) extents where
-     * the gen and flush are the same.  All that remains to break the tie is
-     * an extent with the dirty bit set.
+     * To get here we have at least two extents where the gen and flush
+     * are the same.  All that remains to break the tie is an extent with
+     * the dirty bit set.
      */
     info!(
         log,
-        "extent:{}  dirty: {} {} {}", i, c0.dirty[i], c1.dirty[i], c2.dirty[i],
+        "extent:{}  dirty: {:?}",
+        i,
+        recs.iter().map(|r| r.dirty[i]).collect::<Vec<bool>>(),
     );
     for sc in max_flush.iter() {
-        if rec[*sc as usize].dirty[i] {
+        if recs[*sc as usize].dirty[i] {
             return *sc;
         }
     }
@@ -299,11 +251,11 @@ fn find_source(
     /*
      * To get here, the mismatch has to be a dirty bit set on an extent
      * that had lower gen or flush numbers and is no longer under
-     * consideration, with the remaining two client extents having
+     * consideration, with the remaining client extents having
      * matching gen and flush numbers.
      */
     info!(log, "No maxes found, left with: {:?}", max_flush);
-    assert_eq!(max_flush.len(), 2);
+    assert!(max_flush.len() < recs.len());
 
     /*
      * Note that by always returning the lowest element in the vec, we
@@ -321,50 +273,38 @@ fn find_source(
 fn find_dest(
     i: usize,
     source: u8,
-    c0: &RegionMetadata,
-    c1: &RegionMetadata,
-    c2: &RegionMetadata,
+    recs: &[&RegionMetadata],
     log: &Logger,
 ) -> Vec<u8> {
-    assert!(source < 3);
+    assert!((source as usize) < recs.len());
     let mut dest: Vec<u8> = Vec::new();
 
-    /*
-     * Put the three downstairs RegionMetadata structs into an array with the
-     * index being the client ID.
-     */
-    let rec = vec![c0, c1, c2];
-
     info!(
         log,
         "find dest for source {} for extent at index {}", source, i
     );
 
-    let to_check = match source {
-        0 => vec![1, 2],
-        1 => vec![0, 2],
-        _ => vec![0, 1],
-    };
+    let to_check = (0..recs.len() as u8).filter(|dc| *dc != source);
 
     let source = source as usize;
-    for dc in to_check.iter() {
-        if rec[source].generation[i] != rec[*dc as usize].generation[i] {
-            dest.push(*dc);
+    for dc in to_check {
+        if recs[source].generation[i] != recs[dc as usize].generation[i] {
+            dest.push(dc);
             info!(log, "source {}, add dest {} gen", source, dc);
             continue;
         }
-        if rec[source].flush_numbers[i] != rec[*dc as usize].flush_numbers[i] {
-            dest.push(*dc);
+        if recs[source].flush_numbers[i] != recs[dc as usize].flush_numbers[i] {
+            dest.push(dc);
             info!(log, "source {}, add dest {} flush", source, dc);
             continue;
         }
-        if rec[source].dirty[i] {
-            dest.push(*dc);
+        if recs[source].dirty[i] {
+            dest.push(dc);
             info!(log, "source {}, add dest {} source flush", source, dc);
             continue;
         }
-        if rec[*dc as usize].dirty[i] {
-            dest.push(*dc);
+        if recs[dc as usize].dirty[i] {
+            dest.push(dc);
             info!(log, "source {}, add dest {} dc flush", source, dc);
             continue;
         }
@@ -391,7 +331,7 @@ mod test {
             flush_numbers: vec![3, 3, 3],
             dirty: vec![false, false, false],
         };
-        let to_fix = DownstairsMend::new(&dsr, &dsr, &dsr, csl());
+        let to_fix = DownstairsMend::new(&[&dsr, &dsr, &dsr], csl());
         assert!(to_fix.is_none());
     }
 
@@ -410,7 +350,7 @@ mod test {
             flush_numbers: vec![3, 3, 3, 3],
             dirty: vec![false, false, false, false],
         };
-        let _fix = DownstairsMend::new(&dsr, &dsr, &dsr_long, csl());
+        let _fix = DownstairsMend::new(&[&dsr, &dsr, &dsr_long], csl());
     }
 
     #[test]
@@ -429,7 +369,7 @@ mod test {
             flush_numbers: vec![0, 0, 0],
             dirty: vec![false, false, false, false],
         };
-        let _fix = DownstairsMend::new(&d1, &d1, &d2, csl());
+        let _fix = DownstairsMend::new(&[&d1, &d1, &d2], csl());
     }
 
     #[test]
@@ -448,7 +388,7 @@ mod test {
             flush_numbers: vec![0, 0, 0, 0],
             dirty: vec![false, false, false, false],
         };
-        let _fix = DownstairsMend::new(&d1, &d2, &d1, csl());
+        let _fix = DownstairsMend::new(&[&d1, &d2, &d1], csl());
     }
 
     #[test]
@@ -460,7 +400,7 @@ mod test {
             flush_numbers: vec![0, 0, 0],
             dirty: vec![false, false, false],
         };
-        let _fix = DownstairsMend::new(&d1, &d1, &d1, csl());
+        let _fix = DownstairsMend::new(&[&d1, &d1, &d1], csl());
     }
 
     #[test]
@@ -473,7 +413,7 @@ mod test {
             dirty: vec![false, false, false, false],
         };
 
-        let fix = DownstairsMend::new(&dsr, &dsr, &dsr, csl());
+        let fix = DownstairsMend::new(&[&dsr, &dsr, &dsr], csl());
         assert!(fix.is_none());
     }
 
@@ -504,7 +444,7 @@ mod test {
             flush_numbers: vec![2, 1, 3, 1],
             dirty: vec![false, false, true, false],
         };
-        let mut fix = DownstairsMend::new(&d1, &d2, &d3, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d2, &d3], csl()).unwrap();
 
         // Extent 2 has the mismatch
         let mut ef = fix.mend.remove(&2).unwrap();
@@ -541,7 +481,7 @@ mod test {
             flush_numbers,
             dirty: vec![false, true, false, false],
         };
-        let mut fix = DownstairsMend::new(&d1, &d1, &d2, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d1, &d2], csl()).unwrap();
 
         // Extent 1 has the mismatch, so we should find in the HM.
         let mut ef = fix.mend.remove(&1).unwrap();
@@ -573,7 +513,7 @@ mod test {
             flush_numbers,
             dirty: vec![false, false, true, false],
         };
-        let mut fix = DownstairsMend::new(&d1, &d2, &d1, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d2, &d1], csl()).unwrap();
 
         // Extent 2 has the mismatch
         let mut ef = fix.mend.remove(&2).unwrap();
@@ -601,7 +541,7 @@ mod test {
             flush_numbers: vec![2, 1, 2, 1],
             dirty: vec![true, false, false, true],
         };
-        let mut fix = DownstairsMend::new(&d1, &d1, &d1, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d1, &d1], csl()).unwrap();
 
         // Extents 0 and 3 have the mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
@@ -641,7 +581,7 @@ mod test {
             dirty,
         };
 
-        let mut fix = DownstairsMend::new(&d1, &d2, &d2, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d2, &d2], csl()).unwrap();
         let mut ef = fix.mend.remove(&0).unwrap();
 
         assert_eq!(ef.source, 0);
@@ -672,7 +612,7 @@ mod test {
             dirty,
         };
 
-        let mut fix = DownstairsMend::new(&d1, &d2, &d1, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d2, &d1], csl()).unwrap();
 
         // Extent 0 has the mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
@@ -716,7 +656,7 @@ mod test {
             dirty,
         };
 
-        let mut fix = DownstairsMend::new(&d1, &d2, &d3, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d2, &d3], csl()).unwrap();
 
         // Extent 0 has the first mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
@@ -765,7 +705,7 @@ mod test {
             dirty,
         };
 
-        let mut fix = DownstairsMend::new(&d1, &d2, &d2, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d2, &d2], csl()).unwrap();
 
         // Extent 0 has the first mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
@@ -807,7 +747,7 @@ mod test {
             dirty,
         };
 
-        let mut fix = DownstairsMend::new(&d1, &d2, &d3, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d2, &d3], csl()).unwrap();
 
         // Extent 0 has the first mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
@@ -886,7 +826,7 @@ mod test {
             dirty,
         };
 
-        let mut fix = DownstairsMend::new(&d1, &d1, &d2, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d1, &d2], csl()).unwrap();
         // Extent 0 has the first mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
 
@@ -930,7 +870,7 @@ mod test {
             dirty: vec![true, false, false, true],
         };
 
-        let mut fix = DownstairsMend::new(&d1, &d2, &d3, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d2, &d3], csl()).unwrap();
         // Extent 0 has a flush mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
 
@@ -995,7 +935,7 @@ mod test {
             dirty: vec![false, false, false, false],
         };
 
-        let mut fix = DownstairsMend::new(&d1, &d2, &d3, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d1, &d2, &d3], csl()).unwrap();
         // Extent 0 has a flush mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
 
@@ -1072,7 +1012,7 @@ mod test {
             flush_numbers: flush,
             dirty,
         };
-        let mut fix = DownstairsMend::new(&d0, &d1, &d2, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d0, &d1, &d2], csl()).unwrap();
 
         // Extent 0 has no mismatch
         // Extent 1 has a mismatch, so we should find it in the HM.
@@ -1170,7 +1110,7 @@ mod test {
             flush_numbers: flush,
             dirty,
         };
-        let mut fix = DownstairsMend::new(&d0, &d1, &d2, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d0, &d1, &d2], csl()).unwrap();
 
         // Extent 0 has a mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
@@ -1268,7 +1208,7 @@ mod test {
             flush_numbers: flush,
             dirty,
         };
-        let mut fix = DownstairsMend::new(&d0, &d1, &d2, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d0, &d1, &d2], csl()).unwrap();
 
         // Extent 0 has a mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
@@ -1362,7 +1302,7 @@ mod test {
             flush_numbers: flush2,
             dirty,
         };
-        let mut fix = DownstairsMend::new(&d0, &d1, &d2, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d0, &d1, &d2], csl()).unwrap();
 
         // Extent 0 has no mismatch
         // Extent 1 has a mismatch, so we should find it in the HM.
@@ -1459,7 +1399,7 @@ mod test {
             flush_numbers: flush2,
             dirty,
         };
-        let mut fix = DownstairsMend::new(&d0, &d1, &d2, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d0, &d1, &d2], csl()).unwrap();
 
         // Extent 0 has a mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
@@ -1557,7 +1497,7 @@ mod test {
             flush_numbers: flush2,
             dirty,
         };
-        let mut fix = DownstairsMend::new(&d0, &d1, &d2, csl()).unwrap();
+        let mut fix = DownstairsMend::new(&[&d0, &d1, &d2], csl()).unwrap();
 
         // Extent 0 has a mismatch
         let mut ef = fix.mend.remove(&0).unwrap();
@@ -1614,4 +1554,95 @@ mod test {
         // Extent 8  No mismatch
         assert!(fix.mend.is_empty());
     }
+
+    #[test]
+    fn reconcile_two_replicas() {
+        // Verify a two way mirror finds the extents that differ, and
+        // repairs each from the downstairs with the newer data.
+        let d0 = RegionMetadata {
+            generation: vec![1, 2, 1, 1],
+            flush_numbers: vec![3, 3, 3, 4],
+            dirty: vec![false, false, false, false],
+        };
+        let d1 = RegionMetadata {
+            generation: vec![1, 1, 1, 1],
+            flush_numbers: vec![3, 3, 3, 3],
+            dirty: vec![false, false, true, false],
+        };
+        let mut fix = DownstairsMend::new(&[&d0, &d1], csl()).unwrap();
+
+        // Extent 0 has no mismatch
+        assert!(fix.mend.remove(&0).is_none());
+
+        // Extent 1 has a higher gen on client 0
+        let ef = fix.mend.remove(&1).unwrap();
+        assert_eq!(ef.source, 0);
+        assert_eq!(ef.dest, vec![1]);
+
+        // Extent 2 is dirty on client 1
+        let ef = fix.mend.remove(&2).unwrap();
+        assert_eq!(ef.source, 1);
+        assert_eq!(ef.dest, vec![0]);
+
+        // Extent 3 has a higher flush on client 0
+        let ef = fix.mend.remove(&3).unwrap();
+        assert_eq!(ef.source, 0);
+        assert_eq!(ef.dest, vec![1]);
+
+        assert!(fix.mend.is_empty());
+    }
+
+    #[test]
+    fn reconcile_five_replicas() {
+        // Verify source selection among five downstairs.
+        let d0 = RegionMetadata {
+            generation: vec![1, 1, 1, 1],
+            flush_numbers: vec![3, 3, 3, 3],
+            dirty: vec![false, false, false, false],
+        };
+        let d1 = RegionMetadata {
+            generation: vec![1, 1, 1, 1],
+            flush_numbers: vec![3, 3, 3, 3],
+            dirty: vec![false, false, false, false],
+        };
+        let d2 = RegionMetadata {
+            generation: vec![1, 2, 1, 1],
+            flush_numbers: vec![3, 3, 4, 3],
+            dirty: vec![false, false, false, false],
+        };
+        let d3 = RegionMetadata {
+            generation: vec![1, 2, 1, 1],
+            flush_numbers: vec![3, 4, 4, 3],
+            dirty: vec![false, false, true, false],
+        };
+        let d4 = RegionMetadata {
+            generation: vec![1, 1, 1, 1],
+            flush_numbers: vec![3, 5, 3, 3],
+            dirty: vec![false, false, false, true],
+        };
+        let mut fix =
+            DownstairsMend::new(&[&d0, &d1, &d2, &d3, &d4], csl()).unwrap();
+
+        // Extent 0 has no mismatch
+        assert!(fix.mend.remove(&0).is_none());
+
+        // Extent 1: the highest gen wins over the highest flush, and the
+        // flush breaks the tie between clients 2 and 3.
+        let ef = fix.mend.remove(&1).unwrap();
+        assert_eq!(ef.source, 3);
+        assert_eq!(ef.dest, vec![0, 1, 2, 4]);
+
+        // Extent 2: the dirty bit breaks the tie between 2 and 3.
+        let ef = fix.mend.remove(&2).unwrap();
+        assert_eq!(ef.source, 3);
+        assert_eq!(ef.dest, vec![0, 1, 2, 4]);
+
+        // Extent 3: only client 4 is dirty, it is the source and every
+        // other client is a destination.
+        let ef = fix.mend.remove(&3).unwrap();
+        assert_eq!(ef.source, 4);
+        assert_eq!(ef.dest, vec![0, 1, 2, 3]);
+
+        assert!(fix.mend.is_empty());
+    }
 }
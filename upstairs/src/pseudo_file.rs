@@ -181,6 +181,26 @@ impl<T: BlockIO> CruciblePseudoFile<T> {
     pub fn uuid(&self) -> Uuid {
         self.uuid
     }
+
+    /*
+     * Discard the byte range [offset, offset + len). Only blocks that are
+     * entirely inside the range are discarded, partial blocks at either end
+     * are left untouched. This does not move the file offset.
+     */
+    pub fn discard(&mut self, offset: u64, len: u64) -> IOResult<()> {
+        if !self.active {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                CrucibleError::UpstairsInactive,
+            ));
+        }
+
+        tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current()
+                .block_on(self._discard(offset, len))
+        })
+        .map_err(|e| e.into())
+    }
 }
 
 /*
@@ -328,6 +348,27 @@ impl<T: BlockIO> CruciblePseudoFile<T> {
         Ok(buf.len())
     }
 
+    async fn _discard(
+        &mut self,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), CrucibleError> {
+        let _guard = self.rmw_lock.read().await;
+
+        let first_block = (offset + self.block_size - 1) / self.block_size;
+        let end_block = (offset + len) / self.block_size;
+        if end_block <= first_block {
+            return Ok(());
+        }
+
+        self.block_io
+            .discard(
+                Block::new(first_block, self.block_size.trailing_zeros()),
+                (end_block - first_block) * self.block_size,
+            )
+            .await
+    }
+
     async fn _flush(&mut self) -> Result<(), CrucibleError> {
         let _guard = self.rmw_lock.write().await;
 
@@ -0,0 +1,373 @@
+// Copyright 2022 Oxide Computer Company
+use super::*;
+
+/*
+ * QoS limits on the IO a guest sends.
+ *
+ * Each limit is a token bucket that fills at the limit's rate, up to
+ * burst_secs worth of tokens.  A read or write is only taken off the
+ * guest's queue when every bucket it draws from has a token left, and it
+ * then takes all the tokens it uses, which can leave a bucket in debt.
+ * Anything else draws from no bucket and never waits.
+ * That way a large IO is never stuck waiting for more tokens than its
+ * bucket can hold, the IO after it just waits longer.
+ *
+ * The limits can be changed while IO is flowing.  A bucket keeps what it
+ * has, or owes, across a change, so setting the same limits again does
+ * not hand out a new burst.
+ */
+
+#[derive(Debug)]
+struct Bucket {
+    // Tokens per second.
+    rate: u64,
+    capacity: i64,
+    tokens: i64,
+    // The time the tokens were last brought up to date.
+    filled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, burst_secs: u32, now: Instant) -> Bucket {
+        let capacity = std::cmp::max(rate * burst_secs as u64, 1) as i64;
+        Bucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            filled: now,
+        }
+    }
+
+    fn fill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.filled);
+        let add = elapsed.as_nanos() * self.rate as u128 / 1_000_000_000;
+        let add = std::cmp::min(add, (self.capacity - self.tokens) as u128);
+        let add = add as i64;
+        if add > 0 {
+            // Only move ahead by the time the whole tokens took, so the
+            // rest still counts toward the next one.
+            self.filled += Duration::from_nanos(
+                (add as u128 * 1_000_000_000 / self.rate as u128) as u64,
+            );
+            self.tokens += add;
+        }
+        if self.tokens == self.capacity {
+            self.filled = now;
+        }
+    }
+
+    /*
+     * When this bucket will have a token, or None if it has one now.
+     */
+    fn ready_at(&self) -> Option<Instant> {
+        if self.tokens > 0 {
+            return None;
+        }
+        let need = (1 - self.tokens) as u128;
+        let nanos =
+            (need * 1_000_000_000 + self.rate as u128 - 1) / self.rate as u128;
+        Some(self.filled + Duration::from_nanos(nanos as u64))
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Qos {
+    limits: Option<QosLimits>,
+    read_iops: Option<Bucket>,
+    write_iops: Option<Bucket>,
+    read_bytes: Option<Bucket>,
+    write_bytes: Option<Bucket>,
+    // When the request at the front of the queue can go, if it has to
+    // wait.
+    ready_at: Option<Instant>,
+    // Count of IOs that had to wait, since this was last asked for it.
+    delayed: u64,
+}
+
+/*
+ * A rate of zero would never let anything through, and a bucket has to
+ * be able to count a burst of every rate.
+ */
+pub(crate) fn check_qos_limits(
+    limits: &QosLimits,
+) -> Result<(), CrucibleError> {
+    let rates = [
+        limits.read_iops,
+        limits.write_iops,
+        limits.read_bytes,
+        limits.write_bytes,
+        limits.iop_size,
+        limits.burst_secs.map(|b| b as u64),
+    ];
+    if rates.contains(&Some(0)) {
+        crucible_bail!(InvalidQosLimits, "{:?} has a limit of zero", limits);
+    }
+
+    let burst_secs = limits.burst_secs.unwrap_or(1) as u64;
+    for rate in rates[..4].iter().flatten() {
+        match rate.checked_mul(burst_secs) {
+            Some(burst) if burst <= i64::MAX as u64 => {}
+            _ => {
+                crucible_bail!(
+                    InvalidQosLimits,
+                    "{:?} has a burst of {} x {} seconds, which is too large",
+                    limits,
+                    rate,
+                    burst_secs,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Qos {
+    pub(crate) fn limits(&self) -> Option<QosLimits> {
+        self.limits
+    }
+
+    pub(crate) fn set(&mut self, limits: Option<QosLimits>, now: Instant) {
+        let burst_secs = limits.and_then(|l| l.burst_secs).unwrap_or(1);
+        let bucket = |old: Option<Bucket>, rate: Option<u64>| {
+            let mut new = Bucket::new(rate?, burst_secs, now);
+            if let Some(mut old) = old {
+                old.fill(now);
+                new.tokens = std::cmp::min(old.tokens, new.capacity);
+            }
+            Some(new)
+        };
+        let l = limits.unwrap_or_default();
+        self.read_iops = bucket(self.read_iops.take(), l.read_iops);
+        self.write_iops = bucket(self.write_iops.take(), l.write_iops);
+        self.read_bytes = bucket(self.read_bytes.take(), l.read_bytes);
+        self.write_bytes = bucket(self.write_bytes.take(), l.write_bytes);
+        self.limits = limits;
+        self.ready_at = None;
+    }
+
+    /*
+     * The buckets this op draws from, and how much it takes from each.
+     */
+    fn draws(&mut self, op: &BlockOp) -> Vec<(&mut Bucket, i64)> {
+        let iop_size = self.limits.and_then(|l| l.iop_size);
+        let (iops, bytes, len) = match op {
+            BlockOp::Read { data, .. } => {
+                (&mut self.read_iops, &mut self.read_bytes, data.len())
+            }
+            BlockOp::Write { data, .. } => {
+                (&mut self.write_iops, &mut self.write_bytes, data.len())
+            }
+            _ => return Vec::new(),
+        };
+        let count = match iop_size {
+            Some(iop_size) => {
+                std::cmp::max((len as u64 + iop_size - 1) / iop_size, 1) as i64
+            }
+            None => 1,
+        };
+
+        let mut draws = Vec::new();
+        if let Some(b) = iops.as_mut() {
+            draws.push((b, count));
+        }
+        if let Some(b) = bytes.as_mut() {
+            draws.push((b, len as i64));
+        }
+        draws
+    }
+
+    /*
+     * Does this op draw from any bucket?
+     */
+    pub(crate) fn limited(&mut self, op: &BlockOp) -> bool {
+        !self.draws(op).is_empty()
+    }
+
+    /*
+     * Can this op go now?  If not, note when it can.
+     */
+    pub(crate) fn ready(&mut self, op: &BlockOp, now: Instant) -> bool {
+        let ready_at = self
+            .draws(op)
+            .into_iter()
+            .filter_map(|(b, _)| {
+                b.fill(now);
+                b.ready_at()
+            })
+            .max();
+        if ready_at.is_some() && self.ready_at.is_none() {
+            self.delayed += 1;
+        }
+        self.ready_at = ready_at;
+        ready_at.is_none()
+    }
+
+    /*
+     * This op is going, take the tokens it uses.
+     */
+    pub(crate) fn take(&mut self, op: &BlockOp) {
+        for (b, n) in self.draws(op) {
+            b.tokens -= n;
+        }
+        self.ready_at = None;
+    }
+
+    pub(crate) fn ready_at(&self) -> Option<Instant> {
+        self.ready_at
+    }
+
+    pub(crate) fn take_delayed(&mut self) -> u64 {
+        std::mem::take(&mut self.delayed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read(len: usize) -> BlockOp {
+        BlockOp::Read {
+            offset: Block::new_512(0),
+            data: Buffer::new(len),
+        }
+    }
+
+    fn write(len: usize) -> BlockOp {
+        BlockOp::Write {
+            offset: Block::new_512(0),
+            data: Bytes::from(vec![0; len]),
+        }
+    }
+
+    fn qos(limits: QosLimits, now: Instant) -> Qos {
+        let mut qos = Qos::default();
+        qos.set(Some(limits), now);
+        qos
+    }
+
+    fn go(qos: &mut Qos, op: &BlockOp, now: Instant) -> bool {
+        if qos.ready(op, now) {
+            qos.take(op);
+            true
+        } else {
+            false
+        }
+    }
+
+    #[test]
+    fn burst_then_rate() {
+        let now = Instant::now();
+        let mut qos = qos(
+            QosLimits {
+                read_iops: Some(2),
+                burst_secs: Some(2),
+                ..Default::default()
+            },
+            now,
+        );
+
+        let op = read(512);
+        for _ in 0..4 {
+            assert!(go(&mut qos, &op, now));
+        }
+        assert!(!go(&mut qos, &op, now));
+        assert_eq!(qos.ready_at(), Some(now + Duration::from_millis(500)));
+
+        let later = now + Duration::from_millis(500);
+        assert!(go(&mut qos, &op, later));
+        assert!(!go(&mut qos, &op, later));
+
+        // Writes are not limited.
+        assert!(go(&mut qos, &write(512), later));
+        assert_eq!(qos.take_delayed(), 2);
+        assert_eq!(qos.take_delayed(), 0);
+    }
+
+    #[test]
+    fn large_io_goes_into_debt() {
+        let now = Instant::now();
+        let mut qos = qos(
+            QosLimits {
+                write_bytes: Some(1000),
+                ..Default::default()
+            },
+            now,
+        );
+
+        // More than the bucket holds still goes, but the next write waits
+        // until the debt is paid off.
+        assert!(go(&mut qos, &write(3000), now));
+        assert!(!go(&mut qos, &write(1), now));
+        assert_eq!(qos.ready_at(), Some(now + Duration::from_millis(2001)));
+        assert!(!go(&mut qos, &write(1), now + Duration::from_secs(2)));
+        assert!(go(&mut qos, &write(1), now + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn iop_size_counts_large_io() {
+        let now = Instant::now();
+        let mut qos = qos(
+            QosLimits {
+                read_iops: Some(4),
+                iop_size: Some(4096),
+                ..Default::default()
+            },
+            now,
+        );
+        assert!(go(&mut qos, &read(4096 * 3 + 1), now));
+        assert!(!go(&mut qos, &read(512), now));
+    }
+
+    #[test]
+    fn new_limits_keep_debt() {
+        let now = Instant::now();
+        let limits = QosLimits {
+            write_iops: Some(1),
+            ..Default::default()
+        };
+        let mut qos = qos(limits, now);
+        assert!(go(&mut qos, &write(512), now));
+        assert!(!go(&mut qos, &write(512), now));
+
+        qos.set(Some(limits), now);
+        assert!(!go(&mut qos, &write(512), now));
+
+        // No limits, no waiting.
+        qos.set(None, now);
+        assert!(go(&mut qos, &write(512), now));
+        assert_eq!(qos.limits(), None);
+    }
+
+    #[test]
+    fn zero_limit_is_refused() {
+        let limits = QosLimits {
+            read_bytes: Some(0),
+            ..Default::default()
+        };
+        assert!(check_qos_limits(&limits).is_err());
+        assert!(check_qos_limits(&QosLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn burst_too_large_is_refused() {
+        let limits = QosLimits {
+            write_bytes: Some(u64::MAX / 2),
+            burst_secs: Some(2),
+            ..Default::default()
+        };
+        assert!(check_qos_limits(&limits).is_err());
+
+        let limits = QosLimits {
+            write_bytes: Some(i64::MAX as u64 + 1),
+            ..Default::default()
+        };
+        assert!(check_qos_limits(&limits).is_err());
+
+        let limits = QosLimits {
+            write_bytes: Some(i64::MAX as u64 / 4),
+            burst_secs: Some(4),
+            ..Default::default()
+        };
+        assert!(check_qos_limits(&limits).is_ok());
+    }
+}
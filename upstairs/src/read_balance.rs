@@ -0,0 +1,258 @@
+// Copyright 2022 Oxide Computer Company
+use super::*;
+
+/*
+ * Sending a read to fewer than all the downstairs.
+ *
+ * Every downstairs holds the same data, so with a read policy other than
+ * ReadPolicy::All a read is sent to just one of them and the others skip
+ * it.  The response is still checked against its integrity hashes and the
+ * block record, it just isn't compared with the other downstairs.
+ *
+ * If the downstairs a read was sent to returns an error, the read is sent
+ * to another downstairs that has not already been sent a job that depends
+ * on it, as that downstairs could then return data written after the
+ * read.  The read only fails once no downstairs is left to try.
+ *
+ * For ReadPolicy::LowestLatency, the time each downstairs takes to answer
+ * a read is kept as a moving average.  A downstairs with no average yet
+ * is tried first, and every PROBE_INTERVAL reads one goes to the next
+ * downstairs in turn, so the average of a downstairs we are not using
+ * keeps up with how it is doing now.
+ */
+
+const PROBE_INTERVAL: u64 = 16;
+
+#[derive(Debug)]
+pub(crate) struct ReadBalance {
+    policy: ReadPolicy,
+    // The client that gets the next round robin read.
+    next: u8,
+    reads: u64,
+    // Indexed by client ID.
+    latency: Vec<Option<Duration>>,
+    // When each read still outstanding was sent, by (job ID, client ID).
+    sent: HashMap<(u64, u8), Instant>,
+}
+
+impl ReadBalance {
+    pub(crate) fn new(policy: ReadPolicy, replicas: u8) -> ReadBalance {
+        ReadBalance {
+            policy,
+            next: 0,
+            reads: 0,
+            latency: vec![None; replicas as usize],
+            sent: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn policy(&self) -> ReadPolicy {
+        self.policy
+    }
+
+    /*
+     * Pick the downstairs to send a new read to from these candidates, or
+     * None if it goes to every downstairs.
+     */
+    pub(crate) fn pick(&mut self, candidates: &[u8]) -> Option<u8> {
+        if candidates.is_empty() {
+            return None;
+        }
+        self.reads += 1;
+
+        match self.policy {
+            ReadPolicy::All => None,
+            ReadPolicy::RoundRobin => Some(self.round_robin(candidates)),
+            ReadPolicy::LowestLatency => {
+                if self.reads % PROBE_INTERVAL == 0 {
+                    return Some(self.round_robin(candidates));
+                }
+                let latency = &self.latency;
+                candidates
+                    .iter()
+                    .min_by_key(|c| match latency[**c as usize] {
+                        Some(l) => (true, l),
+                        None => (false, Duration::ZERO),
+                    })
+                    .copied()
+            }
+        }
+    }
+
+    /*
+     * The first candidate at or after the next client in turn.
+     */
+    fn round_robin(&mut self, candidates: &[u8]) -> u8 {
+        let replicas = self.latency.len() as u8;
+        let pick = (0..replicas)
+            .map(|i| (self.next + i) % replicas)
+            .find(|c| candidates.contains(c))
+            .unwrap();
+        self.next = (pick + 1) % replicas;
+        pick
+    }
+
+    pub(crate) fn sent(&mut self, ds_id: u64, client_id: u8) {
+        self.sent.insert((ds_id, client_id), Instant::now());
+    }
+
+    /*
+     * A read came back from this client, fold how long it took into its
+     * average.
+     */
+    pub(crate) fn done(&mut self, ds_id: u64, client_id: u8) {
+        if let Some(sent) = self.sent.remove(&(ds_id, client_id)) {
+            self.record(client_id, sent.elapsed());
+        }
+    }
+
+    fn record(&mut self, client_id: u8, elapsed: Duration) {
+        let latency = &mut self.latency[client_id as usize];
+        *latency = Some(match *latency {
+            Some(l) => (l * 7 + elapsed) / 8,
+            None => elapsed,
+        });
+    }
+
+    /*
+     * This job is retired, so forget any sends of it we never heard back
+     * about.
+     */
+    pub(crate) fn forget(&mut self, ds_id: u64) {
+        for client_id in 0..self.latency.len() as u8 {
+            self.sent.remove(&(ds_id, client_id));
+        }
+    }
+}
+
+impl Downstairs {
+    /*
+     * Under a read policy that sends a read to one downstairs, skip this
+     * new read for all the others.  Only an active downstairs that has
+     * not had errors can be picked.
+     */
+    pub(crate) fn read_pick(&mut self, io: &mut DownstairsIO) {
+        if !io.work.is_read() {
+            return;
+        }
+        let candidates: Vec<u8> = (0..self.replicas)
+            .filter(|c| {
+                self.ds_state[*c as usize] == DsState::Active
+                    && !self.downstairs_errors.contains_key(c)
+                    && io.state.get(c) == Some(&IOState::New)
+            })
+            .collect();
+        if let Some(pick) = self.read_balance.pick(&candidates) {
+            for (c, state) in io.state.iter_mut() {
+                if *c != pick && *state == IOState::New {
+                    *state = IOState::Skipped;
+                }
+            }
+        }
+    }
+
+    /*
+     * A read sent to just this client failed there, send it to another
+     * client if we can.  Return true if we did.
+     */
+    pub(crate) fn read_fallback(&mut self, ds_id: u64, client_id: u8) -> bool {
+        if self.read_balance.policy() == ReadPolicy::All {
+            return false;
+        }
+
+        /*
+         * A client that has been sent a job that depends on this read
+         * could give us data written after it.
+         */
+        let ds_active = &self.ds_active;
+        let dependents: Vec<&DownstairsIO> = ds_active
+            .values()
+            .filter(|job| job.work.deps().contains(&ds_id))
+            .collect();
+        let job = ds_active.get(&ds_id).unwrap();
+        let fallback = (0..self.replicas).find(|c| {
+            *c != client_id
+                && self.ds_state[*c as usize] == DsState::Active
+                && !self.downstairs_errors.contains_key(c)
+                && job.state.get(c) == Some(&IOState::Skipped)
+                && dependents
+                    .iter()
+                    .all(|d| d.state.get(c) == Some(&IOState::New))
+        });
+
+        match fallback {
+            Some(c) => {
+                warn!(
+                    self.log,
+                    "[{}] read {} failed, sending it to [{}]",
+                    client_id,
+                    ds_id,
+                    c
+                );
+                let job = self.ds_active.get_mut(&ds_id).unwrap();
+                job.state.insert(c, IOState::New);
+                self.reads_redirected += 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let mut rb = ReadBalance::new(ReadPolicy::RoundRobin, 3);
+        let picks: Vec<_> = (0..4).map(|_| rb.pick(&[0, 1, 2])).collect();
+        assert_eq!(picks, vec![Some(0), Some(1), Some(2), Some(0)]);
+
+        // A client that can't take the read is passed over.
+        assert_eq!(rb.pick(&[0, 2]), Some(2));
+        assert_eq!(rb.pick(&[0, 2]), Some(0));
+        assert_eq!(rb.pick(&[]), None);
+    }
+
+    #[test]
+    fn all_picks_nobody() {
+        let mut rb = ReadBalance::new(ReadPolicy::All, 3);
+        assert_eq!(rb.pick(&[0, 1, 2]), None);
+    }
+
+    #[test]
+    fn lowest_latency_prefers_fastest() {
+        let mut rb = ReadBalance::new(ReadPolicy::LowestLatency, 3);
+
+        // Clients we have no time for yet go first.
+        rb.record(0, Duration::from_millis(5));
+        rb.record(2, Duration::from_millis(1));
+        assert_eq!(rb.pick(&[0, 1, 2]), Some(1));
+        rb.record(1, Duration::from_millis(3));
+
+        assert_eq!(rb.pick(&[0, 1, 2]), Some(2));
+        assert_eq!(rb.pick(&[0, 1]), Some(1));
+
+        // Slow replies move a client back.
+        for _ in 0..20 {
+            rb.record(2, Duration::from_millis(10));
+        }
+        assert_eq!(rb.pick(&[0, 1, 2]), Some(1));
+    }
+
+    #[test]
+    fn lowest_latency_probes_others() {
+        let mut rb = ReadBalance::new(ReadPolicy::LowestLatency, 3);
+        rb.record(0, Duration::from_millis(1));
+        rb.record(1, Duration::from_millis(5));
+        rb.record(2, Duration::from_millis(5));
+
+        let picks: Vec<_> = (0..PROBE_INTERVAL * 3)
+            .map(|_| rb.pick(&[0, 1, 2]).unwrap())
+            .collect();
+        assert!(picks.contains(&1));
+        assert!(picks.contains(&2));
+        assert!(picks.iter().filter(|c| **c == 0).count() > picks.len() / 2);
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2022 Oxide Computer Company
+use super::*;
+
+use std::time::Instant;
+
+impl Guest {
+    /**
+     * Move every block of an encrypted volume from old_key to new_key,
+     * while the volume stays active.
+     *
+     * New writes are encrypted with new_key as soon as this starts, and
+     * reads accept either key, going by the key ID stored with each
+     * block.  Every block is then read through the upstairs, and the ones
+     * still encrypted with some other key are written back.
+     *
+     * The key IDs are the record of how far this got.  If the upstairs
+     * goes away part way through, open the volume again with new_key as
+     * the key and old_key as the old key, and run this again.  Blocks
+     * that were already moved are only read.
+     *
+     * Once every block has been moved and flushed, old_key is no longer
+     * accepted.
+     */
+    pub async fn rekey(
+        &self,
+        old_key: &[u8],
+        new_key: &[u8],
+        log: &Logger,
+    ) -> Result<(), CrucibleError> {
+        let old_key = VolumeKey::new(old_key)
+            .map_err(|e| CrucibleError::KeyRotationError(e.to_string()))?;
+        let new_key = VolumeKey::new(new_key)
+            .map_err(|e| CrucibleError::KeyRotationError(e.to_string()))?;
+
+        let data = Arc::new(Mutex::new(0));
+        self.send(BlockOp::Rekey {
+            old_key,
+            new_key,
+            data: data.clone(),
+        })
+        .await
+        .wait()
+        .await?;
+        let since = *data.lock().await;
+
+        let bs = self.get_block_size().await? as usize;
+        let end = self.total_size().await? / bs as u64;
+
+        // Move 128 KiB at a time.
+        let block_count = std::cmp::max(131072 / bs, 1) as u64;
+
+        info!(log, "Key rotation of {} blocks begins", end);
+        let rekey_start = Instant::now();
+
+        let showstep = std::cmp::max(end / 25, 1);
+        let mut showat = showstep;
+        let mut offset = 0;
+        let mut stale_blocks = 0;
+        while offset < end {
+            let count = std::cmp::min(block_count, end - offset) as usize;
+            let block = Block::new(offset, bs.trailing_zeros());
+            let buffer = Buffer::new(count * bs);
+
+            self.send(BlockOp::RekeyRead {
+                offset: block,
+                data: buffer.clone(),
+            })
+            .await
+            .wait()
+            .await?;
+
+            let stale: Vec<bool> = {
+                let owned = buffer.owned_vec().await;
+                (0..count).map(|i| owned[i * bs]).collect()
+            };
+
+            let stale_count = stale.iter().filter(|s| **s).count();
+            if stale_count > 0 {
+                let data = Bytes::from(buffer.as_vec().await.clone());
+                self.send(BlockOp::RekeyWrite {
+                    offset: block,
+                    data,
+                    stale,
+                    since,
+                })
+                .await
+                .wait()
+                .await?;
+                stale_blocks += stale_count;
+            }
+
+            offset += count as u64;
+            if offset >= showat {
+                info!(log, "Key rotation at block {} of {}", offset, end);
+                showat += showstep;
+            }
+        }
+
+        self.flush(None).await?;
+        self.send(BlockOp::RekeyDone).await.wait().await?;
+
+        info!(
+            log,
+            "Key rotation of {} blocks done in {} seconds, {} were on an \
+             old key",
+            end,
+            rekey_start.elapsed().as_secs(),
+            stale_blocks,
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright 2022 Oxide Computer Company
+use super::*;
+
+use crucible_common::{read_json_maybe, write_json};
+use std::path::{Path, PathBuf};
+
+/*
+ * Keeping track of a volume scrub.
+ *
+ * A scrub copies every block of a volume's read only parent into the
+ * volume, which can take a long time.  How far it has got is published as
+ * a ScrubStatus, both to anyone watching the volume and to the upstairs of
+ * each sub volume it writes to, which report it on their control server.
+ * Once a status says the scrub is done, the volume no longer reads from
+ * its read only parent.
+ *
+ * With a scrub state directory set, the scrub point is also saved there
+ * after each flush, in a file per volume.  The next scrub of that volume
+ * from the same read only parent starts from there instead of from the
+ * first block.
+ */
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ScrubStatus {
+    pub volume_id: Uuid,
+    /*
+     * Every block below this one has been copied from the read only parent.
+     */
+    pub scrub_point: u64,
+    /*
+     * The block the scrub stops at, the end of the read only parent.
+     */
+    pub end: u64,
+    pub percent_complete: f64,
+    /*
+     * Bytes copied per second since this scrub started, or picked up.
+     */
+    pub bytes_per_second: u64,
+    /*
+     * The volume no longer needs its read only parent.
+     */
+    pub done: bool,
+    /*
+     * Why the scrub stopped, if it failed.
+     */
+    pub error: Option<String>,
+}
+
+/*
+ * What is saved for a volume between scrubs.
+ */
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SavedScrub {
+    // The read only parent the scrub copies from.
+    parent: Uuid,
+    scrub_point: u64,
+}
+
+/*
+ * Where a scrub is, and how fast it is going.
+ */
+#[derive(Debug)]
+pub(crate) struct ScrubProgress {
+    volume_id: Uuid,
+    block_size: u64,
+    start: u64,
+    end: u64,
+    started: Instant,
+}
+
+impl ScrubProgress {
+    pub(crate) fn new(
+        volume_id: Uuid,
+        block_size: u64,
+        start: u64,
+        end: u64,
+    ) -> ScrubProgress {
+        ScrubProgress {
+            volume_id,
+            block_size,
+            start,
+            end,
+            started: Instant::now(),
+        }
+    }
+
+    /*
+     * The volume may shrink below the read only parent during the scrub,
+     * moving the end in.
+     */
+    pub(crate) fn set_end(&mut self, end: u64) {
+        self.end = end;
+    }
+
+    pub(crate) fn status(&self, scrub_point: u64, now: Instant) -> ScrubStatus {
+        let percent_complete = if self.end == 0 {
+            100.0
+        } else {
+            scrub_point as f64 * 100.0 / self.end as f64
+        };
+        let secs = now.saturating_duration_since(self.started).as_secs_f64();
+        let copied = scrub_point.saturating_sub(self.start) * self.block_size;
+        let bytes_per_second = if secs > 0.0 {
+            (copied as f64 / secs) as u64
+        } else {
+            0
+        };
+
+        ScrubStatus {
+            volume_id: self.volume_id,
+            scrub_point,
+            end: self.end,
+            percent_complete,
+            bytes_per_second,
+            done: scrub_point >= self.end,
+            error: None,
+        }
+    }
+}
+
+/*
+ * The scrub state a volume keeps.  It is shared by clones of the volume.
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct ScrubState {
+    // Where the scrub point is saved, if anywhere.
+    dir: Option<PathBuf>,
+    status: Arc<watch::Sender<Option<ScrubStatus>>>,
+}
+
+impl Default for ScrubState {
+    fn default() -> ScrubState {
+        ScrubState {
+            dir: None,
+            status: Arc::new(watch::channel(None).0),
+        }
+    }
+}
+
+impl ScrubState {
+    pub(crate) fn set_dir(&mut self, dir: PathBuf) {
+        self.dir = Some(dir);
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<Option<ScrubStatus>> {
+        self.status.subscribe()
+    }
+
+    pub(crate) fn publish(&self, status: ScrubStatus) {
+        self.status.send_replace(Some(status));
+    }
+
+    /*
+     * The last status published, with this error added.
+     */
+    pub(crate) fn failed(&self, error: &CrucibleError) -> Option<ScrubStatus> {
+        let mut status = self.status.borrow().clone()?;
+        status.error = Some(error.to_string());
+        Some(status)
+    }
+
+    fn path(dir: &Path, volume_id: Uuid) -> PathBuf {
+        dir.join(format!("{}.json", volume_id))
+    }
+
+    /*
+     * Where a scrub of this volume from this parent should start.  A file
+     * we can't read just means starting again from the first block.
+     */
+    pub(crate) fn load(
+        &self,
+        volume_id: Uuid,
+        parent: Uuid,
+        log: &Logger,
+    ) -> u64 {
+        let path = match &self.dir {
+            Some(dir) => ScrubState::path(dir, volume_id),
+            None => return 0,
+        };
+        match read_json_maybe::<_, SavedScrub>(&path) {
+            Ok(Some(saved)) if saved.parent == parent => saved.scrub_point,
+            Ok(_) => 0,
+            Err(e) => {
+                warn!(log, "Ignoring scrub state {:?}: {}", path, e);
+                0
+            }
+        }
+    }
+
+    /*
+     * Only call this once every block below the scrub point is flushed.
+     */
+    pub(crate) fn save(
+        &self,
+        volume_id: Uuid,
+        parent: Uuid,
+        scrub_point: u64,
+        log: &Logger,
+    ) {
+        let path = match &self.dir {
+            Some(dir) => ScrubState::path(dir, volume_id),
+            None => return,
+        };
+        let saved = SavedScrub {
+            parent,
+            scrub_point,
+        };
+        if let Err(e) = write_json(&path, &saved, true) {
+            warn!(log, "Failed to save scrub state {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn csl() -> Logger {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
+    }
+
+    #[test]
+    fn progress_status() {
+        let volume_id = Uuid::new_v4();
+        let progress = ScrubProgress::new(volume_id, 512, 100, 400);
+        let later = progress.started + Duration::from_secs(2);
+
+        let status = progress.status(300, later);
+        assert_eq!(status.volume_id, volume_id);
+        assert_eq!(status.percent_complete, 75.0);
+        assert_eq!(status.bytes_per_second, 200 * 512 / 2);
+        assert!(!status.done);
+
+        assert!(progress.status(400, later).done);
+        assert_eq!(
+            ScrubProgress::new(volume_id, 512, 0, 0)
+                .status(0, later)
+                .percent_complete,
+            100.0
+        );
+    }
+
+    #[test]
+    fn saved_for_the_same_parent() {
+        let dir = tempdir().unwrap();
+        let log = csl();
+        let volume_id = Uuid::new_v4();
+        let parent = Uuid::new_v4();
+
+        let mut state = ScrubState::default();
+        state.save(volume_id, parent, 10, &log);
+        assert_eq!(state.load(volume_id, parent, &log), 0);
+
+        state.set_dir(dir.path().to_path_buf());
+        assert_eq!(state.load(volume_id, parent, &log), 0);
+        state.save(volume_id, parent, 10, &log);
+        assert_eq!(state.load(volume_id, parent, &log), 10);
+
+        // A new parent, or another volume, starts again.
+        assert_eq!(state.load(volume_id, Uuid::new_v4(), &log), 0);
+        assert_eq!(state.load(Uuid::new_v4(), parent, &log), 0);
+
+        // So does a file that can't be read.
+        std::fs::write(ScrubState::path(dir.path(), volume_id), "not json")
+            .unwrap();
+        assert_eq!(state.load(volume_id, parent, &log), 0);
+    }
+
+    #[test]
+    fn failure_keeps_progress() {
+        let state = ScrubState::default();
+        let rx = state.subscribe();
+        let error = CrucibleError::IoError("gone".to_string());
+        assert!(state.failed(&error).is_none());
+
+        let progress = ScrubProgress::new(Uuid::new_v4(), 512, 0, 10);
+        state.publish(progress.status(5, Instant::now()));
+        assert_eq!(rx.borrow().as_ref().unwrap().scrub_point, 5);
+
+        let status = state.failed(&error).unwrap();
+        assert_eq!(status.scrub_point, 5);
+        assert_eq!(status.error, Some("IO Error: gone".to_string()));
+    }
+}
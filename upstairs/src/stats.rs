@@ -48,6 +48,42 @@ pub struct Flush {
     #[datum]
     pub count: Cumulative<i64>,
 }
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct CompressionSaved {
+    /// Count of bytes compression kept off the network
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct BlocksHealed {
+    /// Count of corrupt blocks rewritten from another downstairs
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct QosDelayed {
+    /// Count of IOs held back by a QoS limit
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct FaultedJobTimeout {
+    /// Count of downstairs faulted for leaving a job unanswered too long
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct FaultedQueueFull {
+    /// Count of downstairs faulted for having too many jobs outstanding
+    #[datum]
+    pub count: Cumulative<i64>,
+}
+#[derive(Debug, Default, Copy, Clone, Metric)]
+pub struct FaultedResizeMismatch {
+    /// Count of downstairs faulted for a region size that differs from ours
+    #[datum]
+    pub count: Cumulative<i64>,
+}
 
 // All the counter stats in one struct.
 #[derive(Clone, Debug)]
@@ -59,6 +95,12 @@ pub struct UpCountStat {
     read_count: Read,
     read_bytes: ReadBytes,
     flush_count: Flush,
+    compression_saved: CompressionSaved,
+    blocks_healed: BlocksHealed,
+    qos_delayed: QosDelayed,
+    faulted_job_timeout: FaultedJobTimeout,
+    faulted_queue_full: FaultedQueueFull,
+    faulted_resize_mismatch: FaultedResizeMismatch,
 }
 
 impl UpCountStat {
@@ -71,6 +113,12 @@ impl UpCountStat {
             read_count: Default::default(),
             read_bytes: Default::default(),
             flush_count: Default::default(),
+            compression_saved: Default::default(),
+            blocks_healed: Default::default(),
+            qos_delayed: Default::default(),
+            faulted_job_timeout: Default::default(),
+            faulted_queue_full: Default::default(),
+            faulted_resize_mismatch: Default::default(),
         }
     }
 }
@@ -110,6 +158,32 @@ impl UpStatOuter {
         let datum = ups.flush_count.datum_mut();
         *datum += 1;
     }
+    pub async fn add_compression_saved(&self, bytes: i64) {
+        let mut ups = self.up_stat_wrap.lock().await;
+        let datum = ups.compression_saved.datum_mut();
+        *datum += bytes;
+    }
+    pub async fn add_blocks_healed(&self, blocks: i64) {
+        let mut ups = self.up_stat_wrap.lock().await;
+        let datum = ups.blocks_healed.datum_mut();
+        *datum += blocks;
+    }
+    pub async fn add_qos_delayed(&self, ios: i64) {
+        let mut ups = self.up_stat_wrap.lock().await;
+        let datum = ups.qos_delayed.datum_mut();
+        *datum += ios;
+    }
+    pub(crate) async fn add_downstairs_faulted(&self, reason: FaultReason) {
+        let mut ups = self.up_stat_wrap.lock().await;
+        let datum = match reason {
+            FaultReason::JobTimeout => ups.faulted_job_timeout.datum_mut(),
+            FaultReason::QueueFull => ups.faulted_queue_full.datum_mut(),
+            FaultReason::ResizeMismatch => {
+                ups.faulted_resize_mismatch.datum_mut()
+            }
+        };
+        *datum += 1;
+    }
 }
 
 // This trait is what is called to update the data to send to Oximeter.
@@ -125,7 +199,7 @@ impl Producer for UpStatOuter {
             tokio::runtime::Handle::current().block_on(self.up_stat_wrap.lock())
         });
 
-        let mut data = Vec::with_capacity(6);
+        let mut data = Vec::with_capacity(11);
         let name = ups.stat_name;
 
         data.push(Sample::new(&name, &ups.activated_count));
@@ -134,6 +208,12 @@ impl Producer for UpStatOuter {
         data.push(Sample::new(&name, &ups.write_bytes));
         data.push(Sample::new(&name, &ups.read_count));
         data.push(Sample::new(&name, &ups.read_bytes));
+        data.push(Sample::new(&name, &ups.compression_saved));
+        data.push(Sample::new(&name, &ups.blocks_healed));
+        data.push(Sample::new(&name, &ups.qos_delayed));
+        data.push(Sample::new(&name, &ups.faulted_job_timeout));
+        data.push(Sample::new(&name, &ups.faulted_queue_full));
+        data.push(Sample::new(&name, &ups.faulted_resize_mismatch));
 
         // Yield the available samples.
         Ok(Box::new(data.into_iter()))
@@ -104,13 +104,21 @@ mod up_test {
         def.set_extent_count(10);
 
         let opts = CrucibleOpts {
-            target: vec![],
+            target: (0..3)
+                .map(|p| {
+                    SocketAddr::new(
+                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                        3810 + p,
+                    )
+                })
+                .collect(),
             lossy: false,
             key: None,
             ..Default::default()
         };
 
-        Upstairs::new(&opts, 0, def, Arc::new(Guest::new()), csl())
+        Upstairs::new(&opts, 0, def, Arc::new(Guest::new()), None, csl())
+            .unwrap()
     }
 
     /*
@@ -271,10 +279,11 @@ mod up_test {
 
         let orig_block = block;
 
-        let (nonce, tag, _) = context.encrypt_in_place(&mut block[..])?;
+        let (nonce, tag, key_id, _) =
+            context.encrypt_in_place(&mut block[..], 0, 0, 1)?;
         assert_ne!(block, orig_block);
 
-        context.decrypt_in_place(&mut block[..], &nonce, &tag)?;
+        context.decrypt_in_place(&mut block[..], &nonce, &tag, key_id, 0, 0)?;
         assert_eq!(block, orig_block);
 
         Ok(())
@@ -294,14 +303,22 @@ mod up_test {
 
         let orig_block = block;
 
-        let (_, tag, _) = context.encrypt_in_place(&mut block[..])?;
+        let (_, tag, key_id, _) =
+            context.encrypt_in_place(&mut block[..], 0, 0, 1)?;
         assert_ne!(block, orig_block);
 
         let nonce = context.get_random_nonce();
 
         let block_before_failing_decrypt_in_place = block;
 
-        let result = context.decrypt_in_place(&mut block[..], &nonce, &tag);
+        let result = context.decrypt_in_place(
+            &mut block[..],
+            &nonce,
+            &tag,
+            key_id,
+            0,
+            0,
+        );
         assert!(result.is_err());
 
         /*
@@ -328,14 +345,22 @@ mod up_test {
 
         let orig_block = block;
 
-        let (nonce, mut tag, _) = context.encrypt_in_place(&mut block[..])?;
+        let (nonce, mut tag, key_id, _) =
+            context.encrypt_in_place(&mut block[..], 0, 0, 1)?;
         assert_ne!(block, orig_block);
 
         tag[2] = tag[2].wrapping_add(1);
 
         let block_before_failing_decrypt_in_place = block;
 
-        let result = context.decrypt_in_place(&mut block[..], &nonce, &tag);
+        let result = context.decrypt_in_place(
+            &mut block[..],
+            &nonce,
+            &tag,
+            key_id,
+            0,
+            0,
+        );
         assert!(result.is_err());
 
         /*
@@ -348,6 +373,215 @@ mod up_test {
         Ok(())
     }
 
+    #[test]
+    pub fn test_upstairs_encryption_context_wrong_block() -> Result<()> {
+        use rand::{thread_rng, Rng};
+
+        let key_bytes =
+            base64::decode("EVrH+ABhMP0MLfxynCalDq1vWCCWCWFfsSsJoJeDCx8=")
+                .unwrap();
+        let context = EncryptionContext::new(key_bytes, 512);
+
+        let mut block = [0u8; 512];
+        thread_rng().fill(&mut block[..]);
+
+        let (nonce, tag, key_id, _) =
+            context.encrypt_in_place(&mut block[..], 0, 1, 1)?;
+        let encrypted_block = block;
+
+        /*
+         * The block location is bound into the tag, so a block moved to
+         * another offset or extent must not decrypt.
+         */
+        let result = context.decrypt_in_place(
+            &mut block[..],
+            &nonce,
+            &tag,
+            key_id,
+            0,
+            2,
+        );
+        assert!(result.is_err());
+        assert_eq!(encrypted_block, block);
+
+        let result = context.decrypt_in_place(
+            &mut block[..],
+            &nonce,
+            &tag,
+            key_id,
+            1,
+            1,
+        );
+        assert!(result.is_err());
+        assert_eq!(encrypted_block, block);
+
+        context.decrypt_in_place(&mut block[..], &nonce, &tag, key_id, 0, 1)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_upstairs_encryption_context_legacy_block() -> Result<()> {
+        let key_bytes =
+            base64::decode("EVrH+ABhMP0MLfxynCalDq1vWCCWCWFfsSsJoJeDCx8=")
+                .unwrap();
+        let context = EncryptionContext::new(key_bytes.clone(), 512);
+
+        /*
+         * A block written before keys had an ID, with no associated data,
+         * still decrypts.
+         */
+        let nonce = context.get_random_nonce();
+        let mut block = [3u8; 512];
+        let tag = Aes256GcmSiv::new(Key::from_slice(&key_bytes))
+            .encrypt_in_place_detached(&nonce, b"", &mut block)
+            .unwrap();
+        context.decrypt_in_place(&mut block[..], &nonce, &tag, 0, 0, 1)?;
+        assert_eq!(block, [3u8; 512]);
+
+        /*
+         * A block written now can't pass itself off as one from before,
+         * which would drop where it lives from the tag.
+         */
+        let (nonce, tag, _, _) =
+            context.encrypt_in_place(&mut block[..], 0, 1, 1)?;
+        let encrypted_block = block;
+        let result =
+            context.decrypt_in_place(&mut block[..], &nonce, &tag, 0, 0, 1);
+        assert!(result.is_err());
+        assert_eq!(encrypted_block, block);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_upstairs_encryption_context_write_generation() -> Result<()> {
+        let key_bytes =
+            base64::decode("EVrH+ABhMP0MLfxynCalDq1vWCCWCWFfsSsJoJeDCx8=")
+                .unwrap();
+        let context = EncryptionContext::new(key_bytes, 512);
+
+        let mut block = [0u8; 512];
+        let write_gen = 3;
+        let (nonce, _, _, _) =
+            context.encrypt_in_place(&mut block[..], 0, 1, write_gen)?;
+        assert_eq!(EncryptionContext::write_generation(&nonce), write_gen);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_upstairs_encryption_context_rotate() -> Result<()> {
+        let old_bytes =
+            base64::decode("EVrH+ABhMP0MLfxynCalDq1vWCCWCWFfsSsJoJeDCx8=")
+                .unwrap();
+        let new_bytes =
+            base64::decode("ClENKTXD2bCyXSHnKXY7GGnk+NvQKbwpatjWP2fJzk0=")
+                .unwrap();
+        let old_key = VolumeKey::new(&old_bytes)?;
+        let new_key = VolumeKey::new(&new_bytes)?;
+        assert_ne!(old_key.id(), new_key.id());
+        assert_ne!(old_key.id(), 0);
+
+        let context = EncryptionContext::new(old_bytes.clone(), 512);
+        assert_eq!(context.key_id(), old_key.id());
+
+        let mut old_block = [7u8; 512];
+        let (old_nonce, old_tag, old_id, _) =
+            context.encrypt_in_place(&mut old_block[..], 0, 1, 1)?;
+        assert_eq!(old_id, old_key.id());
+
+        context.rotate(old_key.clone(), new_key.clone())?;
+        assert_eq!(context.key_id(), new_key.id());
+
+        // New blocks use the new key, and old blocks still decrypt
+        let mut new_block = [8u8; 512];
+        let (new_nonce, new_tag, new_id, _) =
+            context.encrypt_in_place(&mut new_block[..], 0, 2, 1)?;
+        assert_eq!(new_id, new_key.id());
+
+        let mut block = old_block;
+        context.decrypt_in_place(
+            &mut block[..],
+            &old_nonce,
+            &old_tag,
+            old_id,
+            0,
+            1,
+        )?;
+        assert_eq!(block, [7u8; 512]);
+
+        // A block from before keys had an ID tries every key
+        let mut legacy_block = [9u8; 512];
+        let legacy_tag = Aes256GcmSiv::new(Key::from_slice(&old_bytes))
+            .encrypt_in_place_detached(&old_nonce, b"", &mut legacy_block)
+            .unwrap();
+        let mut block = legacy_block;
+        context.decrypt_in_place(
+            &mut block[..],
+            &old_nonce,
+            &legacy_tag,
+            0,
+            0,
+            1,
+        )?;
+        assert_eq!(block, [9u8; 512]);
+
+        // Picking the rotation up again is fine
+        context.rotate(old_key, new_key)?;
+        assert_eq!(context.key_id(), new_id);
+
+        // Once the old key is dropped, old blocks no longer decrypt
+        context.drop_retired_keys();
+        let mut block = old_block;
+        let result = context.decrypt_in_place(
+            &mut block[..],
+            &old_nonce,
+            &old_tag,
+            old_id,
+            0,
+            1,
+        );
+        assert!(result.is_err());
+        assert_eq!(block, old_block);
+
+        let mut block = new_block;
+        context.decrypt_in_place(
+            &mut block[..],
+            &new_nonce,
+            &new_tag,
+            new_id,
+            0,
+            2,
+        )?;
+        assert_eq!(block, [8u8; 512]);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_upstairs_encryption_context_rotate_wrong_key() -> Result<()> {
+        let volume_bytes =
+            base64::decode("EVrH+ABhMP0MLfxynCalDq1vWCCWCWFfsSsJoJeDCx8=")
+                .unwrap();
+        let context = EncryptionContext::new(volume_bytes.clone(), 512);
+
+        let volume_key = VolumeKey::new(&volume_bytes)?;
+        let other_key = VolumeKey::new(&[1u8; 32])?;
+        let new_key = VolumeKey::new(&[2u8; 32])?;
+
+        // The old key has to be the one the volume uses
+        assert!(context.rotate(other_key, new_key.clone()).is_err());
+
+        // The new key has to be a different one
+        assert!(context.rotate(volume_key.clone(), volume_key).is_err());
+
+        assert_eq!(context.key_id(), VolumeKey::new(&volume_bytes)?.id());
+        assert!(VolumeKey::new(&[1u8; 16]).is_err());
+
+        Ok(())
+    }
+
     // Validate that an encrypted read response with one context can be
     // decrypted
     #[test]
@@ -365,7 +599,8 @@ mod up_test {
 
         let original_data = data.clone();
 
-        let (nonce, tag, _) = context.encrypt_in_place(&mut data[..])?;
+        let (nonce, tag, key_id, _) =
+            context.encrypt_in_place(&mut data[..], 0, 0, 1)?;
 
         assert_ne!(original_data, data);
 
@@ -382,6 +617,7 @@ mod up_test {
                     crucible_protocol::EncryptionContext {
                         nonce: nonce.to_vec(),
                         tag: tag.to_vec(),
+                        key_id,
                     },
                 ),
             }],
@@ -422,7 +658,8 @@ mod up_test {
 
         let original_data = data.clone();
 
-        let (nonce, tag, _) = context.encrypt_in_place(&mut data[..])?;
+        let (nonce, tag, key_id, _) =
+            context.encrypt_in_place(&mut data[..], 0, 0, 1)?;
 
         assert_ne!(original_data, data);
 
@@ -441,6 +678,7 @@ mod up_test {
                         crucible_protocol::EncryptionContext {
                             nonce: thread_rng().gen::<[u8; 12]>().to_vec(),
                             tag: thread_rng().gen::<[u8; 16]>().to_vec(),
+                            key_id: 0,
                         },
                     ),
                 },
@@ -451,6 +689,7 @@ mod up_test {
                         crucible_protocol::EncryptionContext {
                             nonce: nonce.to_vec(),
                             tag: tag.to_vec(),
+                            key_id,
                         },
                     ),
                 },
@@ -461,6 +700,7 @@ mod up_test {
                         crucible_protocol::EncryptionContext {
                             nonce: thread_rng().gen::<[u8; 12]>().to_vec(),
                             tag: thread_rng().gen::<[u8; 16]>().to_vec(),
+                            key_id: 0,
                         },
                     ),
                 },
@@ -495,6 +735,75 @@ mod up_test {
     //
     //   hash1 == hash2
     //
+    // Validate that an encrypted block read back from a different offset
+    // than it was written to fails decryption
+    #[test]
+    pub fn test_upstairs_validate_encrypted_read_response_wrong_offset() {
+        use rand::{thread_rng, Rng};
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(&mut key[..]);
+        let context = EncryptionContext::new(key.clone(), 512);
+
+        let mut data = BytesMut::with_capacity(512);
+        data.resize(512, 0u8);
+        thread_rng().fill(&mut data[..]);
+
+        let (nonce, tag, key_id, _) =
+            context.encrypt_in_place(&mut data[..], 0, 3, 1).unwrap();
+
+        let read_response_hash = integrity_hash(&[&nonce, &tag, &data[..]]);
+
+        let mut read_response = ReadResponse {
+            eid: 0,
+            offset: Block::new_512(4),
+            data,
+            block_contexts: vec![BlockContext {
+                hash: read_response_hash,
+                encryption_context: Some(
+                    crucible_protocol::EncryptionContext {
+                        nonce: nonce.to_vec(),
+                        tag: tag.to_vec(),
+                        key_id,
+                    },
+                ),
+            }],
+        };
+
+        let result = Downstairs::validate_encrypted_read_response(
+            &mut read_response,
+            &Arc::new(context),
+            &csl(),
+        );
+        assert!(matches!(result, Err(CrucibleError::DecryptionError)));
+    }
+
+    // Validate that a block with data but no block context is an error
+    #[test]
+    pub fn test_upstairs_validate_encrypted_read_response_stripped_context() {
+        use rand::{thread_rng, Rng};
+        let mut key = vec![0u8; 32];
+        thread_rng().fill(&mut key[..]);
+        let context = EncryptionContext::new(key.clone(), 512);
+
+        let mut data = BytesMut::with_capacity(512);
+        data.resize(512, 0u8);
+        thread_rng().fill(&mut data[..]);
+
+        let mut read_response = ReadResponse {
+            eid: 0,
+            offset: Block::new_512(0),
+            data,
+            block_contexts: vec![],
+        };
+
+        let result = Downstairs::validate_encrypted_read_response(
+            &mut read_response,
+            &Arc::new(context),
+            &csl(),
+        );
+        assert!(matches!(result, Err(CrucibleError::BlockIntegrityError(_))));
+    }
+
     // then write a test which validates that an encrypted read response with
     // multiple contexts that match the integrity hash (where only one is
     // correct) can be decrypted.
@@ -890,84 +1199,317 @@ mod up_test {
         assert_eq!(ds.completed.len(), 1);
     }
 
+    fn make_upstairs_with_quorum(
+        replicas: u16,
+        write_quorum: Option<usize>,
+    ) -> Result<Arc<Upstairs>, CrucibleError> {
+        let opts = CrucibleOpts {
+            target: (0..replicas)
+                .map(|p| {
+                    SocketAddr::new(
+                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                        3810 + p,
+                    )
+                })
+                .collect(),
+            write_quorum,
+            ..Default::default()
+        };
+
+        Upstairs::new(
+            &opts,
+            0,
+            RegionDefinition::default(),
+            Arc::new(Guest::new()),
+            None,
+            csl(),
+        )
+    }
+
     #[tokio::test]
-    async fn work_read_one_ok() {
-        let upstairs = Upstairs::default();
-        upstairs.set_active().await.unwrap();
-        let mut ds = upstairs.downstairs.lock().await;
+    async fn replicas_from_targets() {
+        // The replica count comes from the targets, and the write quorum
+        // defaults to a majority of them.
+        for (replicas, quorum) in [(1, 1), (2, 2), (3, 2), (4, 3), (5, 3)] {
+            let up = make_upstairs_with_quorum(replicas, None).unwrap();
+            let ds = up.downstairs.lock().await;
+            assert_eq!(ds.replicas, replicas as u8);
+            assert_eq!(ds.write_quorum, quorum);
+            assert_eq!(ds.ds_state.len(), replicas as usize);
+            assert_eq!(ds.ds_target.len(), replicas as usize);
+        }
 
-        let next_id = ds.next_id();
+        let up = make_upstairs_with_quorum(2, Some(1)).unwrap();
+        assert_eq!(up.downstairs.lock().await.write_quorum, 1);
+    }
 
-        let request = ReadRequest {
-            eid: 0,
-            offset: Block::new_512(7),
-        };
-        let op = create_read_eob(
+    #[tokio::test]
+    async fn write_quorum_larger_than_replicas() {
+        let res = make_upstairs_with_quorum(3, Some(4));
+        assert!(matches!(res, Err(CrucibleError::InvalidReplicas(_))));
+    }
+
+    #[tokio::test]
+    async fn write_quorum_zero() {
+        let res = make_upstairs_with_quorum(3, Some(0));
+        assert!(matches!(res, Err(CrucibleError::InvalidReplicas(_))));
+    }
+
+    #[tokio::test]
+    async fn no_targets() {
+        let res = make_upstairs_with_quorum(0, None);
+        assert!(matches!(res, Err(CrucibleError::InvalidReplicas(_))));
+    }
+
+    #[tokio::test]
+    async fn work_write_two_replicas_quorum_one() {
+        // A two way mirror with a write quorum of one acks a write as soon
+        // as either downstairs has it.
+        let mut ds = Downstairs::new(csl(), 2, 1);
+        let next_id = ds.next_id();
+        ds.enqueue(create_write_eob(
             next_id,
             vec![],
             10,
-            vec![request.clone()],
+            vec![],
+            false,
             ImpactedBlocks::default(),
-        );
-
-        ds.enqueue(op);
-
-        ds.in_progress(next_id, 0);
-        ds.in_progress(next_id, 1);
-        ds.in_progress(next_id, 2);
+        ));
 
-        let response =
-            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
+        assert_eq!(ds.ds_active.get(&next_id).unwrap().state.len(), 2);
+        assert!(ds.in_progress(next_id, 0).is_some());
+        assert!(ds.in_progress(next_id, 1).is_some());
 
         assert!(ds
-            .process_ds_completion(next_id, 0, response, &None, UpState::Active)
+            .process_ds_completion(
+                next_id,
+                1,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
             .unwrap());
         assert_eq!(ds.ackable_work().len(), 1);
-        assert_eq!(ds.completed.len(), 0);
-
-        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
-        assert_eq!(state, AckStatus::AckReady);
         ds.ack(next_id);
 
-        let response =
-            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
-
-        assert!(!ds
-            .process_ds_completion(next_id, 1, response, &None, UpState::Active)
-            .unwrap());
-        assert_eq!(ds.ackable_work().len(), 0);
-        assert_eq!(ds.completed.len(), 0);
-
-        let response =
-            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
-
         assert!(!ds
-            .process_ds_completion(next_id, 2, response, &None, UpState::Active)
+            .process_ds_completion(
+                next_id,
+                0,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
             .unwrap());
-        assert_eq!(ds.ackable_work().len(), 0);
-        // A flush is required to move work to completed
-        assert_eq!(ds.completed.len(), 0);
+        assert!(ds.result(next_id).is_ok());
     }
 
     #[tokio::test]
-    async fn work_read_one_bad_two_ok() {
-        let upstairs = Upstairs::default();
-        upstairs.set_active().await.unwrap();
-        let mut ds = upstairs.downstairs.lock().await;
-
+    async fn work_flush_five_replicas_quorum_three() {
+        // With five downstairs and a write quorum of three, a flush is
+        // acked on the third ok, and only retired once all five are done.
+        let mut ds = Downstairs::new(csl(), 5, 3);
         let next_id = ds.next_id();
-
-        let request = ReadRequest {
-            eid: 0,
-            offset: Block::new_512(7),
-        };
-        let op = create_read_eob(
+        ds.enqueue(create_flush(
             next_id,
             vec![],
             10,
-            vec![request.clone()],
+            0,
+            0,
+            None,
             ImpactedBlocks::default(),
-        );
+        ));
+        for cid in 0..5 {
+            assert!(ds.in_progress(next_id, cid).is_some());
+        }
+
+        for cid in 0..2 {
+            assert!(!ds
+                .process_ds_completion(
+                    next_id,
+                    cid,
+                    Ok(vec![]),
+                    &None,
+                    UpState::Active
+                )
+                .unwrap());
+            assert_eq!(ds.ackable_work().len(), 0);
+        }
+        assert!(ds
+            .process_ds_completion(
+                next_id,
+                2,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert_eq!(ds.ackable_work().len(), 1);
+        ds.ack(next_id);
+
+        for cid in 3..5 {
+            assert_eq!(ds.completed.len(), 0);
+            assert!(!ds
+                .process_ds_completion(
+                    next_id,
+                    cid,
+                    Ok(vec![]),
+                    &None,
+                    UpState::Active
+                )
+                .unwrap());
+        }
+        assert_eq!(ds.completed.len(), 1);
+        assert!(ds.ds_active.is_empty());
+    }
+
+    #[tokio::test]
+    async fn work_flush_five_replicas_errors() {
+        // With five downstairs and a write quorum of three, two errors
+        // still leave a quorum, but three do not.
+        for errors in [2, 3] {
+            let mut ds = Downstairs::new(csl(), 5, 3);
+            let next_id = ds.next_id();
+            ds.enqueue(create_flush(
+                next_id,
+                vec![],
+                10,
+                0,
+                0,
+                None,
+                ImpactedBlocks::default(),
+            ));
+
+            for cid in 0..5 {
+                ds.in_progress(next_id, cid);
+                let response = if cid < errors {
+                    Err(CrucibleError::GenericError("bad".to_string()))
+                } else {
+                    Ok(vec![])
+                };
+                ds.process_ds_completion(
+                    next_id,
+                    cid,
+                    response,
+                    &None,
+                    UpState::Active,
+                )
+                .unwrap();
+            }
+
+            assert_eq!(ds.result(next_id).is_ok(), errors == 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn work_read_five_replicas_all_bad() {
+        // A read only fails when every downstairs returns an error.
+        let mut ds = Downstairs::new(csl(), 5, 3);
+        let next_id = ds.next_id();
+        ds.enqueue(create_read_eob(
+            next_id,
+            vec![],
+            10,
+            vec![ReadRequest {
+                eid: 0,
+                offset: Block::new_512(7),
+            }],
+            ImpactedBlocks::default(),
+        ));
+
+        for cid in 0..5 {
+            ds.in_progress(next_id, cid);
+            ds.process_ds_completion(
+                next_id,
+                cid,
+                Err(CrucibleError::GenericError("bad".to_string())),
+                &None,
+                UpState::Active,
+            )
+            .unwrap();
+            assert_eq!(ds.result(next_id).is_ok(), cid < 4);
+        }
+        assert_eq!(ds.ackable_work().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn work_read_one_ok() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active().await.unwrap();
+        let mut ds = upstairs.downstairs.lock().await;
+
+        let next_id = ds.next_id();
+
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+        };
+        let op = create_read_eob(
+            next_id,
+            vec![],
+            10,
+            vec![request.clone()],
+            ImpactedBlocks::default(),
+        );
+
+        ds.enqueue(op);
+
+        ds.in_progress(next_id, 0);
+        ds.in_progress(next_id, 1);
+        ds.in_progress(next_id, 2);
+
+        let response =
+            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
+
+        assert!(ds
+            .process_ds_completion(next_id, 0, response, &None, UpState::Active)
+            .unwrap());
+        assert_eq!(ds.ackable_work().len(), 1);
+        assert_eq!(ds.completed.len(), 0);
+
+        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
+        assert_eq!(state, AckStatus::AckReady);
+        ds.ack(next_id);
+
+        let response =
+            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
+
+        assert!(!ds
+            .process_ds_completion(next_id, 1, response, &None, UpState::Active)
+            .unwrap());
+        assert_eq!(ds.ackable_work().len(), 0);
+        assert_eq!(ds.completed.len(), 0);
+
+        let response =
+            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
+
+        assert!(!ds
+            .process_ds_completion(next_id, 2, response, &None, UpState::Active)
+            .unwrap());
+        assert_eq!(ds.ackable_work().len(), 0);
+        // A flush is required to move work to completed
+        assert_eq!(ds.completed.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn work_read_one_bad_two_ok() {
+        let upstairs = Upstairs::default();
+        upstairs.set_active().await.unwrap();
+        let mut ds = upstairs.downstairs.lock().await;
+
+        let next_id = ds.next_id();
+
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+        };
+        let op = create_read_eob(
+            next_id,
+            vec![],
+            10,
+            vec![request.clone()],
+            ImpactedBlocks::default(),
+        );
 
         ds.enqueue(op);
 
@@ -1319,7 +1861,7 @@ mod up_test {
     #[tokio::test]
     async fn work_read_hash_mismatch_third() {
         // Test that a hash mismatch on the third response will trigger a panic.
-        let mut ds = Downstairs::new(csl());
+        let mut ds = Downstairs::new(csl(), 3, 2);
 
         let id = ds.next_id();
 
@@ -2247,114 +2789,34 @@ mod up_test {
     }
 
     #[tokio::test]
-    async fn work_delay_completion_flush_order_write() {
-        work_delay_completion_flush_order(false).await;
-    }
-
-    #[tokio::test]
-    async fn work_delay_completion_flush_order_write_unwritten() {
-        work_delay_completion_flush_order(true).await;
-    }
-
-    async fn work_delay_completion_flush_order(is_write_unwritten: bool) {
-        // Verify that a write/write_unwritten remains on the active queue
-        // until a flush comes through and clears it.  In this case, we only
-        // complete 2 of 3 for each IO.  We later come back and finish the
-        // 3rd IO and the flush, which then allows the work to be completed.
-        // Also, we mix up which client finishes which job first.
+    async fn work_completed_discard() {
+        // Verify that a discard is ready to ack after two downstairs
+        // complete it, and that it stays on the active queue until a flush.
         let upstairs = Upstairs::default();
         upstairs.set_active().await.unwrap();
         let mut ds = upstairs.downstairs.lock().await;
 
-        // Build two writes, put them on the work queue.
-        let id1 = ds.next_id();
-        let id2 = ds.next_id();
+        let next_id = ds.next_id();
 
-        let op = create_write_eob(
-            id1,
+        let op = create_discard_eob(
+            next_id,
             vec![],
             10,
-            vec![crucible_protocol::Write {
-                eid: 0,
-                offset: Block::new_512(7),
-                data: Bytes::from(vec![1]),
-                block_context: BlockContext {
-                    encryption_context: None,
-                    hash: 0,
-                },
-            }],
-            is_write_unwritten,
-            ImpactedBlocks::default(),
-        );
-        ds.enqueue(op);
-
-        let op = create_write_eob(
-            id2,
-            vec![],
-            1,
-            vec![crucible_protocol::Write {
+            vec![DiscardRequest {
                 eid: 0,
                 offset: Block::new_512(7),
-                data: Bytes::from(vec![1]),
-                block_context: BlockContext {
-                    encryption_context: None,
-                    hash: 0,
-                },
             }],
-            is_write_unwritten,
-            ImpactedBlocks::default(),
-        );
-        ds.enqueue(op);
-
-        // Submit the two writes, to 2/3 of the downstairs.
-        assert!(ds.in_progress(id1, 0).is_some());
-        assert!(ds.in_progress(id1, 1).is_some());
-        assert!(ds.in_progress(id2, 1).is_some());
-        assert!(ds.in_progress(id2, 2).is_some());
-
-        // Complete the writes that we sent to the 2 downstairs.
-        assert!(!ds
-            .process_ds_completion(id1, 0, Ok(vec![]), &None, UpState::Active)
-            .unwrap());
-        assert!(ds
-            .process_ds_completion(id1, 1, Ok(vec![]), &None, UpState::Active)
-            .unwrap());
-        assert!(!ds
-            .process_ds_completion(id2, 1, Ok(vec![]), &None, UpState::Active)
-            .unwrap());
-        assert!(ds
-            .process_ds_completion(id2, 2, Ok(vec![]), &None, UpState::Active)
-            .unwrap());
-
-        // Ack the writes to the guest.
-        ds.ack(id1);
-        ds.ack(id2);
-
-        // Work stays on active queue till the flush.
-        assert_eq!(ds.ackable_work().len(), 0);
-        assert_eq!(ds.completed.len(), 0);
-
-        // Create and enqueue the flush.
-        let flush_id = ds.next_id();
-        let op = create_flush(
-            flush_id,
-            vec![],
-            10,
-            0,
-            0,
-            None,
             ImpactedBlocks::default(),
         );
         ds.enqueue(op);
 
-        // Send the flush to two downstairs.
-        ds.in_progress(flush_id, 0);
-        ds.in_progress(flush_id, 2);
+        ds.in_progress(next_id, 0);
+        ds.in_progress(next_id, 1);
+        ds.in_progress(next_id, 2);
 
-        // Complete the flush on those downstairs.
         assert!(!ds
             .process_ds_completion(
-                flush_id,
+                next_id,
                 0,
                 Ok(vec![]),
                 &None,
@@ -2363,236 +2825,680 @@ mod up_test {
             .unwrap());
         assert!(ds
             .process_ds_completion(
-                flush_id,
-                2,
+                next_id,
+                1,
                 Ok(vec![]),
                 &None,
                 UpState::Active
             )
             .unwrap());
-
-        // Ack the flush
-        ds.ack(flush_id);
-
-        // Should not retire yet
-        ds.retire_check(flush_id);
-
-        assert_eq!(ds.ackable_work().len(), 0);
-        // Not done yet, until all clients do the work.
-        assert_eq!(ds.completed.len(), 0);
-
-        // Verify who has updated their last flush.
-        assert_eq!(ds.ds_last_flush[0], flush_id);
-        assert_eq!(ds.ds_last_flush[1], 0);
-        assert_eq!(ds.ds_last_flush[2], flush_id);
-
-        // Now, finish sending and completing the writes
-        assert!(ds.in_progress(id1, 2).is_some());
-        assert!(ds.in_progress(id2, 0).is_some());
-        assert!(!ds
-            .process_ds_completion(id1, 2, Ok(vec![]), &None, UpState::Active)
-            .unwrap());
-        assert!(!ds
-            .process_ds_completion(id2, 0, Ok(vec![]), &None, UpState::Active)
-            .unwrap());
-
-        // Completed work won't happen till the last flush is done
-        assert_eq!(ds.completed.len(), 0);
-
-        // Send and complete the flush
-        ds.in_progress(flush_id, 1);
         assert!(!ds
             .process_ds_completion(
-                flush_id,
-                1,
+                next_id,
+                2,
                 Ok(vec![]),
                 &None,
                 UpState::Active
             )
             .unwrap());
 
-        // Now, all three jobs (w,w,f) will move to completed.
-        assert_eq!(ds.completed.len(), 3);
+        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
+        assert_eq!(state, AckStatus::AckReady);
+        ds.ack(next_id);
 
-        // downstairs 1 should now have that flush
-        assert_eq!(ds.ds_last_flush[1], flush_id);
+        // Work stays on active queue till the flush
+        assert_eq!(ds.ackable_work().len(), 0);
+        assert_eq!(ds.completed.len(), 0);
     }
 
     #[tokio::test]
-    async fn work_completed_read_replay() {
-        // Verify that a single read will replay and move back from AckReady
+    async fn work_completed_resize() {
+        // Verify that a resize is acked after two downstairs complete it,
+        // and that our region definition picks up the new extent count at
+        // that point.
         let upstairs = Upstairs::default();
         upstairs.set_active().await.unwrap();
-        let mut ds = upstairs.downstairs.lock().await;
+        upstairs.ddef.lock().await.set_extent_count(4);
 
-        // Build our read IO and submit it to the work queue.
-        let next_id = ds.next_id();
-        let request = ReadRequest {
-            eid: 0,
-            offset: Block::new_512(7),
-        };
-        let op = create_read_eob(
-            next_id,
-            vec![],
-            10,
-            vec![request.clone()],
-            ImpactedBlocks::default(),
-        );
-        ds.enqueue(op);
+        let next_id = {
+            let mut ds = upstairs.downstairs.lock().await;
+            let next_id = ds.next_id();
 
-        // Submit the read to all three downstairs
-        ds.in_progress(next_id, 0);
-        ds.in_progress(next_id, 1);
-        ds.in_progress(next_id, 2);
+            let op = create_resize_eob(
+                next_id,
+                vec![],
+                10,
+                6,
+                ImpactedBlocks::default(),
+            );
+            ds.enqueue(op);
 
-        // Complete the read on one downstairs.
-        let response =
-            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
-        assert!(ds
-            .process_ds_completion(next_id, 0, response, &None, UpState::Active)
+            ds.in_progress(next_id, 0);
+            ds.in_progress(next_id, 1);
+            ds.in_progress(next_id, 2);
+
+            next_id
+        };
+
+        assert!(!upstairs
+            .process_ds_operation(next_id, 0, Ok(vec![]))
+            .await
             .unwrap());
+        assert_eq!(upstairs.ddef.lock().await.extent_count(), 4);
 
-        // One completion should allow for an ACK
-        assert_eq!(ds.ackable_work().len(), 1);
-        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
-        assert_eq!(state, AckStatus::AckReady);
+        assert!(upstairs
+            .process_ds_operation(next_id, 1, Ok(vec![]))
+            .await
+            .unwrap());
+        assert_eq!(upstairs.ddef.lock().await.extent_count(), 6);
 
-        // Be sure the job is not yet in replay
-        assert!(!ds.ds_active.get_mut(&next_id).unwrap().replay);
-        ds.re_new(0);
-        // Now the IO should be replay
-        assert!(ds.ds_active.get_mut(&next_id).unwrap().replay);
+        assert!(!upstairs
+            .process_ds_operation(next_id, 2, Ok(vec![]))
+            .await
+            .unwrap());
+        assert_eq!(upstairs.ddef.lock().await.extent_count(), 6);
 
-        // The act of taking a downstairs offline should move a read
-        // back from AckReady if it was the only completed read.
+        let mut ds = upstairs.downstairs.lock().await;
         let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
-        assert_eq!(state, AckStatus::NotAcked);
+        assert_eq!(state, AckStatus::AckReady);
+        assert!(ds.result(next_id).is_ok());
     }
 
     #[tokio::test]
-    async fn work_completed_two_read_replay() {
-        // Verify that a read will replay and move not back from AckReady if
-        // there is more than one done read.
+    async fn work_resize_two_bad_keeps_ddef() {
+        // If two downstairs fail the resize, the guest gets an error and our
+        // region definition stays the same.  The one that did resize is
+        // faulted.
         let upstairs = Upstairs::default();
         upstairs.set_active().await.unwrap();
-        let mut ds = upstairs.downstairs.lock().await;
+        upstairs.ddef.lock().await.set_extent_count(4);
 
-        // Build a read and put it on the work queue.
-        let next_id = ds.next_id();
-        let request = ReadRequest {
-            eid: 0,
-            offset: Block::new_512(7),
+        let next_id = {
+            let mut ds = upstairs.downstairs.lock().await;
+            ds.ds_state[0] = DsState::Active;
+            ds.ds_state[1] = DsState::Active;
+            ds.ds_state[2] = DsState::Active;
+            let next_id = ds.next_id();
+
+            let op = create_resize_eob(
+                next_id,
+                vec![],
+                10,
+                2,
+                ImpactedBlocks::default(),
+            );
+            ds.enqueue(op);
+
+            ds.in_progress(next_id, 0);
+            ds.in_progress(next_id, 1);
+            ds.in_progress(next_id, 2);
+
+            next_id
         };
-        let op = create_read_eob(
-            next_id,
-            vec![],
-            10,
-            vec![request.clone()],
-            ImpactedBlocks::default(),
-        );
-        ds.enqueue(op);
 
-        // Submit the read to each downstairs.
-        ds.in_progress(next_id, 0);
-        ds.in_progress(next_id, 1);
-        ds.in_progress(next_id, 2);
+        let err_response = Err(CrucibleError::RegionResizeError(
+            "extent 3 has been written to".to_string(),
+        ));
 
-        // Complete the read on one downstairs, verify it is ack ready.
-        let response = Ok(vec![ReadResponse::from_request_with_data(
-            &request,
-            &[1, 2, 3, 4],
-        )]);
-        assert!(ds
-            .process_ds_completion(next_id, 0, response, &None, UpState::Active)
+        assert!(!upstairs
+            .process_ds_operation(next_id, 0, Ok(vec![]))
+            .await
             .unwrap());
-        assert_eq!(ds.ackable_work().len(), 1);
-        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
-        assert_eq!(state, AckStatus::AckReady);
-
-        // Complete the read on a 2nd downstairs.
-        let response = Ok(vec![ReadResponse::from_request_with_data(
-            &request,
-            &[1, 2, 3, 4],
-        )]);
-        assert!(!ds
-            .process_ds_completion(next_id, 1, response, &None, UpState::Active)
+        assert!(!upstairs
+            .process_ds_operation(next_id, 1, err_response.clone())
+            .await
+            .unwrap());
+        assert!(upstairs
+            .process_ds_operation(next_id, 2, err_response)
+            .await
             .unwrap());
 
-        // Now, take the first downstairs offline.
-        ds.re_new(0);
+        assert_eq!(upstairs.ddef.lock().await.extent_count(), 4);
 
-        // Should still be ok to ACK this IO
+        let mut ds = upstairs.downstairs.lock().await;
         let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
         assert_eq!(state, AckStatus::AckReady);
+        assert!(ds.result(next_id).is_err());
 
-        // Taking the second downstairs offline should revert the ACK.
-        ds.re_new(1);
-        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
-        assert_eq!(state, AckStatus::NotAcked);
-
-        // Redo the read on DS 0, IO should go back to ackable.
-        ds.in_progress(next_id, 0);
-
-        let response =
-            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
-        assert!(ds
-            .process_ds_completion(next_id, 0, response, &None, UpState::Active)
-            .unwrap());
-        assert_eq!(ds.ackable_work().len(), 1);
-        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
-        assert_eq!(state, AckStatus::AckReady);
+        // A refused resize doesn't fail the downstairs that refused it.
+        assert_eq!(ds.ds_state[0], DsState::Faulted);
+        assert_eq!(ds.ds_state[1], DsState::Active);
+        assert_eq!(ds.ds_state[2], DsState::Active);
     }
 
     #[tokio::test]
-    async fn work_completed_ack_read_replay() {
-        // Verify that a read we Acked will still replay if that downstairs
-        // goes away. Make sure everything still finishes ok.
+    async fn work_resize_one_bad_is_faulted() {
+        // If one downstairs refuses a resize the others did, its region is
+        // the wrong size, so it's faulted once the resize is decided.
         let upstairs = Upstairs::default();
         upstairs.set_active().await.unwrap();
-        let mut ds = upstairs.downstairs.lock().await;
+        upstairs.ddef.lock().await.set_extent_count(4);
 
-        // Create the read and put it on the work queue.
-        let next_id = ds.next_id();
-        let request = ReadRequest {
-            eid: 0,
-            offset: Block::new_512(7),
-        };
-        let op = create_read_eob(
-            next_id,
-            vec![],
-            10,
-            vec![request.clone()],
-            ImpactedBlocks::default(),
-        );
-        ds.enqueue(op);
+        let next_id = {
+            let mut ds = upstairs.downstairs.lock().await;
+            ds.ds_state[0] = DsState::Active;
+            ds.ds_state[1] = DsState::Active;
+            ds.ds_state[2] = DsState::Active;
+            let next_id = ds.next_id();
 
-        // Submit the read to each downstairs.
-        ds.in_progress(next_id, 0);
-        ds.in_progress(next_id, 1);
-        ds.in_progress(next_id, 2);
+            let op = create_resize_eob(
+                next_id,
+                vec![],
+                10,
+                6,
+                ImpactedBlocks::default(),
+            );
+            ds.enqueue(op);
 
-        // Complete the read on one downstairs.
-        let response =
-            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
-        assert!(ds
-            .process_ds_completion(next_id, 0, response, &None, UpState::Active)
-            .unwrap());
+            ds.in_progress(next_id, 0);
+            ds.in_progress(next_id, 1);
+            ds.in_progress(next_id, 2);
 
-        // Verify the read is now AckReady
-        assert_eq!(ds.ackable_work().len(), 1);
-        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
-        assert_eq!(state, AckStatus::AckReady);
+            next_id
+        };
 
-        // Ack the read to the guest.
-        ds.ack(next_id);
+        let err_response = Err(CrucibleError::RegionResizeError(
+            "extent 3 is closed".to_string(),
+        ));
 
-        // Should not retire yet
-        ds.retire_check(next_id);
+        assert!(!upstairs
+            .process_ds_operation(next_id, 0, err_response)
+            .await
+            .unwrap());
+        assert_eq!(upstairs.ds_state(0).await, DsState::Active);
+        assert!(!upstairs
+            .process_ds_operation(next_id, 1, Ok(vec![]))
+            .await
+            .unwrap());
+        assert!(upstairs
+            .process_ds_operation(next_id, 2, Ok(vec![]))
+            .await
+            .unwrap());
 
-        // No new ackable work.
-        assert_eq!(ds.ackable_work().len(), 0);
-        // Verify the IO has not completed yet.
-        assert_eq!(ds.completed.len(), 0);
+        assert_eq!(upstairs.ddef.lock().await.extent_count(), 6);
+
+        let mut ds = upstairs.downstairs.lock().await;
+        assert!(ds.result(next_id).is_ok());
+        assert_eq!(ds.ds_state[0], DsState::Faulted);
+        assert_eq!(ds.ds_state[1], DsState::Active);
+        assert_eq!(ds.ds_state[2], DsState::Active);
+    }
+
+    #[tokio::test]
+    async fn work_resize_two_bad_of_five_are_faulted() {
+        // With five downstairs, the two that refuse a resize the other
+        // three did are both faulted.
+        let upstairs = make_upstairs_with_quorum(5, None).unwrap();
+        upstairs.set_active().await.unwrap();
+        upstairs.ddef.lock().await.set_extent_count(4);
+
+        let next_id = {
+            let mut ds = upstairs.downstairs.lock().await;
+            for cid in 0..5 {
+                ds.ds_state[cid] = DsState::Active;
+            }
+            let next_id = ds.next_id();
+
+            let op = create_resize_eob(
+                next_id,
+                vec![],
+                10,
+                6,
+                ImpactedBlocks::default(),
+            );
+            ds.enqueue(op);
+
+            for cid in 0..5 {
+                ds.in_progress(next_id, cid);
+            }
+
+            next_id
+        };
+
+        let err_response = Err(CrucibleError::RegionResizeError(
+            "extent 3 is closed".to_string(),
+        ));
+
+        for cid in 0..2 {
+            assert!(!upstairs
+                .process_ds_operation(next_id, cid, err_response.clone())
+                .await
+                .unwrap());
+        }
+        assert!(!upstairs
+            .process_ds_operation(next_id, 2, Ok(vec![]))
+            .await
+            .unwrap());
+        assert!(!upstairs
+            .process_ds_operation(next_id, 3, Ok(vec![]))
+            .await
+            .unwrap());
+        assert!(upstairs
+            .process_ds_operation(next_id, 4, Ok(vec![]))
+            .await
+            .unwrap());
+
+        assert_eq!(upstairs.ddef.lock().await.extent_count(), 6);
+
+        let mut ds = upstairs.downstairs.lock().await;
+        assert!(ds.result(next_id).is_ok());
+        assert_eq!(ds.ds_state[0], DsState::Faulted);
+        assert_eq!(ds.ds_state[1], DsState::Faulted);
+        for cid in 2..5 {
+            assert_eq!(ds.ds_state[cid], DsState::Active);
+        }
+    }
+
+    #[tokio::test]
+    async fn work_shrink_fences_io_past_new_end() {
+        // Once a shrink is queued, IO past the new end of the region is
+        // refused until the shrink is acked.
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        let resize_id = {
+            let mut ds = upstairs.downstairs.lock().await;
+            let next_id = ds.next_id();
+            ds.enqueue(create_resize_eob(
+                next_id,
+                vec![],
+                10,
+                4,
+                ImpactedBlocks::default(),
+            ));
+            next_id
+        };
+
+        // Extent 3 stays, extent 4 is on its way out.
+        upstairs
+            .submit_write(
+                Block::new_512(399),
+                Bytes::from(vec![1; 512]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        assert!(upstairs
+            .submit_write(
+                Block::new_512(399),
+                Bytes::from(vec![1; 1024]),
+                None,
+                false,
+            )
+            .await
+            .is_err());
+        assert!(upstairs
+            .submit_read(Block::new_512(400), Buffer::new(512), None)
+            .await
+            .is_err());
+        assert!(upstairs
+            .submit_discard(Block::new_512(450), 512, None)
+            .await
+            .is_err());
+
+        // Once the resize is acked, the region definition decides.
+        upstairs
+            .downstairs
+            .lock()
+            .await
+            .ds_active
+            .get_mut(&resize_id)
+            .unwrap()
+            .ack_status = AckStatus::AckReady;
+        upstairs
+            .submit_read(Block::new_512(400), Buffer::new(512), None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn work_delay_completion_flush_order_write() {
+        work_delay_completion_flush_order(false).await;
+    }
+
+    #[tokio::test]
+    async fn work_delay_completion_flush_order_write_unwritten() {
+        work_delay_completion_flush_order(true).await;
+    }
+
+    async fn work_delay_completion_flush_order(is_write_unwritten: bool) {
+        // Verify that a write/write_unwritten remains on the active queue
+        // until a flush comes through and clears it.  In this case, we only
+        // complete 2 of 3 for each IO.  We later come back and finish the
+        // 3rd IO and the flush, which then allows the work to be completed.
+        // Also, we mix up which client finishes which job first.
+        let upstairs = Upstairs::default();
+        upstairs.set_active().await.unwrap();
+        let mut ds = upstairs.downstairs.lock().await;
+
+        // Build two writes, put them on the work queue.
+        let id1 = ds.next_id();
+        let id2 = ds.next_id();
+
+        let op = create_write_eob(
+            id1,
+            vec![],
+            10,
+            vec![crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(7),
+                data: Bytes::from(vec![1]),
+                block_context: BlockContext {
+                    encryption_context: None,
+                    hash: 0,
+                },
+            }],
+            is_write_unwritten,
+            ImpactedBlocks::default(),
+        );
+        ds.enqueue(op);
+
+        let op = create_write_eob(
+            id2,
+            vec![],
+            1,
+            vec![crucible_protocol::Write {
+                eid: 0,
+                offset: Block::new_512(7),
+                data: Bytes::from(vec![1]),
+                block_context: BlockContext {
+                    encryption_context: None,
+                    hash: 0,
+                },
+            }],
+            is_write_unwritten,
+            ImpactedBlocks::default(),
+        );
+        ds.enqueue(op);
+
+        // Submit the two writes, to 2/3 of the downstairs.
+        assert!(ds.in_progress(id1, 0).is_some());
+        assert!(ds.in_progress(id1, 1).is_some());
+        assert!(ds.in_progress(id2, 1).is_some());
+        assert!(ds.in_progress(id2, 2).is_some());
+
+        // Complete the writes that we sent to the 2 downstairs.
+        assert!(!ds
+            .process_ds_completion(id1, 0, Ok(vec![]), &None, UpState::Active)
+            .unwrap());
+        assert!(ds
+            .process_ds_completion(id1, 1, Ok(vec![]), &None, UpState::Active)
+            .unwrap());
+        assert!(!ds
+            .process_ds_completion(id2, 1, Ok(vec![]), &None, UpState::Active)
+            .unwrap());
+        assert!(ds
+            .process_ds_completion(id2, 2, Ok(vec![]), &None, UpState::Active)
+            .unwrap());
+
+        // Ack the writes to the guest.
+        ds.ack(id1);
+        ds.ack(id2);
+
+        // Work stays on active queue till the flush.
+        assert_eq!(ds.ackable_work().len(), 0);
+        assert_eq!(ds.completed.len(), 0);
+
+        // Create and enqueue the flush.
+        let flush_id = ds.next_id();
+        let op = create_flush(
+            flush_id,
+            vec![],
+            10,
+            0,
+            0,
+            None,
+            ImpactedBlocks::default(),
+        );
+        ds.enqueue(op);
+
+        // Send the flush to two downstairs.
+        ds.in_progress(flush_id, 0);
+        ds.in_progress(flush_id, 2);
+
+        // Complete the flush on those downstairs.
+        assert!(!ds
+            .process_ds_completion(
+                flush_id,
+                0,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+        assert!(ds
+            .process_ds_completion(
+                flush_id,
+                2,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+
+        // Ack the flush
+        ds.ack(flush_id);
+
+        // Should not retire yet
+        ds.retire_check(flush_id);
+
+        assert_eq!(ds.ackable_work().len(), 0);
+        // Not done yet, until all clients do the work.
+        assert_eq!(ds.completed.len(), 0);
+
+        // Verify who has updated their last flush.
+        assert_eq!(ds.ds_last_flush[0], flush_id);
+        assert_eq!(ds.ds_last_flush[1], 0);
+        assert_eq!(ds.ds_last_flush[2], flush_id);
+
+        // Now, finish sending and completing the writes
+        assert!(ds.in_progress(id1, 2).is_some());
+        assert!(ds.in_progress(id2, 0).is_some());
+        assert!(!ds
+            .process_ds_completion(id1, 2, Ok(vec![]), &None, UpState::Active)
+            .unwrap());
+        assert!(!ds
+            .process_ds_completion(id2, 0, Ok(vec![]), &None, UpState::Active)
+            .unwrap());
+
+        // Completed work won't happen till the last flush is done
+        assert_eq!(ds.completed.len(), 0);
+
+        // Send and complete the flush
+        ds.in_progress(flush_id, 1);
+        assert!(!ds
+            .process_ds_completion(
+                flush_id,
+                1,
+                Ok(vec![]),
+                &None,
+                UpState::Active
+            )
+            .unwrap());
+
+        // Now, all three jobs (w,w,f) will move to completed.
+        assert_eq!(ds.completed.len(), 3);
+
+        // downstairs 1 should now have that flush
+        assert_eq!(ds.ds_last_flush[1], flush_id);
+    }
+
+    #[tokio::test]
+    async fn work_completed_read_replay() {
+        // Verify that a single read will replay and move back from AckReady
+        let upstairs = Upstairs::default();
+        upstairs.set_active().await.unwrap();
+        let mut ds = upstairs.downstairs.lock().await;
+
+        // Build our read IO and submit it to the work queue.
+        let next_id = ds.next_id();
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+        };
+        let op = create_read_eob(
+            next_id,
+            vec![],
+            10,
+            vec![request.clone()],
+            ImpactedBlocks::default(),
+        );
+        ds.enqueue(op);
+
+        // Submit the read to all three downstairs
+        ds.in_progress(next_id, 0);
+        ds.in_progress(next_id, 1);
+        ds.in_progress(next_id, 2);
+
+        // Complete the read on one downstairs.
+        let response =
+            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
+        assert!(ds
+            .process_ds_completion(next_id, 0, response, &None, UpState::Active)
+            .unwrap());
+
+        // One completion should allow for an ACK
+        assert_eq!(ds.ackable_work().len(), 1);
+        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
+        assert_eq!(state, AckStatus::AckReady);
+
+        // Be sure the job is not yet in replay
+        assert!(!ds.ds_active.get_mut(&next_id).unwrap().replay);
+        ds.re_new(0);
+        // Now the IO should be replay
+        assert!(ds.ds_active.get_mut(&next_id).unwrap().replay);
+
+        // The act of taking a downstairs offline should move a read
+        // back from AckReady if it was the only completed read.
+        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
+        assert_eq!(state, AckStatus::NotAcked);
+    }
+
+    #[tokio::test]
+    async fn work_completed_two_read_replay() {
+        // Verify that a read will replay and move not back from AckReady if
+        // there is more than one done read.
+        let upstairs = Upstairs::default();
+        upstairs.set_active().await.unwrap();
+        let mut ds = upstairs.downstairs.lock().await;
+
+        // Build a read and put it on the work queue.
+        let next_id = ds.next_id();
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+        };
+        let op = create_read_eob(
+            next_id,
+            vec![],
+            10,
+            vec![request.clone()],
+            ImpactedBlocks::default(),
+        );
+        ds.enqueue(op);
+
+        // Submit the read to each downstairs.
+        ds.in_progress(next_id, 0);
+        ds.in_progress(next_id, 1);
+        ds.in_progress(next_id, 2);
+
+        // Complete the read on one downstairs, verify it is ack ready.
+        let response = Ok(vec![ReadResponse::from_request_with_data(
+            &request,
+            &[1, 2, 3, 4],
+        )]);
+        assert!(ds
+            .process_ds_completion(next_id, 0, response, &None, UpState::Active)
+            .unwrap());
+        assert_eq!(ds.ackable_work().len(), 1);
+        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
+        assert_eq!(state, AckStatus::AckReady);
+
+        // Complete the read on a 2nd downstairs.
+        let response = Ok(vec![ReadResponse::from_request_with_data(
+            &request,
+            &[1, 2, 3, 4],
+        )]);
+        assert!(!ds
+            .process_ds_completion(next_id, 1, response, &None, UpState::Active)
+            .unwrap());
+
+        // Now, take the first downstairs offline.
+        ds.re_new(0);
+
+        // Should still be ok to ACK this IO
+        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
+        assert_eq!(state, AckStatus::AckReady);
+
+        // Taking the second downstairs offline should revert the ACK.
+        ds.re_new(1);
+        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
+        assert_eq!(state, AckStatus::NotAcked);
+
+        // Redo the read on DS 0, IO should go back to ackable.
+        ds.in_progress(next_id, 0);
+
+        let response =
+            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
+        assert!(ds
+            .process_ds_completion(next_id, 0, response, &None, UpState::Active)
+            .unwrap());
+        assert_eq!(ds.ackable_work().len(), 1);
+        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
+        assert_eq!(state, AckStatus::AckReady);
+    }
+
+    #[tokio::test]
+    async fn work_completed_ack_read_replay() {
+        // Verify that a read we Acked will still replay if that downstairs
+        // goes away. Make sure everything still finishes ok.
+        let upstairs = Upstairs::default();
+        upstairs.set_active().await.unwrap();
+        let mut ds = upstairs.downstairs.lock().await;
+
+        // Create the read and put it on the work queue.
+        let next_id = ds.next_id();
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+        };
+        let op = create_read_eob(
+            next_id,
+            vec![],
+            10,
+            vec![request.clone()],
+            ImpactedBlocks::default(),
+        );
+        ds.enqueue(op);
+
+        // Submit the read to each downstairs.
+        ds.in_progress(next_id, 0);
+        ds.in_progress(next_id, 1);
+        ds.in_progress(next_id, 2);
+
+        // Complete the read on one downstairs.
+        let response =
+            Ok(vec![ReadResponse::from_request_with_data(&request, &[])]);
+        assert!(ds
+            .process_ds_completion(next_id, 0, response, &None, UpState::Active)
+            .unwrap());
+
+        // Verify the read is now AckReady
+        assert_eq!(ds.ackable_work().len(), 1);
+        let state = ds.ds_active.get_mut(&next_id).unwrap().ack_status;
+        assert_eq!(state, AckStatus::AckReady);
+
+        // Ack the read to the guest.
+        ds.ack(next_id);
+
+        // Should not retire yet
+        ds.retire_check(next_id);
+
+        // No new ackable work.
+        assert_eq!(ds.ackable_work().len(), 0);
+        // Verify the IO has not completed yet.
+        assert_eq!(ds.completed.len(), 0);
 
         // Now, take that downstairs offline
         ds.re_new(0);
@@ -3499,6 +4405,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
             // A downstairs is not in Repair state
             ds.ds_state[0] = DsState::Repair;
@@ -3539,6 +4446,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
         }
         // Move that job to next to do.
@@ -3592,6 +4500,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
         }
         // Move that job to next to do.
@@ -3628,6 +4537,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
         }
         // Move that job to next to do.
@@ -3655,6 +4565,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
         }
         // Move that job to next to do.
@@ -3679,6 +4590,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
             ds.reconcile_task_list.push_back(ReconcileIO::new(
                 rep_id + 1,
@@ -3686,6 +4598,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
         }
         // Move that job to next to do.
@@ -3741,6 +4654,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
             ds.reconcile_task_list.push_back(ReconcileIO::new(
                 rep_id + 1,
@@ -3748,6 +4662,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
         }
         // Move that job to next to do.
@@ -3787,6 +4702,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
         }
         // Move that job to next to do.
@@ -3828,6 +4744,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
         }
         // Move that job to next to do.
@@ -3864,6 +4781,7 @@ mod up_test {
                     repair_id: rep_id,
                     extent_id: 1,
                 },
+                3,
             ));
         }
         // Move that job to next to do.
@@ -3992,6 +4910,73 @@ mod up_test {
         assert_eq!(Some(&IOState::New), rio.state.get(&2));
     }
 
+    #[tokio::test]
+    async fn reconcile_rc_to_message_blocks() {
+        // When the source and every downstairs being repaired can copy
+        // just the blocks that differ, the repair message asks for that.
+        let up = Upstairs::default();
+        let mut ds = up.downstairs.lock().await;
+        let r0 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 801);
+        ds.ds_repair.insert(0, r0);
+
+        let delta = Negotiated {
+            version: PROTOCOL_VERSION_MAX,
+            features: FEATURE_DELTA_REPAIR,
+        };
+        let plain = Negotiated {
+            version: PROTOCOL_VERSION_MAX,
+            features: 0,
+        };
+        ds.ds_protocol.insert(0, delta);
+        ds.ds_protocol.insert(1, delta);
+        ds.ds_protocol.insert(2, plain);
+
+        let mut rec_list = HashMap::new();
+        rec_list.insert(
+            4,
+            ExtentFix {
+                source: 0,
+                dest: vec![1],
+            },
+        );
+        ds.convert_rc_to_messages(rec_list, 22, 33);
+        assert_eq!(ds.reconcile_task_list.len(), 4);
+        match &ds.reconcile_task_list[2].op {
+            Message::ExtentRepairBlocks {
+                repair_id,
+                extent_id,
+                source_client_id,
+                source_repair_address,
+                dest_clients,
+            } => {
+                assert_eq!(*repair_id, 2);
+                assert_eq!(*extent_id, 4);
+                assert_eq!(*source_client_id, 0);
+                assert_eq!(*source_repair_address, r0);
+                assert_eq!(*dest_clients, vec![1]);
+            }
+            m => {
+                panic!("{:?} not ExtentRepairBlocks", m);
+            }
+        }
+
+        // Downstairs 2 can't, so its repair copies the whole extent.
+        ds.reconcile_task_list.clear();
+        let mut rec_list = HashMap::new();
+        rec_list.insert(
+            4,
+            ExtentFix {
+                source: 0,
+                dest: vec![1, 2],
+            },
+        );
+        ds.convert_rc_to_messages(rec_list, 22, 33);
+        assert!(matches!(
+            ds.reconcile_task_list[2].op,
+            Message::ExtentRepair { .. }
+        ));
+    }
+
     #[tokio::test]
     async fn reconcile_rc_to_message_two() {
         // Convert another extent fix to the crucible repair messages that
@@ -4110,8 +5095,9 @@ mod up_test {
     }
 
     #[tokio::test]
-    async fn bad_decryption_means_panic() {
-        // Failure to decrypt means panic.
+    async fn bad_decryption_is_healed() {
+        // Failure to decrypt is an error for that downstairs, and the block
+        // is noted for healing.
         // This result has a valid hash, but won't decrypt.
         let upstairs = Upstairs::default();
         upstairs.set_active().await.unwrap();
@@ -4149,7 +5135,8 @@ mod up_test {
 
         let mut data = Vec::from([1u8; 512]);
 
-        let (nonce, tag, _) = context.encrypt_in_place(&mut data).unwrap();
+        let (nonce, tag, key_id, _) =
+            context.encrypt_in_place(&mut data, 0, 7, 1).unwrap();
 
         let nonce = nonce.to_vec();
         let mut tag = tag.to_vec();
@@ -4172,32 +5159,102 @@ mod up_test {
             data: BytesMut::from(&data[..]),
             block_contexts: vec![BlockContext {
                 encryption_context: Some(
-                    crucible_protocol::EncryptionContext { nonce, tag },
+                    crucible_protocol::EncryptionContext { nonce, tag, key_id },
                 ),
                 hash,
             }],
         }]);
 
-        let result =
-            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-                ds.process_ds_completion(
-                    next_id,
-                    0,
-                    response,
-                    &Some(context),
-                    UpState::Active,
-                )
-            }));
-        assert!(result.is_err());
+        ds.process_ds_completion(
+            next_id,
+            0,
+            response,
+            &Some(context),
+            UpState::Active,
+        )
+        .unwrap();
+
+        // The block is left to be healed, and the downstairs keeps going.
+        let job = ds.ds_active.get(&next_id).unwrap();
+        assert_eq!(
+            job.state.get(&0),
+            Some(&IOState::Error(CrucibleError::DecryptionError))
+        );
+        assert_eq!(job.heal, vec![(0, 0)]);
+        assert!(ds.downstairs_errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bad_read_hash_is_healed() {
+        // Verify that a bad hash on a read is an error for that downstairs,
+        // and the block is noted for healing
+        let upstairs = Upstairs::default();
+        upstairs.set_active().await.unwrap();
+        let mut ds = upstairs.downstairs.lock().await;
+
+        let next_id = ds.next_id();
+
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+        };
+
+        let op = create_read_eob(
+            next_id,
+            vec![],
+            10,
+            vec![request.clone()],
+            ImpactedBlocks::default(),
+        );
+
+        ds.enqueue(op);
+        ds.in_progress(next_id, 0);
+
+        // fake read response from downstairs that will fail integrity hash
+        // check
+
+        let data = Vec::from([1u8; 512]);
+
+        let response = Ok(vec![ReadResponse {
+            eid: request.eid,
+            offset: request.offset,
+
+            data: BytesMut::from(&data[..]),
+            block_contexts: vec![BlockContext {
+                encryption_context: None,
+                hash: 10000, // junk hash,
+            }],
+        }]);
+
+        ds.process_ds_completion(next_id, 0, response, &None, UpState::Active)
+            .unwrap();
+
+        // The block is left to be healed, and the downstairs keeps going.
+        let job = ds.ds_active.get(&next_id).unwrap();
+        assert_eq!(
+            job.state.get(&0),
+            Some(&IOState::Error(CrucibleError::HashMismatch))
+        );
+        assert_eq!(job.heal, vec![(0, 0)]);
+        assert!(ds.downstairs_errors.is_empty());
     }
 
     #[tokio::test]
-    async fn bad_read_hash_means_panic() {
-        // Verify that a bad hash on a read will panic
+    async fn stale_read_is_an_error() {
+        // A read that returns a valid older write of a block than the one
+        // we last wrote there is an error for that downstairs, but does
+        // not panic.
         let upstairs = Upstairs::default();
         upstairs.set_active().await.unwrap();
         let mut ds = upstairs.downstairs.lock().await;
 
+        let old_data = Vec::from([1u8; 512]);
+        let old_hash = integrity_hash(&[&old_data]);
+        let new_hash = integrity_hash(&[&[2u8; 512][..]]);
+
+        let write_id = ds.next_id();
+        ds.block_record.record_write(write_id, 0, 7, new_hash);
+
         let next_id = ds.next_id();
 
         let request = ReadRequest {
@@ -4213,42 +5270,141 @@ mod up_test {
             ImpactedBlocks::default(),
         );
 
-        ds.enqueue(op);
-        ds.in_progress(next_id, 0);
+        ds.enqueue(op);
+        ds.in_progress(next_id, 0);
+
+        let response = Ok(vec![ReadResponse {
+            eid: request.eid,
+            offset: request.offset,
+
+            data: BytesMut::from(&old_data[..]),
+            block_contexts: vec![BlockContext {
+                encryption_context: None,
+                hash: old_hash,
+            }],
+        }]);
+
+        ds.process_ds_completion(next_id, 0, response, &None, UpState::Active)
+            .unwrap();
+
+        let job = ds.ds_active.get(&next_id).unwrap();
+        assert!(matches!(
+            job.state.get(&0),
+            Some(IOState::Error(CrucibleError::BlockIntegrityError(_)))
+        ));
+        assert_eq!(ds.downstairs_errors.get(&0), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn write_is_recorded() {
+        // A write records the hash it sent for each block, so a later
+        // read can be checked against it.
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        upstairs
+            .submit_write(
+                Block::new_512(2),
+                Bytes::from(vec![0xff; 512]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        let ds = upstairs.downstairs.lock().await;
+        let hash = integrity_hash(&[&[0xff; 512][..]]);
+        let id = *ds.ds_active.keys().next().unwrap();
+        assert!(ds
+            .block_record
+            .check(id + 1, 0, 2, Some(hash), None)
+            .is_ok());
+        assert!(ds.block_record.check(id + 1, 0, 2, None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn rekey_write_skips_blocks_changed_since() {
+        // A key rotation writes back what it read, so it must not write
+        // over a block the guest has written to since.
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        let since = upstairs.downstairs.lock().await.next_id - 1;
+
+        upstairs
+            .submit_write(
+                Block::new_512(1),
+                Bytes::from(vec![0xff; 512]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        upstairs
+            .submit_rekey_write(
+                Block::new_512(0),
+                Bytes::from(vec![0x55; 512 * 3]),
+                vec![true, true, false],
+                since,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let ds = upstairs.downstairs.lock().await;
+        let id = *ds.ds_active.keys().max().unwrap();
+        match &ds.ds_active.get(&id).unwrap().work {
+            IOop::Write { writes, .. } => {
+                assert_eq!(writes.len(), 1);
+                assert_eq!(writes[0].eid, 0);
+                assert_eq!(writes[0].offset, Block::new_512(0));
+                assert_eq!(writes[0].data, Bytes::from(vec![0x55; 512]));
+            }
+            x => panic!("Expected a write, got {:?}", x),
+        }
+    }
+
+    #[tokio::test]
+    async fn rekey_write_of_nothing_is_not_submitted() {
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
 
-        // fake read response from downstairs that will fail integrity hash
-        // check
+        let since = upstairs.downstairs.lock().await.next_id - 1;
 
-        let data = Vec::from([1u8; 512]);
+        upstairs
+            .submit_rekey_write(
+                Block::new_512(0),
+                Bytes::from(vec![0x55; 512 * 2]),
+                vec![false, false],
+                since,
+                None,
+            )
+            .await
+            .unwrap();
 
-        let response = Ok(vec![ReadResponse {
-            eid: request.eid,
-            offset: request.offset,
+        assert!(upstairs.downstairs.lock().await.ds_active.is_empty());
+    }
 
-            data: BytesMut::from(&data[..]),
-            block_contexts: vec![BlockContext {
-                encryption_context: None,
-                hash: 10000, // junk hash,
-            }],
-        }]);
+    #[tokio::test]
+    async fn rekey_needs_an_encrypted_volume() {
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
 
-        let result =
-            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-                ds.process_ds_completion(
-                    next_id,
-                    0,
-                    response,
-                    &None,
-                    UpState::Active,
-                )
-            }));
-        assert!(result.is_err());
+        let result = upstairs
+            .rekey_start(
+                VolumeKey::new(&[1u8; 32]).unwrap(),
+                VolumeKey::new(&[2u8; 32]).unwrap(),
+            )
+            .await;
+        assert!(matches!(result, Err(CrucibleError::KeyRotationError(_))));
     }
 
     #[test]
-    fn bad_hash_on_encrypted_read_panic() {
-        // Verify that a decryption failure on a read will panic.
-        let mut ds = Downstairs::new(csl());
+    fn bad_hash_on_encrypted_read_is_healed() {
+        // Verify that a bad hash on an encrypted read is an error for that
+        // downstairs, and the block is noted for healing.
+        let mut ds = Downstairs::new(csl(), 3, 2);
         let next_id = ds.next_id();
 
         let request = ReadRequest {
@@ -4281,7 +5437,8 @@ mod up_test {
         // check
         let mut data = Vec::from([1u8; 512]);
 
-        let (nonce, tag, _) = context.encrypt_in_place(&mut data).unwrap();
+        let (nonce, tag, key_id, _) =
+            context.encrypt_in_place(&mut data, 0, 7, 1).unwrap();
 
         let nonce = nonce.to_vec();
         let tag = tag.to_vec();
@@ -4293,23 +5450,330 @@ mod up_test {
             data: BytesMut::from(&data[..]),
             block_contexts: vec![BlockContext {
                 encryption_context: Some(
-                    crucible_protocol::EncryptionContext { nonce, tag },
+                    crucible_protocol::EncryptionContext { nonce, tag, key_id },
                 ),
                 hash: 10000, // junk hash,
             }],
         }]);
 
-        let result =
-            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-                ds.process_ds_completion(
-                    next_id,
-                    0,
-                    response,
-                    &Some(context),
-                    UpState::Active,
-                )
-            }));
-        assert!(result.is_err());
+        ds.process_ds_completion(
+            next_id,
+            0,
+            response,
+            &Some(context),
+            UpState::Active,
+        )
+        .unwrap();
+
+        // The block is left to be healed, and the downstairs keeps going.
+        let job = ds.ds_active.get(&next_id).unwrap();
+        assert_eq!(
+            job.state.get(&0),
+            Some(&IOState::Error(CrucibleError::HashMismatch))
+        );
+        assert_eq!(job.heal, vec![(0, 0)]);
+        assert!(ds.downstairs_errors.is_empty());
+    }
+
+    /*
+     * Queue a read of one block at block 7 of extent 0 with every
+     * downstairs, and return its job ID.
+     */
+    fn heal_read(ds: &mut Downstairs) -> u64 {
+        let mut ddef = RegionDefinition::default();
+        ddef.set_block_size(512);
+        ddef.set_extent_size(Block::new_512(100));
+        ddef.set_extent_count(10);
+
+        let next_id = ds.next_id();
+        let op = create_read_eob(
+            next_id,
+            vec![],
+            10,
+            vec![ReadRequest {
+                eid: 0,
+                offset: Block::new_512(7),
+            }],
+            extent_from_offset(ddef, Block::new_512(7), Block::new_512(1)),
+        );
+        ds.enqueue(op);
+        for cid in 0..3 {
+            ds.in_progress(next_id, cid);
+        }
+
+        next_id
+    }
+
+    fn heal_response(data: &[u8], ctx: BlockContext) -> ReadResponse {
+        ReadResponse {
+            eid: 0,
+            offset: Block::new_512(7),
+            data: BytesMut::from(data),
+            block_contexts: vec![ctx],
+        }
+    }
+
+    #[test]
+    fn corrupt_block_is_healed_from_another_downstairs() {
+        let mut ds = Downstairs::new(csl(), 3, 2);
+        let read_id = heal_read(&mut ds);
+
+        let data = [1u8; 512];
+        let ctx = BlockContext {
+            hash: integrity_hash(&[&data[..]]),
+            encryption_context: None,
+        };
+
+        // Client 0 hands back a block that does not match its hash.
+        let response = heal_response(&[2u8; 512], ctx.clone());
+        ds.process_ds_completion(
+            read_id,
+            0,
+            Ok(vec![response]),
+            &None,
+            UpState::Active,
+        )
+        .unwrap();
+        assert_eq!(ds.ds_active.len(), 1);
+
+        // Client 1 has it intact, which the guest gets, and client 0 gets
+        // a write of it.
+        let response = heal_response(&data, ctx.clone());
+        assert!(ds
+            .process_ds_completion(
+                read_id,
+                1,
+                Ok(vec![response]),
+                &None,
+                UpState::Active,
+            )
+            .unwrap());
+
+        let job = ds.ds_active.get(&read_id).unwrap();
+        assert_eq!(job.ack_status, AckStatus::AckReady);
+        assert_eq!(&job.data.as_ref().unwrap()[0].data[..], &data[..]);
+        assert!(job.heal.is_empty());
+        assert_eq!(ds.blocks_healed, 1);
+
+        let heal_id = read_id + 1;
+        let heal = ds.ds_active.get(&heal_id).unwrap();
+        assert_eq!(heal.ack_status, AckStatus::Acked);
+        assert_eq!(heal.state.get(&0), Some(&IOState::New));
+        assert_eq!(heal.state.get(&1), Some(&IOState::Skipped));
+        assert_eq!(heal.state.get(&2), Some(&IOState::Skipped));
+        match &heal.work {
+            IOop::Write {
+                dependencies,
+                writes,
+            } => {
+                assert_eq!(dependencies, &vec![read_id]);
+                assert_eq!(writes.len(), 1);
+                assert_eq!(writes[0].eid, 0);
+                assert_eq!(writes[0].offset, Block::new_512(7));
+                assert_eq!(&writes[0].data[..], &data[..]);
+                assert_eq!(writes[0].block_context, ctx);
+            }
+            x => panic!("heal job is {:?}", x),
+        }
+        assert_eq!(ds.new_work(0), vec![heal_id]);
+        assert!(ds.new_work(1).is_empty());
+    }
+
+    #[test]
+    fn corrupt_block_after_ack_is_not_healed() {
+        // A read with nothing to heal hands its data to the guest when it
+        // is acked, so a downstairs that answers after that with a corrupt
+        // block can't have it healed from the read.
+        let mut ds = Downstairs::new(csl(), 3, 2);
+        let read_id = heal_read(&mut ds);
+
+        let data = [1u8; 512];
+        let ctx = BlockContext {
+            hash: integrity_hash(&[&data[..]]),
+            encryption_context: None,
+        };
+
+        let response = heal_response(&data, ctx.clone());
+        ds.process_ds_completion(
+            read_id,
+            0,
+            Ok(vec![response]),
+            &None,
+            UpState::Active,
+        )
+        .unwrap();
+        let job = ds.ds_active.get_mut(&read_id).unwrap();
+        assert!(job.heal.is_empty());
+        job.data.take();
+        ds.ack(read_id);
+
+        let response = heal_response(&[2u8; 512], ctx);
+        ds.process_ds_completion(
+            read_id,
+            1,
+            Ok(vec![response]),
+            &None,
+            UpState::Active,
+        )
+        .unwrap();
+        assert_eq!(ds.blocks_healed, 0);
+        assert!(!ds.ds_active.contains_key(&(read_id + 1)));
+        assert!(ds.ds_active.get(&read_id).unwrap().heal.is_empty());
+    }
+
+    #[test]
+    fn corrupt_encrypted_block_is_healed() {
+        // The repair write of an encrypted block carries the same
+        // ciphertext and encryption context the intact copy has.
+        let mut ds = Downstairs::new(csl(), 3, 2);
+        ds.block_record.set_generation(1);
+        let read_id = heal_read(&mut ds);
+
+        let context = Arc::new(EncryptionContext::new(vec![7u8; 32], 512));
+        let mut data = Vec::from([1u8; 512]);
+        let (nonce, tag, key_id, hash) =
+            context.encrypt_in_place(&mut data, 0, 7, 1).unwrap();
+        let ctx = BlockContext {
+            hash,
+            encryption_context: Some(crucible_protocol::EncryptionContext {
+                nonce: nonce.to_vec(),
+                tag: tag.to_vec(),
+                key_id,
+            }),
+        };
+
+        // Client 2's copy was altered, along with its hash, so it only
+        // fails decryption.
+        let mut bad = data.clone();
+        bad[0] ^= 0xff;
+        let mut bad_ctx = ctx.clone();
+        bad_ctx.hash = integrity_hash(&[&nonce[..], &tag[..], &bad[..]]);
+
+        let response = heal_response(&bad, bad_ctx);
+        ds.process_ds_completion(
+            read_id,
+            2,
+            Ok(vec![response]),
+            &Some(context.clone()),
+            UpState::Active,
+        )
+        .unwrap();
+
+        let response = heal_response(&data, ctx.clone());
+        ds.process_ds_completion(
+            read_id,
+            0,
+            Ok(vec![response]),
+            &Some(context),
+            UpState::Active,
+        )
+        .unwrap();
+
+        // The guest gets the plaintext
+        let job = ds.ds_active.get(&read_id).unwrap();
+        assert_eq!(&job.data.as_ref().unwrap()[0].data[..], &[1u8; 512][..]);
+
+        let heal = ds.ds_active.get(&(read_id + 1)).unwrap();
+        assert_eq!(heal.state.get(&2), Some(&IOState::New));
+        match &heal.work {
+            IOop::Write {
+                dependencies: _,
+                writes,
+            } => {
+                assert_eq!(&writes[0].data[..], &data[..]);
+                assert_eq!(writes[0].block_context, ctx);
+            }
+            x => panic!("heal job is {:?}", x),
+        }
+    }
+
+    #[test]
+    fn changed_block_is_not_healed() {
+        // A block written after the read is fixed by that write.
+        let mut ds = Downstairs::new(csl(), 3, 2);
+        let read_id = heal_read(&mut ds);
+
+        let data = [1u8; 512];
+        let ctx = BlockContext {
+            hash: integrity_hash(&[&data[..]]),
+            encryption_context: None,
+        };
+
+        let write_id = ds.next_id();
+        ds.block_record.record_write(write_id, 0, 7, 5);
+
+        let response = heal_response(&[2u8; 512], ctx.clone());
+        ds.process_ds_completion(
+            read_id,
+            0,
+            Ok(vec![response]),
+            &None,
+            UpState::Active,
+        )
+        .unwrap();
+        let response = heal_response(&data, ctx);
+        ds.process_ds_completion(
+            read_id,
+            1,
+            Ok(vec![response]),
+            &None,
+            UpState::Active,
+        )
+        .unwrap();
+
+        assert!(ds.ds_active.get(&read_id).unwrap().heal.is_empty());
+        assert_eq!(ds.ds_active.len(), 1);
+        assert_eq!(ds.blocks_healed, 0);
+    }
+
+    #[test]
+    fn work_skipped_for_a_client_is_not_a_dependency() {
+        // Other downstairs must not wait on a repair write that only one
+        // downstairs gets.
+        let mut ds = Downstairs::new(csl(), 3, 2);
+        let read_id = heal_read(&mut ds);
+
+        let data = [1u8; 512];
+        let ctx = BlockContext {
+            hash: integrity_hash(&[&data[..]]),
+            encryption_context: None,
+        };
+
+        let response = heal_response(&[2u8; 512], ctx.clone());
+        ds.process_ds_completion(
+            read_id,
+            0,
+            Ok(vec![response]),
+            &None,
+            UpState::Active,
+        )
+        .unwrap();
+        let response = heal_response(&data, ctx);
+        ds.process_ds_completion(
+            read_id,
+            1,
+            Ok(vec![response]),
+            &None,
+            UpState::Active,
+        )
+        .unwrap();
+        let heal_id = read_id + 1;
+
+        let next_id = ds.next_id();
+        let op = create_flush(
+            next_id,
+            vec![heal_id],
+            10,
+            0,
+            0,
+            None,
+            ImpactedBlocks::default(),
+        );
+        ds.enqueue(op);
+
+        assert_eq!(ds.in_progress(next_id, 0).unwrap().deps(), &vec![heal_id]);
+        assert!(ds.in_progress(next_id, 1).unwrap().deps().is_empty());
+        assert!(ds.in_progress(next_id, 2).unwrap().deps().is_empty());
     }
 
     #[tokio::test]
@@ -4347,15 +5811,15 @@ mod up_test {
         assert!(guest.consume_req().await.is_none());
 
         // If no IOP limit set, don't track it
-        assert_eq!(*guest.iop_tokens.lock().await, 0);
+        assert_eq!(guest.get_qos().await, None);
 
         Ok(())
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_set_iop_limit() -> Result<()> {
         let mut guest = Guest::new();
-        guest.set_iop_limit(16000, 2);
+        guest.set_iop_limit(16000, 2)?;
 
         assert!(guest.consume_req().await.is_none());
 
@@ -4388,21 +5852,68 @@ mod up_test {
         // remains in the queue.
         assert!(guest.consume_req().await.is_none());
         assert!(!guest.reqs.lock().await.is_empty());
-        assert_eq!(*guest.iop_tokens.lock().await, 2);
 
         // Replenish one token, meaning next read can be consumed
-        guest.leak_iop_tokens(1).await;
-        assert_eq!(*guest.iop_tokens.lock().await, 1);
+        tokio::time::advance(Duration::from_millis(500)).await;
 
         assert!(guest.consume_req().await.is_some());
         assert!(guest.reqs.lock().await.is_empty());
-        assert_eq!(*guest.iop_tokens.lock().await, 2);
 
-        guest.leak_iop_tokens(2).await;
-        assert_eq!(*guest.iop_tokens.lock().await, 0);
+        // A limit of zero would never let a read through.
+        let res = guest.set_iop_limit(16000, 0);
+        assert!(matches!(res, Err(CrucibleError::InvalidQosLimits(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_qos() -> Result<()> {
+        let guest = Guest::new();
+        guest
+            .set_qos(Some(QosLimits {
+                write_iops: Some(2),
+                ..Default::default()
+            }))
+            .await?;
+
+        for _ in 0..3 {
+            let _ = guest
+                .send(BlockOp::Write {
+                    offset: Block::new_512(0),
+                    data: Bytes::from(vec![0; 512]),
+                })
+                .await;
+        }
+        let _ = guest
+            .send(BlockOp::Flush {
+                snapshot_details: None,
+            })
+            .await;
+
+        // The burst lets two writes go, the third has to wait, but the
+        // flush behind it draws from no bucket and goes around it.
+        assert!(guest.consume_req().await.is_some());
+        assert!(guest.consume_req().await.is_some());
+        let req = guest.consume_req().await.unwrap();
+        assert!(matches!(req.op, BlockOp::Flush { .. }));
+        assert!(guest.consume_req().await.is_none());
+        assert_eq!(guest.reqs.lock().await.len(), 1);
+        assert!(guest.qos.lock().await.ready_at().is_some());
+        assert_eq!(guest.qos_delayed().await, 1);
+
+        // Taking the limits away lets it go.
+        guest.set_qos(None).await?;
+        assert_eq!(guest.get_qos().await, None);
+        assert!(guest.consume_req().await.is_some());
 
-        guest.leak_iop_tokens(16000).await;
-        assert_eq!(*guest.iop_tokens.lock().await, 0);
+        // A limit of zero would never let IO through.
+        let res = guest
+            .set_qos(Some(QosLimits {
+                read_bytes: Some(0),
+                ..Default::default()
+            }))
+            .await;
+        assert!(matches!(res, Err(CrucibleError::InvalidQosLimits(_))));
 
         Ok(())
     }
@@ -4411,10 +5922,20 @@ mod up_test {
     async fn test_flush_does_not_consume_iops() -> Result<()> {
         let mut guest = Guest::new();
 
-        // Set 0 as IOP limit
-        guest.set_iop_limit(16000, 0);
+        // Use up the one IOP there is, so the read after it has to wait.
+        guest.set_iop_limit(16000, 1)?;
         assert!(guest.consume_req().await.is_none());
 
+        for _ in 0..2 {
+            let _ = guest
+                .send(BlockOp::Read {
+                    offset: Block::new_512(0),
+                    data: Buffer::new(512),
+                })
+                .await;
+        }
+        assert!(guest.consume_req().await.is_some());
+
         let _ = guest
             .send(BlockOp::Flush {
                 snapshot_details: None,
@@ -4431,19 +5952,22 @@ mod up_test {
             })
             .await;
 
-        assert!(guest.consume_req().await.is_some());
-        assert!(guest.consume_req().await.is_some());
-        assert!(guest.consume_req().await.is_some());
+        // The flushes go around the read that is waiting.
+        for _ in 0..3 {
+            let req = guest.consume_req().await.unwrap();
+            assert!(matches!(req.op, BlockOp::Flush { .. }));
+        }
 
         assert!(guest.consume_req().await.is_none());
+        assert_eq!(guest.reqs.lock().await.len(), 1);
 
         Ok(())
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_set_bw_limit() -> Result<()> {
         let mut guest = Guest::new();
-        guest.set_bw_limit(1024 * 1024); // 1 KiB
+        guest.set_bw_limit(1024 * 1024)?; // 1 MiB
 
         assert!(guest.consume_req().await.is_none());
 
@@ -4476,21 +6000,12 @@ mod up_test {
         // remains in the queue.
         assert!(guest.consume_req().await.is_none());
         assert!(!guest.reqs.lock().await.is_empty());
-        assert_eq!(*guest.bw_tokens.lock().await, 1024 * 1024);
 
-        // Replenish enough tokens, meaning next read can be consumed
-        guest.leak_bw_tokens(1024 * 1024 / 2).await;
-        assert_eq!(*guest.bw_tokens.lock().await, 1024 * 1024 / 2);
+        // Replenish some tokens, meaning next read can be consumed
+        tokio::time::advance(Duration::from_millis(1)).await;
 
         assert!(guest.consume_req().await.is_some());
         assert!(guest.reqs.lock().await.is_empty());
-        assert_eq!(*guest.bw_tokens.lock().await, 1024 * 1024);
-
-        guest.leak_bw_tokens(1024 * 1024).await;
-        assert_eq!(*guest.bw_tokens.lock().await, 0);
-
-        guest.leak_bw_tokens(1024 * 1024 * 1024).await;
-        assert_eq!(*guest.bw_tokens.lock().await, 0);
 
         Ok(())
     }
@@ -4499,10 +6014,20 @@ mod up_test {
     async fn test_flush_does_not_consume_bw() -> Result<()> {
         let mut guest = Guest::new();
 
-        // Set 0 as bandwidth limit
-        guest.set_bw_limit(0);
+        // Use up the bandwidth there is, so the write after it has to wait.
+        guest.set_bw_limit(512)?;
         assert!(guest.consume_req().await.is_none());
 
+        for _ in 0..2 {
+            let _ = guest
+                .send(BlockOp::Write {
+                    offset: Block::new_512(0),
+                    data: Bytes::from(vec![0; 512]),
+                })
+                .await;
+        }
+        assert!(guest.consume_req().await.is_some());
+
         let _ = guest
             .send(BlockOp::Flush {
                 snapshot_details: None,
@@ -4519,21 +6044,28 @@ mod up_test {
             })
             .await;
 
-        assert!(guest.consume_req().await.is_some());
-        assert!(guest.consume_req().await.is_some());
-        assert!(guest.consume_req().await.is_some());
+        // The flushes go around the write that is waiting.
+        for _ in 0..3 {
+            let req = guest.consume_req().await.unwrap();
+            assert!(matches!(req.op, BlockOp::Flush { .. }));
+        }
 
         assert!(guest.consume_req().await.is_none());
+        assert_eq!(guest.reqs.lock().await.len(), 1);
+
+        // A limit of zero would never let a write through.
+        let res = guest.set_bw_limit(0);
+        assert!(matches!(res, Err(CrucibleError::InvalidQosLimits(_))));
 
         Ok(())
     }
 
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_iop_and_bw_limit() -> Result<()> {
         let mut guest = Guest::new();
 
-        guest.set_iop_limit(16384, 500); // 1 IOP is 16 KiB
-        guest.set_bw_limit(6400 * 1024); // 16384 B * 400 = 6400 KiB/s
+        guest.set_iop_limit(16384, 500)?; // 1 IOP is 16 KiB
+        guest.set_bw_limit(6400 * 1024)?; // 16384 B * 400 = 6400 KiB/s
         assert!(guest.consume_req().await.is_none());
 
         // Don't use guest.read, that will send a block size query that will
@@ -4558,24 +6090,17 @@ mod up_test {
         assert!(guest.consume_req().await.is_some());
         assert!(guest.consume_req().await.is_none());
 
-        // Assert we've hit the BW limit before IOPS
-        assert_eq!(*guest.iop_tokens.lock().await, 438); // 437.5 rounded up
-        assert_eq!(*guest.bw_tokens.lock().await, 7000 * 1024);
-
-        guest.leak_iop_tokens(438).await;
-        guest.leak_bw_tokens(7000 * 1024).await;
+        // The first read left the BW bucket 600 KiB in debt, which takes
+        // under 100ms to pay off.
+        tokio::time::advance(Duration::from_millis(100)).await;
 
         assert!(guest.consume_req().await.is_some());
         assert!(guest.reqs.lock().await.is_empty());
 
-        // Back to zero
-        guest.leak_iop_tokens(438).await;
-        guest.leak_bw_tokens(7000 * 1024).await;
-
-        assert_eq!(*guest.iop_tokens.lock().await, 0);
-        assert_eq!(*guest.bw_tokens.lock().await, 0);
-
         // Validate that IOP limit activates by sending 501 1024b IOs
+        let mut guest = Guest::new();
+        guest.set_iop_limit(16384, 500)?;
+        guest.set_bw_limit(6400 * 1024)?;
         for _ in 0..500 {
             let _ = guest
                 .send(BlockOp::Read {
@@ -4594,19 +6119,6 @@ mod up_test {
             .await;
         assert!(guest.consume_req().await.is_none());
 
-        // Assert we've hit the IOPS limit
-        assert_eq!(*guest.iop_tokens.lock().await, 500);
-        assert_eq!(*guest.bw_tokens.lock().await, 500 * 1024);
-
-        // Back to zero
-        guest.leak_iop_tokens(500).await;
-        guest.leak_bw_tokens(500 * 1024).await;
-        guest.reqs.lock().await.clear();
-
-        assert!(guest.reqs.lock().await.is_empty());
-        assert_eq!(*guest.iop_tokens.lock().await, 0);
-        assert_eq!(*guest.bw_tokens.lock().await, 0);
-
         // From
         // https://aws.amazon.com/premiumsupport/knowledge-center/ebs-calculate-optimal-io-size/:
         //
@@ -4620,10 +6132,10 @@ mod up_test {
 
         // I mean, it makes sense: now we submit 500 of those to reach both
         // limits at the same time.
-        for i in 0..500 {
-            assert_eq!(*guest.iop_tokens.lock().await, i);
-            assert_eq!(*guest.bw_tokens.lock().await, i * optimal_io_size);
-
+        let mut guest = Guest::new();
+        guest.set_iop_limit(16384, 500)?;
+        guest.set_bw_limit(6400 * 1024)?;
+        for _ in 0..500 {
             let _ = guest
                 .send(BlockOp::Read {
                     offset: Block::new_512(0),
@@ -4634,22 +6146,27 @@ mod up_test {
             assert!(guest.consume_req().await.is_some());
         }
 
-        assert_eq!(*guest.iop_tokens.lock().await, 500);
-        assert_eq!(*guest.bw_tokens.lock().await, 500 * optimal_io_size);
+        let _ = guest
+            .send(BlockOp::Read {
+                offset: Block::new_512(0),
+                data: Buffer::new(0),
+            })
+            .await;
+        assert!(guest.consume_req().await.is_none());
 
         Ok(())
     }
 
     // Is it possible to submit an IO that will never be sent? It shouldn't be!
-    #[tokio::test]
+    #[tokio::test(start_paused = true)]
     async fn test_impossible_io() -> Result<()> {
         let mut guest = Guest::new();
 
-        guest.set_iop_limit(1024 * 1024 / 2, 10); // 1 IOP is half a KiB
-        guest.set_bw_limit(1024 * 1024); // 1 KiB
+        guest.set_iop_limit(1024 * 1024 / 2, 10)?; // 1 IOP is half a MiB
+        guest.set_bw_limit(1024 * 1024)?; // 1 MiB
         assert!(guest.consume_req().await.is_none());
 
-        // Sending an IO of 10 KiB is larger than the bandwidth limit and
+        // Sending an IO of 10 MiB is larger than the bandwidth limit and
         // represents 20 IOPs, larger than the IOP limit.
         let _ = guest
             .send(BlockOp::Read {
@@ -4664,38 +6181,24 @@ mod up_test {
             })
             .await;
 
-        assert_eq!(*guest.iop_tokens.lock().await, 0);
-        assert_eq!(*guest.bw_tokens.lock().await, 0);
-
         // Even though the first IO is larger than the bandwidth and IOP limit,
         // it should still succeed. The next IO should not, even if it consumes
-        // nothing, because the iops and bw tokens will be larger than the limit
-        // for a while (until they leak enough).
+        // nothing, because the iops and bw buckets will be in debt for a
+        // while (until they fill enough).
 
         assert!(guest.consume_req().await.is_some());
         assert!(guest.consume_req().await.is_none());
 
-        assert_eq!(*guest.iop_tokens.lock().await, 20);
-        assert_eq!(*guest.bw_tokens.lock().await, 10 * 1024 * 1024);
-
-        // Bandwidth trigger is going to be larger and need more leaking to get
+        // Bandwidth debt is going to be larger and need more time to get
         // down to a point where the zero sized IO can fire.
         for _ in 0..9 {
-            guest.leak_iop_tokens(10).await;
-            guest.leak_bw_tokens(1024 * 1024).await;
-
+            tokio::time::advance(Duration::from_secs(1)).await;
             assert!(guest.consume_req().await.is_none());
         }
 
-        assert_eq!(*guest.iop_tokens.lock().await, 0);
-        assert_eq!(*guest.bw_tokens.lock().await, 1024 * 1024);
+        tokio::time::advance(Duration::from_millis(1)).await;
 
-        assert!(guest.consume_req().await.is_none());
-
-        guest.leak_iop_tokens(10).await;
-        guest.leak_bw_tokens(1024 * 1024).await;
-
-        // We've leaked 10 KiB worth, it should fire now!
+        // We've filled 9 MiB and a bit, it should fire now!
         assert!(guest.consume_req().await.is_some());
 
         Ok(())
@@ -5824,27 +7327,276 @@ mod up_test {
         //       block
         // op# | 0 1 2 | deps
         // ----|-------|-----
-        //   0 | W     |
-        //   1 | R     | 0
-        //   2 | R     | 0
+        //   0 | W     |
+        //   1 | R     | 0
+        //   2 | R     | 0
+
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        // op 0
+        upstairs
+            .submit_write(
+                Block::new_512(0),
+                Bytes::from(vec![0xff; 512]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        // op 1
+        upstairs
+            .submit_read(Block::new_512(0), Buffer::new(512), None)
+            .await
+            .unwrap();
+
+        // op 2
+        upstairs
+            .submit_read(Block::new_512(0), Buffer::new(512), None)
+            .await
+            .unwrap();
+
+        let ds = upstairs.downstairs.lock().await;
+        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
+        let jobs: Vec<&DownstairsIO> =
+            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+        assert_eq!(jobs.len(), 3);
+
+        assert!(jobs[0].work.deps().is_empty()); // write @ 0
+        assert_eq!(jobs[1].work.deps(), &[jobs[0].ds_id]); // read @ 0
+        assert_eq!(jobs[2].work.deps(), &[jobs[0].ds_id]); // read @ 0
+    }
+
+    #[tokio::test]
+    async fn test_deps_read_does_not_depend_on_flush() {
+        // Test that the following job dependency graph is made:
+        //
+        //       block
+        // op# | 0 1 2 | deps
+        // ----|-------|-----
+        //   0 | W     |
+        //   1 | FFFFF | 0
+        //   2 | R     | 0
+
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        // op 0
+        upstairs
+            .submit_write(
+                Block::new_512(0),
+                Bytes::from(vec![0xff; 512]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        // op 1
+        upstairs.submit_flush(None, None).await.unwrap();
+
+        // op 2
+        upstairs
+            .submit_read(Block::new_512(0), Buffer::new(512 * 2), None)
+            .await
+            .unwrap();
+
+        let ds = upstairs.downstairs.lock().await;
+        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
+        let jobs: Vec<&DownstairsIO> =
+            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+        assert_eq!(jobs.len(), 3);
+
+        assert!(jobs[0].work.deps().is_empty()); // write @ 0
+        assert_eq!(jobs[1].work.deps(), &[jobs[0].ds_id]); // flush
+        assert_eq!(jobs[2].work.deps(), &[jobs[0].ds_id]); // read @ 0
+    }
+
+    #[tokio::test]
+    async fn test_deps_flushes_depend_on_flushes() {
+        // Test that the following job dependency graph is made:
+        //
+        //       block
+        // op# | 0 1 2 | deps
+        // ----|-------|-----
+        //   0 | FFFFF |
+        //   1 | FFFFF | 0
+        //   2 | FFFFF | 1
+
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        upstairs.submit_flush(None, None).await.unwrap();
+
+        upstairs.submit_flush(None, None).await.unwrap();
+
+        upstairs.submit_flush(None, None).await.unwrap();
+
+        let ds = upstairs.downstairs.lock().await;
+        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
+        let jobs: Vec<&DownstairsIO> =
+            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+        assert_eq!(jobs.len(), 3);
+
+        assert!(jobs[0].work.deps().is_empty());
+        assert_eq!(jobs[1].work.deps(), &[jobs[0].ds_id]);
+        assert_eq!(jobs[2].work.deps(), &[jobs[1].ds_id]);
+    }
+
+    #[tokio::test]
+    async fn test_deps_flushes_depend_on_flushes_and_all_writes() {
+        // Test that the following job dependency graph is made:
+        //
+        //       block
+        // op# | 0 1 2 | deps
+        // ----|-------|-----
+        //   0 | FFFFF |
+        //   1 | W     | 0
+        //   2 |   W   | 0
+        //   3 | FFFFF | 0,1,2
+        //   4 | W     | 3
+        //   5 |   W   | 3
+        //   6 |     W | 3
+        //   7 | FFFFF | 3,4,5,6
+
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        // op 0
+        upstairs.submit_flush(None, None).await.unwrap();
+
+        // ops 1 to 2
+        for i in 0..2 {
+            upstairs
+                .submit_write(
+                    Block::new_512(i),
+                    Bytes::from(vec![0xff; 512]),
+                    None,
+                    false,
+                )
+                .await
+                .unwrap();
+        }
+
+        // op 3
+        upstairs.submit_flush(None, None).await.unwrap();
+
+        // ops 4 to 6
+        for i in 0..3 {
+            upstairs
+                .submit_write(
+                    Block::new_512(i),
+                    Bytes::from(vec![0xff; 512]),
+                    None,
+                    false,
+                )
+                .await
+                .unwrap();
+        }
+
+        // op 7
+        upstairs.submit_flush(None, None).await.unwrap();
+
+        let ds = upstairs.downstairs.lock().await;
+        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
+        let jobs: Vec<&DownstairsIO> =
+            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+        assert_eq!(jobs.len(), 8);
+
+        assert!(jobs[0].work.deps().is_empty()); // flush (op 0)
+
+        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // write (op 1)
+        assert_eq!(jobs[2].work.deps(), &vec![jobs[0].ds_id]); // write (op 2)
+
+        assert_eq!(
+            hashset(jobs[3].work.deps()), // flush (op 3)
+            hashset(&[jobs[0].ds_id, jobs[1].ds_id, jobs[2].ds_id]),
+        );
+
+        assert_eq!(jobs[4].work.deps(), &[jobs[3].ds_id]); // write (op 4)
+        assert_eq!(jobs[5].work.deps(), &[jobs[3].ds_id]); // write (op 5)
+        assert_eq!(jobs[6].work.deps(), &[jobs[3].ds_id]); // write (op 6)
+
+        assert_eq!(
+            hashset(jobs[7].work.deps()), // flush (op 7)
+            hashset(&[
+                jobs[3].ds_id,
+                jobs[4].ds_id,
+                jobs[5].ds_id,
+                jobs[6].ds_id
+            ]),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deps_writes_depend_on_read() {
+        // Test that the following job dependency graph is made:
+        //
+        //       block
+        // op# | 0 1 2 | deps
+        // ----|-------|-----
+        //   0 | R     |
+        //   1 | W     | 0
+
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        // op 0
+        upstairs
+            .submit_read(Block::new_512(0), Buffer::new(512), None)
+            .await
+            .unwrap();
+
+        // op 1
+        upstairs
+            .submit_write(
+                Block::new_512(0),
+                Bytes::from(vec![0xff; 512]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        let ds = upstairs.downstairs.lock().await;
+        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
+        let jobs: Vec<&DownstairsIO> =
+            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+        assert_eq!(jobs.len(), 2);
+
+        assert!(jobs[0].work.deps().is_empty()); // op 0
+        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
+    }
+
+    #[tokio::test]
+    async fn test_deps_write_unwrittens_depend_on_read() {
+        // Test that the following job dependency graph is made:
+        //
+        //       block
+        // op# | 0 1 2 | deps
+        // ----|-------|-----
+        //   0 | R     |
+        //   1 | Wu    | 0
+        //   2 | R     | 1
 
         let upstairs = make_upstairs();
         upstairs.set_active().await.unwrap();
 
         // op 0
         upstairs
-            .submit_write(
-                Block::new_512(0),
-                Bytes::from(vec![0xff; 512]),
-                None,
-                false,
-            )
+            .submit_read(Block::new_512(0), Buffer::new(512), None)
             .await
             .unwrap();
 
         // op 1
         upstairs
-            .submit_read(Block::new_512(0), Buffer::new(512), None)
+            .submit_write(
+                Block::new_512(0),
+                Bytes::from(vec![0xff; 512]),
+                None,
+                true,
+            )
             .await
             .unwrap();
 
@@ -5860,42 +7612,76 @@ mod up_test {
             keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
         assert_eq!(jobs.len(), 3);
 
-        assert!(jobs[0].work.deps().is_empty()); // write @ 0
-        assert_eq!(jobs[1].work.deps(), &[jobs[0].ds_id]); // read @ 0
-        assert_eq!(jobs[2].work.deps(), &[jobs[0].ds_id]); // read @ 0
+        assert!(jobs[0].work.deps().is_empty()); // op 0
+        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
+        assert_eq!(jobs[2].work.deps(), &vec![jobs[1].ds_id]); // op 2
     }
 
     #[tokio::test]
-    async fn test_deps_read_does_not_depend_on_flush() {
+    async fn test_deps_read_write_ladder_1() {
         // Test that the following job dependency graph is made:
         //
-        //       block
-        // op# | 0 1 2 | deps
-        // ----|-------|-----
-        //   0 | W     |
-        //   1 | FFFFF | 0
-        //   2 | R     | 0
+        //          block
+        // op# | 0 1 2 3 4 5 | deps
+        // ----|-------------|-----
+        //   0 | R           |
+        //   1 | Wu          | 0
+        //   2 |   R R       |
+        //   3 |   W W       | 2
+        //   4 |       R R   |
+        //   5 |       WuWu  | 4
 
         let upstairs = make_upstairs();
         upstairs.set_active().await.unwrap();
 
         // op 0
+        upstairs
+            .submit_read(Block::new_512(0), Buffer::new(512), None)
+            .await
+            .unwrap();
+
+        // op 1
         upstairs
             .submit_write(
                 Block::new_512(0),
                 Bytes::from(vec![0xff; 512]),
                 None,
+                true,
+            )
+            .await
+            .unwrap();
+
+        // op 2
+        upstairs
+            .submit_read(Block::new_512(1), Buffer::new(512 * 2), None)
+            .await
+            .unwrap();
+
+        // op 3
+        upstairs
+            .submit_write(
+                Block::new_512(1),
+                Bytes::from(vec![0xff; 512 * 2]),
+                None,
                 false,
             )
             .await
             .unwrap();
 
-        // op 1
-        upstairs.submit_flush(None, None).await.unwrap();
+        // op 4
+        upstairs
+            .submit_read(Block::new_512(3), Buffer::new(512 * 2), None)
+            .await
+            .unwrap();
 
-        // op 2
+        // op 5
         upstairs
-            .submit_read(Block::new_512(0), Buffer::new(512 * 2), None)
+            .submit_write(
+                Block::new_512(3),
+                Bytes::from(vec![0xff; 512 * 2]),
+                None,
+                true,
+            )
             .await
             .unwrap();
 
@@ -5903,72 +7689,82 @@ mod up_test {
         let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
         let jobs: Vec<&DownstairsIO> =
             keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 3);
+        assert_eq!(jobs.len(), 6);
 
-        assert!(jobs[0].work.deps().is_empty()); // write @ 0
-        assert_eq!(jobs[1].work.deps(), &[jobs[0].ds_id]); // flush
-        assert_eq!(jobs[2].work.deps(), &[jobs[0].ds_id]); // read @ 0
+        assert!(jobs[0].work.deps().is_empty()); // op 0
+        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
+
+        assert!(jobs[2].work.deps().is_empty()); // op 2
+        assert_eq!(jobs[3].work.deps(), &vec![jobs[2].ds_id]); // op 3
+
+        assert!(jobs[4].work.deps().is_empty()); // op 4
+        assert_eq!(jobs[5].work.deps(), &vec![jobs[4].ds_id]); // op 5
     }
 
     #[tokio::test]
-    async fn test_deps_flushes_depend_on_flushes() {
+    async fn test_deps_read_write_ladder_2() {
         // Test that the following job dependency graph is made:
         //
-        //       block
-        // op# | 0 1 2 | deps
-        // ----|-------|-----
-        //   0 | FFFFF |
-        //   1 | FFFFF | 0
-        //   2 | FFFFF | 1
+        //          block
+        // op# | 0 1 2 3 4 5 | deps
+        // ----|-------------|-----
+        //   0 | WuWu        |
+        //   1 |   WuWu      | 0
+        //   2 |     WuWu    | 1
+        //   3 |       WuWu  | 2
+        //   4 |         WuWu| 3
 
         let upstairs = make_upstairs();
         upstairs.set_active().await.unwrap();
 
-        upstairs.submit_flush(None, None).await.unwrap();
-
-        upstairs.submit_flush(None, None).await.unwrap();
-
-        upstairs.submit_flush(None, None).await.unwrap();
+        // ops 0 to 4
+        for i in 0..5 {
+            upstairs
+                .submit_write(
+                    Block::new_512(i),
+                    Bytes::from(vec![0xff; 512 * 2]),
+                    None,
+                    true,
+                )
+                .await
+                .unwrap();
+        }
 
         let ds = upstairs.downstairs.lock().await;
         let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
         let jobs: Vec<&DownstairsIO> =
             keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 3);
+        assert_eq!(jobs.len(), 5);
 
-        assert!(jobs[0].work.deps().is_empty());
-        assert_eq!(jobs[1].work.deps(), &[jobs[0].ds_id]);
-        assert_eq!(jobs[2].work.deps(), &[jobs[1].ds_id]);
+        assert!(jobs[0].work.deps().is_empty()); // op 0
+        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
+        assert_eq!(jobs[2].work.deps(), &vec![jobs[1].ds_id]); // op 2
+        assert_eq!(jobs[3].work.deps(), &vec![jobs[2].ds_id]); // op 3
+        assert_eq!(jobs[4].work.deps(), &vec![jobs[3].ds_id]); // op 4
     }
 
     #[tokio::test]
-    async fn test_deps_flushes_depend_on_flushes_and_all_writes() {
+    async fn test_deps_read_write_ladder_3() {
         // Test that the following job dependency graph is made:
         //
-        //       block
-        // op# | 0 1 2 | deps
-        // ----|-------|-----
-        //   0 | FFFFF |
-        //   1 | W     | 0
-        //   2 |   W   | 0
-        //   3 | FFFFF | 0,1,2
-        //   4 | W     | 3
-        //   5 |   W   | 3
-        //   6 |     W | 3
-        //   7 | FFFFF | 3,4,5,6
+        //          block
+        // op# | 0 1 2 3 4 5 | deps
+        // ----|-------------|-----
+        //   0 |         W W |
+        //   1 |       W W   | 0
+        //   2 |     W W     | 1
+        //   3 |   W W       | 2
+        //   4 | W W         | 3
 
         let upstairs = make_upstairs();
         upstairs.set_active().await.unwrap();
 
-        // op 0
-        upstairs.submit_flush(None, None).await.unwrap();
-
-        // ops 1 to 2
-        for i in 0..2 {
+        // ops 0 to 4
+        for i in (0..5).rev() {
             upstairs
                 .submit_write(
                     Block::new_512(i),
-                    Bytes::from(vec![0xff; 512]),
+                    Bytes::from(vec![0xff; 512 * 2]),
                     None,
                     false,
                 )
@@ -5976,79 +7772,192 @@ mod up_test {
                 .unwrap();
         }
 
-        // op 3
-        upstairs.submit_flush(None, None).await.unwrap();
+        let ds = upstairs.downstairs.lock().await;
+        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
+        let jobs: Vec<&DownstairsIO> =
+            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+        assert_eq!(jobs.len(), 5);
+
+        assert!(jobs[0].work.deps().is_empty()); // op 0
+        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
+        assert_eq!(jobs[2].work.deps(), &vec![jobs[1].ds_id]); // op 2
+        assert_eq!(jobs[3].work.deps(), &vec![jobs[2].ds_id]); // op 3
+        assert_eq!(jobs[4].work.deps(), &vec![jobs[3].ds_id]); // op 4
+    }
+
+    #[tokio::test]
+    async fn test_deps_read_write_batman() {
+        // Test that the following job dependency graph is made:
+        //
+        //          block
+        // op# | 0 1 2 3 4 5 | deps
+        // ----|-------------|-----
+        //   0 | W W         |
+        //   1 |         W W |
+        //   2 |   W W W W   | 0,1
+        //   3 |             |
+        //   4 |             |
+
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        // op 0
+        upstairs
+            .submit_write(
+                Block::new_512(0),
+                Bytes::from(vec![0xff; 512 * 2]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        // op 1
+        upstairs
+            .submit_write(
+                Block::new_512(4),
+                Bytes::from(vec![0xff; 512 * 2]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        // op 2
+        upstairs
+            .submit_write(
+                Block::new_512(1),
+                Bytes::from(vec![0xff; 512 * 4]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        let ds = upstairs.downstairs.lock().await;
+        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
+        let jobs: Vec<&DownstairsIO> =
+            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+        assert_eq!(jobs.len(), 3);
+
+        assert!(jobs[0].work.deps().is_empty()); // op 0
+        assert!(jobs[1].work.deps().is_empty()); // op 1
+        assert_eq!(
+            hashset(jobs[2].work.deps()),
+            hashset(&[jobs[0].ds_id, jobs[1].ds_id]),
+        ); // op 2
+    }
+
+    #[tokio::test]
+    async fn test_deps_multi_extent_write() {
+        // Test that the following job dependency graph is made:
+        //
+        //     |      block     |      block      |
+        // op# | 95 96 97 98 99 |  0  1  2  3  4  | deps
+        // ----|----------------|-----------------|-----
+        //   0 |  W  W          |                 |
+        //   1 |     W  W  W  W |  W  W  W        | 0
+        //   2 |                |        W  W     | 1
+
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        // op 0
+        upstairs
+            .submit_write(
+                Block::new_512(95),
+                Bytes::from(vec![0xff; 512 * 2]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
 
-        // ops 4 to 6
-        for i in 0..3 {
-            upstairs
-                .submit_write(
-                    Block::new_512(i),
-                    Bytes::from(vec![0xff; 512]),
-                    None,
-                    false,
-                )
-                .await
-                .unwrap();
-        }
+        // op 1
+        upstairs
+            .submit_write(
+                Block::new_512(96),
+                Bytes::from(vec![0xff; 512 * 7]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
 
-        // op 7
-        upstairs.submit_flush(None, None).await.unwrap();
+        // op 2
+        upstairs
+            .submit_write(
+                Block::new_512(102),
+                Bytes::from(vec![0xff; 512 * 2]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
 
         let ds = upstairs.downstairs.lock().await;
         let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
         let jobs: Vec<&DownstairsIO> =
             keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 8);
-
-        assert!(jobs[0].work.deps().is_empty()); // flush (op 0)
-
-        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // write (op 1)
-        assert_eq!(jobs[2].work.deps(), &vec![jobs[0].ds_id]); // write (op 2)
+        assert_eq!(jobs.len(), 3);
 
-        assert_eq!(
-            hashset(jobs[3].work.deps()), // flush (op 3)
-            hashset(&[jobs[0].ds_id, jobs[1].ds_id, jobs[2].ds_id]),
+        // confirm which extents are impacted (in case make_upstairs changes)
+        assert_eq!(jobs[0].impacted_blocks.extents().len(), 1);
+        assert_eq!(jobs[1].impacted_blocks.extents().len(), 2);
+        assert_eq!(jobs[2].impacted_blocks.extents().len(), 1);
+        assert_ne!(
+            jobs[0].impacted_blocks.extents()[0],
+            jobs[2].impacted_blocks.extents()[0]
         );
 
-        assert_eq!(jobs[4].work.deps(), &[jobs[3].ds_id]); // write (op 4)
-        assert_eq!(jobs[5].work.deps(), &[jobs[3].ds_id]); // write (op 5)
-        assert_eq!(jobs[6].work.deps(), &[jobs[3].ds_id]); // write (op 6)
-
-        assert_eq!(
-            hashset(jobs[7].work.deps()), // flush (op 7)
-            hashset(&[
-                jobs[3].ds_id,
-                jobs[4].ds_id,
-                jobs[5].ds_id,
-                jobs[6].ds_id
-            ]),
-        );
+        // confirm deps
+        assert!(jobs[0].work.deps().is_empty()); // op 0
+        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
+        assert_eq!(jobs[2].work.deps(), &vec![jobs[1].ds_id]); // op 2
     }
 
     #[tokio::test]
-    async fn test_deps_writes_depend_on_read() {
+    async fn test_deps_multi_extent_there_and_back_again() {
         // Test that the following job dependency graph is made:
         //
-        //       block
-        // op# | 0 1 2 | deps
-        // ----|-------|-----
-        //   0 | R     |
-        //   1 | W     | 0
+        //     |      block     |      block      |
+        // op# | 95 96 97 98 99 |  0  1  2  3  4  | deps
+        // ----|----------------|-----------------|-----
+        //   0 |  W  W          |                 |
+        //   1 |     W  W  W  W |  W  W  W        | 0
+        //   2 |                |     W           | 1
+        //   3 |              Wu|  Wu Wu          | 1,2
+        //   4 |              R |                 | 1,3
 
         let upstairs = make_upstairs();
         upstairs.set_active().await.unwrap();
 
         // op 0
         upstairs
-            .submit_read(Block::new_512(0), Buffer::new(512), None)
+            .submit_write(
+                Block::new_512(95),
+                Bytes::from(vec![0xff; 512 * 2]),
+                None,
+                false,
+            )
             .await
             .unwrap();
 
         // op 1
         upstairs
             .submit_write(
-                Block::new_512(0),
+                Block::new_512(96),
+                Bytes::from(vec![0xff; 512 * 7]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        // op 2
+        upstairs
+            .submit_write(
+                Block::new_512(101),
                 Bytes::from(vec![0xff; 512]),
                 None,
                 false,
@@ -6056,50 +7965,102 @@ mod up_test {
             .await
             .unwrap();
 
+        // op 3
+        upstairs
+            .submit_write(
+                Block::new_512(99),
+                Bytes::from(vec![0xff; 512 * 3]),
+                None,
+                true,
+            )
+            .await
+            .unwrap();
+
+        // op 4
+        upstairs
+            .submit_read(Block::new_512(99), Buffer::new(512), None)
+            .await
+            .unwrap();
+
         let ds = upstairs.downstairs.lock().await;
         let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
         let jobs: Vec<&DownstairsIO> =
             keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs.len(), 5);
+
+        // confirm which extents are impacted (in case make_upstairs changes)
+        assert_eq!(jobs[0].impacted_blocks.extents().len(), 1);
+        assert_eq!(jobs[1].impacted_blocks.extents().len(), 2);
+        assert_eq!(jobs[2].impacted_blocks.extents().len(), 1);
+        assert_eq!(jobs[3].impacted_blocks.extents().len(), 2);
+        assert_eq!(jobs[4].impacted_blocks.extents().len(), 1);
+
+        assert_ne!(
+            jobs[0].impacted_blocks.extents()[0],
+            jobs[2].impacted_blocks.extents()[0]
+        );
+        assert_ne!(
+            jobs[4].impacted_blocks.extents()[0],
+            jobs[2].impacted_blocks.extents()[0]
+        );
 
         assert!(jobs[0].work.deps().is_empty()); // op 0
         assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
+        assert_eq!(jobs[2].work.deps(), &vec![jobs[1].ds_id]); // op 2
+        assert_eq!(
+            hashset(jobs[3].work.deps()),
+            hashset(&[jobs[1].ds_id, jobs[2].ds_id])
+        ); // op 3
+        assert_eq!(
+            hashset(jobs[4].work.deps()),
+            hashset(&[jobs[1].ds_id, jobs[3].ds_id])
+        ); // op 4
     }
 
     #[tokio::test]
-    async fn test_deps_write_unwrittens_depend_on_read() {
+    async fn test_deps_multi_extent_batman() {
         // Test that the following job dependency graph is made:
         //
-        //       block
-        // op# | 0 1 2 | deps
-        // ----|-------|-----
-        //   0 | R     |
-        //   1 | Wu    | 0
-        //   2 | R     | 1
+        //     |      block     |      block      |
+        // op# | 95 96 97 98 99 |  0  1  2  3  4  | deps
+        // ----|----------------|-----------------|-----
+        //   0 |  W  W          |                 |
+        //   1 |                |        W        |
+        //   2 |     Wu Wu Wu Wu|  Wu Wu Wu       | 0,1
 
         let upstairs = make_upstairs();
         upstairs.set_active().await.unwrap();
 
         // op 0
         upstairs
-            .submit_read(Block::new_512(0), Buffer::new(512), None)
+            .submit_write(
+                Block::new_512(95),
+                Bytes::from(vec![0xff; 512 * 2]),
+                None,
+                false,
+            )
             .await
             .unwrap();
 
         // op 1
         upstairs
             .submit_write(
-                Block::new_512(0),
+                Block::new_512(102),
                 Bytes::from(vec![0xff; 512]),
                 None,
-                true,
+                false,
             )
             .await
             .unwrap();
 
         // op 2
         upstairs
-            .submit_read(Block::new_512(0), Buffer::new(512), None)
+            .submit_write(
+                Block::new_512(96),
+                Bytes::from(vec![0xff; 512 * 7]),
+                None,
+                true,
+            )
             .await
             .unwrap();
 
@@ -6109,697 +8070,1389 @@ mod up_test {
             keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
         assert_eq!(jobs.len(), 3);
 
+        // confirm which extents are impacted (in case make_upstairs changes)
+        assert_eq!(jobs[0].impacted_blocks.extents().len(), 1);
+        assert_eq!(jobs[1].impacted_blocks.extents().len(), 1);
+        assert_eq!(jobs[2].impacted_blocks.extents().len(), 2);
+
+        assert_ne!(
+            jobs[0].impacted_blocks.extents()[0],
+            jobs[1].impacted_blocks.extents()[0]
+        );
+
         assert!(jobs[0].work.deps().is_empty()); // op 0
-        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
-        assert_eq!(jobs[2].work.deps(), &vec![jobs[1].ds_id]); // op 2
+        assert!(jobs[1].work.deps().is_empty()); // op 1
+        assert_eq!(
+            hashset(jobs[2].work.deps()),
+            hashset(&[jobs[0].ds_id, jobs[1].ds_id])
+        ); // op 2
     }
 
     #[tokio::test]
-    async fn test_deps_read_write_ladder_1() {
-        // Test that the following job dependency graph is made:
-        //
-        //          block
-        // op# | 0 1 2 3 4 5 | deps
-        // ----|-------------|-----
-        //   0 | R           |
-        //   1 | Wu          | 0
-        //   2 |   R R       |
-        //   3 |   W W       | 2
-        //   4 |       R R   |
-        //   5 |       WuWu  | 4
+    async fn test_deps_depend_on_acked_work() {
+        // Test that jobs will depend on acked work (important for the case of
+        // replay - the upstairs will replay all work since the last flush if a
+        // downstairs leaves and comes back)
+
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
+
+        // submit a write, complete, then ack it
+
+        upstairs
+            .submit_write(
+                Block::new_512(0),
+                Bytes::from(vec![0xff; 512]),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        {
+            let mut ds = upstairs.downstairs.lock().await;
+            let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
+            let jobs: Vec<&DownstairsIO> =
+                keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+            assert_eq!(jobs.len(), 1);
+
+            let ds_id = jobs[0].ds_id;
+
+            for client_id in 0..3 {
+                ds.in_progress(ds_id, client_id);
+                ds.process_ds_completion(
+                    ds_id,
+                    client_id,
+                    Ok(vec![]),
+                    &None,
+                    UpState::Active,
+                )
+                .unwrap();
+            }
 
-        let upstairs = make_upstairs();
-        upstairs.set_active().await.unwrap();
+            ds.ack(ds_id);
+        }
 
-        // op 0
-        upstairs
-            .submit_read(Block::new_512(0), Buffer::new(512), None)
-            .await
-            .unwrap();
+        // submit an overlapping write
 
-        // op 1
         upstairs
             .submit_write(
                 Block::new_512(0),
                 Bytes::from(vec![0xff; 512]),
                 None,
-                true,
+                false,
             )
             .await
             .unwrap();
 
-        // op 2
-        upstairs
-            .submit_read(Block::new_512(1), Buffer::new(512 * 2), None)
-            .await
-            .unwrap();
+        {
+            let ds = upstairs.downstairs.lock().await;
+            let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
+            let jobs: Vec<&DownstairsIO> =
+                keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+
+            // retire_check not run yet, so there's two active jobs
+            assert_eq!(jobs.len(), 2);
+
+            // the second write should still depend on the first write!
+            assert_eq!(jobs[1].work.deps(), &[jobs[0].ds_id]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deps_retire_check() {
+        // Test that retire_check still works for flushes that depend on writes
+        // but not reads, where the read hasn't completed yet
+
+        let upstairs = make_upstairs();
+        upstairs.set_active().await.unwrap();
 
-        // op 3
         upstairs
             .submit_write(
-                Block::new_512(1),
-                Bytes::from(vec![0xff; 512 * 2]),
+                Block::new_512(0),
+                Bytes::from(vec![0xff; 512]),
                 None,
                 false,
             )
             .await
             .unwrap();
 
-        // op 4
         upstairs
-            .submit_read(Block::new_512(3), Buffer::new(512 * 2), None)
+            .submit_read(Block::new_512(1), Buffer::new(512), None)
             .await
             .unwrap();
 
-        // op 5
         upstairs
             .submit_write(
-                Block::new_512(3),
-                Bytes::from(vec![0xff; 512 * 2]),
+                Block::new_512(2),
+                Bytes::from(vec![0xff; 512]),
                 None,
-                true,
+                false,
             )
             .await
             .unwrap();
 
-        let ds = upstairs.downstairs.lock().await;
+        upstairs.submit_flush(None, None).await.unwrap();
+
+        // complete and ack the previous flush and both writes
+
+        let mut ds = upstairs.downstairs.lock().await;
         let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
         let jobs: Vec<&DownstairsIO> =
             keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 6);
-
-        assert!(jobs[0].work.deps().is_empty()); // op 0
-        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
-
-        assert!(jobs[2].work.deps().is_empty()); // op 2
-        assert_eq!(jobs[3].work.deps(), &vec![jobs[2].ds_id]); // op 3
-
-        assert!(jobs[4].work.deps().is_empty()); // op 4
-        assert_eq!(jobs[5].work.deps(), &vec![jobs[4].ds_id]); // op 5
-    }
-
-    #[tokio::test]
-    async fn test_deps_read_write_ladder_2() {
-        // Test that the following job dependency graph is made:
-        //
-        //          block
-        // op# | 0 1 2 3 4 5 | deps
-        // ----|-------------|-----
-        //   0 | WuWu        |
-        //   1 |   WuWu      | 0
-        //   2 |     WuWu    | 1
-        //   3 |       WuWu  | 2
-        //   4 |         WuWu| 3
+        assert_eq!(jobs.len(), 4);
 
-        let upstairs = make_upstairs();
-        upstairs.set_active().await.unwrap();
+        let write_1 = jobs[0].ds_id;
+        let read = jobs[1].ds_id;
+        let write_2 = jobs[2].ds_id;
+        let flush = jobs[3].ds_id;
 
-        // ops 0 to 4
-        for i in 0..5 {
-            upstairs
-                .submit_write(
-                    Block::new_512(i),
-                    Bytes::from(vec![0xff; 512 * 2]),
-                    None,
-                    true,
+        for ds_id in &[write_1, write_2] {
+            for client_id in 0..3 {
+                ds.in_progress(*ds_id, client_id);
+                ds.process_ds_completion(
+                    *ds_id,
+                    client_id,
+                    Ok(vec![]),
+                    &None,
+                    UpState::Active,
                 )
-                .await
                 .unwrap();
+            }
+
+            ds.ack(*ds_id);
+            ds.retire_check(*ds_id);
         }
 
-        let ds = upstairs.downstairs.lock().await;
-        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
-        let jobs: Vec<&DownstairsIO> =
-            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 5);
+        // complete and ack flush
+        for client_id in 0..3 {
+            ds.in_progress(flush, client_id);
+            ds.process_ds_completion(
+                flush,
+                client_id,
+                Ok(vec![]),
+                &None,
+                UpState::Active,
+            )
+            .unwrap();
+        }
 
-        assert!(jobs[0].work.deps().is_empty()); // op 0
-        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
-        assert_eq!(jobs[2].work.deps(), &vec![jobs[1].ds_id]); // op 2
-        assert_eq!(jobs[3].work.deps(), &vec![jobs[2].ds_id]); // op 3
-        assert_eq!(jobs[4].work.deps(), &vec![jobs[3].ds_id]); // op 4
-    }
+        ds.ack(flush);
 
-    #[tokio::test]
-    async fn test_deps_read_write_ladder_3() {
-        // Test that the following job dependency graph is made:
-        //
-        //          block
-        // op# | 0 1 2 3 4 5 | deps
-        // ----|-------------|-----
-        //   0 |         W W |
-        //   1 |       W W   | 0
-        //   2 |     W W     | 1
-        //   3 |   W W       | 2
-        //   4 | W W         | 3
+        // call retire_check on flush (where the read hasn't been acked yet)
 
-        let upstairs = make_upstairs();
-        upstairs.set_active().await.unwrap();
+        ds.retire_check(flush);
 
-        // ops 0 to 4
-        for i in (0..5).rev() {
-            upstairs
-                .submit_write(
-                    Block::new_512(i),
-                    Bytes::from(vec![0xff; 512 * 2]),
-                    None,
-                    false,
-                )
-                .await
-                .unwrap();
-        }
+        // only the read should be left (retire_check removes the flush and the
+        // deps of the flush)
 
-        let ds = upstairs.downstairs.lock().await;
         let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
         let jobs: Vec<&DownstairsIO> =
             keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 5);
+        assert_eq!(jobs.len(), 1);
 
-        assert!(jobs[0].work.deps().is_empty()); // op 0
-        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
-        assert_eq!(jobs[2].work.deps(), &vec![jobs[1].ds_id]); // op 2
-        assert_eq!(jobs[3].work.deps(), &vec![jobs[2].ds_id]); // op 3
-        assert_eq!(jobs[4].work.deps(), &vec![jobs[3].ds_id]); // op 4
-    }
+        assert_eq!(jobs[0].ds_id, read);
+        assert!(matches!(jobs[0].work, IOop::Read { .. }));
 
-    #[tokio::test]
-    async fn test_deps_read_write_batman() {
-        // Test that the following job dependency graph is made:
-        //
-        //          block
-        // op# | 0 1 2 3 4 5 | deps
-        // ----|-------------|-----
-        //   0 | W W         |
-        //   1 |         W W |
-        //   2 |   W W W W   | 0,1
-        //   3 |             |
-        //   4 |             |
+        // complete and ack the read, then do another flush. this should retire
+        // everything.
+        for client_id in 0..3 {
+            ds.in_progress(read, client_id);
 
-        let upstairs = make_upstairs();
-        upstairs.set_active().await.unwrap();
+            let request = ReadRequest {
+                eid: 0,
+                offset: Block::new_512(1),
+            };
 
-        // op 0
-        upstairs
-            .submit_write(
-                Block::new_512(0),
-                Bytes::from(vec![0xff; 512 * 2]),
-                None,
-                false,
+            ds.process_ds_completion(
+                read,
+                client_id,
+                Ok(vec![ReadResponse::from_request_with_data(&request, &[])]),
+                &None,
+                UpState::Active,
             )
-            .await
             .unwrap();
+        }
 
-        // op 1
-        upstairs
-            .submit_write(
-                Block::new_512(4),
-                Bytes::from(vec![0xff; 512 * 2]),
-                None,
-                false,
-            )
-            .await
-            .unwrap();
+        ds.ack(read);
+        ds.retire_check(read);
 
-        // op 2
-        upstairs
-            .submit_write(
-                Block::new_512(1),
-                Bytes::from(vec![0xff; 512 * 4]),
-                None,
-                false,
+        drop(ds);
+        upstairs.submit_flush(None, None).await.unwrap();
+
+        let mut ds = upstairs.downstairs.lock().await;
+        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
+        let jobs: Vec<&DownstairsIO> =
+            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+        assert_eq!(jobs.len(), 2);
+
+        let flush = jobs[1].ds_id;
+
+        for client_id in 0..3 {
+            ds.in_progress(flush, client_id);
+            ds.process_ds_completion(
+                flush,
+                client_id,
+                Ok(vec![]),
+                &None,
+                UpState::Active,
             )
-            .await
             .unwrap();
+        }
 
-        let ds = upstairs.downstairs.lock().await;
-        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
-        let jobs: Vec<&DownstairsIO> =
-            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 3);
+        ds.ack(flush);
+        ds.retire_check(flush);
 
-        assert!(jobs[0].work.deps().is_empty()); // op 0
-        assert!(jobs[1].work.deps().is_empty()); // op 1
-        assert_eq!(
-            hashset(jobs[2].work.deps()),
-            hashset(&[jobs[0].ds_id, jobs[1].ds_id]),
-        ); // op 2
+        assert_eq!(ds.ds_active.keys().count(), 0);
     }
 
-    #[tokio::test]
-    async fn test_deps_multi_extent_write() {
-        // Test that the following job dependency graph is made:
-        //
-        //     |      block     |      block      |
-        // op# | 95 96 97 98 99 |  0  1  2  3  4  | deps
-        // ----|----------------|-----------------|-----
-        //   0 |  W  W          |                 |
-        //   1 |     W  W  W  W |  W  W  W        | 0
-        //   2 |                |        W  W     | 1
+    /*
+     * Set up an active upstairs with three active downstairs at known
+     * addresses, for the migration tests below.
+     */
+    async fn make_migration_upstairs() -> Arc<Upstairs> {
+        let up = make_upstairs();
+        up.set_active().await.unwrap();
+        let mut ds = up.downstairs.lock().await;
+        for cid in 0..3 {
+            ds.ds_target.insert(cid, migration_target(cid as u16));
+            ds.ds_state[cid as usize] = DsState::Active;
+        }
+        drop(ds);
+        up
+    }
 
-        let upstairs = make_upstairs();
-        upstairs.set_active().await.unwrap();
+    fn migration_target(n: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 3810 + n)
+    }
 
-        // op 0
-        upstairs
-            .submit_write(
-                Block::new_512(95),
-                Bytes::from(vec![0xff; 512 * 2]),
-                None,
-                false,
-            )
-            .await
-            .unwrap();
+    /*
+     * Walk a migration of client 1 through copying one extent, with
+     * client 0 as the source.
+     */
+    fn migration_copy_extent(ds: &mut Downstairs, eid: usize) {
+        // Close the extent on the source and the new downstairs.
+        assert!(ds.migration_advance());
+        let close = ds.reconcile_current_work.as_ref().unwrap().id;
+        match ds.rep_in_progress(0) {
+            Some(Message::ExtentClose { extent_id, .. }) => {
+                assert_eq!(extent_id, eid)
+            }
+            x => panic!("expected close, got {:?}", x),
+        }
+        assert!(ds.rep_in_progress(1).is_some());
+        assert!(ds.rep_in_progress(2).is_none());
+        assert!(!ds.migration_rep_done(0, close));
+        assert!(ds.migration_rep_done(1, close));
+
+        // Only the new downstairs gets the repair.
+        assert!(ds.migration_advance());
+        let repair = ds.reconcile_current_work.as_ref().unwrap().id;
+        assert!(ds.rep_in_progress(0).is_none());
+        match ds.rep_in_progress(1) {
+            Some(Message::ExtentRepair {
+                extent_id,
+                source_client_id,
+                dest_clients,
+                ..
+            }) => {
+                assert_eq!(extent_id, eid);
+                assert_eq!(source_client_id, 0);
+                assert_eq!(dest_clients, vec![1]);
+            }
+            x => panic!("expected repair, got {:?}", x),
+        }
+        assert!(ds.rep_in_progress(2).is_none());
+        assert!(ds.migration_rep_done(1, repair));
+
+        // Both reopen the extent.
+        assert!(ds.migration_advance());
+        let reopen = ds.reconcile_current_work.as_ref().unwrap().id;
+        assert!(matches!(
+            ds.rep_in_progress(0),
+            Some(Message::ExtentReopen { .. })
+        ));
+        assert!(ds.rep_in_progress(1).is_some());
+        assert!(ds.rep_in_progress(2).is_none());
+        assert!(!ds.migration_rep_done(0, reopen));
+        assert!(ds.migration_rep_done(1, reopen));
+    }
 
-        // op 1
-        upstairs
-            .submit_write(
-                Block::new_512(96),
-                Bytes::from(vec![0xff; 512 * 7]),
-                None,
-                false,
+    /*
+     * Start a migration of client 1 on this downstairs and get the new
+     * downstairs connected.  Return the first job it will see.
+     */
+    fn migration_connect(ds: &mut Downstairs, extent_count: u32) -> u64 {
+        ds.ds_repair.insert(0, migration_target(10));
+        ds.ds_repair.insert(2, migration_target(12));
+        let cid = ds
+            .migration_start(
+                migration_target(1),
+                migration_target(5),
+                extent_count,
             )
-            .await
             .unwrap();
+        assert_eq!(cid, 1);
+        ds.ds_state[1] = DsState::Migrating;
 
-        // op 2
-        upstairs
-            .submit_write(
-                Block::new_512(102),
-                Bytes::from(vec![0xff; 512 * 2]),
-                None,
-                false,
-            )
-            .await
-            .unwrap();
+        // Nothing to do until the new downstairs is ready.
+        assert!(!ds.migration_advance());
 
-        let ds = upstairs.downstairs.lock().await;
-        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
-        let jobs: Vec<&DownstairsIO> =
-            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 3);
+        let first_job = ds.migration_connected(1).unwrap();
+        ds.migration_ready(1, first_job - 1).unwrap();
+        first_job
+    }
 
-        // confirm which extents are impacted (in case make_upstairs changes)
-        assert_eq!(jobs[0].impacted_blocks.extents().len(), 1);
-        assert_eq!(jobs[1].impacted_blocks.extents().len(), 2);
-        assert_eq!(jobs[2].impacted_blocks.extents().len(), 1);
-        assert_ne!(
-            jobs[0].impacted_blocks.extents()[0],
-            jobs[2].impacted_blocks.extents()[0]
-        );
+    #[tokio::test]
+    async fn migrate_downstairs_requires_active_upstairs() {
+        let up = make_upstairs();
+        let res = up
+            .migrate_downstairs(migration_target(0), migration_target(5))
+            .await;
+        assert!(matches!(res, Err(CrucibleError::UpstairsInactive)));
+    }
 
-        // confirm deps
-        assert!(jobs[0].work.deps().is_empty()); // op 0
-        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
-        assert_eq!(jobs[2].work.deps(), &vec![jobs[1].ds_id]); // op 2
+    #[tokio::test]
+    async fn migrate_downstairs_bad_targets() {
+        let up = make_migration_upstairs().await;
+
+        // The old downstairs has to be one we are using.
+        let res = up
+            .migrate_downstairs(migration_target(5), migration_target(6))
+            .await;
+        assert!(matches!(res, Err(CrucibleError::MigrationError(_))));
+
+        // The new downstairs must not be one we are already using.
+        let res = up
+            .migrate_downstairs(migration_target(0), migration_target(2))
+            .await;
+        assert!(matches!(res, Err(CrucibleError::MigrationError(_))));
+
+        let ds = up.downstairs.lock().await;
+        assert!(ds.migration.is_none());
+        assert_eq!(ds.ds_state, vec![DsState::Active; 3]);
     }
 
     #[tokio::test]
-    async fn test_deps_multi_extent_there_and_back_again() {
-        // Test that the following job dependency graph is made:
-        //
-        //     |      block     |      block      |
-        // op# | 95 96 97 98 99 |  0  1  2  3  4  | deps
-        // ----|----------------|-----------------|-----
-        //   0 |  W  W          |                 |
-        //   1 |     W  W  W  W |  W  W  W        | 0
-        //   2 |                |     W           | 1
-        //   3 |              Wu|  Wu Wu          | 1,2
-        //   4 |              R |                 | 1,3
+    async fn migrate_downstairs_requires_all_active() {
+        let up = make_migration_upstairs().await;
+        up.downstairs.lock().await.ds_state[2] = DsState::Offline;
 
-        let upstairs = make_upstairs();
-        upstairs.set_active().await.unwrap();
+        let res = up
+            .migrate_downstairs(migration_target(0), migration_target(5))
+            .await;
+        assert!(matches!(res, Err(CrucibleError::MigrationError(_))));
+        assert!(up.downstairs.lock().await.migration.is_none());
+    }
 
-        // op 0
-        upstairs
-            .submit_write(
-                Block::new_512(95),
-                Bytes::from(vec![0xff; 512 * 2]),
-                None,
-                false,
-            )
-            .await
-            .unwrap();
+    #[tokio::test]
+    async fn migrate_downstairs_skips_old_jobs() {
+        // Jobs issued before the migration starts are never sent to the
+        // new downstairs.
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        let next_id = ds.next_id();
+        ds.enqueue(create_flush(
+            next_id,
+            vec![],
+            10,
+            0,
+            0,
+            None,
+            ImpactedBlocks::default(),
+        ));
+        ds.in_progress(next_id, 0);
+        drop(ds);
 
-        // op 1
-        upstairs
-            .submit_write(
-                Block::new_512(96),
-                Bytes::from(vec![0xff; 512 * 7]),
-                None,
-                false,
-            )
+        up.migrate_downstairs(migration_target(1), migration_target(5))
             .await
             .unwrap();
+        assert_eq!(up.ds_state(1).await, DsState::Migrating);
+        assert_eq!(up.ds_target(1).await, migration_target(5));
 
-        // op 2
-        upstairs
-            .submit_write(
-                Block::new_512(101),
-                Bytes::from(vec![0xff; 512]),
-                None,
-                false,
-            )
-            .await
-            .unwrap();
+        let mut ds = up.downstairs.lock().await;
+        let job = ds.ds_active.get(&next_id).unwrap();
+        assert_eq!(job.state.get(&0), Some(&IOState::InProgress));
+        assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+        assert_eq!(job.state.get(&2), Some(&IOState::New));
 
-        // op 3
-        upstairs
-            .submit_write(
-                Block::new_512(99),
-                Bytes::from(vec![0xff; 512 * 3]),
-                None,
-                true,
-            )
-            .await
-            .unwrap();
+        // New jobs are also skipped until the new downstairs connects.
+        let next_id = ds.next_id();
+        ds.enqueue(create_flush(
+            next_id,
+            vec![],
+            11,
+            0,
+            0,
+            None,
+            ImpactedBlocks::default(),
+        ));
+        assert!(ds.in_progress(next_id, 1).is_none());
+        let job = ds.ds_active.get(&next_id).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+        drop(ds);
 
-        // op 4
-        upstairs
-            .submit_read(Block::new_512(99), Buffer::new(512), None)
+        // Only one migration at a time.
+        let res = up
+            .migrate_downstairs(migration_target(2), migration_target(6))
+            .await;
+        assert!(matches!(res, Err(CrucibleError::MigrationError(_))));
+    }
+
+    #[tokio::test]
+    async fn migrate_downstairs_no_deactivate() {
+        let up = make_migration_upstairs().await;
+        up.migrate_downstairs(migration_target(1), migration_target(5))
             .await
             .unwrap();
+        assert!(up.set_deactivate(None).await.is_err());
+        assert_eq!(up.active.lock().await.up_state, UpState::Active);
+    }
 
-        let ds = upstairs.downstairs.lock().await;
-        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
-        let jobs: Vec<&DownstairsIO> =
-            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 5);
-
-        // confirm which extents are impacted (in case make_upstairs changes)
-        assert_eq!(jobs[0].impacted_blocks.extents().len(), 1);
-        assert_eq!(jobs[1].impacted_blocks.extents().len(), 2);
-        assert_eq!(jobs[2].impacted_blocks.extents().len(), 1);
-        assert_eq!(jobs[3].impacted_blocks.extents().len(), 2);
-        assert_eq!(jobs[4].impacted_blocks.extents().len(), 1);
+    #[tokio::test]
+    async fn migration_copies_every_extent() {
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        migration_connect(&mut ds, 3);
 
-        assert_ne!(
-            jobs[0].impacted_blocks.extents()[0],
-            jobs[2].impacted_blocks.extents()[0]
-        );
-        assert_ne!(
-            jobs[4].impacted_blocks.extents()[0],
-            jobs[2].impacted_blocks.extents()[0]
-        );
+        for eid in 0..3 {
+            migration_copy_extent(&mut ds, eid);
+        }
 
-        assert!(jobs[0].work.deps().is_empty()); // op 0
-        assert_eq!(jobs[1].work.deps(), &vec![jobs[0].ds_id]); // op 1
-        assert_eq!(jobs[2].work.deps(), &vec![jobs[1].ds_id]); // op 2
-        assert_eq!(
-            hashset(jobs[3].work.deps()),
-            hashset(&[jobs[1].ds_id, jobs[2].ds_id])
-        ); // op 3
-        assert_eq!(
-            hashset(jobs[4].work.deps()),
-            hashset(&[jobs[1].ds_id, jobs[3].ds_id])
-        ); // op 4
+        // After the last extent, the new downstairs takes over.
+        drop(ds);
+        assert!(up.migration_progress().await);
+        let ds = up.downstairs.lock().await;
+        assert!(ds.migration.is_none());
+        assert_eq!(ds.ds_state[1], DsState::Active);
+        assert_eq!(*ds.ds_target.get(&1).unwrap(), migration_target(5));
+        drop(ds);
+        assert_eq!(up.opts.lock().await.target[1], migration_target(5));
     }
 
     #[tokio::test]
-    async fn test_deps_multi_extent_batman() {
-        // Test that the following job dependency graph is made:
-        //
-        //     |      block     |      block      |
-        // op# | 95 96 97 98 99 |  0  1  2  3  4  | deps
-        // ----|----------------|-----------------|-----
-        //   0 |  W  W          |                 |
-        //   1 |                |        W        |
-        //   2 |     Wu Wu Wu Wu|  Wu Wu Wu       | 0,1
+    async fn migration_fence_holds_io() {
+        // An extent is not closed until the source and the new downstairs
+        // have finished all IO from before the fence, and IO after the
+        // fence is held back from them until the extent is copied.
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        let first_job = migration_connect(&mut ds, 1);
+
+        let before = ds.next_id();
+        assert_eq!(before, first_job);
+        ds.enqueue(create_flush(
+            before,
+            vec![],
+            10,
+            0,
+            0,
+            None,
+            ImpactedBlocks::default(),
+        ));
 
-        let upstairs = make_upstairs();
-        upstairs.set_active().await.unwrap();
+        // Setting the fence has no work for the downstairs yet.
+        assert!(!ds.migration_advance());
+        assert!(ds.reconcile_current_work.is_none());
 
-        // op 0
-        upstairs
-            .submit_write(
-                Block::new_512(95),
-                Bytes::from(vec![0xff; 512 * 2]),
-                None,
-                false,
-            )
-            .await
-            .unwrap();
+        let after = ds.next_id();
+        ds.enqueue(create_flush(
+            after,
+            vec![before],
+            11,
+            0,
+            0,
+            None,
+            ImpactedBlocks::default(),
+        ));
+        for cid in 0..3 {
+            assert!(!ds.migration_fenced(cid, before));
+        }
+        assert!(ds.migration_fenced(0, after));
+        assert!(ds.migration_fenced(1, after));
+        assert!(!ds.migration_fenced(2, after));
 
-        // op 1
-        upstairs
-            .submit_write(
-                Block::new_512(102),
-                Bytes::from(vec![0xff; 512]),
-                None,
-                false,
+        for cid in 0..3 {
+            assert!(ds.in_progress(before, cid).is_some());
+            assert!(ds.migration_drained(cid).is_none());
+            ds.process_ds_completion(
+                before,
+                cid,
+                Ok(vec![]),
+                &None,
+                UpState::Active,
             )
-            .await
             .unwrap();
+        }
+        assert!(ds.migration_drained(0).is_some());
+        assert!(ds.migration_drained(2).is_none());
 
-        // op 2
-        upstairs
-            .submit_write(
-                Block::new_512(96),
-                Bytes::from(vec![0xff; 512 * 7]),
-                None,
-                true,
-            )
-            .await
-            .unwrap();
+        migration_copy_extent(&mut ds, 0);
+        drop(ds);
+        assert!(up.migration_progress().await);
+        let ds = up.downstairs.lock().await;
+        assert!(ds.migration.is_none());
+        for cid in 0..3 {
+            assert!(!ds.migration_fenced(cid, after));
+        }
+    }
 
-        let ds = upstairs.downstairs.lock().await;
-        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
-        let jobs: Vec<&DownstairsIO> =
-            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 3);
+    #[tokio::test]
+    async fn migration_read_from_new_downstairs_is_ignored() {
+        // The new downstairs may not have the data for a read yet, so
+        // a read it returns is not used.
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        migration_connect(&mut ds, 3);
 
-        // confirm which extents are impacted (in case make_upstairs changes)
-        assert_eq!(jobs[0].impacted_blocks.extents().len(), 1);
-        assert_eq!(jobs[1].impacted_blocks.extents().len(), 1);
-        assert_eq!(jobs[2].impacted_blocks.extents().len(), 2);
+        let id = ds.next_id();
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+        };
+        ds.enqueue(create_read_eob(
+            id,
+            vec![],
+            10,
+            vec![request.clone()],
+            ImpactedBlocks::default(),
+        ));
+        for cid in 0..3 {
+            assert!(ds.in_progress(id, cid).is_some());
+        }
 
-        assert_ne!(
-            jobs[0].impacted_blocks.extents()[0],
-            jobs[1].impacted_blocks.extents()[0]
-        );
+        let response =
+            Ok(vec![ReadResponse::from_request_with_data(&request, &[1])]);
+        assert!(!ds
+            .process_ds_completion(id, 1, response, &None, UpState::Active)
+            .unwrap());
+        let job = ds.ds_active.get(&id).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+        assert_eq!(job.ack_status, AckStatus::NotAcked);
+        assert!(job.data.is_none());
 
-        assert!(jobs[0].work.deps().is_empty()); // op 0
-        assert!(jobs[1].work.deps().is_empty()); // op 1
-        assert_eq!(
-            hashset(jobs[2].work.deps()),
-            hashset(&[jobs[0].ds_id, jobs[1].ds_id])
-        ); // op 2
+        let response =
+            Ok(vec![ReadResponse::from_request_with_data(&request, &[2])]);
+        assert!(ds
+            .process_ds_completion(id, 0, response, &None, UpState::Active)
+            .unwrap());
+        let job = ds.ds_active.get(&id).unwrap();
+        assert_eq!(job.ack_status, AckStatus::AckReady);
+        assert_eq!(job.data.as_ref().unwrap()[0].data[..], [2]);
     }
 
     #[tokio::test]
-    async fn test_deps_depend_on_acked_work() {
-        // Test that jobs will depend on acked work (important for the case of
-        // replay - the upstairs will replay all work since the last flush if a
-        // downstairs leaves and comes back)
+    async fn migration_source_missing_retries_extent() {
+        // If the source goes away while an extent is being copied, the
+        // new downstairs reopens it and the copy of that extent starts
+        // over from another downstairs.
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        migration_connect(&mut ds, 2);
+        migration_copy_extent(&mut ds, 0);
 
-        let upstairs = make_upstairs();
-        upstairs.set_active().await.unwrap();
+        // Start on extent 1, then lose the source.
+        assert!(ds.migration_advance());
+        let close = ds.reconcile_current_work.as_ref().unwrap().id;
+        assert!(ds.rep_in_progress(0).is_some());
+        assert!(ds.rep_in_progress(1).is_some());
+        assert!(!ds.migration_rep_done(1, close));
+        ds.ds_state[0] = DsState::Offline;
+        ds.migration_missing(0);
+
+        // A late reply from the source is ignored.
+        assert!(!ds.migration_rep_done(0, close));
+
+        // Only the new downstairs closed the extent, so only it reopens.
+        assert!(ds.migration_advance());
+        let reopen = ds.reconcile_current_work.as_ref().unwrap().id;
+        assert!(ds.rep_in_progress(0).is_none());
+        assert!(matches!(
+            ds.rep_in_progress(1),
+            Some(Message::ExtentReopen { extent_id: 1, .. })
+        ));
+        assert!(ds.rep_in_progress(2).is_none());
+        assert!(ds.migration_rep_done(1, reopen));
+
+        // Extent 1 is copied again, this time from client 2.
+        assert!(ds.migration_advance());
+        assert_eq!(ds.migration.as_ref().unwrap().next_extent, 1);
+        assert_eq!(ds.migration.as_ref().unwrap().source, 2);
+        assert!(ds.rep_in_progress(0).is_none());
+        assert!(matches!(
+            ds.rep_in_progress(2),
+            Some(Message::ExtentClose { extent_id: 1, .. })
+        ));
+    }
 
-        // submit a write, complete, then ack it
+    #[tokio::test]
+    async fn migration_new_downstairs_missing_starts_over() {
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        migration_connect(&mut ds, 2);
+        migration_copy_extent(&mut ds, 0);
 
-        upstairs
-            .submit_write(
-                Block::new_512(0),
-                Bytes::from(vec![0xff; 512]),
-                None,
-                false,
-            )
-            .await
-            .unwrap();
+        // Lose the new downstairs while it is repairing extent 1.
+        assert!(ds.migration_advance());
+        let close = ds.reconcile_current_work.as_ref().unwrap().id;
+        assert!(ds.rep_in_progress(0).is_some());
+        assert!(ds.rep_in_progress(1).is_some());
+        assert!(!ds.migration_rep_done(0, close));
+        assert!(ds.migration_rep_done(1, close));
+        assert!(ds.migration_advance());
+        assert!(ds.rep_in_progress(1).is_some());
+        ds.migration_missing(1);
 
-        {
-            let mut ds = upstairs.downstairs.lock().await;
-            let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
-            let jobs: Vec<&DownstairsIO> =
-                keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-            assert_eq!(jobs.len(), 1);
+        // The source still reopens the extent it closed.
+        assert!(ds.migration_advance());
+        let reopen = ds.reconcile_current_work.as_ref().unwrap().id;
+        assert!(ds.rep_in_progress(1).is_none());
+        assert!(ds.rep_in_progress(0).is_some());
+        assert!(ds.migration_rep_done(0, reopen));
+
+        // Then we wait for the new downstairs to come back.
+        ds.migration_advance();
+        assert!(ds.reconcile_current_work.is_none());
+        assert_eq!(ds.migration.as_ref().unwrap().step, MigrateStep::Connect);
+
+        // Once it does, the copy starts again at the first extent.
+        let first_job = ds.migration_connected(1).unwrap();
+        ds.migration_ready(1, first_job - 1).unwrap();
+        migration_copy_extent(&mut ds, 0);
+        migration_copy_extent(&mut ds, 1);
+        drop(ds);
+        assert!(up.migration_progress().await);
+        let ds = up.downstairs.lock().await;
+        assert_eq!(ds.ds_state[1], DsState::Active);
+    }
 
-            let ds_id = jobs[0].ds_id;
+    /*
+     * Queue a write of one block to each of these extents.
+     */
+    fn enqueue_extent_writes(ds: &mut Downstairs, eids: &[u64]) -> u64 {
+        let id = ds.next_id();
+        let writes = eids
+            .iter()
+            .map(|eid| crucible_protocol::Write {
+                eid: *eid,
+                offset: Block::new_512(7),
+                data: Bytes::from(vec![1]),
+                block_context: BlockContext {
+                    encryption_context: None,
+                    hash: 0,
+                },
+            })
+            .collect();
+        ds.enqueue(create_write_eob(
+            id,
+            vec![],
+            10,
+            writes,
+            false,
+            ImpactedBlocks::default(),
+        ));
+        id
+    }
 
-            for client_id in 0..3 {
-                ds.in_progress(ds_id, client_id);
+    #[tokio::test]
+    async fn offline_past_replay_window_tracks_dirty_extents() {
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        ds.ds_uuid.insert(1, Uuid::new_v4());
+        ds.replay_window = 3;
+        ds.ds_state[1] = DsState::Offline;
+
+        // Within the window, jobs are held for replay.
+        let first = enqueue_extent_writes(&mut ds, &[2]);
+        let second = enqueue_extent_writes(&mut ds, &[4, 5]);
+        assert!(ds.in_progress(first, 0).is_some());
+        assert!(!ds.dirty.is_tracking(1));
+        let job = ds.ds_active.get(&second).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::New));
+
+        // Past it, the offline downstairs skips every job since its last
+        // flush, and the extents they change are dirty.
+        let read = ds.next_id();
+        ds.enqueue(create_read_eob(
+            read,
+            vec![],
+            10,
+            vec![ReadRequest {
+                eid: 9,
+                offset: Block::new_512(7),
+            }],
+            ImpactedBlocks::default(),
+        ));
+        let third = enqueue_extent_writes(&mut ds, &[1]);
+        assert!(ds.dirty.is_tracking(1));
+        assert_eq!(ds.dirty.extents(1), vec![1, 2, 4, 5]);
+        for id in [first, second, read, third] {
+            let job = ds.ds_active.get(&id).unwrap();
+            assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+        }
+        let job = ds.ds_active.get(&first).unwrap();
+        assert_eq!(job.state.get(&0), Some(&IOState::InProgress));
+        assert_eq!(job.state.get(&2), Some(&IOState::New));
+
+        // New jobs are skipped for it from now on.
+        let fourth = enqueue_extent_writes(&mut ds, &[8]);
+        let job = ds.ds_active.get(&fourth).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+        assert_eq!(job.state.get(&2), Some(&IOState::New));
+        assert_eq!(ds.dirty.extents(1), vec![1, 2, 4, 5, 8]);
+
+        // Other downstairs are not tracked.
+        assert!(!ds.dirty.is_tracking(0));
+        assert!(!ds.dirty.is_tracking(2));
+    }
+
+    #[tokio::test]
+    async fn offline_returns_to_catch_up() {
+        // A downstairs offline past the replay window comes back through
+        // a migration to itself that copies only the dirty extents.
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        ds.ds_uuid.insert(1, Uuid::new_v4());
+        ds.ds_repair.insert(0, migration_target(10));
+        ds.ds_repair.insert(2, migration_target(12));
+        ds.replay_window = 0;
+        ds.ds_state[1] = DsState::Offline;
+        for eid in [3, 1] {
+            let id = enqueue_extent_writes(&mut ds, &[eid]);
+            for cid in [0, 2] {
+                ds.in_progress(id, cid);
                 ds.process_ds_completion(
-                    ds_id,
-                    client_id,
+                    id,
+                    cid,
                     Ok(vec![]),
                     &None,
                     UpState::Active,
                 )
                 .unwrap();
             }
+        }
+        assert_eq!(ds.dirty.extents(1), vec![1, 3]);
+        drop(ds);
 
-            ds.ack(ds_id);
+        up.catch_up_start(1).await.unwrap();
+        assert_eq!(up.ds_state(1).await, DsState::Migrating);
+        assert_eq!(up.ds_target(1).await, migration_target(1));
+
+        let mut ds = up.downstairs.lock().await;
+        let first_job = ds.migration_connected(1).unwrap();
+        ds.migration_ready(1, first_job - 1).unwrap();
+        migration_copy_extent(&mut ds, 1);
+        migration_copy_extent(&mut ds, 3);
+        assert_eq!(ds.dirty.extents(1), vec![3]);
+        drop(ds);
+        assert!(up.migration_progress().await);
+        let ds = up.downstairs.lock().await;
+        assert!(ds.migration.is_none());
+        assert_eq!(ds.ds_state[1], DsState::Active);
+        assert!(!ds.dirty.is_tracking(1));
+    }
+
+    #[tokio::test]
+    async fn catch_up_missing_marks_unflushed_work_dirty() {
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        ds.ds_uuid.insert(1, Uuid::new_v4());
+        ds.ds_repair.insert(0, migration_target(10));
+        ds.replay_window = 0;
+        ds.ds_state[1] = DsState::Offline;
+        enqueue_extent_writes(&mut ds, &[3]);
+        ds.catch_up_start(1).unwrap();
+        ds.ds_state[1] = DsState::Migrating;
+        let first_job = ds.migration_connected(1).unwrap();
+        ds.migration_ready(1, first_job - 1).unwrap();
+
+        // The downstairs does a write to another extent, then goes away
+        // before it flushes it.
+        let id = enqueue_extent_writes(&mut ds, &[6]);
+        assert!(ds.in_progress(id, 1).is_some());
+        assert!(!ds
+            .process_ds_completion(id, 1, Ok(vec![]), &None, UpState::Active)
+            .unwrap());
+        assert_eq!(ds.dirty.extents(1), vec![3]);
+        ds.migration_missing(1);
+        assert_eq!(ds.dirty.extents(1), vec![3, 6]);
+
+        // When it comes back it copies both.
+        ds.migration_connected(1).unwrap();
+        assert_eq!(ds.migration.as_ref().unwrap().extents, vec![3, 6]);
+    }
+
+    #[tokio::test]
+    async fn catch_up_waits_for_migration() {
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        ds.ds_uuid.insert(2, Uuid::new_v4());
+        migration_connect(&mut ds, 2);
+        ds.ds_state[2] = DsState::Offline;
+        ds.dirty_start(2);
+
+        let res = ds.catch_up_start(2);
+        assert!(matches!(res, Err(CrucibleError::MigrationError(_))));
+        assert_eq!(ds.migration.as_ref().unwrap().client_id, 1);
+    }
+
+    #[tokio::test]
+    async fn saved_dirty_extents_are_repaired() {
+        // Dirty extents kept from before a restart are repaired when the
+        // upstairs activates, even if the extents look the same.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dirty.json");
+        let region = Uuid::new_v4();
+
+        let up = make_upstairs();
+        let mut ds = up.downstairs.lock().await;
+        ds.dirty = DirtyExtents::new(Some(path.clone()), &csl());
+        ds.ds_uuid.insert(2, region);
+        ds.ds_state[2] = DsState::Offline;
+        ds.dirty_start(2);
+        enqueue_extent_writes(&mut ds, &[4]);
+        enqueue_extent_writes(&mut ds, &[7]);
+        drop(ds);
+
+        // The extents marked by the writes are written out by the flush.
+        let before =
+            DirtyExtents::new(Some(path.clone()), &csl()).take_saved(&region);
+        assert!(before.is_empty());
+        up.submit_flush(None, None).await.unwrap();
+
+        let up = make_upstairs();
+        let mut ds = up.downstairs.lock().await;
+        ds.dirty = DirtyExtents::new(Some(path.clone()), &csl());
+        for cid in 0..3 {
+            ds.ds_uuid.insert(cid, Uuid::new_v4());
         }
+        ds.ds_uuid.insert(2, region);
+
+        // Extent 7 also differs on client 1.
+        let mut mend = Some(DownstairsMend {
+            mend: HashMap::new(),
+        });
+        mend.as_mut().unwrap().mend.insert(
+            7,
+            ExtentFix {
+                source: 0,
+                dest: vec![1],
+            },
+        );
+        ds.mend_saved_dirty(&mut mend);
 
-        // submit an overlapping write
+        let mend = mend.unwrap().mend;
+        assert_eq!(mend.len(), 2);
+        assert_eq!(mend[&4].source, 0);
+        assert_eq!(mend[&4].dest, vec![2]);
+        assert_eq!(mend[&7].source, 0);
+        assert_eq!(mend[&7].dest, vec![1, 2]);
 
-        upstairs
-            .submit_write(
-                Block::new_512(0),
-                Bytes::from(vec![0xff; 512]),
-                None,
-                false,
-            )
-            .await
-            .unwrap();
+        // Once on the list, they are forgotten.
+        let mut mend = None;
+        ds.mend_saved_dirty(&mut mend);
+        assert!(mend.is_none());
+        let saved = DirtyExtents::new(Some(path), &csl()).take_saved(&region);
+        assert!(saved.is_empty());
+    }
 
-        {
-            let ds = upstairs.downstairs.lock().await;
-            let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
-            let jobs: Vec<&DownstairsIO> =
-                keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
+    #[tokio::test]
+    async fn downstairs_transition_offline_migrating() {
+        let up = Upstairs::default();
+        up.ds_transition(0, DsState::WaitActive).await;
+        up.ds_transition(0, DsState::WaitQuorum).await;
+        up.ds_transition(0, DsState::Active).await;
+        up.set_active().await.unwrap();
+        up.ds_missing(0).await;
+        assert_eq!(up.ds_state(0).await, DsState::Offline);
+        up.ds_transition(0, DsState::Migrating).await;
+    }
 
-            // retire_check not run yet, so there's two active jobs
-            assert_eq!(jobs.len(), 2);
+    /*
+     * An upstairs with three active downstairs that sends each read to one
+     * of them with this policy.
+     */
+    async fn make_read_policy_upstairs(policy: ReadPolicy) -> Arc<Upstairs> {
+        let up = make_upstairs();
+        up.set_active().await.unwrap();
+        let mut ds = up.downstairs.lock().await;
+        for cid in 0..3 {
+            ds.ds_state[cid] = DsState::Active;
+        }
+        ds.read_balance = ReadBalance::new(policy, 3);
+        drop(ds);
+        up
+    }
+
+    fn enqueue_read(ds: &mut Downstairs, dependencies: Vec<u64>) -> u64 {
+        let id = ds.next_id();
+        ds.enqueue(create_read_eob(
+            id,
+            dependencies,
+            10,
+            vec![ReadRequest {
+                eid: 0,
+                offset: Block::new_512(7),
+            }],
+            ImpactedBlocks::default(),
+        ));
+        id
+    }
+
+    fn read_targets(ds: &Downstairs, id: u64) -> Vec<u8> {
+        let job = ds.ds_active.get(&id).unwrap();
+        (0..3)
+            .filter(|c| job.state.get(c) == Some(&IOState::New))
+            .collect()
+    }
+
+    fn read_reply(id: u64, ds: &mut Downstairs, cid: u8, data: u8) -> bool {
+        let request = ReadRequest {
+            eid: 0,
+            offset: Block::new_512(7),
+        };
+        let response = Ok(vec![ReadResponse::from_request_with_data(
+            &request,
+            &[data],
+        )]);
+        ds.process_ds_completion(id, cid, response, &None, UpState::Active)
+            .unwrap()
+    }
 
-            // the second write should still depend on the first write!
-            assert_eq!(jobs[1].work.deps(), &[jobs[0].ds_id]);
-        }
+    #[tokio::test]
+    async fn read_policy_all_sends_to_every_downstairs() {
+        let up = make_read_policy_upstairs(ReadPolicy::All).await;
+        let mut ds = up.downstairs.lock().await;
+        let id = enqueue_read(&mut ds, vec![]);
+        assert_eq!(read_targets(&ds, id), vec![0, 1, 2]);
     }
 
     #[tokio::test]
-    async fn test_deps_retire_check() {
-        // Test that retire_check still works for flushes that depend on writes
-        // but not reads, where the read hasn't completed yet
+    async fn read_policy_round_robin() {
+        let up = make_read_policy_upstairs(ReadPolicy::RoundRobin).await;
+        let mut ds = up.downstairs.lock().await;
 
-        let upstairs = make_upstairs();
-        upstairs.set_active().await.unwrap();
+        let targets: Vec<Vec<u8>> = (0..4)
+            .map(|_| {
+                let id = enqueue_read(&mut ds, vec![]);
+                read_targets(&ds, id)
+            })
+            .collect();
+        assert_eq!(targets, vec![vec![0], vec![1], vec![2], vec![0]]);
+
+        // A downstairs that is not active is passed over.
+        ds.ds_state[1] = DsState::Offline;
+        let id = enqueue_read(&mut ds, vec![]);
+        assert_eq!(read_targets(&ds, id), vec![2]);
+        let id = enqueue_read(&mut ds, vec![]);
+        assert_eq!(read_targets(&ds, id), vec![0]);
+
+        // The one reply is enough to ack the read.
+        assert!(ds.in_progress(id, 0).is_some());
+        assert!(read_reply(id, &mut ds, 0, 1));
+        let job = ds.ds_active.get(&id).unwrap();
+        assert_eq!(job.ack_status, AckStatus::AckReady);
+        assert_eq!(job.data.as_ref().unwrap()[0].data[..], [1]);
+        assert!(ds.result(id).is_ok());
+
+        // Writes are still sent to every downstairs.
+        let id = enqueue_extent_writes(&mut ds, &[0]);
+        let job = ds.ds_active.get(&id).unwrap();
+        assert_eq!(job.state.get(&0), Some(&IOState::New));
+        assert_eq!(job.state.get(&1), Some(&IOState::New));
+        assert_eq!(job.state.get(&2), Some(&IOState::New));
+    }
 
-        upstairs
-            .submit_write(
-                Block::new_512(0),
-                Bytes::from(vec![0xff; 512]),
-                None,
-                false,
+    #[tokio::test]
+    async fn read_policy_falls_back_on_error() {
+        let up = make_read_policy_upstairs(ReadPolicy::RoundRobin).await;
+        let mut ds = up.downstairs.lock().await;
+        let id = enqueue_read(&mut ds, vec![]);
+        assert_eq!(read_targets(&ds, id), vec![0]);
+
+        assert!(ds.in_progress(id, 0).is_some());
+        assert!(!ds
+            .process_ds_completion(
+                id,
+                0,
+                Err(CrucibleError::GenericError("bad".to_string())),
+                &None,
+                UpState::Active,
             )
-            .await
-            .unwrap();
+            .unwrap());
+        assert_eq!(ds.reads_redirected, 1);
+        assert_eq!(read_targets(&ds, id), vec![1]);
+        assert_eq!(
+            ds.ds_active.get(&id).unwrap().ack_status,
+            AckStatus::NotAcked
+        );
 
-        upstairs
-            .submit_read(Block::new_512(1), Buffer::new(512), None)
-            .await
-            .unwrap();
+        assert!(ds.in_progress(id, 1).is_some());
+        assert!(read_reply(id, &mut ds, 1, 2));
+        let job = ds.ds_active.get(&id).unwrap();
+        assert_eq!(job.ack_status, AckStatus::AckReady);
+        assert_eq!(job.data.as_ref().unwrap()[0].data[..], [2]);
+        assert!(ds.result(id).is_ok());
+    }
 
-        upstairs
-            .submit_write(
-                Block::new_512(2),
-                Bytes::from(vec![0xff; 512]),
-                None,
-                false,
-            )
-            .await
-            .unwrap();
+    #[tokio::test]
+    async fn read_policy_fallback_skips_downstairs_past_dependents() {
+        // A downstairs that already has a write that depends on the read
+        // can't take the read, it could return the new data.
+        let up = make_read_policy_upstairs(ReadPolicy::RoundRobin).await;
+        let mut ds = up.downstairs.lock().await;
+        let read = enqueue_read(&mut ds, vec![]);
+        assert_eq!(read_targets(&ds, read), vec![0]);
+
+        let write = ds.next_id();
+        ds.enqueue(create_write_eob(
+            write,
+            vec![read],
+            11,
+            vec![],
+            false,
+            ImpactedBlocks::default(),
+        ));
+        assert!(ds.in_progress(write, 1).is_some());
 
-        upstairs.submit_flush(None, None).await.unwrap();
+        let error = Err(CrucibleError::GenericError("bad".to_string()));
+        assert!(ds.in_progress(read, 0).is_some());
+        assert!(!ds
+            .process_ds_completion(read, 0, error, &None, UpState::Active)
+            .unwrap());
+        assert_eq!(read_targets(&ds, read), vec![2]);
 
-        // complete and ack the previous flush and both writes
+        // Once every downstairs that could take it fails, the read fails.
+        let error = Err(CrucibleError::GenericError("bad".to_string()));
+        assert!(ds.in_progress(read, 2).is_some());
+        assert!(ds
+            .process_ds_completion(read, 2, error, &None, UpState::Active)
+            .unwrap());
+        assert_eq!(ds.reads_redirected, 1);
+        let job = ds.ds_active.get(&read).unwrap();
+        assert_eq!(job.ack_status, AckStatus::AckReady);
+        assert!(ds.result(read).is_err());
+    }
 
-        let mut ds = upstairs.downstairs.lock().await;
-        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
-        let jobs: Vec<&DownstairsIO> =
-            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 4);
+    #[tokio::test]
+    async fn read_policy_corrupt_block_is_read_elsewhere() {
+        // A block that fails its integrity check is read from another
+        // downstairs, and still healed.
+        let up = make_read_policy_upstairs(ReadPolicy::LowestLatency).await;
+        let mut ds = up.downstairs.lock().await;
+        let id = ds.next_id();
+        ds.enqueue(create_read_eob(
+            id,
+            vec![],
+            10,
+            vec![ReadRequest {
+                eid: 0,
+                offset: Block::new_512(7),
+            }],
+            extent_from_offset(
+                *up.ddef.lock().await,
+                Block::new_512(7),
+                Block::new_512(1),
+            ),
+        ));
+        let first = read_targets(&ds, id)[0];
 
-        let write_1 = jobs[0].ds_id;
-        let read = jobs[1].ds_id;
-        let write_2 = jobs[2].ds_id;
-        let flush = jobs[3].ds_id;
+        let data = [1u8; 512];
+        let ctx = BlockContext {
+            hash: integrity_hash(&[&data[..]]),
+            encryption_context: None,
+        };
+        let response = heal_response(&[2u8; 512], ctx.clone());
+        assert!(ds.in_progress(id, first).is_some());
+        assert!(!ds
+            .process_ds_completion(
+                id,
+                first,
+                Ok(vec![response]),
+                &None,
+                UpState::Active,
+            )
+            .unwrap());
+        let targets = read_targets(&ds, id);
+        assert_eq!(targets.len(), 1);
+        assert_ne!(targets[0], first);
 
-        for ds_id in &[write_1, write_2] {
-            for client_id in 0..3 {
-                ds.in_progress(*ds_id, client_id);
-                ds.process_ds_completion(
-                    *ds_id,
-                    client_id,
-                    Ok(vec![]),
-                    &None,
-                    UpState::Active,
-                )
-                .unwrap();
-            }
+        assert!(ds.in_progress(id, targets[0]).is_some());
+        let response = heal_response(&data, ctx);
+        assert!(ds
+            .process_ds_completion(
+                id,
+                targets[0],
+                Ok(vec![response]),
+                &None,
+                UpState::Active,
+            )
+            .unwrap());
+        assert!(ds.result(id).is_ok());
+        assert_eq!(ds.blocks_healed, 1);
+    }
 
-            ds.ack(*ds_id);
-            ds.retire_check(*ds_id);
+    /*
+     * An upstairs with three active downstairs, each with a region, that
+     * faults a downstairs with more than queue jobs outstanding.
+     */
+    async fn make_fault_upstairs(queue: usize) -> Arc<Upstairs> {
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        for cid in 0..3 {
+            ds.ds_uuid.insert(cid, Uuid::new_v4());
         }
+        ds.fault_check = FaultCheck::new(None, Some(queue), 3);
+        drop(ds);
+        up
+    }
 
-        // complete and ack flush
-        for client_id in 0..3 {
-            ds.in_progress(flush, client_id);
+    fn write_done(ds: &mut Downstairs, id: u64, cids: &[u8]) {
+        for cid in cids {
+            ds.in_progress(id, *cid);
             ds.process_ds_completion(
-                flush,
-                client_id,
+                id,
+                *cid,
                 Ok(vec![]),
                 &None,
                 UpState::Active,
             )
             .unwrap();
         }
+    }
 
-        ds.ack(flush);
+    #[tokio::test]
+    async fn slow_downstairs_is_faulted() {
+        let up = make_fault_upstairs(2).await;
+        let mut ds = up.downstairs.lock().await;
+        let mut ids = Vec::new();
+        for eid in [3, 5] {
+            let id = enqueue_extent_writes(&mut ds, &[eid]);
+            write_done(&mut ds, id, &[0, 2]);
+            ids.push(id);
+        }
+        ds.in_progress(ids[0], 1);
+        drop(ds);
 
-        // call retire_check on flush (where the read hasn't been acked yet)
+        // Two jobs behind is not enough.
+        assert_eq!(up.fault_check().await, None);
 
-        ds.retire_check(flush);
+        let mut ds = up.downstairs.lock().await;
+        let id = enqueue_extent_writes(&mut ds, &[7]);
+        ids.push(id);
+        drop(ds);
+        assert_eq!(up.fault_check().await, Some(false));
+        assert_eq!(up.ds_state(1).await, DsState::Faulted);
 
-        // only the read should be left (retire_check removes the flush and the
-        // deps of the flush)
+        let mut ds = up.downstairs.lock().await;
+        assert_eq!(
+            ds.fault_check.reasons(),
+            vec![None, Some(FaultReason::QueueFull), None]
+        );
+        for id in &ids {
+            let job = ds.ds_active.get(id).unwrap();
+            assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+        }
+        assert_eq!(ds.dirty.extents(1), vec![3, 5, 7]);
+
+        // It skips new work, and the extents that work changes are dirty.
+        let id = enqueue_extent_writes(&mut ds, &[8]);
+        let job = ds.ds_active.get(&id).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+        assert_eq!(job.state.get(&0), Some(&IOState::New));
+        assert_eq!(ds.dirty.extents(1), vec![3, 5, 7, 8]);
+        drop(ds);
 
-        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
-        let jobs: Vec<&DownstairsIO> =
-            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 1);
+        // Anything it still sends back is dropped.
+        assert!(!up
+            .process_ds_operation(ids[0], 1, Ok(vec![]))
+            .await
+            .unwrap());
+        let ds = up.downstairs.lock().await;
+        let job = ds.ds_active.get(&ids[0]).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+    }
 
-        assert_eq!(jobs[0].ds_id, read);
-        assert!(matches!(jobs[0].work, IOop::Read { .. }));
+    #[tokio::test]
+    async fn all_slow_downstairs_are_not_faulted() {
+        // If every downstairs is behind, faulting one won't help.
+        let up = make_fault_upstairs(1).await;
+        let mut ds = up.downstairs.lock().await;
+        enqueue_extent_writes(&mut ds, &[1]);
+        enqueue_extent_writes(&mut ds, &[2]);
+        drop(ds);
+        assert_eq!(up.fault_check().await, None);
+        for cid in 0..3 {
+            assert_eq!(up.ds_state(cid).await, DsState::Active);
+        }
+    }
 
-        // complete and ack the read, then do another flush. this should retire
-        // everything.
-        for client_id in 0..3 {
-            ds.in_progress(read, client_id);
+    #[tokio::test]
+    async fn no_fault_without_the_others_active() {
+        let up = make_fault_upstairs(0).await;
+        let mut ds = up.downstairs.lock().await;
+        let id = enqueue_extent_writes(&mut ds, &[1]);
+        write_done(&mut ds, id, &[0]);
+        ds.ds_state[2] = DsState::Offline;
+        drop(ds);
+        assert_eq!(up.fault_check().await, None);
+        assert_eq!(up.ds_state(1).await, DsState::Active);
+    }
 
-            let request = ReadRequest {
-                eid: 0,
-                offset: Block::new_512(1),
-            };
+    #[tokio::test]
+    async fn faulted_read_goes_elsewhere() {
+        let up = make_fault_upstairs(0).await;
+        let mut ds = up.downstairs.lock().await;
+        ds.read_balance = ReadBalance::new(ReadPolicy::RoundRobin, 3);
+        let id = enqueue_read(&mut ds, vec![]);
+        assert!(ds.in_progress(id, 0).is_some());
+        drop(ds);
 
-            ds.process_ds_completion(
-                read,
-                client_id,
-                Ok(vec![ReadResponse::from_request_with_data(&request, &[])]),
-                &None,
-                UpState::Active,
-            )
-            .unwrap();
-        }
+        assert_eq!(up.fault_check().await, Some(false));
+        assert_eq!(up.ds_state(0).await, DsState::Faulted);
+        let mut ds = up.downstairs.lock().await;
+        assert_eq!(read_targets(&ds, id), vec![1]);
 
-        ds.ack(read);
-        ds.retire_check(read);
+        assert!(ds.in_progress(id, 1).is_some());
+        assert!(read_reply(id, &mut ds, 1, 4));
+        assert!(ds.result(id).is_ok());
+    }
 
+    #[tokio::test]
+    async fn faulted_returns_to_catch_up() {
+        let up = make_fault_upstairs(0).await;
+        let mut ds = up.downstairs.lock().await;
+        ds.ds_repair.insert(0, migration_target(10));
+        ds.ds_repair.insert(2, migration_target(12));
+        let id = enqueue_extent_writes(&mut ds, &[4]);
+        write_done(&mut ds, id, &[0, 2]);
         drop(ds);
-        upstairs.submit_flush(None, None).await.unwrap();
 
-        let mut ds = upstairs.downstairs.lock().await;
-        let keys: Vec<&u64> = ds.ds_active.keys().sorted().collect();
-        let jobs: Vec<&DownstairsIO> =
-            keys.iter().map(|k| ds.ds_active.get(k).unwrap()).collect();
-        assert_eq!(jobs.len(), 2);
+        assert_eq!(up.fault_check().await, Some(false));
+        assert_eq!(up.ds_state(1).await, DsState::Faulted);
 
-        let flush = jobs[1].ds_id;
+        // It stays faulted when its task hangs up, and is caught up by
+        // copying the extents it missed when it comes back.
+        up.ds_missing(1).await;
+        assert_eq!(up.ds_state(1).await, DsState::Faulted);
+        up.catch_up_start(1).await.unwrap();
+        assert_eq!(up.ds_state(1).await, DsState::Migrating);
 
-        for client_id in 0..3 {
-            ds.in_progress(flush, client_id);
-            ds.process_ds_completion(
-                flush,
-                client_id,
-                Ok(vec![]),
-                &None,
-                UpState::Active,
-            )
-            .unwrap();
+        let mut ds = up.downstairs.lock().await;
+        let first_job = ds.migration_connected(1).unwrap();
+        ds.migration_ready(1, first_job - 1).unwrap();
+        migration_copy_extent(&mut ds, 4);
+        drop(ds);
+        assert!(up.migration_progress().await);
+        let ds = up.downstairs.lock().await;
+        assert_eq!(ds.ds_state[1], DsState::Active);
+        assert!(!ds.dirty.is_tracking(1));
+    }
+
+    #[tokio::test]
+    async fn faulted_replaced_stops_tracking() {
+        let up = make_fault_upstairs(0).await;
+        let mut ds = up.downstairs.lock().await;
+        let id = enqueue_extent_writes(&mut ds, &[2]);
+        write_done(&mut ds, id, &[0, 2]);
+        drop(ds);
+
+        assert_eq!(up.fault_check().await, Some(false));
+        assert_eq!(up.ds_state(1).await, DsState::Faulted);
+
+        // Replacing it with a new downstairs copies every extent, so the
+        // dirty extents of the old one no longer matter.
+        let mut ds = up.downstairs.lock().await;
+        assert!(ds.dirty.is_tracking(1));
+        migration_connect(&mut ds, 3);
+        for eid in 0..3 {
+            migration_copy_extent(&mut ds, eid);
         }
+        drop(ds);
+        assert!(up.migration_progress().await);
+        let ds = up.downstairs.lock().await;
+        assert_eq!(ds.ds_state[1], DsState::Active);
+        assert!(!ds.dirty.is_tracking(1));
+    }
 
-        ds.ack(flush);
-        ds.retire_check(flush);
+    #[tokio::test]
+    async fn downstairs_transition_migrating() {
+        let up = Upstairs::default();
+        up.ds_transition(0, DsState::WaitActive).await;
+        up.ds_transition(0, DsState::WaitQuorum).await;
+        up.ds_transition(0, DsState::Active).await;
+        up.set_active().await.unwrap();
+        up.ds_transition(0, DsState::Migrating).await;
+    }
 
-        assert_eq!(ds.ds_active.keys().count(), 0);
+    #[tokio::test]
+    #[should_panic]
+    async fn downstairs_transition_migrating_not_active() {
+        let up = Upstairs::default();
+        up.ds_transition(0, DsState::WaitActive).await;
+        up.ds_transition(0, DsState::Migrating).await;
+    }
+
+    /*
+     * An upstairs with all downstairs active, and what each of them
+     * agreed to when it connected.
+     */
+    async fn make_negotiated_upstairs(
+        protocols: &[Negotiated],
+    ) -> Arc<Upstairs> {
+        let up = make_migration_upstairs().await;
+        let mut ds = up.downstairs.lock().await;
+        for (cid, protocol) in protocols.iter().enumerate() {
+            ds.ds_protocol.insert(cid as u8, *protocol);
+        }
+        drop(ds);
+        up
+    }
+
+    #[tokio::test]
+    async fn discard_needs_every_downstairs_to_support_it() {
+        let current = Negotiated {
+            version: PROTOCOL_VERSION_MAX,
+            features: SUPPORTED_FEATURES,
+        };
+        let no_discard = Negotiated {
+            version: PROTOCOL_VERSION_MAX,
+            features: 0,
+        };
+
+        let up =
+            make_negotiated_upstairs(&[current, no_discard, current]).await;
+        assert!(up
+            .submit_discard(Block::new_512(0), 512, None)
+            .await
+            .is_err());
+        assert!(up.downstairs.lock().await.ds_active.is_empty());
+
+        let up = make_negotiated_upstairs(&[current, current, current]).await;
+        assert!(up
+            .submit_discard(Block::new_512(0), 512, None)
+            .await
+            .is_ok());
+        assert_eq!(up.downstairs.lock().await.ds_active.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn resize_needs_every_downstairs_to_support_it() {
+        let current = Negotiated {
+            version: PROTOCOL_VERSION_MAX,
+            features: SUPPORTED_FEATURES,
+        };
+        let old = Negotiated {
+            version: VERSION_REGION_RESIZE - 1,
+            features: SUPPORTED_FEATURES,
+        };
+
+        let up = make_negotiated_upstairs(&[current, current, old]).await;
+        assert!(up.submit_resize(20, None).await.is_err());
+        assert!(up.downstairs.lock().await.ds_active.is_empty());
+
+        let up = make_negotiated_upstairs(&[current, current, current]).await;
+        assert!(up.submit_resize(20, None).await.is_ok());
+        assert!(!up.downstairs.lock().await.ds_active.is_empty());
     }
 }
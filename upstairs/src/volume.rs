@@ -4,6 +4,7 @@ use super::*;
 use async_recursion::async_recursion;
 use oximeter::types::ProducerRegistry;
 use std::ops::Range;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
 use crucible_client_types::VolumeConstructionRequest;
@@ -25,18 +26,35 @@ pub struct Volume {
      */
     block_size: u64,
     count: Arc<AtomicU32>,
+
+    /*
+     * When set, the sub volumes are striped, this many blocks at a time,
+     * instead of following one another.
+     */
+    stripe_blocks: Option<u64>,
+
+    /*
+     * How a scrub of this volume is going, and where it is saved.
+     */
+    scrub: ScrubState,
 }
 
 #[derive(Clone)]
 pub struct SubVolume {
-    lba_range: Range<u64>,
+    lba_start: u64,
+
+    /*
+     * The end of the range moves if the sub volume is resized, and the
+     * Volume is usually shared behind an Arc.
+     */
+    lba_end: Arc<AtomicU64>,
     block_io: Arc<dyn BlockIO + Send + Sync>,
 }
 
 impl Debug for SubVolume {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
         f.debug_struct("SubVolume")
-            .field("lba_range", &self.lba_range)
+            .field("lba_range", &self.lba_range())
             .finish()
     }
 }
@@ -50,6 +68,8 @@ impl Volume {
             scrub_point: Arc::new(AtomicU64::new(0)),
             block_size,
             count: Arc::new(AtomicU32::new(0)),
+            stripe_blocks: None,
+            scrub: ScrubState::default(),
         }
     }
 
@@ -70,13 +90,13 @@ impl Volume {
         let block_size = block_io.get_block_size().await?;
         let uuid = block_io.get_uuid().await?;
 
-        let sub_volume = SubVolume {
-            lba_range: Range {
+        let sub_volume = SubVolume::new(
+            Range {
                 start: 0,
                 end: block_io.total_size().await? / block_size,
             },
             block_io,
-        };
+        );
 
         Ok(Self {
             uuid,
@@ -85,9 +105,51 @@ impl Volume {
             scrub_point: Arc::new(AtomicU64::new(0)),
             block_size,
             count: Arc::new(AtomicU32::new(0)),
+            stripe_blocks: None,
+            scrub: ScrubState::default(),
         })
     }
 
+    // Stripe the sub volumes, stripe_blocks blocks at a time, instead of
+    // laying them out one after another.  This has to be set before any
+    // sub volume is added.
+    pub fn set_stripe_blocks(
+        &mut self,
+        stripe_blocks: u64,
+    ) -> Result<(), CrucibleError> {
+        if stripe_blocks == 0 {
+            crucible_bail!(InvalidNumberOfBlocks, "stripe of zero blocks");
+        }
+        if !self.sub_volumes.is_empty() {
+            crucible_bail!(
+                Unsupported,
+                "stripe set after sub volumes were added"
+            );
+        }
+        self.stripe_blocks = Some(stripe_blocks);
+        Ok(())
+    }
+
+    // Save the scrub point in this directory as a scrub goes, so a scrub
+    // that is stopped picks up where it was the next time it runs.
+    pub fn set_scrub_state_dir(&mut self, dir: PathBuf) {
+        self.scrub.set_dir(dir);
+    }
+
+    // Watch how a scrub of this volume is going. There is no status until
+    // a scrub starts. A status that is done means the volume no longer
+    // needs its read only parent.
+    pub fn scrub_status(&self) -> watch::Receiver<Option<ScrubStatus>> {
+        self.scrub.subscribe()
+    }
+
+    async fn publish_scrub_status(&self, status: ScrubStatus) {
+        for sub_volume in &self.sub_volumes {
+            sub_volume.set_scrub_status(status.clone()).await;
+        }
+        self.scrub.publish(status);
+    }
+
     fn compute_next_lba_range(&self, number_of_blocks: u64) -> Range<u64> {
         if self.sub_volumes.is_empty() {
             Range {
@@ -96,7 +158,7 @@ impl Volume {
             }
         } else {
             let last_sub_volume_end =
-                self.sub_volumes.last().unwrap().lba_range.end;
+                self.sub_volumes.last().unwrap().lba_range().end;
 
             Range {
                 start: last_sub_volume_end,
@@ -117,10 +179,28 @@ impl Volume {
 
         let number_of_blocks = block_io.total_size().await? / block_size;
 
-        self.sub_volumes.push(SubVolume {
-            lba_range: self.compute_next_lba_range(number_of_blocks),
+        // Striped sub volumes each hold the same whole number of stripes.
+        if let Some(stripe_blocks) = self.stripe_blocks {
+            if number_of_blocks % stripe_blocks != 0 {
+                crucible_bail!(
+                    InvalidNumberOfBlocks,
+                    "{} blocks is not a whole number of {} block stripes",
+                    number_of_blocks,
+                    stripe_blocks
+                );
+            }
+            if let Some(first) = self.sub_volumes.first() {
+                let lba_range = first.lba_range();
+                if lba_range.end - lba_range.start != number_of_blocks {
+                    crucible_bail!(SubvolumeSizeMismatch);
+                }
+            }
+        }
+
+        self.sub_volumes.push(SubVolume::new(
+            self.compute_next_lba_range(number_of_blocks),
             block_io,
-        });
+        ));
 
         Ok(())
     }
@@ -158,6 +238,11 @@ impl Volume {
     // This will be used to construct volumes from snapshots. Among other
     // things.
     //
+    // The read only parent can itself be a Volume with a read only parent,
+    // and so on, so a chain of snapshots can be any depth. Each block is read
+    // from the first layer down the chain that has written it. See
+    // Volume::flatten for taking a layer out of the chain.
+    //
     pub async fn add_read_only_parent(
         &mut self,
         block_io: Arc<dyn BlockIO + Send + Sync>,
@@ -174,14 +259,14 @@ impl Volume {
         // None when done - read block by block, don't overwrite if owned by sub
         // volume
 
-        self.read_only_parent = Some(Arc::new(SubVolume {
-            // Read only parent LBA range always starts from 0
-            lba_range: Range {
+        // Read only parent LBA range always starts from 0
+        self.read_only_parent = Some(Arc::new(SubVolume::new(
+            Range {
                 start: 0,
                 end: number_of_blocks,
             },
             block_io,
-        }));
+        )));
 
         Ok(())
     }
@@ -214,7 +299,21 @@ impl Volume {
     //
     // For request 3, it affects all sub volumes.
     //
-    // Sort that out here. Note: start and length are in blocks!
+    // If the volume is striped, the LBAs instead go round-robin across the
+    // sub volumes a stripe at a time. With a stripe of s blocks:
+    //
+    //                   0    s    2s   3s   4s   5s   6s
+    //   volume:         |-s0-|-s1-|-s2-|-s3-|-s4-|-s5-|
+    //   sub volume 0:   |-s0-|-s3-|
+    //   sub volume 1:             |-s1-|-s4-|
+    //   sub volume 2:                       |-s2-|-s5-|
+    //
+    // A request then gets one range per stripe it touches, so a large
+    // request is spread over all of the sub volumes.
+    //
+    // Sort that out here. Note: start and length are in blocks! Each range
+    // returned is a range of the volume's LBAs, use compute_sub_volume_lba
+    // to find where it starts in the sub volume.
     //
     pub fn sub_volumes_for_lba_range(
         &self,
@@ -223,6 +322,25 @@ impl Volume {
     ) -> Vec<(Range<u64>, &SubVolume)> {
         let mut sv_vec = vec![];
 
+        if let Some(stripe_blocks) = self.stripe_blocks {
+            let volume_end = match self.sub_volumes.last() {
+                Some(sub_volume) => sub_volume.lba_range().end,
+                None => return sv_vec,
+            };
+            let end = std::cmp::min(start + length, volume_end);
+            let mut lba = start;
+            while lba < end {
+                let stripe = lba / stripe_blocks;
+                let stripe_end =
+                    std::cmp::min((stripe + 1) * stripe_blocks, end);
+                let index = stripe % self.sub_volumes.len() as u64;
+                sv_vec
+                    .push((lba..stripe_end, &self.sub_volumes[index as usize]));
+                lba = stripe_end;
+            }
+            return sv_vec;
+        }
+
         for sub_volume in &self.sub_volumes {
             let coverage = sub_volume.lba_range_coverage(start, length);
             if let Some(coverage) = coverage {
@@ -233,6 +351,25 @@ impl Volume {
         sv_vec
     }
 
+    // Compute the sub volume LBA for a volume LBA that
+    // sub_volumes_for_lba_range placed in this sub volume. Stripe n of a
+    // striped volume is stripe n / (number of sub volumes) of its sub
+    // volume.
+    pub fn compute_sub_volume_lba(
+        &self,
+        sub_volume: &SubVolume,
+        address: u64,
+    ) -> u64 {
+        match self.stripe_blocks {
+            None => sub_volume.compute_sub_volume_lba(address),
+            Some(stripe_blocks) => {
+                let stripe = address / stripe_blocks;
+                let stripes = self.sub_volumes.len() as u64;
+                (stripe / stripes) * stripe_blocks + address % stripe_blocks
+            }
+        }
+    }
+
     pub fn read_only_parent_for_lba_range(
         &self,
         start: u64,
@@ -256,14 +393,32 @@ impl Volume {
     // the read only side, and write_unwritten to the LBA of the SubVolume
     // that is "below" it.
     //
-    // Still TODO: here
-    // If there is an error, how to we tell Nexus?
-    // If we finish the scrub, how do we tell Nexus?
+    // How the scrub is going, including how it failed, is published as a
+    // ScrubStatus (see scrub_status), and is passed on to the sub volumes.
+    // With a scrub state directory set, the scrub point is saved there
+    // after each flush, and a later scrub from the same read only parent
+    // starts from it.
     pub async fn scrub(
         &self,
         log: &Logger,
         start_delay: Option<u64>,
         scrub_pause: Option<u64>,
+    ) -> Result<(), CrucibleError> {
+        let result = self.scrub_volume(log, start_delay, scrub_pause).await;
+        if let Err(e) = &result {
+            warn!(log, "Scrub for {} failed: {}", self.uuid, e);
+            if let Some(status) = self.scrub.failed(e) {
+                self.publish_scrub_status(status).await;
+            }
+        }
+        result
+    }
+
+    async fn scrub_volume(
+        &self,
+        log: &Logger,
+        start_delay: Option<u64>,
+        scrub_pause: Option<u64>,
     ) -> Result<(), CrucibleError> {
         info!(log, "Scrub check for {}", self.uuid);
         // XXX Can we assert volume is activated?
@@ -295,8 +450,21 @@ impl Volume {
             info!(log, "Scrub with total_size:{:?} block_size:{:?}", ts, bs);
             let scrub_start = Instant::now();
 
-            let start = read_only_parent.lba_range.start;
-            let end = read_only_parent.lba_range.end;
+            let start = read_only_parent.lba_range().start;
+            let mut end = read_only_parent.lba_range().end;
+
+            // Pick up from where an earlier scrub from this parent stopped.
+            let parent_id = read_only_parent.get_uuid().await?;
+            let saved = self.scrub.load(self.uuid, parent_id, log);
+            let mut offset = std::cmp::min(std::cmp::max(start, saved), end);
+            if offset > start {
+                info!(log, "Scrub for {} picks up at {}", self.uuid, offset);
+                self.scrub_point.store(offset, Ordering::SeqCst);
+            }
+            let mut progress =
+                ScrubProgress::new(self.uuid, bs as u64, offset, end);
+            self.publish_scrub_status(progress.status(offset, Instant::now()))
+                .await;
 
             // Based on some seat of the pants measurements, we are doing
             // 256 KiB IOs during the scrub. Here we select how many blocks
@@ -315,8 +483,7 @@ impl Volume {
 
             let mut retries = 0;
             let showstep = (end - start) / 25;
-            let mut showat = start + showstep;
-            let mut offset = start;
+            let mut showat = offset + showstep;
             while offset < end {
                 if offset + block_count as u64 > end {
                     block_count = (end - offset) as usize;
@@ -352,7 +519,6 @@ impl Volume {
                         }
                     }
                     if retry_count > 5 {
-                        // TODO: Nexus needs to know the scrub had problems.
                         crucible_bail!(
                             IoError,
                             "Scrub failed to read at offset {}",
@@ -361,7 +527,6 @@ impl Volume {
                     }
                 }
 
-                // TODO: Nexus needs to know about this failure.
                 self.write_unwritten(
                     Block::new(offset, bs.trailing_zeros()),
                     Bytes::from(buffer.as_vec().await.clone()),
@@ -373,6 +538,11 @@ impl Volume {
                 // Set the scrub high water mark
                 self.scrub_point.store(offset, Ordering::SeqCst);
 
+                // If the volume was shrunk below the read only parent while
+                // we were scrubbing, stop at the new end.
+                end = std::cmp::min(end, self.total_size().await? / bs as u64);
+                progress.set_end(end);
+
                 if offset > showat {
                     info!(
                         log,
@@ -381,8 +551,16 @@ impl Volume {
                         end,
                         self.scrub_point
                     );
+                    // Only save a scrub point once what is below it is
+                    // flushed.
+                    self.flush(None).await?;
+                    self.scrub.save(self.uuid, parent_id, offset, log);
                     showat += showstep;
                 }
+                self.publish_scrub_status(
+                    progress.status(offset, Instant::now()),
+                )
+                .await;
                 // Pause just a bit between IOs so we don't starve the guest
                 // TODO: More benchmarking to find a good value here.
                 tokio::time::sleep(Duration::from_millis(pause_millis)).await;
@@ -400,8 +578,14 @@ impl Volume {
                 pause_millis,
             );
             self.flush(None).await?;
+            self.scrub.save(self.uuid, parent_id, offset, log);
+            self.publish_scrub_status(progress.status(offset, Instant::now()))
+                .await;
         } else {
             info!(log, "Scrub for {} not required", self.uuid);
+            let progress = ScrubProgress::new(self.uuid, self.block_size, 0, 0);
+            self.publish_scrub_status(progress.status(0, Instant::now()))
+                .await;
         }
 
         Ok(())
@@ -432,11 +616,12 @@ impl Volume {
             data.len() as u64 / self.block_size,
         );
 
-        // TODO parallel dispatch!
+        // Send each part to its sub volume, all at once.
         let mut data_index = 0;
+        let mut writes = Vec::with_capacity(affected_sub_volumes.len());
         for (coverage, sub_volume) in affected_sub_volumes {
             let sub_offset = Block::new(
-                sub_volume.compute_sub_volume_lba(coverage.start),
+                self.compute_sub_volume_lba(sub_volume, coverage.start),
                 offset.shift,
             );
             let sz = (coverage.end - coverage.start) as usize
@@ -449,15 +634,14 @@ impl Volume {
 
             // Take the write or write_unwritten path here.
             if is_write_unwritten {
-                sub_volume
-                    .write_unwritten(sub_offset, slice.clone())
-                    .await?;
+                writes.push(sub_volume.write_unwritten(sub_offset, slice));
             } else {
-                sub_volume.write(sub_offset, slice.clone()).await?;
+                writes.push(sub_volume.write(sub_offset, slice));
             }
 
             data_index += sz;
         }
+        join_all(writes).await?;
 
         if is_write_unwritten {
             cdt::volume__writeunwritten__done!(|| (cc, self.uuid));
@@ -476,7 +660,7 @@ impl BlockIO for Volume {
             sub_volume.conditional_activate().await?;
 
             let sub_volume_computed_size = self.block_size
-                * (sub_volume.lba_range.end - sub_volume.lba_range.start);
+                * (sub_volume.lba_range().end - sub_volume.lba_range().start);
 
             if sub_volume.total_size().await? != sub_volume_computed_size {
                 crucible_bail!(SubvolumeSizeMismatch);
@@ -525,8 +709,8 @@ impl BlockIO for Volume {
 
             for sub_volume in &self.sub_volumes {
                 // Range is [start, end), meaning 0..10 is 10
-                total_blocks += (sub_volume.lba_range.end
-                    - sub_volume.lba_range.start)
+                total_blocks += (sub_volume.lba_range().end
+                    - sub_volume.lba_range().start)
                     as u64;
             }
 
@@ -534,8 +718,8 @@ impl BlockIO for Volume {
         } else if let Some(ref read_only_parent) = &self.read_only_parent {
             // If this volume only has a read only parent, report that size for
             // total size
-            let total_blocks = read_only_parent.lba_range.end
-                - read_only_parent.lba_range.start;
+            let total_blocks = read_only_parent.lba_range().end
+                - read_only_parent.lba_range().start;
             Ok(total_blocks * self.block_size)
         } else {
             // If this volume has neither, then total size is 0
@@ -578,11 +762,13 @@ impl BlockIO for Volume {
             data.len() as u64 / self.block_size,
         );
 
-        // TODO parallel dispatch!
-        let mut data_index = 0;
-        for (coverage, sub_volume) in affected_sub_volumes {
+        // Send every sub volume read at once, then put the results
+        // together in order.
+        let mut sub_buffers = Vec::with_capacity(affected_sub_volumes.len());
+        let mut reads = Vec::with_capacity(affected_sub_volumes.len());
+        for (coverage, sub_volume) in &affected_sub_volumes {
             let sub_offset = Block::new(
-                sub_volume.compute_sub_volume_lba(coverage.start),
+                self.compute_sub_volume_lba(sub_volume, coverage.start),
                 offset.shift,
             );
             // 0..10 would be size 10
@@ -590,7 +776,16 @@ impl BlockIO for Volume {
                 * self.block_size as usize;
             let sub_buffer = Buffer::new(sz);
 
-            sub_volume.read(sub_offset, sub_buffer.clone()).await?;
+            reads.push(sub_volume.read(sub_offset, sub_buffer.clone()));
+            sub_buffers.push(sub_buffer);
+        }
+        join_all(reads).await?;
+
+        let mut data_index = 0;
+        for ((coverage, _), sub_buffer) in
+            affected_sub_volumes.into_iter().zip(sub_buffers)
+        {
+            let sz = sub_buffer.len();
 
             // When performing a read, check the parent coverage: if it's
             // Some(range), then we have to perform multiple reads:
@@ -742,6 +937,115 @@ impl BlockIO for Volume {
         Ok(())
     }
 
+    // A discarded block is unwritten again, so where the read only parent
+    // still covers it, reads would once again be served from the parent.
+    // Those blocks are written with zeros instead, which the scrubber will
+    // not write over. Nothing is discarded from the read only parent itself.
+    async fn discard(
+        &self,
+        offset: Block,
+        len: u64,
+    ) -> Result<(), CrucibleError> {
+        if self.sub_volumes.is_empty() {
+            crucible_bail!(CannotReceiveBlocks, "No sub volumes!");
+        }
+        let cc = self.next_count();
+        cdt::volume__discard__start!(|| (cc, self.uuid));
+
+        let affected_sub_volumes =
+            self.sub_volumes_for_lba_range(offset.value, len / self.block_size);
+
+        let mut discards = Vec::with_capacity(affected_sub_volumes.len());
+        let mut zeroed = Vec::new();
+        let scrub_point = self.scrub_point.load(Ordering::SeqCst);
+        for (coverage, sub_volume) in affected_sub_volumes {
+            // Until the scrubber is done with the parent, a read that
+            // reaches past the scrub point still looks at the parent for
+            // every unwritten block, so go by the parent alone.
+            let parent = match &self.read_only_parent {
+                Some(parent) if scrub_point < parent.lba_range().end => parent
+                    .lba_range_coverage(
+                        coverage.start,
+                        coverage.end - coverage.start,
+                    ),
+                _ => None,
+            }
+            .unwrap_or(coverage.end..coverage.end);
+
+            for (range, zero) in [
+                (coverage.start..parent.start, false),
+                (parent.clone(), true),
+                (parent.end..coverage.end, false),
+            ] {
+                if range.is_empty() {
+                    continue;
+                }
+                let sub_offset = Block::new(
+                    self.compute_sub_volume_lba(sub_volume, range.start),
+                    offset.shift,
+                );
+                let blocks = range.end - range.start;
+                if zero {
+                    zeroed.push((sub_volume, sub_offset, blocks));
+                } else {
+                    discards.push(
+                        sub_volume
+                            .discard(sub_offset, blocks * self.block_size),
+                    );
+                }
+            }
+        }
+        join_all(discards).await?;
+
+        // Write the zeros a bounded piece at a time, as a discard can
+        // cover much more than we would want to hold in memory.
+        let chunk_blocks = std::cmp::max(1, 131072 / self.block_size);
+        let zeros =
+            Bytes::from(vec![0; (chunk_blocks * self.block_size) as usize]);
+        for (sub_volume, sub_offset, blocks) in zeroed {
+            let mut done = 0;
+            while done < blocks {
+                let n = std::cmp::min(chunk_blocks, blocks - done);
+                sub_volume
+                    .write(
+                        Block::new(sub_offset.value + done, offset.shift),
+                        zeros.slice(..(n * self.block_size) as usize),
+                    )
+                    .await?;
+                done += n;
+            }
+        }
+
+        cdt::volume__discard__done!(|| (cc, self.uuid));
+        Ok(())
+    }
+
+    // Only the last sub volume can be resized, as changing the size of any
+    // other would move the LBAs of the sub volumes that follow it. The read
+    // only parent keeps its size. A striped volume can't be resized, as
+    // every one of its sub volumes would have to change together.
+    async fn resize(&self, extent_count: u32) -> Result<(), CrucibleError> {
+        if self.stripe_blocks.is_some() {
+            crucible_bail!(Unsupported, "resize of a striped volume");
+        }
+        let sub_volume = match self.sub_volumes.last() {
+            Some(sub_volume) => sub_volume,
+            None => crucible_bail!(CannotReceiveBlocks, "No sub volumes!"),
+        };
+        let cc = self.next_count();
+        cdt::volume__resize__start!(|| (cc, self.uuid));
+
+        sub_volume.resize(extent_count).await?;
+
+        let number_of_blocks = sub_volume.total_size().await? / self.block_size;
+        sub_volume
+            .lba_end
+            .store(sub_volume.lba_start + number_of_blocks, Ordering::SeqCst);
+
+        cdt::volume__resize__done!(|| (cc, self.uuid));
+        Ok(())
+    }
+
     async fn show_work(&self) -> Result<WQCounts, CrucibleError> {
         let mut wq_counts = WQCounts {
             up_count: 0,
@@ -764,10 +1068,33 @@ impl BlockIO for Volume {
 
         Ok(wq_counts)
     }
+
+    // A volume that is itself a sub volume passes a scrub status on to
+    // where its blocks are written.
+    async fn set_scrub_status(&self, status: ScrubStatus) {
+        for sub_volume in &self.sub_volumes {
+            sub_volume.set_scrub_status(status.clone()).await;
+        }
+    }
 }
 
 // Traditional subvolume is just one region set
 impl SubVolume {
+    pub fn new(
+        lba_range: Range<u64>,
+        block_io: Arc<dyn BlockIO + Send + Sync>,
+    ) -> SubVolume {
+        SubVolume {
+            lba_start: lba_range.start,
+            lba_end: Arc::new(AtomicU64::new(lba_range.end)),
+            block_io,
+        }
+    }
+
+    pub fn lba_range(&self) -> Range<u64> {
+        self.lba_start..self.lba_end.load(Ordering::SeqCst)
+    }
+
     // Compute sub volume LBA from total volume LBA.
     //
     // Total volume address:                    x
@@ -783,8 +1110,9 @@ impl SubVolume {
     //     = 210
     //
     pub fn compute_sub_volume_lba(&self, address: u64) -> u64 {
-        assert!(self.lba_range.contains(&address));
-        address - self.lba_range.start
+        let lba_range = self.lba_range();
+        assert!(lba_range.contains(&address));
+        address - lba_range.start
     }
 
     pub fn lba_range_coverage(
@@ -794,6 +1122,8 @@ impl SubVolume {
     ) -> Option<Range<u64>> {
         assert!(length >= 1);
 
+        let lba_range = self.lba_range();
+
         let end = start + length - 1;
 
         // No coverage:
@@ -803,11 +1133,11 @@ impl SubVolume {
         // argument range:                                  |--------|
         //
 
-        if end < self.lba_range.start {
+        if end < lba_range.start {
             return None;
         }
 
-        if start >= self.lba_range.end {
+        if start >= lba_range.end {
             return None;
         }
 
@@ -817,36 +1147,36 @@ impl SubVolume {
         // argument range:              |-------|
         // argument range:                 |--------|
 
-        if self.lba_range.contains(&start) && self.lba_range.contains(&end) {
+        if lba_range.contains(&start) && lba_range.contains(&end) {
             return Some(start..(start + length));
         }
 
         // Partial coverage:
 
-        if self.lba_range.contains(&start) {
-            assert!(!self.lba_range.contains(&end));
+        if lba_range.contains(&start) {
+            assert!(!lba_range.contains(&end));
 
             // lba_range:                  |-------------|
             // argument range:                         |--------|
             // coverage:                               ^^^
 
-            Some(start..self.lba_range.end)
-        } else if self.lba_range.contains(&end) {
-            assert!(!self.lba_range.contains(&start));
+            Some(start..lba_range.end)
+        } else if lba_range.contains(&end) {
+            assert!(!lba_range.contains(&start));
 
             // lba_range:                  |-------------|
             // argument range:          |-------|
             // coverage:                   ^^^^^^
-            Some(self.lba_range.start..(end + 1))
-        } else if start < self.lba_range.start && end > self.lba_range.end {
+            Some(lba_range.start..(end + 1))
+        } else if start < lba_range.start && end > lba_range.end {
             // lba_range:                  |-------------|
             // argument range:          |--------------------|
             // coverage:                   ^^^^^^^^^^^^^^^
-            Some(self.lba_range.clone())
+            Some(lba_range)
         } else {
             panic!(
                 "should never get here! {:?} {} {}",
-                self.lba_range, start, length
+                lba_range, start, length
             );
         }
     }
@@ -909,9 +1239,25 @@ impl BlockIO for SubVolume {
         self.block_io.flush(snapshot_details).await
     }
 
+    async fn discard(
+        &self,
+        offset: Block,
+        len: u64,
+    ) -> Result<(), CrucibleError> {
+        self.block_io.discard(offset, len).await
+    }
+
+    async fn resize(&self, extent_count: u32) -> Result<(), CrucibleError> {
+        self.block_io.resize(extent_count).await
+    }
+
     async fn show_work(&self) -> Result<WQCounts, CrucibleError> {
         self.block_io.show_work().await
     }
+
+    async fn set_scrub_status(&self, status: ScrubStatus) {
+        self.block_io.set_scrub_status(status).await
+    }
 }
 
 impl Volume {
@@ -926,9 +1272,18 @@ impl Volume {
                 block_size,
                 sub_volumes,
                 read_only_parent,
+                stripe_blocks,
+                scrub_state_dir,
             } => {
                 let mut vol = Volume::new_with_id(block_size, id);
 
+                if let Some(stripe_blocks) = stripe_blocks {
+                    vol.set_stripe_blocks(stripe_blocks)?;
+                }
+                if let Some(dir) = scrub_state_dir {
+                    vol.set_scrub_state_dir(PathBuf::from(dir));
+                }
+
                 for subreq in sub_volumes {
                     vol.add_subvolume(Arc::new(
                         Volume::construct(subreq, producer_registry.clone())
@@ -966,6 +1321,7 @@ impl Volume {
                 opts,
                 gen,
             } => {
+                check_replicas(&opts)?;
                 let mut vol = Volume::new(block_size);
                 vol.add_subvolume_create_guest(opts, gen, producer_registry)
                     .await?;
@@ -984,7 +1340,215 @@ impl Volume {
                 .await?;
                 Ok(vol)
             }
+
+            VolumeConstructionRequest::ErasureCoded {
+                id,
+                block_size,
+                data_shards,
+                parity_shards,
+                opts,
+                gen,
+                shard_state,
+            } => {
+                let mut vol = Volume::new(block_size);
+                vol.add_subvolume(
+                    ErasureCodedBlockIO::create(
+                        id,
+                        block_size,
+                        data_shards,
+                        parity_shards,
+                        opts,
+                        gen,
+                        shard_state.map(PathBuf::from),
+                        producer_registry,
+                    )
+                    .await?,
+                )
+                .await?;
+                Ok(vol)
+            }
+        }
+    }
+}
+
+// The ID of the volume, region, or other source a request builds.
+fn request_id(request: &VolumeConstructionRequest) -> Uuid {
+    match request {
+        VolumeConstructionRequest::Volume { id, .. }
+        | VolumeConstructionRequest::Url { id, .. }
+        | VolumeConstructionRequest::File { id, .. }
+        | VolumeConstructionRequest::ErasureCoded { id, .. } => *id,
+        VolumeConstructionRequest::Region { opts, .. } => opts.id,
+    }
+}
+
+// Remove and return the read only parent of a request, leaving just its
+// own layer.
+fn take_read_only_parent(
+    request: &mut VolumeConstructionRequest,
+) -> Option<Box<VolumeConstructionRequest>> {
+    match request {
+        VolumeConstructionRequest::Volume {
+            read_only_parent, ..
+        } => read_only_parent.take(),
+        _ => None,
+    }
+}
+
+// Find parent_id in the read only parent chain of a request. Return the
+// layer of its child and its own layer, both without read only parents,
+// and the request with the parent taken out of the chain.
+fn flatten_request(
+    mut request: VolumeConstructionRequest,
+    parent_id: Uuid,
+) -> Result<(
+    VolumeConstructionRequest,
+    VolumeConstructionRequest,
+    VolumeConstructionRequest,
+)> {
+    let mut parent = match take_read_only_parent(&mut request) {
+        Some(parent) => *parent,
+        None => bail!("{} is not a read only parent of the volume", parent_id),
+    };
+
+    let (child, parent_layer, new_parent) = if request_id(&parent) == parent_id
+    {
+        let grandparent = take_read_only_parent(&mut parent);
+        (request.clone(), parent, grandparent)
+    } else {
+        let (child, parent_layer, parent) = flatten_request(parent, parent_id)?;
+        (child, parent_layer, Some(Box::new(parent)))
+    };
+
+    if let VolumeConstructionRequest::Volume {
+        read_only_parent, ..
+    } = &mut request
+    {
+        *read_only_parent = new_parent;
+    }
+
+    Ok((child, parent_layer, request))
+}
+
+// Blocks are merged into a flattened layer with writes, so every part of
+// it has to take them: a file or URL can't, nor can a region opened read
+// only.
+fn check_writable(request: &VolumeConstructionRequest) -> Result<()> {
+    match request {
+        VolumeConstructionRequest::Volume { sub_volumes, .. } => {
+            sub_volumes.iter().try_for_each(check_writable)
+        }
+        VolumeConstructionRequest::Region { opts, .. }
+        | VolumeConstructionRequest::ErasureCoded { opts, .. } => {
+            if opts.read_only {
+                bail!("{} is read only, it can't be flattened into", opts.id);
+            }
+            Ok(())
+        }
+        VolumeConstructionRequest::Url { id, .. }
+        | VolumeConstructionRequest::File { id, .. } => {
+            bail!("{} is read only, it can't be flattened into", id)
+        }
+    }
+}
+
+// Copy every block that `from` has written into `to`. This uses
+// write_unwritten, so any block `to` has written itself is kept. Return
+// the number of blocks `from` has written.
+async fn copy_written_blocks(
+    from: &(dyn BlockIO + Send + Sync),
+    to: &(dyn BlockIO + Send + Sync),
+) -> Result<u64, CrucibleError> {
+    let block_size = from.get_block_size().await?;
+    if to.get_block_size().await? != block_size {
+        crucible_bail!(BlockSizeMismatch);
+    }
+    let shift = block_size.trailing_zeros();
+
+    // A parent may be smaller than its child, but the child can't take
+    // blocks past its own end.
+    let end = std::cmp::min(from.total_size().await?, to.total_size().await?)
+        / block_size;
+
+    // Copy in the same size IOs the scrubber uses.
+    let chunk = 131072 / block_size;
+    let mut written = 0;
+    let mut offset = 0;
+    while offset < end {
+        let count = std::cmp::min(chunk, end - offset);
+        let buffer = Buffer::new((count * block_size) as usize);
+        from.read(Block::new(offset, shift), buffer.clone()).await?;
+        let data = buffer.as_vec().await.clone();
+        let owned = buffer.owned_vec().await.clone();
+        let owned_block = |i: u64| owned[(i * block_size) as usize];
+
+        // Write each run of written blocks with one IO.
+        let mut i = 0;
+        while i < count {
+            if !owned_block(i) {
+                i += 1;
+                continue;
+            }
+            let run_start = i;
+            while i < count && owned_block(i) {
+                i += 1;
+            }
+            let run = Bytes::copy_from_slice(
+                &data[(run_start * block_size) as usize
+                    ..(i * block_size) as usize],
+            );
+            to.write_unwritten(Block::new(offset + run_start, shift), run)
+                .await?;
+            written += i - run_start;
         }
+
+        offset += count;
+    }
+
+    Ok(written)
+}
+
+impl Volume {
+    // Take the read only parent parent_id out of the chain of `request`
+    // by merging it into its child, the layer it is the read only parent
+    // of. Every block the parent's own layer has written is copied into
+    // the child, unless the child has written that block itself, and the
+    // child then takes the parent's read only parent as its own. Only the
+    // blocks the parent wrote are copied, not the whole disk as a scrub of
+    // the top volume would.
+    //
+    // Return the request with the parent taken out. A volume built from it
+    // reads the same data as one built from `request`, and no longer uses
+    // the parent.
+    //
+    // This activates the child and the parent itself, so neither can be in
+    // use elsewhere while it runs. A child that is read only is refused.
+    pub async fn flatten(
+        log: &Logger,
+        request: VolumeConstructionRequest,
+        parent_id: Uuid,
+        producer_registry: Option<ProducerRegistry>,
+    ) -> Result<VolumeConstructionRequest> {
+        let (child, parent, request) = flatten_request(request, parent_id)?;
+        check_writable(&child)?;
+
+        let child = Volume::construct(child, producer_registry.clone()).await?;
+        let parent = Volume::construct(parent, producer_registry).await?;
+        child.activate().await?;
+        parent.activate().await?;
+
+        info!(log, "Flatten {} into {}", parent_id, child.uuid);
+        let written = copy_written_blocks(&parent, &child).await?;
+        child.flush(None).await?;
+        info!(
+            log,
+            "Flatten {} done, {} blocks written", parent_id, written
+        );
+
+        parent.deactivate().await?;
+        child.deactivate().await?;
+
+        Ok(request)
     }
 }
 
@@ -997,10 +1561,7 @@ mod test {
 
     #[test]
     fn test_single_block() -> Result<()> {
-        let sub_volume = SubVolume {
-            lba_range: 0..10,
-            block_io: Arc::new(Guest::new()),
-        };
+        let sub_volume = SubVolume::new(0..10, Arc::new(Guest::new()));
 
         // Coverage inside region
         assert_eq!(sub_volume.lba_range_coverage(0, 1), Some(0..1));
@@ -1010,10 +1571,7 @@ mod test {
 
     #[test]
     fn test_single_sub_volume_lba_coverage() -> Result<()> {
-        let sub_volume = SubVolume {
-            lba_range: 0..2048,
-            block_io: Arc::new(Guest::new()),
-        };
+        let sub_volume = SubVolume::new(0..2048, Arc::new(Guest::new()));
 
         // Coverage inside region
         assert_eq!(sub_volume.lba_range_coverage(0, 1), Some(0..1),);
@@ -1032,10 +1590,7 @@ mod test {
 
     #[test]
     fn test_single_sub_volume_lba_coverage_with_offset() -> Result<()> {
-        let sub_volume = SubVolume {
-            lba_range: 1024..2048,
-            block_io: Arc::new(Guest::new()),
-        };
+        let sub_volume = SubVolume::new(1024..2048, Arc::new(Guest::new()));
 
         // No coverage before region
         assert_eq!(sub_volume.lba_range_coverage(0, 512), None,);
@@ -1064,30 +1619,32 @@ mod test {
         let volume = Volume {
             uuid: Uuid::new_v4(),
             sub_volumes: vec![
-                SubVolume {
-                    lba_range: Range { start: 0, end: 512 },
-                    block_io: Arc::new(InMemoryBlockIO::new(
+                SubVolume::new(
+                    Range { start: 0, end: 512 },
+                    Arc::new(InMemoryBlockIO::new(
                         Uuid::new_v4(),
                         512,
                         512 * 512,
                     )),
-                },
-                SubVolume {
-                    lba_range: Range {
+                ),
+                SubVolume::new(
+                    Range {
                         start: 512,
                         end: 1024,
                     },
-                    block_io: Arc::new(InMemoryBlockIO::new(
+                    Arc::new(InMemoryBlockIO::new(
                         Uuid::new_v4(),
                         512,
                         512 * 512,
                     )),
-                },
+                ),
             ],
             read_only_parent: None,
             scrub_point: Arc::new(AtomicU64::new(0)),
             block_size: 512,
             count: Arc::new(AtomicU32::new(0)),
+            stripe_blocks: None,
+            scrub: ScrubState::default(),
         };
 
         assert_eq!(volume.total_size().await?, 512 * 1024);
@@ -1104,41 +1661,43 @@ mod test {
         let volume = Volume {
             uuid: Uuid::new_v4(),
             sub_volumes: vec![
-                SubVolume {
-                    lba_range: Range { start: 0, end: 512 },
-                    block_io: Arc::new(InMemoryBlockIO::new(
+                SubVolume::new(
+                    Range { start: 0, end: 512 },
+                    Arc::new(InMemoryBlockIO::new(
                         Uuid::new_v4(),
                         512,
                         512 * 512,
                     )),
-                },
-                SubVolume {
-                    lba_range: Range {
+                ),
+                SubVolume::new(
+                    Range {
                         start: 512,
                         end: 1024,
                     },
-                    block_io: Arc::new(InMemoryBlockIO::new(
+                    Arc::new(InMemoryBlockIO::new(
                         Uuid::new_v4(),
                         512,
                         512 * 512,
                     )),
-                },
-                SubVolume {
-                    lba_range: Range {
+                ),
+                SubVolume::new(
+                    Range {
                         start: 1024,
                         end: 1536,
                     },
-                    block_io: Arc::new(InMemoryBlockIO::new(
+                    Arc::new(InMemoryBlockIO::new(
                         Uuid::new_v4(),
                         512,
                         512 * 512,
                     )),
-                },
+                ),
             ],
             read_only_parent: None,
             scrub_point: Arc::new(AtomicU64::new(0)),
             block_size: 512,
             count: Arc::new(AtomicU32::new(0)),
+            stripe_blocks: None,
+            scrub: ScrubState::default(),
         };
 
         // volume:       |--------|--------|--------|
@@ -1195,18 +1754,16 @@ mod test {
         // sub volume:  |-------------------|
         let volume = Volume {
             uuid: Uuid::new_v4(),
-            sub_volumes: vec![SubVolume {
-                lba_range: Range { start: 0, end: 512 },
-                block_io: Arc::new(InMemoryBlockIO::new(
-                    Uuid::new_v4(),
-                    512,
-                    512 * 512,
-                )),
-            }],
+            sub_volumes: vec![SubVolume::new(
+                Range { start: 0, end: 512 },
+                Arc::new(InMemoryBlockIO::new(Uuid::new_v4(), 512, 512 * 512)),
+            )],
             read_only_parent: None,
             scrub_point: Arc::new(AtomicU64::new(0)),
             block_size: 512,
             count: Arc::new(AtomicU32::new(0)),
+            stripe_blocks: None,
+            scrub: ScrubState::default(),
         };
 
         assert!(volume.read_only_parent_for_lba_range(0, 512).is_none());
@@ -1220,25 +1777,19 @@ mod test {
         // parent:      |xxxxxxxxx|
         let volume = Volume {
             uuid: Uuid::new_v4(),
-            sub_volumes: vec![SubVolume {
-                lba_range: Range { start: 0, end: 512 },
-                block_io: Arc::new(InMemoryBlockIO::new(
-                    Uuid::new_v4(),
-                    512,
-                    512 * 512,
-                )),
-            }],
-            read_only_parent: Some(Arc::new(SubVolume {
-                lba_range: Range { start: 0, end: 256 },
-                block_io: Arc::new(InMemoryBlockIO::new(
-                    Uuid::new_v4(),
-                    512,
-                    256 * 512,
-                )),
-            })),
+            sub_volumes: vec![SubVolume::new(
+                Range { start: 0, end: 512 },
+                Arc::new(InMemoryBlockIO::new(Uuid::new_v4(), 512, 512 * 512)),
+            )],
+            read_only_parent: Some(Arc::new(SubVolume::new(
+                Range { start: 0, end: 256 },
+                Arc::new(InMemoryBlockIO::new(Uuid::new_v4(), 512, 256 * 512)),
+            ))),
             scrub_point: Arc::new(AtomicU64::new(0)),
             block_size: 512,
             count: Arc::new(AtomicU32::new(0)),
+            stripe_blocks: None,
+            scrub: ScrubState::default(),
         };
 
         assert!(volume.read_only_parent_for_lba_range(0, 512).is_some());
@@ -1342,42 +1893,447 @@ mod test {
         Ok(())
     }
 
-    // Accept an initialization value so that we can test when the read only
-    // parent is uninitialized, and is initialized with a value
-    async fn test_parent_read_only_region(
-        block_size: u64,
-        parent: Arc<dyn BlockIO + Send + Sync>,
-        mut volume: Volume,
-        read_only_parent_init_value: u8,
-    ) -> Result<()> {
+    #[tokio::test]
+    async fn test_volume_discard_multiple_sub_volumes() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        // volumes: 0 0 0
+        //                1 1 1 1 1
+        let subvolume_1 = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 3,
+        ));
+        let subvolume_2 = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 5,
+        ));
+
+        let mut volume = Volume::new(BLOCK_SIZE as u64);
+        volume.add_subvolume(subvolume_1).await?;
+        volume.add_subvolume(subvolume_2).await?;
         volume.activate().await?;
-        assert_eq!(volume.get_block_size().await?, 512);
-        assert_eq!(block_size, 512);
-        assert_eq!(volume.total_size().await?, 4096);
 
-        // Volume is set up like this:
-        //
-        // parent blocks: n n n n
-        //    sub volume: 0 0 0 0 0 0 0 0
-        //
-        // where "n" is read_only_parent_init_value (or "-" if the read only
-        // parent was uninitialized).
-        //
-        // blocks that have not been written to will be marked with "-" and will
-        // assumed to be all zeros.
+        volume
+            .write(
+                Block::new(0, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![9; BLOCK_SIZE * 8]),
+            )
+            .await?;
 
-        // The first 2048b of the initial read should come only from the parent
-        // (aka read_only_parent_init_value), and the second 2048b should come
-        // from the subvolume(s) (aka be uninitialized).
-        let buffer = Buffer::new(4096);
+        // Discard blocks 2 through 4, spanning both sub volumes
         volume
-            .read(Block::new(0, block_size.trailing_zeros()), buffer.clone())
+            .discard(
+                Block::new(2, BLOCK_SIZE.trailing_zeros()),
+                BLOCK_SIZE as u64 * 3,
+            )
             .await?;
 
-        let mut expected = vec![read_only_parent_init_value; 512 * 4];
-        expected.extend(vec![0x00; 512 * 4]);
+        let buffer = Buffer::new(BLOCK_SIZE * 8);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
 
-        assert_eq!(*buffer.as_vec().await, expected);
+        let mut expected = vec![9; BLOCK_SIZE * 2];
+        expected.extend(vec![0; BLOCK_SIZE * 3]);
+        expected.extend(vec![9; BLOCK_SIZE * 3]);
+        assert_eq!(expected, *buffer.as_vec().await);
+
+        // Discarded blocks are unwritten, so write_unwritten lands there
+        // but not on the blocks around them.
+        volume
+            .write_unwritten(
+                Block::new(1, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![3; BLOCK_SIZE * 5]),
+            )
+            .await?;
+
+        let buffer = Buffer::new(BLOCK_SIZE * 8);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+
+        let mut expected = vec![9; BLOCK_SIZE * 2];
+        expected.extend(vec![3; BLOCK_SIZE * 3]);
+        expected.extend(vec![9; BLOCK_SIZE * 3]);
+        assert_eq!(expected, *buffer.as_vec().await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_volume_discard_with_read_only_parent() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        let subvolume = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 4,
+        ));
+        let parent = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 2,
+        ));
+        parent
+            .write(
+                Block::new(0, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![11; BLOCK_SIZE * 2]),
+            )
+            .await?;
+
+        let mut volume = Volume::new(BLOCK_SIZE as u64);
+        volume.add_subvolume(subvolume).await?;
+        volume.add_read_only_parent(parent).await?;
+        volume.activate().await?;
+
+        volume
+            .write(
+                Block::new(0, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![9; BLOCK_SIZE * 4]),
+            )
+            .await?;
+        volume
+            .discard(
+                Block::new(1, BLOCK_SIZE.trailing_zeros()),
+                BLOCK_SIZE as u64 * 2,
+            )
+            .await?;
+
+        // Even without a scrub, the discarded blocks read back as zero and
+        // not as the parent's data.
+        let buffer = Buffer::new(BLOCK_SIZE * 4);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+
+        let mut expected = vec![9; BLOCK_SIZE];
+        expected.extend(vec![0; BLOCK_SIZE * 2]);
+        expected.extend(vec![9; BLOCK_SIZE]);
+        assert_eq!(expected, *buffer.as_vec().await);
+
+        // The block past the parent is unwritten, the one the parent
+        // covers is not, so a scrub can't bring its data back.
+        volume
+            .write_unwritten(
+                Block::new(1, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![3; BLOCK_SIZE * 2]),
+            )
+            .await?;
+        let buffer = Buffer::new(BLOCK_SIZE * 4);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+
+        let mut expected = vec![9; BLOCK_SIZE];
+        expected.extend(vec![0; BLOCK_SIZE]);
+        expected.extend(vec![3; BLOCK_SIZE]);
+        expected.extend(vec![9; BLOCK_SIZE]);
+        assert_eq!(expected, *buffer.as_vec().await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_out_of_bounds() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        let disk = InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 4,
+        );
+
+        let offset = Block::new(3, BLOCK_SIZE.trailing_zeros());
+        assert!(disk
+            .write(offset, Bytes::from(vec![1; BLOCK_SIZE * 2]))
+            .await
+            .is_err());
+        assert!(disk
+            .write_unwritten(offset, Bytes::from(vec![1; BLOCK_SIZE * 2]))
+            .await
+            .is_err());
+        assert!(disk
+            .read(offset, Buffer::new(BLOCK_SIZE * 2))
+            .await
+            .is_err());
+        assert!(disk.discard(offset, BLOCK_SIZE as u64 * 2).await.is_err());
+        assert!(disk.discard(offset, BLOCK_SIZE as u64 - 1).await.is_err());
+
+        // The last block is still in bounds.
+        disk.discard(offset, BLOCK_SIZE as u64).await?;
+
+        Ok(())
+    }
+
+    async fn striped_volume(
+        block_size: usize,
+        stripe_blocks: u64,
+        subvolumes: &[Arc<InMemoryBlockIO>],
+    ) -> Result<Volume> {
+        let mut volume = Volume::new(block_size as u64);
+        volume.set_stripe_blocks(stripe_blocks)?;
+        for subvolume in subvolumes {
+            volume.add_subvolume(subvolume.clone()).await?;
+        }
+        volume.activate().await?;
+        Ok(volume)
+    }
+
+    #[tokio::test]
+    async fn test_striped_affected_subvolumes() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        // volume:  0 0 1 1 2 2 0 0 1 1 2 2 ...
+        let subvolumes: Vec<_> = (0..3)
+            .map(|_| {
+                Arc::new(InMemoryBlockIO::new(
+                    Uuid::new_v4(),
+                    BLOCK_SIZE as u64,
+                    BLOCK_SIZE * 8,
+                ))
+            })
+            .collect();
+        let volume = striped_volume(BLOCK_SIZE, 2, &subvolumes).await?;
+        assert_eq!(volume.total_size().await?, BLOCK_SIZE as u64 * 24);
+
+        let starts: Vec<_> = volume
+            .sub_volumes_for_lba_range(1, 6)
+            .into_iter()
+            .map(|(coverage, sub_volume)| {
+                (coverage, sub_volume.lba_range().start)
+            })
+            .collect();
+        assert_eq!(starts, vec![(1..2, 0), (2..4, 8), (4..6, 16), (6..7, 0)]);
+
+        // Stripe 3 is the second stripe of sub volume 0.
+        let sub_volume = &volume.sub_volumes[0];
+        assert_eq!(volume.compute_sub_volume_lba(sub_volume, 6), 2);
+        assert_eq!(volume.compute_sub_volume_lba(sub_volume, 7), 3);
+        let sub_volume = &volume.sub_volumes[2];
+        assert_eq!(volume.compute_sub_volume_lba(sub_volume, 23), 7);
+
+        // Nothing past the end.
+        assert_eq!(volume.sub_volumes_for_lba_range(22, 10).len(), 1);
+        assert!(volume.sub_volumes_for_lba_range(24, 10).is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_striped_volume_io() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        let subvolumes: Vec<_> = (0..2)
+            .map(|_| {
+                Arc::new(InMemoryBlockIO::new(
+                    Uuid::new_v4(),
+                    BLOCK_SIZE as u64,
+                    BLOCK_SIZE * 4,
+                ))
+            })
+            .collect();
+        let volume = striped_volume(BLOCK_SIZE, 2, &subvolumes).await?;
+
+        // Block n of the volume is filled with n.
+        let mut data = vec![];
+        for n in 0..8 {
+            data.extend(vec![n as u8; BLOCK_SIZE]);
+        }
+        volume
+            .write(
+                Block::new(0, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(data.clone()),
+            )
+            .await?;
+
+        let buffer = Buffer::new(BLOCK_SIZE * 8);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+        assert_eq!(data, *buffer.as_vec().await);
+
+        // Each sub volume got every other stripe.
+        for (subvolume, blocks) in
+            subvolumes.iter().zip([[0, 1, 4, 5], [2, 3, 6, 7]])
+        {
+            let buffer = Buffer::new(BLOCK_SIZE * 4);
+            subvolume
+                .read(
+                    Block::new(0, BLOCK_SIZE.trailing_zeros()),
+                    buffer.clone(),
+                )
+                .await?;
+            let mut expected = vec![];
+            for n in blocks {
+                expected.extend(vec![n as u8; BLOCK_SIZE]);
+            }
+            assert_eq!(expected, *buffer.as_vec().await);
+        }
+
+        // A read that starts and ends in the middle of stripes.
+        let buffer = Buffer::new(BLOCK_SIZE * 5);
+        volume
+            .read(Block::new(1, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+        assert_eq!(data[BLOCK_SIZE..BLOCK_SIZE * 6], *buffer.as_vec().await);
+
+        // Discard blocks 3 through 4, from both sub volumes.
+        volume
+            .discard(
+                Block::new(3, BLOCK_SIZE.trailing_zeros()),
+                BLOCK_SIZE as u64 * 2,
+            )
+            .await?;
+        let buffer = Buffer::new(BLOCK_SIZE * 8);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+        data[BLOCK_SIZE * 3..BLOCK_SIZE * 5].fill(0);
+        assert_eq!(data, *buffer.as_vec().await);
+
+        // A striped volume can't be resized.
+        assert!(volume.resize(1).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_striped_volume_with_read_only_parent() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        let subvolumes: Vec<_> = (0..2)
+            .map(|_| {
+                Arc::new(InMemoryBlockIO::new(
+                    Uuid::new_v4(),
+                    BLOCK_SIZE as u64,
+                    BLOCK_SIZE * 4,
+                ))
+            })
+            .collect();
+        let mut volume = Volume::new(BLOCK_SIZE as u64);
+        volume.set_stripe_blocks(2)?;
+        for subvolume in &subvolumes {
+            volume.add_subvolume(subvolume.clone()).await?;
+        }
+
+        let parent = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 5,
+        ));
+        parent
+            .write(
+                Block::new(0, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![11; BLOCK_SIZE * 5]),
+            )
+            .await?;
+        volume.add_read_only_parent(parent).await?;
+        volume.activate().await?;
+
+        // Write block 3, which is in sub volume 1.
+        volume
+            .write(
+                Block::new(3, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![55; BLOCK_SIZE]),
+            )
+            .await?;
+
+        let buffer = Buffer::new(BLOCK_SIZE * 8);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+
+        let mut expected = vec![11; BLOCK_SIZE * 3];
+        expected.extend(vec![55; BLOCK_SIZE]);
+        expected.extend(vec![11; BLOCK_SIZE]);
+        expected.extend(vec![0; BLOCK_SIZE * 3]);
+        assert_eq!(expected, *buffer.as_vec().await);
+
+        // Of the blocks under the parent, only the one written is owned by
+        // this volume.
+        let mut owned = vec![false; BLOCK_SIZE * 3];
+        owned.extend(vec![true; BLOCK_SIZE]);
+        owned.extend(vec![false; BLOCK_SIZE]);
+        assert_eq!(owned, buffer.owned_vec().await[..BLOCK_SIZE * 5]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_striped_sub_volume_sizes() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        let mut volume = Volume::new(BLOCK_SIZE as u64);
+        assert!(volume.set_stripe_blocks(0).is_err());
+        volume.set_stripe_blocks(4)?;
+
+        // Not a whole number of stripes
+        let subvolume = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 6,
+        ));
+        assert!(volume.add_subvolume(subvolume).await.is_err());
+
+        let subvolume = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 8,
+        ));
+        volume.add_subvolume(subvolume).await?;
+
+        // Not the same size as the first
+        let subvolume = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 4,
+        ));
+        assert!(volume.add_subvolume(subvolume).await.is_err());
+
+        // Too late to change the stripe
+        assert!(volume.set_stripe_blocks(2).is_err());
+
+        Ok(())
+    }
+
+    // Accept an initialization value so that we can test when the read only
+    // parent is uninitialized, and is initialized with a value
+    async fn test_parent_read_only_region(
+        block_size: u64,
+        parent: Arc<dyn BlockIO + Send + Sync>,
+        mut volume: Volume,
+        read_only_parent_init_value: u8,
+    ) -> Result<()> {
+        volume.activate().await?;
+        assert_eq!(volume.get_block_size().await?, 512);
+        assert_eq!(block_size, 512);
+        assert_eq!(volume.total_size().await?, 4096);
+
+        // Volume is set up like this:
+        //
+        // parent blocks: n n n n
+        //    sub volume: 0 0 0 0 0 0 0 0
+        //
+        // where "n" is read_only_parent_init_value (or "-" if the read only
+        // parent was uninitialized).
+        //
+        // blocks that have not been written to will be marked with "-" and will
+        // assumed to be all zeros.
+
+        // The first 2048b of the initial read should come only from the parent
+        // (aka read_only_parent_init_value), and the second 2048b should come
+        // from the subvolume(s) (aka be uninitialized).
+        let buffer = Buffer::new(4096);
+        volume
+            .read(Block::new(0, block_size.trailing_zeros()), buffer.clone())
+            .await?;
+
+        let mut expected = vec![read_only_parent_init_value; 512 * 4];
+        expected.extend(vec![0x00; 512 * 4]);
+
+        assert_eq!(*buffer.as_vec().await, expected);
 
         // If the parent volume has data, it should be returned. Write ones to
         // the first block of the parent:
@@ -1798,16 +2754,18 @@ mod test {
         let volume = Volume {
             uuid: Uuid::new_v4(),
             sub_volumes: vec![],
-            read_only_parent: Some(Arc::new(SubVolume {
-                lba_range: Range {
+            read_only_parent: Some(Arc::new(SubVolume::new(
+                Range {
                     start: 0,
                     end: parent.total_size().await? / BLOCK_SIZE as u64,
                 },
-                block_io: parent.clone(),
-            })),
+                parent.clone(),
+            ))),
             scrub_point: Arc::new(AtomicU64::new(0)),
             block_size: BLOCK_SIZE,
             count: Arc::new(AtomicU32::new(0)),
+            stripe_blocks: None,
+            scrub: ScrubState::default(),
         };
 
         volume.activate().await?;
@@ -1837,16 +2795,18 @@ mod test {
         let volume = Volume {
             uuid: Uuid::new_v4(),
             sub_volumes: vec![],
-            read_only_parent: Some(Arc::new(SubVolume {
-                lba_range: Range {
+            read_only_parent: Some(Arc::new(SubVolume::new(
+                Range {
                     start: 0,
                     end: parent.total_size().await.unwrap() / BLOCK_SIZE as u64,
                 },
-                block_io: parent.clone(),
-            })),
+                parent.clone(),
+            ))),
             scrub_point: Arc::new(AtomicU64::new(0)),
             block_size: BLOCK_SIZE,
             count: Arc::new(AtomicU32::new(0)),
+            stripe_blocks: None,
+            scrub: ScrubState::default(),
         };
 
         volume.activate().await.unwrap();
@@ -1871,16 +2831,18 @@ mod test {
         let volume = Volume {
             uuid: Uuid::new_v4(),
             sub_volumes: vec![],
-            read_only_parent: Some(Arc::new(SubVolume {
-                lba_range: Range {
+            read_only_parent: Some(Arc::new(SubVolume::new(
+                Range {
                     start: 0,
                     end: parent.total_size().await.unwrap() / BLOCK_SIZE as u64,
                 },
-                block_io: parent.clone(),
-            })),
+                parent.clone(),
+            ))),
             scrub_point: Arc::new(AtomicU64::new(0)),
             block_size: BLOCK_SIZE,
             count: Arc::new(AtomicU32::new(0)),
+            stripe_blocks: None,
+            scrub: ScrubState::default(),
         };
 
         volume.activate().await.unwrap();
@@ -2048,6 +3010,190 @@ mod test {
         Ok(())
     }
 
+    // An in memory layer of ten blocks, with `value` written to `blocks`.
+    async fn chain_layer(
+        value: u8,
+        blocks: Range<u64>,
+    ) -> Result<Arc<InMemoryBlockIO>> {
+        const BLOCK_SIZE: usize = 512;
+
+        let layer = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 10,
+        ));
+        let len = (blocks.end - blocks.start) as usize * BLOCK_SIZE;
+        layer
+            .write(
+                Block::new(blocks.start, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![value; len]),
+            )
+            .await?;
+        Ok(layer)
+    }
+
+    // A volume for the first layer, with the rest of the layers as its
+    // chain of read only parents.
+    async fn chain_volume(layers: &[Arc<InMemoryBlockIO>]) -> Result<Volume> {
+        let mut parent: Option<Volume> = None;
+        for layer in layers.iter().rev() {
+            let mut volume = Volume::new(512);
+            volume.add_subvolume(layer.clone()).await?;
+            if let Some(parent) = parent {
+                volume.add_read_only_parent(Arc::new(parent)).await?;
+            }
+            parent = Some(volume);
+        }
+        Ok(parent.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_read_only_parent_chain_and_flatten() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+
+        // top:  4
+        //    a:         3
+        //    b:     2 2 2 2
+        //    c: 1 1 1 1 1 1 1 1 1 1
+        let top = chain_layer(4, 0..1).await?;
+        let a = chain_layer(3, 4..5).await?;
+        let b = chain_layer(2, 2..6).await?;
+        let c = chain_layer(1, 0..10).await?;
+
+        let mut expected = vec![4; BLOCK_SIZE];
+        expected.extend(vec![1; BLOCK_SIZE]);
+        expected.extend(vec![2; BLOCK_SIZE * 2]);
+        expected.extend(vec![3; BLOCK_SIZE]);
+        expected.extend(vec![2; BLOCK_SIZE]);
+        expected.extend(vec![1; BLOCK_SIZE * 4]);
+
+        let volume =
+            chain_volume(&[top.clone(), a.clone(), b.clone(), c.clone()])
+                .await?;
+        let buffer = Buffer::new(BLOCK_SIZE * 10);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+        assert_eq!(expected, *buffer.as_vec().await);
+
+        // Merge b into a. Of the four blocks b wrote, the one a wrote
+        // itself is kept.
+        assert_eq!(copy_written_blocks(&*b, &*a).await?, 4);
+
+        let volume = chain_volume(&[top, a, c]).await?;
+        let buffer = Buffer::new(BLOCK_SIZE * 10);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+        assert_eq!(expected, *buffer.as_vec().await);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_request() {
+        let file = |id| VolumeConstructionRequest::File {
+            id,
+            block_size: 512,
+            path: format!("/tmp/{}.raw", id),
+        };
+        let volume = |id, parent: Option<VolumeConstructionRequest>| {
+            VolumeConstructionRequest::Volume {
+                id,
+                block_size: 512,
+                sub_volumes: vec![file(Uuid::new_v4())],
+                read_only_parent: parent.map(Box::new),
+                stripe_blocks: None,
+                scrub_state_dir: None,
+            }
+        };
+        let without_parent = |mut request| {
+            take_read_only_parent(&mut request);
+            request
+        };
+
+        // top -> a -> b -> c
+        let c = file(Uuid::new_v4());
+        let b = volume(Uuid::new_v4(), Some(c.clone()));
+        let a = volume(Uuid::new_v4(), Some(b.clone()));
+        let top = volume(Uuid::new_v4(), Some(a.clone()));
+
+        // Merging b into a leaves top -> a -> c
+        let (child, parent, request) =
+            flatten_request(top.clone(), request_id(&b)).unwrap();
+        assert_eq!(child, without_parent(a.clone()));
+        assert_eq!(parent, without_parent(b.clone()));
+        let new_a = {
+            let mut a = without_parent(a.clone());
+            if let VolumeConstructionRequest::Volume {
+                read_only_parent, ..
+            } = &mut a
+            {
+                *read_only_parent = Some(Box::new(c.clone()));
+            }
+            a
+        };
+        let mut new_top = without_parent(top.clone());
+        if let VolumeConstructionRequest::Volume {
+            read_only_parent, ..
+        } = &mut new_top
+        {
+            *read_only_parent = Some(Box::new(new_a));
+        }
+        assert_eq!(request, new_top);
+
+        // The bottom of the chain can be merged too, leaving b with no
+        // read only parent.
+        let (child, parent, _) =
+            flatten_request(top.clone(), request_id(&c)).unwrap();
+        assert_eq!(child, without_parent(b));
+        assert_eq!(parent, c);
+
+        // Not a read only parent
+        assert!(flatten_request(top.clone(), request_id(&top)).is_err());
+        assert!(flatten_request(top, Uuid::new_v4()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_flatten_refuses_read_only_child() {
+        let log = Logger::root(slog::Discard, o!());
+        let region = |read_only| VolumeConstructionRequest::Region {
+            block_size: 512,
+            opts: CrucibleOpts {
+                id: Uuid::new_v4(),
+                read_only,
+                ..Default::default()
+            },
+            gen: 1,
+        };
+        let parent = VolumeConstructionRequest::File {
+            id: Uuid::new_v4(),
+            block_size: 512,
+            path: String::from("/nonexistent"),
+        };
+        let volume = |sub_volumes| VolumeConstructionRequest::Volume {
+            id: Uuid::new_v4(),
+            block_size: 512,
+            sub_volumes,
+            read_only_parent: Some(Box::new(parent.clone())),
+            stripe_blocks: None,
+            scrub_state_dir: None,
+        };
+
+        // Nothing is constructed, so none of these has to exist.
+        for child in [
+            volume(vec![region(true)]),
+            volume(vec![region(false), region(true)]),
+            volume(vec![parent.clone()]),
+        ] {
+            let res =
+                Volume::flatten(&log, child, request_id(&parent), None).await;
+            assert!(res.unwrap_err().to_string().contains("read only"));
+        }
+
+        assert!(check_writable(&volume(vec![region(false)])).is_ok());
+    }
+
     #[tokio::test]
     async fn construct_file_block_io() {
         const BLOCK_SIZE: usize = 512;
@@ -2068,6 +3214,8 @@ mod test {
                 block_size: 512,
                 path: file_path.into_os_string().into_string().unwrap(),
             })),
+            stripe_blocks: None,
+            scrub_state_dir: None,
         };
         let volume = Volume::construct(request, None).await.unwrap();
 
@@ -2088,6 +3236,101 @@ mod test {
         assert_eq!(vec![0x5; BLOCK_SIZE], *buffer.as_vec().await);
     }
 
+    #[tokio::test]
+    async fn construct_with_scrub_state_dir() {
+        let log = Logger::root(slog::Discard, o!());
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("disk.raw");
+        File::create(&file_path)
+            .unwrap()
+            .write_all(&[0u8; 1024])
+            .unwrap();
+
+        let parent_id = Uuid::new_v4();
+        let request = VolumeConstructionRequest::Volume {
+            id: Uuid::new_v4(),
+            block_size: 512,
+            sub_volumes: vec![],
+            read_only_parent: Some(Box::new(VolumeConstructionRequest::File {
+                id: parent_id,
+                block_size: 512,
+                path: file_path.into_os_string().into_string().unwrap(),
+            })),
+            stripe_blocks: None,
+            scrub_state_dir: Some(
+                dir.path()
+                    .to_path_buf()
+                    .into_os_string()
+                    .into_string()
+                    .unwrap(),
+            ),
+        };
+        let volume = Volume::construct(request, None).await.unwrap();
+
+        // The scrub point is kept in the directory the request names.
+        volume.scrub.save(volume.uuid, parent_id, 1, &log);
+        assert_eq!(volume.scrub.load(volume.uuid, parent_id, &log), 1);
+        assert!(std::fs::read_dir(dir.path()).unwrap().count() > 1);
+    }
+
+    #[tokio::test]
+    async fn construct_region_with_bad_write_quorum() {
+        let request = VolumeConstructionRequest::Region {
+            block_size: 512,
+            opts: CrucibleOpts {
+                target: vec!["127.0.0.1:3810".parse().unwrap()],
+                write_quorum: Some(2),
+                ..Default::default()
+            },
+            gen: 1,
+        };
+        let err = Volume::construct(request, None).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CrucibleError>(),
+            Some(CrucibleError::InvalidReplicas(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn construct_striped_file_block_io() {
+        const BLOCK_SIZE: usize = 512;
+
+        let dir = tempdir().unwrap();
+        let mut sub_volumes = vec![];
+        for n in 0..2u8 {
+            let file_path = dir.path().join(format!("disk{}.raw", n));
+            let mut file = File::create(&file_path).unwrap();
+            file.write_all(&vec![n; BLOCK_SIZE * 2]).unwrap();
+            sub_volumes.push(VolumeConstructionRequest::File {
+                id: Uuid::new_v4(),
+                block_size: BLOCK_SIZE as u64,
+                path: file_path.into_os_string().into_string().unwrap(),
+            });
+        }
+
+        let request = VolumeConstructionRequest::Volume {
+            id: Uuid::new_v4(),
+            block_size: BLOCK_SIZE as u64,
+            sub_volumes,
+            read_only_parent: None,
+            stripe_blocks: Some(1),
+            scrub_state_dir: None,
+        };
+        let volume = Volume::construct(request, None).await.unwrap();
+
+        let buffer = Buffer::new(BLOCK_SIZE * 4);
+        volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await
+            .unwrap();
+
+        let mut expected = vec![];
+        for n in [0, 1, 0, 1] {
+            expected.extend(vec![n; BLOCK_SIZE]);
+        }
+        assert_eq!(expected, *buffer.as_vec().await);
+    }
+
     // Test that blocks are correctly returned during read-only parent +
     // subvolume overlap.
     async fn test_correct_blocks_returned(
@@ -2374,6 +3617,78 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_scrub_picks_up_from_saved_point() -> Result<()> {
+        const BLOCK_SIZE: usize = 512;
+        let log = Logger::root(slog::Discard, o!());
+        let dir = tempdir()?;
+
+        let parent = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 1024,
+        ));
+        parent
+            .write(
+                Block::new(0, BLOCK_SIZE.trailing_zeros()),
+                Bytes::from(vec![1; BLOCK_SIZE * 1024]),
+            )
+            .await?;
+        let sub_volume = Arc::new(InMemoryBlockIO::new(
+            Uuid::new_v4(),
+            BLOCK_SIZE as u64,
+            BLOCK_SIZE * 1024,
+        ));
+
+        let mut volume = Volume::new(BLOCK_SIZE as u64);
+        volume.add_subvolume(sub_volume.clone()).await?;
+        volume.add_read_only_parent(parent.clone()).await?;
+        volume.set_scrub_state_dir(dir.path().to_path_buf());
+        volume.activate().await?;
+        let status = volume.scrub_status();
+        assert!(status.borrow().is_none());
+
+        // An earlier scrub got half way.
+        let parent_id = parent.get_uuid().await?;
+        volume.scrub.save(volume.uuid, parent_id, 512, &log);
+
+        volume.scrub(&log, None, None).await?;
+
+        let buffer = Buffer::new(BLOCK_SIZE * 1024);
+        sub_volume
+            .read(Block::new(0, BLOCK_SIZE.trailing_zeros()), buffer.clone())
+            .await?;
+        let data = buffer.as_vec().await;
+        assert!(data[..BLOCK_SIZE * 512].iter().all(|b| *b == 0));
+        assert!(data[BLOCK_SIZE * 512..].iter().all(|b| *b == 1));
+
+        let done = status.borrow().clone().unwrap();
+        assert!(done.done);
+        assert_eq!(done.scrub_point, 1024);
+        assert_eq!(done.percent_complete, 100.0);
+        assert_eq!(done.error, None);
+        assert_eq!(volume.scrub.load(volume.uuid, parent_id, &log), 1024);
+
+        // Nothing reads from the parent now.
+        assert_eq!(volume.scrub_point.load(Ordering::SeqCst), 1024);
+        assert!(volume.read_only_parent_for_lba_range(0, 1024).is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scrub_without_read_only_parent_is_done() -> Result<()> {
+        let log = Logger::root(slog::Discard, o!());
+        let volume = Volume::new(512);
+        volume.scrub(&log, None, None).await?;
+
+        let status = volume.scrub_status().borrow().clone().unwrap();
+        assert!(status.done);
+        assert_eq!(status.percent_complete, 100.0);
+
+        Ok(())
+    }
+
     // This test accepts a vec of sub-volume sizes, and a size for the read
     // only parent.  A volume is created using these inputs.  The test will
     // then walk all possible scrub points and IO sizes to test that the